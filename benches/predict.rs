@@ -0,0 +1,233 @@
+//! Benchmarks of prediction on synthetic data.
+//!
+//! To compare a change with the code before it, save a baseline on the parent commit with
+//! `cargo bench --bench predict -- --save-baseline before`, then run
+//! `cargo bench --bench predict -- --baseline before` with the change.
+use criterion::{black_box, criterion_group, criterion_main, Criterion, Throughput};
+use omikuji::model::bench::{dense_product_into, DenseKernel};
+use omikuji::model::{PredictOptions, TrainHyperParam};
+use omikuji::{DataSet, FeaturePairs, Model, Warnings};
+use rand::prelude::*;
+use rand::rngs::StdRng;
+use std::fmt::Write;
+
+/// Generate a dataset where each example has one label and about `nnz` features, half of which
+/// are drawn from a band of features owned by its label and the rest uniformly.
+fn synthetic_dataset(
+    n_examples: usize,
+    n_features: usize,
+    n_labels: usize,
+    nnz: usize,
+    seed: u64,
+) -> DataSet {
+    let mut rng = StdRng::seed_from_u64(seed);
+    let band = (n_features / n_labels).max(1);
+    let mut text = format!("{} {} {}\n", n_examples, n_features, n_labels);
+    for _ in 0..n_examples {
+        let label = rng.gen_range(0..n_labels);
+        let mut features = (0..nnz)
+            .map(|i| {
+                if i % 2 == 0 {
+                    (label * band + rng.gen_range(0..band)) % n_features
+                } else {
+                    rng.gen_range(0..n_features)
+                }
+            })
+            .collect::<Vec<_>>();
+        features.sort_unstable();
+        features.dedup();
+
+        write!(text, "{}", label).unwrap();
+        for feature in features {
+            write!(text, " {}:{:.3}", feature, rng.gen_range(0.1..1.)).unwrap();
+        }
+        text.push('\n');
+    }
+    DataSet::read_xc_repo_data_with_warnings(text.as_bytes(), &Warnings::new()).unwrap()
+}
+
+/// Train a model on the dataset with small leaves, so that predictions search several levels.
+fn train_model(dataset: &DataSet, n_trees: usize) -> Model {
+    let mut hyper_param = TrainHyperParam::default();
+    hyper_param.n_trees = n_trees;
+    hyper_param.min_branch_size = 10;
+    hyper_param.train(dataset.clone())
+}
+
+/// Prediction inputs allocated per request, as serving code receives them, in a heap-allocated
+/// vector and in inline feature pairs.
+fn bench_input_types(c: &mut Criterion) {
+    let dataset = synthetic_dataset(2000, 5000, 200, 100, 0);
+    let model = train_model(&dataset, 3);
+    let inputs = synthetic_dataset(200, 5000, 200, 100, 1);
+    let inputs = inputs.feature_lists();
+    let predictor = model.predictor(PredictOptions::default()).unwrap();
+
+    let mut group = c.benchmark_group("input_types");
+    group.bench_function("vec", |b| {
+        b.iter(|| {
+            for pairs in inputs {
+                black_box(model.predict(pairs.to_vec(), 10));
+            }
+        })
+    });
+    group.bench_function("feature_pairs", |b| {
+        b.iter(|| {
+            for pairs in inputs {
+                let pairs = pairs.iter().cloned().collect::<FeaturePairs>();
+                black_box(model.predict(pairs, 10));
+            }
+        })
+    });
+    group.bench_function("feature_pairs_with_predictor", |b| {
+        b.iter(|| {
+            for pairs in inputs {
+                let pairs = pairs.iter().cloned().collect::<FeaturePairs>();
+                black_box(predictor.predict_with_beam(pairs, 10));
+            }
+        })
+    });
+    group.finish();
+}
+
+/// A large test set predicted one example at a time, in a batch parallelized across examples, and
+/// in a batch on a dedicated thread pool built once.
+fn bench_batch(c: &mut Criterion) {
+    let dataset = synthetic_dataset(2000, 5000, 200, 100, 0);
+    let model = train_model(&dataset, 3);
+    let test_set = synthetic_dataset(100_000, 5000, 200, 100, 2);
+    let feature_vecs = test_set.feature_lists();
+    let pool = rayon::ThreadPoolBuilder::new().build().unwrap();
+
+    let mut group = c.benchmark_group("batch");
+    group.sample_size(10);
+    group.throughput(Throughput::Elements(feature_vecs.len() as u64));
+    group.bench_function("predict_loop", |b| {
+        b.iter(|| {
+            feature_vecs
+                .iter()
+                .map(|feature_vec| model.predict(feature_vec, 10))
+                .collect::<Vec<_>>()
+        })
+    });
+    group.bench_function("predict_batch", |b| {
+        b.iter(|| model.predict_batch(feature_vecs, 10))
+    });
+    group.bench_function("predict_batch_in_pool", |b| {
+        b.iter(|| model.predict_batch_in_pool(feature_vecs, 10, &pool))
+    });
+    group.finish();
+}
+
+/// Aggregating scores over trees for a model with many labels and a wide beam, ranking all labels
+/// reached, only the best few, or none.
+fn bench_aggregation(c: &mut Criterion) {
+    let dataset = synthetic_dataset(20_000, 20_000, 5000, 50, 0);
+    let model = train_model(&dataset, 3);
+    let inputs = synthetic_dataset(200, 20_000, 5000, 50, 3);
+    let inputs = inputs.feature_lists();
+
+    let mut group = c.benchmark_group("aggregation");
+    group.bench_function("predict", |b| {
+        b.iter(|| {
+            for feature_vec in inputs {
+                black_box(model.predict(feature_vec, 20));
+            }
+        })
+    });
+    group.bench_function("predict_top_k", |b| {
+        b.iter(|| {
+            for feature_vec in inputs {
+                black_box(model.predict_top_k(feature_vec, 20, 5));
+            }
+        })
+    });
+    group.bench_function("predict_unsorted", |b| {
+        b.iter(|| {
+            for feature_vec in inputs {
+                black_box(model.predict_unsorted(feature_vec, 20));
+            }
+        })
+    });
+    group.finish();
+}
+
+/// Products of a 30k-feature by 100-label leaf weight matrix with sparse inputs, with the matrix
+/// stored sparse and dense.
+fn bench_leaf_weights(c: &mut Criterion) {
+    let dataset = synthetic_dataset(3000, 30_000, 100, 300, 0);
+    let mut hyper_param = TrainHyperParam::default();
+    hyper_param.n_trees = 1;
+    // A single leaf with all labels, whose weights are all kept
+    hyper_param.min_branch_size = 1000;
+    hyper_param.linear.weight_threshold = 0.;
+    let sparse = hyper_param.train(dataset);
+    let mut dense = sparse.clone();
+    dense.densify_weights(0.);
+    let inputs = synthetic_dataset(200, 30_000, 100, 300, 4);
+    let inputs = inputs.feature_lists();
+
+    let mut group = c.benchmark_group("leaf_weights");
+    for (name, model) in [("sparse", &sparse), ("dense", &dense)] {
+        group.bench_function(name, |b| {
+            b.iter(|| {
+                for feature_vec in inputs {
+                    black_box(model.predict(feature_vec, 1));
+                }
+            })
+        });
+    }
+    group.finish();
+}
+
+/// Products of a 30k-feature by 100-label dense weight matrix with the kernel used before
+/// unrolling, which takes dot products with the columns, and with the unrolled one, for a sparse
+/// input with 300 active features and a dense one with all of them.
+fn bench_dense_kernels(c: &mut Criterion) {
+    let (n_features, n_labels) = (30_000, 100);
+    let mut rng = StdRng::seed_from_u64(0);
+    let weights =
+        ndarray::Array2::from_shape_fn((n_features, n_labels), |_| rng.gen_range(-1f32..1.));
+    let sparse_indices = {
+        let mut indices = (0..300)
+            .map(|_| rng.gen_range(0..n_features as u32))
+            .collect::<Vec<_>>();
+        indices.sort_unstable();
+        indices.dedup();
+        indices
+    };
+    let inputs = [
+        ("sparse_input", sparse_indices),
+        ("dense_input", (0..n_features as u32).collect()),
+    ];
+
+    let mut group = c.benchmark_group("dense_kernels");
+    for (input_name, indices) in inputs {
+        let values = indices.iter().map(|_| rng.gen_range(0.1..1.)).collect();
+        let vec = sprs::CsVecI::new(n_features, indices, values);
+        for (kernel_name, kernel) in [
+            ("columns", DenseKernel::Columns),
+            ("unrolled", DenseKernel::Unrolled),
+        ] {
+            let mut out = vec![0.; n_labels];
+            group.bench_function(format!("{}/{}", input_name, kernel_name), |b| {
+                b.iter(|| {
+                    out.iter_mut().for_each(|o| *o = 0.);
+                    dense_product_into(kernel, weights.view(), vec.view(), &mut out);
+                    black_box(&out);
+                })
+            });
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    bench_input_types,
+    bench_batch,
+    bench_aggregation,
+    bench_leaf_weights,
+    bench_dense_kernels
+);
+criterion_main!(benches);
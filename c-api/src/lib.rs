@@ -2,7 +2,7 @@ use itertools::Itertools;
 use libc::size_t;
 use omikuji::rayon;
 use std::convert::TryInto;
-use std::ffi::CStr;
+use std::ffi::{CStr, CString};
 use std::os::raw::{c_char, c_float, c_void};
 use std::slice;
 
@@ -177,6 +177,46 @@ pub unsafe extern "C" fn omikuji_n_trees(model_ptr: *const Model) -> size_t {
     (*model_ptr).n_trees()
 }
 
+/// Describe the input the model expects and the meaning of its scores, as a JSON object, or
+/// return null if it can't be described.
+///
+/// # Safety
+/// The model pointer must have been obtained by calling [load_omikuji_model()] or
+/// [train_omikuji_model()]. The caller is responsible for freeing the returned string by calling
+/// [free_omikuji_string()].
+///
+#[no_mangle]
+pub unsafe extern "C" fn omikuji_model_schema_json(model_ptr: *const Model) -> *mut c_char {
+    assert!(!model_ptr.is_null(), "Model should not be null");
+    let model_ptr = model_ptr as *const c_void as *const omikuji::Model;
+    let maybe_json = serde_json::to_string(&(*model_ptr).schema())
+        .map_err(|e| format!("Failed to serialize model schema: {}", e))
+        .and_then(|json| {
+            CString::new(json).map_err(|e| format!("Failed to convert model schema: {}", e))
+        });
+
+    match maybe_json {
+        Ok(json) => json.into_raw(),
+        Err(msg) => {
+            eprintln!("{}", msg);
+            std::ptr::null_mut()
+        }
+    }
+}
+
+/// Free a string returned by this library.
+///
+/// # Safety
+/// The pointer must have been returned by a function of this library documented to require
+/// freeing with this function, and must not be used afterwards.
+///
+#[no_mangle]
+pub unsafe extern "C" fn free_omikuji_string(ptr: *mut c_char) {
+    if !ptr.is_null() {
+        drop(CString::from_raw(ptr));
+    }
+}
+
 /// Make predictions with omikuji model.
 ///
 /// # Safety
@@ -301,7 +341,7 @@ impl From<omikuji::model::TrainHyperParam> for HyperParam {
             n_trees: hyper_param.n_trees,
             min_branch_size: hyper_param.min_branch_size,
             max_depth: hyper_param.max_depth,
-            centroid_threshold: hyper_param.centroid_threshold,
+            centroid_threshold: hyper_param.cluster.centroid_threshold,
             collapse_every_n_layers: hyper_param.collapse_every_n_layers,
             linear_loss_type: match hyper_param.linear.loss_type {
                 omikuji::model::liblinear::LossType::Hinge => LossType::Hinge,
@@ -329,10 +369,17 @@ impl TryInto<omikuji::model::TrainHyperParam> for HyperParam {
             n_trees: self.n_trees,
             min_branch_size: self.min_branch_size,
             max_depth: self.max_depth,
-            centroid_threshold: self.centroid_threshold,
             collapse_every_n_layers: self.collapse_every_n_layers,
             tree_structure_only: self.tree_structure_only,
             train_trees_1_by_1: self.train_trees_1_by_1,
+            ensemble_mode: omikuji::model::train::EnsembleMode::Independent,
+            memory_budget_bytes: None,
+            node_failure_policy: omikuji::model::train::NodeFailurePolicy::Abort,
+            feature_projection: None,
+            time_budget: None,
+            record_objective: false,
+            weight_storage: omikuji::model::train::WeightStorage::MemoryOptimal,
+            tree_reuse_policy: omikuji::model::label_tree::TreeReusePolicy::Adapt,
             linear: omikuji::model::liblinear::HyperParam {
                 loss_type: match self.linear_loss_type {
                     LossType::Hinge => omikuji::model::liblinear::LossType::Hinge,
@@ -348,6 +395,7 @@ impl TryInto<omikuji::model::TrainHyperParam> for HyperParam {
                 balanced: self.cluster_balanced,
                 eps: self.cluster_eps,
                 min_size: self.cluster_min_size,
+                centroid_threshold: self.centroid_threshold,
             },
         };
 
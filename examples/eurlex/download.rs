@@ -0,0 +1,78 @@
+//! Fetching data files into a local cache, verified by their SHA-256 checksums.
+//!
+//! Files are downloaded with `curl` to avoid pulling an HTTP client into the dependencies of the
+//! crate. A file is only moved into the cache once its checksum matches, so an interrupted or
+//! corrupted download is never mistaken for a cached copy.
+use sha2::{Digest, Sha256};
+use std::fmt::Write;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// A file to fetch, with the expected SHA-256 checksum of its content as lowercase hex.
+pub struct RemoteFile {
+    pub name: &'static str,
+    pub sha256: &'static str,
+}
+
+/// Find a verified copy of the file in one of the given directories, or download it from
+/// `base_url` into the first of them.
+pub fn fetch(file: &RemoteFile, base_url: &str, dirs: &[&Path]) -> io::Result<PathBuf> {
+    for dir in dirs {
+        let path = dir.join(file.name);
+        if path.is_file() {
+            if sha256_file(&path)? == file.sha256 {
+                return Ok(path);
+            }
+            eprintln!("Ignoring {} with unexpected checksum", path.display());
+        }
+    }
+
+    let cache_dir = dirs
+        .first()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "No cache directory"))?;
+    fs::create_dir_all(cache_dir)?;
+    let path = cache_dir.join(file.name);
+    let partial_path = cache_dir.join(format!("{}.part", file.name));
+    let url = format!("{}/{}", base_url.trim_end_matches('/'), file.name);
+    eprintln!("Downloading {} to {}", url, path.display());
+    let status = Command::new("curl")
+        .args(["--fail", "--location", "--silent", "--show-error"])
+        .args(["--retry", "3", "--output"])
+        .arg(&partial_path)
+        .arg(&url)
+        .status()?;
+    if !status.success() {
+        let _ = fs::remove_file(&partial_path);
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            format!("Failed to download {}: curl exited with {}", url, status),
+        ));
+    }
+
+    let checksum = sha256_file(&partial_path)?;
+    if checksum != file.sha256 {
+        fs::remove_file(&partial_path)?;
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "Checksum mismatch for {}: expected {}, but got {}",
+                url, file.sha256, checksum
+            ),
+        ));
+    }
+    fs::rename(&partial_path, &path)?;
+    Ok(path)
+}
+
+/// Compute the SHA-256 checksum of a file as lowercase hex.
+pub fn sha256_file(path: &Path) -> io::Result<String> {
+    let mut hasher = Sha256::new();
+    io::copy(&mut fs::File::open(path)?, &mut hasher)?;
+    let mut hex = String::with_capacity(64);
+    for byte in hasher.finalize() {
+        write!(hex, "{:02x}", byte).unwrap();
+    }
+    Ok(hex)
+}
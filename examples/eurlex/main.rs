@@ -0,0 +1,161 @@
+//! Train on EUR-Lex-4K with the default hyper-parameters, and check that precision and nDCG at
+//! 1, 3, and 5 are within a tolerance band of the reference numbers.
+//!
+//! Run with `cargo run --release --example eurlex`, or as an ignored test with
+//! `cargo test --release --example eurlex -- --ignored`. The data files bundled in `examples/`
+//! are used if present; otherwise they're downloaded into `target/eurlex/` from
+//! `OMIKUJI_EURLEX_URL`, which defaults to this repository. Either way, their checksums are
+//! verified first.
+//!
+//! Since this exercises training and prediction end to end, it's the regression test to run for
+//! any change that could affect accuracy.
+mod download;
+
+use download::RemoteFile;
+use omikuji::model::{eval, TrainHyperParam};
+use omikuji::DataSet;
+use std::path::Path;
+
+const DEFAULT_BASE_URL: &str = "https://raw.githubusercontent.com/tomtung/omikuji/master/examples";
+
+const TRAIN_FILE: RemoteFile = RemoteFile {
+    name: "eurlex_train.txt",
+    sha256: "6056e0145c7c8e532a320f1577af27546b6971c5507dfff81cb40312b189f007",
+};
+
+const TEST_FILE: RemoteFile = RemoteFile {
+    name: "eurlex_test.txt",
+    sha256: "808e4cd19372d6e4c1a1bd21fd4f19a80c7f95561f16848c5736492858166105",
+};
+
+/// The values of k that metrics are checked at.
+const KS: [usize; 3] = [1, 3, 5];
+
+/// Beam size for prediction, the same as the CLI's default.
+const BEAM_SIZE: usize = 10;
+
+/// Reference precision@k in percent, as reported in the README for the default balanced 2-means
+/// clustering.
+const REFERENCE_PRECISIONS: [f32; 3] = [82.1, 68.8, 57.7];
+
+/// Reference nDCG@k in percent. The README doesn't report nDCG, so these are the numbers of
+/// Parabel in the Extreme Classification Repository, whose precisions the README shows to be on
+/// par; nDCG@1 equals precision@1 by definition.
+const REFERENCE_NDCGS: [f32; 3] = [82.25, 72.17, 66.54];
+
+/// Allowed deviation from the reference numbers in percentage points, which leaves room for the
+/// randomness of clustering.
+const TOLERANCE: f32 = 1.5;
+
+/// Metrics at each k in [`KS`], in percent.
+#[derive(Debug)]
+struct Metrics {
+    precisions: [f32; 3],
+    ndcgs: [f32; 3],
+}
+
+impl Metrics {
+    /// Take the metrics at each k in [`KS`] from the results of evaluation.
+    fn from_results(results: &eval::EvaluationResults) -> Self {
+        let at_ks = |values: &[f32]| KS.map(|k| values[k - 1] * 100.);
+        Self {
+            precisions: at_ks(&results.metrics.precisions),
+            ndcgs: at_ks(&results.metrics.ndcgs),
+        }
+    }
+
+    /// Returns a message for each metric outside of the tolerance band of its reference.
+    fn check(&self) -> Vec<String> {
+        let precisions = self.precisions.iter().zip(&REFERENCE_PRECISIONS);
+        let ndcgs = self.ndcgs.iter().zip(&REFERENCE_NDCGS);
+        KS.iter()
+            .map(|k| format!("P@{}", k))
+            .zip(precisions)
+            .chain(KS.iter().map(|k| format!("nDCG@{}", k)).zip(ndcgs))
+            .filter(|&(_, (&actual, &expected))| !is_within_tolerance(actual, expected))
+            .map(|(name, (actual, expected))| {
+                format!(
+                    "{} = {:.2}, but expected {:.2} ± {:.2}",
+                    name, actual, expected, TOLERANCE
+                )
+            })
+            .collect()
+    }
+}
+
+/// Check if a metric is within the tolerance band of its reference, which NaN never is.
+fn is_within_tolerance(actual: f32, expected: f32) -> bool {
+    (actual - expected).abs() <= TOLERANCE
+}
+
+fn load_dataset(file: &RemoteFile) -> DataSet {
+    let base_url =
+        std::env::var("OMIKUJI_EURLEX_URL").unwrap_or_else(|_| DEFAULT_BASE_URL.to_owned());
+    let manifest_dir = Path::new(env!("CARGO_MANIFEST_DIR"));
+    let cache_dir = manifest_dir.join("target").join("eurlex");
+    let bundled_dir = manifest_dir.join("examples");
+    let path = download::fetch(
+        file,
+        &base_url,
+        &[cache_dir.as_path(), bundled_dir.as_path()],
+    )
+    .unwrap_or_else(|e| panic!("Failed to fetch {}: {}", file.name, e));
+    DataSet::load_xc_repo_data_file(&path)
+        .unwrap_or_else(|e| panic!("Failed to load {}: {}", path.display(), e))
+}
+
+/// Train and evaluate, returning the metrics on the test set.
+fn run() -> Metrics {
+    let train_dataset = load_dataset(&TRAIN_FILE);
+    let test_dataset = load_dataset(&TEST_FILE);
+
+    let hyper_param = TrainHyperParam::default();
+    println!("Training with {:?}", hyper_param);
+    let model = hyper_param.train(train_dataset);
+
+    let (_, results) = eval::test_all(&model, &test_dataset, BEAM_SIZE);
+    Metrics::from_results(&results)
+}
+
+fn main() {
+    let metrics = run();
+    println!(
+        "P@[1, 3, 5] = {:.2?}, nDCG@[1, 3, 5] = {:.2?}",
+        metrics.precisions, metrics.ndcgs
+    );
+    let failures = metrics.check();
+    for failure in &failures {
+        eprintln!("{}", failure);
+    }
+    if !failures.is_empty() {
+        std::process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check() {
+        let metrics = Metrics {
+            precisions: REFERENCE_PRECISIONS,
+            ndcgs: REFERENCE_NDCGS,
+        };
+        assert!(metrics.check().is_empty());
+
+        let metrics = Metrics {
+            precisions: [82.1, f32::NAN, 57.7],
+            ndcgs: [82.25, 72.17, 60.],
+        };
+        assert_eq!(2, metrics.check().len());
+    }
+
+    #[test]
+    #[ignore]
+    fn test_reference_metrics() {
+        let metrics = run();
+        let failures = metrics.check();
+        assert!(failures.is_empty(), "{:?}: {:?}", metrics, failures);
+    }
+}
@@ -3,6 +3,7 @@ __all__ = ["Model", "LossType"]
 
 from ._libomikuji import lib, ffi
 from enum import Enum
+import json
 import os
 from typing import Optional
 
@@ -79,6 +80,18 @@ class Model:
         """The number of trees in the forest model."""
         return lib.omikuji_n_trees(self._model_ptr)
 
+    @property
+    def schema(self):
+        """A dict describing the input the model expects and the meaning of its scores."""
+        assert self._model_ptr != ffi.NULL
+        json_ptr = lib.omikuji_model_schema_json(self._model_ptr)
+        if json_ptr == ffi.NULL:
+            raise RuntimeError("Failed to describe model schema")
+        try:
+            return json.loads(ffi.string(json_ptr).decode())
+        finally:
+            lib.free_omikuji_string(json_ptr)
+
     def save(self, path):
         """Save Omikuji model to the given directory."""
         assert self._model_ptr != ffi.NULL
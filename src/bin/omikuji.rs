@@ -1,10 +1,22 @@
 use clap::{Args, Parser, Subcommand, ValueEnum};
 use const_default::ConstDefault;
+use omikuji::model::label_tree::TreeReusePolicy;
 use omikuji::model::liblinear::LossType;
-use omikuji::model::TrainHyperParam;
+use omikuji::model::projection::ProjectionParams;
+use omikuji::model::train::{NodeFailurePolicy, NormalizationPolicy, TrainError, WeightStorage};
+use omikuji::model::{TrainHyperParam, TrainOptions};
+use omikuji::FloatFormat;
 use std::fs::File;
-use std::io::{BufWriter, Write};
-use std::path::PathBuf;
+use std::io::{BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+// Count allocations so that `omikuji bench` can report them; this slows down every allocation, so
+// it's only done when built for it
+#[cfg(feature = "count-allocations")]
+#[global_allocator]
+static ALLOCATOR: omikuji::model::bench::CountingAllocator =
+    omikuji::model::bench::CountingAllocator;
 
 #[derive(Parser)]
 #[command(version, about, long_about = None)]
@@ -20,6 +32,22 @@ enum Commands {
 
     /// Test an existing omikuji model
     Test(TestArgs),
+
+    /// Measure prediction latency of an existing omikuji model
+    Bench(BenchArgs),
+
+    /// Export or verify expected predictions of an existing omikuji model
+    #[command(subcommand)]
+    Conformance(ConformanceCommands),
+}
+
+#[derive(Subcommand)]
+enum ConformanceCommands {
+    /// Write the predictions of a model for examples of a dataset as a conformance suite
+    Export(ConformanceExportArgs),
+
+    /// Check that a model reproduces the predictions in a conformance suite
+    Verify(ConformanceVerifyArgs),
 }
 
 #[derive(Args)]
@@ -39,6 +67,10 @@ struct TrainArgs {
     #[arg(long)]
     model_path: Option<PathBuf>,
 
+    /// Optional path of a file with the name of each label, one per line, to save with the model
+    #[arg(long)]
+    label_names_path: Option<PathBuf>,
+
     /// Number of worker threads
     ///
     /// If 0, the number is selected automatically.
@@ -58,7 +90,7 @@ struct TrainArgs {
     max_depth: usize,
 
     /// Threshold for pruning label centroid vectors
-    #[arg(long, value_name = "THRESHOLD", default_value_t = TrainHyperParam::DEFAULT.centroid_threshold)]
+    #[arg(long, value_name = "THRESHOLD", default_value_t = TrainHyperParam::DEFAULT.cluster.centroid_threshold)]
     centroid_threshold: f32,
 
     /// Number of adjacent layers to collapse
@@ -79,6 +111,80 @@ struct TrainArgs {
     #[arg(long)]
     train_trees_1_by_1: bool,
 
+    /// Upper bound on the memory accounted for during training, in bytes
+    ///
+    /// Nodes are trained one after another when needed to stay within the budget, and training
+    /// fails if that is not enough.
+    #[arg(long, value_name = "BYTES")]
+    memory_budget_bytes: Option<usize>,
+
+    /// What to do when the classifier of a node fails to train
+    #[arg(value_enum, long, value_name = "POLICY", default_value_t = TrainHyperParam::DEFAULT.node_failure_policy.into())]
+    node_failure_policy: CliNodeFailurePolicy,
+
+    /// Dimension to randomly project feature vectors to before training, if provided
+    ///
+    /// The projection is saved with the model and applied to test data as well.
+    #[arg(long, value_name = "DIM")]
+    projection_dim: Option<usize>,
+
+    /// Number of projected dimensions each input feature contributes to
+    #[arg(long, value_name = "NNZ", default_value_t = 4)]
+    projection_nnz_per_feature: usize,
+
+    /// Seed for generating the random projection
+    #[arg(long, value_name = "SEED", default_value_t = 0)]
+    projection_seed: u64,
+
+    /// Directory to write finished trees to while later trees are trained, if provided
+    ///
+    /// This saves memory when training multiple trees, which are then saved into the model path
+    /// one at a time, without the whole model being held in memory.
+    #[arg(long, value_name = "DIR")]
+    spill_dir: Option<PathBuf>,
+
+    /// Wall-clock time in seconds that training should finish within, if provided
+    ///
+    /// When time runs out, remaining trees are skipped and the tree being trained is finished
+    /// quickly with shallower branches and approximate classifiers.
+    #[arg(long, value_name = "SECS")]
+    time_budget_secs: Option<u64>,
+
+    /// Path of a CSV file to write solver objective curves to, if provided
+    ///
+    /// The curves are recorded for a sample of classifiers at each depth of the trees, for
+    /// checking that the solver converges.
+    #[arg(long, value_name = "PATH")]
+    objective_curves_path: Option<PathBuf>,
+
+    /// Seconds to spend timing sparse and dense weight storage on this machine, if provided
+    ///
+    /// Weight matrices denser than the density where dense storage becomes faster are then stored
+    /// densely, which takes more memory but makes prediction faster.
+    #[arg(long, value_name = "SECS")]
+    calibrate_weight_storage_secs: Option<f64>,
+
+    /// Path of a trained model to reuse the label tree of instead of clustering labels, if provided
+    ///
+    /// Only classifiers are trained, so labels stay in the same leaves as in that model. New
+    /// labels are attached to the leaf with the nearest centroid, and labels without examples are
+    /// dropped.
+    #[arg(long, value_name = "MODEL_PATH")]
+    reuse_label_tree_from: Option<PathBuf>,
+
+    /// Fail instead of adapting the reused label tree if the labels differ from its labels
+    #[arg(long)]
+    strict_tree_reuse: bool,
+
+    /// Fail if the l2 norm of a feature vector differs from 1 by more than this tolerance, instead
+    /// of normalizing the feature vectors
+    #[arg(long, value_name = "TOLERANCE")]
+    require_normalized: Option<f32>,
+
+    /// Use feature vectors as given, without normalizing them, both in training and prediction
+    #[arg(long, conflicts_with = "require_normalized")]
+    no_normalization: bool,
+
     /// Loss function used by linear classifiers
     #[arg(value_enum, long = "linear.loss", value_name = "LOSS", default_value_t = TrainHyperParam::DEFAULT.linear.loss_type.into())]
     linear_loss: CliLossType,
@@ -115,6 +221,16 @@ struct TrainArgs {
     /// clusters instead
     #[arg(long = "cluster.min_size", value_name = "MIN_SIZE", default_value_t = TrainHyperParam::DEFAULT.cluster.min_size)]
     cluster_min_size: usize,
+
+    /// Comma-separated labels to leave out of clustering, e.g., catch-all labels
+    ///
+    /// These labels are attached to the root of each tree as a flat leaf instead.
+    #[arg(
+        long = "cluster.exclude_labels",
+        value_name = "LABELS",
+        value_delimiter = ','
+    )]
+    cluster_exclude_labels: Vec<omikuji::Index>,
 }
 
 #[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
@@ -141,16 +257,63 @@ impl From<CliLossType> for LossType {
     }
 }
 
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+enum CliNodeFailurePolicy {
+    Abort,
+    Retry,
+    CentroidFallback,
+}
+
+impl From<NodeFailurePolicy> for CliNodeFailurePolicy {
+    fn from(policy: NodeFailurePolicy) -> Self {
+        match policy {
+            NodeFailurePolicy::Abort => Self::Abort,
+            NodeFailurePolicy::Retry => Self::Retry,
+            NodeFailurePolicy::CentroidFallback => Self::CentroidFallback,
+        }
+    }
+}
+
+impl From<CliNodeFailurePolicy> for NodeFailurePolicy {
+    fn from(policy: CliNodeFailurePolicy) -> Self {
+        match policy {
+            CliNodeFailurePolicy::Abort => NodeFailurePolicy::Abort,
+            CliNodeFailurePolicy::Retry => NodeFailurePolicy::Retry,
+            CliNodeFailurePolicy::CentroidFallback => NodeFailurePolicy::CentroidFallback,
+        }
+    }
+}
+
 impl From<&TrainArgs> for TrainHyperParam {
     fn from(args: &TrainArgs) -> Self {
         omikuji::model::train::HyperParam {
             n_trees: args.n_trees,
             min_branch_size: args.min_branch_size,
             max_depth: args.max_depth,
-            centroid_threshold: args.centroid_threshold,
             collapse_every_n_layers: args.collapse_every_n_layers,
             tree_structure_only: args.tree_structure_only,
             train_trees_1_by_1: args.train_trees_1_by_1,
+            ensemble_mode: TrainHyperParam::DEFAULT.ensemble_mode,
+            memory_budget_bytes: args.memory_budget_bytes,
+            node_failure_policy: args.node_failure_policy.into(),
+            feature_projection: args.projection_dim.map(|n_components| ProjectionParams {
+                n_components,
+                nnz_per_feature: args.projection_nnz_per_feature,
+                seed: args.projection_seed,
+            }),
+            time_budget: args.time_budget_secs.map(Duration::from_secs),
+            record_objective: args.objective_curves_path.is_some(),
+            weight_storage: match args.calibrate_weight_storage_secs {
+                Some(secs) => WeightStorage::SpeedOptimalCalibrated {
+                    time_budget: Duration::from_secs_f64(secs),
+                },
+                None => WeightStorage::MemoryOptimal,
+            },
+            tree_reuse_policy: if args.strict_tree_reuse {
+                TreeReusePolicy::Strict
+            } else {
+                TreeReusePolicy::Adapt
+            },
             linear: omikuji::model::liblinear::HyperParam {
                 loss_type: args.linear_loss.into(),
                 eps: args.linear_eps,
@@ -163,6 +326,7 @@ impl From<&TrainArgs> for TrainHyperParam {
                 balanced: !args.cluster_unbalanced,
                 eps: args.cluster_eps,
                 min_size: args.cluster_min_size,
+                centroid_threshold: args.centroid_threshold,
             },
         }
     }
@@ -198,6 +362,18 @@ struct TestArgs {
     #[arg(long, default_value_t = 10)]
     beam_size: usize,
 
+    /// Comma-separated, increasing beam sizes to evaluate with a single search instead
+    ///
+    /// Results are the same as those of testing with each beam size separately, and are written
+    /// as a JSON array in the same order. Predictions can't be written out with this option.
+    #[arg(
+        long,
+        value_delimiter = ',',
+        value_parser = parse_positive,
+        conflicts_with_all = ["beam_size", "chunk_size", "out_path"]
+    )]
+    beam_sizes: Vec<usize>,
+
     /// Number of top predictions to write out for each test example
     #[arg(long, value_name = "K", default_value_t = 5)]
     k_top: usize,
@@ -205,6 +381,137 @@ struct TestArgs {
     /// Path to the which predictions will be written, if provided
     #[arg(long)]
     out_path: Option<PathBuf>,
+
+    /// Path to which evaluation results will be written as JSON, if provided
+    #[arg(long)]
+    results_path: Option<PathBuf>,
+
+    /// Stream the test data this many examples at a time instead of loading it into memory
+    ///
+    /// Predictions are not kept, so they can't be written out with this option.
+    #[arg(
+        long,
+        value_name = "N_EXAMPLES",
+        value_parser = parse_positive,
+        conflicts_with = "out_path"
+    )]
+    chunk_size: Option<usize>,
+
+    /// Format of written scores: "shortest", "significant:<DIGITS>", or "fixed:<DECIMALS>"
+    ///
+    /// The default writes the shortest form that parses back to the exact score.
+    #[arg(long, value_name = "FORMAT", default_value_t = FloatFormat::Shortest)]
+    float_format: FloatFormat,
+}
+
+#[derive(Args)]
+#[command(rename_all = "snake_case")]
+struct BenchArgs {
+    /// Path of the directory where the trained model is saved
+    #[arg(required = true)]
+    model_path: PathBuf,
+
+    /// Path to the dataset file that examples are sampled from
+    ///
+    /// The dataset file is expected to be in the format of the Extreme Classification
+    /// Repository.
+    #[arg(long, required = true)]
+    test_file: PathBuf,
+
+    /// Density threshold above which sparse weight vectors are converted to dense format
+    #[arg(long, value_name = "DENSITY", default_value_t = 0.1)]
+    max_sparse_density: f32,
+
+    /// Beam size for beam search
+    #[arg(long, default_value_t = 10)]
+    beam: usize,
+
+    /// How long to measure for each number of threads, e.g., "30s" or "500ms"
+    #[arg(long, value_parser = parse_duration, default_value = "10s")]
+    duration: Duration,
+
+    /// How long to predict before measuring for each number of threads
+    #[arg(long, value_parser = parse_duration, default_value = "1s")]
+    warmup: Duration,
+
+    /// Comma-separated numbers of threads predicting concurrently, each measured separately
+    #[arg(long, value_delimiter = ',', default_value = "1")]
+    threads: Vec<usize>,
+
+    /// Maximum number of examples sampled from the dataset
+    #[arg(long, default_value_t = 10_000)]
+    n_samples: usize,
+
+    /// Also predict for the examples once as a batch, and print where beam search spends its
+    /// time by depth of each tree
+    #[arg(long)]
+    profile: bool,
+
+    /// Format of throughputs and latencies: "shortest", "significant:<DIGITS>", or
+    /// "fixed:<DECIMALS>"
+    #[arg(long, value_name = "FORMAT", default_value_t = FloatFormat::TABLE)]
+    float_format: FloatFormat,
+}
+
+#[derive(Args)]
+#[command(rename_all = "snake_case")]
+struct ConformanceExportArgs {
+    /// Path of the directory where the trained model is saved
+    #[arg(required = true)]
+    model_path: PathBuf,
+
+    /// Path to the dataset file whose examples are used as inputs
+    ///
+    /// The dataset file is expected to be in the format of the Extreme Classification
+    /// Repository.
+    #[arg(required = true)]
+    test_data_path: PathBuf,
+
+    /// Path to which the conformance suite will be written
+    #[arg(required = true)]
+    out_path: PathBuf,
+
+    /// Comma-separated beam sizes to predict with
+    #[arg(long, value_delimiter = ',', default_value = "1,10")]
+    beams: Vec<usize>,
+
+    /// Maximum number of examples used as inputs, taken from the start of the dataset
+    #[arg(long, default_value_t = 100)]
+    n_examples: usize,
+}
+
+#[derive(Args)]
+#[command(rename_all = "snake_case")]
+struct ConformanceVerifyArgs {
+    /// Path of the directory where the trained model is saved
+    #[arg(required = true)]
+    model_path: PathBuf,
+
+    /// Path of the conformance suite to verify against
+    #[arg(required = true)]
+    suite_path: PathBuf,
+}
+
+/// Parse a duration given in seconds or milliseconds, e.g., "30s", "500ms" or "1.5".
+fn parse_duration(s: &str) -> Result<Duration, String> {
+    let (number, scale) = if let Some(ms) = s.strip_suffix("ms") {
+        (ms, 1e-3)
+    } else {
+        (s.strip_suffix('s').unwrap_or(s), 1.)
+    };
+    number
+        .parse::<f64>()
+        .ok()
+        .filter(|&v| v.is_finite() && v >= 0.)
+        .map(|v| Duration::from_secs_f64(v * scale))
+        .ok_or_else(|| format!("Invalid duration: {}", s))
+}
+
+fn parse_positive(s: &str) -> Result<usize, String> {
+    s.parse::<usize>()
+        .ok()
+        .filter(|&n| n > 0)
+        .ok_or_else(|| format!("Expected a positive integer, got {}", s))
 }
 
 fn set_num_threads(num_threads: usize) {
@@ -218,15 +525,75 @@ fn set_num_threads(num_threads: usize) {
 fn train(args: &TrainArgs) {
     set_num_threads(args.n_threads);
     let train_hyperparam: TrainHyperParam = args.into();
+    let mut train_options = TrainOptions {
+        cluster_exclude_labels: args.cluster_exclude_labels.clone(),
+        spill_dir: args.spill_dir.clone(),
+        normalization_policy: match (args.require_normalized, args.no_normalization) {
+            (Some(tolerance), _) => NormalizationPolicy::RequireNormalized { tolerance },
+            (None, true) => NormalizationPolicy::None,
+            (None, false) => NormalizationPolicy::Normalize,
+        },
+        ..TrainOptions::default()
+    };
+    if let Some(model_path) = args.reuse_label_tree_from.as_ref() {
+        let model = omikuji::Model::load(model_path).expect("Failed to load model to reuse");
+        train_options.label_tree = Some(model.export_label_tree());
+    }
+    if let Some(label_names_path) = args.label_names_path.as_ref() {
+        let label_names = std::fs::read_to_string(label_names_path)
+            .expect("Failed to read label names")
+            .lines()
+            .map(str::to_owned)
+            .collect();
+        train_options.label_names = Some(label_names);
+    }
 
+    let warnings = omikuji::Warnings::new();
     let training_dataset = {
-        omikuji::DataSet::load_xc_repo_data_file(args.training_data_path.as_path())
-            .expect("Failed to load training data")
+        omikuji::DataSet::load_xc_repo_data_file_with_warnings(
+            args.training_data_path.as_path(),
+            &warnings,
+        )
+        .expect("Failed to load training data")
+    };
+
+    // With a spill directory, trees are saved from it one at a time instead of being read back
+    // into memory all at once
+    let on_error = |error| training_failed(error, &args.training_data_path);
+    let training_metadata = match (args.model_path.as_ref(), train_options.spill_dir.is_some()) {
+        (Some(model_path), true) => train_hyperparam
+            .train_to_dir(training_dataset, model_path, &train_options, &warnings)
+            .unwrap_or_else(on_error),
+        (model_path, _) => {
+            let model = train_hyperparam
+                .try_train_with_options(training_dataset, &train_options, &warnings)
+                .unwrap_or_else(on_error);
+            if let Some(model_path) = model_path {
+                model.save(model_path).expect("Failed to save model");
+            }
+            model.training_metadata().clone()
+        }
     };
+    if let Some(objective_curves_path) = args.objective_curves_path.as_ref() {
+        let file = File::create(objective_curves_path).expect("Failed to create objective curves");
+        training_metadata
+            .write_objective_curves_csv(BufWriter::new(file), FloatFormat::Shortest)
+            .expect("Failed to write objective curves");
+    }
+    print_warnings(warnings);
+}
 
-    let model = train_hyperparam.train(training_dataset);
-    if let Some(model_path) = args.model_path.as_ref() {
-        model.save(model_path).expect("Failed to save model");
+/// Panic with a training error, pointing unnormalized examples to their lines in the data file.
+fn training_failed(error: TrainError, data_path: &Path) -> ! {
+    match error {
+        TrainError::NotNormalized { example, norm } => panic!(
+            "Training failed: Feature vector on line {} of {} has l2 norm {}, but should be \
+             normalized",
+            example + 2, // 1-based, after the header line
+            data_path.display(),
+            norm
+        ),
+        error => panic!("Training failed: {}", error),
     }
 }
 
@@ -240,11 +607,76 @@ fn test(args: &TestArgs) {
         model
     };
 
-    let test_dataset = omikuji::DataSet::load_xc_repo_data_file(args.test_data_path.as_path())
+    let warnings = omikuji::Warnings::new();
+    if !args.beam_sizes.is_empty() {
+        if let Err(message) = omikuji::model::multi_beam::check_beam_sizes(&args.beam_sizes) {
+            use clap::CommandFactory;
+            Cli::command()
+                .error(clap::error::ErrorKind::ValueValidation, message)
+                .exit();
+        }
+        let test_dataset = omikuji::DataSet::load_xc_repo_data_file_with_warnings(
+            args.test_data_path.as_path(),
+            &warnings,
+        )
         .expect("Failed to load test data");
+        let results = omikuji::model::eval::test_all_multi_beam(
+            &model,
+            &test_dataset,
+            &args.beam_sizes,
+            &warnings,
+        )
+        .expect("Beam sizes were checked");
+        if let Some(results_path) = args.results_path.as_ref() {
+            let file = File::create(results_path).expect("Failed to create results file");
+            omikuji::model::eval::write_results_json(&results, BufWriter::new(file))
+                .expect("Failed to write evaluation results");
+        }
+        print_warnings(warnings);
+        return;
+    }
 
-    let (predictions, _) =
-        { omikuji::model::eval::test_all(&model, &test_dataset, args.beam_size) };
+    let (predictions, results) = match args.chunk_size {
+        Some(chunk_size) => {
+            let file = File::open(args.test_data_path.as_path()).expect("Failed to open test data");
+            let results = omikuji::model::eval::test_streaming(
+                &model,
+                BufReader::new(file),
+                args.beam_size,
+                chunk_size,
+                |progress| {
+                    log::info!(
+                        "Evaluated {} of {} examples; Precision@1 so far = {:.2}",
+                        progress.n_examples,
+                        progress.n_total_examples,
+                        progress.precisions[0] * 100.
+                    )
+                },
+                &warnings,
+            )
+            .expect("Failed to evaluate on test data");
+            (Vec::new(), results)
+        }
+        None => {
+            let test_dataset = omikuji::DataSet::load_xc_repo_data_file_with_warnings(
+                args.test_data_path.as_path(),
+                &warnings,
+            )
+            .expect("Failed to load test data");
+            omikuji::model::eval::test_all_with_warnings(
+                &model,
+                &test_dataset,
+                args.beam_size,
+                &warnings,
+            )
+        }
+    };
+    if let Some(results_path) = args.results_path.as_ref() {
+        let file = File::create(results_path).expect("Failed to create results file");
+        results
+            .write_json(BufWriter::new(file))
+            .expect("Failed to write evaluation results");
+    }
     if let Some(out_path) = args.out_path.as_ref() {
         let mut writer =
             BufWriter::new(File::create(out_path).expect("Failed to create output file"));
@@ -253,11 +685,101 @@ fn test(args: &TestArgs) {
                 if i > 0 {
                     write!(&mut writer, "\t").unwrap();
                 }
-                write!(&mut writer, "{} {:.3}", label, score).unwrap();
+                write!(
+                    &mut writer,
+                    "{} {}",
+                    label,
+                    args.float_format.display_f32(score)
+                )
+                .unwrap();
             }
             writeln!(&mut writer).unwrap();
         }
     }
+    print_warnings(warnings);
+}
+
+fn bench(args: &BenchArgs) {
+    let model = {
+        let mut model =
+            omikuji::Model::load(args.model_path.as_path()).expect("Failed to load model");
+        model.densify_weights(args.max_sparse_density);
+        model
+    };
+    let dataset = omikuji::DataSet::load_xc_repo_data_file(args.test_file.as_path())
+        .expect("Failed to load test data");
+
+    let config = omikuji::model::bench::BenchConfig {
+        beam_size: args.beam,
+        duration: args.duration,
+        warmup: args.warmup,
+        n_threads: args.threads.clone(),
+        n_samples: args.n_samples,
+        ..omikuji::model::bench::BenchConfig::default()
+    };
+    let results = omikuji::model::bench::run(&model, dataset.feature_lists(), &config);
+    print!(
+        "{}",
+        omikuji::model::bench::format_table(&results, args.float_format)
+    );
+
+    if args.profile {
+        let feature_vecs = &dataset.feature_lists()[..args.n_samples.min(dataset.len())];
+        let (_, profile) = model.predict_batch_profiled(feature_vecs, args.beam);
+        println!();
+        print!(
+            "{}",
+            omikuji::model::bench::format_profile_table(&profile, args.float_format)
+        );
+    }
+}
+
+fn conformance(command: &ConformanceCommands) {
+    match command {
+        ConformanceCommands::Export(args) => {
+            let model =
+                omikuji::Model::load(args.model_path.as_path()).expect("Failed to load model");
+            let dataset = omikuji::DataSet::load_xc_repo_data_file(args.test_data_path.as_path())
+                .expect("Failed to load test data");
+            let inputs = &dataset.feature_lists()[..args.n_examples.min(dataset.len())];
+            let writer =
+                BufWriter::new(File::create(&args.out_path).expect("Failed to create output file"));
+            model
+                .export_conformance_suite(inputs, &args.beams, writer)
+                .expect("Failed to write conformance suite");
+        }
+        ConformanceCommands::Verify(args) => {
+            let model =
+                omikuji::Model::load(args.model_path.as_path()).expect("Failed to load model");
+            let reader = std::io::BufReader::new(
+                File::open(&args.suite_path).expect("Failed to open conformance suite"),
+            );
+            let report = model
+                .verify_conformance(reader)
+                .expect("Failed to read conformance suite");
+            for mismatch in &report.mismatches {
+                eprintln!("{}", mismatch);
+            }
+            println!(
+                "{} of {} cases matched",
+                report.n_cases - report.mismatches.len(),
+                report.n_cases
+            );
+            if !report.is_success() {
+                std::process::exit(1);
+            }
+        }
+    }
+}
+
+fn print_warnings(warnings: omikuji::Warnings) {
+    let warnings = warnings.into_vec();
+    if !warnings.is_empty() {
+        eprintln!("{} warnings:", warnings.len());
+        for warning in warnings {
+            eprintln!("  - {}", warning);
+        }
+    }
 }
 
 fn main() {
@@ -266,6 +788,8 @@ fn main() {
     match &cli.command {
         Commands::Train(args) => train(args),
         Commands::Test(args) => test(args),
+        Commands::Bench(args) => bench(args),
+        Commands::Conformance(command) => conformance(command),
     }
 }
 
@@ -274,3 +798,19 @@ fn verify_cli() {
     use clap::CommandFactory;
     Cli::command().debug_assert();
 }
+
+#[test]
+fn test_parse_duration() {
+    assert_eq!(Ok(Duration::from_secs(30)), parse_duration("30s"));
+    assert_eq!(Ok(Duration::from_millis(500)), parse_duration("500ms"));
+    assert_eq!(Ok(Duration::from_millis(1500)), parse_duration("1.5"));
+    assert!(parse_duration("-1s").is_err());
+    assert!(parse_duration("soon").is_err());
+}
+
+#[test]
+fn test_parse_positive() {
+    assert_eq!(Ok(3), parse_positive("3"));
+    assert!(parse_positive("0").is_err());
+    assert!(parse_positive("-1").is_err());
+}
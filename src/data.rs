@@ -1,30 +1,146 @@
+use crate::index::{to_index, IndexKind};
 use crate::mat_util::*;
-use crate::{Index, IndexSet, IndexValueVec};
+use crate::util::create_progress_bar;
+use crate::{Index, IndexSet, IndexValueVec, Warning, Warnings};
+use hashbrown::HashMap;
 use itertools::Itertools;
 use log::info;
+use rand::prelude::*;
+use rand::rngs::StdRng;
 use rayon::prelude::*;
 use std::fs;
-use std::io::{Error, ErrorKind, Result};
+use std::io::{Error, ErrorKind, Read, Result};
+use std::path::Path;
+use std::sync::Mutex;
 use std::time;
 
+pub(crate) mod compression;
+mod labels;
+mod mmap;
+pub use compression::Compression;
+pub use labels::LabelMatrix;
+pub(crate) use mmap::MappedCsr;
+pub use mmap::MmapDataSet;
+
+/// The number of lines parsed in parallel at a time when loading data; the label lists parsed from
+/// each chunk are copied into the label matrix and freed before the next chunk is parsed.
+const PARSE_CHUNK_SIZE: usize = 16 * 1024;
+
 /// A training dataset loaded in memory.
 #[derive(Clone)]
 pub struct DataSet {
     pub(crate) n_features: usize,
     pub(crate) n_labels: usize,
     pub(crate) feature_lists: Vec<IndexValueVec>,
-    pub(crate) label_sets: Vec<IndexSet>,
+    pub(crate) labels: LabelMatrix,
 }
 
 impl DataSet {
+    /// The number of examples in the dataset.
+    pub fn len(&self) -> usize {
+        self.feature_lists.len()
+    }
+
+    /// Whether the dataset contains no examples.
+    pub fn is_empty(&self) -> bool {
+        self.feature_lists.is_empty()
+    }
+
+    /// The feature vectors of examples.
+    pub fn feature_lists(&self) -> &[IndexValueVec] {
+        &self.feature_lists
+    }
+
+    /// The label lists of examples, each sorted.
+    pub fn label_lists(&self) -> impl ExactSizeIterator<Item = &[Index]> + Clone + '_ {
+        self.labels.iter()
+    }
+
+    /// The labels of examples as a matrix with a row per example.
+    pub fn labels(&self) -> &LabelMatrix {
+        &self.labels
+    }
+
+    /// The labels of each example as a set.
+    #[deprecated(note = "use `label_lists` or `labels` instead, which don't copy the labels")]
+    pub fn label_sets(&self) -> Vec<IndexSet> {
+        self.labels.to_sets()
+    }
+
+    /// Create a new dataset from the examples at the given indices, in the given order.
+    pub fn take_examples(&self, indices: &[usize]) -> Self {
+        Self {
+            n_features: self.n_features,
+            n_labels: self.n_labels,
+            feature_lists: indices
+                .iter()
+                .map(|&i| self.feature_lists[i].clone())
+                .collect(),
+            labels: self.labels.take_rows(indices),
+        }
+    }
+
+    /// Randomly split the dataset into two, where the second part contains `fraction` of examples.
+    ///
+    /// The split is deterministic given the seed.
+    pub fn split(&self, fraction: f32, seed: u64) -> (Self, Self) {
+        assert!(
+            (0. ..=1.).contains(&fraction),
+            "fraction must be in [0, 1], but is {}",
+            fraction
+        );
+        let mut indices = (0..self.len()).collect_vec();
+        indices.shuffle(&mut StdRng::seed_from_u64(seed));
+
+        let n_second = ((self.len() as f32) * fraction).round() as usize;
+        let (second, first) = indices.split_at(n_second);
+        (self.take_examples(first), self.take_examples(second))
+    }
+
+    /// Randomly sample `fraction` of examples without replacement.
+    ///
+    /// The sample is deterministic given the seed.
+    pub fn subsample(&self, fraction: f32, seed: u64) -> Self {
+        self.split(fraction, seed).1
+    }
+
+    /// Compute the centroid feature vector of each label, as a matrix with a row per label.
+    ///
+    /// Each centroid is the sum of the feature vectors of the label's examples, l2-normalized,
+    /// with entries below the threshold pruned and the result normalized again. Rows of labels
+    /// without examples are empty. There is a row for every label up to the largest one in the
+    /// dataset, even if that exceeds the declared number of labels.
+    pub fn label_centroids(&self, threshold: f32) -> SparseMat {
+        let (labels, centroids) = compute_label_centroids(
+            |i| &self.feature_lists[i],
+            self.labels.iter(),
+            self.n_labels,
+            threshold,
+        );
+        let n_rows = labels
+            .last()
+            .map_or(0, |&label| label as usize + 1)
+            .max(self.n_labels);
+        let mut rows = vec![Vec::new(); n_rows];
+        for (label, centroid) in labels.into_iter().zip(centroids) {
+            rows[label as usize] = centroid;
+        }
+        csrmat_from_index_value_pair_lists(rows, self.n_features)
+    }
+
     /// Parse a line in a data file from the Extreme Classification Repository
     ///
     /// The line should be in the following format:
     /// label1,label2,...labelk ft1:ft1_val ft2:ft2_val ft3:ft3_val .. ftd:ftd_val
-    fn parse_xc_repo_data_line(line: &str, n_features: usize) -> Result<(IndexValueVec, IndexSet)> {
+    ///
+    /// Labels are returned sorted and without duplicates.
+    pub(crate) fn parse_xc_repo_data_line(
+        line: &str,
+        n_features: usize,
+    ) -> Result<(IndexValueVec, Vec<Index>)> {
         let mut token_iter = line.split(' ');
 
-        let mut labels = IndexSet::new();
+        let mut labels = Vec::new();
         {
             let labels_str = token_iter.next().ok_or_else(|| {
                 Error::new(
@@ -34,7 +150,7 @@ impl DataSet {
             })?;
             for label_str in labels_str.split(',') {
                 if !label_str.is_empty() {
-                    labels.insert(label_str.parse::<Index>().map_err(|_| {
+                    labels.push(label_str.parse::<Index>().map_err(|_| {
                         Error::new(
                             ErrorKind::InvalidData,
                             format!("Failed to parse label {} in line \"{}\"", label_str, line),
@@ -42,7 +158,8 @@ impl DataSet {
                     })?);
                 }
             }
-            labels.shrink_to_fit();
+            labels.sort_unstable();
+            labels.dedup();
         }
 
         let mut features = Vec::new();
@@ -88,44 +205,152 @@ impl DataSet {
     }
 
     /// Load a data file from the Extreme Classification Repository
-    pub fn load_xc_repo_data_file<P: AsRef<std::path::Path>>(path: P) -> Result<Self> {
+    ///
+    /// The file may be compressed with any codec enabled by features, see [`Compression`].
+    pub fn load_xc_repo_data_file<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::load_xc_repo_data_file_with_warnings(path, &Warnings::new())
+    }
+
+    /// Load a data file from the Extreme Classification Repository, collecting warnings about
+    /// suspicious examples.
+    ///
+    /// The file may be compressed with any codec enabled by features, see [`Compression`].
+    pub fn load_xc_repo_data_file_with_warnings<P: AsRef<Path>>(
+        path: P,
+        warnings: &Warnings,
+    ) -> Result<Self> {
         info!("Loading data from {}", path.as_ref().display());
+        Self::read_xc_repo_data_with_warnings(fs::File::open(path)?, warnings)
+    }
+
+    /// Load a data file like [`Self::load_xc_repo_data_file`], but also take the extension of the
+    /// file name as a hint of its compression.
+    ///
+    /// Compression is still detected from the content, but a file whose extension names a
+    /// compression format that its content isn't in is rejected, since it's likely truncated or
+    /// mislabeled, rather than parsed as text.
+    pub fn load_auto<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        info!("Loading data from {}", path.display());
+        let (reader, compression) = compression::decompress(fs::File::open(path)?)?;
+        match Compression::from_extension(path) {
+            Some(hint) if compression != Some(hint) => Err(Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "Expected {:?}-compressed data in {} from its extension, but found {}",
+                    hint,
+                    path.display(),
+                    match compression {
+                        Some(compression) => format!("{:?}-compressed data", compression),
+                        None => "uncompressed data".to_owned(),
+                    }
+                ),
+            )),
+            _ => Self::parse_xc_repo_data(reader, &Warnings::new()),
+        }
+    }
+
+    /// Read data in the format of the Extreme Classification Repository, collecting warnings
+    /// about suspicious examples.
+    ///
+    /// The data may be compressed with any codec enabled by features, see [`Compression`].
+    pub fn read_xc_repo_data_with_warnings<R: Read>(
+        reader: R,
+        warnings: &Warnings,
+    ) -> Result<Self> {
+        let (reader, _) = compression::decompress(reader)?;
+        Self::parse_xc_repo_data(reader, warnings)
+    }
+
+    /// Parse the header line of data in the format of the Extreme Classification Repository into
+    /// the numbers of examples, features, and labels.
+    pub(crate) fn parse_xc_repo_header(line: &str) -> Result<(usize, usize, usize)> {
+        let tokens = line.split_whitespace().collect_vec();
+        if tokens.len() != 3 {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "Expect header line with 3 space-separated tokens, found {} instead",
+                    tokens.len()
+                ),
+            ));
+        }
+
+        let n_examples = tokens[0].parse::<usize>().map_err(|_| {
+            Error::new(ErrorKind::InvalidData, "Failed to parse number of examples")
+        })?;
+        let n_features = tokens[1].parse::<usize>().map_err(|_| {
+            Error::new(ErrorKind::InvalidData, "Failed to parse number of features")
+        })?;
+        let n_labels = tokens[2]
+            .parse::<usize>()
+            .map_err(|_| Error::new(ErrorKind::InvalidData, "Failed to parse number of labels"))?;
+        crate::index::check_dimensions(n_examples, n_features, n_labels)
+            .map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+
+        Ok((n_examples, n_features, n_labels))
+    }
+
+    /// Collect warnings about a suspicious example parsed from the given 1-based line.
+    pub(crate) fn check_xc_repo_example(
+        line: usize,
+        features: &[(Index, f32)],
+        labels: &[Index],
+        n_labels: usize,
+        warnings: &Warnings,
+    ) {
+        if labels.is_empty() {
+            warnings.push(Warning::ExampleWithoutLabels { line });
+        }
+        if features.is_empty() {
+            warnings.push(Warning::ExampleWithoutFeatures { line });
+        }
+        for &label in labels {
+            if label as usize >= n_labels {
+                warnings.push(Warning::LabelOutOfRange {
+                    line,
+                    label,
+                    n_labels,
+                });
+            }
+        }
+    }
+
+    /// Parse uncompressed data in the format of the Extreme Classification Repository.
+    fn parse_xc_repo_data(mut reader: impl Read, warnings: &Warnings) -> Result<Self> {
         let start_t = time::Instant::now();
 
-        let file_content = fs::read_to_string(path)?;
+        let mut file_content = String::new();
+        reader.read_to_string(&mut file_content)?;
         info!("Parsing data");
         let lines: Vec<&str> = file_content.par_lines().collect();
-        let (n_examples, n_features, n_labels) = {
-            let tokens = lines[0].split_whitespace().collect_vec();
-            if tokens.len() != 3 {
-                return Err(Error::new(
-                    ErrorKind::InvalidData,
-                    format!(
-                        "Expect header line with 3 space-separated tokens, found {} instead",
-                        tokens.len()
-                    ),
-                ));
-            }
+        let (n_examples, n_features, n_labels) = Self::parse_xc_repo_header(lines[0])?;
 
-            let n_examples = tokens[0].parse::<usize>().map_err(|_| {
-                Error::new(ErrorKind::InvalidData, "Failed to parse number of examples")
-            })?;
-            let n_features = tokens[1].parse::<usize>().map_err(|_| {
-                Error::new(ErrorKind::InvalidData, "Failed to parse number of features")
-            })?;
-            let n_labels = tokens[2].parse::<usize>().map_err(|_| {
-                Error::new(ErrorKind::InvalidData, "Failed to parse number of labels")
-            })?;
+        let lines = &lines[1..];
 
-            (n_examples, n_features, n_labels)
-        };
-
-        let lines: Vec<_> = lines
-            .into_par_iter()
-            .skip(1)
-            .map(|line| Self::parse_xc_repo_data_line(line, n_features))
-            .collect::<Result<_>>()?;
-        let (feature_lists, label_sets): (Vec<_>, Vec<_>) = lines.into_iter().unzip();
+        // Count labels first, so that the label matrix is allocated once; duplicates are counted
+        // too, so this is an upper bound
+        let nnz = lines
+            .par_iter()
+            .map(|line| {
+                let labels_str = line.split(' ').next().unwrap_or("");
+                labels_str.split(',').filter(|s| !s.is_empty()).count()
+            })
+            .sum();
+        let mut feature_lists = Vec::with_capacity(lines.len());
+        let mut labels = LabelMatrix::with_capacity(lines.len(), nnz);
+        // Lines are parsed in chunks, so that only the separate label lists of a chunk are alive
+        // at once before they're copied into the matrix
+        for chunk in lines.chunks(PARSE_CHUNK_SIZE) {
+            let parsed = chunk
+                .par_iter()
+                .map(|line| Self::parse_xc_repo_data_line(line, n_features))
+                .collect::<Result<Vec<_>>>()?;
+            for (features, example_labels) in parsed {
+                feature_lists.push(features);
+                labels.push_row(example_labels);
+            }
+        }
 
         if n_examples != feature_lists.len() {
             return Err(Error::new(
@@ -138,6 +363,11 @@ impl DataSet {
             ));
         }
 
+        for (i, (features, labels)) in feature_lists.iter().zip(labels.iter()).enumerate() {
+            let line = i + 2; // 1-based, after the header line
+            Self::check_xc_repo_example(line, features, labels, n_labels, warnings);
+        }
+
         info!(
             "Loaded {} examples; it took {:.2}s",
             n_examples,
@@ -147,24 +377,269 @@ impl DataSet {
             n_features,
             n_labels,
             feature_lists,
-            label_sets,
+            labels,
         })
     }
 }
 
+/// Compute centroid feature vectors for labels from feature vectors and label lists of examples.
+///
+/// Examples of each label are found by transposing the example-to-label matrix, after which
+/// centroids are computed for labels in parallel. Only labels with examples are returned, in
+/// increasing order.
+pub(crate) fn compute_label_centroids<'a, Row, GetRow>(
+    get_row: GetRow,
+    label_lists: impl Iterator<Item = &'a [Index]>,
+    n_labels: usize,
+    threshold: f32,
+) -> (Vec<Index>, Vec<IndexValueVec>)
+where
+    Row: AsRef<[(Index, f32)]>,
+    GetRow: Fn(usize) -> Row + Sync,
+{
+    info!("Computing label centroids");
+    let label_lists = label_lists
+        .map(|labels| {
+            let mut labels = labels.iter().map(|&label| (label, 1f32)).collect_vec();
+            labels.sort_by_index();
+            labels
+        })
+        .collect_vec();
+    // Labels are not guaranteed to be within the declared range
+    let n_labels = label_lists
+        .iter()
+        .flatten()
+        .map(|&(label, _)| label as usize + 1)
+        .max()
+        .unwrap_or(0)
+        .max(n_labels);
+    let label_to_examples =
+        fast_transpose(&csrmat_from_index_value_pair_lists(label_lists, n_labels));
+
+    let pb = Mutex::new(create_progress_bar(n_labels as u64));
+    pb.lock()
+        .expect("Failed to lock progress bar")
+        .message("Labels ");
+    let (labels, centroids): (Vec<_>, Vec<_>) = (0..n_labels)
+        .into_par_iter()
+        .filter_map(|label| {
+            pb.lock().expect("Failed to lock progress bar").inc();
+            let examples = label_to_examples.outer_view(label).unwrap();
+            if examples.nnz() == 0 {
+                return None;
+            }
+
+            let mut feature_to_sum = HashMap::<Index, f32>::new();
+            for &example in examples.indices() {
+                for &(feature, value) in get_row(example as usize).as_ref() {
+                    *feature_to_sum.entry(feature).or_default() += value;
+                }
+            }
+
+            let mut v = feature_to_sum.into_iter().collect_vec();
+            v.l2_normalize();
+            if threshold > 0. {
+                // Clustering compares centroids by cosine similarity, so restore unit length
+                v.prune_with_threshold(threshold);
+                v.l2_normalize();
+            }
+            v.sort_by_index();
+            Some((to_index(label, IndexKind::Label), v))
+        })
+        .unzip();
+    (labels, centroids)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::iter::FromIterator;
+    use assert_approx_eq::assert_approx_eq;
 
     #[test]
     fn test_parse_xc_repo_data_line() {
+        let (features, labels) =
+            DataSet::parse_xc_repo_data_line("11,12 21:1 23:2 24:3", 25).unwrap();
         assert_eq!(
             (
                 vec![(21, 1.), (23, 2.), (24, 3.)],
                 IndexSet::from_iter(vec![11, 12]),
             ),
-            DataSet::parse_xc_repo_data_line("11,12 21:1 23:2 24:3", 25).unwrap()
+            (features, IndexSet::from_iter(labels))
         );
     }
+
+    #[test]
+    fn test_parse_xc_repo_data_line_sorts_labels() {
+        assert_eq!(
+            (vec![(21, 1.), (23, 2.), (24, 3.)], vec![11, 12]),
+            DataSet::parse_xc_repo_data_line("12,11,12 21:1 23:2 24:3", 25).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_load_with_warnings() {
+        let path =
+            std::env::temp_dir().join(format!("omikuji-warnings-{}.txt", std::process::id()));
+        fs::write(&path, "3 10 4\n0,1 1:1 2:1\n 3:1\n2,5\n").unwrap();
+        let warnings = Warnings::new();
+        let dataset = DataSet::load_xc_repo_data_file_with_warnings(&path, &warnings).unwrap();
+        assert_eq!(3, dataset.len());
+        assert_eq!(
+            vec![&[0, 1][..], &[][..], &[2, 5][..]],
+            dataset.label_lists().collect_vec()
+        );
+        assert_eq!(3, dataset.labels().nnz());
+        assert_eq!(
+            vec![
+                Warning::ExampleWithoutLabels { line: 3 },
+                Warning::ExampleWithoutFeatures { line: 4 },
+                Warning::LabelOutOfRange {
+                    line: 4,
+                    label: 5,
+                    n_labels: 4,
+                },
+            ],
+            warnings.into_vec()
+        );
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_load_with_too_many_features() {
+        let path =
+            std::env::temp_dir().join(format!("omikuji-overflow-{}.txt", std::process::id()));
+        fs::write(&path, format!("1 {} 2\n0 1:1\n", Index::MAX)).unwrap();
+        let err = DataSet::load_xc_repo_data_file(&path).unwrap_err();
+        assert_eq!(ErrorKind::InvalidData, err.kind());
+        assert!(err.to_string().starts_with("feature index 4294967295"));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_load_compressed() {
+        let plain = DataSet::read_xc_repo_data_with_warnings(
+            &include_bytes!("data/fixtures/tiny.txt")[..],
+            &Warnings::new(),
+        )
+        .unwrap();
+        assert_eq!(4, plain.len());
+        assert_eq!(
+            vec![&[0, 1][..], &[2][..], &[1][..], &[0, 2][..]],
+            plain.label_lists().collect_vec()
+        );
+
+        for &(data, extension, available) in &[
+            (
+                &include_bytes!("data/fixtures/tiny.txt.gz")[..],
+                "gz",
+                cfg!(feature = "gzip"),
+            ),
+            (
+                &include_bytes!("data/fixtures/tiny.txt.bz2")[..],
+                "bz2",
+                cfg!(feature = "bzip2"),
+            ),
+            (
+                &include_bytes!("data/fixtures/tiny.txt.zst")[..],
+                "zst",
+                cfg!(feature = "zstd"),
+            ),
+        ] {
+            let path = std::env::temp_dir().join(format!(
+                "omikuji-compressed-{}.txt.{}",
+                std::process::id(),
+                extension
+            ));
+            fs::write(&path, data).unwrap();
+            for result in [
+                DataSet::load_xc_repo_data_file(&path),
+                DataSet::load_auto(&path),
+            ] {
+                match result {
+                    Ok(dataset) => {
+                        assert!(available);
+                        assert_eq!(plain.feature_lists, dataset.feature_lists);
+                        assert_eq!(plain.labels, dataset.labels);
+                    }
+                    Err(e) => {
+                        assert!(!available, "{}", e);
+                        assert_eq!(ErrorKind::InvalidData, e.kind());
+                    }
+                }
+            }
+
+            // Uncompressed data isn't parsed when the extension says otherwise
+            fs::write(&path, include_bytes!("data/fixtures/tiny.txt")).unwrap();
+            assert!(DataSet::load_xc_repo_data_file(&path).is_ok());
+            let err = DataSet::load_auto(&path).unwrap_err();
+            assert_eq!(ErrorKind::InvalidData, err.kind());
+            fs::remove_file(&path).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_label_centroids() {
+        let dataset = DataSet {
+            n_features: 4,
+            n_labels: 4,
+            feature_lists: vec![
+                vec![(0, 1.), (2, 2.)],
+                vec![(1, 1.), (3, 2.)],
+                vec![(0, 3.), (3, 4.)],
+            ],
+            labels: LabelMatrix::from_rows(vec![vec![0, 1], vec![0], vec![3]]),
+        };
+
+        let centroids = dataset.label_centroids(0.);
+        assert_eq!((4, 4), centroids.shape());
+        let row = |label: usize| {
+            let row = centroids.outer_view(label).unwrap();
+            row.iter().map(|(i, &v)| (i as Index, v)).collect_vec()
+        };
+        let sqrt_10 = 10f32.sqrt();
+        assert_eq!(
+            vec![
+                (0, 1. / sqrt_10),
+                (1, 1. / sqrt_10),
+                (2, 2. / sqrt_10),
+                (3, 2. / sqrt_10),
+            ],
+            row(0)
+        );
+        let sqrt_5 = 5f32.sqrt();
+        assert_eq!(vec![(0, 1. / sqrt_5), (2, 2. / sqrt_5)], row(1));
+        // Label 2 has no examples, so its row is empty rather than NaN
+        assert!(row(2).is_empty());
+        assert_eq!(vec![(0, 0.6), (3, 0.8)], row(3));
+
+        // Pruning removes small entries, and rows are normalized again
+        let pruned = dataset.label_centroids(0.5);
+        let row = pruned.outer_view(0).unwrap();
+        assert_eq!(vec![2, 3], row.indices());
+        for &v in row.data() {
+            assert_approx_eq!(1. / 2f32.sqrt(), v, 1e-6);
+        }
+        assert_eq!(0, pruned.outer_view(2).unwrap().nnz());
+    }
+
+    #[test]
+    fn test_split() {
+        let dataset = crate::test_util::toy_dataset(100, 5, 0);
+        let (first, second) = dataset.split(0.3, 42);
+        assert_eq!(70, first.len());
+        assert_eq!(30, second.len());
+        assert_eq!(dataset.n_features, second.n_features);
+
+        // Same seed gives the same split
+        let (_, second_again) = dataset.split(0.3, 42);
+        assert_eq!(second.feature_lists, second_again.feature_lists);
+
+        // Together the two parts contain every example exactly once
+        let mut all = first.feature_lists.clone();
+        all.extend(second.feature_lists.iter().cloned());
+        assert_eq!(dataset.len(), all.len());
+        for v in &dataset.feature_lists {
+            assert!(all.contains(v));
+        }
+    }
 }
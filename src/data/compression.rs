@@ -0,0 +1,283 @@
+//! Transparent compression of data and model files.
+//!
+//! Compression is detected by the magic bytes at the start of the data, so that compressed files
+//! can be read as if they were plain text. Each codec is only available when the crate is built
+//! with the feature of the same name; data compressed with a codec that isn't available is
+//! rejected with an error rather than parsed as text.
+use serde::{Deserialize, Serialize};
+use std::io::{BufRead, BufReader, Cursor, Error, ErrorKind, Read, Result, Write};
+use std::ops::RangeInclusive;
+use std::path::Path;
+
+/// The length of the longest magic bytes that [`Compression::detect`] looks at.
+const MAX_MAGIC_LEN: usize = 4;
+
+/// A compression format of data and model files.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Compression {
+    /// Gzip, available with the `gzip` feature; concatenated members are read as one stream.
+    Gzip,
+    /// Bzip2, available with the `bzip2` feature; concatenated streams are read as one.
+    Bzip2,
+    /// Zstandard, available with the `zstd` feature.
+    Zstd,
+}
+
+impl Compression {
+    /// Detect the compression format from the first bytes of the data, if it's compressed.
+    ///
+    /// Returns `None` for data that may be uncompressed, including data too short to tell.
+    pub fn detect(magic: &[u8]) -> Option<Self> {
+        if magic.starts_with(&[0x1f, 0x8b]) {
+            Some(Compression::Gzip)
+        } else if magic.starts_with(b"BZh") {
+            Some(Compression::Bzip2)
+        } else if magic.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+            Some(Compression::Zstd)
+        } else {
+            None
+        }
+    }
+
+    /// Guess the compression format from the extension of a file name, if it names one.
+    pub fn from_extension<P: AsRef<Path>>(path: P) -> Option<Self> {
+        let extension = path.as_ref().extension()?.to_str()?.to_ascii_lowercase();
+        match extension.as_str() {
+            "gz" | "gzip" => Some(Compression::Gzip),
+            "bz2" | "bzip2" => Some(Compression::Bzip2),
+            "zst" | "zstd" => Some(Compression::Zstd),
+            _ => None,
+        }
+    }
+
+    /// The name of the feature the codec is available with.
+    fn feature_name(self) -> &'static str {
+        match self {
+            Compression::Gzip => "gzip",
+            Compression::Bzip2 => "bzip2",
+            Compression::Zstd => "zstd",
+        }
+    }
+
+    /// The range of compression levels the codec supports, from fastest to smallest.
+    pub fn levels(self) -> RangeInclusive<u32> {
+        match self {
+            Compression::Gzip => 0..=9,
+            Compression::Bzip2 => 1..=9,
+            Compression::Zstd => 1..=22,
+        }
+    }
+
+    /// Wrap the reader to decompress its content.
+    fn decoder<'a, R: BufRead + 'a>(self, reader: R) -> Result<Box<dyn Read + 'a>> {
+        match self {
+            #[cfg(feature = "gzip")]
+            Compression::Gzip => Ok(Box::new(flate2::bufread::MultiGzDecoder::new(reader))),
+            #[cfg(feature = "bzip2")]
+            Compression::Bzip2 => Ok(Box::new(bzip2::bufread::MultiBzDecoder::new(reader))),
+            #[cfg(feature = "zstd")]
+            Compression::Zstd => Ok(Box::new(zstd::stream::read::Decoder::with_buffer(reader)?)),
+            #[allow(unreachable_patterns)]
+            _ => {
+                drop(reader);
+                Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!(
+                        "Data is {:?}-compressed, but omikuji was built without the \"{}\" feature",
+                        self,
+                        self.feature_name()
+                    ),
+                ))
+            }
+        }
+    }
+}
+
+/// Compress everything the given function writes with the given codec, at the given level or
+/// the codec's default one, and finish the compressed stream.
+pub(crate) fn compress<W, F>(
+    writer: W,
+    compression: Compression,
+    level: Option<u32>,
+    write: F,
+) -> Result<()>
+where
+    W: Write,
+    F: FnOnce(&mut dyn Write) -> Result<()>,
+{
+    if let Some(level) = level {
+        if !compression.levels().contains(&level) {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "{:?} compression level must be in {:?}, got {}",
+                    compression,
+                    compression.levels(),
+                    level
+                ),
+            ));
+        }
+    }
+
+    match compression {
+        #[cfg(feature = "gzip")]
+        Compression::Gzip => {
+            let level = level.map_or_else(flate2::Compression::default, flate2::Compression::new);
+            let mut encoder = flate2::write::GzEncoder::new(writer, level);
+            write(&mut encoder)?;
+            encoder.finish()?.flush()
+        }
+        #[cfg(feature = "bzip2")]
+        Compression::Bzip2 => {
+            let level = level.map_or_else(bzip2::Compression::default, bzip2::Compression::new);
+            let mut encoder = bzip2::write::BzEncoder::new(writer, level);
+            write(&mut encoder)?;
+            encoder.finish()?.flush()
+        }
+        #[cfg(feature = "zstd")]
+        Compression::Zstd => {
+            // Level 0 selects zstd's default level
+            let mut encoder = zstd::stream::write::Encoder::new(writer, level.unwrap_or(0) as i32)?;
+            // Unlike gzip and bzip2, zstd doesn't check the integrity of the data by default
+            encoder.include_checksum(true)?;
+            write(&mut encoder)?;
+            encoder.finish()?.flush()
+        }
+        #[allow(unreachable_patterns)]
+        _ => {
+            drop((writer, write));
+            Err(Error::new(
+                ErrorKind::Unsupported,
+                format!(
+                    "{:?} compression requires omikuji to be built with the \"{}\" feature",
+                    compression,
+                    compression.feature_name()
+                ),
+            ))
+        }
+    }
+}
+
+/// Wrap the reader to decompress its content if it's compressed, returning the reader with the
+/// compression format detected.
+pub(crate) fn decompress<'a, R: Read + 'a>(
+    mut reader: R,
+) -> Result<(Box<dyn Read + 'a>, Option<Compression>)> {
+    // A single read can return fewer bytes than the magic bytes before the end of the data, so
+    // read until there are enough, then put them back in front of the rest for the decoder
+    let mut magic = Vec::with_capacity(MAX_MAGIC_LEN);
+    (&mut reader)
+        .take(MAX_MAGIC_LEN as u64)
+        .read_to_end(&mut magic)?;
+    let compression = Compression::detect(&magic);
+    let reader = BufReader::new(Cursor::new(magic).chain(reader));
+    let reader = match compression {
+        Some(compression) => compression.decoder(reader)?,
+        None => Box::new(reader),
+    };
+    Ok((reader, compression))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The same tiny dataset, uncompressed and compressed with each codec.
+    const TINY_TXT: &[u8] = include_bytes!("fixtures/tiny.txt");
+    const TINY_GZ: &[u8] = include_bytes!("fixtures/tiny.txt.gz");
+    const TINY_BZ2: &[u8] = include_bytes!("fixtures/tiny.txt.bz2");
+    const TINY_ZST: &[u8] = include_bytes!("fixtures/tiny.txt.zst");
+
+    fn read_all<R: Read>(data: R) -> Result<(Vec<u8>, Option<Compression>)> {
+        let (mut reader, compression) = decompress(data)?;
+        let mut content = Vec::new();
+        reader.read_to_end(&mut content)?;
+        Ok((content, compression))
+    }
+
+    #[test]
+    fn test_detect() {
+        assert_eq!(None, Compression::detect(TINY_TXT));
+        assert_eq!(Some(Compression::Gzip), Compression::detect(TINY_GZ));
+        assert_eq!(Some(Compression::Bzip2), Compression::detect(TINY_BZ2));
+        assert_eq!(Some(Compression::Zstd), Compression::detect(TINY_ZST));
+        assert_eq!(None, Compression::detect(&[0x1f]));
+        assert_eq!(None, Compression::detect(&[]));
+
+        assert_eq!(
+            Some(Compression::Gzip),
+            Compression::from_extension("train.txt.GZ")
+        );
+        assert_eq!(
+            Some(Compression::Bzip2),
+            Compression::from_extension("train.bz2")
+        );
+        assert_eq!(
+            Some(Compression::Zstd),
+            Compression::from_extension("train.zst")
+        );
+        assert_eq!(None, Compression::from_extension("train.txt"));
+        assert_eq!(None, Compression::from_extension("train"));
+    }
+
+    #[test]
+    fn test_decompress_plain() {
+        assert_eq!((TINY_TXT.to_vec(), None), read_all(TINY_TXT).unwrap());
+        assert_eq!((Vec::new(), None), read_all(&[]).unwrap());
+    }
+
+    /// A reader that returns one byte at a time.
+    struct ByteByByte<'a>(&'a [u8]);
+
+    impl Read for ByteByByte<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+            match (self.0.split_first(), buf.first_mut()) {
+                (Some((&byte, rest)), Some(slot)) => {
+                    *slot = byte;
+                    self.0 = rest;
+                    Ok(1)
+                }
+                _ => Ok(0),
+            }
+        }
+    }
+
+    #[test]
+    fn test_decompress_short_reads() {
+        // Compression is detected even if the magic bytes take several reads
+        for &data in &[TINY_TXT, TINY_GZ, TINY_BZ2, TINY_ZST, &[0x1f][..], &[][..]] {
+            match (read_all(data), read_all(ByteByByte(data))) {
+                (Ok(expected), Ok(actual)) => assert_eq!(expected, actual),
+                (Err(expected), Err(actual)) => assert_eq!(expected.kind(), actual.kind()),
+                (expected, actual) => panic!("Expected {:?}, got {:?}", expected, actual),
+            }
+        }
+    }
+
+    #[test]
+    fn test_decompress_codecs() {
+        for &(data, compression) in &[
+            (TINY_GZ, Compression::Gzip),
+            (TINY_BZ2, Compression::Bzip2),
+            (TINY_ZST, Compression::Zstd),
+        ] {
+            let available = match compression {
+                Compression::Gzip => cfg!(feature = "gzip"),
+                Compression::Bzip2 => cfg!(feature = "bzip2"),
+                Compression::Zstd => cfg!(feature = "zstd"),
+            };
+            match read_all(data) {
+                Ok((content, detected)) => {
+                    assert!(available, "{:?} shouldn't be available", compression);
+                    assert_eq!(Some(compression), detected);
+                    assert_eq!(TINY_TXT, &content[..]);
+                }
+                Err(e) => {
+                    assert!(!available, "{:?} failed: {}", compression, e);
+                    assert_eq!(ErrorKind::InvalidData, e.kind());
+                    assert!(e.to_string().contains(compression.feature_name()));
+                }
+            }
+        }
+    }
+}
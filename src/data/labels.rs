@@ -0,0 +1,180 @@
+use crate::{Index, IndexSet};
+use std::iter::FromIterator;
+use std::mem;
+
+/// Label lists of examples, stored as row pointers into a single flat array of labels.
+///
+/// Each row is sorted and has no duplicates. Compared to a separate collection per example, this
+/// saves the overhead of an allocation per example and keeps the labels of consecutive examples
+/// next to each other in memory.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LabelMatrix {
+    indptr: Vec<usize>,
+    indices: Vec<Index>,
+}
+
+impl Default for LabelMatrix {
+    fn default() -> Self {
+        Self::with_capacity(0, 0)
+    }
+}
+
+impl LabelMatrix {
+    /// Create an empty matrix with room for the given numbers of rows and labels.
+    pub fn with_capacity(n_rows: usize, nnz: usize) -> Self {
+        let mut indptr = Vec::with_capacity(n_rows + 1);
+        indptr.push(0);
+        Self {
+            indptr,
+            indices: Vec::with_capacity(nnz),
+        }
+    }
+
+    /// Create a matrix with the given label lists as rows.
+    pub fn from_rows<R: IntoIterator<Item = Index>>(rows: impl IntoIterator<Item = R>) -> Self {
+        let mut matrix = Self::default();
+        for row in rows {
+            matrix.push_row(row);
+        }
+        matrix
+    }
+
+    /// Append a row with the given labels, which are sorted and deduplicated.
+    pub fn push_row(&mut self, labels: impl IntoIterator<Item = Index>) {
+        let start = self.indices.len();
+        self.indices.extend(labels);
+        self.indices[start..].sort_unstable();
+        // Deduplicate within the new row only
+        let (mut read, mut write) = (start, start);
+        while read < self.indices.len() {
+            if write == start || self.indices[read] != self.indices[write - 1] {
+                self.indices[write] = self.indices[read];
+                write += 1;
+            }
+            read += 1;
+        }
+        self.indices.truncate(write);
+        self.indptr.push(self.indices.len());
+    }
+
+    /// The number of rows.
+    pub fn len(&self) -> usize {
+        self.indptr.len() - 1
+    }
+
+    /// Whether the matrix has no rows.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The total number of labels over all rows.
+    pub fn nnz(&self) -> usize {
+        self.indices.len()
+    }
+
+    /// The sorted labels of the given row.
+    pub fn row(&self, i: usize) -> &[Index] {
+        &self.indices[self.indptr[i]..self.indptr[i + 1]]
+    }
+
+    /// Whether the given row contains the label.
+    pub fn contains(&self, i: usize, label: Index) -> bool {
+        self.row(i).binary_search(&label).is_ok()
+    }
+
+    /// Iterate over rows in order.
+    pub fn iter(&self) -> impl ExactSizeIterator<Item = &[Index]> + Clone + '_ {
+        self.indptr
+            .windows(2)
+            .map(move |w| &self.indices[w[0]..w[1]])
+    }
+
+    /// Create a new matrix from the rows at the given indices, in the given order.
+    pub fn take_rows(&self, rows: &[usize]) -> Self {
+        let nnz = rows.iter().map(|&i| self.row(i).len()).sum();
+        let mut matrix = Self::with_capacity(rows.len(), nnz);
+        for &i in rows {
+            matrix.indices.extend_from_slice(self.row(i));
+            matrix.indptr.push(matrix.indices.len());
+        }
+        matrix
+    }
+
+    /// Convert rows into separate label sets.
+    pub fn to_sets(&self) -> Vec<IndexSet> {
+        self.iter()
+            .map(|row| row.iter().cloned().collect())
+            .collect()
+    }
+
+    /// The memory used by the row pointers and labels, in bytes.
+    pub fn mem_size(&self) -> usize {
+        self.indptr.len() * mem::size_of::<usize>() + self.indices.len() * mem::size_of::<Index>()
+    }
+}
+
+impl<R: IntoIterator<Item = Index>> FromIterator<R> for LabelMatrix {
+    fn from_iter<I: IntoIterator<Item = R>>(rows: I) -> Self {
+        Self::from_rows(rows)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::count_allocations;
+
+    #[test]
+    fn test_label_matrix() {
+        let matrix = LabelMatrix::from_rows(vec![vec![3, 1, 3], vec![], vec![7, 0]]);
+        assert_eq!(3, matrix.len());
+        assert_eq!(4, matrix.nnz());
+        assert_eq!(&[1, 3], matrix.row(0));
+        assert!(matrix.row(1).is_empty());
+        assert_eq!(&[0, 7], matrix.row(2));
+        assert!(matrix.contains(2, 7));
+        assert!(!matrix.contains(0, 2));
+        assert_eq!(
+            vec![&[1, 3][..], &[][..], &[0, 7][..]],
+            matrix.iter().collect::<Vec<_>>()
+        );
+
+        let taken = matrix.take_rows(&[2, 0, 2]);
+        assert_eq!(
+            LabelMatrix::from_rows(vec![vec![0, 7], vec![1, 3], vec![0, 7]]),
+            taken
+        );
+        assert_eq!(
+            vec![
+                IndexSet::from_iter(vec![1, 3]),
+                IndexSet::new(),
+                IndexSet::from_iter(vec![0, 7])
+            ],
+            matrix.to_sets()
+        );
+        assert!(LabelMatrix::default().is_empty());
+    }
+
+    #[test]
+    fn test_fewer_allocations_than_sets() {
+        let rows = (0..1000)
+            .map(|i| vec![i % 7, i % 11, i % 13])
+            .collect::<Vec<_>>();
+        let (matrix, n_matrix_allocations) = count_allocations(|| {
+            let mut matrix = LabelMatrix::with_capacity(rows.len(), 3 * rows.len());
+            for row in &rows {
+                matrix.push_row(row.iter().cloned());
+            }
+            matrix
+        });
+        let (sets, n_set_allocations) = count_allocations(|| {
+            rows.iter()
+                .map(|row| row.iter().cloned().collect::<IndexSet>())
+                .collect::<Vec<_>>()
+        });
+        assert_eq!(sets, matrix.to_sets());
+        assert_eq!(2, n_matrix_allocations);
+        assert!(n_set_allocations > rows.len());
+        assert!(matrix.mem_size() <= (rows.len() + 1) * 8 + 3 * rows.len() * 4);
+    }
+}
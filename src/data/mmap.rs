@@ -0,0 +1,367 @@
+//! A binary dataset format whose feature matrix can be memory-mapped and used for training as is.
+//!
+//! # Layout
+//!
+//! All integers are little-endian.
+//!
+//! | Offset        | Content                                                          |
+//! |---------------|------------------------------------------------------------------|
+//! | 0             | Magic bytes [`MMAP_MAGIC`]                                       |
+//! | 8             | Length of the JSON header as `u64`                               |
+//! | 16            | JSON header with dimensions and label lists                      |
+//! | aligned to 8  | Row pointers, `n_examples + 1` values of `u64`                   |
+//! |               | Column indices, `nnz` values of `u32`                            |
+//! |               | Feature values, `nnz` values of `f32`                            |
+//!
+//! Feature vectors are stored the way training consumes them, i.e., l2-normalized and with the
+//! bias term appended as an extra feature with index `n_features`.
+use super::{DataSet, LabelMatrix};
+use crate::mat_util::*;
+use crate::{check_dimensions, checked_index, Index, IndexKind};
+use log::info;
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{BufWriter, Error, ErrorKind, Result, Write};
+use std::path::Path;
+use std::sync::Arc;
+use std::time;
+
+/// Magic bytes at the start of a memory-mappable dataset file.
+pub(crate) const MMAP_MAGIC: &[u8; 8] = b"OMKJDSM1";
+
+#[derive(Serialize, Deserialize)]
+struct Header {
+    n_examples: usize,
+    n_features: usize,
+    n_labels: usize,
+    nnz: usize,
+    label_lists: Vec<Vec<Index>>,
+}
+
+#[inline]
+fn align_to_8(offset: usize) -> usize {
+    (offset + 7) / 8 * 8
+}
+
+/// A read-only CSR feature matrix backed by a memory-mapped file.
+pub(crate) struct MappedCsr {
+    // NB: `mat` borrows from the memory owned by `_mmap`, so it must be declared (and thus
+    // dropped) first. The mapping never moves, since it's not backed by the struct itself.
+    mat: SparseMatView<'static>,
+    _mmap: memmap2::Mmap,
+}
+
+impl MappedCsr {
+    /// A view of the mapped matrix.
+    pub(crate) fn view(&self) -> SparseMatView {
+        self.mat.view()
+    }
+}
+
+/// A dataset whose feature matrix is memory-mapped from a file written by [`DataSet::save_mmap`].
+///
+/// Feature vectors are read directly from the mapping without parsing or copying.
+#[derive(Clone)]
+pub struct MmapDataSet {
+    pub(crate) n_features: usize,
+    pub(crate) n_labels: usize,
+    pub(crate) labels: LabelMatrix,
+    pub(crate) feature_matrix: Arc<MappedCsr>,
+}
+
+impl MmapDataSet {
+    /// The number of examples in the dataset.
+    pub fn len(&self) -> usize {
+        self.labels.len()
+    }
+
+    /// Whether the dataset contains no examples.
+    pub fn is_empty(&self) -> bool {
+        self.labels.is_empty()
+    }
+
+    /// The dimension of feature vectors, not counting the bias term.
+    pub fn n_features(&self) -> usize {
+        self.n_features
+    }
+
+    /// The number of labels as declared in the dataset.
+    pub fn n_labels(&self) -> usize {
+        self.n_labels
+    }
+}
+
+impl DataSet {
+    /// Write the dataset in a binary format that can be opened with [`Self::open_mmap`].
+    ///
+    /// Feature vectors are l2-normalized and have the bias term appended before being written.
+    pub fn save_mmap<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        info!("Saving dataset to {}", path.as_ref().display());
+        let start_t = time::Instant::now();
+
+        let n_examples = self.feature_lists.len();
+        let bias_index = checked_index(self.n_features, IndexKind::Feature)
+            .map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+        let nnz = self
+            .feature_lists
+            .iter()
+            .map(|v| v.len() + 1)
+            .sum::<usize>();
+        let header = serde_json::to_vec(&Header {
+            n_examples,
+            n_features: self.n_features,
+            n_labels: self.n_labels,
+            nnz,
+            label_lists: self.labels.iter().map(<[Index]>::to_vec).collect(),
+        })
+        .map_err(|e| {
+            Error::new(
+                ErrorKind::Other,
+                format!("Unable to serialize header: {}", e),
+            )
+        })?;
+
+        let mut writer = BufWriter::new(File::create(path)?);
+        writer.write_all(MMAP_MAGIC)?;
+        writer.write_all(&(header.len() as u64).to_le_bytes())?;
+        writer.write_all(&header)?;
+        let header_end = MMAP_MAGIC.len() + 8 + header.len();
+        writer.write_all(&vec![0u8; align_to_8(header_end) - header_end])?;
+
+        let mut offset = 0u64;
+        writer.write_all(&offset.to_le_bytes())?;
+        for v in &self.feature_lists {
+            offset += v.len() as u64 + 1;
+            writer.write_all(&offset.to_le_bytes())?;
+        }
+        for v in &self.feature_lists {
+            for &(i, _) in v {
+                writer.write_all(&i.to_le_bytes())?;
+            }
+            writer.write_all(&bias_index.to_le_bytes())?;
+        }
+        for v in &self.feature_lists {
+            let mut v = v.clone();
+            v.l2_normalize();
+            for (_, value) in v {
+                writer.write_all(&value.to_le_bytes())?;
+            }
+            writer.write_all(&1f32.to_le_bytes())?;
+        }
+        writer.flush()?;
+
+        info!(
+            "Saved {} examples; it took {:.2}s",
+            n_examples,
+            start_t.elapsed().as_secs_f32()
+        );
+        Ok(())
+    }
+
+    /// Memory-map a dataset file written by [`Self::save_mmap`].
+    ///
+    /// Only the header is parsed; feature vectors stay in the mapped file, which must not be
+    /// modified while the returned dataset is in use. This is only supported on 64-bit
+    /// little-endian platforms, where the file layout matches the in-memory one.
+    pub fn open_mmap<P: AsRef<Path>>(path: P) -> Result<MmapDataSet> {
+        if !cfg!(all(target_endian = "little", target_pointer_width = "64")) {
+            return Err(Error::new(
+                ErrorKind::Unsupported,
+                "Memory-mapped datasets are only supported on 64-bit little-endian platforms",
+            ));
+        }
+
+        info!("Mapping data from {}", path.as_ref().display());
+        let file = File::open(path)?;
+        // Safety: we require that the file is not modified while it's mapped
+        let mmap = unsafe { memmap2::Mmap::map(&file)? };
+
+        let invalid_data = |msg: &str| Error::new(ErrorKind::InvalidData, msg.to_owned());
+        if mmap.len() < MMAP_MAGIC.len() + 8 || &mmap[..MMAP_MAGIC.len()] != MMAP_MAGIC {
+            return Err(invalid_data("Not a memory-mappable dataset file"));
+        }
+        let header_len = {
+            let mut buf = [0u8; 8];
+            buf.copy_from_slice(&mmap[MMAP_MAGIC.len()..MMAP_MAGIC.len() + 8]);
+            u64::from_le_bytes(buf) as usize
+        };
+        let header_start = MMAP_MAGIC.len() + 8;
+        let header_end = header_start
+            .checked_add(header_len)
+            .filter(|&end| end <= mmap.len())
+            .ok_or_else(|| invalid_data("Truncated header"))?;
+        let Header {
+            n_examples,
+            n_features,
+            n_labels,
+            nnz,
+            label_lists,
+        } = serde_json::from_slice(&mmap[header_start..header_end]).map_err(|e| {
+            Error::new(
+                ErrorKind::InvalidData,
+                format!("Unable to parse header: {}", e),
+            )
+        })?;
+        check_dimensions(n_examples, n_features, n_labels)
+            .map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+        if label_lists.len() != n_examples {
+            return Err(invalid_data(
+                "Number of label lists doesn't match number of examples",
+            ));
+        }
+
+        // The header is untrusted, so offsets are computed without overflow and checked against
+        // the mapping before any slice is made from them
+        let indptr_start = align_to_8(header_end);
+        let array_end = |start: usize, n: usize, size: usize| {
+            n.checked_mul(size).and_then(|len| start.checked_add(len))
+        };
+        let (indices_start, data_start, _) = n_examples
+            .checked_add(1)
+            .and_then(|n_indptr| array_end(indptr_start, n_indptr, 8))
+            .and_then(|indices_start| {
+                let data_start = array_end(indices_start, nnz, 4)?;
+                Some((indices_start, data_start, array_end(data_start, nnz, 4)?))
+            })
+            .filter(|&(_, _, data_end)| data_end <= mmap.len())
+            .ok_or_else(|| invalid_data("Truncated feature matrix"))?;
+
+        // Safety: the offsets are within the mapping as checked above, and they're properly
+        // aligned because the mapping is page-aligned and the arrays are laid out accordingly.
+        // The slices live as long as the mapping, which is kept in the same struct.
+        let indptr = unsafe {
+            std::slice::from_raw_parts(
+                mmap.as_ptr().add(indptr_start) as *const usize,
+                n_examples + 1,
+            )
+        };
+        if indptr[0] != 0 || indptr.windows(2).any(|w| w[0] > w[1]) || indptr[n_examples] != nnz {
+            return Err(invalid_data(
+                "Row offsets of the feature matrix are out of order or don't end at nnz",
+            ));
+        }
+        // Safety: as for the row offsets above
+        let (indices, data) = unsafe {
+            let base = mmap.as_ptr();
+            (
+                std::slice::from_raw_parts(base.add(indices_start) as *const Index, nnz),
+                std::slice::from_raw_parts(base.add(data_start) as *const f32, nnz),
+            )
+        };
+        let mat = SparseMatView::new_view(
+            sprs::CompressedStorage::CSR,
+            (n_examples, n_features + 1),
+            indptr,
+            indices,
+            data,
+        )
+        .map_err(|e| {
+            Error::new(
+                ErrorKind::InvalidData,
+                format!("Invalid feature matrix: {}", e),
+            )
+        })?;
+
+        let labels = LabelMatrix::from_rows(label_lists);
+        if labels
+            .iter()
+            .flatten()
+            .any(|&label| label as usize >= n_labels)
+        {
+            return Err(invalid_data("Label index out of range"));
+        }
+
+        info!(
+            "Mapped {} examples with {} non-zero features",
+            n_examples, nnz
+        );
+        Ok(MmapDataSet {
+            n_features,
+            n_labels,
+            labels,
+            feature_matrix: Arc::new(MappedCsr { mat, _mmap: mmap }),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::toy_dataset;
+    use itertools::Itertools;
+
+    #[test]
+    fn test_save_and_open_mmap() {
+        let dataset = toy_dataset(30, 4, 0);
+        let path = std::env::temp_dir().join(format!("omikuji-mmap-{}.bin", std::process::id()));
+        dataset.save_mmap(&path).unwrap();
+        let mapped = DataSet::open_mmap(&path).unwrap();
+
+        assert_eq!(dataset.len(), mapped.len());
+        assert_eq!(dataset.n_features, mapped.n_features());
+        assert_eq!(dataset.labels, mapped.labels);
+
+        let mat = mapped.feature_matrix.view();
+        assert_eq!((30, dataset.n_features + 1), mat.shape());
+        for (expected, row) in dataset.feature_lists.iter().zip(mat.outer_iterator()) {
+            let mut expected = expected.clone();
+            expected.l2_normalize();
+            expected.push((dataset.n_features as Index, 1.));
+            assert_eq!(
+                expected,
+                row.iter().map(|(i, &v)| (i as Index, v)).collect_vec()
+            );
+        }
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_open_mmap_rejects_corrupt_files() {
+        let dataset = toy_dataset(10, 4, 0);
+        let path =
+            std::env::temp_dir().join(format!("omikuji-mmap-corrupt-{}.bin", std::process::id()));
+        dataset.save_mmap(&path).unwrap();
+        let bytes = std::fs::read(&path).unwrap();
+        let header_len = {
+            let mut buf = [0u8; 8];
+            buf.copy_from_slice(&bytes[MMAP_MAGIC.len()..MMAP_MAGIC.len() + 8]);
+            u64::from_le_bytes(buf) as usize
+        };
+        let indptr_start = align_to_8(MMAP_MAGIC.len() + 8 + header_len);
+
+        // Truncated
+        std::fs::write(&path, &bytes[..bytes.len() - 1]).unwrap();
+        assert_eq!(
+            ErrorKind::InvalidData,
+            DataSet::open_mmap(&path).unwrap_err().kind()
+        );
+
+        // Row offsets out of order
+        let mut corrupt = bytes.clone();
+        corrupt[indptr_start + 8..indptr_start + 16].copy_from_slice(&u64::MAX.to_le_bytes());
+        std::fs::write(&path, &corrupt).unwrap();
+        assert_eq!(
+            ErrorKind::InvalidData,
+            DataSet::open_mmap(&path).unwrap_err().kind()
+        );
+
+        // A header whose sizes overflow
+        let header =
+            String::from_utf8(bytes[MMAP_MAGIC.len() + 8..][..header_len].to_vec()).unwrap();
+        let mut value: serde_json::Value = serde_json::from_str(&header).unwrap();
+        value["nnz"] = serde_json::json!(u64::MAX / 2);
+        let header = serde_json::to_vec(&value).unwrap();
+        let mut corrupt = MMAP_MAGIC.to_vec();
+        corrupt.extend_from_slice(&(header.len() as u64).to_le_bytes());
+        corrupt.extend_from_slice(&header);
+        corrupt.resize(align_to_8(corrupt.len()) + 64, 0);
+        std::fs::write(&path, &corrupt).unwrap();
+        assert_eq!(
+            ErrorKind::InvalidData,
+            DataSet::open_mmap(&path).unwrap_err().kind()
+        );
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,208 @@
+use std::fmt;
+use std::num::NonZeroUsize;
+use std::str::FromStr;
+
+/// How floats are formatted in text outputs.
+///
+/// Outputs meant to be parsed again, e.g., prediction files and CSV exports, default to
+/// [`FloatFormat::Shortest`], so that values survive the round trip exactly; tables meant for
+/// people default to [`FloatFormat::TABLE`].
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum FloatFormat {
+    /// The shortest representation that parses back to the same value, using scientific
+    /// notation for very large or small values.
+    #[default]
+    Shortest,
+    /// Rounded to the given number of significant digits.
+    Significant(NonZeroUsize),
+    /// Rounded to the given number of decimal places.
+    Fixed(usize),
+}
+
+impl FloatFormat {
+    /// The default format for tables meant for people.
+    pub const TABLE: Self = FloatFormat::Fixed(4);
+
+    /// Wrap a value for formatting with [`fmt::Display`].
+    ///
+    /// Width and alignment flags of the format string are respected, e.g., `{:>10}`.
+    pub fn display<F: Into<f64>>(self, value: F) -> Formatted {
+        Formatted {
+            format: self,
+            value: value.into(),
+        }
+    }
+
+    /// Wrap an `f32` for formatting with [`fmt::Display`].
+    ///
+    /// Unlike [`Self::display`], the shortest format is computed for `f32` precision, so that,
+    /// e.g., `0.1f32` is written as `0.1` rather than `0.10000000149011612`.
+    pub fn display_f32(self, value: f32) -> FormattedF32 {
+        FormattedF32 {
+            format: self,
+            value,
+        }
+    }
+
+    /// Rounded to the given number of significant digits, which must be positive.
+    pub fn significant(digits: usize) -> Result<Self, String> {
+        NonZeroUsize::new(digits)
+            .map(FloatFormat::Significant)
+            .ok_or_else(|| "The number of significant digits must be positive".to_owned())
+    }
+
+    fn fmt_f64(self, value: f64, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FloatFormat::Shortest => f.pad(ryu::Buffer::new().format(value)),
+            FloatFormat::Significant(digits) => f.pad(&format_significant(value, digits)),
+            FloatFormat::Fixed(decimals) => f.pad(&format!("{:.*}", decimals, value)),
+        }
+    }
+}
+
+/// Round to the given number of significant digits, in positional notation unless the value is
+/// very large or small.
+fn format_significant(value: f64, digits: NonZeroUsize) -> String {
+    let digits = digits.get();
+    if value == 0. || !value.is_finite() {
+        return format!("{}", value);
+    }
+    // Round first, since rounding can carry over into the next power of ten
+    let scientific = format!("{:.*e}", digits - 1, value);
+    let exponent = scientific[scientific.find('e').unwrap() + 1..]
+        .parse::<i32>()
+        .unwrap();
+    if exponent < -4 || exponent >= digits.max(6) as i32 {
+        scientific
+    } else {
+        let decimals = (digits as i32 - 1 - exponent).max(0) as usize;
+        format!("{:.*}", decimals, value)
+    }
+}
+
+/// A float wrapped for formatting, see [`FloatFormat::display`].
+#[derive(Copy, Clone, Debug)]
+pub struct Formatted {
+    format: FloatFormat,
+    value: f64,
+}
+
+impl fmt::Display for Formatted {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.format.fmt_f64(self.value, f)
+    }
+}
+
+/// An `f32` wrapped for formatting, see [`FloatFormat::display_f32`].
+#[derive(Copy, Clone, Debug)]
+pub struct FormattedF32 {
+    format: FloatFormat,
+    value: f32,
+}
+
+impl fmt::Display for FormattedF32 {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.format {
+            FloatFormat::Shortest => f.pad(ryu::Buffer::new().format(self.value)),
+            format => format.fmt_f64(self.value.into(), f),
+        }
+    }
+}
+
+impl fmt::Display for FloatFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FloatFormat::Shortest => write!(f, "shortest"),
+            FloatFormat::Significant(digits) => write!(f, "significant:{}", digits),
+            FloatFormat::Fixed(decimals) => write!(f, "fixed:{}", decimals),
+        }
+    }
+}
+
+/// Parses the [`fmt::Display`] form: `shortest`, `significant:<DIGITS>`, or `fixed:<DECIMALS>`.
+impl FromStr for FloatFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || {
+            format!(
+                "Invalid float format {:?}, expected \"shortest\", \"significant:<DIGITS>\", or \
+                 \"fixed:<DECIMALS>\"",
+                s
+            )
+        };
+        match s.split_once(':') {
+            None if s == "shortest" => Ok(FloatFormat::Shortest),
+            Some(("significant", n)) => FloatFormat::significant(n.parse().map_err(|_| invalid())?),
+            Some(("fixed", n)) => Ok(FloatFormat::Fixed(n.parse().map_err(|_| invalid())?)),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_formats() {
+        let shortest = FloatFormat::Shortest;
+        assert_eq!("0.1", shortest.display_f32(0.1).to_string());
+        assert_eq!("0.10000000149011612", shortest.display(0.1f32).to_string());
+        assert_eq!("1.0", shortest.display_f32(1.).to_string());
+        assert_eq!("-2.5", shortest.display_f32(-2.5).to_string());
+        assert_eq!("1e30", shortest.display_f32(1e30).to_string());
+        assert_eq!("NaN", shortest.display_f32(f32::NAN).to_string());
+
+        let significant = FloatFormat::significant(3).unwrap();
+        assert_eq!("0.123", significant.display(0.123456).to_string());
+        assert_eq!("123", significant.display(123.456).to_string());
+        assert_eq!("1.00", significant.display(0.9996).to_string());
+        assert_eq!("1.23e-5", significant.display(0.0000123456).to_string());
+        assert_eq!("1.23e7", significant.display(12345678.).to_string());
+        assert_eq!("0", significant.display(0.).to_string());
+
+        assert_eq!("0.1235", FloatFormat::TABLE.display(0.123456).to_string());
+        assert_eq!("12.0000", FloatFormat::TABLE.display(12).to_string());
+        assert_eq!(
+            "  0.50",
+            format!("{:>6}", FloatFormat::Fixed(2).display(0.5))
+        );
+    }
+
+    #[test]
+    fn test_shortest_round_trip() {
+        // Sample bit patterns across the whole range of f32, including subnormals
+        let mut bits = 0u32;
+        for _ in 0..100_000 {
+            let value = f32::from_bits(bits);
+            if value.is_finite() {
+                let text = FloatFormat::Shortest.display_f32(value).to_string();
+                assert_eq!(bits, text.parse::<f32>().unwrap().to_bits(), "{}", text);
+            }
+            bits = bits.wrapping_add(42_949);
+        }
+
+        for &value in &[0.1, 1. / 3., 6.02214076e23, -1e-300, f64::MIN_POSITIVE] {
+            let text = FloatFormat::Shortest.display(value).to_string();
+            assert_eq!(value.to_bits(), text.parse::<f64>().unwrap().to_bits());
+        }
+    }
+
+    #[test]
+    fn test_parse() {
+        for &format in &[
+            FloatFormat::Shortest,
+            FloatFormat::significant(5).unwrap(),
+            FloatFormat::Fixed(0),
+            FloatFormat::TABLE,
+        ] {
+            assert_eq!(Ok(format), format.to_string().parse());
+        }
+        assert!("fixed".parse::<FloatFormat>().is_err());
+        assert!("fixed:x".parse::<FloatFormat>().is_err());
+        assert!("significant:0".parse::<FloatFormat>().is_err());
+        assert!(FloatFormat::significant(0).is_err());
+        assert!("round:2".parse::<FloatFormat>().is_err());
+    }
+}
@@ -0,0 +1,166 @@
+//! Checked conversions into [`Index`], which can be narrower than `usize`.
+//!
+//! Dimensions are checked with [`check_dimensions`] where data enters the library, so that
+//! conversions deeper inside can rely on them fitting and use [`to_index`].
+use crate::Index;
+use num_traits::{Bounded, ToPrimitive};
+use std::convert::TryFrom;
+use std::fmt;
+
+/// What a value converted into an index stands for.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum IndexKind {
+    /// A feature, including the bias feature appended after the input features.
+    Feature,
+    /// A label.
+    Label,
+    /// An example in a dataset.
+    Example,
+    /// A row of a sparse matrix.
+    Row,
+    /// A column of a sparse matrix.
+    Column,
+}
+
+impl fmt::Display for IndexKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            IndexKind::Feature => write!(f, "feature"),
+            IndexKind::Label => write!(f, "label"),
+            IndexKind::Example => write!(f, "example"),
+            IndexKind::Row => write!(f, "row"),
+            IndexKind::Column => write!(f, "column"),
+        }
+    }
+}
+
+/// A value too large to be stored as an index.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct IndexOverflow {
+    pub kind: IndexKind,
+    pub value: usize,
+    /// The largest value that can be stored.
+    pub max: usize,
+}
+
+impl fmt::Display for IndexOverflow {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} index {} exceeds the largest supported index {}",
+            self.kind, self.value, self.max
+        )
+    }
+}
+
+impl std::error::Error for IndexOverflow {}
+
+/// Convert a value into an index, failing if it doesn't fit.
+pub fn checked_index(value: usize, kind: IndexKind) -> Result<Index, IndexOverflow> {
+    checked_index_as(value, kind)
+}
+
+/// Like [`checked_index`], but for any index type.
+fn checked_index_as<I>(value: usize, kind: IndexKind) -> Result<I, IndexOverflow>
+where
+    I: TryFrom<usize> + Bounded + ToPrimitive,
+{
+    I::try_from(value).map_err(|_| IndexOverflow {
+        kind,
+        value,
+        max: I::max_value().to_usize().unwrap_or(usize::MAX),
+    })
+}
+
+/// Convert a value already known to fit into an index.
+///
+/// Panics with a description of the value if it doesn't fit, which means that it should have
+/// been rejected by [`check_dimensions`].
+#[inline]
+pub(crate) fn to_index(value: usize, kind: IndexKind) -> Index {
+    checked_index(value, kind).unwrap_or_else(|e| panic!("{}", e))
+}
+
+/// Check that all indices of a dataset with the given dimensions fit, including the bias
+/// feature appended after the input features.
+pub fn check_dimensions(
+    n_examples: usize,
+    n_features: usize,
+    n_labels: usize,
+) -> Result<(), IndexOverflow> {
+    check_dimensions_as::<Index>(n_examples, n_features, n_labels)
+}
+
+fn check_dimensions_as<I>(
+    n_examples: usize,
+    n_features: usize,
+    n_labels: usize,
+) -> Result<(), IndexOverflow>
+where
+    I: TryFrom<usize> + Bounded + ToPrimitive,
+{
+    if n_examples > 0 {
+        checked_index_as::<I>(n_examples - 1, IndexKind::Example)?;
+    }
+    checked_index_as::<I>(n_features, IndexKind::Feature)?; // The bias feature
+    if n_labels > 0 {
+        checked_index_as::<I>(n_labels - 1, IndexKind::Label)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_checked_index() {
+        assert_eq!(Ok(255u8), checked_index_as::<u8>(255, IndexKind::Label));
+        let overflow = checked_index_as::<u8>(256, IndexKind::Label).unwrap_err();
+        assert_eq!(
+            IndexOverflow {
+                kind: IndexKind::Label,
+                value: 256,
+                max: 255,
+            },
+            overflow
+        );
+        assert_eq!(
+            "label index 256 exceeds the largest supported index 255",
+            overflow.to_string()
+        );
+        assert_eq!(Ok(7), checked_index(7, IndexKind::Feature));
+    }
+
+    #[test]
+    fn test_check_dimensions() {
+        assert!(check_dimensions_as::<u8>(256, 255, 256).is_err());
+        assert!(check_dimensions_as::<u8>(256, 254, 256).is_ok());
+        assert!(check_dimensions_as::<u8>(0, 0, 0).is_ok());
+
+        // The bias feature needs an index of its own
+        assert_eq!(
+            Err(IndexOverflow {
+                kind: IndexKind::Feature,
+                value: 255,
+                max: 255,
+            }),
+            check_dimensions_as::<u8>(1, 255, 1)
+        );
+        assert_eq!(
+            IndexKind::Example,
+            check_dimensions_as::<u8>(257, 1, 1).unwrap_err().kind
+        );
+        assert_eq!(
+            IndexKind::Label,
+            check_dimensions_as::<u8>(1, 1, 257).unwrap_err().kind
+        );
+
+        let max = Index::MAX as usize;
+        assert!(check_dimensions(max + 1, max - 1, max + 1).is_ok());
+        assert_eq!(
+            IndexKind::Feature,
+            check_dimensions(1, max, 1).unwrap_err().kind
+        );
+    }
+}
@@ -7,13 +7,27 @@ for extreme multi-label classification.
 
 pub type Index = u32;
 pub type IndexValueVec = Vec<(Index, f32)>;
+/// Feature index-value pairs that are stored inline up to `N` pairs, so that typical prediction
+/// inputs need no heap allocation.
+pub type FeaturePairs<const N: usize = 128> = smallvec::SmallVec<[(Index, f32); N]>;
 pub type IndexSet = hashbrown::HashSet<Index>;
 pub type DataSet = data::DataSet;
 pub type Model = model::Model;
 
 pub mod data;
+mod float_format;
+mod index;
 mod mat_util;
+mod math;
 pub mod model;
+#[cfg(test)]
+mod test_util;
 mod util;
+mod warnings;
+
+pub use float_format::{FloatFormat, Formatted, FormattedF32};
+pub use index::{check_dimensions, checked_index, IndexKind, IndexOverflow};
+pub use util::CancellationToken;
+pub use warnings::{Warning, Warnings};
 
 pub use rayon; // Re-export Rayon for downstream parallelization control
@@ -1,13 +1,20 @@
+use crate::index::{to_index, IndexKind};
 use crate::Index;
+use half::f16;
 use hashbrown::HashSet;
-use itertools::Itertools;
+use itertools::{izip, Itertools};
 use ndarray::ArrayViewMut1;
 use num_traits::{Float, Num, Unsigned, Zero};
 use ordered_float::NotNan;
+use rayon::prelude::*;
 use serde::{Deserialize, Serialize};
+use smallvec::SmallVec;
 use sprs::{CsMatBase, CsMatI, CsVecViewI, SpIndex};
+use std::borrow::Cow;
 use std::fmt::Display;
 use std::ops::{AddAssign, Deref, DerefMut, DivAssign};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
 
 pub type SparseVec = sprs::CsVecI<f32, Index>;
 pub type SparseVecView<'a> = sprs::CsVecViewI<'a, f32, Index>;
@@ -22,10 +29,42 @@ pub type DenseMatViewMut<'a> = ndarray::ArrayViewMut2<'a, f32>;
 /// The matrix has dimensions (# of features) x (# of classes). Compare to storing the weights
 /// as a (# of classes) x (# of features) matrix, this storage is more cache friendly when the
 /// matrix is dense.
-#[derive(Clone, Debug, Serialize, Deserialize)]
+///
+/// Memory-mapped matrices are serialized as the dense or sparse matrices they map, so they're
+/// loaded back as such.
+#[derive(Clone, Debug, Deserialize)]
 pub enum WeightMat {
     Sparse(LilMat),
     Dense(DenseMat),
+    /// Dense, with 8-bit values; see [`QuantizedMat`].
+    Quantized(QuantizedMat),
+    /// Dense or sparse, with half-precision values; see [`HalfMat`].
+    Half(HalfMat),
+    /// Dense or sparse, with values in a memory-mapped file; see [`MappedMat`].
+    #[serde(skip_deserializing)]
+    Mapped(MappedMat),
+}
+
+/// The serialized form of [`WeightMat`], with the same name and variants.
+#[derive(Serialize)]
+#[serde(rename = "WeightMat")]
+enum SerializedWeightMat<'a> {
+    Sparse(&'a LilMat),
+    Dense(&'a DenseMat),
+    Quantized(&'a QuantizedMat),
+    Half(&'a HalfMat),
+}
+
+impl Serialize for WeightMat {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            Self::Sparse(m) => SerializedWeightMat::Sparse(m).serialize(serializer),
+            Self::Dense(m) => SerializedWeightMat::Dense(m).serialize(serializer),
+            Self::Quantized(m) => SerializedWeightMat::Quantized(m).serialize(serializer),
+            Self::Half(m) => SerializedWeightMat::Half(m).serialize(serializer),
+            Self::Mapped(m) => m.to_in_memory().serialize(serializer),
+        }
+    }
 }
 
 impl WeightMat {
@@ -34,11 +73,50 @@ impl WeightMat {
     /// This is equivalent to dot(vec, mat).
     pub fn t_dot_vec(&self, vec: SparseVecView) -> DenseVec {
         match self {
-            Self::Dense(mat) => mat.t().outer_iter().map(|w| vec.dot_dense(w)).collect(),
+            Self::Dense(_) | Self::Quantized(_) | Self::Half(_) | Self::Mapped(_) => {
+                self.t_dot_vec_with(vec, Vec::new())
+            }
             Self::Sparse(mat) => mat.t_dot_csvec(vec),
         }
     }
 
+    /// Like [`Self::t_dot_vec`], but stores the result in the given buffer instead of allocating.
+    pub fn t_dot_vec_with(&self, vec: SparseVecView, buffer: Vec<f32>) -> DenseVec {
+        let mut out = buffer;
+        out.clear();
+        out.resize(self.shape().1, 0.);
+        match self {
+            Self::Dense(mat) => dense_t_dot_csvec_into(mat.view(), vec, &mut out),
+            Self::Sparse(mat) => mat.t_dot_csvec_into(vec, &mut out),
+            Self::Quantized(mat) => mat.t_dot_csvec_into(vec, &mut out),
+            Self::Half(mat) => mat.t_dot_csvec_into(vec, &mut out),
+            Self::Mapped(mat) => mat.t_dot_csvec_into(vec, &mut out),
+        }
+        DenseVec::from(out)
+    }
+
+    /// Compute dot products with several sparse vectors after transposing, one row of the result
+    /// for each vector.
+    ///
+    /// This is equivalent to stacking the results of [`Self::t_dot_vec`] for the vectors, e.g.,
+    /// to score examples that reach the same node together, while the matrix stays in caches.
+    pub fn t_dot_mat(&self, vecs: &[SparseVecView]) -> DenseMat {
+        let mut out = DenseMat::zeros((vecs.len(), self.shape().1));
+        for (vec, mut out_row) in vecs.iter().zip(out.outer_iter_mut()) {
+            let out_row = out_row
+                .as_slice_mut()
+                .expect("Rows of a new matrix should be contiguous");
+            match self {
+                Self::Dense(mat) => dense_t_dot_csvec_into(mat.view(), vec.view(), out_row),
+                Self::Sparse(mat) => mat.t_dot_csvec_into(vec.view(), out_row),
+                Self::Quantized(mat) => mat.t_dot_csvec_into(vec.view(), out_row),
+                Self::Half(mat) => mat.t_dot_csvec_into(vec.view(), out_row),
+                Self::Mapped(mat) => mat.t_dot_csvec_into(vec.view(), out_row),
+            }
+        }
+        out
+    }
+
     /// Get the shape of the matrix.
     pub fn shape(&self) -> sprs::Shape {
         match self {
@@ -48,33 +126,240 @@ impl WeightMat {
                 (shape[0], shape[1])
             }
             Self::Sparse(mat) => mat.shape(),
+            Self::Quantized(mat) => mat.shape(),
+            Self::Half(mat) => mat.shape(),
+            Self::Mapped(mat) => mat.shape(),
         }
     }
 
-    /// Returns whether the matrix is dense.
+    /// Returns whether the matrix is stored in dense format with full-precision values.
     pub fn is_dense(&self) -> bool {
+        self.dense_view().is_some()
+    }
+
+    /// A view of the matrix if it's stored in dense format with full-precision values.
+    pub fn dense_view(&self) -> Option<ndarray::ArrayView2<f32>> {
         match self {
-            Self::Dense(_) => true,
-            Self::Sparse(_) => false,
+            Self::Dense(m) => Some(m.view()),
+            Self::Mapped(m) => m.dense_view(),
+            Self::Sparse(_) | Self::Quantized(_) | Self::Half(_) => None,
         }
     }
 
+    /// Returns whether the matrix is stored with quantized values.
+    pub fn is_quantized(&self) -> bool {
+        matches!(self, Self::Quantized(_))
+    }
+
+    /// Returns whether the matrix is stored with half-precision values.
+    pub fn is_half(&self) -> bool {
+        matches!(self, Self::Half(_))
+    }
+
     /// Returns the ratio of non-zero elements in the matrix when it's sparse.
     pub fn density(&self) -> f32 {
         match self {
-            Self::Dense(_) => 1.,
+            Self::Dense(_) | Self::Quantized(_) => 1.,
             Self::Sparse(m) => m.density() as f32,
+            Self::Half(m) => m.density(),
+            Self::Mapped(m) => m.density(),
         }
     }
 
     /// Store the matrix in dense format if it's not already so.
+    ///
+    /// Quantized matrices are already stored densely, and are kept as they are, while
+    /// half-precision matrices stay in half precision. Sparse memory-mapped matrices are copied
+    /// into memory.
     pub fn densify(&mut self) {
         *self = match self {
-            Self::Dense(_) => {
+            Self::Dense(_) | Self::Quantized(_) => {
                 return; // Already dense, do nothing
             }
             Self::Sparse(m) => Self::Dense(m.to_dense()),
+            Self::Half(m) => {
+                m.densify();
+                return;
+            }
+            Self::Mapped(m) if m.is_dense() => return,
+            Self::Mapped(m) => Self::Dense(m.to_dense()),
+        };
+    }
+
+    /// Store the matrix in sparse format if it's not already so.
+    ///
+    /// Quantized matrices are stored densely by design, and are kept as they are, while
+    /// half-precision matrices stay in half precision. Dense memory-mapped matrices are copied
+    /// into memory.
+    pub fn sparsify(&mut self) {
+        *self = match self {
+            Self::Sparse(_) | Self::Quantized(_) => {
+                return; // Already sparse or can't be, do nothing
+            }
+            Self::Dense(m) => Self::Sparse(LilMat::from_dense(m)),
+            Self::Half(m) => {
+                m.sparsify();
+                return;
+            }
+            Self::Mapped(m) if !m.is_dense() => return,
+            Self::Mapped(m) => Self::Sparse(LilMat::from_dense(&m.to_dense())),
+        };
+    }
+
+    /// A copy of the matrix in dense format.
+    pub fn to_dense(&self) -> DenseMat {
+        match self {
+            Self::Dense(m) => m.clone(),
+            Self::Sparse(m) => m.to_dense(),
+            Self::Quantized(m) => m.to_dense(),
+            Self::Half(m) => m.to_dense(),
+            Self::Mapped(m) => m.to_dense(),
+        }
+    }
+
+    /// The number of non-zero elements in the matrix.
+    pub fn nnz(&self) -> usize {
+        match self {
+            Self::Dense(m) => m.iter().filter(|v| !v.is_zero()).count(),
+            Self::Sparse(m) => sprs::SparseMat::nnz(m),
+            Self::Quantized(m) => m.nonzero_entries().count(),
+            Self::Half(m) => m.nonzero_entries().count(),
+            Self::Mapped(m) => m.nnz(),
+        }
+    }
+
+    /// The mean of the columns of the matrix.
+    pub fn column_mean(&self) -> DenseVec {
+        let mean = |m: &DenseMat| {
+            m.mean_axis(ndarray::Axis(1))
+                .unwrap_or_else(|| DenseVec::zeros(m.nrows()))
+        };
+        match self {
+            Self::Dense(m) => mean(m),
+            Self::Sparse(m) => m.column_mean(),
+            Self::Quantized(m) => mean(&m.to_dense()),
+            Self::Half(m) => m.to_single().column_mean(),
+            Self::Mapped(m) => m.to_in_memory().column_mean(),
+        }
+    }
+
+    /// The memory used by the elements of the matrix and their indices, in bytes.
+    pub fn mem_size(&self) -> usize {
+        match self {
+            Self::Dense(m) => std::mem::size_of::<f32>() * m.len(),
+            Self::Sparse(m) => m.mem_size(),
+            Self::Quantized(m) => m.mem_size(),
+            Self::Half(m) => m.mem_size(),
+            Self::Mapped(m) => m.mem_size(),
+        }
+    }
+
+    /// Read every element of the matrix and its indices in memory order, returning a checksum of
+    /// them; this brings the memory of [`Self::mem_size`] into caches and page tables.
+    pub fn touch(&self) -> u64 {
+        match self {
+            Self::Dense(m) => m
+                .iter()
+                .fold(0u64, |sum, v| sum.wrapping_add(v.to_bits() as u64)),
+            Self::Sparse(m) => m.touch(),
+            Self::Quantized(m) => m.touch(),
+            Self::Half(m) => m.touch(),
+            Self::Mapped(m) => m.touch(),
+        }
+    }
+
+    /// The smallest and largest absolute values among non-zero elements, if there are any.
+    pub fn nonzero_abs_range(&self) -> Option<(f32, f32)> {
+        let values: Box<dyn Iterator<Item = f32>> = match self {
+            Self::Dense(m) => Box::new(m.iter().copied()),
+            Self::Sparse(m) => Box::new(m.data.iter().copied()),
+            Self::Quantized(m) => Box::new(m.nonzero_entries().map(|(_, _, v)| v)),
+            Self::Half(m) => Box::new(m.nonzero_entries().map(|(_, _, v)| v)),
+            Self::Mapped(m) => Box::new(m.nonzero_entries().map(|(_, _, v)| v)),
         };
+        values
+            .filter(|v| !v.is_zero())
+            .map(|v| v.abs())
+            .fold(None, |range, v| match range {
+                None => Some((v, v)),
+                Some((min, max)) => Some((min.min(v), max.max(v))),
+            })
+    }
+
+    /// Iterate over non-zero elements as (row, column, value), in row-major order regardless of
+    /// the storage format.
+    pub fn nonzero_entries(&self) -> Box<dyn Iterator<Item = (usize, usize, f32)> + '_> {
+        match self {
+            Self::Dense(m) => Box::new(
+                m.indexed_iter()
+                    .filter(|(_, v)| !v.is_zero())
+                    .map(|((row, col), &v)| (row, col, v)),
+            ),
+            Self::Sparse(m) => Box::new(m.nonzero_entries()),
+            Self::Quantized(m) => Box::new(m.nonzero_entries()),
+            Self::Half(m) => Box::new(m.nonzero_entries()),
+            Self::Mapped(m) => m.nonzero_entries(),
+        }
+    }
+
+    /// Set elements with absolute values smaller than the threshold to zero, returning the
+    /// number of non-zero elements removed.
+    pub fn prune_with_threshold(&mut self, threshold: f32) -> usize {
+        match self {
+            Self::Dense(m) => {
+                let mut n_removed = 0;
+                m.mapv_inplace(|v| {
+                    if !v.is_zero() && v.abs() < threshold {
+                        n_removed += 1;
+                        0.
+                    } else {
+                        v
+                    }
+                });
+                n_removed
+            }
+            Self::Sparse(m) => m.prune_with_threshold(threshold),
+            Self::Quantized(m) => m.prune_with_threshold(threshold),
+            Self::Half(m) => m.prune_with_threshold(threshold),
+            Self::Mapped(m) => {
+                *self = m.to_in_memory();
+                self.prune_with_threshold(threshold)
+            }
+        }
+    }
+
+    /// Store the matrix in whichever of the dense and sparse formats takes up less memory, as in
+    /// [`Self::from_rows`]. Quantized and half-precision matrices are kept as they are.
+    pub fn shrink_storage(&mut self) {
+        let (rows, cols) = self.shape();
+        let dense_size = std::mem::size_of::<f32>() * rows * cols;
+        match self {
+            Self::Sparse(m) if dense_size <= m.mem_size() => *self = Self::Dense(m.to_dense()),
+            Self::Dense(m) => {
+                let sparse = LilMat::from_dense(m);
+                if sparse.mem_size() < dense_size {
+                    *self = Self::Sparse(sparse);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Create a new matrix with only the given rows, which must be sorted and distinct, in that
+    /// order.
+    ///
+    /// Dense and sparse matrices keep their format. Quantized matrices are quantized again, and
+    /// mapped matrices are copied into memory.
+    pub fn select_rows(&self, rows: &[usize]) -> Self {
+        match self {
+            Self::Dense(m) => Self::Dense(m.select(ndarray::Axis(0), rows)),
+            Self::Sparse(m) => Self::Sparse(m.select_rows(rows)),
+            Self::Quantized(m) => Self::Quantized(QuantizedMat::quantize(
+                &Self::Dense(m.to_dense()).select_rows(rows),
+            )),
+            Self::Half(m) => Self::Half(HalfMat::from_single(&m.to_single().select_rows(rows))),
+            Self::Mapped(m) => m.to_in_memory().select_rows(rows),
+        }
     }
 
     /// Create a new matrix from sparse row vectors.
@@ -95,6 +380,170 @@ impl WeightMat {
             Self::Sparse(mat)
         }
     }
+
+    /// Find the density above which dense storage makes [`Self::t_dot_vec`] faster than sparse
+    /// storage on the current machine.
+    ///
+    /// Both storage formats are timed on random matrices of each sample shape and density, with
+    /// random input vectors of `input_nnz` non-zeros, for about
+    /// [`DEFAULT_CALIBRATION_TIME`] in total. The densities must be ascending and within
+    /// `(0, 1]`; the result is interpolated between the two sampled densities where dense
+    /// storage becomes faster, and is 1 if it never does.
+    pub fn calibrate_crossover(
+        sample_shapes: &[(usize, usize)],
+        sample_densities: &[f32],
+        input_nnz: usize,
+    ) -> f32 {
+        let timings = Self::time_storage_formats(
+            sample_shapes,
+            sample_densities,
+            input_nnz,
+            DEFAULT_CALIBRATION_TIME,
+        );
+        crossover_density(sample_densities, &timings)
+    }
+
+    /// Time [`Self::t_dot_vec`] with both storage formats for [`Self::calibrate_crossover`],
+    /// returning the seconds per product summed over the shapes, as (sparse, dense), for each
+    /// density; the measurements take about the given time in total.
+    pub(crate) fn time_storage_formats(
+        sample_shapes: &[(usize, usize)],
+        sample_densities: &[f32],
+        input_nnz: usize,
+        time_budget: std::time::Duration,
+    ) -> Vec<(f64, f64)> {
+        use rand::prelude::*;
+
+        assert!(!sample_shapes.is_empty(), "No sample shapes given");
+        assert!(!sample_densities.is_empty(), "No sample densities given");
+        assert!(
+            sample_densities
+                .iter()
+                .tuple_windows()
+                .all(|(prev, next)| prev < next)
+                && sample_densities[0] > 0.
+                && *sample_densities.last().unwrap() <= 1.,
+            "Sample densities must be ascending and within (0, 1], but are {:?}",
+            sample_densities
+        );
+
+        // Each measurement repeats products for its share of the budget, and at least once
+        let time_per_measurement =
+            time_budget / (2 * sample_shapes.len() * sample_densities.len()) as u32;
+        let time_kernel = |mat: &WeightMat, vec: SparseVecView| {
+            let start_t = std::time::Instant::now();
+            let mut n_products = 0u32;
+            loop {
+                std::hint::black_box(mat.t_dot_vec(vec));
+                n_products += 1;
+                if start_t.elapsed() >= time_per_measurement {
+                    break;
+                }
+            }
+            start_t.elapsed().as_secs_f64() / n_products as f64
+        };
+
+        let mut rng = rand::rngs::StdRng::seed_from_u64(0);
+        let mut timings = vec![(0., 0.); sample_densities.len()];
+        for &(n_rows, n_cols) in sample_shapes {
+            let mut input_indices =
+                rand::seq::index::sample(&mut rng, n_rows, input_nnz.min(n_rows))
+                    .into_iter()
+                    .map(|i| to_index(i, IndexKind::Feature))
+                    .collect_vec();
+            input_indices.sort_unstable();
+            let input_data = input_indices.iter().map(|_| rng.gen::<f32>()).collect_vec();
+            let input = SparseVec::new(n_rows, input_indices, input_data);
+
+            for (&density, (sparse_secs, dense_secs)) in sample_densities.iter().zip(&mut timings) {
+                let columns = (0..n_cols)
+                    .map(|_| {
+                        let (indices, data): (Vec<_>, Vec<_>) = (0..n_rows)
+                            .filter(|_| rng.gen_bool(density as f64))
+                            .map(|i| (to_index(i, IndexKind::Feature), rng.gen::<f32>() - 0.5))
+                            .unzip();
+                        SparseVec::new(n_rows, indices, data)
+                    })
+                    .collect_vec();
+                let sparse = Self::Sparse(LilMat::from_columns(&columns));
+                let dense = Self::Dense(sparse.to_dense());
+                *sparse_secs += time_kernel(&sparse, input.view());
+                *dense_secs += time_kernel(&dense, input.view());
+            }
+        }
+        timings
+    }
+
+    /// Create a new matrix with the given number of rows from copies of columns of other
+    /// matrices, given as pairs of matrices and column indices, in order.
+    ///
+    /// The matrix is stored in dense format if all the source matrices are dense, and in sparse
+    /// format otherwise.
+    pub fn from_columns_of(n_rows: usize, columns: &[(&WeightMat, usize)]) -> Self {
+        let shape = (n_rows, columns.len());
+        if columns.iter().all(|(mat, _)| mat.is_dense()) {
+            let mut dense = DenseMat::zeros(shape);
+            for (j, &(mat, col)) in columns.iter().enumerate() {
+                if let Some(m) = mat.dense_view() {
+                    dense.column_mut(j).assign(&m.column(col));
+                }
+            }
+            return Self::Dense(dense);
+        }
+
+        // Scan each distinct source matrix once, mapping its columns to their new positions
+        let mut triplets = Vec::new();
+        let mut scanned = Vec::<&WeightMat>::new();
+        for &(mat, _) in columns {
+            if scanned.iter().any(|&other| std::ptr::eq(other, mat)) {
+                continue;
+            }
+            scanned.push(mat);
+            let mut col_to_new_cols = hashbrown::HashMap::<usize, Vec<usize>>::new();
+            for (j, &(other, col)) in columns.iter().enumerate() {
+                if std::ptr::eq(other, mat) {
+                    col_to_new_cols.entry(col).or_default().push(j);
+                }
+            }
+            for (row, col, value) in mat.nonzero_entries() {
+                if let Some(new_cols) = col_to_new_cols.get(&col) {
+                    triplets.extend(new_cols.iter().map(|&j| (row, j, value)));
+                }
+            }
+        }
+
+        triplets.sort_unstable_by_key(|&(row, col, _)| (row, col));
+        let mut lil = LilMat::with_capacity(shape, n_rows.min(triplets.len()), triplets.len());
+        for (row, col, value) in triplets {
+            lil.append_value(row, col, value);
+        }
+        Self::Sparse(lil)
+    }
+}
+
+/// How long [`WeightMat::calibrate_crossover`] measures for in total.
+pub const DEFAULT_CALIBRATION_TIME: std::time::Duration = std::time::Duration::from_secs(1);
+
+/// The density at which the second timing of each pair, for dense storage, first becomes no
+/// larger than the first, for sparse storage, interpolated linearly between sampled densities;
+/// 1 if it never does.
+pub(crate) fn crossover_density(densities: &[f32], timings: &[(f64, f64)]) -> f32 {
+    assert_eq!(densities.len(), timings.len());
+    let mut prev: Option<(f32, f64)> = None;
+    for (&density, &(sparse_secs, dense_secs)) in densities.iter().zip(timings) {
+        let dense_excess = dense_secs - sparse_secs;
+        if dense_excess <= 0. {
+            return match prev {
+                Some((prev_density, prev_excess)) => {
+                    let fraction = prev_excess / (prev_excess - dense_excess);
+                    prev_density + (density - prev_density) * fraction as f32
+                }
+                None => density,
+            };
+        }
+        prev = Some((density, dense_excess));
+    }
+    1.
 }
 
 pub trait IndexValuePairs<IndexT: SpIndex + Unsigned, ValueT: Copy>:
@@ -167,6 +616,14 @@ pub trait OwnedIndexValuePairs<IndexT, ValueT> {
     fn prune_with_threshold(&mut self, threshold: ValueT)
     where
         ValueT: Float;
+
+    /// Merge consecutive pairs with the same index by summing their values.
+    ///
+    /// Assumes that pairs are sorted by indices.
+    fn sum_duplicate_indices(&mut self)
+    where
+        IndexT: PartialEq,
+        ValueT: Copy + AddAssign;
 }
 
 impl<IndexT, ValueT> OwnedIndexValuePairs<IndexT, ValueT> for Vec<(IndexT, ValueT)> {
@@ -176,6 +633,47 @@ impl<IndexT, ValueT> OwnedIndexValuePairs<IndexT, ValueT> for Vec<(IndexT, Value
     {
         self.retain(|&(_, v)| v.abs() >= threshold);
     }
+
+    fn sum_duplicate_indices(&mut self)
+    where
+        IndexT: PartialEq,
+        ValueT: Copy + AddAssign,
+    {
+        self.dedup_by(|(i, v), (j, w)| {
+            if i == j {
+                *w += *v;
+                true
+            } else {
+                false
+            }
+        });
+    }
+}
+
+impl<IndexT, ValueT, const N: usize> OwnedIndexValuePairs<IndexT, ValueT>
+    for SmallVec<[(IndexT, ValueT); N]>
+{
+    fn prune_with_threshold(&mut self, threshold: ValueT)
+    where
+        ValueT: Float,
+    {
+        self.retain(|&mut (_, v)| v.abs() >= threshold);
+    }
+
+    fn sum_duplicate_indices(&mut self)
+    where
+        IndexT: PartialEq,
+        ValueT: Copy + AddAssign,
+    {
+        self.dedup_by(|(i, v), (j, w)| {
+            if i == j {
+                *w += *v;
+                true
+            } else {
+                false
+            }
+        });
+    }
 }
 
 pub fn csrmat_from_index_value_pair_lists<IndexT, ValueT>(
@@ -204,6 +702,184 @@ where
     sprs::CsMatI::new((n_row, n_col), indptr, indices, data)
 }
 
+/// Kernels computing `dot(vec, mat)` for a dense matrix and a sparse vector.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum DenseKernel {
+    /// Dot products of the vector with each column, which are strided in the standard layout;
+    /// the scalar path that products used before [`Self::Unrolled`].
+    Columns,
+    /// The rows of active features scaled and added to the output, four rows at a time and in
+    /// blocks of 8 columns; only for matrices in standard layout.
+    Unrolled,
+}
+
+/// The number of output columns that [`DenseKernel::Unrolled`] updates together, which the
+/// compiler maps to SIMD registers, e.g., a single one with AVX2.
+const DENSE_KERNEL_LANES: usize = 8;
+
+/// Compute `dot(vec, mat)` for a dense matrix into the given zero-filled output, with
+/// [`DenseKernel::Unrolled`] if the matrix is in standard layout, and with
+/// [`DenseKernel::Columns`] otherwise.
+fn dense_t_dot_csvec_into(mat: ndarray::ArrayView2<f32>, vec: SparseVecView, out: &mut [f32]) {
+    let kernel = if mat.is_standard_layout() {
+        DenseKernel::Unrolled
+    } else {
+        DenseKernel::Columns
+    };
+    dense_t_dot_csvec_into_with(kernel, mat, vec, out);
+}
+
+/// Compute `dot(vec, mat)` for a dense matrix into the given zero-filled output with the given
+/// kernel.
+///
+/// Both kernels sum each output element over the active features in the same order, so they give
+/// the same results up to rounding.
+///
+/// # Panics
+///
+/// Panics if [`DenseKernel::Unrolled`] is given a matrix that isn't in standard layout.
+pub(crate) fn dense_t_dot_csvec_into_with(
+    kernel: DenseKernel,
+    mat: ndarray::ArrayView2<f32>,
+    vec: SparseVecView,
+    out: &mut [f32],
+) {
+    debug_assert_eq!(mat.ncols(), out.len());
+    match kernel {
+        DenseKernel::Columns => {
+            for (o, w) in out.iter_mut().zip_eq(mat.t().outer_iter()) {
+                *o = vec.dot_dense(w);
+            }
+        }
+        DenseKernel::Unrolled => {
+            let data = mat
+                .to_slice()
+                .expect("The unrolled kernel needs a matrix in standard layout");
+            unrolled_rows_add_into(data, mat.ncols(), vec, out);
+        }
+    }
+}
+
+/// Add the rows of a row-major matrix with `n_cols` columns, scaled by the values of the sparse
+/// vector at their indices, to the output.
+///
+/// Rows are taken four at a time, so the output is loaded and stored once for every four active
+/// features, and the columns in blocks of [`DENSE_KERNEL_LANES`], whose fixed-size inner loop the
+/// compiler unrolls into SIMD instructions. Sums are written out term by term to keep the order
+/// in which each output element is summed.
+fn unrolled_rows_add_into(data: &[f32], n_cols: usize, vec: SparseVecView, out: &mut [f32]) {
+    const LANES: usize = DENSE_KERNEL_LANES;
+    let n_blocked = n_cols - n_cols % LANES;
+    let row = |i: Index| {
+        let start = i as usize * n_cols;
+        &data[start..start + n_cols]
+    };
+
+    let mut indices = vec.indices().chunks_exact(4);
+    let mut values = vec.data().chunks_exact(4);
+    for (i, v) in (&mut indices).zip(&mut values) {
+        let (r0, r1, r2, r3) = (row(i[0]), row(i[1]), row(i[2]), row(i[3]));
+        let (v0, v1, v2, v3) = (v[0], v[1], v[2], v[3]);
+        for (o, w0, w1, w2, w3) in izip!(
+            out[..n_blocked].chunks_exact_mut(LANES),
+            r0[..n_blocked].chunks_exact(LANES),
+            r1[..n_blocked].chunks_exact(LANES),
+            r2[..n_blocked].chunks_exact(LANES),
+            r3[..n_blocked].chunks_exact(LANES),
+        ) {
+            for (o, &w0, &w1, &w2, &w3) in izip!(o, w0, w1, w2, w3) {
+                *o = *o + v0 * w0 + v1 * w1 + v2 * w2 + v3 * w3;
+            }
+        }
+        for (o, &w0, &w1, &w2, &w3) in izip!(
+            &mut out[n_blocked..],
+            &r0[n_blocked..],
+            &r1[n_blocked..],
+            &r2[n_blocked..],
+            &r3[n_blocked..],
+        ) {
+            *o = *o + v0 * w0 + v1 * w1 + v2 * w2 + v3 * w3;
+        }
+    }
+    for (&i, &v) in indices.remainder().iter().zip(values.remainder()) {
+        for (o, &w) in out.iter_mut().zip(row(i)) {
+            *o += v * w;
+        }
+    }
+}
+
+/// A raw pointer that can be shared between threads, for writing to disjoint positions of a buffer.
+struct SyncPtr<T>(*mut T);
+
+unsafe impl<T: Send> Sync for SyncPtr<T> {}
+
+impl<T> SyncPtr<T> {
+    // NB: accessing the pointer through a method makes closures capture the whole wrapper
+    #[inline]
+    fn get(&self) -> *mut T {
+        self.0
+    }
+}
+
+/// Transpose a CSR matrix in parallel, returning the result in CSR format.
+///
+/// This is a counting sort: the first pass counts entries in each column, and the second pass
+/// scatters entries into their rows in the transposed matrix at the precomputed offsets. The result
+/// is exactly the same as converting the transposed view to CSR format with sprs.
+pub fn fast_transpose(mat: &SparseMat) -> SparseMat {
+    assert!(mat.is_csr());
+    let (n_rows, n_cols) = mat.shape();
+
+    // First pass: count entries in each column
+    let cursors = (0..n_cols).map(|_| AtomicUsize::new(0)).collect_vec();
+    mat.indices().par_iter().for_each(|&j| {
+        cursors[j.index()].fetch_add(1, Ordering::Relaxed);
+    });
+
+    let mut indptr = Vec::<usize>::with_capacity(n_cols + 1);
+    indptr.push(0);
+    for count in &cursors {
+        indptr.push(indptr[indptr.len() - 1] + count.load(Ordering::Relaxed));
+    }
+    // Reuse the counters as write positions
+    cursors
+        .par_iter()
+        .zip(indptr.par_iter())
+        .for_each(|(cursor, &offset)| cursor.store(offset, Ordering::Relaxed));
+
+    // Second pass: scatter entries into rows of the transposed matrix
+    let mut pairs: Vec<(Index, f32)> = vec![(0, 0.); mat.nnz()];
+    {
+        let out = SyncPtr(pairs.as_mut_ptr());
+        (0..n_rows).into_par_iter().for_each(|i| {
+            let row = mat.outer_view(i).unwrap();
+            for (j, &v) in row.iter() {
+                let pos = cursors[j].fetch_add(1, Ordering::Relaxed);
+                // Safety: positions are within the buffer by construction of indptr, and each is
+                // handed out exactly once by the atomic counter, so writes never overlap
+                unsafe {
+                    *out.get().add(pos) = (to_index(i, IndexKind::Row), v);
+                }
+            }
+        });
+    }
+
+    // Entries within each row were written in arbitrary order, so restore the order of indices
+    let mut row_slices = Vec::with_capacity(n_cols);
+    let mut rest = pairs.as_mut_slice();
+    for w in indptr.windows(2) {
+        let (row, tail) = rest.split_at_mut(w[1] - w[0]);
+        row_slices.push(row);
+        rest = tail;
+    }
+    row_slices
+        .into_par_iter()
+        .for_each(|row| row.sort_unstable_by_key(|&(i, _)| i));
+
+    let (indices, data) = pairs.into_iter().unzip();
+    SparseMat::new((n_cols, n_rows), indptr, indices, data)
+}
+
 pub trait CsMatBaseTools<DataT, IndexT: SpIndex, Iptr: SpIndex>: sprs::SparseMat {
     fn copy_outer_dims(&self, indices: &[usize]) -> CsMatI<DataT, IndexT, Iptr>;
 }
@@ -258,121 +934,846 @@ where
 {
     /// Shrinks inner indices of a Sparse matrix.
     ///
-    /// The operation can be reversed by calling remap_inner_indices on the returned
-    /// matrix and mapping.
-    fn shrink_inner_indices(self) -> (Self, Vec<I>) {
-        let new_index_to_old = {
-            let mut old_indices = Vec::with_capacity(self.inner_dims());
-            let mut index_set = HashSet::with_capacity(self.inner_dims());
-            for &i in self.indices() {
-                if index_set.insert(i.index()) {
-                    old_indices.push(i);
+    /// The operation can be reversed by calling remap_inner_indices on the returned
+    /// matrix and mapping.
+    fn shrink_inner_indices(self) -> (Self, Vec<I>) {
+        let new_index_to_old = {
+            let mut old_indices = Vec::with_capacity(self.inner_dims());
+            let mut index_set = HashSet::with_capacity(self.inner_dims());
+            for &i in self.indices() {
+                if index_set.insert(i.index()) {
+                    old_indices.push(i);
+                }
+            }
+            old_indices.sort_unstable();
+            old_indices
+        };
+
+        let old_index_to_new = {
+            let mut lookup = vec![I::zero(); self.inner_dims()];
+            for (new_index, &old_index) in new_index_to_old.iter().enumerate() {
+                lookup[old_index.index()] = I::from::<usize>(new_index).unwrap_or_else(|| {
+                    panic!("Failed to convert usize {} to index type", new_index)
+                });
+            }
+            lookup
+        };
+
+        let mat = self.remap_inner_indices(&old_index_to_new, new_index_to_old.len());
+        (mat, new_index_to_old)
+    }
+
+    /// Remap inner indices according to the given mapping.
+    ///
+    /// The mapping is assumed to be well-formed, i.e. sorted, within range, and without duplicates.
+    fn remap_inner_indices(self, old_index_to_new: &[I], new_inner_dims: usize) -> Self {
+        let outer_dims = self.outer_dims();
+        let is_csr = self.is_csr();
+
+        let (indptr, mut indices, data) = self.into_raw_storage();
+        for index in &mut indices {
+            *index = old_index_to_new[index.index()];
+        }
+        let new_mat = CsMatI::new((outer_dims, new_inner_dims), indptr, indices, data);
+        if is_csr {
+            new_mat
+        } else {
+            new_mat.transpose_into()
+        }
+    }
+}
+
+pub fn csvec_dot_self<N, I>(vec: &CsVecViewI<N, I>) -> N
+where
+    I: SpIndex,
+    N: Num + AddAssign + Copy,
+{
+    let mut prod = N::zero();
+    for &val in vec.data() {
+        prod += val * val;
+    }
+    prod
+}
+
+pub fn dense_add_assign_csvec<N, I>(mut dense_vec: ArrayViewMut1<N>, csvec: CsVecViewI<N, I>)
+where
+    I: sprs::SpIndex,
+    N: Num + Copy + AddAssign,
+{
+    assert_eq!(dense_vec.len(), csvec.dim());
+    for (i, &v) in csvec.iter() {
+        // This is safe because we checked length above
+        unsafe {
+            *dense_vec.uget_mut(i) += v;
+        }
+    }
+}
+
+pub fn dense_add_assign_csvec_mul_scalar<N, I>(
+    mut dense_vec: ArrayViewMut1<N>,
+    csvec: CsVecViewI<N, I>,
+    scalar: N,
+) where
+    I: sprs::SpIndex,
+    N: Num + Copy + AddAssign,
+{
+    assert_eq!(dense_vec.len(), csvec.dim());
+    for (i, &v) in csvec.iter() {
+        // This is safe because we checked length above
+        unsafe {
+            *dense_vec.uget_mut(i) += v * scalar;
+        }
+    }
+}
+
+pub fn dense_vec_l2_normalize<N>(mut vec: ArrayViewMut1<N>)
+where
+    N: Float + DivAssign + ndarray::ScalarOperand,
+{
+    let length = vec.dot(&vec).sqrt();
+    if length > N::from(1e-5).unwrap() {
+        vec /= length;
+    } else {
+        vec.fill(N::zero());
+    }
+}
+
+pub fn find_max<N>(arr: ndarray::ArrayView1<N>) -> Option<(N, usize)>
+where
+    N: Float + Display,
+{
+    if let Some((i, &v)) = arr
+        .indexed_iter()
+        .max_by_key(|(_, &l)| NotNan::new(l).unwrap())
+    {
+        Some((v, i))
+    } else {
+        None
+    }
+}
+
+/// A dense matrix with values quantized to 8 bits.
+///
+/// Each column `j` has its own scale and zero point, and the value of an element stored as `q`
+/// is `scales[j] * (q - zero_points[j])`. The range of each column always includes zero, so zero
+/// elements stay exactly zero. Values are stored row-major, as in [`DenseMat`].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct QuantizedMat {
+    n_rows: usize,
+    n_cols: usize,
+    #[serde(with = "i8_bytes")]
+    values: Vec<i8>,
+    scales: Vec<f32>,
+    #[serde(with = "i8_bytes")]
+    zero_points: Vec<i8>,
+}
+
+impl QuantizedMat {
+    /// Quantize the given matrix, column by column.
+    pub fn quantize(mat: &WeightMat) -> Self {
+        let (n_rows, n_cols) = mat.shape();
+        let mut col_ranges = vec![(0f32, 0f32); n_cols];
+        for (_, col, v) in mat.nonzero_entries() {
+            let (min, max) = &mut col_ranges[col];
+            *min = min.min(v);
+            *max = max.max(v);
+        }
+
+        let mut scales = Vec::with_capacity(n_cols);
+        let mut zero_points = Vec::with_capacity(n_cols);
+        for (min, max) in col_ranges {
+            let scale = if max > min { (max - min) / 255. } else { 1. };
+            scales.push(scale);
+            zero_points.push(Self::clamp(-128. - min / scale));
+        }
+
+        let mut quantized = Self {
+            n_rows,
+            n_cols,
+            values: Vec::new(),
+            scales,
+            zero_points,
+        };
+        quantized.values = quantized
+            .zero_points
+            .iter()
+            .copied()
+            .cycle()
+            .take(n_rows * n_cols)
+            .collect();
+        for (row, col, v) in mat.nonzero_entries() {
+            let (scale, zero_point) = (quantized.scales[col], quantized.zero_points[col]);
+            quantized.values[row * n_cols + col] = Self::clamp(v / scale + zero_point as f32);
+        }
+        quantized
+    }
+
+    fn clamp(v: f32) -> i8 {
+        v.round().max(i8::MIN as f32).min(i8::MAX as f32) as i8
+    }
+
+    fn value(&self, row: usize, col: usize) -> f32 {
+        let q = self.values[row * self.n_cols + col];
+        self.scales[col] * (q as i16 - self.zero_points[col] as i16) as f32
+    }
+
+    /// Get the shape of the matrix.
+    pub fn shape(&self) -> sprs::Shape {
+        (self.n_rows, self.n_cols)
+    }
+
+    /// The value of each non-zero element after dequantization, with its row and column, in
+    /// row-major order.
+    pub fn nonzero_entries(&self) -> impl Iterator<Item = (usize, usize, f32)> + '_ {
+        (0..self.n_rows)
+            .flat_map(move |row| (0..self.n_cols).map(move |col| (row, col)))
+            .filter(move |&(row, col)| {
+                self.values[row * self.n_cols + col] != self.zero_points[col]
+            })
+            .map(move |(row, col)| (row, col, self.value(row, col)))
+    }
+
+    /// A copy of the matrix in dense format, after dequantization.
+    pub fn to_dense(&self) -> DenseMat {
+        DenseMat::from_shape_fn(self.shape(), |(row, col)| self.value(row, col))
+    }
+
+    /// The memory used by the values of the matrix and their scales and zero points, in bytes.
+    pub fn mem_size(&self) -> usize {
+        std::mem::size_of_val(self.values.as_slice())
+            + std::mem::size_of_val(self.scales.as_slice())
+            + std::mem::size_of_val(self.zero_points.as_slice())
+    }
+
+    /// Read every value, scale, and zero point in memory order, returning a checksum of them.
+    pub fn touch(&self) -> u64 {
+        let sum = self
+            .values
+            .iter()
+            .chain(&self.zero_points)
+            .fold(0u64, |sum, &q| sum.wrapping_add(q as u8 as u64));
+        self.scales
+            .iter()
+            .fold(sum, |sum, v| sum.wrapping_add(v.to_bits() as u64))
+    }
+
+    /// Set elements whose dequantized absolute values are smaller than the threshold to zero,
+    /// returning the number of non-zero elements set.
+    pub fn prune_with_threshold(&mut self, threshold: f32) -> usize {
+        let mut n_removed = 0;
+        for row in 0..self.n_rows {
+            for col in 0..self.n_cols {
+                let zero_point = self.zero_points[col];
+                if self.values[row * self.n_cols + col] != zero_point
+                    && self.value(row, col).abs() < threshold
+                {
+                    self.values[row * self.n_cols + col] = zero_point;
+                    n_removed += 1;
+                }
+            }
+        }
+        n_removed
+    }
+
+    /// Add the product of the transposed matrix and a sparse vector to `out`.
+    pub fn t_dot_csvec_into(&self, vec: SparseVecView, out: &mut [f32]) {
+        assert_eq!(
+            self.n_rows,
+            vec.dim(),
+            "Dimension mismatch: {} != {}",
+            self.n_rows,
+            vec.dim()
+        );
+        assert_eq!(self.n_cols, out.len());
+
+        for (row, &v) in vec.iter() {
+            let values = &self.values[row * self.n_cols..(row + 1) * self.n_cols];
+            for (((o, &q), &zero_point), &scale) in out
+                .iter_mut()
+                .zip(values)
+                .zip(&self.zero_points)
+                .zip(&self.scales)
+            {
+                *o += v * scale * (q as i16 - zero_point as i16) as f32;
+            }
+        }
+    }
+}
+
+/// (De)serializing vectors of 8-bit integers as byte strings, which is much more compact than
+/// sequences of integers in CBOR.
+mod i8_bytes {
+    use serde::de::{Deserializer, SeqAccess, Visitor};
+    use serde::ser::Serializer;
+    use std::fmt;
+
+    pub fn serialize<S: Serializer>(values: &[i8], serializer: S) -> Result<S::Ok, S::Error> {
+        let bytes = values.iter().map(|&v| v as u8).collect::<Vec<_>>();
+        serializer.serialize_bytes(&bytes)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<i8>, D::Error> {
+        struct BytesVisitor;
+
+        impl<'de> Visitor<'de> for BytesVisitor {
+            type Value = Vec<i8>;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                formatter.write_str("a byte string or a sequence of 8-bit integers")
+            }
+
+            fn visit_bytes<E>(self, bytes: &[u8]) -> Result<Self::Value, E> {
+                Ok(bytes.iter().map(|&b| b as i8).collect())
+            }
+
+            fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+                let mut values = Vec::with_capacity(seq.size_hint().unwrap_or(0));
+                while let Some(v) = seq.next_element()? {
+                    values.push(v);
+                }
+                Ok(values)
+            }
+        }
+
+        deserializer.deserialize_byte_buf(BytesVisitor)
+    }
+}
+
+/// Half-precision values, (de)serialized as a byte string of their bits in little-endian order,
+/// which is much more compact than a sequence of integers in CBOR.
+#[derive(Debug, Clone, Default)]
+pub struct HalfValues(Vec<f16>);
+
+impl Serialize for HalfValues {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let bytes = self
+            .0
+            .iter()
+            .flat_map(|v| v.to_bits().to_le_bytes())
+            .collect::<Vec<_>>();
+        serializer.serialize_bytes(&bytes)
+    }
+}
+
+impl<'de> Deserialize<'de> for HalfValues {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct BytesVisitor;
+
+        impl<'de> serde::de::Visitor<'de> for BytesVisitor {
+            type Value = HalfValues;
+
+            fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+                formatter.write_str("a byte string of 16-bit floats")
+            }
+
+            fn visit_bytes<E: serde::de::Error>(self, bytes: &[u8]) -> Result<Self::Value, E> {
+                if bytes.len() % 2 != 0 {
+                    return Err(E::invalid_length(bytes.len(), &self));
+                }
+                Ok(HalfValues(
+                    bytes
+                        .chunks_exact(2)
+                        .map(|b| f16::from_bits(u16::from_le_bytes([b[0], b[1]])))
+                        .collect(),
+                ))
+            }
+        }
+
+        deserializer.deserialize_byte_buf(BytesVisitor)
+    }
+}
+
+/// A dense or sparse matrix with half-precision values, which are converted to `f32` on the fly.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum HalfMat {
+    /// Values stored row-major, as in [`DenseMat`].
+    Dense {
+        n_rows: usize,
+        n_cols: usize,
+        values: HalfValues,
+    },
+    /// Values stored in the format of [`LilMat`].
+    Sparse(LilMat<HalfValues>),
+}
+
+impl HalfMat {
+    /// Convert the given matrix to half precision, keeping it sparse if it's sparse.
+    pub fn from_single(mat: &WeightMat) -> Self {
+        let (n_rows, n_cols) = mat.shape();
+        match mat {
+            WeightMat::Sparse(m) => Self::Sparse(LilMat {
+                outer_dim: m.outer_dim,
+                inner_dim: m.inner_dim,
+                indptr: m.indptr.clone(),
+                outer_inds: m.outer_inds.clone(),
+                inner_inds: m.inner_inds.clone(),
+                data: HalfValues(m.data.iter().map(|&v| f16::from_f32(v)).collect()),
+            }),
+            WeightMat::Half(m) => m.clone(),
+            WeightMat::Mapped(m) => Self::from_single(&m.to_in_memory()),
+            WeightMat::Dense(_) | WeightMat::Quantized(_) => Self::Dense {
+                n_rows,
+                n_cols,
+                values: HalfValues(mat.to_dense().iter().map(|&v| f16::from_f32(v)).collect()),
+            },
+        }
+    }
+
+    /// Convert the matrix back to single precision, in the same storage format.
+    pub fn to_single(&self) -> WeightMat {
+        let to_single = |values: &HalfValues| values.0.iter().map(|v| v.to_f32()).collect_vec();
+        match self {
+            Self::Sparse(m) => WeightMat::Sparse(LilMat {
+                outer_dim: m.outer_dim,
+                inner_dim: m.inner_dim,
+                indptr: m.indptr.clone(),
+                outer_inds: m.outer_inds.clone(),
+                inner_inds: m.inner_inds.clone(),
+                data: to_single(&m.data),
+            }),
+            Self::Dense { values, .. } => WeightMat::Dense(
+                DenseMat::from_shape_vec(self.shape(), to_single(values))
+                    .expect("Values should match the shape of the matrix"),
+            ),
+        }
+    }
+
+    /// Get the shape of the matrix.
+    pub fn shape(&self) -> sprs::Shape {
+        match self {
+            Self::Dense { n_rows, n_cols, .. } => (*n_rows, *n_cols),
+            Self::Sparse(m) => (m.outer_dim, m.inner_dim),
+        }
+    }
+
+    /// The ratio of stored elements in the matrix, which is 1 when it's dense.
+    pub fn density(&self) -> f32 {
+        match self {
+            Self::Sparse(m) if m.outer_dim.is_zero() && m.inner_dim.is_zero() => f32::nan(),
+            Self::Sparse(m) => m.data.0.len() as f32 / (m.outer_dim * m.inner_dim) as f32,
+            Self::Dense { .. } => 1.,
+        }
+    }
+
+    /// Returns whether the matrix is stored in dense format.
+    pub fn is_dense(&self) -> bool {
+        matches!(self, Self::Dense { .. })
+    }
+
+    /// Store the matrix in sparse format if it's not already so.
+    pub fn sparsify(&mut self) {
+        if self.is_dense() {
+            *self = Self::from_single(&WeightMat::Sparse(LilMat::from_dense(&self.to_dense())));
+        }
+    }
+
+    /// Store the matrix in dense format if it's not already so.
+    pub fn densify(&mut self) {
+        if !self.is_dense() {
+            *self = Self::from_single(&WeightMat::Dense(self.to_dense()));
+        }
+    }
+
+    /// A copy of the matrix in dense format, in single precision.
+    pub fn to_dense(&self) -> DenseMat {
+        self.to_single().to_dense()
+    }
+
+    /// The memory used by the values of the matrix and their indices, in bytes.
+    pub fn mem_size(&self) -> usize {
+        match self {
+            Self::Dense { values, .. } => std::mem::size_of_val(values.0.as_slice()),
+            Self::Sparse(m) => {
+                std::mem::size_of_val(m.indptr.as_slice())
+                    + std::mem::size_of_val(m.outer_inds.as_slice())
+                    + std::mem::size_of_val(m.inner_inds.as_slice())
+                    + std::mem::size_of_val(m.data.0.as_slice())
+            }
+        }
+    }
+
+    /// Read every value and index in memory order, returning a checksum of them.
+    pub fn touch(&self) -> u64 {
+        let (sum, values) = match self {
+            Self::Dense { values, .. } => (0u64, values),
+            Self::Sparse(m) => {
+                let sum = m
+                    .indptr
+                    .iter()
+                    .fold(0u64, |sum, &i| sum.wrapping_add(i as u64));
+                let sum = m
+                    .outer_inds
+                    .iter()
+                    .chain(&m.inner_inds)
+                    .fold(sum, |sum, &i| sum.wrapping_add(i as u64));
+                (sum, &m.data)
+            }
+        };
+        values
+            .0
+            .iter()
+            .fold(sum, |sum, v| sum.wrapping_add(v.to_bits() as u64))
+    }
+
+    /// Each non-zero element in single precision with its row and column, in row-major order.
+    pub fn nonzero_entries(&self) -> Box<dyn Iterator<Item = (usize, usize, f32)> + '_> {
+        let entries: Box<dyn Iterator<Item = (usize, usize, f16)> + '_> = match self {
+            Self::Sparse(m) => Box::new(m.outer_inds.iter().enumerate().flat_map(
+                move |(i, &outer_ind)| {
+                    (m.indptr[i]..m.indptr[i + 1]).map(move |j| {
+                        (
+                            outer_ind.index_unchecked(),
+                            m.inner_inds[j].index_unchecked(),
+                            m.data.0[j],
+                        )
+                    })
+                },
+            )),
+            Self::Dense { n_cols, values, .. } => Box::new(
+                values
+                    .0
+                    .iter()
+                    .enumerate()
+                    .map(move |(i, &v)| (i / n_cols, i % n_cols, v)),
+            ),
+        };
+        Box::new(
+            entries
+                .map(|(row, col, v)| (row, col, v.to_f32()))
+                .filter(|(_, _, v)| !v.is_zero()),
+        )
+    }
+
+    /// Remove elements with absolute values smaller than the threshold, returning the number of
+    /// elements removed.
+    pub fn prune_with_threshold(&mut self, threshold: f32) -> usize {
+        let mut mat = self.to_single();
+        let n_removed = mat.prune_with_threshold(threshold);
+        *self = Self::from_single(&mat);
+        n_removed
+    }
+
+    /// Add the product of the transposed matrix and a sparse vector to `out`.
+    pub fn t_dot_csvec_into(&self, vec: SparseVecView, out: &mut [f32]) {
+        let (n_rows, n_cols) = self.shape();
+        assert_eq!(
+            n_rows,
+            vec.dim(),
+            "Dimension mismatch: {} != {}",
+            n_rows,
+            vec.dim()
+        );
+        assert_eq!(n_cols, out.len());
+        match self {
+            Self::Sparse(m) => lil_t_dot_csvec_into(
+                &m.indptr,
+                &m.outer_inds,
+                &m.inner_inds,
+                &m.data.0,
+                f16::to_f32,
+                vec,
+                out,
+            ),
+            Self::Dense { values, .. } => {
+                for (row, &v) in vec.iter() {
+                    let values = &values.0[row * n_cols..(row + 1) * n_cols];
+                    for (o, w) in out.iter_mut().zip(values) {
+                        *o += v * w.to_f32();
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// The flat arrays holding the elements of a full-precision matrix, as stored in files that can be
+/// memory-mapped.
+#[derive(Clone, Debug)]
+pub(crate) enum FlatArrays<'a> {
+    /// Values in row-major order.
+    Dense {
+        shape: (usize, usize),
+        values: Cow<'a, [f32]>,
+    },
+    /// Indices and values in the storage format of [`LilMat`].
+    Sparse {
+        shape: (usize, usize),
+        indptr: &'a [usize],
+        outer_inds: &'a [Index],
+        inner_inds: &'a [Index],
+        data: &'a [f32],
+    },
+}
+
+impl WeightMat {
+    /// The flat arrays holding the elements of the matrix, unless it's quantized or in half
+    /// precision.
+    pub(crate) fn flat_arrays(&self) -> Option<FlatArrays> {
+        match self {
+            Self::Dense(m) => Some(FlatArrays::Dense {
+                shape: m.dim(),
+                values: m
+                    .as_slice()
+                    .map_or_else(|| Cow::Owned(m.iter().copied().collect()), Cow::Borrowed),
+            }),
+            Self::Sparse(m) => Some(FlatArrays::Sparse {
+                shape: m.shape(),
+                indptr: &m.indptr,
+                outer_inds: &m.outer_inds,
+                inner_inds: &m.inner_inds,
+                data: &m.data,
+            }),
+            Self::Mapped(m) => Some(m.arrays.clone()),
+            Self::Quantized(_) | Self::Half(_) => None,
+        }
+    }
+}
+
+/// A dense or sparse matrix whose elements stay in a memory-mapped file.
+///
+/// Products are computed directly off the mapping, so the elements are never copied, and
+/// processes mapping the same file share its pages. Operations that modify the matrix copy it into
+/// memory first.
+#[derive(Clone, Debug)]
+pub struct MappedMat {
+    // NB: The arrays borrow from the memory owned by `_mmap`. The mapping never moves, since it's
+    // not backed by the struct itself, and every copy of the matrix keeps it alive. The `'static`
+    // lifetime must not leave the struct: the arrays are only handed out borrowed from `self`.
+    arrays: FlatArrays<'static>,
+    _mmap: Arc<memmap2::Mmap>,
+}
+
+impl MappedMat {
+    /// Create a matrix from the given arrays, checking that their lengths are consistent.
+    ///
+    /// Column indices of sparse matrices aren't checked, to avoid reading all of them; products
+    /// panic if they're out of range.
+    ///
+    /// # Safety
+    ///
+    /// The arrays must be within the given mapping, except for owned dense values. The matrix
+    /// keeps them for as long as it keeps the mapping, regardless of the lifetime they're given
+    /// with.
+    pub(crate) unsafe fn new(
+        arrays: FlatArrays<'_>,
+        mmap: Arc<memmap2::Mmap>,
+    ) -> Result<Self, String> {
+        match &arrays {
+            FlatArrays::Dense { shape, values } => {
+                if values.len() != shape.0 * shape.1 {
+                    return Err(format!(
+                        "Expected {} values for shape {:?}, but got {}",
+                        shape.0 * shape.1,
+                        shape,
+                        values.len()
+                    ));
                 }
             }
-            old_indices.sort_unstable();
-            old_indices
-        };
-
-        let old_index_to_new = {
-            let mut lookup = vec![I::zero(); self.inner_dims()];
-            for (new_index, &old_index) in new_index_to_old.iter().enumerate() {
-                lookup[old_index.index()] = I::from::<usize>(new_index).unwrap_or_else(|| {
-                    panic!("Failed to convert usize {} to index type", new_index)
-                });
+            FlatArrays::Sparse {
+                shape,
+                indptr,
+                outer_inds,
+                inner_inds,
+                data,
+            } => {
+                if indptr.len() != outer_inds.len() + 1
+                    || indptr.first() != Some(&0)
+                    || indptr.last() != Some(&data.len())
+                    || inner_inds.len() != data.len()
+                {
+                    return Err("Inconsistent lengths of sparse matrix arrays".to_owned());
+                }
+                if indptr.windows(2).any(|w| w[0] > w[1])
+                    || outer_inds.windows(2).any(|w| w[0] >= w[1])
+                    || outer_inds.last().map_or(false, |&i| i as usize >= shape.0)
+                {
+                    return Err("Invalid row indices of sparse matrix".to_owned());
+                }
             }
-            lookup
-        };
+        }
+        Ok(Self {
+            // Safety: the arrays are within the mapping, which the matrix keeps alive
+            arrays: std::mem::transmute::<FlatArrays<'_>, FlatArrays<'static>>(arrays),
+            _mmap: mmap,
+        })
+    }
 
-        let mat = self.remap_inner_indices(&old_index_to_new, new_index_to_old.len());
-        (mat, new_index_to_old)
+    /// Get the shape of the matrix.
+    pub fn shape(&self) -> sprs::Shape {
+        match self.arrays {
+            FlatArrays::Dense { shape, .. } | FlatArrays::Sparse { shape, .. } => shape,
+        }
     }
 
-    /// Remap inner indices according to the given mapping.
-    ///
-    /// The mapping is assumed to be well-formed, i.e. sorted, within range, and without duplicates.
-    fn remap_inner_indices(self, old_index_to_new: &[I], new_inner_dims: usize) -> Self {
-        let outer_dims = self.outer_dims();
-        let is_csr = self.is_csr();
+    /// Returns whether the matrix is stored in dense format.
+    pub fn is_dense(&self) -> bool {
+        matches!(self.arrays, FlatArrays::Dense { .. })
+    }
 
-        let (indptr, mut indices, data) = self.into_raw_storage();
-        for index in &mut indices {
-            *index = old_index_to_new[index.index()];
+    /// A view of the matrix if it's stored in dense format.
+    pub fn dense_view(&self) -> Option<ndarray::ArrayView2<f32>> {
+        match &self.arrays {
+            FlatArrays::Dense { shape, values } => Some(
+                ndarray::ArrayView2::from_shape(*shape, values.as_ref())
+                    .expect("Values should match the shape of the matrix"),
+            ),
+            FlatArrays::Sparse { .. } => None,
         }
-        let new_mat = CsMatI::new((outer_dims, new_inner_dims), indptr, indices, data);
-        if is_csr {
-            new_mat
-        } else {
-            new_mat.transpose_into()
+    }
+
+    /// The ratio of stored elements in the matrix, which is 1 when it's dense.
+    pub fn density(&self) -> f32 {
+        match self.arrays {
+            FlatArrays::Dense { .. } => 1.,
+            FlatArrays::Sparse { shape, .. } if shape.0.is_zero() && shape.1.is_zero() => {
+                f32::nan()
+            }
+            FlatArrays::Sparse { shape, data, .. } => {
+                data.len() as f32 / (shape.0 * shape.1) as f32
+            }
         }
     }
-}
 
-pub fn csvec_dot_self<N, I>(vec: &CsVecViewI<N, I>) -> N
-where
-    I: SpIndex,
-    N: Num + AddAssign + Copy,
-{
-    let mut prod = N::zero();
-    for &val in vec.data() {
-        prod += val * val;
+    /// The number of non-zero elements in the matrix.
+    pub fn nnz(&self) -> usize {
+        match &self.arrays {
+            FlatArrays::Dense { values, .. } => values.iter().filter(|v| !v.is_zero()).count(),
+            FlatArrays::Sparse { data, .. } => data.len(),
+        }
     }
-    prod
-}
 
-pub fn dense_add_assign_csvec<N, I>(mut dense_vec: ArrayViewMut1<N>, csvec: CsVecViewI<N, I>)
-where
-    I: sprs::SpIndex,
-    N: Num + Copy + AddAssign,
-{
-    assert_eq!(dense_vec.len(), csvec.dim());
-    for (i, &v) in csvec.iter() {
-        // This is safe because we checked length above
-        unsafe {
-            *dense_vec.uget_mut(i) += v;
+    /// A copy of the matrix in memory, in the same storage format.
+    pub fn to_in_memory(&self) -> WeightMat {
+        match self.arrays {
+            FlatArrays::Dense { .. } => WeightMat::Dense(self.to_dense()),
+            FlatArrays::Sparse {
+                shape,
+                indptr,
+                outer_inds,
+                inner_inds,
+                data,
+            } => WeightMat::Sparse(LilMat {
+                outer_dim: shape.0,
+                inner_dim: shape.1,
+                indptr: indptr.to_vec(),
+                outer_inds: outer_inds.to_vec(),
+                inner_inds: inner_inds.to_vec(),
+                data: data.to_vec(),
+            }),
         }
     }
-}
 
-pub fn dense_add_assign_csvec_mul_scalar<N, I>(
-    mut dense_vec: ArrayViewMut1<N>,
-    csvec: CsVecViewI<N, I>,
-    scalar: N,
-) where
-    I: sprs::SpIndex,
-    N: Num + Copy + AddAssign,
-{
-    assert_eq!(dense_vec.len(), csvec.dim());
-    for (i, &v) in csvec.iter() {
-        // This is safe because we checked length above
-        unsafe {
-            *dense_vec.uget_mut(i) += v * scalar;
+    /// A copy of the matrix in memory, in dense format.
+    pub fn to_dense(&self) -> DenseMat {
+        match self.dense_view() {
+            Some(view) => view.to_owned(),
+            None => self.to_in_memory().to_dense(),
         }
     }
-}
 
-pub fn dense_vec_l2_normalize<N>(mut vec: ArrayViewMut1<N>)
-where
-    N: Float + DivAssign + ndarray::ScalarOperand,
-{
-    let length = vec.dot(&vec).sqrt();
-    if length > N::from(1e-5).unwrap() {
-        vec /= length;
-    } else {
-        vec.fill(N::zero());
+    /// The size of the mapped elements and their indices, in bytes.
+    pub fn mem_size(&self) -> usize {
+        match &self.arrays {
+            FlatArrays::Dense { values, .. } => std::mem::size_of_val(values.as_ref()),
+            FlatArrays::Sparse {
+                indptr,
+                outer_inds,
+                inner_inds,
+                data,
+                ..
+            } => {
+                std::mem::size_of_val(*indptr)
+                    + std::mem::size_of_val(*outer_inds)
+                    + std::mem::size_of_val(*inner_inds)
+                    + std::mem::size_of_val(*data)
+            }
+        }
     }
-}
 
-pub fn find_max<N>(arr: ndarray::ArrayView1<N>) -> Option<(N, usize)>
-where
-    N: Float + Display,
-{
-    if let Some((i, &v)) = arr
-        .indexed_iter()
-        .max_by_key(|(_, &l)| NotNan::new(l).unwrap())
-    {
-        Some((v, i))
-    } else {
-        None
+    /// Read every element and index in memory order, returning a checksum of them; this faults
+    /// in the mapped pages.
+    pub fn touch(&self) -> u64 {
+        match &self.arrays {
+            FlatArrays::Dense { values, .. } => values
+                .iter()
+                .fold(0u64, |sum, v| sum.wrapping_add(v.to_bits() as u64)),
+            FlatArrays::Sparse {
+                indptr,
+                outer_inds,
+                inner_inds,
+                data,
+                ..
+            } => {
+                let sum = indptr
+                    .iter()
+                    .fold(0u64, |sum, &i| sum.wrapping_add(i as u64));
+                let sum = outer_inds
+                    .iter()
+                    .chain(inner_inds.iter())
+                    .fold(sum, |sum, &i| sum.wrapping_add(i as u64));
+                data.iter()
+                    .fold(sum, |sum, v| sum.wrapping_add(v.to_bits() as u64))
+            }
+        }
+    }
+
+    /// Each non-zero element with its row and column, in row-major order.
+    pub fn nonzero_entries(&self) -> Box<dyn Iterator<Item = (usize, usize, f32)> + '_> {
+        match &self.arrays {
+            FlatArrays::Dense { shape, values } => Box::new(
+                values
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, v)| !v.is_zero())
+                    .map(move |(i, &v)| (i / shape.1, i % shape.1, v)),
+            ),
+            FlatArrays::Sparse {
+                indptr,
+                outer_inds,
+                inner_inds,
+                data,
+                ..
+            } => Box::new(
+                outer_inds
+                    .iter()
+                    .enumerate()
+                    .flat_map(move |(i, &outer_ind)| {
+                        (indptr[i]..indptr[i + 1]).map(move |j| {
+                            (
+                                outer_ind.index_unchecked(),
+                                inner_inds[j].index_unchecked(),
+                                data[j],
+                            )
+                        })
+                    }),
+            ),
+        }
+    }
+
+    /// Add the product of the transposed matrix and a sparse vector to `out`.
+    pub fn t_dot_csvec_into(&self, vec: SparseVecView, out: &mut [f32]) {
+        let (n_rows, n_cols) = self.shape();
+        assert_eq!(
+            n_rows,
+            vec.dim(),
+            "Dimension mismatch: {} != {}",
+            n_rows,
+            vec.dim()
+        );
+        assert_eq!(n_cols, out.len());
+
+        match &self.arrays {
+            FlatArrays::Dense { .. } => {
+                dense_t_dot_csvec_into(self.dense_view().unwrap(), vec, out);
+            }
+            FlatArrays::Sparse {
+                indptr,
+                outer_inds,
+                inner_inds,
+                data,
+                ..
+            } => lil_t_dot_csvec_into(indptr, outer_inds, inner_inds, data, |v| v, vec, out),
+        }
     }
 }
 
@@ -385,15 +1786,15 @@ where
 /// indices, respectively. Specifically, the matrix has `indptr.len() - 1` non-empty rows.
 /// The `i`-th non-empty row has index `outer_inds[i]`, and the non-zero values in that row
 /// have column indices `inner_inds[indptr[i]..indptr[i + 1]]` and corresponding values
-/// `data[indptr[i]..indptr[i+1]]`.
+/// `data[indptr[i]..indptr[i+1]]`. Values are stored as `f32`, except in [`HalfMat`].
 #[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct LilMat {
+pub struct LilMat<V = Vec<f32>> {
     outer_dim: usize,
     inner_dim: usize,
     indptr: Vec<usize>,
     outer_inds: Vec<Index>,
     inner_inds: Vec<Index>,
-    data: Vec<f32>,
+    data: V,
 }
 
 impl LilMat {
@@ -464,6 +1865,17 @@ impl LilMat {
         mat
     }
 
+    /// Create a new matrix with the non-zero elements of a dense matrix.
+    pub fn from_dense(mat: &DenseMat) -> Self {
+        let (rows, cols) = mat.dim();
+        let nnz = mat.iter().filter(|v| !v.is_zero()).count();
+        let mut sparse = Self::with_capacity((rows, cols), rows.min(nnz), nnz);
+        for ((row, col), &v) in mat.indexed_iter() {
+            sparse.append_value(row, col, v);
+        }
+        sparse
+    }
+
     /// Get the shape of the matrix.
     ///
     /// Note that here we assume the matrix is stored column-first, so the outer dimension is
@@ -495,7 +1907,10 @@ impl LilMat {
         assert!(outer_ind < self.outer_dim, "Outer index out of range");
         assert!(inner_ind < self.inner_dim, "Inner index out of range");
 
-        let (outer_ind, inner_ind) = (Index::from_usize(outer_ind), Index::from_usize(inner_ind));
+        let (outer_ind, inner_ind) = (
+            to_index(outer_ind, IndexKind::Row),
+            to_index(inner_ind, IndexKind::Column),
+        );
 
         // When either the matrix is empty, or the last outer index is strictly less than
         // the new one, we are appending to a new outer index.
@@ -529,6 +1944,65 @@ impl LilMat {
         );
     }
 
+    /// Remove elements with absolute values smaller than the threshold, returning the number of
+    /// elements removed.
+    pub fn prune_with_threshold(&mut self, threshold: f32) -> usize {
+        let nnz = self.data.len();
+        let mut pruned = Self::with_capacity(self.shape(), self.outer_inds.len(), nnz);
+        for (i, &outer_ind) in self.outer_inds.iter().enumerate() {
+            for j in self.indptr[i]..self.indptr[i + 1] {
+                if self.data[j].abs() >= threshold {
+                    pruned.append_value(
+                        outer_ind.index_unchecked(),
+                        self.inner_inds[j].index_unchecked(),
+                        self.data[j],
+                    );
+                }
+            }
+        }
+        *self = pruned;
+        nnz - self.data.len()
+    }
+
+    /// Create a new matrix with only the given rows, which must be sorted and distinct, in that
+    /// order.
+    pub fn select_rows(&self, rows: &[usize]) -> Self {
+        let mut selected = Self::with_capacity(
+            (rows.len(), self.inner_dim),
+            rows.len().min(self.outer_inds.len()),
+            self.data.len(),
+        );
+        for (i, &outer_ind) in self.outer_inds.iter().enumerate() {
+            if let Ok(new_row) = rows.binary_search(&outer_ind.index_unchecked()) {
+                for j in self.indptr[i]..self.indptr[i + 1] {
+                    selected.append_value(
+                        new_row,
+                        self.inner_inds[j].index_unchecked(),
+                        self.data[j],
+                    );
+                }
+            }
+        }
+        selected
+    }
+
+    /// Iterate over non-zero elements as (outer index, inner index, value), in order.
+    pub fn nonzero_entries(&self) -> impl Iterator<Item = (usize, usize, f32)> + '_ {
+        self.outer_inds
+            .iter()
+            .enumerate()
+            .flat_map(move |(i, &outer_ind)| {
+                (self.indptr[i]..self.indptr[i + 1]).map(move |j| {
+                    (
+                        outer_ind.index_unchecked(),
+                        self.inner_inds[j].index_unchecked(),
+                        self.data[j],
+                    )
+                })
+            })
+            .filter(|&(_, _, v)| !v.is_zero())
+    }
+
     /// Assign non-zero values to a dense matrix.
     pub fn assign_to_dense(&self, mut array: DenseMatViewMut) {
         for ((&ind_l, &ind_r), &outer_ind) in self
@@ -558,6 +2032,21 @@ impl LilMat {
         dense_mat
     }
 
+    /// The mean of the columns of the matrix.
+    pub fn column_mean(&self) -> DenseVec {
+        let (rows, cols) = self.shape();
+        let mut mean = DenseVec::zeros(rows);
+        if cols > 0 {
+            for (i, &row) in self.outer_inds.iter().enumerate() {
+                let sum = self.data[self.indptr[i]..self.indptr[i + 1]]
+                    .iter()
+                    .sum::<f32>();
+                mean[row.index_unchecked()] = sum / cols as f32;
+            }
+        }
+        mean
+    }
+
     /// The size in memory in bytes.
     pub fn mem_size(&self) -> usize {
         std::mem::size_of_val(self.indptr.as_slice())
@@ -566,10 +2055,33 @@ impl LilMat {
             + std::mem::size_of_val(self.data.as_slice())
     }
 
+    /// Read every element and index in memory order, returning a checksum of them.
+    pub fn touch(&self) -> u64 {
+        let sum = self
+            .indptr
+            .iter()
+            .fold(0u64, |sum, &i| sum.wrapping_add(i as u64));
+        let sum = self
+            .outer_inds
+            .iter()
+            .chain(&self.inner_inds)
+            .fold(sum, |sum, &i| sum.wrapping_add(i as u64));
+        self.data
+            .iter()
+            .fold(sum, |sum, v| sum.wrapping_add(v.to_bits() as u64))
+    }
+
     /// Compute dot product with a sparse vector after transposing.
     ///
     /// The implementation uses binary search on row (column after transposing) indices.
     pub fn t_dot_csvec(&self, vec: SparseVecView) -> DenseVec {
+        let mut out = DenseVec::zeros(self.shape().1);
+        self.t_dot_csvec_into(vec, out.as_slice_mut().unwrap());
+        out
+    }
+
+    /// Like [`Self::t_dot_csvec`], but adds the result to the given slice.
+    pub fn t_dot_csvec_into(&self, vec: SparseVecView, out: &mut [f32]) {
         let (t_cols, t_rows) = self.shape();
         assert_eq!(
             t_cols,
@@ -578,31 +2090,51 @@ impl LilMat {
             t_cols,
             vec.dim()
         );
-        let mut out = DenseVec::zeros(t_rows);
-
-        let mut i = 0; // i marks the next matrix outer index from which to binary search
-        for (outer_idx, &val1) in vec.iter() {
-            // NB:
-            //  Since the binary search is done on the slice [i..], the returned index di is an
-            //  offset from i.
-            let (di, found) =
-                match self.outer_inds[i..].binary_search(&Index::from_usize(outer_idx)) {
-                    Ok(di) => (di, true),
-                    Err(di) => (di, false),
-                };
-            i += di;
-            if found {
-                let rng = self.indptr[i].index_unchecked()..self.indptr[i + 1].index_unchecked();
-                for (&inner_idx, &val2) in self.inner_inds[rng.clone()]
-                    .iter()
-                    .zip_eq(self.data[rng.clone()].iter())
-                {
-                    out[inner_idx.index_unchecked()] += val1 * val2;
-                }
+        assert_eq!(t_rows, out.len());
+
+        lil_t_dot_csvec_into(
+            &self.indptr,
+            &self.outer_inds,
+            &self.inner_inds,
+            &self.data,
+            |v| v,
+            vec,
+            out,
+        );
+    }
+}
+
+/// Add the product of a transposed matrix in the storage format of [`LilMat`] and a sparse vector
+/// to `out`, with values converted to `f32` by the given function.
+fn lil_t_dot_csvec_into<T: Copy>(
+    indptr: &[usize],
+    outer_inds: &[Index],
+    inner_inds: &[Index],
+    data: &[T],
+    to_f32: impl Fn(T) -> f32,
+    vec: SparseVecView,
+    out: &mut [f32],
+) {
+    let mut i = 0; // i marks the next matrix outer index from which to binary search
+    for (outer_idx, &val1) in vec.iter() {
+        // NB:
+        //  Since the binary search is done on the slice [i..], the returned index di is an
+        //  offset from i.
+        let (di, found) = match outer_inds[i..].binary_search(&to_index(outer_idx, IndexKind::Row))
+        {
+            Ok(di) => (di, true),
+            Err(di) => (di, false),
+        };
+        i += di;
+        if found {
+            let rng = indptr[i].index_unchecked()..indptr[i + 1].index_unchecked();
+            for (&inner_idx, &val2) in inner_inds[rng.clone()]
+                .iter()
+                .zip_eq(data[rng.clone()].iter())
+            {
+                out[inner_idx.index_unchecked()] += val1 * to_f32(val2);
             }
         }
-
-        out
     }
 }
 
@@ -639,6 +2171,31 @@ mod tests {
         assert!(!vec![(1u32, 0.), (5, 0.), (3, 0.)].is_valid_sparse_vec(6));
     }
 
+    #[test]
+    fn test_weight_mat_prune_with_threshold() {
+        let dense = array![[0., 0.5, -2.], [0.1, -0.3, 0.], [1., 0., 0.05]];
+        let mut sparse = WeightMat::Sparse(LilMat::from_columns(&[
+            CsVecI::new(3, vec![1, 2], vec![0.1, 1.]),
+            CsVecI::new(3, vec![0, 1], vec![0.5, -0.3]),
+            CsVecI::new(3, vec![0, 2], vec![-2., 0.05]),
+        ]));
+        let mut dense = WeightMat::Dense(dense);
+        assert_eq!(dense.to_dense(), sparse.to_dense());
+        assert_eq!(Some((0.05, 2.)), dense.nonzero_abs_range());
+        assert_eq!(Some((0.05, 2.)), sparse.nonzero_abs_range());
+
+        assert_eq!(3, dense.prune_with_threshold(0.4));
+        assert_eq!(3, sparse.prune_with_threshold(0.4));
+        let expected = array![[0., 0.5, -2.], [0., 0., 0.], [1., 0., 0.]];
+        assert_eq!(expected, dense.to_dense());
+        assert_eq!(expected, sparse.to_dense());
+        assert_eq!(3, dense.nnz());
+        assert_eq!(3, sparse.nnz());
+
+        assert_eq!(3, sparse.prune_with_threshold(10.));
+        assert_eq!(None, sparse.nonzero_abs_range());
+    }
+
     #[test]
     fn test_sort_by_index() {
         let mut pairs = vec![(1, 123.), (3, 321.), (2, 213.), (4, 432.)];
@@ -646,6 +2203,39 @@ mod tests {
         assert_eq!(vec![(1, 123.), (2, 213.), (3, 321.), (4, 432.)], pairs);
     }
 
+    #[test]
+    fn test_fast_transpose() {
+        use rand::prelude::*;
+        use rand::rngs::StdRng;
+
+        let mut rng = StdRng::seed_from_u64(0);
+        for &(n_rows, n_cols, density) in &[(0, 5, 0.5), (7, 0, 0.5), (50, 30, 0.1), (200, 7, 0.6)]
+        {
+            let pair_lists = (0..n_rows)
+                .map(|_| {
+                    (0..n_cols)
+                        .filter(|_| rng.gen_bool(density))
+                        .map(|j| (j as Index, rng.gen_range(-1f32..1.)))
+                        .collect_vec()
+                })
+                .collect_vec();
+            let mat: SparseMat = csrmat_from_index_value_pair_lists(pair_lists, n_cols);
+            assert_eq!(mat.transpose_view().to_csr(), fast_transpose(&mat));
+        }
+    }
+
+    #[test]
+    fn test_sum_duplicate_indices() {
+        let mut pairs = vec![(1, 1.), (1, 2.), (3, 3.), (4, 4.), (4, 5.), (4, 6.)];
+        pairs.sum_duplicate_indices();
+        assert_eq!(vec![(1, 3.), (3, 3.), (4, 15.)], pairs);
+
+        let mut pairs: SmallVec<[(Index, f32); 4]> =
+            SmallVec::from_slice(&[(1, 1.), (1, 2.), (3, 3.), (4, 4.), (4, 5.)]);
+        pairs.sum_duplicate_indices();
+        assert_eq!(&[(1, 3.), (3, 3.), (4, 9.)], pairs.as_slice());
+    }
+
     #[test]
     fn test_l2_normalize() {
         let mut pairs = vec![(1, 1.), (5, 2.), (50, 4.), (100, 6.), (1000, 8.)];
@@ -838,6 +2428,20 @@ mod tests {
             mat.assign_to_dense(array.view_mut());
             assert_eq!(expected_array, array);
 
+            let expected_entries = vec![(0, 1, 2.), (1, 0, 1.), (2, 3, 4.), (3, 0, 3.), (3, 3, 5.)];
+            assert_eq!(
+                expected_entries,
+                WeightMat::Sparse(mat.clone())
+                    .nonzero_entries()
+                    .collect::<Vec<_>>()
+            );
+            assert_eq!(
+                expected_entries,
+                WeightMat::Dense(array.clone())
+                    .nonzero_entries()
+                    .collect::<Vec<_>>()
+            );
+
             assert_eq!(
                 expected_array,
                 LilMat::from_columns(&vec![
@@ -852,6 +2456,316 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_crossover_density() {
+        let densities = [0.1, 0.2, 0.4];
+        assert_eq!(
+            0.1,
+            crossover_density(&densities, &[(2., 1.), (2., 1.), (2., 1.)])
+        );
+        assert_eq!(
+            1.,
+            crossover_density(&densities, &[(1., 2.), (1., 2.), (1., 2.)])
+        );
+        // Dense is 1 slower at 0.2 and 1 faster at 0.4, so they break even half-way
+        let threshold = crossover_density(&densities, &[(1., 3.), (2., 3.), (3., 2.)]);
+        assert!((threshold - 0.3).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_calibrate_crossover() {
+        let densities = [0.05, 0.2, 0.5, 1.];
+        let timings = WeightMat::time_storage_formats(
+            &[(500, 4), (200, 16)],
+            &densities,
+            50,
+            std::time::Duration::from_millis(40),
+        );
+        assert_eq!(densities.len(), timings.len());
+        assert!(timings
+            .iter()
+            .all(|&(sparse, dense)| sparse > 0. && dense > 0.));
+        let threshold = crossover_density(&densities, &timings);
+        assert!(threshold > 0. && threshold <= 1.);
+    }
+
+    #[test]
+    fn test_weight_mat_from_columns_of() {
+        let a = array![[1., 0.], [0., 2.], [3., 0.]];
+        let b = array![[0., 4.], [5., 0.], [0., 0.]];
+        let expected = array![[0., 4., 1., 0.], [2., 0., 0., 2.], [0., 0., 3., 0.]];
+        let columns = |a: &WeightMat, b: &WeightMat| {
+            WeightMat::from_columns_of(3, &[(a, 1), (b, 1), (a, 0), (a, 1)]).to_dense()
+        };
+
+        let (dense_a, dense_b) = (WeightMat::Dense(a.clone()), WeightMat::Dense(b.clone()));
+        assert!(WeightMat::from_columns_of(3, &[(&dense_a, 0)]).is_dense());
+        assert_eq!(expected, columns(&dense_a, &dense_b));
+
+        let sparse_a = WeightMat::Sparse(LilMat::from_columns(&[
+            SparseVec::new(3, vec![0, 2], vec![1., 3.]),
+            SparseVec::new(3, vec![1], vec![2.]),
+        ]));
+        assert!(!WeightMat::from_columns_of(3, &[(&sparse_a, 0)]).is_dense());
+        assert_eq!(expected, columns(&sparse_a, &dense_b));
+        assert_eq!((3, 0), WeightMat::from_columns_of(3, &[]).shape());
+    }
+
+    #[test]
+    fn test_weight_mat_sparsify() {
+        let a = array![[1., 0.], [0., 0.], [3., 2.]];
+        let mut mat = WeightMat::Dense(a.clone());
+        mat.sparsify();
+        assert!(!mat.is_dense());
+        assert_eq!(3, mat.nnz());
+        assert_eq!(a, mat.to_dense());
+        mat.densify();
+        assert!(mat.is_dense());
+        assert_eq!(a, mat.to_dense());
+
+        let mut half = WeightMat::Half(HalfMat::from_single(&WeightMat::Dense(a.clone())));
+        assert_eq!(1., half.density());
+        half.sparsify();
+        assert!(half.is_half() && half.density() < 1.);
+        assert_eq!(a, half.to_dense());
+
+        let mut quantized = WeightMat::Quantized(QuantizedMat::quantize(&mat));
+        quantized.sparsify();
+        assert!(quantized.is_quantized());
+    }
+
+    #[test]
+    fn test_weight_mat_select_rows() {
+        let a = array![[1., 0.], [0., 0.], [3., 2.], [0., 4.]];
+        let expected = array![[1., 0.], [3., 2.], [0., 4.]];
+        let rows = [0, 2, 3];
+
+        let dense = WeightMat::Dense(a.clone());
+        assert!(dense.select_rows(&rows).is_dense());
+        assert_eq!(expected, dense.select_rows(&rows).to_dense());
+
+        let mut sparse = dense.clone();
+        sparse.shrink_storage();
+        assert!(!sparse.is_dense());
+        assert!(!sparse.select_rows(&rows).is_dense());
+        assert_eq!(expected, sparse.select_rows(&rows).to_dense());
+        assert_eq!(array![[0., 4.]], sparse.select_rows(&[1, 3]).to_dense());
+
+        let half = WeightMat::Half(HalfMat::from_single(&dense));
+        assert!(half.select_rows(&rows).is_half());
+        assert_eq!(expected, half.select_rows(&rows).to_dense());
+    }
+
+    #[test]
+    fn test_dense_t_dot_csvec() {
+        use ndarray::ShapeBuilder;
+        use rand::prelude::*;
+
+        let mut rng = rand::rngs::StdRng::seed_from_u64(0);
+        // Column counts that fill whole blocks of lanes, and that leave some columns over
+        for (n_rows, n_cols) in [(3000, 100), (500, 8), (500, 3)] {
+            let values = (0..n_rows * n_cols)
+                .map(|_| rng.gen::<f32>() - 0.5)
+                .collect_vec();
+            let standard = DenseMat::from_shape_vec((n_rows, n_cols), values).unwrap();
+            // The same matrix in column-major layout, which takes dot products with the columns
+            let mut column_major = DenseMat::zeros((n_rows, n_cols).f());
+            column_major.assign(&standard);
+            assert!(column_major.as_slice().is_none());
+
+            // Active feature counts that fill whole groups of four rows, and that leave some over
+            for density in [0.2, 0.5, 1.] {
+                let mut indices = (0..n_rows as Index)
+                    .filter(|_| rng.gen_bool(density))
+                    .collect_vec();
+                indices.truncate(indices.len() - indices.len() % 4);
+                for n_extra in 0..4 {
+                    let indices = &indices[..indices.len() - n_extra];
+                    let data = indices.iter().map(|_| rng.gen::<f32>()).collect_vec();
+                    let vec = SparseVec::new(n_rows, indices.to_vec(), data);
+
+                    let mut expected = vec![0.; n_cols];
+                    dense_t_dot_csvec_into_with(
+                        DenseKernel::Columns,
+                        standard.view(),
+                        vec.view(),
+                        &mut expected,
+                    );
+                    let mut actual = vec![0.; n_cols];
+                    dense_t_dot_csvec_into_with(
+                        DenseKernel::Unrolled,
+                        standard.view(),
+                        vec.view(),
+                        &mut actual,
+                    );
+                    for (&e, &a) in expected.iter().zip(&actual) {
+                        assert!((e - a).abs() <= 1e-5 * e.abs().max(1.), "{} != {}", e, a);
+                    }
+
+                    // Products pick the kernel by layout
+                    let column_major = WeightMat::Dense(column_major.clone());
+                    let standard = WeightMat::Dense(standard.clone());
+                    assert_eq!(expected, column_major.t_dot_vec(vec.view()).to_vec());
+                    assert_eq!(actual, standard.t_dot_vec(vec.view()).to_vec());
+                }
+            }
+        }
+        assert_eq!(
+            array![0., 0.],
+            WeightMat::Dense(DenseMat::zeros((3, 2)))
+                .t_dot_vec(SparseVec::new(3, vec![], vec![]).view())
+        );
+    }
+
+    #[test]
+    fn test_weight_mat_t_dot_mat() {
+        use rand::prelude::*;
+        use rand::rngs::StdRng;
+
+        let mut rng = StdRng::seed_from_u64(0);
+        let (n_rows, n_cols) = (50, 7);
+        let random_sparse_vec = |rng: &mut StdRng, dim: usize| {
+            let indices = (0..dim as Index)
+                .filter(|_| rng.gen_bool(0.3))
+                .collect_vec();
+            let data = indices.iter().map(|_| rng.gen::<f32>() - 0.5).collect_vec();
+            SparseVec::new(dim, indices, data)
+        };
+        let columns = (0..n_cols)
+            .map(|_| random_sparse_vec(&mut rng, n_rows))
+            .collect_vec();
+        let sparse = WeightMat::Sparse(LilMat::from_columns(&columns));
+        let dense = WeightMat::Dense(sparse.to_dense());
+        let vecs = (0..10)
+            .map(|_| random_sparse_vec(&mut rng, n_rows))
+            .collect_vec();
+        let views = vecs.iter().map(|vec| vec.view()).collect_vec();
+
+        for mat in &[sparse, dense] {
+            let product = mat.t_dot_mat(&views);
+            assert_eq!((vecs.len(), n_cols), product.dim());
+            for (vec, row) in vecs.iter().zip(product.outer_iter()) {
+                assert_eq!(mat.t_dot_vec(vec.view()), row);
+            }
+            assert_eq!((0, n_cols), mat.t_dot_mat(&[]).dim());
+        }
+    }
+
+    #[test]
+    fn test_quantized_mat() {
+        use rand::prelude::*;
+        use rand::rngs::StdRng;
+
+        let mut rng = StdRng::seed_from_u64(0);
+        let (n_rows, n_cols) = (50, 7);
+        let dense = DenseMat::from_shape_fn((n_rows, n_cols), |(_, col)| {
+            if col == 0 || rng.gen_bool(0.3) {
+                0.
+            } else {
+                (rng.gen::<f32>() - 0.3) * col as f32
+            }
+        });
+        let mat = WeightMat::Quantized(QuantizedMat::quantize(&WeightMat::Dense(dense.clone())));
+        assert!(mat.is_quantized() && !mat.is_dense());
+        assert_eq!((n_rows, n_cols), mat.shape());
+        assert_eq!(n_rows * n_cols + 5 * n_cols, mat.mem_size());
+
+        // Zeros are kept exactly, and other values are within half a step of the originals
+        let dequantized = mat.to_dense();
+        for col in 0..n_cols {
+            let column = dense.column(col);
+            let max = column.iter().fold(0f32, |max, v| max.max(v.abs()));
+            for (&v, &w) in column.iter().zip(dequantized.column(col)) {
+                assert_eq!(v.is_zero(), w.is_zero());
+                assert!((v - w).abs() <= max / 255. + 1e-6);
+            }
+        }
+        assert_eq!(dense.iter().filter(|v| !v.is_zero()).count(), mat.nnz());
+
+        let vec = SparseVec::new(n_rows, vec![1, 7, 30], vec![0.5, -2., 1.5]);
+        let expected = WeightMat::Dense(dequantized).t_dot_vec(vec.view());
+        for (v, w) in mat.t_dot_vec(vec.view()).iter().zip(&expected) {
+            assert!((v - w).abs() < 1e-5);
+        }
+
+        let mut pruned = mat.clone();
+        let n_small = mat
+            .nonzero_entries()
+            .filter(|&(_, _, v)| v.abs() < 0.5)
+            .count();
+        assert_eq!(n_small, pruned.prune_with_threshold(0.5));
+        assert_eq!(mat.nnz() - n_small, pruned.nnz());
+
+        // Values are stored as byte strings, and the matrix is serialized in a round trip
+        let bytes = serde_cbor::to_vec(&mat).unwrap();
+        assert!(bytes.len() < n_rows * n_cols + 10 * n_cols + 100);
+        let loaded = serde_cbor::from_slice::<WeightMat>(&bytes).unwrap();
+        assert_eq!(mat.to_dense(), loaded.to_dense());
+    }
+
+    #[test]
+    fn test_half_mat() {
+        let sparse_values = LilMat::from_columns(&[
+            SparseVec::new(4, vec![0, 2], vec![1.5, -0.1]),
+            SparseVec::new(4, vec![], vec![]),
+            SparseVec::new(4, vec![1, 2, 3], vec![0.3, 2., -4.]),
+        ]);
+        let dense_values = sparse_values.to_dense();
+        let vec = SparseVec::new(4, vec![0, 2, 3], vec![1., 0.5, -2.]);
+
+        for mat in &[
+            WeightMat::Sparse(sparse_values.clone()),
+            WeightMat::Dense(dense_values.clone()),
+        ] {
+            let half = WeightMat::Half(HalfMat::from_single(mat));
+            assert!(half.is_half() && !half.is_dense());
+            assert_eq!(mat.shape(), half.shape());
+            assert_eq!(mat.density(), half.density());
+            let n_stored = if mat.is_dense() { 12 } else { mat.nnz() };
+            assert_eq!(mat.mem_size(), half.mem_size() + 2 * n_stored);
+            assert_eq!(mat.nnz(), half.nnz());
+
+            // Values are rounded to 11 significant bits
+            for (v, w) in mat.to_dense().iter().zip(&half.to_dense()) {
+                assert!((v - w).abs() <= v.abs() / 1024.);
+            }
+            for (v, w) in mat
+                .t_dot_vec(vec.view())
+                .iter()
+                .zip(&half.t_dot_vec(vec.view()))
+            {
+                assert!((v - w).abs() < 1e-2);
+            }
+
+            let bytes = serde_cbor::to_vec(&half).unwrap();
+            let loaded = serde_cbor::from_slice::<WeightMat>(&bytes).unwrap();
+            assert!(loaded.is_half());
+            assert_eq!(half.to_dense(), loaded.to_dense());
+            assert_eq!(half.density(), loaded.density());
+
+            let mut pruned = half.clone();
+            assert_eq!(2, pruned.prune_with_threshold(0.4));
+            assert_eq!(mat.nnz() - 2, pruned.nnz());
+            assert_eq!(half.density() < 1., pruned.density() < 1.);
+        }
+
+        let mut half = HalfMat::from_single(&WeightMat::Sparse(sparse_values));
+        half.densify();
+        assert_eq!(1., half.density());
+        assert_eq!(dense_values.len() * 2, half.mem_size());
+
+        // Values are serialized in two bytes each
+        let values = (0..1000).map(|i| i as f32 / 7. - 50.).collect_vec();
+        let dense = DenseMat::from_shape_vec((100, 10), values).unwrap();
+        let half = HalfMat::from_single(&WeightMat::Dense(dense));
+        let bytes = serde_cbor::to_vec(&half).unwrap();
+        assert!(bytes.len() < 2 * 1000 + 64);
+        let loaded = serde_cbor::from_slice::<HalfMat>(&bytes).unwrap();
+        assert_eq!(half.to_dense(), loaded.to_dense());
+        let odd_bytes = serde_cbor::to_vec(&serde_cbor::Value::Bytes(vec![1, 2, 3])).unwrap();
+        assert!(serde_cbor::from_slice::<HalfValues>(&odd_bytes).is_err());
+    }
+
     #[test]
     fn test_lil_mat_t_dot_csvec() {
         let csvec = SparseVec::new(4, vec![0, 2, 3], vec![1., 2., 3.]); // [1, 0, 2, 3]
@@ -0,0 +1,85 @@
+//! Elementwise math used when transforming classifier scores.
+//!
+//! With the `fast-math` feature, [`exp`] is computed by [`fast_exp`] instead of [`f32::exp`].
+//! The approximation is a fixed sequence of arithmetic and bit operations without table lookups,
+//! followed by checks for NaN and out-of-range inputs that select special values instead.
+
+/// Inputs above this overflow to infinity in [`fast_exp`].
+const EXP_MAX_INPUT: f32 = 88.37;
+/// Inputs below this flush to zero in [`fast_exp`], since results would be subnormal.
+const EXP_MIN_INPUT: f32 = -87.33;
+
+/// Adding and subtracting this rounds values smaller than 2^22 in magnitude to integers.
+const ROUNDING_MAGIC: f32 = 12_582_912.; // 1.5 * 2^23
+
+/// The exponential function used for scores, see the module documentation.
+#[inline]
+pub fn exp(x: f32) -> f32 {
+    if cfg!(feature = "fast-math") {
+        fast_exp(x)
+    } else {
+        x.exp()
+    }
+}
+
+/// An approximation of `exp(x)` with a maximum relative error of about 2e-7 for normal results.
+///
+/// The input is reduced to `x = n ln(2) + r` with `|r| <= ln(2) / 2`, `exp(r)` is approximated by
+/// the polynomial from Cephes' `expf`, and the result is scaled by `2^n` by constructing the
+/// exponent bits directly. Results below the smallest normal `f32` are flushed to zero, and NaN is
+/// propagated.
+#[inline]
+pub fn fast_exp(x: f32) -> f32 {
+    let clamped = x.clamp(EXP_MIN_INPUT, EXP_MAX_INPUT);
+    let n = (clamped * std::f32::consts::LOG2_E + ROUNDING_MAGIC) - ROUNDING_MAGIC;
+
+    // ln(2) split into a part exactly representable with few bits and a remainder, so that the
+    // reduction loses no precision
+    let r = clamped - n * 0.693_359_4 + n * 2.121_944_4e-4;
+    let r2 = r * r;
+    let p = ((((1.987_569_1e-4 * r + 1.398_199_9e-3) * r + 8.333_452e-3) * r + 4.166_579_6e-2) * r
+        + 1.666_666_6e-1)
+        * r
+        + 0.5;
+    let exp_r = p * r2 + r + 1.;
+    let scale = f32::from_bits(((n as i32 + 127) as u32) << 23);
+    let y = exp_r * scale;
+
+    if x.is_nan() {
+        x
+    } else if x > EXP_MAX_INPUT {
+        f32::INFINITY
+    } else if x < EXP_MIN_INPUT {
+        0.
+    } else {
+        y
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fast_exp_relative_error() {
+        let mut max_error = 0f64;
+        let n_steps = 200_000;
+        for i in 0..=n_steps {
+            let x = EXP_MIN_INPUT + (EXP_MAX_INPUT - EXP_MIN_INPUT) * i as f32 / n_steps as f32;
+            let expected = f64::from(x).exp();
+            let error = ((f64::from(fast_exp(x)) - expected) / expected).abs();
+            max_error = max_error.max(error);
+        }
+        assert!(max_error < 1e-6, "Max relative error {:e}", max_error);
+    }
+
+    #[test]
+    fn test_fast_exp_special_values() {
+        assert_eq!(1., fast_exp(0.));
+        assert_eq!(0., fast_exp(-100.));
+        assert_eq!(0., fast_exp(f32::NEG_INFINITY));
+        assert_eq!(f32::INFINITY, fast_exp(100.));
+        assert_eq!(f32::INFINITY, fast_exp(f32::INFINITY));
+        assert!(fast_exp(f32::NAN).is_nan());
+    }
+}
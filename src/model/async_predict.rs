@@ -0,0 +1,268 @@
+//! Prediction from async code with bounded concurrency, available with the `async` feature.
+//!
+//! Prediction is CPU-bound, so it runs on Tokio's blocking thread pool instead of the async
+//! worker threads; a semaphore bounds how many predictions run at the same time.
+use super::predict::{PredictError, PredictOptions};
+use super::Model;
+use crate::{FeaturePairs, Index, IndexValueVec};
+use std::sync::Arc;
+use tokio::sync::Semaphore;
+use tokio::task::JoinHandle;
+
+/// A shared model that runs predictions on the blocking thread pool, at most `max_concurrency`
+/// at a time.
+///
+/// Cloning is cheap, and clones share both the model and the concurrency limit.
+#[derive(Clone)]
+pub struct AsyncPredictor {
+    model: Arc<Model>,
+    semaphore: Arc<Semaphore>,
+    max_concurrency: usize,
+}
+
+impl AsyncPredictor {
+    /// Create a predictor for the given model.
+    pub fn new(model: Arc<Model>, max_concurrency: usize) -> Self {
+        assert!(max_concurrency > 0, "max_concurrency must be positive");
+        Self {
+            model,
+            semaphore: Arc::new(Semaphore::new(max_concurrency)),
+            max_concurrency,
+        }
+    }
+
+    /// The model used for prediction.
+    pub fn model(&self) -> &Arc<Model> {
+        &self.model
+    }
+
+    /// The maximum number of predictions that run at the same time.
+    pub fn max_concurrency(&self) -> usize {
+        self.max_concurrency
+    }
+
+    /// Returns a ranked list of predictions for the given input example, as with
+    /// [`Model::predict_with_options`].
+    pub async fn predict(
+        &self,
+        feature_vec: impl AsRef<[(Index, f32)]>,
+        options: PredictOptions,
+    ) -> Result<IndexValueVec, PredictError> {
+        options
+            .validate_for(&self.model)
+            .map_err(PredictError::InvalidOptions)?;
+        let handle = self
+            .spawn_prediction(feature_vec, Arc::new(options))
+            .await?;
+        join(handle).await?
+    }
+
+    /// Returns ranked lists of predictions for the given input examples, in the same order.
+    ///
+    /// Predictions are started as permits become available, so a large batch never occupies more
+    /// than `max_concurrency` blocking threads, and other callers can interleave with it. If the
+    /// options are invalid, every input gets the same error.
+    pub async fn predict_many<F>(
+        &self,
+        feature_vecs: impl IntoIterator<Item = F>,
+        options: PredictOptions,
+    ) -> Vec<Result<IndexValueVec, PredictError>>
+    where
+        F: AsRef<[(Index, f32)]>,
+    {
+        if let Err(message) = options.validate_for(&self.model) {
+            return feature_vecs
+                .into_iter()
+                .map(|_| Err(PredictError::InvalidOptions(message.clone())))
+                .collect();
+        }
+        // Predictions share the options, which can hold many feature group weights
+        let options = Arc::new(options);
+        let mut handles = Vec::new();
+        for feature_vec in feature_vecs {
+            handles.push(
+                self.spawn_prediction(feature_vec, Arc::clone(&options))
+                    .await,
+            );
+        }
+        let mut results = Vec::with_capacity(handles.len());
+        for handle in handles {
+            results.push(match handle {
+                Ok(handle) => join(handle).await.and_then(|result| result),
+                Err(e) => Err(e),
+            });
+        }
+        results
+    }
+
+    /// Run arbitrary work with the model on the blocking thread pool, counting towards the
+    /// concurrency limit.
+    ///
+    /// This is useful for other kinds of prediction, e.g., [`Model::predict_binary`]. If the work
+    /// panics, the panic is caught and returned as [`PredictError::TaskFailed`].
+    pub async fn run_blocking<T, F>(&self, f: F) -> Result<T, PredictError>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Model) -> T + Send + 'static,
+    {
+        let handle = self.spawn_blocking(f).await?;
+        join(handle).await
+    }
+
+    async fn spawn_prediction(
+        &self,
+        feature_vec: impl AsRef<[(Index, f32)]>,
+        options: Arc<PredictOptions>,
+    ) -> Result<JoinHandle<Result<IndexValueVec, PredictError>>, PredictError> {
+        // Copy the input so that it can move to another thread; short inputs stay inline
+        let feature_vec = FeaturePairs::<128>::from_slice(feature_vec.as_ref());
+        self.spawn_blocking(move |model| model.predict_with_options(&feature_vec, &options))
+            .await
+    }
+
+    /// Wait for a permit, then start the work on the blocking thread pool; the permit is released
+    /// when the work finishes.
+    async fn spawn_blocking<T, F>(&self, f: F) -> Result<JoinHandle<T>, PredictError>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Model) -> T + Send + 'static,
+    {
+        let permit = Arc::clone(&self.semaphore)
+            .acquire_owned()
+            .await
+            .map_err(|e| PredictError::TaskFailed(e.to_string()))?;
+        let model = Arc::clone(&self.model);
+        Ok(tokio::task::spawn_blocking(move || {
+            let _permit = permit;
+            f(&model)
+        }))
+    }
+}
+
+/// Wait for a blocking task, returning an error if it panicked or was cancelled.
+async fn join<T>(handle: JoinHandle<T>) -> Result<T, PredictError> {
+    match handle.await {
+        Ok(result) => Ok(result),
+        Err(e) if e.is_panic() => {
+            let payload = e.into_panic();
+            let message = if let Some(message) = payload.downcast_ref::<&str>() {
+                (*message).to_owned()
+            } else if let Some(message) = payload.downcast_ref::<String>() {
+                message.clone()
+            } else {
+                "task panicked".to_owned()
+            };
+            Err(PredictError::TaskFailed(message))
+        }
+        Err(e) => Err(PredictError::TaskFailed(e.to_string())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{toy_dataset, toy_model};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+    use std::time::Duration;
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_bounded_concurrency() {
+        let predictor = AsyncPredictor::new(Arc::new(toy_model(2, 0)), 3);
+        let running = Arc::new(AtomicUsize::new(0));
+        let high_water_mark = Arc::new(AtomicUsize::new(0));
+
+        let tasks = (0..20)
+            .map(|i| {
+                let predictor = predictor.clone();
+                let running = Arc::clone(&running);
+                let high_water_mark = Arc::clone(&high_water_mark);
+                tokio::spawn(async move {
+                    predictor
+                        .run_blocking(move |model| {
+                            let n_running = running.fetch_add(1, Ordering::SeqCst) + 1;
+                            high_water_mark.fetch_max(n_running, Ordering::SeqCst);
+                            thread::sleep(Duration::from_millis(5));
+                            let result = model.predict(&[(i % 8, 1.)], 5);
+                            running.fetch_sub(1, Ordering::SeqCst);
+                            result
+                        })
+                        .await
+                        .unwrap()
+                })
+            })
+            .collect::<Vec<_>>();
+        for task in tasks {
+            assert!(!task.await.unwrap().is_empty());
+        }
+        assert!(high_water_mark.load(Ordering::SeqCst) <= 3);
+        assert!(high_water_mark.load(Ordering::SeqCst) > 0);
+        assert_eq!(3, predictor.semaphore.available_permits());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_results_match_sync_prediction() {
+        let model = Arc::new(toy_model(2, 0));
+        let predictor = AsyncPredictor::new(Arc::clone(&model), 2);
+        let dataset = toy_dataset(30, 8, 1);
+        let options = PredictOptions::default();
+
+        let expected = dataset
+            .feature_lists
+            .iter()
+            .map(|v| model.predict_with_options(v, &options))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            expected,
+            predictor
+                .predict_many(&dataset.feature_lists, options.clone())
+                .await
+        );
+        assert_eq!(
+            expected[0],
+            predictor
+                .predict(&dataset.feature_lists[0], options.clone())
+                .await
+        );
+        // Errors are returned, not raised
+        assert!(predictor
+            .predict(&[(Index::MAX, 1.)], options.clone())
+            .await
+            .is_err());
+        let invalid_options = PredictOptions {
+            beam_size: 0,
+            ..options
+        };
+        assert!(matches!(
+            predictor
+                .predict(&dataset.feature_lists[0], invalid_options.clone())
+                .await,
+            Err(PredictError::InvalidOptions(_))
+        ));
+        let results = predictor
+            .predict_many(&dataset.feature_lists[..3], invalid_options)
+            .await;
+        assert_eq!(3, results.len());
+        assert!(results
+            .iter()
+            .all(|result| matches!(result, Err(PredictError::InvalidOptions(_)))));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_panicking_task() {
+        let predictor = AsyncPredictor::new(Arc::new(toy_model(2, 0)), 1);
+        let result = predictor
+            .run_blocking(|_| -> usize { panic!("Worker failed") })
+            .await;
+        match result {
+            Err(PredictError::TaskFailed(message)) => assert_eq!("Worker failed", message),
+            result => panic!("Unexpected result {:?}", result),
+        }
+        // The permit is released, so later predictions still run
+        assert_eq!(1, predictor.semaphore.available_permits());
+        assert!(predictor
+            .run_blocking(|model| model.n_trees())
+            .await
+            .is_ok());
+    }
+}
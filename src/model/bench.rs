@@ -0,0 +1,444 @@
+//! Latency benchmarks of single-request prediction.
+//!
+//! Each configured number of threads is measured separately: every thread repeatedly predicts for
+//! examples sampled from a test set, one request at a time, first for a warmup period whose
+//! predictions aren't recorded and then for the measured duration.
+//!
+//! Allocations made by measured predictions are reported too if [`CountingAllocator`] is the
+//! global allocator, as in the `omikuji` binary built with the `count-allocations` feature.
+use super::predict::BatchPredictProfile;
+use super::Model;
+use crate::mat_util::{dense_t_dot_csvec_into_with, SparseVecView};
+use crate::{FloatFormat, IndexValueVec};
+use rand::prelude::*;
+use rand::rngs::StdRng;
+use serde::{Deserialize, Serialize};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+use std::fmt::Write;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// A global allocator that counts the allocations made by each thread, delegating to the system
+/// allocator, so that benchmarks can report allocations; install it with `#[global_allocator]`.
+pub struct CountingAllocator;
+
+thread_local! {
+    /// The number of allocations made by this thread, and their total size in bytes.
+    static THREAD_ALLOCATIONS: Cell<(u64, u64)> = const { Cell::new((0, 0)) };
+}
+
+fn count_allocation(size: usize) {
+    // The counter may already be gone while the thread is shutting down
+    let _ = THREAD_ALLOCATIONS.try_with(|counts| {
+        let (n_allocations, n_bytes) = counts.get();
+        counts.set((n_allocations + 1, n_bytes + size as u64));
+    });
+}
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        count_allocation(layout.size());
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        count_allocation(new_size);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+/// The number of allocations made by the current thread so far and their total size in bytes,
+/// which stay zero unless [`CountingAllocator`] is installed.
+pub(crate) fn thread_allocations() -> (u64, u64) {
+    THREAD_ALLOCATIONS.with(Cell::get)
+}
+
+/// Whether [`CountingAllocator`] is the global allocator.
+fn is_counting_allocations() -> bool {
+    let before = thread_allocations();
+    drop(std::hint::black_box(Box::new(0u8)));
+    thread_allocations() != before
+}
+
+pub use crate::mat_util::DenseKernel;
+
+/// Compute `dot(vec, weights)` into the given zero-filled output with the given kernel, for a
+/// dense weight matrix with one row per feature, as leaves with dense weights score labels.
+///
+/// Predictions pick the kernel by the layout of the matrix, so this lets benchmarks compare
+/// kernels on the same matrix.
+///
+/// # Panics
+///
+/// Panics if [`DenseKernel::Unrolled`] is given a matrix that isn't in standard layout, or if the
+/// dimensions don't match.
+pub fn dense_product_into(
+    kernel: DenseKernel,
+    weights: ndarray::ArrayView2<f32>,
+    vec: SparseVecView,
+    out: &mut [f32],
+) {
+    assert_eq!(weights.nrows(), vec.dim(), "Dimension mismatch");
+    assert_eq!(weights.ncols(), out.len(), "Dimension mismatch");
+    dense_t_dot_csvec_into_with(kernel, weights, vec, out);
+}
+
+/// Settings of a benchmark run.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct BenchConfig {
+    /// Beam size for beam search.
+    pub beam_size: usize,
+    /// How long predictions are measured for each number of threads.
+    pub duration: Duration,
+    /// How long predictions are made before measuring for each number of threads.
+    pub warmup: Duration,
+    /// Numbers of threads to measure with, each predicting concurrently.
+    pub n_threads: Vec<usize>,
+    /// Maximum number of examples sampled from the test set.
+    pub n_samples: usize,
+    /// Seed for sampling examples.
+    pub seed: u64,
+}
+
+impl Default for BenchConfig {
+    fn default() -> Self {
+        Self {
+            beam_size: 10,
+            duration: Duration::from_secs(10),
+            warmup: Duration::from_secs(1),
+            n_threads: vec![1],
+            n_samples: 10_000,
+            seed: 0,
+        }
+    }
+}
+
+impl BenchConfig {
+    /// Check if the settings are valid.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.beam_size == 0 {
+            Err("beam_size must be positive".to_owned())
+        } else if self.duration.is_zero() {
+            Err("duration must be positive".to_owned())
+        } else if self.n_threads.is_empty() {
+            Err("n_threads must not be empty".to_owned())
+        } else if self.n_threads.contains(&0) {
+            Err("n_threads must only contain positive numbers".to_owned())
+        } else if self.n_samples == 0 {
+            Err("n_samples must be positive".to_owned())
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// Measurements with a given number of threads.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct BenchResult {
+    pub n_threads: usize,
+    /// The number of predictions measured, summed over threads.
+    pub n_predictions: usize,
+    /// Wall-clock time of the measured period.
+    pub elapsed: Duration,
+    pub latency_p50: Duration,
+    pub latency_p95: Duration,
+    pub latency_p99: Duration,
+    pub latency_max: Duration,
+    /// Allocations made by measured predictions, if [`CountingAllocator`] is installed.
+    #[serde(default)]
+    pub allocations: Option<AllocStats>,
+}
+
+/// Allocations made by predictions, on average; reallocations count as allocations of their new
+/// size.
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct AllocStats {
+    pub allocations_per_prediction: f64,
+    pub bytes_per_prediction: f64,
+}
+
+impl BenchResult {
+    /// Predictions per second over all threads.
+    pub fn throughput(&self) -> f64 {
+        self.n_predictions as f64 / self.elapsed.as_secs_f64()
+    }
+}
+
+/// Run the benchmark with examples sampled from the given feature vectors.
+pub fn run(
+    model: &Model,
+    feature_vecs: &[IndexValueVec],
+    config: &BenchConfig,
+) -> Vec<BenchResult> {
+    config.validate().unwrap();
+    assert!(!feature_vecs.is_empty(), "No examples to benchmark with");
+
+    let mut rng = StdRng::seed_from_u64(config.seed);
+    let samples = feature_vecs
+        .choose_multiple(&mut rng, config.n_samples)
+        .collect::<Vec<_>>();
+
+    config
+        .n_threads
+        .iter()
+        .map(|&n_threads| run_with_threads(model, &samples, config, n_threads))
+        .collect()
+}
+
+fn run_with_threads(
+    model: &Model,
+    samples: &[&IndexValueVec],
+    config: &BenchConfig,
+    n_threads: usize,
+) -> BenchResult {
+    let start_t = Instant::now();
+    let measure_start_t = start_t + config.warmup;
+    let end_t = measure_start_t + config.duration;
+    let is_counting_allocations = is_counting_allocations();
+
+    let (mut latencies, (n_allocations, n_bytes)) = thread::scope(|scope| {
+        let handles = (0..n_threads)
+            .map(|thread_index| {
+                scope.spawn(move || {
+                    let mut latencies = Vec::new();
+                    let mut allocations = (0, 0);
+                    // Threads start at different offsets so that they don't predict in lockstep
+                    let mut i = thread_index * samples.len() / n_threads;
+                    loop {
+                        let request_start_t = Instant::now();
+                        if request_start_t >= end_t {
+                            break;
+                        }
+                        let allocations_before = thread_allocations();
+                        model.predict(samples[i % samples.len()], config.beam_size);
+                        if request_start_t >= measure_start_t {
+                            latencies.push(request_start_t.elapsed());
+                            let allocations_after = thread_allocations();
+                            allocations.0 += allocations_after.0 - allocations_before.0;
+                            allocations.1 += allocations_after.1 - allocations_before.1;
+                        }
+                        i += 1;
+                    }
+                    (latencies, allocations)
+                })
+            })
+            .collect::<Vec<_>>();
+        let mut all_latencies = Vec::new();
+        let mut all_allocations = (0, 0);
+        for handle in handles {
+            let (latencies, allocations) = handle.join().unwrap();
+            all_latencies.extend(latencies);
+            all_allocations.0 += allocations.0;
+            all_allocations.1 += allocations.1;
+        }
+        (all_latencies, all_allocations)
+    });
+    let elapsed = Instant::now().saturating_duration_since(measure_start_t);
+
+    latencies.sort_unstable();
+    BenchResult {
+        n_threads,
+        n_predictions: latencies.len(),
+        elapsed,
+        latency_p50: percentile(&latencies, 50.),
+        latency_p95: percentile(&latencies, 95.),
+        latency_p99: percentile(&latencies, 99.),
+        latency_max: latencies.last().cloned().unwrap_or_default(),
+        allocations: (is_counting_allocations && !latencies.is_empty()).then(|| {
+            let n_predictions = latencies.len() as f64;
+            AllocStats {
+                allocations_per_prediction: n_allocations as f64 / n_predictions,
+                bytes_per_prediction: n_bytes as f64 / n_predictions,
+            }
+        }),
+    }
+}
+
+/// The nearest-rank percentile of sorted values, or zero if there are none.
+fn percentile(sorted: &[Duration], p: f64) -> Duration {
+    if sorted.is_empty() {
+        return Duration::default();
+    }
+    let rank = (p / 100. * sorted.len() as f64).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
+
+/// Format results as a whitespace-separated table with a header line, with latencies in
+/// microseconds, and allocations per prediction, which are `-` if they weren't counted.
+///
+/// Throughputs, latencies and allocations are formatted with the given format, usually
+/// [`FloatFormat::TABLE`].
+pub fn format_table(results: &[BenchResult], float_format: FloatFormat) -> String {
+    let mut table = format!(
+        "{:>8} {:>12} {:>14} {:>10} {:>10} {:>10} {:>10} {:>12} {:>12}\n",
+        "threads",
+        "predictions",
+        "throughput/s",
+        "p50_us",
+        "p95_us",
+        "p99_us",
+        "max_us",
+        "allocs/pred",
+        "bytes/pred"
+    );
+    let micros = |d: Duration| d.as_secs_f64() * 1e6;
+    for result in results {
+        let (allocations, bytes) = match result.allocations {
+            Some(stats) => (
+                float_format
+                    .display(stats.allocations_per_prediction)
+                    .to_string(),
+                float_format.display(stats.bytes_per_prediction).to_string(),
+            ),
+            None => ("-".to_owned(), "-".to_owned()),
+        };
+        writeln!(
+            table,
+            "{:>8} {:>12} {:>14} {:>10} {:>10} {:>10} {:>10} {:>12} {:>12}",
+            result.n_threads,
+            result.n_predictions,
+            float_format.display(result.throughput()),
+            float_format.display(micros(result.latency_p50)),
+            float_format.display(micros(result.latency_p95)),
+            float_format.display(micros(result.latency_p99)),
+            float_format.display(micros(result.latency_max)),
+            allocations,
+            bytes,
+        )
+        .unwrap();
+    }
+    table
+}
+
+/// Format a profile as a whitespace-separated table with a header line, with a row for each depth
+/// of each tree, and times in microseconds per prediction.
+///
+/// The share is the fraction of the time at all depths of all trees that is spent at the depth.
+pub fn format_profile_table(profile: &BatchPredictProfile, float_format: FloatFormat) -> String {
+    let mut table = format!(
+        "{:>6} {:>6} {:>14} {:>10} {:>8}\n",
+        "tree", "depth", "nodes/predict", "time_us", "share"
+    );
+    let n_predictions = profile.n_predictions.max(1) as f64;
+    let total_secs = profile.total_depth_time().as_secs_f64();
+    for (tree, tree_profile) in profile.trees.iter().enumerate() {
+        for (depth, depth_profile) in tree_profile.depths.iter().enumerate() {
+            let secs = depth_profile.time.as_secs_f64();
+            writeln!(
+                table,
+                "{:>6} {:>6} {:>14} {:>10} {:>8}",
+                tree,
+                depth + 1,
+                float_format.display(depth_profile.n_nodes as f64 / n_predictions),
+                float_format.display(secs * 1e6 / n_predictions),
+                float_format.display(if total_secs > 0. {
+                    secs / total_secs
+                } else {
+                    0.
+                }),
+            )
+            .unwrap();
+        }
+    }
+    table
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{toy_dataset, toy_model};
+
+    #[test]
+    fn test_percentile() {
+        let values = (1..=100).map(Duration::from_millis).collect::<Vec<_>>();
+        assert_eq!(Duration::from_millis(50), percentile(&values, 50.));
+        assert_eq!(Duration::from_millis(99), percentile(&values, 99.));
+        assert_eq!(Duration::from_millis(100), percentile(&values, 100.));
+        assert_eq!(Duration::from_millis(1), percentile(&values, 0.));
+        assert_eq!(Duration::from_millis(7), percentile(&values[6..7], 95.));
+        assert_eq!(Duration::default(), percentile(&[], 50.));
+    }
+
+    #[test]
+    fn test_run() {
+        let model = toy_model(2, 0);
+        let dataset = toy_dataset(20, 8, 1);
+        let config = BenchConfig {
+            beam_size: 5,
+            duration: Duration::from_millis(500),
+            warmup: Duration::from_millis(500),
+            n_threads: vec![1, 2],
+            ..BenchConfig::default()
+        };
+        let results = run(&model, &dataset.feature_lists, &config);
+        assert_eq!(2, results.len());
+        for (result, &n_threads) in results.iter().zip(&config.n_threads) {
+            assert_eq!(n_threads, result.n_threads);
+            assert!(result.n_predictions > 0);
+            assert!(result.elapsed >= config.duration);
+            assert!(result.latency_p50 <= result.latency_p95);
+            assert!(result.latency_p95 <= result.latency_p99);
+            assert!(result.latency_p99 <= result.latency_max);
+            // Unit tests run with the counting allocator installed
+            let allocations = result.allocations.unwrap();
+            assert!(allocations.allocations_per_prediction > 0.);
+            assert!(allocations.bytes_per_prediction > 0.);
+        }
+
+        let table = format_table(&results, FloatFormat::TABLE);
+        let mut lines = table.lines();
+        assert_eq!(
+            vec![
+                "threads",
+                "predictions",
+                "throughput/s",
+                "p50_us",
+                "p95_us",
+                "p99_us",
+                "max_us",
+                "allocs/pred",
+                "bytes/pred"
+            ],
+            lines.next().unwrap().split_whitespace().collect::<Vec<_>>()
+        );
+        for (line, result) in lines.zip(&results) {
+            let fields = line.split_whitespace().collect::<Vec<_>>();
+            assert_eq!(9, fields.len());
+            assert_eq!(result.n_threads, fields[0].parse::<usize>().unwrap());
+            assert_eq!(result.n_predictions, fields[1].parse::<usize>().unwrap());
+            for field in &fields[2..] {
+                assert!(field.parse::<f64>().unwrap() >= 0.);
+                assert_eq!(
+                    Some(4),
+                    field.split_once('.').map(|(_, decimals)| decimals.len())
+                );
+            }
+        }
+
+        let (_, profile) = model.predict_batch_profiled(&dataset.feature_lists, 5);
+        let table = format_profile_table(&profile, FloatFormat::TABLE);
+        let n_depths = profile
+            .trees
+            .iter()
+            .map(|tree| tree.depths.len())
+            .sum::<usize>();
+        assert_eq!(n_depths + 1, table.lines().count());
+        let share = table
+            .lines()
+            .skip(1)
+            .map(|line| {
+                line.split_whitespace()
+                    .last()
+                    .unwrap()
+                    .parse::<f64>()
+                    .unwrap()
+            })
+            .sum::<f64>();
+        assert!((share - 1.).abs() < 1e-2, "{}", table);
+    }
+}
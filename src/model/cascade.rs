@@ -0,0 +1,250 @@
+//! Two-stage prediction, where a coarse pass over the top levels of trees is later resumed.
+//!
+//! [`CoarseModel::predict`] runs beam search down to a given depth and returns the frontier, i.e.,
+//! internal nodes (or leaves reached early) with their path scores. [`Model::predict_from_nodes`]
+//! continues beam search from such a frontier without evaluating the top levels again.
+use super::limits::SearchBudget;
+use super::predict::LeafTransform;
+use super::{Frontier, Model, TreeNode};
+use crate::{Index, IndexValueVec};
+use serde::{Deserialize, Serialize};
+
+/// A stable identifier of a node in a model.
+///
+/// A node is identified by the index of its tree and the indices of the children taken on the
+/// way from the root, so the identifier stays the same for as long as the model isn't modified.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct NodeId {
+    tree: usize,
+    path: Vec<usize>,
+}
+
+impl NodeId {
+    /// The root of the tree at the given index.
+    pub(crate) fn root(tree: usize) -> Self {
+        Self {
+            tree,
+            path: Vec::new(),
+        }
+    }
+
+    /// The child at the given index among the children of this node.
+    pub(crate) fn child(&self, index: usize) -> Self {
+        let mut path = self.path.clone();
+        path.push(index);
+        Self {
+            tree: self.tree,
+            path,
+        }
+    }
+
+    /// The same node in the tree at a different index.
+    pub(crate) fn with_tree(&self, tree: usize) -> Self {
+        Self {
+            tree,
+            path: self.path.clone(),
+        }
+    }
+
+    /// The index of the tree the node is in.
+    pub fn tree(&self) -> usize {
+        self.tree
+    }
+
+    /// The indices of the children taken on the way from the root to the node.
+    pub fn path(&self) -> &[usize] {
+        &self.path
+    }
+
+    /// The depth of the node, where roots have depth 1.
+    pub fn depth(&self) -> usize {
+        self.path.len() + 1
+    }
+
+    /// Find the node in the given model, if it exists.
+    fn resolve<'a>(&self, model: &'a Model) -> Option<&'a TreeNode> {
+        let mut node = model.trees.get(self.tree)?;
+        for &i in &self.path {
+            node = match node {
+                TreeNode::Branch { children, .. } => children.get(i)?,
+                TreeNode::Leaf { .. } => return None,
+            };
+        }
+        Some(node)
+    }
+}
+
+/// A read-only view of a model that only searches down to a given depth.
+#[derive(Clone, Copy)]
+pub struct CoarseModel<'a> {
+    model: &'a Model,
+    depth: usize,
+}
+
+impl<'a> CoarseModel<'a> {
+    /// The depth that beam search stops at.
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+
+    /// Returns the frontier of beam search at the truncation depth, as nodes with their path
+    /// scores.
+    ///
+    /// Path scores are sums of classifier scores along the paths from roots, where higher is
+    /// better. Leaves reached before the truncation depth stay on the frontier. Nodes are grouped
+    /// by tree, and the result can be passed as is to [`Model::predict_from_nodes`].
+    pub fn predict(
+        &self,
+        feature_vec: impl AsRef<[(Index, f32)]>,
+        beam_size: usize,
+    ) -> Vec<(NodeId, f32)> {
+        let feature_vec = self.model.prepare_feature_vec(feature_vec.as_ref());
+        let mut nodes = Vec::new();
+        for (tree, root) in self.model.trees.iter().enumerate() {
+            let mut frontier = Frontier::new(vec![(root, 0., Vec::new())]);
+            TreeNode::expand_frontier(
+                &mut frontier,
+                self.model.settings.classifier_loss_type,
+                &feature_vec,
+                beam_size,
+                self.depth - 1,
+                |path: &Vec<usize>, i| {
+                    let mut path = path.clone();
+                    path.push(i);
+                    Some(path)
+                },
+                &mut SearchBudget::unlimited(),
+            )
+            .unwrap_or_else(|message| panic!("Corrupt tree: {}", message));
+            nodes.extend(
+                frontier
+                    .nodes
+                    .into_iter()
+                    .map(|(_, score, path)| (NodeId { tree, path }, score)),
+            );
+        }
+        nodes
+    }
+}
+
+impl Model {
+    /// Returns a view of the model that only searches the top `depth` levels of each tree, where
+    /// roots are at depth 1.
+    pub fn truncate_depth(&self, depth: usize) -> CoarseModel<'_> {
+        assert!(depth > 0, "depth must be positive");
+        CoarseModel { model: self, depth }
+    }
+
+    /// Returns a ranked list of predictions, resuming beam search from the given frontier.
+    ///
+    /// Start nodes are given with their path scores, as returned by [`CoarseModel::predict`] with
+    /// the same input vector. Nodes of each tree are expanded in the order given, and trees with
+    /// no start nodes contribute no predictions. If the frontier is the unmodified output of a
+    /// coarse pass with the same beam size, the result equals that of [`Self::predict`].
+    pub fn predict_from_nodes(
+        &self,
+        feature_vec: impl AsRef<[(Index, f32)]>,
+        start_nodes: &[(NodeId, f32)],
+        beam_size: usize,
+    ) -> IndexValueVec {
+        let feature_vec = self.prepare_feature_vec(feature_vec.as_ref());
+        let mut frontiers = vec![Vec::new(); self.trees.len()];
+        for (node_id, score) in start_nodes {
+            let node = node_id
+                .resolve(self)
+                .unwrap_or_else(|| panic!("Node {:?} not found in the model", node_id));
+            frontiers[node_id.tree].push((node, *score, ()));
+        }
+
+        let tree_predictions = frontiers
+            .into_iter()
+            .map(|nodes| {
+                let mut frontier = Frontier::new(nodes);
+                let mut label_score_pairs = Vec::new();
+                let loss_type = self.settings.classifier_loss_type;
+                let mut budget = SearchBudget::unlimited();
+                TreeNode::expand_frontier(
+                    &mut frontier,
+                    loss_type,
+                    &feature_vec,
+                    beam_size,
+                    usize::MAX,
+                    |_, _| Some(()),
+                    &mut budget,
+                )
+                .and_then(|_| {
+                    TreeNode::score_leaves(
+                        &mut frontier,
+                        loss_type,
+                        &feature_vec,
+                        beam_size,
+                        LeafTransform::Exp,
+                        &mut budget,
+                        &mut label_score_pairs,
+                    )
+                })
+                .unwrap_or_else(|message| panic!("Corrupt tree: {}", message));
+                label_score_pairs
+            })
+            .collect();
+        self.average_tree_predictions(tree_predictions, self.trees.len(), |n_labels| n_labels)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{toy_dataset, toy_model};
+
+    #[test]
+    fn test_resume_from_coarse_frontier() {
+        let model = toy_model(2, 0);
+        let coarse = model.truncate_depth(2);
+        for feature_vec in &toy_dataset(20, 8, 1).feature_lists {
+            for &beam_size in &[1, 3, 10] {
+                let frontier = coarse.predict(feature_vec, beam_size);
+                assert!(frontier.iter().all(|(node_id, _)| node_id.depth() <= 2));
+                assert_eq!(
+                    model.predict(feature_vec, beam_size),
+                    model.predict_from_nodes(feature_vec, &frontier, beam_size)
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_roots_as_frontier() {
+        let model = toy_model(2, 0);
+        let feature_vec = &toy_dataset(1, 8, 1).feature_lists[0];
+        let roots = model.truncate_depth(1).predict(feature_vec, 5);
+        assert_eq!(
+            vec![
+                (
+                    NodeId {
+                        tree: 0,
+                        path: vec![]
+                    },
+                    0.
+                ),
+                (
+                    NodeId {
+                        tree: 1,
+                        path: vec![]
+                    },
+                    0.
+                )
+            ],
+            roots
+        );
+        assert_eq!(
+            model.predict(feature_vec, 5),
+            model.predict_from_nodes(feature_vec, &roots, 5)
+        );
+        assert!(NodeId {
+            tree: 0,
+            path: vec![1000]
+        }
+        .resolve(&model)
+        .is_none());
+    }
+}
@@ -0,0 +1,125 @@
+//! Checkpoints of a model saved one after another into a directory, e.g., as trees are added, so
+//! that work can be resumed from the newest complete checkpoint after a crash.
+//!
+//! Each checkpoint is a model directory named `checkpoint-<index>` within the checkpoint
+//! directory, written as by [`Model::save`]; checkpoints with higher indices are newer. A checkpoint
+//! whose save was interrupted, or whose files are corrupt, is skipped when resuming in favor of the
+//! one before it.
+use super::{io_sink, Model, SaveOptions};
+use log::{info, warn};
+use std::io;
+use std::path::{Path, PathBuf};
+
+static CHECKPOINT_DIR_PREFIX: &str = "checkpoint-";
+
+fn checkpoint_path(dir: &Path, index: usize) -> PathBuf {
+    dir.join(format!("{}{}", CHECKPOINT_DIR_PREFIX, index))
+}
+
+impl Model {
+    /// Save the model as the checkpoint with the given index in the given directory, which is
+    /// created if needed, returning the path of the checkpoint.
+    ///
+    /// Existing checkpoints are never overwritten, so saving at an index that is already taken
+    /// fails.
+    pub fn save_checkpoint<P: AsRef<Path>>(&self, dir: P, index: usize) -> io::Result<PathBuf> {
+        self.save_checkpoint_with_sink(dir.as_ref(), index, &io_sink::FileSink)
+    }
+
+    /// Like [`Self::save_checkpoint`], but creates files through the given sink.
+    fn save_checkpoint_with_sink(
+        &self,
+        dir: &Path,
+        index: usize,
+        sink: &impl io_sink::IoSink,
+    ) -> io::Result<PathBuf> {
+        let path = checkpoint_path(dir, index);
+        if path.exists() {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("Checkpoint {} already exists", path.display()),
+            ));
+        }
+        info!("Saving checkpoint {}", path.display());
+        self.save_with_sink(&path, &SaveOptions::default(), sink)?;
+        Ok(path)
+    }
+
+    /// Load the newest checkpoint in the given directory that loads completely, returning its
+    /// index with the model, or `None` if there is none.
+    ///
+    /// Newer checkpoints that fail to load, e.g., because saving them was interrupted or their
+    /// files are corrupt, are skipped with a warning.
+    pub fn load_latest_checkpoint<P: AsRef<Path>>(dir: P) -> io::Result<Option<(usize, Model)>> {
+        let dir = dir.as_ref();
+        if !dir.exists() {
+            return Ok(None);
+        }
+        let mut indices = Vec::new();
+        for entry in dir.read_dir()? {
+            let file_name = entry?.file_name();
+            if let Some(index) = file_name
+                .to_string_lossy()
+                .strip_prefix(CHECKPOINT_DIR_PREFIX)
+                .and_then(|s| s.parse::<usize>().ok())
+            {
+                indices.push(index);
+            }
+        }
+        indices.sort_unstable_by(|a, b| b.cmp(a));
+
+        for index in indices {
+            let path = checkpoint_path(dir, index);
+            match Model::load(&path) {
+                Ok(model) => {
+                    info!("Resuming from checkpoint {}", path.display());
+                    return Ok(Some((index, model)));
+                }
+                Err(e) => warn!("Skipping checkpoint {}: {}", path.display(), e),
+            }
+        }
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::io_sink::FailingSink;
+    use crate::test_util::toy_model;
+    use std::fs;
+
+    #[test]
+    fn test_resume_skips_corrupt_checkpoints() {
+        let dir = std::env::temp_dir().join(format!("omikuji-checkpoints-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        assert!(Model::load_latest_checkpoint(&dir).unwrap().is_none());
+
+        toy_model(1, 0).save_checkpoint(&dir, 0).unwrap();
+        let path = toy_model(2, 0).save_checkpoint(&dir, 1).unwrap();
+        assert_eq!(
+            io::ErrorKind::AlreadyExists,
+            toy_model(2, 0).save_checkpoint(&dir, 1).unwrap_err().kind()
+        );
+        let (index, model) = Model::load_latest_checkpoint(&dir).unwrap().unwrap();
+        assert_eq!((1, 2), (index, model.n_trees()));
+
+        // The latest checkpoint was interrupted while being saved
+        assert!(toy_model(3, 0)
+            .save_checkpoint_with_sink(&dir, 2, &FailingSink::new(100))
+            .is_err());
+        let (index, model) = Model::load_latest_checkpoint(&dir).unwrap().unwrap();
+        assert_eq!((1, 2), (index, model.n_trees()));
+
+        // A tree of the checkpoint before it was corrupted after it was saved
+        let tree_path = path.join("tree1.cbor");
+        let mut bytes = fs::read(&tree_path).unwrap();
+        let last = bytes.len() - 1;
+        bytes[last] ^= 0x01;
+        fs::write(&tree_path, &bytes).unwrap();
+        let (index, model) = Model::load_latest_checkpoint(&dir).unwrap().unwrap();
+        assert_eq!((0, 1), (index, model.n_trees()));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,206 @@
+//! Checksums of saved models, so that corrupt or truncated files are rejected when loading rather
+//! than loaded into a model that gives wrong predictions.
+//!
+//! Framed model streams end with [`CHECKSUM_MAGIC`] followed by the SHA-256 digest of everything
+//! before it, and model directories record the SHA-256 digest of each tree file in their
+//! [`FormatHeader`](super::version::FormatHeader). Models saved before checksums were added are
+//! loaded with a warning.
+//!
+//! In model directories, only tree files are covered; the JSON files saved next to them, e.g.,
+//! `settings.json`, `label_names.json`, and `used_features.json`, are only checked by parsing.
+//! Files in the memory-mappable format of [`Model::save_mmap`] have no checksum at all.
+use super::{read_format_header, tree_file_paths, Model};
+use log::warn;
+use sha2::{Digest, Sha256};
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::Path;
+
+/// Magic bytes that start the checksum trailer of a framed model stream.
+pub(super) const CHECKSUM_MAGIC: &[u8; 8] = b"OMKJSUM1";
+
+/// A reader or writer that hashes the bytes passing through it.
+pub(super) struct Hashed<T> {
+    inner: T,
+    hasher: Sha256,
+}
+
+impl<T> Hashed<T> {
+    pub fn new(inner: T) -> Self {
+        Self {
+            inner,
+            hasher: Sha256::new(),
+        }
+    }
+
+    /// The digest of the bytes so far.
+    pub fn digest(&self) -> [u8; 32] {
+        self.hasher.clone().finalize().into()
+    }
+}
+
+impl<R: Read> Read for Hashed<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.hasher.update(&buf[..n]);
+        Ok(n)
+    }
+}
+
+impl<W: Write> Write for Hashed<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.hasher.update(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Format a digest as lowercase hex.
+pub(super) fn to_hex(digest: &[u8]) -> String {
+    digest.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Write the checksum trailer of a stream with the given digest.
+pub(super) fn write_trailer<W: Write + ?Sized>(writer: &mut W, digest: [u8; 32]) -> io::Result<()> {
+    writer.write_all(CHECKSUM_MAGIC)?;
+    writer.write_all(&digest)
+}
+
+/// Read the checksum trailer of a stream and check it against the digest of the stream before it.
+///
+/// Streams that end without a trailer pass with a warning.
+pub(super) fn check_trailer<R: Read>(reader: &mut R, digest: [u8; 32]) -> io::Result<()> {
+    let mut magic = Vec::with_capacity(CHECKSUM_MAGIC.len());
+    reader
+        .by_ref()
+        .take(CHECKSUM_MAGIC.len() as u64)
+        .read_to_end(&mut magic)?;
+    if magic.is_empty() {
+        warn!("Model stream has no checksum, so its integrity can't be verified");
+        return Ok(());
+    }
+    if magic != CHECKSUM_MAGIC {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Unexpected data after the last tree of the model stream",
+        ));
+    }
+    let mut expected = [0u8; 32];
+    reader.read_exact(&mut expected).map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Model stream ends in the middle of its checksum",
+        )
+    })?;
+    check_digest(&digest, &to_hex(&expected), "model stream")
+}
+
+/// Check a digest against the expected one, given in hex, for the named data.
+pub(super) fn check_digest(digest: &[u8; 32], expected: &str, name: &str) -> io::Result<()> {
+    let actual = to_hex(digest);
+    if actual == expected {
+        Ok(())
+    } else {
+        Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "Checksum mismatch for {}: expected {}, got {}; it's corrupt or truncated",
+                name, expected, actual
+            ),
+        ))
+    }
+}
+
+impl Model {
+    /// Check the tree files of a model directory against the checksums recorded when it was
+    /// saved, without deserializing them.
+    ///
+    /// Only tree files have checksums, so the other files in the directory, e.g., `settings.json`,
+    /// `label_names.json`, and `used_features.json`, aren't verified; a corrupt one fails to
+    /// parse when the model is loaded, but one changed into other valid JSON goes unnoticed.
+    /// Directories saved before checksums were recorded pass with a warning.
+    pub fn verify_dir<P: AsRef<Path>>(dir_path: P) -> io::Result<()> {
+        let dir_path = dir_path.as_ref();
+        let header = match read_format_header(dir_path)? {
+            Some(header) => header,
+            None => {
+                warn!(
+                    "Model directory {} has no format header, so its integrity can't be verified",
+                    dir_path.display()
+                );
+                return Ok(());
+            }
+        };
+        let tree_paths = tree_file_paths(dir_path)?;
+        if tree_paths.len() != header.n_trees {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "Model has {} trees, but its header records {}; tree files may be missing",
+                    tree_paths.len(),
+                    header.n_trees
+                ),
+            ));
+        }
+
+        let mut n_unchecked = 0;
+        for tree_path in &tree_paths {
+            match header.tree_checksum(tree_path) {
+                Some(expected) => {
+                    let mut hashed = Hashed::new(io::sink());
+                    io::copy(&mut File::open(tree_path)?, &mut hashed)?;
+                    check_digest(&hashed.digest(), expected, &tree_path.display().to_string())?;
+                }
+                None => n_unchecked += 1,
+            }
+        }
+        if n_unchecked > 0 {
+            warn!(
+                "{} tree files have no checksum, so their integrity can't be verified",
+                n_unchecked
+            );
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn test_dir_checksums() {
+        let model = crate::test_util::toy_model(2, 0);
+        let dir = std::env::temp_dir().join(format!("omikuji-checksum-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        model.save(&dir).unwrap();
+        Model::verify_dir(&dir).unwrap();
+
+        let tree_path = dir.join("tree1.cbor");
+        let mut bytes = fs::read(&tree_path).unwrap();
+        let last = bytes.len() - 1;
+        bytes[last] ^= 0x01;
+        fs::write(&tree_path, &bytes).unwrap();
+        let error = Model::verify_dir(&dir).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, error.kind());
+        assert!(error.to_string().contains("tree1.cbor"), "{}", error);
+        assert_eq!(
+            io::ErrorKind::InvalidData,
+            Model::load(&dir).unwrap_err().kind()
+        );
+
+        // Directories saved before checksums were recorded are still accepted
+        bytes[last] ^= 0x01;
+        fs::write(&tree_path, &bytes).unwrap();
+        fs::remove_file(dir.join("format.json")).unwrap();
+        Model::verify_dir(&dir).unwrap();
+        assert_eq!(2, Model::load(&dir).unwrap().n_trees());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
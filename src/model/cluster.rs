@@ -21,6 +21,14 @@ pub struct HyperParam {
     pub balanced: bool,
     pub eps: f32,
     pub min_size: usize,
+    /// Threshold below which entries of l2-normalized label centroids are set to zero.
+    ///
+    /// Labels are clustered by cosine similarity of their centroids, so pruned centroids are
+    /// re-normalized to unit length. A larger threshold gives sparser centroids and faster
+    /// clustering, but the splits found can differ; centroids with no entry above the threshold
+    /// become empty and are only clustered by chance.
+    #[serde(default)]
+    pub centroid_threshold: f32,
 }
 
 impl ConstDefault for HyperParam {
@@ -29,6 +37,7 @@ impl ConstDefault for HyperParam {
         balanced: true,
         eps: 0.0001,
         min_size: 2,
+        centroid_threshold: 0.,
     };
 }
 
@@ -50,6 +59,11 @@ impl HyperParam {
                 "min_size must be positive, but is {}",
                 self.min_size
             ))
+        } else if !(self.centroid_threshold >= 0. && self.centroid_threshold < 1.) {
+            Err(format!(
+                "centroid_threshold must be in [0, 1), but is {}",
+                self.centroid_threshold
+            ))
         } else {
             Ok(())
         }
@@ -0,0 +1,384 @@
+//! Renumbering the labels and features of a model densely.
+//!
+//! After labels are pruned, the remaining label indices can be far apart, e.g., 40k labels with
+//! indices up to 3M, which wastes memory in anything indexed by label. Compaction renumbers the
+//! labels of a model as `0, 1, ...` in their original order, along with everything else indexed
+//! by label, and keeps the original indices so that predictions can be translated back.
+//!
+//! Likewise, models trained on large sparse feature spaces, e.g., of hashed features, often have
+//! non-zero weights for only a fraction of the features, while their weight matrices have a row
+//! for every feature. Compacting features drops the rows of features without weights and keeps
+//! the input index of each remaining row, so that inputs are still given by their input indices.
+use super::{Model, Settings, TreeNode};
+use crate::index::{to_index, IndexKind};
+use crate::{Index, IndexValueVec};
+use hashbrown::HashMap;
+use itertools::Itertools;
+use log::info;
+use rayon::prelude::*;
+use std::io;
+use std::time;
+
+impl TreeNode {
+    /// Renumber the labels of all leaves in the subtree as given.
+    pub(super) fn renumber_labels(&mut self, new_labels: &HashMap<Index, Index>) {
+        match self {
+            TreeNode::Branch { children, .. } => {
+                for child in children {
+                    child.renumber_labels(new_labels);
+                }
+            }
+            TreeNode::Leaf { labels, .. } => {
+                for label in labels {
+                    *label = new_labels[label];
+                }
+            }
+        }
+    }
+
+    /// Keep only the given rows of the weight matrices of all nodes in the subtree.
+    fn select_weight_rows(&mut self, rows: &[usize]) {
+        match self {
+            TreeNode::Branch {
+                ref mut weights,
+                ref mut children,
+            } => {
+                *weights = weights.select_rows(rows);
+                children
+                    .par_iter_mut()
+                    .for_each(|child| child.select_weight_rows(rows));
+            }
+            TreeNode::Leaf {
+                ref mut weights, ..
+            } => *weights = weights.select_rows(rows),
+        }
+    }
+}
+
+impl Model {
+    /// Renumber the labels of the model as `0, 1, ...`, keeping their order, and return the
+    /// previous index of each new label.
+    ///
+    /// Predictions are the same as before, up to the renumbering; label thresholds and the label
+    /// graph are renumbered too, with graph edges to labels the model can't predict dropped.
+    /// Training metadata keeps the labels the model was trained with. The original indices are
+    /// kept in the model, also across repeated compactions, and predictions are translated back
+    /// to them with [`PredictOptions::translate_output`].
+    ///
+    /// [`PredictOptions::translate_output`]: super::PredictOptions::translate_output
+    pub fn compact_labels(&mut self) -> Vec<Index> {
+        let previous_labels = self.labels();
+        let new_labels = previous_labels
+            .iter()
+            .enumerate()
+            .map(|(new_label, &label)| (label, new_label as Index))
+            .collect::<HashMap<_, _>>();
+
+        for tree in self.trees_mut() {
+            tree.renumber_labels(&new_labels);
+        }
+        if let Some(thresholds) = self.label_thresholds.as_mut() {
+            *thresholds = thresholds.select(&previous_labels);
+        }
+        if let Some(graph) = self.label_graph.as_mut() {
+            *graph = graph.renumber(&new_labels);
+        }
+        self.original_labels = Some(match &self.original_labels {
+            Some(original_labels) => previous_labels
+                .iter()
+                .map(|&label| original_labels[label as usize])
+                .collect(),
+            None => previous_labels.clone(),
+        });
+        previous_labels
+    }
+
+    /// The original index of each label of a model compacted with [`Self::compact_labels`].
+    pub fn original_labels(&self) -> Option<&[Index]> {
+        self.original_labels.as_deref()
+    }
+
+    /// Translate the labels of the given predictions back to their original indices, if the model
+    /// was compacted.
+    pub(crate) fn translate_labels(&self, predictions: &mut IndexValueVec) {
+        if let Some(original_labels) = &self.original_labels {
+            for (label, _) in predictions {
+                *label = original_labels[*label as usize];
+            }
+        }
+    }
+
+    /// Drop the weights of features that have no non-zero weight in any tree, and return the input
+    /// index of each remaining feature.
+    ///
+    /// Weight matrices are left with a row for each feature the model uses, plus the bias. Inputs
+    /// are still given by their input indices up to [`Self::n_features`]; features the model
+    /// doesn't use are left out when inputs are prepared, after normalization, so predictions are
+    /// the same as before, except that quantized weights are quantized again. The used features
+    /// are kept in the model, also across repeated compactions.
+    ///
+    /// Mapped weights are copied into memory.
+    pub fn compact_features(&mut self) -> Vec<Index> {
+        let start_t = time::Instant::now();
+        let n_rows = self.settings.n_weight_features();
+        let mut is_used = vec![false; n_rows];
+        for tree in &self.trees {
+            tree.visit_weights(&mut |weights| {
+                for (row, _, _) in weights.nonzero_entries() {
+                    // The bias row, which is last, is always kept
+                    if row < n_rows {
+                        is_used[row] = true;
+                    }
+                }
+            });
+        }
+        let rows = (0..n_rows)
+            .filter(|&row| is_used[row])
+            .chain(std::iter::once(n_rows))
+            .collect_vec();
+
+        // Labels stay the same, so the trees are changed in place
+        for tree in &mut self.trees {
+            tree.select_weight_rows(&rows);
+        }
+        let used_features = rows[..rows.len() - 1]
+            .iter()
+            .map(|&row| match &self.used_features {
+                Some(used_features) => used_features[row],
+                None => to_index(row, IndexKind::Feature),
+            })
+            .collect_vec();
+
+        info!(
+            "Kept {} of {} features; it took {:.2}s",
+            used_features.len(),
+            self.settings.n_features,
+            start_t.elapsed().as_secs_f32()
+        );
+        self.settings.n_used_features = Some(used_features.len());
+        self.used_features = Some(used_features.clone());
+        used_features
+    }
+
+    /// The input index of each feature with weights in a model compacted with
+    /// [`Self::compact_features`].
+    pub fn used_features(&self) -> Option<&[Index]> {
+        self.used_features.as_deref()
+    }
+
+    /// Translate features given as rows of weight matrices back to their input indices, if the
+    /// features were compacted; the bias becomes the feature at index [`Self::n_features`].
+    pub(super) fn translate_features(&self, pairs: &mut IndexValueVec) {
+        if let Some(used_features) = &self.used_features {
+            for (feature, _) in pairs {
+                *feature = used_features
+                    .get(*feature as usize)
+                    .copied()
+                    .unwrap_or_else(|| to_index(self.settings.n_features, IndexKind::Feature));
+            }
+        }
+    }
+}
+
+/// Check that the used features of a loaded model are consistent with its settings.
+pub(super) fn check_used_features(
+    settings: Settings,
+    used_features: Option<&[Index]>,
+) -> io::Result<()> {
+    let is_consistent = match used_features {
+        Some(used_features) => {
+            settings.n_used_features == Some(used_features.len())
+                && used_features.windows(2).all(|w| w[0] < w[1])
+                && used_features
+                    .last()
+                    .map_or(true, |&feature| (feature as usize) < settings.n_features)
+        }
+        None => settings.n_used_features.is_none(),
+    };
+    if is_consistent {
+        Ok(())
+    } else {
+        Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Used features don't match the model settings",
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::{PredictOptions, TrainHyperParam};
+    use crate::test_util::{toy_dataset, toy_model};
+    use assert_approx_eq::assert_approx_eq;
+
+    /// Renumber the labels of the given predictions with the given new index of each label.
+    fn renumbered(
+        predictions: &IndexValueVec,
+        new_labels: &HashMap<Index, Index>,
+    ) -> IndexValueVec {
+        predictions
+            .iter()
+            .map(|&(label, score)| (new_labels[&label], score))
+            .collect()
+    }
+
+    #[test]
+    fn test_compact_labels() {
+        // Spread out the labels, as pruning would leave them
+        let mut model = toy_model(2, 0);
+        let spread = model
+            .labels()
+            .into_iter()
+            .map(|label| (label, label * 1000 + 7))
+            .collect::<HashMap<_, _>>();
+        for tree in model.trees_mut() {
+            tree.renumber_labels(&spread);
+        }
+        // Link the first two labels, and the first to a label the model can't predict
+        let labels = model.labels();
+        let n_labels = *labels.last().unwrap() as usize + 2;
+        let mut adjacency = vec![Vec::new(); n_labels];
+        adjacency[labels[0] as usize] = vec![(labels[1], 0.5), (n_labels as Index - 1, 0.5)];
+        model.attach_label_graph(
+            crate::mat_util::csrmat_from_index_value_pair_lists(adjacency, n_labels),
+            0.5,
+        );
+        let original = model.clone();
+        let feature_vecs = toy_dataset(20, 8, 1).feature_lists;
+
+        let previous_labels = model.compact_labels();
+        assert_eq!(original.labels(), previous_labels);
+        assert_eq!(
+            (0..previous_labels.len() as Index).collect::<Vec<_>>(),
+            model.labels()
+        );
+        assert_eq!(Some(&previous_labels[..]), model.original_labels());
+        let new_labels = previous_labels
+            .iter()
+            .enumerate()
+            .map(|(new_label, &label)| (label, new_label as Index))
+            .collect::<HashMap<_, _>>();
+
+        let options = PredictOptions {
+            translate_output: true,
+            ..PredictOptions::default()
+        };
+        for feature_vec in &feature_vecs {
+            let expected = original.predict(feature_vec, 10);
+            assert_eq!(
+                renumbered(&expected, &new_labels),
+                model.predict(feature_vec, 10)
+            );
+            assert_eq!(
+                expected,
+                model.predict_with_options(feature_vec, &options).unwrap()
+            );
+        }
+
+        assert_eq!(&[(0, vec![(1, 0.5)])], model.label_graph().unwrap().rows());
+
+        // Compacting again changes nothing, and still translates back to the first indices
+        let mut twice = model.clone();
+        assert_eq!(model.labels(), twice.compact_labels());
+        assert_eq!(model.original_labels(), twice.original_labels());
+
+        // The original indices are saved with the model
+        let mut buffer = Vec::new();
+        model.save_to_writer(&mut buffer).unwrap();
+        let loaded = Model::load_from_reader(&buffer[..]).unwrap();
+        assert_eq!(model.original_labels(), loaded.original_labels());
+
+        // Only compacted models can translate their predictions
+        assert!(options.validate_for(&original).is_err());
+    }
+
+    #[test]
+    fn test_compact_features() {
+        // Spread out the features, as hashing them into a large feature space would
+        let spread = |feature_lists: &mut Vec<IndexValueVec>| {
+            for features in feature_lists {
+                for (feature, _) in features.iter_mut() {
+                    *feature = *feature * 5 + 3;
+                }
+            }
+        };
+        let mut dataset = toy_dataset(60, 8, 0);
+        spread(&mut dataset.feature_lists);
+        dataset.n_features = dataset.n_features * 5 + 3;
+        let mut hyper_param = TrainHyperParam::default();
+        hyper_param.n_trees = 2;
+        hyper_param.min_branch_size = 2;
+        let mut original = hyper_param.train(dataset);
+        // Keep dense and sparse matrices in different trees
+        original.trees_mut()[0].densify_weights(0.);
+        let mut feature_vecs = toy_dataset(20, 8, 1).feature_lists;
+        spread(&mut feature_vecs);
+        // Features the model doesn't use still count towards the norm of inputs
+        feature_vecs[0].insert(0, (0, 2.));
+
+        let mut model = original.clone();
+        let used_features = model.compact_features();
+        assert_eq!(Some(&used_features[..]), model.used_features());
+        assert!(!used_features.is_empty());
+        assert!(used_features.windows(2).all(|w| w[0] < w[1]));
+        assert!(used_features.iter().all(|&feature| feature % 5 == 3));
+        assert_eq!(original.n_features(), model.n_features());
+        assert!(model.mem_size() < original.mem_size());
+        model.validate().unwrap();
+
+        let assert_same_predictions = |expected: &Model, actual: &Model| {
+            for feature_vec in &feature_vecs {
+                let expected = expected.predict(feature_vec, 10);
+                let predictions = actual.predict(feature_vec, 10);
+                assert_eq!(expected.len(), predictions.len());
+                for (&(label, score), &(expected_label, expected_score)) in
+                    predictions.iter().zip(&expected)
+                {
+                    assert_eq!(expected_label, label);
+                    assert_approx_eq!(expected_score, score, 1e-6);
+                }
+            }
+        };
+        assert_same_predictions(&original, &model);
+
+        // Explanations are given by input indices
+        let feature_vec = &feature_vecs[1];
+        let (label, _) = model.predict(feature_vec, 1)[0];
+        let contributions = model.explain(feature_vec, 1, label);
+        let expected = original.explain(feature_vec, 1, label);
+        assert_eq!(
+            expected.iter().map(|&(feature, _)| feature).collect_vec(),
+            contributions
+                .iter()
+                .map(|&(feature, _)| feature)
+                .collect_vec()
+        );
+
+        // Compacting again changes nothing
+        let mut twice = model.clone();
+        assert_eq!(used_features, twice.compact_features());
+        assert_same_predictions(&original, &twice);
+
+        // The used features are saved with the model
+        let mut buffer = Vec::new();
+        model.save_to_writer(&mut buffer).unwrap();
+        let loaded = Model::load_from_reader(&buffer[..]).unwrap();
+        assert_eq!(model.used_features(), loaded.used_features());
+        assert_same_predictions(&original, &loaded);
+        let dir =
+            std::env::temp_dir().join(format!("omikuji-compact-features-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        model.save(&dir).unwrap();
+        let loaded = Model::load(&dir).unwrap();
+        assert_eq!(model.used_features(), loaded.used_features());
+        assert_same_predictions(&original, &loaded);
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        // Used features must match the settings
+        check_used_features(model.settings, model.used_features()).unwrap();
+        assert!(check_used_features(model.settings, None).is_err());
+        assert!(check_used_features(original.settings, model.used_features()).is_err());
+        assert!(check_used_features(model.settings, Some(&used_features[1..])).is_err());
+    }
+}
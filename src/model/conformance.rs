@@ -0,0 +1,386 @@
+//! Conformance suites for checking reimplementations of prediction against a model.
+//!
+//! A suite is a JSON document with input vectors and, for each input and beam size, the exact
+//! output of [`Model::predict_with_options`]. Floats are stored both as numbers for readability
+//! and as their IEEE 754 bit patterns, which are what outputs are compared by. Labels with tied
+//! scores are listed in increasing order, since the order of ties is otherwise unspecified.
+use super::predict::PredictOptions;
+use super::Model;
+use crate::{Index, IndexValueVec};
+use const_default::ConstDefault;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::io;
+
+/// Version of the suite format, bumped on incompatible changes.
+const FORMAT_VERSION: u32 = 1;
+
+/// An index-value pair with the exact bit pattern of the value.
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ConformanceEntry {
+    pub index: Index,
+    /// The value, for readability only.
+    pub value: f32,
+    /// The bit pattern of the value, which is authoritative.
+    pub value_bits: u32,
+}
+
+impl ConformanceEntry {
+    fn new((index, value): (Index, f32)) -> Self {
+        Self {
+            index,
+            value,
+            value_bits: value.to_bits(),
+        }
+    }
+
+    fn pair(&self) -> (Index, f32) {
+        (self.index, f32::from_bits(self.value_bits))
+    }
+}
+
+/// The expected result of predicting for an input.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum ConformanceOutput {
+    /// Ranked label-score pairs.
+    Predictions(Vec<ConformanceEntry>),
+    /// Prediction fails with the given error message.
+    Error(String),
+}
+
+impl ConformanceOutput {
+    fn new(result: Result<IndexValueVec, impl fmt::Display>) -> Self {
+        match result {
+            Ok(mut predictions) => {
+                // Break ties by label so that outputs are comparable across runs; NaN scores of a
+                // broken model are ranked first instead of failing the comparison
+                predictions
+                    .sort_by(|&(l1, s1), &(l2, s2)| s2.total_cmp(&s1).then_with(|| l1.cmp(&l2)));
+                Self::Predictions(predictions.into_iter().map(ConformanceEntry::new).collect())
+            }
+            Err(e) => Self::Error(e.to_string()),
+        }
+    }
+}
+
+/// A prediction with given options for one of the inputs of a suite.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ConformanceCase {
+    /// Index of the input in [`ConformanceSuite::inputs`].
+    pub input: usize,
+    pub options: PredictOptions,
+    pub expected: ConformanceOutput,
+}
+
+/// Input vectors with the expected outputs of a model.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ConformanceSuite {
+    pub format_version: u32,
+    pub inputs: Vec<Vec<ConformanceEntry>>,
+    pub cases: Vec<ConformanceCase>,
+}
+
+/// Where a case of a suite differs from the actual output of a model.
+#[derive(Clone, Debug, PartialEq)]
+pub enum MismatchDetail {
+    /// Predictions differ first at the given rank; a side is `None` if its list is shorter.
+    Prediction {
+        rank: usize,
+        expected: Option<(Index, f32)>,
+        actual: Option<(Index, f32)>,
+    },
+    /// One side is an error, or both are errors with different messages.
+    Outcome {
+        expected: ConformanceOutput,
+        actual: ConformanceOutput,
+    },
+}
+
+/// A case of a suite whose expected output differs from the actual output.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Mismatch {
+    /// Index of the case in [`ConformanceSuite::cases`].
+    pub case: usize,
+    /// Index of the input in [`ConformanceSuite::inputs`].
+    pub input: usize,
+    pub beam_size: usize,
+    pub detail: MismatchDetail,
+}
+
+impl fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Case {} (input {}, beam size {}): ",
+            self.case, self.input, self.beam_size
+        )?;
+        let format_pair = |pair: Option<(Index, f32)>| match pair {
+            Some((label, score)) => format!("label {} with score {:e}", label, score),
+            None => "nothing".to_owned(),
+        };
+        match &self.detail {
+            MismatchDetail::Prediction {
+                rank,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "expected {} at rank {}, but got {}",
+                format_pair(*expected),
+                rank,
+                format_pair(*actual)
+            ),
+            MismatchDetail::Outcome { expected, actual } => {
+                let describe = |output: &ConformanceOutput| match output {
+                    ConformanceOutput::Predictions(predictions) => {
+                        format!("{} predictions", predictions.len())
+                    }
+                    ConformanceOutput::Error(message) => format!("error \"{}\"", message),
+                };
+                write!(
+                    f,
+                    "expected {}, but got {}",
+                    describe(expected),
+                    describe(actual)
+                )
+            }
+        }
+    }
+}
+
+/// The result of verifying a model against a suite.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ConformanceReport {
+    /// The number of cases checked.
+    pub n_cases: usize,
+    pub mismatches: Vec<Mismatch>,
+}
+
+impl ConformanceReport {
+    /// Returns whether all cases matched.
+    pub fn is_success(&self) -> bool {
+        self.mismatches.is_empty()
+    }
+}
+
+fn compare(expected: &ConformanceOutput, actual: &ConformanceOutput) -> Option<MismatchDetail> {
+    match (expected, actual) {
+        (ConformanceOutput::Predictions(expected), ConformanceOutput::Predictions(actual)) => (0
+            ..expected.len().max(actual.len()))
+            .map(|rank| (rank, expected.get(rank), actual.get(rank)))
+            .find(|(_, e, a)| {
+                e.map(|e| (e.index, e.value_bits)) != a.map(|a| (a.index, a.value_bits))
+            })
+            .map(|(rank, e, a)| MismatchDetail::Prediction {
+                rank,
+                expected: e.map(ConformanceEntry::pair),
+                actual: a.map(ConformanceEntry::pair),
+            }),
+        _ if expected == actual => None,
+        _ => Some(MismatchDetail::Outcome {
+            expected: expected.clone(),
+            actual: actual.clone(),
+        }),
+    }
+}
+
+impl Model {
+    /// Write a conformance suite with the outputs of the model for each input and beam size.
+    ///
+    /// Predictions are made with [`Model::predict_with_options`] under default options other than
+    /// the beam size.
+    pub fn export_conformance_suite<W: io::Write>(
+        &self,
+        inputs: &[Vec<(Index, f32)>],
+        beams: &[usize],
+        writer: W,
+    ) -> io::Result<()> {
+        let mut cases = Vec::with_capacity(inputs.len() * beams.len());
+        for (input, feature_vec) in inputs.iter().enumerate() {
+            for &beam_size in beams {
+                let options = PredictOptions {
+                    beam_size,
+                    ..PredictOptions::DEFAULT
+                };
+                options
+                    .validate()
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+                let expected =
+                    ConformanceOutput::new(self.predict_with_options(feature_vec, &options));
+                cases.push(ConformanceCase {
+                    input,
+                    options,
+                    expected,
+                });
+            }
+        }
+
+        let suite = ConformanceSuite {
+            format_version: FORMAT_VERSION,
+            inputs: inputs
+                .iter()
+                .map(|feature_vec| {
+                    feature_vec
+                        .iter()
+                        .cloned()
+                        .map(ConformanceEntry::new)
+                        .collect()
+                })
+                .collect(),
+            cases,
+        };
+        serde_json::to_writer(writer, &suite).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::Other,
+                format!("Failed to write conformance suite: {}", e),
+            )
+        })
+    }
+
+    /// Re-run the cases of a conformance suite, reporting those whose outputs differ.
+    pub fn verify_conformance<R: io::Read>(&self, reader: R) -> io::Result<ConformanceReport> {
+        let invalid_data = |message: String| io::Error::new(io::ErrorKind::InvalidData, message);
+        let suite: ConformanceSuite = serde_json::from_reader(reader)
+            .map_err(|e| invalid_data(format!("Failed to parse conformance suite: {}", e)))?;
+        if suite.format_version != FORMAT_VERSION {
+            return Err(invalid_data(format!(
+                "Unsupported conformance suite version {}, expected {}",
+                suite.format_version, FORMAT_VERSION
+            )));
+        }
+        let inputs = suite
+            .inputs
+            .iter()
+            .map(|entries| {
+                entries
+                    .iter()
+                    .map(ConformanceEntry::pair)
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+
+        let mut report = ConformanceReport {
+            n_cases: suite.cases.len(),
+            mismatches: Vec::new(),
+        };
+        for (i, case) in suite.cases.iter().enumerate() {
+            let feature_vec = inputs.get(case.input).ok_or_else(|| {
+                invalid_data(format!(
+                    "Case {} refers to input {}, but there are {} inputs",
+                    i,
+                    case.input,
+                    inputs.len()
+                ))
+            })?;
+            case.options.validate().map_err(invalid_data)?;
+            let actual =
+                ConformanceOutput::new(self.predict_with_options(feature_vec, &case.options));
+            if let Some(detail) = compare(&case.expected, &actual) {
+                report.mismatches.push(Mismatch {
+                    case: i,
+                    input: case.input,
+                    beam_size: case.options.beam_size,
+                    detail,
+                });
+            }
+        }
+        Ok(report)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{toy_dataset, toy_model};
+
+    #[test]
+    fn test_output_order() {
+        let output = ConformanceOutput::new(Ok::<_, String>(vec![
+            (2, 0.5),
+            (1, 0.5),
+            (3, f32::NAN),
+            (0, 0.9),
+        ]));
+        let entries = match output {
+            ConformanceOutput::Predictions(entries) => entries,
+            ConformanceOutput::Error(e) => panic!("Unexpected error: {}", e),
+        };
+        let labels = entries
+            .iter()
+            .map(|entry| entry.pair().0)
+            .collect::<Vec<_>>();
+        assert_eq!(vec![3, 0, 1, 2], labels);
+        assert!(entries[0].pair().1.is_nan());
+    }
+
+    #[test]
+    fn test_conformance_round_trip() {
+        let model = toy_model(2, 0);
+        let mut inputs = toy_dataset(10, 8, 1).feature_lists;
+        // An out-of-range feature makes prediction fail under the default options
+        inputs.push(vec![(model.n_features() as Index, 1.)]);
+        let beams = [1, 3, 10];
+
+        let mut suite = Vec::new();
+        model
+            .export_conformance_suite(&inputs, &beams, &mut suite)
+            .unwrap();
+        let report = model.verify_conformance(suite.as_slice()).unwrap();
+        assert_eq!(inputs.len() * beams.len(), report.n_cases);
+        assert!(report.is_success(), "{:?}", report.mismatches);
+
+        let parsed: ConformanceSuite = serde_json::from_slice(&suite).unwrap();
+        assert!(matches!(
+            parsed.cases.last().unwrap().expected,
+            ConformanceOutput::Error(_)
+        ));
+        for (case, &beam_size) in parsed.cases.iter().zip(beams.iter().cycle()) {
+            assert_eq!(beam_size, case.options.beam_size);
+            if let ConformanceOutput::Predictions(predictions) = &case.expected {
+                for entry in predictions {
+                    assert_eq!(entry.value.to_bits(), entry.value_bits);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_conformance_perturbed_model() {
+        let model = toy_model(2, 0);
+        let inputs = toy_dataset(10, 8, 1).feature_lists;
+        let mut suite = Vec::new();
+        model
+            .export_conformance_suite(&inputs, &[1, 5], &mut suite)
+            .unwrap();
+
+        // Dropping a tree changes the averaged scores
+        let mut perturbed = model.clone();
+        perturbed.trees_mut().truncate(1);
+        let report = perturbed.verify_conformance(suite.as_slice()).unwrap();
+        assert_eq!(20, report.n_cases);
+        assert!(!report.is_success());
+        for mismatch in &report.mismatches {
+            assert_eq!(mismatch.case / 2, mismatch.input);
+            assert_eq!([1, 5][mismatch.case % 2], mismatch.beam_size);
+            match mismatch.detail {
+                MismatchDetail::Prediction {
+                    rank,
+                    expected,
+                    actual,
+                } => {
+                    assert!(expected.is_some() || actual.is_some());
+                    assert_ne!(
+                        expected.map(|(l, s)| (l, s.to_bits())),
+                        actual.map(|(l, s)| (l, s.to_bits()))
+                    );
+                    assert!(rank < 40);
+                }
+                MismatchDetail::Outcome { .. } => panic!("Unexpected {}", mismatch),
+            }
+        }
+
+        let mut invalid = suite.clone();
+        invalid.truncate(suite.len() / 2);
+        assert!(model.verify_conformance(invalid.as_slice()).is_err());
+    }
+}
@@ -0,0 +1,277 @@
+//! Choosing between dense and sparse storage of weight matrices for inference.
+//!
+//! Training stores each matrix in whichever format takes up less memory, but dense matrices are
+//! faster to multiply with. Since every prediction goes through the nodes near the roots, it's
+//! often worth storing those densely even where that takes more memory, while keeping deeper
+//! nodes sparse; a [`DensityPolicy`] decides this for each matrix.
+use super::{Model, TreeNode};
+use crate::mat_util::*;
+use log::info;
+use rayon::prelude::*;
+use std::fmt;
+use std::sync::Arc;
+use std::time;
+
+/// What a [`DensityPolicy`] knows about a weight matrix.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct MatrixInfo {
+    /// The depth of the node of the matrix, which is 0 for the root.
+    pub depth: usize,
+    /// The shape of the matrix, i.e., the number of features plus one for the bias, by the number
+    /// of children or labels of the node.
+    pub shape: (usize, usize),
+    /// The ratio of non-zero weights among all weights of the matrix, regardless of its format.
+    pub density: f32,
+}
+
+/// Which weight matrices to store densely; all others are stored sparsely.
+#[derive(Clone)]
+pub enum DensityPolicy {
+    /// Store matrices of nodes shallower than the given depth densely, e.g., `AboveDepth(2)` for
+    /// the roots and their children.
+    AboveDepth(usize),
+    /// Store matrices with a density higher than the given one densely.
+    AboveDensity(f32),
+    /// Store matrices for which the given function returns true densely.
+    Custom(Arc<dyn Fn(&MatrixInfo) -> bool + Send + Sync>),
+}
+
+impl DensityPolicy {
+    /// Whether to store the matrix described by the given info densely.
+    pub fn is_dense(&self, info: &MatrixInfo) -> bool {
+        match self {
+            DensityPolicy::AboveDepth(depth) => info.depth < *depth,
+            DensityPolicy::AboveDensity(density) => info.density > *density,
+            DensityPolicy::Custom(f) => f(info),
+        }
+    }
+}
+
+impl fmt::Debug for DensityPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DensityPolicy::AboveDepth(depth) => f.debug_tuple("AboveDepth").field(depth).finish(),
+            DensityPolicy::AboveDensity(density) => {
+                f.debug_tuple("AboveDensity").field(density).finish()
+            }
+            DensityPolicy::Custom(_) => f.write_str("Custom(..)"),
+        }
+    }
+}
+
+/// What was changed by [`Model::set_density_policy`].
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct DensityChange {
+    /// The number of matrices converted to dense format.
+    pub n_densified: usize,
+    /// The number of matrices converted to sparse format.
+    pub n_sparsified: usize,
+    /// The memory used by the weights before the change, in bytes.
+    pub mem_size_before: usize,
+    /// The memory used by the weights after the change, in bytes.
+    pub mem_size_after: usize,
+}
+
+impl DensityChange {
+    /// The memory the change costs, in bytes, which is negative if it saves memory.
+    pub fn mem_cost(&self) -> isize {
+        self.mem_size_after as isize - self.mem_size_before as isize
+    }
+
+    fn merge(&mut self, other: DensityChange) {
+        self.n_densified += other.n_densified;
+        self.n_sparsified += other.n_sparsified;
+        self.mem_size_before += other.mem_size_before;
+        self.mem_size_after += other.mem_size_after;
+    }
+}
+
+/// Whether the matrix is stored in dense format, whatever the precision of its values.
+fn is_stored_densely(weights: &WeightMat) -> bool {
+    match weights {
+        WeightMat::Quantized(_) => true,
+        WeightMat::Half(m) => m.is_dense(),
+        _ => weights.is_dense(),
+    }
+}
+
+impl TreeNode {
+    /// Store the weights of all nodes in the subtree as the policy decides, given the depth of
+    /// the subtree.
+    fn apply_density_policy(&mut self, policy: &DensityPolicy, depth: usize) -> DensityChange {
+        let weights = match self {
+            TreeNode::Branch { weights, .. } | TreeNode::Leaf { weights, .. } => weights,
+        };
+        let (rows, cols) = weights.shape();
+        let info = MatrixInfo {
+            depth,
+            shape: (rows, cols),
+            density: if rows * cols == 0 {
+                0.
+            } else {
+                weights.nnz() as f32 / (rows * cols) as f32
+            },
+        };
+
+        let mut change = DensityChange {
+            mem_size_before: weights.mem_size(),
+            ..DensityChange::default()
+        };
+        let was_dense = is_stored_densely(weights);
+        if policy.is_dense(&info) {
+            weights.densify();
+        } else {
+            weights.sparsify();
+        }
+        match (was_dense, is_stored_densely(weights)) {
+            (false, true) => change.n_densified += 1,
+            (true, false) => change.n_sparsified += 1,
+            _ => {}
+        }
+        change.mem_size_after = weights.mem_size();
+
+        if let TreeNode::Branch { children, .. } = self {
+            for child_change in children
+                .par_iter_mut()
+                .map(|child| child.apply_density_policy(policy, depth + 1))
+                .collect::<Vec<_>>()
+            {
+                change.merge(child_change);
+            }
+        }
+        change
+    }
+}
+
+impl Model {
+    /// Store each weight matrix densely or sparsely as the given policy decides, and report how
+    /// much memory that costs.
+    ///
+    /// Predictions are the same either way; dense matrices are faster to predict with, at the cost
+    /// of memory for their zeros. Quantized matrices are always stored densely and are kept as
+    /// they are, while half-precision matrices stay in half precision. Memory-mapped matrices
+    /// that are converted are copied into memory.
+    pub fn set_density_policy(&mut self, policy: &DensityPolicy) -> DensityChange {
+        info!("Applying density policy {:?}...", policy);
+        let start_t = time::Instant::now();
+
+        // Labels stay the same, so the trees are changed in place
+        let mut change = DensityChange::default();
+        for tree_change in self
+            .trees
+            .par_iter_mut()
+            .map(|tree| tree.apply_density_policy(policy, 0))
+            .collect::<Vec<_>>()
+        {
+            change.merge(tree_change);
+        }
+
+        info!(
+            "Densified {} and sparsified {} matrices, changing weight memory by {} bytes; it took \
+             {:.2}s",
+            change.n_densified,
+            change.n_sparsified,
+            change.mem_cost(),
+            start_t.elapsed().as_secs_f32()
+        );
+        change
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{toy_dataset, toy_model};
+
+    fn n_dense_by_depth(model: &Model) -> Vec<(usize, usize)> {
+        fn count(node: &TreeNode, depth: usize, counts: &mut Vec<(usize, usize)>) {
+            if counts.len() <= depth {
+                counts.resize(depth + 1, (0, 0));
+            }
+            let weights = match node {
+                TreeNode::Branch { weights, .. } | TreeNode::Leaf { weights, .. } => weights,
+            };
+            if weights.is_dense() {
+                counts[depth].0 += 1;
+            }
+            counts[depth].1 += 1;
+            if let TreeNode::Branch { children, .. } = node {
+                for child in children {
+                    count(child, depth + 1, counts);
+                }
+            }
+        }
+
+        let mut counts = Vec::new();
+        for tree in &model.trees {
+            count(tree, 0, &mut counts);
+        }
+        counts
+    }
+
+    #[test]
+    fn test_set_density_policy() {
+        let original = toy_model(2, 0);
+        let feature_vecs = toy_dataset(20, 8, 1).feature_lists;
+        let assert_same_predictions = |model: &Model| {
+            for feature_vec in &feature_vecs {
+                assert_eq!(
+                    original.predict(feature_vec, 10),
+                    model.predict(feature_vec, 10)
+                );
+            }
+        };
+
+        let mut model = original.clone();
+        let change = model.set_density_policy(&DensityPolicy::AboveDepth(1));
+        let counts = n_dense_by_depth(&model);
+        assert!(counts.len() > 1);
+        assert_eq!((2, 2), counts[0]);
+        assert!(counts[1..].iter().all(|&(n_dense, _)| n_dense == 0));
+        assert_eq!(
+            model.mem_size() as isize - original.mem_size() as isize,
+            change.mem_cost()
+        );
+        assert_same_predictions(&model);
+
+        // Applying the same policy again changes nothing
+        let again = model.set_density_policy(&DensityPolicy::AboveDepth(1));
+        assert_eq!((0, 0), (again.n_densified, again.n_sparsified));
+        assert_eq!(0, again.mem_cost());
+
+        // Everything is sparse above a density of one
+        let mem_size = model.mem_size();
+        let change = model.set_density_policy(&DensityPolicy::AboveDensity(1.));
+        assert_eq!((0, 2), (change.n_densified, change.n_sparsified));
+        assert_eq!(
+            model.mem_size() as isize - mem_size as isize,
+            change.mem_cost()
+        );
+        assert!(n_dense_by_depth(&model)
+            .iter()
+            .all(|&(n_dense, _)| n_dense == 0));
+        assert_same_predictions(&model);
+
+        // Custom policies see the depth, shape, and density of each matrix
+        let n_rows = model.n_features() + 1;
+        model.set_density_policy(&DensityPolicy::Custom(Arc::new(
+            move |info: &MatrixInfo| {
+                assert_eq!(n_rows, info.shape.0);
+                assert!(info.density >= 0. && info.density <= 1.);
+                info.depth == 1 && info.shape.1 > 1
+            },
+        )));
+        let counts = n_dense_by_depth(&model);
+        assert_eq!(0, counts[0].0);
+        assert!(counts[1].0 > 0);
+        assert_same_predictions(&model);
+
+        let change =
+            model.set_density_policy(&DensityPolicy::Custom(Arc::new(|_: &MatrixInfo| true)));
+        assert!(change.n_densified > 0 && change.n_sparsified == 0);
+        assert!(n_dense_by_depth(&model)
+            .iter()
+            .all(|&(n_dense, n_matrices)| n_dense == n_matrices));
+        assert_same_predictions(&model);
+    }
+}
@@ -0,0 +1,319 @@
+//! Detecting drift of inputs away from the training data.
+//!
+//! An [`InputProfile`] records how often the most frequent features occur in the training data.
+//! Recent inputs are compared against it by the share of non-zero values that falls on each
+//! profiled feature, with the remaining features pooled together, using the population stability
+//! index (PSI): the sum over features of `(actual - expected) * ln(actual / expected)`. Broken
+//! feature pipelines, e.g., a different tokenizer version, shift these shares long before the
+//! predictions visibly degrade.
+use super::Model;
+use crate::{DataSet, Index};
+use hashbrown::HashMap;
+use itertools::Itertools;
+use ordered_float::NotNan;
+use serde::{Deserialize, Serialize};
+use std::cmp::Reverse;
+
+/// The default number of most frequent features kept in an input profile.
+pub const DEFAULT_MAX_PROFILED_FEATURES: usize = 10_000;
+
+/// Shares smaller than this are raised to it, so that features absent from either side have a
+/// large but finite divergence.
+const MIN_SHARE: f32 = 1e-6;
+
+/// Statistics of the inputs a model was trained on.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct InputProfile {
+    pub n_examples: usize,
+    /// The mean number of non-zero features per example.
+    pub mean_nnz: f32,
+    /// The most frequent features, sorted by index.
+    pub features: Vec<FeatureProfile>,
+}
+
+/// Statistics of a feature in an [`InputProfile`].
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct FeatureProfile {
+    pub index: Index,
+    /// The fraction of examples where the feature is non-zero.
+    pub occurrence_rate: f32,
+    /// The mean of the non-zero values of the feature.
+    pub mean_value: f32,
+}
+
+/// How recent inputs differ from the [`InputProfile`] of a model.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct DriftReport {
+    pub n_inputs: usize,
+    /// The population stability index over profiled features and the pooled remaining features;
+    /// as a rule of thumb, below 0.1 is stable and above 0.25 is a major shift.
+    pub divergence: f32,
+    /// The expected and actual shares of non-zero values on features that weren't profiled.
+    pub unprofiled_shares: (f32, f32),
+    /// The mean number of non-zero features per recent input.
+    pub mean_nnz: f32,
+    /// Profiled features by decreasing contribution to the divergence.
+    pub features: Vec<FeatureDrift>,
+}
+
+/// How the usage of a profiled feature changed in recent inputs.
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct FeatureDrift {
+    pub index: Index,
+    /// The share of non-zero values on this feature in the training data.
+    pub expected_share: f32,
+    /// The share of non-zero values on this feature in recent inputs.
+    pub actual_share: f32,
+    /// The mean non-zero value of this feature in the training data.
+    pub expected_mean_value: f32,
+    /// The mean non-zero value of this feature in recent inputs, if it occurred in any.
+    pub actual_mean_value: Option<f32>,
+    /// The contribution of this feature to [`DriftReport::divergence`].
+    pub divergence: f32,
+}
+
+impl InputProfile {
+    /// Profile the given feature vectors, keeping the `max_features` most frequent features;
+    /// there must be at least one.
+    pub fn fit<F: AsRef<[(Index, f32)]>>(
+        feature_vecs: &[F],
+        max_features: usize,
+    ) -> Result<Self, String> {
+        if feature_vecs.is_empty() {
+            return Err("Can't profile an empty dataset".to_owned());
+        }
+        let (stats, total_nnz) = feature_stats(feature_vecs);
+        let n_examples = feature_vecs.len();
+        let features = stats
+            .into_iter()
+            .map(|(index, (count, sum))| FeatureProfile {
+                index,
+                occurrence_rate: count as f32 / n_examples as f32,
+                mean_value: sum / count as f32,
+            })
+            // Ties are broken by index, so that the profile is deterministic
+            .sorted_unstable_by_key(|f| (Reverse(NotNan::new(f.occurrence_rate).unwrap()), f.index))
+            .take(max_features)
+            .sorted_unstable_by_key(|f| f.index)
+            .collect();
+        Ok(Self {
+            n_examples,
+            mean_nnz: total_nnz as f32 / n_examples as f32,
+            features,
+        })
+    }
+
+    /// The expected share of non-zero values on the given profiled feature.
+    fn share(&self, feature: &FeatureProfile) -> f32 {
+        if self.mean_nnz > 0. {
+            feature.occurrence_rate / self.mean_nnz
+        } else {
+            0.
+        }
+    }
+
+    /// Compare the given inputs against the profile.
+    pub fn check_drift<F: AsRef<[(Index, f32)]>>(&self, feature_vecs: &[F]) -> DriftReport {
+        let (stats, total_nnz) = feature_stats(feature_vecs);
+        let share_of = |count: usize| {
+            if total_nnz > 0 {
+                count as f32 / total_nnz as f32
+            } else {
+                0.
+            }
+        };
+        let divergence = |expected: f32, actual: f32| {
+            let (expected, actual) = (expected.max(MIN_SHARE), actual.max(MIN_SHARE));
+            (actual - expected) * (actual / expected).ln()
+        };
+
+        let mut profiled_count = 0;
+        let mut features = self
+            .features
+            .iter()
+            .map(|feature| {
+                let (count, sum) = stats.get(&feature.index).copied().unwrap_or((0, 0.));
+                profiled_count += count;
+                let expected_share = self.share(feature);
+                let actual_share = share_of(count);
+                FeatureDrift {
+                    index: feature.index,
+                    expected_share,
+                    actual_share,
+                    expected_mean_value: feature.mean_value,
+                    actual_mean_value: Some(sum / count as f32).filter(|_| count > 0),
+                    divergence: divergence(expected_share, actual_share),
+                }
+            })
+            .collect_vec();
+        features.sort_unstable_by_key(|f| (Reverse(NotNan::new(f.divergence).unwrap()), f.index));
+
+        let expected_unprofiled =
+            (1. - self.features.iter().map(|f| self.share(f)).sum::<f32>()).clamp(0., 1.);
+        let actual_unprofiled = share_of(total_nnz - profiled_count);
+        DriftReport {
+            n_inputs: feature_vecs.len(),
+            divergence: features.iter().map(|f| f.divergence).sum::<f32>()
+                + divergence(expected_unprofiled, actual_unprofiled),
+            unprofiled_shares: (expected_unprofiled, actual_unprofiled),
+            mean_nnz: if feature_vecs.is_empty() {
+                0.
+            } else {
+                total_nnz as f32 / feature_vecs.len() as f32
+            },
+            features,
+        }
+    }
+}
+
+/// Count the examples where each feature is non-zero and sum its values, returning the stats by
+/// feature with the total number of non-zero values.
+fn feature_stats<F: AsRef<[(Index, f32)]>>(
+    feature_vecs: &[F],
+) -> (HashMap<Index, (usize, f32)>, usize) {
+    let mut stats = HashMap::<Index, (usize, f32)>::new();
+    let mut total_nnz = 0;
+    for feature_vec in feature_vecs {
+        for &(index, value) in feature_vec.as_ref() {
+            if value != 0. {
+                let (count, sum) = stats.entry(index).or_insert((0, 0.));
+                *count += 1;
+                *sum += value;
+                total_nnz += 1;
+            }
+        }
+    }
+    (stats, total_nnz)
+}
+
+impl Model {
+    /// Profile the inputs of the training data, keeping the `max_features` most frequent features,
+    /// e.g., [`DEFAULT_MAX_PROFILED_FEATURES`].
+    ///
+    /// The profile is saved with the model, and used by [`Self::check_input_drift`]. Features are
+    /// profiled as given, before normalization or projection, so that the profile describes what
+    /// the feature pipeline produces. An empty dataset is an error, which leaves the model as is.
+    pub fn fit_input_profile(
+        &mut self,
+        dataset: &DataSet,
+        max_features: usize,
+    ) -> Result<(), String> {
+        self.input_profile = Some(InputProfile::fit(&dataset.feature_lists, max_features)?);
+        Ok(())
+    }
+
+    /// The profile of the training inputs, if one was fitted.
+    pub fn input_profile(&self) -> Option<&InputProfile> {
+        self.input_profile.as_ref()
+    }
+
+    /// Compare recent inputs against the profile of the training inputs, returning `None` if the
+    /// model has no profile.
+    pub fn check_input_drift<F: AsRef<[(Index, f32)]>>(
+        &self,
+        recent_inputs: &[F],
+    ) -> Option<DriftReport> {
+        self.input_profile
+            .as_ref()
+            .map(|profile| profile.check_drift(recent_inputs))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{toy_dataset, toy_model};
+    use assert_approx_eq::assert_approx_eq;
+
+    #[test]
+    fn test_profile() {
+        let feature_vecs = vec![
+            vec![(0, 1.), (2, 3.)],
+            vec![(0, 3.), (1, 0.)],
+            vec![(0, 2.), (5, 1.)],
+            vec![],
+        ];
+        let profile = InputProfile::fit(&feature_vecs, 2).unwrap();
+        assert_eq!(4, profile.n_examples);
+        assert_eq!(1.25, profile.mean_nnz);
+        // Features 2 and 5 are tied, so the one with the smaller index is kept
+        assert_eq!(
+            vec![
+                FeatureProfile {
+                    index: 0,
+                    occurrence_rate: 0.75,
+                    mean_value: 2.,
+                },
+                FeatureProfile {
+                    index: 2,
+                    occurrence_rate: 0.25,
+                    mean_value: 3.,
+                },
+            ],
+            profile.features
+        );
+
+        let report = profile.check_drift(&feature_vecs);
+        assert!(report.divergence.abs() < 1e-6);
+        assert!(InputProfile::fit::<Vec<(Index, f32)>>(&[], 2).is_err());
+        assert_approx_eq!(0.2, report.unprofiled_shares.0);
+        assert_approx_eq!(0.2, report.unprofiled_shares.1);
+        assert_eq!(
+            vec![0, 2],
+            report.features.iter().map(|f| f.index).collect_vec()
+        );
+    }
+
+    #[test]
+    fn test_check_input_drift() {
+        let mut model = toy_model(1, 0);
+        assert!(model.check_input_drift(&[vec![(0, 1.)]]).is_none());
+
+        let training_data = toy_dataset(400, 8, 0);
+        assert!(model
+            .fit_input_profile(
+                &training_data.take_examples(&[]),
+                DEFAULT_MAX_PROFILED_FEATURES
+            )
+            .is_err());
+        assert!(model.input_profile().is_none());
+        model
+            .fit_input_profile(&training_data, DEFAULT_MAX_PROFILED_FEATURES)
+            .unwrap();
+        let profile = model.input_profile().unwrap().clone();
+
+        // Another sample from the same distribution barely drifts
+        let recent = toy_dataset(400, 8, 1).feature_lists;
+        let report = model.check_input_drift(&recent).unwrap();
+        assert_eq!(recent.len(), report.n_inputs);
+        assert!(report.divergence < 0.1, "{:?}", report);
+
+        // Dropping the most frequent feature is flagged first
+        let frequent = profile
+            .features
+            .iter()
+            .max_by_key(|f| NotNan::new(f.occurrence_rate).unwrap())
+            .unwrap()
+            .index;
+        let broken = recent
+            .iter()
+            .map(|feature_vec| {
+                feature_vec
+                    .iter()
+                    .map(|&(i, v)| (i, if i == frequent { 0. } else { v }))
+                    .collect_vec()
+            })
+            .collect_vec();
+        let report = model.check_input_drift(&broken).unwrap();
+        assert!(report.divergence > 0.25, "{:?}", report);
+        assert_eq!(frequent, report.features[0].index);
+        assert_eq!(0., report.features[0].actual_share);
+        assert_eq!(None, report.features[0].actual_mean_value);
+
+        // The profile is saved with the model
+        let mut buffer = Vec::new();
+        model.save_to_writer(&mut buffer).unwrap();
+        let loaded = Model::load_from_reader(&buffer[..]).unwrap();
+        assert_eq!(Some(&profile), loaded.input_profile());
+    }
+}
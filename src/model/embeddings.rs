@@ -0,0 +1,170 @@
+//! Label embeddings derived from the classifiers along the paths of a tree.
+use super::{Model, TreeNode};
+use crate::index::{to_index, IndexKind};
+use crate::mat_util::*;
+use crate::Index;
+use itertools::Itertools;
+
+/// A leaf together with the weights of the branches on its path and the children taken.
+type LeafPath<'a> = (&'a TreeNode, Vec<(&'a WeightMat, usize)>);
+
+impl Model {
+    /// Returns the labels of the given tree in increasing order, together with a matrix whose
+    /// rows are the labels' path embeddings.
+    ///
+    /// Each leaf is summarized by its centroid, the mean of the feature weights of its label
+    /// classifiers, which is prepared like an input vector: l2-normalized with the bias appended.
+    /// For a label in a leaf at depth `d`, where roots have depth 1, the first `d - 1` columns
+    /// are the scores that the branch classifiers on the path give the centroid for the children
+    /// taken, and column `d` is the score that the label's own classifier gives it. Scores are
+    /// raw classifier margins, before the loss-specific transform used in prediction.
+    ///
+    /// The number of columns is the depth of the deepest leaf, and rows of labels in shallower
+    /// leaves are padded with zeros. Labels in the same leaf share their first `d - 1` entries.
+    pub fn label_path_embeddings(&self, tree_index: usize) -> (Vec<Index>, DenseMat) {
+        assert!(
+            tree_index < self.trees.len(),
+            "Tree index {} out of range for {} trees",
+            tree_index,
+            self.trees.len()
+        );
+        let mut leaf_paths = Vec::new();
+        collect_leaf_paths(&self.trees[tree_index], &mut Vec::new(), &mut leaf_paths);
+        let n_dims = leaf_paths
+            .iter()
+            .map(|(_, path)| path.len() + 1)
+            .max()
+            .unwrap_or(1);
+
+        let mut label_to_embedding = Vec::new();
+        for (leaf, path) in leaf_paths {
+            if let TreeNode::Leaf {
+                ref weights,
+                ref labels,
+            } = leaf
+            {
+                if labels.is_empty() {
+                    continue;
+                }
+                let centroid = self.leaf_centroid(weights);
+                let prefix = path
+                    .iter()
+                    .map(|(branch_weights, child_index)| {
+                        branch_weights.t_dot_vec(centroid.view())[*child_index]
+                    })
+                    .collect_vec();
+                let label_scores = weights.t_dot_vec(centroid.view());
+                for (&label, &score) in labels.iter().zip(label_scores.iter()) {
+                    let mut embedding = prefix.clone();
+                    embedding.push(score);
+                    embedding.resize(n_dims, 0.);
+                    label_to_embedding.push((label, embedding));
+                }
+            }
+        }
+        label_to_embedding.sort_unstable_by_key(|&(label, _)| label);
+
+        let mut embeddings = DenseMat::zeros((label_to_embedding.len(), n_dims));
+        for (mut row, (_, embedding)) in embeddings.outer_iter_mut().zip(&label_to_embedding) {
+            row.assign(&ndarray::ArrayView1::from(embedding.as_slice()));
+        }
+        let labels = label_to_embedding
+            .into_iter()
+            .map(|(label, _)| label)
+            .collect();
+        (labels, embeddings)
+    }
+
+    /// The mean of the feature weights of a leaf's label classifiers, prepared like an input.
+    fn leaf_centroid(&self, leaf_weights: &WeightMat) -> SparseVec {
+        let n_features = self.settings.n_weight_features();
+        let mean = leaf_weights.column_mean();
+        let mut pairs = mean
+            .iter()
+            .take(n_features) // Skip the bias term
+            .enumerate()
+            .filter(|&(_, &v)| v != 0.)
+            .map(|(i, &v)| (to_index(i, IndexKind::Feature), v))
+            .collect_vec();
+        pairs.l2_normalize();
+        pairs.push((to_index(n_features, IndexKind::Feature), 1.));
+        let (indices, data) = pairs.into_iter().unzip();
+        SparseVec::new(n_features + 1, indices, data)
+    }
+}
+
+/// Collect the leaves of the subtree together with the paths leading to them, in pre-order.
+fn collect_leaf_paths<'a>(
+    node: &'a TreeNode,
+    path: &mut Vec<(&'a WeightMat, usize)>,
+    leaf_paths: &mut Vec<LeafPath<'a>>,
+) {
+    match node {
+        TreeNode::Branch {
+            ref weights,
+            ref children,
+        } => {
+            for (i, child) in children.iter().enumerate() {
+                path.push((weights, i));
+                collect_leaf_paths(child, path, leaf_paths);
+                path.pop();
+            }
+        }
+        TreeNode::Leaf { .. } => leaf_paths.push((node, path.clone())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::TrainHyperParam;
+    use crate::test_util::{toy_dataset, toy_model};
+
+    #[test]
+    fn test_label_path_embeddings() {
+        let model = toy_model(2, 0);
+        let (labels, embeddings) = model.label_path_embeddings(1);
+        assert_eq!((0..8).collect_vec(), labels);
+
+        let mut leaf_paths = Vec::new();
+        collect_leaf_paths(&model.trees[1], &mut Vec::new(), &mut leaf_paths);
+        let depth = leaf_paths
+            .iter()
+            .map(|(_, path)| path.len() + 1)
+            .max()
+            .unwrap();
+        assert!(depth > 1);
+        assert_eq!((8, depth), embeddings.dim());
+
+        for (leaf, path) in leaf_paths {
+            if let TreeNode::Leaf { ref labels, .. } = leaf {
+                let d = path.len() + 1;
+                let rows = labels
+                    .iter()
+                    .map(|&label| embeddings.row(label as usize))
+                    .collect_vec();
+                for row in &rows {
+                    // Siblings share the prefix from the branches, and rows are padded with zeros
+                    assert_eq!(
+                        rows[0].slice(ndarray::s![..d - 1]),
+                        row.slice(ndarray::s![..d - 1])
+                    );
+                    assert!(row.slice(ndarray::s![d..]).iter().all(|&v| v == 0.));
+                    assert!(row.iter().all(|v| v.is_finite()));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_label_path_embeddings_of_single_leaf() {
+        let mut hyper_param = TrainHyperParam::default();
+        hyper_param.n_trees = 1;
+        let model = hyper_param.train(toy_dataset(30, 5, 0));
+        assert!(model.trees[0].is_leaf());
+
+        let (labels, embeddings) = model.label_path_embeddings(0);
+        assert_eq!((0..5).collect_vec(), labels);
+        assert_eq!((5, 1), embeddings.dim());
+    }
+}
@@ -0,0 +1,344 @@
+//! Ensembles of independently trained models over the same label space.
+//!
+//! Each model in an ensemble takes its own input vector, e.g., when models are trained on
+//! different feature views of the same examples.
+use super::{io_sink, label_rank, scores, Model};
+use crate::{Index, IndexValueVec};
+use hashbrown::HashMap;
+use itertools::Itertools;
+use log::info;
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::time;
+
+static ENSEMBLE_SETTINGS_FILE_NAME: &str = "ensemble.json";
+static MODEL_DIR_NAME_PREFIX: &str = "model";
+
+/// How label scores from models in an ensemble are combined.
+///
+/// A label not predicted by a model has a score of 0 from that model.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Combiner {
+    /// Weighted sum of scores.
+    WeightedSum,
+    /// Weighted sum of scores divided by the sum of weights.
+    #[default]
+    WeightedMean,
+    /// Maximum of weighted scores.
+    WeightedMax,
+}
+
+#[derive(Serialize, Deserialize)]
+struct EnsembleSettings {
+    combiner: Combiner,
+    n_models: usize,
+}
+
+/// An ensemble of models that share the same label space.
+#[derive(Clone, Debug)]
+pub struct Ensemble {
+    models: Vec<Model>,
+    combiner: Combiner,
+}
+
+impl Ensemble {
+    /// Create an ensemble, checking that all models can predict the same set of labels.
+    pub fn new(models: Vec<Model>, combiner: Combiner) -> Result<Self, String> {
+        if models.is_empty() {
+            return Err("An ensemble must have at least one model".to_owned());
+        }
+        let labels = models[0].collect_sorted_labels();
+        for (i, model) in models.iter().enumerate().skip(1) {
+            if model.collect_sorted_labels() != labels {
+                return Err(format!(
+                    "Model {} has a different label space than model 0",
+                    i
+                ));
+            }
+        }
+        Ok(Self { models, combiner })
+    }
+
+    /// The models in the ensemble.
+    pub fn models(&self) -> &[Model] {
+        &self.models
+    }
+
+    /// The policy for combining label scores.
+    pub fn combiner(&self) -> Combiner {
+        self.combiner
+    }
+
+    /// The range of scores that [`Self::predict`] can return with the given weights, as inclusive
+    /// bounds.
+    ///
+    /// This is derived from [`Model::score_range`] of each model and the combiner: weighted sums
+    /// add up the weighted bounds, weighted means divide them by the sum of weights, and weighted
+    /// maxima take the largest weighted bounds. Since labels a model doesn't predict count as 0,
+    /// the lower bound is never above 0.
+    pub fn score_range(&self, weights: &[f32]) -> (f32, f32) {
+        assert_eq!(
+            self.models.len(),
+            weights.len(),
+            "Expected one weight per model"
+        );
+        let weighted_ranges = self.models.iter().zip(weights).map(|(model, &weight)| {
+            let (min, max) = model.score_range();
+            (weight * min, weight * max)
+        });
+        let (min, max) = match self.combiner {
+            Combiner::WeightedSum | Combiner::WeightedMean => weighted_ranges
+                .fold((0., 0.), |(min, max), (weighted_min, weighted_max)| {
+                    (min + weighted_min, max + weighted_max)
+                }),
+            Combiner::WeightedMax => weighted_ranges
+                .fold((f32::INFINITY, f32::NEG_INFINITY), |(min, max), range| {
+                    (min.min(range.0), max.max(range.1))
+                }),
+        };
+        let (min, max) = if self.combiner == Combiner::WeightedMean {
+            let total_weight = weights.iter().sum::<f32>();
+            (min / total_weight, max / total_weight)
+        } else {
+            (min, max)
+        };
+        (min.min(0.), max.max(0.))
+    }
+
+    /// Returns a ranked list of predictions combined over all models.
+    ///
+    /// # Arguments
+    ///
+    /// * `inputs` - One input vector per model, each with the same assumptions as in
+    /// [`Model::predict`]
+    /// * `beam_size` - Beam size for beam search in each model
+    /// * `weights` - One non-negative weight per model
+    pub fn predict(
+        &self,
+        inputs: &[&[(Index, f32)]],
+        beam_size: usize,
+        weights: &[f32],
+    ) -> IndexValueVec {
+        assert_eq!(
+            self.models.len(),
+            inputs.len(),
+            "Expected one input vector per model"
+        );
+        assert_eq!(
+            self.models.len(),
+            weights.len(),
+            "Expected one weight per model"
+        );
+        assert!(
+            weights.iter().all(|&w| w >= 0.),
+            "Weights must be non-negative"
+        );
+
+        let model_predictions: Vec<_> = self
+            .models
+            .par_iter()
+            .zip(inputs.par_iter())
+            .map(|(model, feature_vec)| model.predict(feature_vec, beam_size))
+            .collect();
+
+        let mut label_to_score = HashMap::<Index, f32>::new();
+        for (label_score_pairs, &weight) in model_predictions.iter().zip(weights) {
+            for &(label, score) in label_score_pairs {
+                let combined = label_to_score.entry(label).or_insert(0.);
+                match self.combiner {
+                    Combiner::WeightedSum | Combiner::WeightedMean => *combined += weight * score,
+                    Combiner::WeightedMax => *combined = combined.max(weight * score),
+                }
+            }
+        }
+        if self.combiner == Combiner::WeightedMean {
+            let total_weight = weights.iter().sum::<f32>();
+            assert!(total_weight > 0., "Weights must not all be zero");
+            label_to_score
+                .values_mut()
+                .for_each(|score| *score /= total_weight);
+        }
+
+        let mut label_score_pairs = label_to_score.into_iter().collect_vec();
+        label_score_pairs.sort_unstable_by_key(|&pair| label_rank(pair));
+        debug_assert!(
+            label_score_pairs
+                .iter()
+                .all(|&(_, score)| scores::is_within(score, self.score_range(weights))),
+            "Scores outside of {:?}",
+            self.score_range(weights)
+        );
+        label_score_pairs
+    }
+
+    /// Serialize the ensemble into the directory with the given path.
+    ///
+    /// Each model is saved in its own sub-directory with [`Model::save`].
+    pub fn save<P: AsRef<std::path::Path>>(&self, dir_path: P) -> io::Result<()> {
+        info!("Saving ensemble...");
+        let start_t = time::Instant::now();
+
+        let dir_path = dir_path.as_ref();
+        std::fs::create_dir_all(dir_path)?;
+        let settings = EnsembleSettings {
+            combiner: self.combiner,
+            n_models: self.models.len(),
+        };
+        io_sink::write_atomically(
+            &io_sink::FileSink,
+            &dir_path.join(ENSEMBLE_SETTINGS_FILE_NAME),
+            |writer| {
+                serde_json::to_writer_pretty(writer, &settings).map_err(|e| {
+                    io::Error::new(
+                        io::ErrorKind::Other,
+                        format!("Unable to serialize ensemble settings: {}", e),
+                    )
+                })
+            },
+        )?;
+
+        for (i, model) in self.models.iter().enumerate() {
+            model.save(dir_path.join(format!("{}{}", MODEL_DIR_NAME_PREFIX, i)))?;
+        }
+
+        info!(
+            "Ensemble saved; it took {:.2}s",
+            start_t.elapsed().as_secs_f32()
+        );
+        Ok(())
+    }
+
+    /// Deserialize an ensemble from the given directory.
+    pub fn load<P: AsRef<std::path::Path>>(dir_path: P) -> io::Result<Self> {
+        let dir_path = dir_path.as_ref();
+        info!("Loading ensemble from {}...", dir_path.display());
+
+        let reader = std::io::BufReader::new(std::fs::File::open(
+            dir_path.join(ENSEMBLE_SETTINGS_FILE_NAME),
+        )?);
+        let EnsembleSettings { combiner, n_models } = serde_json::from_reader(reader)?;
+        let models = (0..n_models)
+            .map(|i| Model::load(dir_path.join(format!("{}{}", MODEL_DIR_NAME_PREFIX, i))))
+            .collect::<io::Result<Vec<_>>>()?;
+
+        Self::new(models, combiner).map_err(|msg| io::Error::new(io::ErrorKind::InvalidData, msg))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::TrainHyperParam;
+    use crate::test_util::{toy_dataset, toy_model};
+    use assert_approx_eq::assert_approx_eq;
+
+    fn two_models() -> (Model, Model) {
+        (toy_model(1, 0), toy_model(2, 1))
+    }
+
+    fn to_map(pairs: IndexValueVec) -> HashMap<Index, f32> {
+        pairs.into_iter().collect()
+    }
+
+    #[test]
+    fn test_weighted_combination() {
+        let (model0, model1) = two_models();
+        let dataset = toy_dataset(10, 8, 2);
+        let weights = [0.25, 0.75];
+
+        for &combiner in &[
+            Combiner::WeightedSum,
+            Combiner::WeightedMean,
+            Combiner::WeightedMax,
+        ] {
+            let ensemble = Ensemble::new(vec![model0.clone(), model1.clone()], combiner).unwrap();
+            for feature_vec in &dataset.feature_lists {
+                let scores0 = to_map(model0.predict(feature_vec, 3));
+                let scores1 = to_map(model1.predict(feature_vec, 3));
+                let predictions = ensemble.predict(
+                    &[feature_vec.as_slice(), feature_vec.as_slice()],
+                    3,
+                    &weights,
+                );
+                assert_eq!(
+                    scores0.keys().chain(scores1.keys()).unique().count(),
+                    predictions.len()
+                );
+                assert!(predictions.windows(2).all(|w| w[0].1 >= w[1].1));
+
+                for (label, score) in predictions {
+                    let s0 = weights[0] * scores0.get(&label).cloned().unwrap_or(0.);
+                    let s1 = weights[1] * scores1.get(&label).cloned().unwrap_or(0.);
+                    let expected = match combiner {
+                        Combiner::WeightedSum => s0 + s1,
+                        Combiner::WeightedMean => s0 + s1, // Weights sum up to 1
+                        Combiner::WeightedMax => s0.max(s1),
+                    };
+                    assert_approx_eq!(expected, score, 1e-6);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_score_range() {
+        let (model0, model1) = two_models();
+        assert_eq!((0., 1.), model0.score_range());
+        let weights = [0.5, 2.];
+        for &(combiner, expected) in &[
+            (Combiner::WeightedSum, (0., 2.5)),
+            (Combiner::WeightedMean, (0., 1.)),
+            (Combiner::WeightedMax, (0., 2.)),
+        ] {
+            let ensemble = Ensemble::new(vec![model0.clone(), model1.clone()], combiner).unwrap();
+            let range = ensemble.score_range(&weights);
+            assert_eq!(expected, range);
+            for feature_vec in &toy_dataset(10, 8, 2).feature_lists {
+                let inputs = [feature_vec.as_slice(), feature_vec.as_slice()];
+                for (_, score) in ensemble.predict(&inputs, 10, &weights) {
+                    assert!(scores::is_within(score, range), "{:?}: {}", combiner, score);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_mismatched_label_spaces() {
+        let mut hyper_param = TrainHyperParam::default();
+        hyper_param.n_trees = 1;
+        let other = hyper_param.train(toy_dataset(20, 4, 0));
+
+        assert!(Ensemble::new(vec![], Combiner::default()).is_err());
+        assert!(Ensemble::new(vec![toy_model(1, 0), other], Combiner::default()).is_err());
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_mismatched_weights() {
+        let (model0, model1) = two_models();
+        let ensemble = Ensemble::new(vec![model0, model1], Combiner::default()).unwrap();
+        ensemble.predict(&[&[(0, 1.)], &[(0, 1.)]], 3, &[1.]);
+    }
+
+    #[test]
+    fn test_save_and_load() {
+        let (model0, model1) = two_models();
+        let ensemble = Ensemble::new(vec![model0, model1], Combiner::WeightedMax).unwrap();
+        let dir = std::env::temp_dir().join(format!("omikuji-ensemble-{}", std::process::id()));
+        ensemble.save(&dir).unwrap();
+        let loaded = Ensemble::load(&dir).unwrap();
+
+        assert_eq!(Combiner::WeightedMax, loaded.combiner());
+        assert_eq!(2, loaded.models().len());
+        for feature_vec in &toy_dataset(10, 8, 2).feature_lists {
+            let inputs = [feature_vec.as_slice(), feature_vec.as_slice()];
+            assert_eq!(
+                ensemble.predict(&inputs, 3, &[1., 2.]),
+                loaded.predict(&inputs, 3, &[1., 2.])
+            );
+        }
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
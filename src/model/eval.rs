@@ -1,39 +1,845 @@
+use super::multi_beam;
 use crate::util::create_progress_bar;
-use crate::{DataSet, Index, IndexValueVec, Model};
-use hashbrown::HashSet;
-use itertools::izip;
+use crate::{DataSet, FloatFormat, Index, IndexValueVec, Model, Warning, Warnings};
+use hashbrown::{HashMap, HashSet};
+use itertools::{izip, Itertools};
 use log::info;
 use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::io::{self, BufRead, Write};
 use std::sync::Mutex;
 use std::time;
 
-fn precision_at_k(
+/// A scalar metric for scoring predictions against true labels, where higher is better.
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Metric {
+    /// Precision at the given k.
+    PrecisionAtK(usize),
+    /// Normalized discounted cumulative gain at the given k, see [`ndcg_at_k`].
+    NdcgAtK(usize),
+    /// Recall at the given k, see [`recall_at_k`].
+    RecallAtK(usize),
+}
+
+impl Metric {
+    /// Check if the metric settings are valid.
+    pub fn validate(&self) -> Result<(), String> {
+        match *self {
+            Metric::PrecisionAtK(0) => Err("k must be positive for precision@k".to_owned()),
+            Metric::NdcgAtK(0) => Err("k must be positive for nDCG@k".to_owned()),
+            Metric::RecallAtK(0) => Err("k must be positive for recall@k".to_owned()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Compute the metric averaged over all examples.
+    pub fn compute(
+        &self,
+        true_labels: &[HashSet<Index>],
+        predicted_labels: &[IndexValueVec],
+    ) -> f32 {
+        match *self {
+            Metric::PrecisionAtK(k) => precision_at_k(k, true_labels, predicted_labels)[k - 1],
+            Metric::NdcgAtK(k) => ndcg_at_k(k, true_labels, predicted_labels)[k - 1],
+            Metric::RecallAtK(k) => recall_at_k(k, true_labels, predicted_labels)[k - 1],
+        }
+    }
+}
+
+/// Predict for every example in the dataset in parallel, without logging or progress reporting.
+pub fn predict_all(model: &Model, dataset: &DataSet, beam_size: usize) -> Vec<IndexValueVec> {
+    dataset
+        .feature_lists
+        .par_iter()
+        .map(|feature_vec| model.predict(feature_vec, beam_size))
+        .collect()
+}
+
+/// How gold labels that never appear in the model are treated when computing metrics.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum UnseenLabelMode {
+    /// Unseen labels count as misses, as if the model failed to predict them.
+    Penalize,
+    /// Unseen labels are removed from the gold label sets, and examples left without gold labels
+    /// are left out of the averages.
+    Exclude,
+}
+
+/// Counts of gold labels in test data that never appear in the model, e.g., because they didn't
+/// occur in the training split.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UnseenLabelCounts {
+    /// The number of distinct gold labels not in the model.
+    pub unseen_gold_labels: usize,
+    /// The number of examples with at least one gold label not in the model.
+    pub examples_with_unseen_labels: usize,
+}
+
+impl UnseenLabelCounts {
+    /// Count the gold labels not among the given sorted model labels.
+    pub fn count(model_labels: &[Index], true_labels: &[HashSet<Index>]) -> Self {
+        let is_unseen = |label: &&Index| model_labels.binary_search(label).is_err();
+        Self {
+            unseen_gold_labels: true_labels
+                .iter()
+                .flatten()
+                .filter(is_unseen)
+                .unique()
+                .count(),
+            examples_with_unseen_labels: true_labels
+                .iter()
+                .filter(|labels| labels.iter().any(|label| is_unseen(&label)))
+                .count(),
+        }
+    }
+}
+
+/// Precision@k and nDCG@k under both modes of treating gold labels not in the model, side by
+/// side.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct UnseenLabelEvaluation {
+    pub counts: UnseenLabelCounts,
+    /// Precision@k for k = 1, ..., max_k with [`UnseenLabelMode::Penalize`].
+    pub penalize_precisions: Vec<f32>,
+    /// Precision@k for k = 1, ..., max_k with [`UnseenLabelMode::Exclude`].
+    pub exclude_precisions: Vec<f32>,
+    /// nDCG@k for k = 1, ..., max_k with [`UnseenLabelMode::Penalize`].
+    #[serde(default)]
+    pub penalize_ndcgs: Vec<f32>,
+    /// nDCG@k for k = 1, ..., max_k with [`UnseenLabelMode::Exclude`].
+    #[serde(default)]
+    pub exclude_ndcgs: Vec<f32>,
+}
+
+/// Compute precision@k for k = 1, ..., max_k, treating gold labels not in the model as the given
+/// mode says.
+pub fn precision_at_k_with_mode(
+    model: &Model,
     max_k: usize,
     true_labels: &[HashSet<Index>],
     predicted_labels: &[IndexValueVec],
+    mode: UnseenLabelMode,
 ) -> Vec<f32> {
+    match mode {
+        UnseenLabelMode::Penalize => precision_at_k(max_k, true_labels, predicted_labels),
+        UnseenLabelMode::Exclude => {
+            let (true_labels, predicted_labels) =
+                exclude_unseen_labels(&model.labels(), true_labels, predicted_labels);
+            precision_at_k(max_k, &true_labels, &predicted_labels)
+        }
+    }
+}
+
+/// Like [`precision_at_k_with_mode`], but computes nDCG@k.
+pub fn ndcg_at_k_with_mode(
+    model: &Model,
+    max_k: usize,
+    true_labels: &[HashSet<Index>],
+    predicted_labels: &[IndexValueVec],
+    mode: UnseenLabelMode,
+) -> Vec<f32> {
+    match mode {
+        UnseenLabelMode::Penalize => ndcg_at_k(max_k, true_labels, predicted_labels),
+        UnseenLabelMode::Exclude => {
+            let (true_labels, predicted_labels) =
+                exclude_unseen_labels(&model.labels(), true_labels, predicted_labels);
+            ndcg_at_k(max_k, &true_labels, &predicted_labels)
+        }
+    }
+}
+
+/// Count gold labels not in the model, and compute precision@k and nDCG@k for k = 1, ..., max_k
+/// in both modes of treating them.
+pub fn evaluate_unseen_labels(
+    model: &Model,
+    max_k: usize,
+    true_labels: &[HashSet<Index>],
+    predicted_labels: &[IndexValueVec],
+) -> UnseenLabelEvaluation {
+    let model_labels = model.labels();
+    let (seen_true_labels, seen_predicted_labels) =
+        exclude_unseen_labels(&model_labels, true_labels, predicted_labels);
+    UnseenLabelEvaluation {
+        counts: UnseenLabelCounts::count(&model_labels, true_labels),
+        penalize_precisions: precision_at_k(max_k, true_labels, predicted_labels),
+        exclude_precisions: precision_at_k(max_k, &seen_true_labels, &seen_predicted_labels),
+        penalize_ndcgs: ndcg_at_k(max_k, true_labels, predicted_labels),
+        exclude_ndcgs: ndcg_at_k(max_k, &seen_true_labels, &seen_predicted_labels),
+    }
+}
+
+/// Remove gold labels not among the given sorted model labels, and drop examples left without
+/// gold labels.
+fn exclude_unseen_labels(
+    model_labels: &[Index],
+    true_labels: &[HashSet<Index>],
+    predicted_labels: &[IndexValueVec],
+) -> (Vec<HashSet<Index>>, Vec<IndexValueVec>) {
     assert_eq!(true_labels.len(), predicted_labels.len());
-    let mut ps = vec![0.; max_k];
-    for (truth, predictions) in izip!(true_labels, predicted_labels) {
+    izip!(true_labels, predicted_labels)
+        .filter_map(|(truth, predictions)| {
+            let seen: HashSet<_> = truth
+                .iter()
+                .filter(|label| model_labels.binary_search(label).is_ok())
+                .cloned()
+                .collect();
+            if seen.is_empty() {
+                None
+            } else {
+                Some((seen, predictions.clone()))
+            }
+        })
+        .unzip()
+}
+
+fn precision_at_k(
+    max_k: usize,
+    true_labels: &[HashSet<Index>],
+    predicted_labels: &[IndexValueVec],
+) -> Vec<f32> {
+    MetricAccumulator::over(max_k, None, true_labels, predicted_labels).precisions()
+}
+
+/// Compute nDCG@k for k = 1, ..., max_k, averaged over all examples.
+///
+/// A true label at rank r, counting from 1, gains 1 / log2(r + 1); the discounted cumulative
+/// gain of the top k predictions is divided by that of an ideal ranking, i.e., one with all
+/// true labels first, up to k of them. Examples without true labels score 0 but still count in
+/// the average, as they do for precision@k.
+pub fn ndcg_at_k(
+    max_k: usize,
+    true_labels: &[HashSet<Index>],
+    predicted_labels: &[IndexValueVec],
+) -> Vec<f32> {
+    MetricAccumulator::over(max_k, None, true_labels, predicted_labels).ndcgs()
+}
+
+/// Compute recall@k for k = 1, ..., max_k, i.e., the fraction of true labels among the top k
+/// predictions, averaged over all examples.
+///
+/// Examples without true labels score 0 but still count in the average, as they do for
+/// precision@k.
+pub fn recall_at_k(
+    max_k: usize,
+    true_labels: &[HashSet<Index>],
+    predicted_labels: &[IndexValueVec],
+) -> Vec<f32> {
+    MetricAccumulator::over(max_k, None, true_labels, predicted_labels).recalls()
+}
+
+/// Running sums of metrics over examples, so that metrics can be computed over examples seen a
+/// chunk at a time, e.g., when evaluating on data that doesn't fit in memory.
+///
+/// Metrics are the same as those computed over all examples at once, as long as examples are
+/// added in the same order. Sums are kept in double precision, so that they stay accurate over
+/// millions of examples.
+#[derive(Clone, Debug)]
+pub struct MetricAccumulator {
+    n_examples: usize,
+    precision_sums: Vec<f64>,
+    recall_sums: Vec<f64>,
+    ndcg_sums: Vec<f64>,
+    /// The gain of a true label at each rank, for nDCG@k.
+    gains: Vec<f64>,
+    threshold: Option<f32>,
+    label_to_counts: HashMap<Index, LabelCounts>,
+}
+
+impl MetricAccumulator {
+    /// Prepare to accumulate metrics at k = 1, ..., max_k, and at the given score threshold, if
+    /// any.
+    pub fn new(max_k: usize, threshold: Option<f32>) -> Self {
+        Self {
+            n_examples: 0,
+            precision_sums: vec![0.; max_k],
+            recall_sums: vec![0.; max_k],
+            ndcg_sums: vec![0.; max_k],
+            gains: (0..max_k).map(|k| 1. / ((k + 2) as f64).log2()).collect(),
+            threshold,
+            label_to_counts: HashMap::new(),
+        }
+    }
+
+    /// Accumulate metrics over all the given examples.
+    fn over(
+        max_k: usize,
+        threshold: Option<f32>,
+        true_labels: &[HashSet<Index>],
+        predicted_labels: &[IndexValueVec],
+    ) -> Self {
+        assert_eq!(true_labels.len(), predicted_labels.len());
+        let mut accumulator = Self::new(max_k, threshold);
+        for (truth, predictions) in izip!(true_labels, predicted_labels) {
+            accumulator.add(truth, predictions);
+        }
+        accumulator
+    }
+
+    /// Add an example with the given true labels and predictions, sorted by descending score.
+    pub fn add(&mut self, truth: &HashSet<Index>, predictions: &[(Index, f32)]) {
+        self.n_examples += 1;
+        if let Some(threshold) = self.threshold {
+            count_example(
+                &mut self.label_to_counts,
+                Cutoff::Threshold(threshold),
+                truth,
+                predictions,
+            );
+        }
+
+        // Precision@k only counts as far as there are predictions, so examples with fewer than k
+        // predictions score 0 at k
+        let max_k = self.precision_sums.len();
         let mut n_correct = 0;
         for k in 0..max_k.min(predictions.len()) {
             if truth.contains(&predictions[k].0) {
                 n_correct += 1;
             }
-            ps[k] += n_correct as f32 / (k + 1) as f32;
+            self.precision_sums[k] += n_correct as f64 / (k + 1) as f64;
+        }
+
+        if truth.is_empty() {
+            return;
+        }
+        let mut n_correct = 0;
+        let mut dcg = 0.;
+        let mut ideal_dcg = 0.;
+        for k in 0..max_k {
+            if k < predictions.len() && truth.contains(&predictions[k].0) {
+                n_correct += 1;
+                dcg += self.gains[k];
+            }
+            if k < truth.len() {
+                ideal_dcg += self.gains[k];
+            }
+            self.recall_sums[k] += n_correct as f64 / truth.len() as f64;
+            self.ndcg_sums[k] += dcg / ideal_dcg;
+        }
+    }
+
+    /// The number of examples added so far.
+    pub fn n_examples(&self) -> usize {
+        self.n_examples
+    }
+
+    fn averages(&self, sums: &[f64]) -> Vec<f32> {
+        sums.iter()
+            .map(|&sum| (sum / self.n_examples as f64) as f32)
+            .collect()
+    }
+
+    /// Precision@k for k = 1, ..., max_k over the examples so far.
+    pub fn precisions(&self) -> Vec<f32> {
+        self.averages(&self.precision_sums)
+    }
+
+    /// Recall@k for k = 1, ..., max_k over the examples so far, see [`recall_at_k`].
+    pub fn recalls(&self) -> Vec<f32> {
+        self.averages(&self.recall_sums)
+    }
+
+    /// nDCG@k for k = 1, ..., max_k over the examples so far, see [`ndcg_at_k`].
+    pub fn ndcgs(&self) -> Vec<f32> {
+        self.averages(&self.ndcg_sums)
+    }
+
+    /// All metrics over the examples so far.
+    pub fn metrics(&self) -> EvaluationMetrics {
+        EvaluationMetrics {
+            precisions: self.precisions(),
+            recalls: self.recalls(),
+            ndcgs: self.ndcgs(),
+            threshold_metrics: self.threshold.map(|threshold| {
+                ThresholdMetrics::from_label_counts(threshold, &self.label_to_counts)
+            }),
+        }
+    }
+}
+
+/// Precision, recall, and their harmonic mean.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct PrecisionRecallF1 {
+    pub precision: f32,
+    pub recall: f32,
+    pub f1: f32,
+}
+
+impl PrecisionRecallF1 {
+    /// Compute from counts of true positives, false positives, and false negatives; each ratio
+    /// with a zero denominator is taken as 0.
+    fn from_counts(counts: LabelCounts) -> Self {
+        let ratio = |n: usize, d: usize| if d == 0 { 0. } else { n as f32 / d as f32 };
+        let precision = ratio(counts.n_true_positives, counts.n_predicted());
+        let recall = ratio(counts.n_true_positives, counts.n_true());
+        Self {
+            precision,
+            recall,
+            f1: f1(precision, recall),
+        }
+    }
+}
+
+fn f1(precision: f32, recall: f32) -> f32 {
+    if precision + recall == 0. {
+        0.
+    } else {
+        2. * precision * recall / (precision + recall)
+    }
+}
+
+/// Counts of how predictions of a label, or of all labels, compare with the true labels.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+struct LabelCounts {
+    n_true_positives: usize,
+    n_false_positives: usize,
+    n_false_negatives: usize,
+}
+
+impl LabelCounts {
+    fn n_predicted(&self) -> usize {
+        self.n_true_positives + self.n_false_positives
+    }
+
+    fn n_true(&self) -> usize {
+        self.n_true_positives + self.n_false_negatives
+    }
+
+    fn add(&mut self, other: LabelCounts) {
+        self.n_true_positives += other.n_true_positives;
+        self.n_false_positives += other.n_false_positives;
+        self.n_false_negatives += other.n_false_negatives;
+    }
+}
+
+/// Which of the labels returned by [`Model::predict`] count as predicted.
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Cutoff {
+    /// The given number of labels with the highest scores.
+    TopK(usize),
+    /// Labels with scores at or above the given threshold.
+    Threshold(f32),
+}
+
+impl Cutoff {
+    /// The labels predicted among the given predictions, which are sorted by descending score.
+    fn predicted(&self, predictions: &[(Index, f32)]) -> HashSet<Index> {
+        match *self {
+            Cutoff::TopK(k) => predictions
+                .iter()
+                .take(k)
+                .map(|&(label, _)| label)
+                .collect(),
+            Cutoff::Threshold(threshold) => predictions
+                .iter()
+                .filter(|&&(_, score)| score >= threshold)
+                .map(|&(label, _)| label)
+                .collect(),
+        }
+    }
+}
+
+/// Count, for each label, how predictions within the cutoff compare with the true labels.
+///
+/// Labels that are neither predicted nor true in any example are left out.
+fn count_by_label(
+    cutoff: Cutoff,
+    true_labels: &[HashSet<Index>],
+    predicted_labels: &[IndexValueVec],
+) -> HashMap<Index, LabelCounts> {
+    assert_eq!(true_labels.len(), predicted_labels.len());
+    let mut label_to_counts = HashMap::<Index, LabelCounts>::new();
+    for (truth, predictions) in izip!(true_labels, predicted_labels) {
+        count_example(&mut label_to_counts, cutoff, truth, predictions);
+    }
+    label_to_counts
+}
+
+/// Add the counts of a single example to those of each label.
+fn count_example(
+    label_to_counts: &mut HashMap<Index, LabelCounts>,
+    cutoff: Cutoff,
+    truth: &HashSet<Index>,
+    predictions: &[(Index, f32)],
+) {
+    let predicted = cutoff.predicted(predictions);
+    for &label in &predicted {
+        let counts = label_to_counts.entry(label).or_default();
+        if truth.contains(&label) {
+            counts.n_true_positives += 1;
+        } else {
+            counts.n_false_positives += 1;
+        }
+    }
+    for &label in truth.difference(&predicted) {
+        label_to_counts.entry(label).or_default().n_false_negatives += 1;
+    }
+}
+
+/// Micro- and macro-averaged metrics of labels with the given counts.
+fn average_counts(
+    label_counts: impl Iterator<Item = LabelCounts>,
+) -> (PrecisionRecallF1, PrecisionRecallF1) {
+    let mut total = LabelCounts::default();
+    let mut macro_sum = PrecisionRecallF1::default();
+    let mut n_labels = 0;
+    for counts in label_counts {
+        total.add(counts);
+        let metrics = PrecisionRecallF1::from_counts(counts);
+        macro_sum.precision += metrics.precision;
+        macro_sum.recall += metrics.recall;
+        macro_sum.f1 += metrics.f1;
+        n_labels += 1;
+    }
+
+    let macro_averaged = if n_labels == 0 {
+        PrecisionRecallF1::default()
+    } else {
+        PrecisionRecallF1 {
+            precision: macro_sum.precision / n_labels as f32,
+            recall: macro_sum.recall / n_labels as f32,
+            f1: macro_sum.f1 / n_labels as f32,
+        }
+    };
+    (PrecisionRecallF1::from_counts(total), macro_averaged)
+}
+
+/// Precision, recall, and F1 of the labels predicted with scores at or above a threshold.
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ThresholdMetrics {
+    /// The score threshold, which applies to the scores returned by [`Model::predict`].
+    pub threshold: f32,
+    /// Metrics over all pairs of examples and labels at once.
+    pub micro_averaged: PrecisionRecallF1,
+    /// Metrics of each label, averaged over labels.
+    ///
+    /// Labels that are neither predicted nor true in any example are left out of the averages.
+    /// The F1 score is the average of the F1 scores of labels, rather than that of the averaged
+    /// precision and recall.
+    pub macro_averaged: PrecisionRecallF1,
+    /// The number of labels in the macro averages.
+    pub n_macro_averaged_labels: usize,
+}
+
+impl ThresholdMetrics {
+    /// Compute metrics of predictions with scores at or above the given threshold.
+    pub fn compute(
+        threshold: f32,
+        true_labels: &[HashSet<Index>],
+        predicted_labels: &[IndexValueVec],
+    ) -> Self {
+        let label_to_counts =
+            count_by_label(Cutoff::Threshold(threshold), true_labels, predicted_labels);
+        Self::from_label_counts(threshold, &label_to_counts)
+    }
+
+    fn from_label_counts(threshold: f32, label_to_counts: &HashMap<Index, LabelCounts>) -> Self {
+        // Labels are averaged in order, so that the averages don't depend on the order of the map
+        let (micro_averaged, macro_averaged) = average_counts(
+            label_to_counts
+                .iter()
+                .sorted_by_key(|&(&label, _)| label)
+                .map(|(_, &counts)| counts),
+        );
+        Self {
+            threshold,
+            micro_averaged,
+            macro_averaged,
+            n_macro_averaged_labels: label_to_counts.len(),
+        }
+    }
+}
+
+/// Metrics of predictions against true labels.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct EvaluationMetrics {
+    /// Precision@k for k = 1, ..., max_k.
+    pub precisions: Vec<f32>,
+    /// Recall@k for k = 1, ..., max_k.
+    pub recalls: Vec<f32>,
+    /// nDCG@k for k = 1, ..., max_k.
+    pub ndcgs: Vec<f32>,
+    /// Metrics at a score threshold, if one was given.
+    #[serde(default)]
+    pub threshold_metrics: Option<ThresholdMetrics>,
+}
+
+impl EvaluationMetrics {
+    /// Compute metrics at k = 1, ..., max_k, and at the given score threshold, if any.
+    pub fn compute(
+        max_k: usize,
+        threshold: Option<f32>,
+        true_labels: &[HashSet<Index>],
+        predicted_labels: &[IndexValueVec],
+    ) -> Self {
+        MetricAccumulator::over(max_k, threshold, true_labels, predicted_labels).metrics()
+    }
+}
+
+/// How predictions of a single label compare with the true labels.
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct LabelMetrics {
+    pub label: Index,
+    /// The number of examples where the label is predicted and true.
+    pub n_true_positives: usize,
+    /// The number of examples where the label is predicted but not true.
+    pub n_false_positives: usize,
+    /// The number of examples where the label is true but not predicted.
+    pub n_false_negatives: usize,
+    /// The number of examples where the label is true and among the labels returned by
+    /// [`Model::predict`], whether predicted or not.
+    pub n_ranked: usize,
+    /// The average rank of the label, counting from 1, over the examples where it's true and
+    /// among the labels returned; none if there are no such examples.
+    pub mean_rank: Option<f32>,
+}
+
+impl LabelMetrics {
+    /// The number of examples where the label is true, i.e., its frequency in the test set.
+    pub fn n_true(&self) -> usize {
+        self.counts().n_true()
+    }
+
+    /// Precision, recall, and F1 of the label, where each ratio with a zero denominator is
+    /// taken as 0.
+    pub fn metrics(&self) -> PrecisionRecallF1 {
+        PrecisionRecallF1::from_counts(self.counts())
+    }
+
+    fn counts(&self) -> LabelCounts {
+        LabelCounts {
+            n_true_positives: self.n_true_positives,
+            n_false_positives: self.n_false_positives,
+            n_false_negatives: self.n_false_negatives,
+        }
+    }
+}
+
+/// Compute metrics of each label that is predicted within the cutoff or true in some example,
+/// sorted by label.
+pub fn evaluate_labels(
+    cutoff: Cutoff,
+    true_labels: &[HashSet<Index>],
+    predicted_labels: &[IndexValueVec],
+) -> Vec<LabelMetrics> {
+    let label_to_counts = count_by_label(cutoff, true_labels, predicted_labels);
+    let mut label_to_ranks = HashMap::<Index, (usize, usize)>::new();
+    for (truth, predictions) in izip!(true_labels, predicted_labels) {
+        for (rank, &(label, _)) in (1..).zip(predictions) {
+            if truth.contains(&label) {
+                let (n_ranked, rank_sum) = label_to_ranks.entry(label).or_default();
+                *n_ranked += 1;
+                *rank_sum += rank;
+            }
         }
     }
-    for p in &mut ps {
-        *p /= predicted_labels.len() as f32;
+
+    label_to_counts
+        .into_iter()
+        .map(|(label, counts)| {
+            let (n_ranked, rank_sum) = label_to_ranks.get(&label).copied().unwrap_or_default();
+            LabelMetrics {
+                label,
+                n_true_positives: counts.n_true_positives,
+                n_false_positives: counts.n_false_positives,
+                n_false_negatives: counts.n_false_negatives,
+                n_ranked,
+                mean_rank: if n_ranked > 0 {
+                    Some(rank_sum as f32 / n_ranked as f32)
+                } else {
+                    None
+                },
+            }
+        })
+        .sorted_by_key(|metrics| metrics.label)
+        .collect()
+}
+
+/// Count the examples each label is true in, e.g., to bucket labels by their frequency in the
+/// training set with [`bucket_by_frequency`].
+pub fn label_frequencies(true_labels: &[HashSet<Index>]) -> HashMap<Index, usize> {
+    let mut label_to_frequency = HashMap::new();
+    for &label in true_labels.iter().flatten() {
+        *label_to_frequency.entry(label).or_insert(0) += 1;
+    }
+    label_to_frequency
+}
+
+/// Metrics of labels aggregated over a bucket of labels with similar frequencies.
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct FrequencyBucket {
+    /// The lowest frequency of labels in the bucket.
+    pub min_frequency: usize,
+    /// The highest frequency of labels in the bucket.
+    pub max_frequency: usize,
+    /// The number of labels in the bucket.
+    pub n_labels: usize,
+    pub n_true_positives: usize,
+    pub n_false_positives: usize,
+    pub n_false_negatives: usize,
+    /// Metrics over all pairs of examples and labels in the bucket at once.
+    pub micro_averaged: PrecisionRecallF1,
+    /// Metrics of each label in the bucket, averaged over labels.
+    pub macro_averaged: PrecisionRecallF1,
+    /// The average rank of labels in the bucket where they're true and ranked, if ever.
+    pub mean_rank: Option<f32>,
+}
+
+/// Split labels into the given number of buckets by their frequencies, e.g., in the training set
+/// as counted by [`label_frequencies`], and aggregate their metrics in each bucket.
+///
+/// Buckets are ordered from the most to the least frequent labels and have the same number of
+/// labels, give or take one; e.g., 10 buckets are deciles. Labels missing from the given
+/// frequencies have a frequency of 0. Buckets that would be empty, since there are fewer labels
+/// than buckets, are left out. Zero buckets is an error.
+pub fn bucket_by_frequency(
+    label_metrics: &[LabelMetrics],
+    label_to_frequency: &HashMap<Index, usize>,
+    n_buckets: usize,
+) -> Result<Vec<FrequencyBucket>, String> {
+    if n_buckets == 0 {
+        return Err("The number of buckets must be positive".to_owned());
     }
-    ps
+    let frequency_of =
+        |metrics: &LabelMetrics| label_to_frequency.get(&metrics.label).copied().unwrap_or(0);
+    let sorted = label_metrics
+        .iter()
+        .sorted_by_key(|metrics| (std::cmp::Reverse(frequency_of(metrics)), metrics.label))
+        .collect_vec();
+
+    let n_labels = sorted.len();
+    Ok((0..n_buckets)
+        .map(|i| &sorted[i * n_labels / n_buckets..(i + 1) * n_labels / n_buckets])
+        .filter(|bucket| !bucket.is_empty())
+        .map(|bucket| {
+            let (micro_averaged, macro_averaged) =
+                average_counts(bucket.iter().map(|metrics| metrics.counts()));
+            let n_ranked = bucket.iter().map(|metrics| metrics.n_ranked).sum::<usize>();
+            let rank_sum = bucket
+                .iter()
+                .filter_map(|metrics| Some(metrics.mean_rank? * metrics.n_ranked as f32))
+                .sum::<f32>();
+            FrequencyBucket {
+                min_frequency: frequency_of(bucket[bucket.len() - 1]),
+                max_frequency: frequency_of(bucket[0]),
+                n_labels: bucket.len(),
+                n_true_positives: bucket.iter().map(|m| m.n_true_positives).sum(),
+                n_false_positives: bucket.iter().map(|m| m.n_false_positives).sum(),
+                n_false_negatives: bucket.iter().map(|m| m.n_false_negatives).sum(),
+                micro_averaged,
+                macro_averaged,
+                mean_rank: if n_ranked > 0 {
+                    Some(rank_sum / n_ranked as f32)
+                } else {
+                    None
+                },
+            }
+        })
+        .collect())
+}
+
+/// Results of evaluating a model on a test set.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct EvaluationResults {
+    /// The number of test examples.
+    pub n_examples: usize,
+    /// The beam size predictions were made with.
+    pub beam_size: usize,
+    /// The wall-clock time spent predicting, in seconds.
+    pub prediction_secs: f32,
+    pub metrics: EvaluationMetrics,
+    /// Metrics with gold labels not in the model penalized and excluded, if there are any such
+    /// labels.
+    #[serde(default)]
+    pub unseen_labels: Option<UnseenLabelEvaluation>,
+}
+
+impl EvaluationResults {
+    /// Log the results, with metrics at k = 1, 3, and 5 as far as they were computed.
+    pub fn log(&self) {
+        info!(
+            "Done testing on {} examples with beam size {}; it took {:.2}s",
+            self.n_examples, self.beam_size, self.prediction_secs
+        );
+        info!("{}", format_at_ks("Precision", &self.metrics.precisions));
+        info!("{}", format_at_ks("nDCG", &self.metrics.ndcgs));
+        info!("{}", format_at_ks("Recall", &self.metrics.recalls));
+        if let Some(threshold_metrics) = self.metrics.threshold_metrics.as_ref() {
+            for (averaging, metrics) in &[
+                ("Micro", threshold_metrics.micro_averaged),
+                ("Macro", threshold_metrics.macro_averaged),
+            ] {
+                info!(
+                    "{}-averaged at threshold {}: precision = {:.2}, recall = {:.2}, F1 = {:.2}",
+                    averaging,
+                    threshold_metrics.threshold,
+                    metrics.precision * 100.,
+                    metrics.recall * 100.,
+                    metrics.f1 * 100.,
+                );
+            }
+        }
+        if let Some(evaluation) = self.unseen_labels.as_ref() {
+            info!(
+                "{} labels in {} examples never appear in the model; excluding them, {}",
+                evaluation.counts.unseen_gold_labels,
+                evaluation.counts.examples_with_unseen_labels,
+                format_at_ks("Precision", &evaluation.exclude_precisions),
+            );
+        }
+    }
+
+    /// Write the results as JSON.
+    pub fn write_json<W: Write>(&self, writer: W) -> io::Result<()> {
+        serde_json::to_writer_pretty(writer, self).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::Other,
+                format!("Unable to serialize evaluation results: {}", e),
+            )
+        })
+    }
+}
+
+/// Format the values of a metric at k = 1, 3, and 5 as percentages, leaving out values that
+/// weren't computed.
+fn format_at_ks(name: &str, values: &[f32]) -> String {
+    let ks = [1, 3, 5]
+        .iter()
+        .cloned()
+        .filter(|&k| k <= values.len())
+        .collect_vec();
+    format!(
+        "{}@[{}] = [{}]",
+        name,
+        ks.iter().join(", "),
+        ks.iter()
+            .map(|&k| format!("{:.2}", values[k - 1] * 100.))
+            .join(", ")
+    )
 }
 
 pub fn test_all(
     model: &Model,
     test_dataset: &DataSet,
     beam_size: usize,
-) -> (Vec<IndexValueVec>, Vec<f32>) {
+) -> (Vec<IndexValueVec>, EvaluationResults) {
+    test_all_with_warnings(model, test_dataset, beam_size, &Warnings::new())
+}
+
+/// Like [`test_all`], but also collects warnings about the test data.
+pub fn test_all_with_warnings(
+    model: &Model,
+    test_dataset: &DataSet,
+    beam_size: usize,
+    warnings: &Warnings,
+) -> (Vec<IndexValueVec>, EvaluationResults) {
+    let true_labels = test_dataset.labels.to_sets();
+    let unseen_label_counts = UnseenLabelCounts::count(&model.labels(), &true_labels);
+    if unseen_label_counts.unseen_gold_labels > 0 {
+        warnings.push(Warning::LabelsNotInModel {
+            n_labels: unseen_label_counts.unseen_gold_labels,
+        });
+    }
+
     let n_examples = test_dataset.feature_lists.len();
     let pb = Mutex::new(create_progress_bar(n_examples as u64));
     let start_t = time::Instant::now();
@@ -46,19 +852,956 @@ pub fn test_all(
             predictions
         })
         .collect::<Vec<_>>();
-    info!(
-        "Done testing on {} examples; it took {:.2}s",
-        n_examples,
-        start_t.elapsed().as_secs_f32()
-    );
+    let prediction_secs = start_t.elapsed().as_secs_f32();
 
-    let precisions = precision_at_k(5, &test_dataset.label_sets, &predicted_labels);
-    info!(
-        "Precision@[1, 3, 5] = [{:.2}, {:.2}, {:.2}]",
-        precisions[0] * 100.,
-        precisions[2] * 100.,
-        precisions[4] * 100.,
+    let results = evaluation_results(
+        model,
+        beam_size,
+        prediction_secs,
+        &true_labels,
+        &predicted_labels,
+        unseen_label_counts.unseen_gold_labels > 0,
     );
+    results.log();
+    (predicted_labels, results)
+}
+
+/// Like [`test_all_with_warnings`], but evaluates each of the given beam sizes from a single
+/// search per example with [`Model::predict_multi_beam`], returning results in the same order.
+///
+/// Beam sizes that aren't as [`multi_beam::check_beam_sizes`] requires are an error. The results
+/// are the same as those of evaluating each beam size separately, except that the prediction time
+/// of each is that of the single search for all of them.
+pub fn test_all_multi_beam(
+    model: &Model,
+    test_dataset: &DataSet,
+    beam_sizes: &[usize],
+    warnings: &Warnings,
+) -> Result<Vec<EvaluationResults>, String> {
+    multi_beam::check_beam_sizes(beam_sizes)?;
+    let true_labels = test_dataset.labels.to_sets();
+    let unseen_label_counts = UnseenLabelCounts::count(&model.labels(), &true_labels);
+    if unseen_label_counts.unseen_gold_labels > 0 {
+        warnings.push(Warning::LabelsNotInModel {
+            n_labels: unseen_label_counts.unseen_gold_labels,
+        });
+    }
+
+    let pb = Mutex::new(create_progress_bar(test_dataset.feature_lists.len() as u64));
+    let start_t = time::Instant::now();
+    let predictions_by_example = test_dataset
+        .feature_lists
+        .par_iter()
+        .map(|feature_vec| {
+            let predictions = model
+                .predict_multi_beam(feature_vec, beam_sizes)
+                .expect("Beam sizes were checked");
+            pb.lock().expect("Failed to lock progress bar").add(1);
+            predictions
+        })
+        .collect::<Vec<_>>();
+    let prediction_secs = start_t.elapsed().as_secs_f32();
+
+    Ok(beam_sizes
+        .iter()
+        .enumerate()
+        .map(|(i, &beam_size)| {
+            let predicted_labels = predictions_by_example
+                .iter()
+                .map(|predictions| predictions[i].clone())
+                .collect_vec();
+            let results = evaluation_results(
+                model,
+                beam_size,
+                prediction_secs,
+                &true_labels,
+                &predicted_labels,
+                unseen_label_counts.unseen_gold_labels > 0,
+            );
+            results.log();
+            results
+        })
+        .collect())
+}
+
+/// The results of evaluating the given predictions, including metrics for gold labels not in
+/// the model if there are any.
+fn evaluation_results(
+    model: &Model,
+    beam_size: usize,
+    prediction_secs: f32,
+    true_labels: &[HashSet<Index>],
+    predicted_labels: &[IndexValueVec],
+    has_unseen_labels: bool,
+) -> EvaluationResults {
+    EvaluationResults {
+        n_examples: true_labels.len(),
+        beam_size,
+        prediction_secs,
+        metrics: EvaluationMetrics::compute(5, None, true_labels, predicted_labels),
+        unseen_labels: if has_unseen_labels {
+            Some(evaluate_unseen_labels(
+                model,
+                5,
+                true_labels,
+                predicted_labels,
+            ))
+        } else {
+            None
+        },
+    }
+}
 
-    (predicted_labels, precisions)
+/// Write the results of several evaluations, e.g., from [`test_all_multi_beam`], as a JSON array.
+pub fn write_results_json<W: Write>(results: &[EvaluationResults], writer: W) -> io::Result<()> {
+    serde_json::to_writer_pretty(writer, results).map_err(|e| {
+        io::Error::new(
+            io::ErrorKind::Other,
+            format!("Unable to serialize evaluation results: {}", e),
+        )
+    })
+}
+
+/// Write predictions in the sparse format of the Extreme Classification Repository: a header line
+/// with the numbers of examples and labels, then a line for each example with its `top_k` best
+/// predictions as space-separated `label:score` pairs, with scores in the given format.
+///
+/// Predictions with labels not less than `n_labels` are rejected.
+pub fn write_predictions<W: Write>(
+    mut writer: W,
+    predictions: &[IndexValueVec],
+    n_labels: usize,
+    top_k: usize,
+    float_format: FloatFormat,
+) -> io::Result<()> {
+    writeln!(writer, "{} {}", predictions.len(), n_labels)?;
+    for example_predictions in predictions {
+        for (i, &(label, score)) in example_predictions.iter().take(top_k).enumerate() {
+            if label as usize >= n_labels {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "Predicted label {} is out of range for {} labels",
+                        label, n_labels
+                    ),
+                ));
+            }
+            if i > 0 {
+                write!(writer, " ")?;
+            }
+            write!(writer, "{}:{}", label, float_format.display_f32(score))?;
+        }
+        writeln!(writer)?;
+    }
+    writer.flush()
+}
+
+/// Read predictions in the format written by [`write_predictions`], e.g., by other tools,
+/// returning them with the number of labels given by the header.
+///
+/// The predictions of each example are ranked by decreasing score, keeping the order of the file
+/// among ties.
+pub fn read_predictions<R: BufRead>(reader: R) -> io::Result<(Vec<IndexValueVec>, usize)> {
+    let invalid = |message: String| io::Error::new(io::ErrorKind::InvalidData, message);
+    let mut lines = reader.lines();
+    let header = lines
+        .next()
+        .ok_or_else(|| invalid("Failed to find header line".to_owned()))??;
+    let dims = header
+        .split_whitespace()
+        .map(str::parse::<usize>)
+        .collect::<Result<Vec<_>, _>>();
+    let (n_examples, n_labels) = match dims.as_deref() {
+        Ok(&[n_examples, n_labels]) => (n_examples, n_labels),
+        _ => {
+            return Err(invalid(format!(
+                "Expect header line with the numbers of examples and labels, found \"{}\" instead",
+                header
+            )))
+        }
+    };
+
+    let mut predictions = Vec::with_capacity(n_examples);
+    for (i, line) in lines.enumerate() {
+        let line = line?;
+        let mut example_predictions = line
+            .split_whitespace()
+            .map(|pair_str| {
+                let pair = pair_str.split_once(':').and_then(|(label, score)| {
+                    Some((label.parse::<Index>().ok()?, score.parse::<f32>().ok()?))
+                });
+                match pair {
+                    Some((label, score)) if (label as usize) < n_labels && !score.is_nan() => {
+                        Ok((label, score))
+                    }
+                    _ => Err(invalid(format!(
+                        "Failed to parse prediction \"{}\" on line {}",
+                        pair_str,
+                        i + 2
+                    ))),
+                }
+            })
+            .collect::<io::Result<IndexValueVec>>()?;
+        example_predictions.sort_by(|(_, a), (_, b)| b.partial_cmp(a).unwrap());
+        predictions.push(example_predictions);
+    }
+    if predictions.len() != n_examples {
+        return Err(invalid(format!(
+            "Expect predictions for {} examples as given by the header, found {} instead",
+            n_examples,
+            predictions.len()
+        )));
+    }
+    Ok((predictions, n_labels))
+}
+
+/// Score predictions read with [`read_predictions`], e.g., from other tools, against the true
+/// labels of the test data, with the metrics of [`test_all`].
+///
+/// The predictions must be for the same numbers of examples and labels as the test data.
+pub fn evaluate_predictions_file<R: BufRead>(
+    reader: R,
+    test_dataset: &DataSet,
+) -> io::Result<EvaluationMetrics> {
+    let (predictions, n_labels) = read_predictions(reader)?;
+    if (predictions.len(), n_labels) != (test_dataset.len(), test_dataset.n_labels) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "Predictions for {} examples and {} labels don't match test data with {} \
+                 examples and {} labels",
+                predictions.len(),
+                n_labels,
+                test_dataset.len(),
+                test_dataset.n_labels
+            ),
+        ));
+    }
+    Ok(EvaluationMetrics::compute(
+        5,
+        None,
+        &test_dataset.labels.to_sets(),
+        &predictions,
+    ))
+}
+
+/// Progress of [`test_streaming`], reported after each chunk of examples.
+#[derive(Clone, Debug, PartialEq)]
+pub struct StreamingProgress {
+    /// The number of examples evaluated so far.
+    pub n_examples: usize,
+    /// The number of examples in the data, as given by its header.
+    pub n_total_examples: usize,
+    /// Precision@k for k = 1, ..., 5 over the examples so far.
+    pub precisions: Vec<f32>,
+}
+
+/// Evaluate the model on data in the format of the Extreme Classification Repository read from
+/// the given source, a chunk of examples at a time, so that the data never needs to fit in memory.
+///
+/// Each chunk of up to `chunk_size` lines is parsed and predicted for in parallel, and added to
+/// running metrics before it's dropped; progress is then reported to the given callback. A
+/// `chunk_size` of 0 is an error of kind [`io::ErrorKind::InvalidInput`].
+/// Compressed data is detected as when loading datasets. Results are the same as those of
+/// [`test_all_with_warnings`] on the loaded data, including warnings about the data, except that
+/// the time spent includes reading and parsing.
+pub fn test_streaming<R: BufRead>(
+    model: &Model,
+    reader: R,
+    beam_size: usize,
+    chunk_size: usize,
+    mut progress: impl FnMut(&StreamingProgress),
+    warnings: &Warnings,
+) -> io::Result<EvaluationResults> {
+    if chunk_size == 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Chunk size must be positive",
+        ));
+    }
+    let start_t = time::Instant::now();
+    let (reader, _) = crate::data::compression::decompress(reader)?;
+    let mut lines = io::BufReader::new(reader).lines();
+    let header = lines.next().ok_or_else(|| {
+        io::Error::new(io::ErrorKind::InvalidData, "Failed to find header line")
+    })??;
+    let (n_total_examples, n_features, n_labels) = DataSet::parse_xc_repo_header(&header)?;
+
+    let model_labels = model.labels();
+    let mut all = MetricAccumulator::new(5, None);
+    // Examples with gold labels not in the model left out, for UnseenLabelMode::Exclude
+    let mut seen = MetricAccumulator::new(5, None);
+    let mut unseen_labels = HashSet::<Index>::new();
+    let mut examples_with_unseen_labels = 0;
+    loop {
+        let chunk = lines
+            .by_ref()
+            .take(chunk_size)
+            .collect::<io::Result<Vec<_>>>()?;
+        if chunk.is_empty() {
+            break;
+        }
+        let examples = chunk
+            .par_iter()
+            .map(|line| {
+                let (features, labels) = DataSet::parse_xc_repo_data_line(line, n_features)?;
+                let predictions = model.predict(&features, beam_size);
+                Ok((features, labels, predictions))
+            })
+            .collect::<io::Result<Vec<_>>>()?;
+
+        for (features, labels, predictions) in examples {
+            let line = all.n_examples() + 2; // 1-based, after the header line
+            DataSet::check_xc_repo_example(line, &features, &labels, n_labels, warnings);
+            let truth: HashSet<Index> = labels.iter().cloned().collect();
+            all.add(&truth, &predictions);
+
+            let seen_truth: HashSet<Index> = truth
+                .iter()
+                .filter(|label| model_labels.binary_search(label).is_ok())
+                .cloned()
+                .collect();
+            if seen_truth.len() < truth.len() {
+                examples_with_unseen_labels += 1;
+                unseen_labels.extend(truth.difference(&seen_truth));
+            }
+            if !seen_truth.is_empty() {
+                seen.add(&seen_truth, &predictions);
+            }
+        }
+        progress(&StreamingProgress {
+            n_examples: all.n_examples(),
+            n_total_examples,
+            precisions: all.precisions(),
+        });
+    }
+
+    if n_total_examples != all.n_examples() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "Expected {} examples, but read {}",
+                n_total_examples,
+                all.n_examples()
+            ),
+        ));
+    }
+    if !unseen_labels.is_empty() {
+        warnings.push(Warning::LabelsNotInModel {
+            n_labels: unseen_labels.len(),
+        });
+    }
+
+    let results = EvaluationResults {
+        n_examples: all.n_examples(),
+        beam_size,
+        prediction_secs: start_t.elapsed().as_secs_f32(),
+        metrics: all.metrics(),
+        unseen_labels: if unseen_labels.is_empty() {
+            None
+        } else {
+            Some(UnseenLabelEvaluation {
+                counts: UnseenLabelCounts {
+                    unseen_gold_labels: unseen_labels.len(),
+                    examples_with_unseen_labels,
+                },
+                penalize_precisions: all.precisions(),
+                exclude_precisions: seen.precisions(),
+                penalize_ndcgs: all.ndcgs(),
+                exclude_ndcgs: seen.ndcgs(),
+            })
+        },
+    };
+    results.log();
+    Ok(results)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mat_util::*;
+    use crate::model::liblinear::LossType;
+    use crate::model::scores;
+    use crate::model::{TrainHyperParam, TreeNode};
+    use crate::test_util::{toy_dataset, toy_model};
+    use assert_approx_eq::assert_approx_eq;
+
+    /// Scores of all labels from exhaustive search, transformed with the standard library's
+    /// exponential.
+    fn exact_scores(model: &Model, feature_vec: &[(Index, f32)]) -> HashMap<Index, f32> {
+        fn visit(
+            node: &TreeNode,
+            loss_type: LossType,
+            feature_vec: &SparseVec,
+            path_score: f32,
+            label_to_score: &mut HashMap<Index, f32>,
+        ) {
+            let transform = |margin: f32| scores::log_score(margin, loss_type);
+            match node {
+                TreeNode::Branch { weights, children } => {
+                    let scores = weights.t_dot_vec(feature_vec.view());
+                    for (child, &score) in izip!(children, &scores) {
+                        let child_score = path_score + transform(score);
+                        visit(child, loss_type, feature_vec, child_score, label_to_score);
+                    }
+                }
+                TreeNode::Leaf { weights, labels } => {
+                    let scores = weights.t_dot_vec(feature_vec.view());
+                    for (&label, &score) in izip!(labels, &scores) {
+                        *label_to_score.entry(label).or_insert(0.) +=
+                            (path_score + transform(score)).exp();
+                    }
+                }
+            }
+        }
+
+        let feature_vec = model.prepare_feature_vec(feature_vec);
+        let mut label_to_score = HashMap::new();
+        for tree in &model.trees {
+            let loss_type = model.settings.classifier_loss_type;
+            visit(tree, loss_type, &feature_vec, 0., &mut label_to_score);
+        }
+        for score in label_to_score.values_mut() {
+            *score /= model.trees.len() as f32;
+        }
+        label_to_score
+    }
+
+    #[test]
+    fn test_score_transforms_keep_precision() {
+        let mut hyper_param = TrainHyperParam::default();
+        hyper_param.n_trees = 2;
+        hyper_param.min_branch_size = 2;
+        hyper_param.linear.loss_type = LossType::Log;
+        let model = hyper_param.train(toy_dataset(60, 8, 0));
+        let dataset = toy_dataset(40, 8, 1);
+
+        // With the widest useful beam, search is exhaustive
+        let predictions = predict_all(&model, &dataset, model.max_useful_beam());
+        let mut exact_predictions = Vec::new();
+        for (feature_vec, predicted) in izip!(&dataset.feature_lists, &predictions) {
+            let label_to_score = exact_scores(&model, feature_vec);
+            assert_eq!(label_to_score.len(), predicted.len());
+            for &(label, score) in predicted {
+                assert_approx_eq!(label_to_score[&label], score, 1e-5);
+            }
+
+            let mut exact = label_to_score.into_iter().collect_vec();
+            exact.sort_by(|(_, a), (_, b)| b.partial_cmp(a).unwrap());
+            exact_predictions.push(exact);
+        }
+        assert_eq!(
+            precision_at_k(5, &dataset.labels.to_sets(), &exact_predictions),
+            precision_at_k(5, &dataset.labels.to_sets(), &predictions)
+        );
+    }
+
+    #[test]
+    fn test_ndcg_at_k() {
+        let true_labels: Vec<HashSet<Index>> = vec![
+            [0, 1].iter().cloned().collect(),
+            [2].iter().cloned().collect(),
+            HashSet::new(),
+        ];
+        let predicted_labels = vec![
+            vec![(1, 0.9), (5, 0.5), (0, 0.1)],
+            vec![(4, 0.8), (2, 0.2)],
+            vec![(3, 0.7)],
+        ];
+        // Gains at ranks 1, 2, and 3
+        let (g1, g2, g3) = (1., 1. / 3f32.log2(), 0.5);
+        let expected = [
+            (1. + 0.) / 3.,
+            (g1 / (g1 + g2) + g2) / 3.,
+            ((g1 + g3) / (g1 + g2) + g2) / 3.,
+        ];
+        let ndcgs = ndcg_at_k(3, &true_labels, &predicted_labels);
+        assert_eq!(3, ndcgs.len());
+        for (&expected, &actual) in izip!(&expected, &ndcgs) {
+            assert_approx_eq!(expected, actual, 1e-6);
+        }
+        assert_eq!(
+            ndcgs[2],
+            Metric::NdcgAtK(3).compute(&true_labels, &predicted_labels)
+        );
+        assert!(Metric::NdcgAtK(0).validate().is_err());
+
+        // Perfect rankings score 1 at every k, even past the number of true labels
+        let perfect = ndcg_at_k(3, &true_labels[..1], &[vec![(0, 0.9), (1, 0.8)]]);
+        for ndcg in perfect {
+            assert_approx_eq!(1., ndcg, 1e-6);
+        }
+    }
+
+    #[test]
+    fn test_recall_at_k() {
+        let true_labels: Vec<HashSet<Index>> = vec![
+            [0, 1, 2].iter().cloned().collect(),
+            [3].iter().cloned().collect(),
+            HashSet::new(),
+        ];
+        let predicted_labels = vec![
+            vec![(1, 0.9), (5, 0.5), (0, 0.1)],
+            vec![(4, 0.8), (3, 0.2)],
+            vec![(3, 0.7)],
+        ];
+        let expected = [
+            (1. / 3. + 0.) / 3.,
+            (1. / 3. + 1.) / 3.,
+            (2. / 3. + 1.) / 3.,
+        ];
+        let recalls = recall_at_k(3, &true_labels, &predicted_labels);
+        for (&expected, &actual) in izip!(&expected, &recalls) {
+            assert_approx_eq!(expected, actual, 1e-6);
+        }
+        assert_eq!(
+            recalls[1],
+            Metric::RecallAtK(2).compute(&true_labels, &predicted_labels)
+        );
+        assert!(Metric::RecallAtK(0).validate().is_err());
+    }
+
+    /// True labels of three examples, with scored predictions for them that include labels 3 and
+    /// 4, which are never true.
+    fn scored_predictions() -> (Vec<HashSet<Index>>, Vec<IndexValueVec>) {
+        let true_labels = vec![
+            [0, 1].iter().cloned().collect(),
+            [1].iter().cloned().collect(),
+            [2].iter().cloned().collect(),
+        ];
+        let predicted_labels = vec![
+            vec![(0, 0.9), (3, 0.6), (1, 0.4)],
+            vec![(1, 0.8), (4, 0.1)],
+            vec![(0, 0.5)],
+        ];
+        (true_labels, predicted_labels)
+    }
+
+    #[test]
+    fn test_threshold_metrics() {
+        let (true_labels, predicted_labels) = scored_predictions();
+        let metrics = ThresholdMetrics::compute(0.5, &true_labels, &predicted_labels);
+
+        // At or above the threshold are 0 and 3, 1, and 0, so 2 of 4 predictions are right, and
+        // 2 of 4 true labels are found
+        let micro = metrics.micro_averaged;
+        assert_approx_eq!(0.5, micro.precision, 1e-6);
+        assert_approx_eq!(0.5, micro.recall, 1e-6);
+        assert_approx_eq!(0.5, micro.f1, 1e-6);
+
+        // Labels 0, 1, 2, and 3 count, but label 4 is never predicted above the threshold nor
+        // true; by label, precisions are [1/2, 1, 0, 0], recalls are [1, 1/2, 0, 0], and F1
+        // scores are [2/3, 2/3, 0, 0]
+        assert_eq!(4, metrics.n_macro_averaged_labels);
+        let macro_averaged = metrics.macro_averaged;
+        assert_approx_eq!(1.5 / 4., macro_averaged.precision, 1e-6);
+        assert_approx_eq!(1.5 / 4., macro_averaged.recall, 1e-6);
+        assert_approx_eq!((4. / 3.) / 4., macro_averaged.f1, 1e-6);
+
+        // Nothing is predicted above a high threshold
+        let metrics = ThresholdMetrics::compute(1., &true_labels, &predicted_labels);
+        assert_eq!(PrecisionRecallF1::default(), metrics.micro_averaged);
+        assert_eq!(3, metrics.n_macro_averaged_labels);
+
+        let metrics = EvaluationMetrics::compute(2, Some(0.5), &true_labels, &predicted_labels);
+        assert_eq!(
+            precision_at_k(2, &true_labels, &predicted_labels),
+            metrics.precisions
+        );
+        assert_eq!(2, metrics.recalls.len());
+        assert_eq!(2, metrics.ndcgs.len());
+        assert_eq!(
+            Some(ThresholdMetrics::compute(
+                0.5,
+                &true_labels,
+                &predicted_labels
+            )),
+            metrics.threshold_metrics
+        );
+    }
+
+    #[test]
+    fn test_evaluate_labels() {
+        let (true_labels, predicted_labels) = scored_predictions();
+        let label_metrics = evaluate_labels(Cutoff::TopK(1), &true_labels, &predicted_labels);
+        let summary = label_metrics
+            .iter()
+            .map(|m| {
+                (
+                    m.label,
+                    m.n_true_positives,
+                    m.n_false_positives,
+                    m.n_false_negatives,
+                    m.n_true(),
+                    m.mean_rank,
+                )
+            })
+            .collect_vec();
+        // Label 1 is true twice, ranked 3rd and 1st; label 2 is never ranked
+        assert_eq!(
+            vec![
+                (0, 1, 1, 0, 1, Some(1.)),
+                (1, 1, 0, 1, 2, Some(2.)),
+                (2, 0, 0, 1, 1, None),
+            ],
+            summary
+        );
+        assert_eq!(
+            PrecisionRecallF1 {
+                precision: 1.,
+                recall: 0.5,
+                f1: 2. / 3.,
+            },
+            label_metrics[1].metrics()
+        );
+
+        // Per-label counts agree with the thresholded metrics
+        let label_metrics =
+            evaluate_labels(Cutoff::Threshold(0.5), &true_labels, &predicted_labels);
+        assert_eq!(
+            vec![0, 1, 2, 3],
+            label_metrics.iter().map(|m| m.label).collect_vec()
+        );
+        let metrics = ThresholdMetrics::compute(0.5, &true_labels, &predicted_labels);
+        let (micro_averaged, macro_averaged) =
+            average_counts(label_metrics.iter().map(|m| m.counts()));
+        assert_eq!(metrics.micro_averaged, micro_averaged);
+        assert_eq!(metrics.macro_averaged, macro_averaged);
+    }
+
+    #[test]
+    fn test_bucket_by_frequency() {
+        let (true_labels, predicted_labels) = scored_predictions();
+        let label_metrics = evaluate_labels(Cutoff::TopK(1), &true_labels, &predicted_labels);
+        let frequencies: HashMap<Index, usize> =
+            [(0, 10), (1, 5), (2, 1)].iter().cloned().collect();
+
+        let buckets = bucket_by_frequency(&label_metrics, &frequencies, 2).unwrap();
+        assert_eq!(2, buckets.len());
+        // The head bucket has label 0 only, and the tail one has labels 1 and 2
+        assert_eq!(
+            (10, 10, 1, 1, 1, 0),
+            (
+                buckets[0].max_frequency,
+                buckets[0].min_frequency,
+                buckets[0].n_labels,
+                buckets[0].n_true_positives,
+                buckets[0].n_false_positives,
+                buckets[0].n_false_negatives,
+            )
+        );
+        assert_eq!(
+            (5, 1, 2, 1, 0, 2),
+            (
+                buckets[1].max_frequency,
+                buckets[1].min_frequency,
+                buckets[1].n_labels,
+                buckets[1].n_true_positives,
+                buckets[1].n_false_positives,
+                buckets[1].n_false_negatives,
+            )
+        );
+        assert_approx_eq!(1. / 3., buckets[1].micro_averaged.recall, 1e-6);
+        assert_approx_eq!(0.25, buckets[1].macro_averaged.recall, 1e-6);
+        assert_eq!(Some(2.), buckets[1].mean_rank);
+
+        // Empty buckets are left out
+        assert_eq!(
+            3,
+            bucket_by_frequency(&label_metrics, &frequencies, 5)
+                .unwrap()
+                .len()
+        );
+        assert!(bucket_by_frequency(&label_metrics, &frequencies, 0).is_err());
+        let expected: HashMap<Index, usize> = [(0, 1), (1, 2), (2, 1)].iter().cloned().collect();
+        assert_eq!(expected, label_frequencies(&true_labels));
+    }
+
+    #[test]
+    fn test_all_results() {
+        let model = toy_model(1, 0);
+        let dataset = toy_dataset(10, 8, 1);
+        let (predictions, results) = test_all(&model, &dataset, 3);
+        assert_eq!((10, 3), (results.n_examples, results.beam_size));
+        assert_eq!(
+            EvaluationMetrics::compute(5, None, &dataset.labels.to_sets(), &predictions),
+            results.metrics
+        );
+        assert_eq!(None, results.unseen_labels);
+
+        let mut buf = Vec::new();
+        results.write_json(&mut buf).unwrap();
+        let parsed: EvaluationResults = serde_json::from_slice(&buf).unwrap();
+        assert_eq!(results, parsed);
+    }
+
+    #[test]
+    fn test_all_multi_beam_matches_separate_runs() {
+        let model = toy_model(2, 0);
+        let dataset = toy_dataset(20, 8, 1);
+        let beam_sizes = [1, 2, 5];
+        let results = test_all_multi_beam(&model, &dataset, &beam_sizes, &Warnings::new()).unwrap();
+        assert_eq!(beam_sizes.len(), results.len());
+        for (&beam_size, results) in beam_sizes.iter().zip(&results) {
+            let (_, expected) = test_all(&model, &dataset, beam_size);
+            assert_eq!(beam_size, results.beam_size);
+            assert_eq!(expected.n_examples, results.n_examples);
+            assert_eq!(expected.metrics, results.metrics);
+            assert_eq!(expected.unseen_labels, results.unseen_labels);
+        }
+
+        let mut buf = Vec::new();
+        write_results_json(&results, &mut buf).unwrap();
+        let parsed: Vec<EvaluationResults> = serde_json::from_slice(&buf).unwrap();
+        assert_eq!(results, parsed);
+    }
+
+    #[test]
+    fn test_prediction_file_round_trip() {
+        let model = toy_model(2, 0);
+        let dataset = toy_dataset(10, 8, 1);
+        let predictions = dataset
+            .feature_lists
+            .iter()
+            .map(|feature_vec| model.predict(feature_vec, 10))
+            .collect_vec();
+        let truncated = predictions
+            .iter()
+            .map(|example_predictions| example_predictions.iter().take(3).cloned().collect_vec())
+            .collect_vec();
+
+        let mut buf = Vec::new();
+        write_predictions(&mut buf, &predictions, 8, 3, FloatFormat::Shortest).unwrap();
+        assert!(buf.starts_with(b"10 8\n"));
+        let (read, n_labels) = read_predictions(buf.as_slice()).unwrap();
+        assert_eq!(8, n_labels);
+        assert_eq!(truncated, read);
+        assert_eq!(
+            EvaluationMetrics::compute(5, None, &dataset.labels.to_sets(), &truncated),
+            evaluate_predictions_file(buf.as_slice(), &dataset).unwrap()
+        );
+
+        let mut buf = Vec::new();
+        write_predictions(
+            &mut buf,
+            &[vec![(4, 0.123456)]],
+            8,
+            3,
+            FloatFormat::Fixed(2),
+        )
+        .unwrap();
+        assert_eq!("1 8\n4:0.12\n", String::from_utf8(buf).unwrap());
+
+        // Predictions from other tools are ranked when read
+        let (read, _) = read_predictions(&b"2 8\n1:0.2 3:0.5 0:0.2\n\n"[..]).unwrap();
+        assert_eq!(vec![vec![(3, 0.5), (1, 0.2), (0, 0.2)], vec![]], read);
+
+        let invalid_data: [&[u8]; 5] = [
+            b"2 8\n1:0.5\n",
+            b"1 8\n8:0.5\n",
+            b"1 8\n1:NaN\n",
+            b"1 8\n1 0.5\n",
+            b"1\n1:0.5\n",
+        ];
+        for data in &invalid_data {
+            assert_eq!(
+                io::ErrorKind::InvalidData,
+                read_predictions(*data).unwrap_err().kind()
+            );
+        }
+        assert_eq!(
+            io::ErrorKind::InvalidInput,
+            write_predictions(io::sink(), &[vec![(8, 0.5)]], 8, 3, FloatFormat::Shortest)
+                .unwrap_err()
+                .kind()
+        );
+        assert_eq!(
+            io::ErrorKind::InvalidData,
+            evaluate_predictions_file(&b"1 8\n1:0.5\n"[..], &dataset)
+                .unwrap_err()
+                .kind()
+        );
+    }
+
+    #[test]
+    fn test_format_at_ks() {
+        assert_eq!(
+            "Precision@[1, 3, 5] = [50.00, 25.00, 12.50]",
+            format_at_ks("Precision", &[0.5, 0.4, 0.25, 0.2, 0.125])
+        );
+        assert_eq!("nDCG@[1] = [100.00]", format_at_ks("nDCG", &[1., 0.9]));
+    }
+
+    /// Write the dataset in the format of the Extreme Classification Repository.
+    fn to_xc_repo_data(dataset: &DataSet) -> String {
+        let mut data = format!(
+            "{} {} {}\n",
+            dataset.len(),
+            dataset.n_features,
+            dataset.n_labels
+        );
+        for (features, labels) in izip!(dataset.feature_lists(), dataset.label_lists()) {
+            data += &labels.iter().join(",");
+            for (feature, value) in features {
+                data += &format!(" {}:{}", feature, value);
+            }
+            data += "\n";
+        }
+        data
+    }
+
+    #[test]
+    fn test_streaming_matches_in_memory() {
+        let model = toy_model(2, 0);
+        let mut dataset = toy_dataset(25, 8, 1);
+        // Some gold labels aren't in the model, and one example has no labels
+        dataset.labels = dataset
+            .label_lists()
+            .enumerate()
+            .map(|(i, labels)| match i {
+                0 => vec![],
+                1 | 2 => labels.iter().cloned().chain(Some(9)).collect_vec(),
+                _ => labels.to_vec(),
+            })
+            .collect();
+        let data = to_xc_repo_data(&dataset);
+        let loaded =
+            DataSet::read_xc_repo_data_with_warnings(data.as_bytes(), &Warnings::new()).unwrap();
+        let in_memory_warnings = Warnings::new();
+        let (_, expected) = test_all_with_warnings(&model, &loaded, 5, &in_memory_warnings);
+        assert!(expected.unseen_labels.is_some());
+
+        for &chunk_size in &[1, 7, 25, 100] {
+            let warnings = Warnings::new();
+            let mut reports = Vec::new();
+            let results = test_streaming(
+                &model,
+                data.as_bytes(),
+                5,
+                chunk_size,
+                |progress| reports.push(progress.clone()),
+                &warnings,
+            )
+            .unwrap();
+            assert_eq!(
+                EvaluationResults {
+                    prediction_secs: expected.prediction_secs,
+                    ..results
+                },
+                expected
+            );
+
+            assert_eq!((25 + chunk_size - 1) / chunk_size, reports.len());
+            let last = reports.last().unwrap();
+            assert_eq!((25, 25), (last.n_examples, last.n_total_examples));
+            assert_eq!(expected.metrics.precisions, last.precisions);
+
+            // Warnings about the data come from parsing here, rather than from loading
+            let warnings = warnings.into_vec();
+            assert!(warnings.contains(&Warning::ExampleWithoutLabels { line: 2 }));
+            assert!(warnings.contains(&Warning::LabelsNotInModel { n_labels: 1 }));
+        }
+
+        // Data with fewer examples than its header says is rejected
+        let truncated = &data[..data.trim_end().rfind('\n').unwrap() + 1];
+        assert!(test_streaming(
+            &model,
+            truncated.as_bytes(),
+            5,
+            10,
+            |_| {},
+            &Warnings::new()
+        )
+        .is_err());
+
+        // So is an empty chunk size
+        let error =
+            test_streaming(&model, data.as_bytes(), 5, 0, |_| {}, &Warnings::new()).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidInput, error.kind());
+    }
+
+    #[test]
+    fn test_all_warns_about_labels_not_in_model() {
+        let model = toy_model(1, 0);
+        let mut dataset = toy_dataset(10, 8, 1);
+        let extra_labels = [Some(8), Some(9), Some(9)];
+        dataset.labels = dataset
+            .label_lists()
+            .enumerate()
+            .map(|(i, labels)| {
+                let extra = extra_labels.get(i).cloned().flatten();
+                labels.iter().cloned().chain(extra).collect_vec()
+            })
+            .collect();
+
+        let warnings = Warnings::new();
+        let (_, results) = test_all_with_warnings(&model, &dataset, 5, &warnings);
+        assert_eq!(
+            vec![Warning::LabelsNotInModel { n_labels: 2 }],
+            warnings.into_vec()
+        );
+        assert_eq!(2, results.unseen_labels.unwrap().counts.unseen_gold_labels);
+    }
+
+    #[test]
+    fn test_evaluate_unseen_labels() {
+        let model = toy_model(1, 0);
+        assert_eq!((0..8).collect_vec(), model.labels());
+        assert_eq!(8, model.n_labels());
+
+        let true_labels: Vec<HashSet<Index>> = vec![
+            [0, 1].iter().cloned().collect(),
+            [2, 100].iter().cloned().collect(),
+            [100, 101].iter().cloned().collect(),
+            [3].iter().cloned().collect(),
+        ];
+        let predicted_labels = vec![
+            vec![(0, 0.9), (5, 0.1)],
+            vec![(2, 0.8), (6, 0.2)],
+            vec![(4, 0.7), (7, 0.3)],
+            vec![(1, 0.6), (3, 0.4)],
+        ];
+        let evaluation = evaluate_unseen_labels(&model, 2, &true_labels, &predicted_labels);
+        assert_eq!(
+            UnseenLabelCounts {
+                unseen_gold_labels: 2,
+                examples_with_unseen_labels: 2,
+            },
+            evaluation.counts
+        );
+        // The third example can't score anything, and only counts when penalizing
+        assert_eq!(
+            vec![2. / 4., (0.5 + 0.5 + 0.5) / 4.],
+            evaluation.penalize_precisions
+        );
+        assert_eq!(
+            vec![2. / 3., (0.5 + 0.5 + 0.5) / 3.],
+            evaluation.exclude_precisions
+        );
+
+        assert_eq!(
+            ndcg_at_k(2, &true_labels, &predicted_labels),
+            evaluation.penalize_ndcgs
+        );
+        assert_eq!(
+            evaluation.exclude_ndcgs,
+            ndcg_at_k_with_mode(
+                &model,
+                2,
+                &true_labels,
+                &predicted_labels,
+                UnseenLabelMode::Exclude
+            )
+        );
+
+        for &(mode, ref expected) in &[
+            (UnseenLabelMode::Penalize, &evaluation.penalize_precisions),
+            (UnseenLabelMode::Exclude, &evaluation.exclude_precisions),
+        ] {
+            assert_eq!(
+                **expected,
+                precision_at_k_with_mode(&model, 2, &true_labels, &predicted_labels, mode)
+            );
+        }
+    }
 }
@@ -0,0 +1,172 @@
+//! Explaining predictions by the contributions of input features.
+//!
+//! Every classifier in a tree is linear, so its margin for an input is the sum of weight × value
+//! over the active features of the prepared input, i.e., after normalization and with the bias
+//! feature appended. A label's score in a tree is determined by the margins of the classifiers on
+//! the path from the root to the label, so summing the products along that path shows which
+//! features pushed the label up or down.
+use super::{Model, TreeNode};
+use crate::mat_util::*;
+use crate::{Index, IndexValueVec};
+use hashbrown::HashMap;
+
+impl TreeNode {
+    /// The weights and column of each classifier on the path from the node to the given label,
+    /// from the label's own classifier in its leaf up to the node, if the label is in the subtree.
+    fn label_path(&self, label: Index) -> Option<Vec<(&WeightMat, usize)>> {
+        match self {
+            TreeNode::Branch { weights, children } => {
+                children.iter().enumerate().find_map(|(i, child)| {
+                    let mut path = child.label_path(label)?;
+                    path.push((weights, i));
+                    Some(path)
+                })
+            }
+            TreeNode::Leaf { weights, labels } => {
+                let i = labels.iter().position(|&l| l == label)?;
+                Some(vec![(weights, i)])
+            }
+        }
+    }
+}
+
+/// The weight × value product of each active feature for the classifier in the given column,
+/// which sum to its margin.
+fn classifier_contributions(
+    weights: &WeightMat,
+    column: usize,
+    feature_vec: &SparseVec,
+) -> IndexValueVec {
+    let mut contributions = weights
+        .nonzero_entries()
+        .filter(|&(_, col, _)| col == column)
+        .filter_map(|(row, _, weight)| {
+            let &value = feature_vec.get(row)?;
+            Some((row as Index, weight * value))
+        })
+        .collect::<IndexValueVec>();
+    contributions.sort_unstable_by_key(|&(index, _)| index);
+    contributions
+}
+
+impl Model {
+    /// Returns the contribution of each active feature to the margins of the classifiers that
+    /// score the given label, summed over the trees where beam search reaches it, sorted by
+    /// feature index.
+    ///
+    /// Contributions are products of weights and prepared feature values, so features of models
+    /// with a feature projection are the projected ones. The bias is the feature at index
+    /// [`Self::n_features`]. Features that only meet zero weights are left out, and the result is
+    /// empty if the label isn't reached in any tree.
+    ///
+    /// This is meant for debugging, and is much slower than prediction.
+    pub fn explain(
+        &self,
+        feature_vec: impl AsRef<[(Index, f32)]>,
+        beam_size: usize,
+        label: Index,
+    ) -> IndexValueVec {
+        let mut feature_to_contribution = HashMap::<Index, f32>::new();
+        for tree_path in self.explain_paths(feature_vec, beam_size, label) {
+            for contributions in tree_path {
+                for (index, contribution) in contributions {
+                    *feature_to_contribution.entry(index).or_insert(0.) += contribution;
+                }
+            }
+        }
+        let mut contributions = feature_to_contribution
+            .into_iter()
+            .collect::<IndexValueVec>();
+        contributions.sort_unstable_by_key(|&(index, _)| index);
+        contributions
+    }
+
+    /// Like [`Self::explain`], but with the contributions to each classifier on the path to the
+    /// label kept apart, from the leaf up, for each tree; the paths of trees where the label isn't
+    /// reached are empty.
+    fn explain_paths(
+        &self,
+        feature_vec: impl AsRef<[(Index, f32)]>,
+        beam_size: usize,
+        label: Index,
+    ) -> Vec<Vec<IndexValueVec>> {
+        let feature_vec = feature_vec.as_ref();
+        let tree_predictions = self.predict_per_tree(feature_vec, beam_size);
+        let feature_vec = self.prepare_feature_vec(feature_vec);
+        self.trees
+            .iter()
+            .zip(&tree_predictions)
+            .map(|(tree, predictions)| {
+                if !predictions.iter().any(|&(l, _)| l == label) {
+                    return Vec::new();
+                }
+                tree.label_path(label)
+                    .expect("A label reached in a tree should be in one of its leaves")
+                    .into_iter()
+                    .map(|(weights, column)| {
+                        let mut contributions =
+                            classifier_contributions(weights, column, &feature_vec);
+                        self.translate_features(&mut contributions);
+                        contributions
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::math;
+    use crate::model::liblinear;
+    use crate::test_util::{toy_dataset, toy_model};
+    use assert_approx_eq::assert_approx_eq;
+
+    #[test]
+    fn test_explain() {
+        let model = toy_model(3, 0);
+        // The score of a classifier as in [`liblinear::predict`]
+        let classifier_score = |contributions: &IndexValueVec| {
+            let margin = contributions.iter().map(|&(_, c)| c).sum::<f32>();
+            match model.settings.classifier_loss_type {
+                liblinear::LossType::Log => -math::exp(-margin).ln_1p(),
+                liblinear::LossType::Hinge => -(1. - margin).max(0.).powi(2),
+            }
+        };
+        for feature_vec in &toy_dataset(10, 8, 1).feature_lists {
+            for (label, score) in model.predict(feature_vec, 3) {
+                // The margins along the paths give back the predicted score
+                let tree_paths = model.explain_paths(feature_vec, 3, label);
+                let reconstructed = tree_paths
+                    .iter()
+                    .filter(|tree_path| !tree_path.is_empty())
+                    .map(|tree_path| math::exp(tree_path.iter().map(classifier_score).sum()))
+                    .sum::<f32>()
+                    / model.n_trees() as f32;
+                assert_approx_eq!(score, reconstructed, 1e-4);
+
+                let contributions = model.explain(feature_vec, 3, label);
+                assert!(contributions.windows(2).all(|w| w[0].0 < w[1].0));
+                assert_approx_eq!(
+                    tree_paths
+                        .iter()
+                        .flatten()
+                        .flatten()
+                        .map(|&(_, c)| c)
+                        .sum::<f32>(),
+                    contributions.iter().map(|&(_, c)| c).sum::<f32>(),
+                    1e-4
+                );
+            }
+        }
+
+        // Labels that aren't reached have nothing to explain
+        let feature_vec = &toy_dataset(1, 8, 1).feature_lists[0];
+        let predicted = model.predict(feature_vec, 1);
+        let unreached = (0..model.n_labels() as Index)
+            .find(|label| predicted.iter().all(|&(l, _)| l != *label))
+            .unwrap();
+        assert!(model.explain(feature_vec, 1, unreached).is_empty());
+    }
+}
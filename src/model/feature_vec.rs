@@ -0,0 +1,311 @@
+//! Checking and fixing input feature vectors.
+//!
+//! Prediction assumes that feature vectors are sorted by index, without duplicate indices, and
+//! within the model's features, and doesn't check it, since checking costs time on every input.
+//! Inputs that break these assumptions give wrong predictions or panics far from their cause, so
+//! callers that can't vouch for their inputs can check them here first, and fix them.
+use super::{Model, PredictError, PredictOptions};
+use crate::mat_util::*;
+use crate::{Index, IndexValueVec};
+use std::fmt;
+
+/// What is wrong with a feature vector, at the position of the first offending pair; reported in
+/// checked prediction as [`PredictError::InvalidFeatureVec`].
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum FeatureVecError {
+    /// The index is beyond the model's features.
+    IndexOutOfRange {
+        position: usize,
+        index: Index,
+        n_features: usize,
+    },
+    /// The index is the same as that of the previous pair.
+    DuplicateIndex { position: usize, index: Index },
+    /// The index is smaller than that of the previous pair.
+    Unsorted {
+        position: usize,
+        index: Index,
+        previous_index: Index,
+    },
+    /// The value is infinite or NaN.
+    NonFiniteValue {
+        position: usize,
+        index: Index,
+        value: f32,
+    },
+}
+
+impl fmt::Display for FeatureVecError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            FeatureVecError::IndexOutOfRange {
+                position,
+                index,
+                n_features,
+            } => write!(
+                f,
+                "Feature index {} at position {} out of range for {} features",
+                index, position, n_features
+            ),
+            FeatureVecError::DuplicateIndex { position, index } => {
+                write!(
+                    f,
+                    "Duplicate feature index {} at position {}",
+                    index, position
+                )
+            }
+            FeatureVecError::Unsorted {
+                position,
+                index,
+                previous_index,
+            } => write!(
+                f,
+                "Feature vector unsorted at position {}: index {} follows index {}",
+                position, index, previous_index
+            ),
+            FeatureVecError::NonFiniteValue {
+                position,
+                index,
+                value,
+            } => write!(
+                f,
+                "Feature {} at position {} has non-finite value {}",
+                index, position, value
+            ),
+        }
+    }
+}
+
+impl std::error::Error for FeatureVecError {}
+
+impl Model {
+    /// Check that the given feature vector is sorted by index, has no duplicate indices, has
+    /// indices within the model's features, and has finite values, reporting the first problem.
+    ///
+    /// Indices of models with a feature projection can be arbitrarily large.
+    pub fn validate_feature_vec(
+        &self,
+        feature_vec: impl AsRef<[(Index, f32)]>,
+    ) -> Result<(), FeatureVecError> {
+        let feature_vec = feature_vec.as_ref();
+        let n_features = self.n_features();
+        // Indices of projected inputs can be arbitrarily large
+        let max_n_features = match self.feature_projection {
+            Some(_) => usize::MAX,
+            None => n_features,
+        };
+        if feature_vec.is_valid_sparse_vec(max_n_features)
+            && feature_vec.iter().all(|&(_, value)| value.is_finite())
+        {
+            return Ok(());
+        }
+
+        // Find the first problem
+        for (position, &(index, value)) in feature_vec.iter().enumerate() {
+            if let Some(&(previous_index, _)) = position.checked_sub(1).map(|i| &feature_vec[i]) {
+                if index == previous_index {
+                    return Err(FeatureVecError::DuplicateIndex { position, index });
+                } else if index < previous_index {
+                    return Err(FeatureVecError::Unsorted {
+                        position,
+                        index,
+                        previous_index,
+                    });
+                }
+            }
+            if index as usize >= max_n_features {
+                return Err(FeatureVecError::IndexOutOfRange {
+                    position,
+                    index,
+                    n_features,
+                });
+            }
+            if !value.is_finite() {
+                return Err(FeatureVecError::NonFiniteValue {
+                    position,
+                    index,
+                    value,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Like [`Self::predict`], but checks the feature vector with
+    /// [`Self::validate_feature_vec`] first, and reports problems as
+    /// [`Self::predict_with_options`] does.
+    pub fn predict_checked(
+        &self,
+        feature_vec: impl AsRef<[(Index, f32)]>,
+        beam_size: usize,
+    ) -> Result<IndexValueVec, PredictError> {
+        let feature_vec = feature_vec.as_ref();
+        self.validate_feature_vec(feature_vec)
+            .map_err(PredictError::InvalidFeatureVec)?;
+        self.predict_with_options(
+            feature_vec,
+            &PredictOptions {
+                beam_size,
+                ..PredictOptions::default()
+            },
+        )
+    }
+
+    /// Fix the given feature vector for the model: sort it by index, merge pairs with the same
+    /// index by summing their values, and drop indices beyond the model's features.
+    ///
+    /// Non-finite values are kept, since there's no right value to replace them with.
+    pub fn sanitize_feature_vec(&self, feature_vec: impl AsRef<[(Index, f32)]>) -> IndexValueVec {
+        let n_features = self.n_features();
+        let mut sanitized = feature_vec
+            .as_ref()
+            .iter()
+            .filter(|&&(index, _)| {
+                self.feature_projection.is_some() || (index as usize) < n_features
+            })
+            .cloned()
+            .collect::<IndexValueVec>();
+        sanitized.sort_by_index();
+        sanitized.sum_duplicate_indices();
+        sanitized
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{toy_dataset, toy_model};
+
+    #[test]
+    fn test_validate_feature_vec() {
+        let model = toy_model(1, 0);
+        let n_features = model.n_features();
+        let n = n_features as Index;
+        assert_eq!(Ok(()), model.validate_feature_vec(IndexValueVec::new()));
+        assert_eq!(Ok(()), model.validate_feature_vec([(0, 1.), (n - 1, 2.)]));
+        assert_eq!(
+            Err(FeatureVecError::IndexOutOfRange {
+                position: 1,
+                index: n,
+                n_features,
+            }),
+            model.validate_feature_vec([(0, 1.), (n, 2.)])
+        );
+        assert_eq!(
+            Err(FeatureVecError::DuplicateIndex {
+                position: 2,
+                index: 3,
+            }),
+            model.validate_feature_vec([(1, 1.), (3, 2.), (3, 1.)])
+        );
+        assert_eq!(
+            Err(FeatureVecError::Unsorted {
+                position: 1,
+                index: 1,
+                previous_index: 3,
+            }),
+            model.validate_feature_vec([(3, 1.), (1, 2.)])
+        );
+        let error = model.validate_feature_vec([(1, f32::NAN)]).unwrap_err();
+        assert!(matches!(
+            error,
+            FeatureVecError::NonFiniteValue {
+                position: 0,
+                index: 1,
+                ..
+            }
+        ));
+        assert_eq!(
+            format!(
+                "Feature index {} at position 1 out of range for {} features",
+                n, n_features
+            ),
+            model
+                .validate_feature_vec([(0, 1.), (n, 2.)])
+                .unwrap_err()
+                .to_string()
+        );
+        assert_eq!(
+            Err(PredictError::InvalidFeatureVec(
+                FeatureVecError::DuplicateIndex {
+                    position: 1,
+                    index: 3,
+                }
+            )),
+            model.predict_checked([(3, 1.), (3, 2.)], 5)
+        );
+
+        for feature_vec in &toy_dataset(10, 8, 1).feature_lists {
+            assert_eq!(
+                Ok(model.predict(feature_vec, 5)),
+                model.predict_checked(feature_vec, 5)
+            );
+        }
+    }
+
+    #[test]
+    fn test_checked_prediction_variants() {
+        let model = toy_model(2, 0);
+        let feature_vecs = toy_dataset(10, 8, 1).feature_lists;
+        for feature_vec in &feature_vecs {
+            let mut unsorted = model.predict_unsorted_checked(feature_vec, 5).unwrap();
+            unsorted.sort_by_index();
+            let mut predictions = model.predict(feature_vec, 5);
+            predictions.sort_by_index();
+            assert_eq!(predictions, unsorted);
+            assert_eq!(
+                Ok(model.predict_top_k(feature_vec, 5, 3)),
+                model.predict_top_k_checked(feature_vec, 5, 3)
+            );
+            assert_eq!(
+                Ok(model.predict_per_tree(feature_vec, 5)),
+                model.predict_per_tree_checked(feature_vec, 5)
+            );
+        }
+        let expected = model
+            .predict_batch(&feature_vecs, 5)
+            .into_iter()
+            .map(Ok)
+            .collect::<Vec<_>>();
+        assert_eq!(expected, model.predict_batch_checked(&feature_vecs, 5));
+        assert_eq!(
+            expected,
+            model.predict_batch_sequential_checked(&feature_vecs, 5)
+        );
+
+        // Inputs that would panic fail on their own
+        let infinite = vec![(0, f32::INFINITY)];
+        assert!(matches!(
+            model.predict_unsorted_checked(&infinite, 5),
+            Err(PredictError::InvalidFeatureVec(_))
+        ));
+        assert!(model.predict_top_k_checked(&infinite, 5, 3).is_err());
+        assert!(model.predict_per_tree_checked(&infinite, 5).is_err());
+        let batch = vec![feature_vecs[0].clone(), infinite, feature_vecs[1].clone()];
+        for results in [
+            model.predict_batch_checked(&batch, 5),
+            model.predict_batch_sequential_checked(&batch, 5),
+        ] {
+            assert_eq!(Ok(model.predict(&batch[0], 5)), results[0]);
+            assert!(results[1].is_err());
+            assert_eq!(Ok(model.predict(&batch[2], 5)), results[2]);
+        }
+
+        // A zero beam size fails every example
+        assert!(model
+            .predict_batch_checked(&feature_vecs, 0)
+            .iter()
+            .all(|result| matches!(result, Err(PredictError::InvalidOptions(_)))));
+        assert!(model.predict_per_tree_checked(&feature_vecs[0], 0).is_err());
+    }
+
+    #[test]
+    fn test_sanitize_feature_vec() {
+        let model = toy_model(1, 0);
+        let n = model.n_features() as Index;
+        let sanitized = model.sanitize_feature_vec([(3, 1.), (n, 5.), (1, 2.), (3, 0.5), (0, 1.)]);
+        assert_eq!(vec![(0, 1.), (1, 2.), (3, 1.5)], sanitized);
+        assert_eq!(Ok(()), model.validate_feature_vec(&sanitized));
+        assert!(model.sanitize_feature_vec(IndexValueVec::new()).is_empty());
+    }
+}
@@ -0,0 +1,968 @@
+//! Serialization of models into a single stream of per-tree frames.
+//!
+//! The stream starts with [`FRAMED_MAGIC`], followed by the length of a JSON manifest as a
+//! little-endian `u64` and the manifest itself. Each tree is then stored as a frame, i.e., its
+//! length as a little-endian `u64` followed by the CBOR-serialized tree. Since every frame is
+//! prefixed by its length, readers that support seeking can skip trees without deserializing them.
+//!
+//! The last frame is followed by a checksum trailer, i.e., [`CHECKSUM_MAGIC`] and the SHA-256
+//! digest of everything before it, which is verified when loading; streams saved before
+//! checksums were added end right after the last frame and are loaded with a warning.
+//!
+//! Streams without the magic bytes are assumed to be in the legacy single-blob format, i.e., the
+//! whole model serialized as one CBOR value.
+//!
+//! Either format may be compressed with any codec enabled by features, see [`Compression`];
+//! compression is detected from the magic bytes of the stream when loading.
+use super::checksum::{self, Hashed};
+use super::drift::InputProfile;
+use super::label_graph::LabelGraph;
+use super::limits::InferenceLimits;
+use super::projection::ProjectionParams;
+use super::thresholds::LabelThresholds;
+use super::train::TrainingMetadata;
+use super::version::{self, CRATE_VERSION};
+use super::{compact, io_sink, Model, SaveOptions, Settings, TreeNode};
+use crate::data::compression::{self, Compression};
+use crate::Index;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::fs;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time;
+
+/// Magic bytes at the start of a framed model stream, the last of which is the format version.
+pub(crate) const FRAMED_MAGIC: &[u8; 8] = b"OMKJFRM1";
+
+/// Everything in a model except its trees.
+#[derive(Serialize, Deserialize)]
+pub(super) struct Manifest {
+    pub(super) settings: Settings,
+    pub(super) n_trees: usize,
+    #[serde(default)]
+    label_thresholds: Option<LabelThresholds>,
+    #[serde(default)]
+    training_metadata: TrainingMetadata,
+    #[serde(default)]
+    inference_limits: InferenceLimits,
+    #[serde(default)]
+    feature_projection: Option<ProjectionParams>,
+    #[serde(default)]
+    input_profile: Option<InputProfile>,
+    #[serde(default)]
+    label_graph: Option<LabelGraph>,
+    #[serde(default)]
+    original_labels: Option<Vec<Index>>,
+    #[serde(default)]
+    label_names: Option<Vec<String>>,
+    #[serde(default)]
+    pub(super) used_features: Option<Vec<Index>>,
+    /// The version of the crate the model was saved by, if recorded.
+    #[serde(default)]
+    crate_version: Option<String>,
+}
+
+/// A writer that only counts the number of bytes written to it.
+struct ByteCounter(u64);
+
+impl Write for ByteCounter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0 += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// A reader or writer that counts the number of bytes passing through it.
+struct Counted<T> {
+    inner: T,
+    n_bytes: u64,
+}
+
+impl<T> Counted<T> {
+    fn new(inner: T) -> Self {
+        Self { inner, n_bytes: 0 }
+    }
+}
+
+impl<R: Read> Read for Counted<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.n_bytes += n as u64;
+        Ok(n)
+    }
+}
+
+impl<W: Write> Write for Counted<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.n_bytes += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Progress of [`Model::save_with_progress`], reported after the manifest and after each tree.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct SaveProgress {
+    /// The number of trees written so far.
+    pub n_trees_saved: usize,
+    /// The number of trees in the model.
+    pub n_trees: usize,
+    /// The number of bytes written so far.
+    pub n_bytes: u64,
+}
+
+/// Progress of [`Model::load_with_progress`], reported after the manifest and after each tree.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct LoadProgress {
+    /// The number of trees read so far.
+    pub n_trees_loaded: usize,
+    /// The number of trees in the model.
+    pub n_trees: usize,
+    /// The number of bytes read so far, after decompression.
+    pub n_bytes: u64,
+}
+
+fn to_io_error<E: std::fmt::Display>(kind: io::ErrorKind, msg: &str, e: E) -> io::Error {
+    io::Error::new(kind, format!("{}: {}", msg, e))
+}
+
+fn read_u64<R: Read>(reader: &mut R) -> io::Result<u64> {
+    let mut buf = [0u8; 8];
+    reader.read_exact(&mut buf)?;
+    Ok(u64::from_le_bytes(buf))
+}
+
+/// Read the magic bytes, returning false if the stream doesn't start with them.
+///
+/// On success the stream is positioned right after the bytes read.
+fn read_magic<R: Read>(reader: &mut R) -> io::Result<(bool, Vec<u8>)> {
+    let mut buf = Vec::with_capacity(FRAMED_MAGIC.len());
+    reader
+        .by_ref()
+        .take(FRAMED_MAGIC.len() as u64)
+        .read_to_end(&mut buf)?;
+    Ok((version::check_magic(&buf, FRAMED_MAGIC)?, buf))
+}
+
+fn read_manifest<R: Read>(reader: &mut R) -> io::Result<Manifest> {
+    let manifest_len = read_u64(reader)?;
+    let manifest: Manifest = serde_json::from_reader(reader.take(manifest_len)).map_err(|e| {
+        to_io_error(
+            io::ErrorKind::InvalidData,
+            "Unable to deserialize manifest",
+            e,
+        )
+    })?;
+    if let Some(crate_version) = manifest.crate_version.as_ref() {
+        info!("Model was saved by omikuji {}", crate_version);
+    }
+    compact::check_used_features(manifest.settings, manifest.used_features.as_deref())?;
+    Ok(manifest)
+}
+
+fn read_tree_frame<R: Read>(
+    reader: &mut R,
+    settings: Settings,
+    index: usize,
+) -> io::Result<TreeNode> {
+    let frame_len = read_u64(reader)?;
+    let tree: TreeNode = serde_cbor::from_reader(reader.take(frame_len)).map_err(|e| {
+        to_io_error(
+            io::ErrorKind::InvalidData,
+            &format!("Unable to deserialize tree {}", index),
+            e,
+        )
+    })?;
+    if !tree.is_valid(settings) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Tree {} is invalid", index),
+        ));
+    }
+    Ok(tree)
+}
+
+fn write_tree_frame<W: Write + ?Sized>(writer: &mut W, tree: &TreeNode) -> io::Result<()> {
+    // Measure the frame first so that trees never need to be buffered in memory
+    let mut counter = ByteCounter(0);
+    serde_cbor::to_writer(&mut counter, tree)
+        .map_err(|e| to_io_error(io::ErrorKind::Other, "Unable to serialize tree", e))?;
+    writer.write_all(&counter.0.to_le_bytes())?;
+    serde_cbor::to_writer(writer, tree)
+        .map_err(|e| to_io_error(io::ErrorKind::Other, "Unable to serialize tree", e))
+}
+
+/// Trees written to temporary files as frames, so that trees trained earlier don't need to be
+/// held in memory while later ones are trained.
+///
+/// The files are removed when the spill is dropped.
+pub(crate) struct TreeSpill {
+    paths: Vec<PathBuf>,
+}
+
+impl TreeSpill {
+    /// Prepare to spill the given number of trees into files in the given directory, which is
+    /// created if needed.
+    pub fn new(dir: &Path, n_trees: usize) -> io::Result<Self> {
+        static N_SPILLS: AtomicUsize = AtomicUsize::new(0);
+        fs::create_dir_all(dir)?;
+        let prefix = format!(
+            "omikuji-spill-{}-{}",
+            std::process::id(),
+            N_SPILLS.fetch_add(1, Ordering::Relaxed)
+        );
+        Ok(Self {
+            paths: (0..n_trees)
+                .map(|i| dir.join(format!("{}-tree{}.frame", prefix, i)))
+                .collect(),
+        })
+    }
+
+    /// Write the tree at the given index into its file, which only appears once complete.
+    pub fn spill(&self, index: usize, tree: &TreeNode) -> io::Result<()> {
+        info!("Spilling tree {} to {}", index, self.paths[index].display());
+        io_sink::write_atomically(&io_sink::FileSink, &self.paths[index], |writer| {
+            write_tree_frame(writer, tree)
+        })
+    }
+
+    /// Paths of the trees that were spilled, in order of their indices; trees can be missing if
+    /// they were skipped in training.
+    fn spilled_paths(&self) -> impl Iterator<Item = &PathBuf> {
+        self.paths.iter().filter(|path| path.exists())
+    }
+
+    /// Read all spilled trees back into memory.
+    pub fn load_trees(&self, settings: Settings) -> io::Result<Vec<TreeNode>> {
+        self.spilled_paths()
+            .enumerate()
+            .map(|(i, path)| {
+                let mut reader = io::BufReader::new(fs::File::open(path)?);
+                read_tree_frame(&mut reader, settings, i)
+            })
+            .collect()
+    }
+
+    /// Save the given model into a directory as [`Model::save`] does, with the spilled trees in
+    /// place of its own, reading them back one at a time.
+    pub fn save_model(&self, model: &Model, dir_path: &Path) -> io::Result<()> {
+        let trees = self.spilled_paths().enumerate().map(|(i, path)| {
+            let mut reader = io::BufReader::new(fs::File::open(path)?);
+            read_tree_frame(&mut reader, model.settings, i).map(Cow::Owned)
+        });
+        model.save_with_trees(dir_path, &SaveOptions::default(), &io_sink::FileSink, trees)
+    }
+
+    /// Serialize the given model with the spilled trees in place of its own, copying the frames
+    /// one at a time.
+    ///
+    /// The output is the same as that of [`Model::save_to_writer`] for the model with the
+    /// spilled trees.
+    pub fn write_model<W: Write>(&self, model: &Model, writer: W) -> io::Result<()> {
+        let paths = self.spilled_paths().collect::<Vec<_>>();
+        let mut writer = Hashed::new(writer);
+        model.write_manifest(&mut writer, paths.len())?;
+        for path in paths {
+            io::copy(&mut fs::File::open(path)?, &mut writer)?;
+        }
+        let digest = writer.digest();
+        checksum::write_trailer(&mut writer, digest)?;
+        writer.flush()
+    }
+}
+
+impl Drop for TreeSpill {
+    fn drop(&mut self) {
+        for path in &self.paths {
+            if path.exists() {
+                if let Err(e) = fs::remove_file(path) {
+                    warn!("Failed to remove spilled tree {}: {}", path.display(), e);
+                }
+            }
+        }
+    }
+}
+
+fn load_legacy_blob<R: Read>(reader: R) -> io::Result<Model> {
+    let model: Model = serde_cbor::from_reader(reader)
+        .map_err(|e| to_io_error(io::ErrorKind::InvalidData, "Unable to deserialize model", e))?;
+    if let Some(i) = model.trees.iter().position(|t| !t.is_valid(model.settings)) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Tree {} is invalid", i),
+        ));
+    }
+    Ok(model)
+}
+
+pub(super) fn check_tree_indices(tree_indices: &[usize], n_trees: usize) -> io::Result<()> {
+    if tree_indices.is_empty() {
+        Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "At least one tree must be selected",
+        ))
+    } else if let Some(&i) = tree_indices.iter().find(|&&i| i >= n_trees) {
+        Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Tree index {} out of range for {} trees", i, n_trees),
+        ))
+    } else {
+        Ok(())
+    }
+}
+
+impl Model {
+    /// Serialize the model into a single stream of per-tree frames.
+    pub fn save_to_writer<W: Write>(&self, writer: W) -> io::Result<()> {
+        self.save_with_progress(writer, |_| {})
+    }
+
+    /// Like [`Self::save_to_writer`], but reports progress to the given callback after the
+    /// manifest and after each tree.
+    ///
+    /// Trees are serialized straight into the writer one at a time, and the writer is flushed
+    /// after each of them, so that writers that upload data in chunks can send each tree as soon
+    /// as it's written.
+    pub fn save_with_progress<W: Write>(
+        &self,
+        writer: W,
+        mut progress: impl FnMut(SaveProgress),
+    ) -> io::Result<()> {
+        info!("Saving model to stream...");
+        let start_t = time::Instant::now();
+
+        let mut writer = Counted::new(Hashed::new(writer));
+        let n_trees = self.trees.len();
+        self.write_manifest(&mut writer, n_trees)?;
+        writer.flush()?;
+        progress(SaveProgress {
+            n_trees_saved: 0,
+            n_trees,
+            n_bytes: writer.n_bytes,
+        });
+        for (i, tree) in self.trees.iter().enumerate() {
+            write_tree_frame(&mut writer, tree)?;
+            writer.flush()?;
+            progress(SaveProgress {
+                n_trees_saved: i + 1,
+                n_trees,
+                n_bytes: writer.n_bytes,
+            });
+        }
+        let digest = writer.inner.digest();
+        checksum::write_trailer(&mut writer, digest)?;
+        writer.flush()?;
+
+        info!(
+            "Model saved; it took {:.2}s",
+            start_t.elapsed().as_secs_f32()
+        );
+        Ok(())
+    }
+
+    /// Like [`Self::save_to_writer`], but compresses the stream with the given codec, at the given
+    /// level or the codec's default one.
+    ///
+    /// The codec must be enabled by its feature, see [`Compression`]. Compressed streams are
+    /// detected when loading, so readers don't need to know which codec was used.
+    pub fn save_compressed<W: Write>(
+        &self,
+        writer: W,
+        compression: Compression,
+        level: Option<u32>,
+    ) -> io::Result<()> {
+        compression::compress(writer, compression, level, |writer| {
+            self.save_to_writer(writer)
+        })
+    }
+
+    /// The manifest of a model with the given number of trees.
+    pub(super) fn manifest(&self, n_trees: usize) -> Manifest {
+        Manifest {
+            settings: self.settings,
+            n_trees,
+            label_thresholds: self.label_thresholds.clone(),
+            training_metadata: self.training_metadata.clone(),
+            inference_limits: self.inference_limits,
+            feature_projection: self.feature_projection,
+            input_profile: self.input_profile.clone(),
+            label_graph: self.label_graph.clone(),
+            original_labels: self.original_labels.clone(),
+            label_names: self.label_names.clone(),
+            used_features: self.used_features.clone(),
+            crate_version: Some(CRATE_VERSION.to_owned()),
+        }
+    }
+
+    /// Create a model from its manifest and its trees, checking that its label names cover its
+    /// labels.
+    pub(super) fn from_manifest(manifest: Manifest, trees: Vec<TreeNode>) -> io::Result<Self> {
+        let Manifest {
+            settings,
+            n_trees: _,
+            label_thresholds,
+            training_metadata,
+            inference_limits,
+            feature_projection,
+            input_profile,
+            label_graph,
+            original_labels,
+            label_names,
+            used_features,
+            crate_version: _,
+        } = manifest;
+        let model = Self {
+            trees,
+            settings,
+            label_thresholds,
+            training_metadata,
+            inference_limits,
+            feature_projection,
+            input_profile,
+            label_graph,
+            original_labels,
+            label_names,
+            used_features,
+            sorted_labels: Default::default(),
+            label_index: Default::default(),
+        };
+        model.check_loaded_label_names()?;
+        Ok(model)
+    }
+
+    /// Write the magic bytes and the manifest of a model with the given number of trees.
+    fn write_manifest<W: Write>(&self, writer: &mut W, n_trees: usize) -> io::Result<()> {
+        let manifest = serde_json::to_vec(&self.manifest(n_trees))
+            .map_err(|e| to_io_error(io::ErrorKind::Other, "Unable to serialize manifest", e))?;
+        writer.write_all(FRAMED_MAGIC)?;
+        writer.write_all(&(manifest.len() as u64).to_le_bytes())?;
+        writer.write_all(&manifest)
+    }
+
+    /// Deserialize a model from a stream written by [`Self::save_to_writer()`] or
+    /// [`Self::save_compressed()`].
+    ///
+    /// Streams in the legacy single-blob format are also accepted.
+    pub fn load_from_reader<R: Read>(reader: R) -> io::Result<Self> {
+        Self::load_with_progress(reader, |_| {})
+    }
+
+    /// Like [`Self::load_from_reader`], but reports progress to the given callback after the
+    /// manifest and after each tree.
+    ///
+    /// Models in the legacy single-blob format are read at once, so progress is only reported
+    /// when they're fully loaded.
+    pub fn load_with_progress<R: Read>(
+        reader: R,
+        mut progress: impl FnMut(LoadProgress),
+    ) -> io::Result<Self> {
+        let start_t = time::Instant::now();
+        let (reader, compression) = compression::decompress(reader)?;
+        if let Some(compression) = compression {
+            info!("Decompressing {:?}-compressed model stream", compression);
+        }
+        let mut reader = Counted::new(Hashed::new(reader));
+        let (is_framed, prefix) = read_magic(&mut reader)?;
+        if !is_framed {
+            info!("No frame header found; loading model in the single-blob format");
+            let model = load_legacy_blob(io::Cursor::new(prefix).chain(&mut reader))?;
+            progress(LoadProgress {
+                n_trees_loaded: model.trees.len(),
+                n_trees: model.trees.len(),
+                n_bytes: reader.n_bytes,
+            });
+            return Ok(model);
+        }
+
+        let manifest = read_manifest(&mut reader)?;
+        let settings = manifest.settings;
+        info!("Loaded model settings {:?}...", settings);
+        let n_trees = manifest.n_trees;
+        progress(LoadProgress {
+            n_trees_loaded: 0,
+            n_trees,
+            n_bytes: reader.n_bytes,
+        });
+        let trees = (0..n_trees)
+            .map(|i| {
+                let tree = read_tree_frame(&mut reader, settings, i)?;
+                progress(LoadProgress {
+                    n_trees_loaded: i + 1,
+                    n_trees,
+                    n_bytes: reader.n_bytes,
+                });
+                Ok(tree)
+            })
+            .collect::<io::Result<Vec<_>>>()?;
+        let digest = reader.inner.digest();
+        checksum::check_trailer(&mut reader, digest)?;
+
+        info!(
+            "Loaded model with {} trees; it took {:.2}s",
+            trees.len(),
+            start_t.elapsed().as_secs_f32()
+        );
+        Self::from_manifest(manifest, trees)
+    }
+
+    /// Check the integrity of a model stream written by [`Self::save_to_writer()`] or
+    /// [`Self::save_compressed()`] against its checksum, without deserializing its trees.
+    ///
+    /// Returns an error of kind [`io::ErrorKind::InvalidData`] if the stream is corrupt or
+    /// truncated. Streams saved without a checksum, including those in the legacy single-blob
+    /// format, can't be verified and pass with a warning. The checksum covers the whole stream,
+    /// unlike those of model directories, see [`Self::verify_dir()`]; files saved with
+    /// [`Self::save_mmap()`] have none.
+    pub fn verify<R: Read>(reader: R) -> io::Result<()> {
+        let start_t = time::Instant::now();
+        let (reader, _) = compression::decompress(reader)?;
+        let mut reader = Hashed::new(reader);
+        let (is_framed, _) = read_magic(&mut reader)?;
+        if !is_framed {
+            warn!("Model stream is in the legacy single-blob format, so it can't be verified");
+            return Ok(());
+        }
+
+        let manifest = read_manifest(&mut reader)?;
+        for i in 0..manifest.n_trees {
+            let truncated = || {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Model stream ends in the middle of tree {}", i),
+                )
+            };
+            let frame_len = read_u64(&mut reader).map_err(|_| truncated())?;
+            let n_copied = io::copy(&mut reader.by_ref().take(frame_len), &mut io::sink())?;
+            if n_copied != frame_len {
+                return Err(truncated());
+            }
+        }
+        let digest = reader.digest();
+        checksum::check_trailer(&mut reader, digest)?;
+
+        info!(
+            "Verified model stream with {} trees; it took {:.2}s",
+            manifest.n_trees,
+            start_t.elapsed().as_secs_f32()
+        );
+        Ok(())
+    }
+
+    /// Deserialize only the trees at the given indices from a model stream.
+    ///
+    /// Frames of trees that are not selected are skipped without being deserialized. Trees in the
+    /// returned model are in the order of the given indices. Streams in the legacy single-blob
+    /// format or compressed are fully loaded before the selected trees are taken. The stream may
+    /// start anywhere in the reader, e.g., after a header of the caller's own.
+    ///
+    /// Since skipped trees aren't read, the checksum of the stream isn't verified; use
+    /// [`Self::verify`] for that.
+    pub fn load_partial<R: Read + Seek>(mut reader: R, tree_indices: &[usize]) -> io::Result<Self> {
+        let start_t = time::Instant::now();
+        let start_pos = reader.stream_position()?;
+        let (is_framed, prefix) = read_magic(&mut reader)?;
+        if !is_framed {
+            if Compression::detect(&prefix).is_some() {
+                warn!("Model stream is compressed and can't be skipped through; loading all trees first");
+            } else {
+                warn!("Model stream is in the legacy single-blob format; loading all trees first");
+            }
+            reader.seek(SeekFrom::Start(start_pos))?;
+            let model = Self::load_from_reader(reader)?;
+            check_tree_indices(tree_indices, model.trees.len())?;
+            return Ok(model.take_trees(tree_indices));
+        }
+
+        let Manifest {
+            settings,
+            n_trees,
+            label_thresholds,
+            training_metadata,
+            inference_limits,
+            feature_projection,
+            input_profile,
+            label_graph,
+            original_labels,
+            label_names,
+            used_features,
+            crate_version: _,
+        } = read_manifest(&mut reader)?;
+        check_tree_indices(tree_indices, n_trees)?;
+
+        let mut loaded = vec![None; n_trees];
+        for (i, slot) in loaded.iter_mut().enumerate() {
+            if tree_indices.contains(&i) {
+                *slot = Some(read_tree_frame(&mut reader, settings, i)?);
+            } else {
+                let frame_len = read_u64(&mut reader)?;
+                reader.seek(SeekFrom::Current(frame_len as i64))?;
+            }
+            if (i + 1..n_trees).all(|j| !tree_indices.contains(&j)) {
+                break; // No need to read any further
+            }
+        }
+
+        let mut trees = Vec::<TreeNode>::with_capacity(tree_indices.len());
+        for (pos, &i) in tree_indices.iter().enumerate() {
+            let tree = match loaded[i].take() {
+                Some(tree) => tree,
+                None => {
+                    // The same index was already selected earlier
+                    let first = tree_indices[..pos]
+                        .iter()
+                        .position(|&j| j == i)
+                        .expect("Selected tree should have been loaded");
+                    trees[first].clone()
+                }
+            };
+            trees.push(tree);
+        }
+        info!(
+            "Loaded {} of {} trees; it took {:.2}s",
+            trees.len(),
+            n_trees,
+            start_t.elapsed().as_secs_f32()
+        );
+        let model = Self {
+            trees,
+            settings,
+            label_thresholds,
+            training_metadata: training_metadata.select_trees(n_trees, tree_indices),
+            inference_limits,
+            feature_projection,
+            input_profile,
+            label_graph,
+            original_labels,
+            label_names,
+            used_features,
+            sorted_labels: Default::default(),
+            label_index: Default::default(),
+        };
+        model.check_loaded_label_names()?;
+        Ok(model)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{toy_dataset, toy_model};
+    use std::io::Cursor;
+
+    fn assert_same_predictions(expected: &Model, actual: &Model) {
+        for feature_vec in &toy_dataset(20, 8, 1).feature_lists {
+            assert_eq!(
+                expected.predict(feature_vec, 3),
+                actual.predict(feature_vec, 3)
+            );
+        }
+    }
+
+    #[test]
+    fn test_framed_round_trip() {
+        let model = toy_model(2, 0);
+        let mut buf = Vec::new();
+        model.save_to_writer(&mut buf).unwrap();
+        assert!(buf.starts_with(FRAMED_MAGIC));
+
+        let loaded = Model::load_from_reader(Cursor::new(&buf)).unwrap();
+        assert_eq!(2, loaded.n_trees());
+        assert_same_predictions(&model, &loaded);
+    }
+
+    #[test]
+    fn test_checksum() {
+        let model = toy_model(2, 0);
+        let mut buf = Vec::new();
+        model.save_to_writer(&mut buf).unwrap();
+        let trailer_start = buf.len() - checksum::CHECKSUM_MAGIC.len() - 32;
+        assert_eq!(
+            checksum::CHECKSUM_MAGIC,
+            &buf[trailer_start..trailer_start + 8]
+        );
+        Model::verify(&buf[..]).unwrap();
+
+        for &i in &[buf.len() / 2, trailer_start - 1, buf.len() - 1] {
+            let mut flipped = buf.clone();
+            flipped[i] ^= 0x01;
+            assert!(Model::load_from_reader(&flipped[..]).is_err());
+            assert_eq!(
+                io::ErrorKind::InvalidData,
+                Model::verify(&flipped[..]).unwrap_err().kind()
+            );
+        }
+        let mut flipped = buf.clone();
+        flipped[buf.len() - 1] ^= 0x01;
+        let error = Model::load_from_reader(&flipped[..]).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, error.kind());
+        assert!(error.to_string().contains("Checksum mismatch"));
+
+        // Truncated streams are rejected wherever they end
+        for &len in &[trailer_start - 1, trailer_start + 4, buf.len() - 1] {
+            assert!(Model::load_from_reader(&buf[..len]).is_err());
+            assert!(Model::verify(&buf[..len]).is_err());
+        }
+
+        // Streams saved before checksums were added are still accepted
+        let without_checksum = &buf[..trailer_start];
+        let loaded = Model::load_from_reader(without_checksum).unwrap();
+        assert_same_predictions(&model, &loaded);
+        Model::verify(without_checksum).unwrap();
+
+        let mut extra = buf.clone();
+        extra.push(0);
+        assert!(Model::load_from_reader(&extra[..]).is_err());
+    }
+
+    /// A writer that records the length of its contents at each flush.
+    #[derive(Default)]
+    struct FlushRecorder {
+        buf: Vec<u8>,
+        flushed_lens: Vec<usize>,
+    }
+
+    impl Write for FlushRecorder {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.buf.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.flushed_lens.push(self.buf.len());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_save_and_load_with_progress() {
+        let model = toy_model(3, 0);
+        let mut writer = FlushRecorder::default();
+        let mut saved = Vec::new();
+        model
+            .save_with_progress(&mut writer, |progress| saved.push(progress))
+            .unwrap();
+        assert_eq!(
+            (0..=3).collect::<Vec<_>>(),
+            saved.iter().map(|p| p.n_trees_saved).collect::<Vec<_>>()
+        );
+        assert!(saved.iter().all(|p| p.n_trees == 3));
+        assert!(saved.windows(2).all(|w| w[0].n_bytes < w[1].n_bytes));
+        // Each report comes after everything written so far was flushed
+        for progress in &saved {
+            assert!(writer.flushed_lens.contains(&(progress.n_bytes as usize)));
+        }
+        // Only the checksum trailer comes after the last tree
+        assert_eq!(
+            writer.buf.len() as u64,
+            saved.last().unwrap().n_bytes + checksum::CHECKSUM_MAGIC.len() as u64 + 32
+        );
+
+        let mut loaded_progress = Vec::new();
+        let loaded = Model::load_with_progress(writer.buf.as_slice(), |progress| {
+            loaded_progress.push(progress)
+        })
+        .unwrap();
+        assert_same_predictions(&model, &loaded);
+        assert_eq!(
+            saved
+                .iter()
+                .map(|p| (p.n_trees_saved, p.n_trees, p.n_bytes))
+                .collect::<Vec<_>>(),
+            loaded_progress
+                .iter()
+                .map(|p| (p.n_trees_loaded, p.n_trees, p.n_bytes))
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_load_partial() {
+        let model = toy_model(3, 0);
+        let mut buf = Vec::new();
+        model.save_to_writer(&mut buf).unwrap();
+
+        for indices in [vec![0], vec![2], vec![2, 0], vec![0, 1, 2]] {
+            let partial = Model::load_partial(Cursor::new(&buf), &indices).unwrap();
+            assert_eq!(indices.len(), partial.n_trees());
+            assert_same_predictions(&model.take_trees(&indices), &partial);
+        }
+
+        assert!(Model::load_partial(Cursor::new(&buf), &[3]).is_err());
+        assert!(Model::load_partial(Cursor::new(&buf), &[]).is_err());
+    }
+
+    #[test]
+    fn test_load_partial_legacy_blob() {
+        let model = toy_model(2, 0);
+        let buf = serde_cbor::to_vec(&model).unwrap();
+
+        let partial = Model::load_partial(Cursor::new(&buf), &[1]).unwrap();
+        assert_same_predictions(&model.take_trees(&[1]), &partial);
+        assert!(Model::load_partial(Cursor::new(&buf), &[2]).is_err());
+
+        let full = Model::load_from_reader(Cursor::new(&buf)).unwrap();
+        assert_same_predictions(&model, &full);
+    }
+
+    #[test]
+    fn test_load_partial_after_offset() {
+        let model = toy_model(2, 0);
+        let header = b"header";
+        let mut framed = Vec::new();
+        model.save_to_writer(&mut framed).unwrap();
+        let legacy = serde_cbor::to_vec(&model).unwrap();
+
+        for stream in [framed, legacy] {
+            let buf = [&header[..], &stream].concat();
+            let mut reader = Cursor::new(&buf);
+            reader.seek(SeekFrom::Start(header.len() as u64)).unwrap();
+            let partial = Model::load_partial(reader, &[1]).unwrap();
+            assert_same_predictions(&model.take_trees(&[1]), &partial);
+        }
+    }
+
+    #[test]
+    fn test_load_from_dir_partial() {
+        let model = toy_model(3, 0);
+        let dir = std::env::temp_dir().join(format!("omikuji-dir-partial-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        model.save(&dir).unwrap();
+
+        for indices in [vec![0], vec![2], vec![2, 0], vec![1, 1], vec![0, 1, 2]] {
+            let partial = Model::load_from_dir_partial(&dir, &indices).unwrap();
+            assert_eq!(indices.len(), partial.n_trees());
+            assert_same_predictions(&model.take_trees(&indices), &partial);
+        }
+        assert_same_predictions(&model, &Model::load(&dir).unwrap());
+
+        assert!(Model::load_from_dir_partial(&dir, &[3]).is_err());
+        assert!(Model::load_from_dir_partial(&dir, &[]).is_err());
+
+        // Unselected trees are never read
+        fs::write(dir.join("tree1.cbor"), b"corrupt").unwrap();
+        let partial = Model::load_from_dir_partial(&dir, &[2, 0]).unwrap();
+        assert_same_predictions(&model.take_trees(&[2, 0]), &partial);
+        assert!(Model::load(&dir).is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    fn is_available(compression: Compression) -> bool {
+        match compression {
+            Compression::Gzip => cfg!(feature = "gzip"),
+            Compression::Bzip2 => cfg!(feature = "bzip2"),
+            Compression::Zstd => cfg!(feature = "zstd"),
+        }
+    }
+
+    #[test]
+    fn test_compressed_round_trip() {
+        let model = toy_model(2, 0);
+        let mut plain = Vec::new();
+        model.save_to_writer(&mut plain).unwrap();
+
+        for &compression in &[Compression::Gzip, Compression::Bzip2, Compression::Zstd] {
+            let level = Some(*compression.levels().end());
+            let mut buf = Vec::new();
+            let result = model.save_compressed(&mut buf, compression, level);
+            if !is_available(compression) {
+                assert_eq!(io::ErrorKind::Unsupported, result.unwrap_err().kind());
+                continue;
+            }
+            result.unwrap();
+            assert_eq!(Some(compression), Compression::detect(&buf));
+            assert!(buf.len() < plain.len());
+
+            let loaded = Model::load_from_reader(Cursor::new(&buf)).unwrap();
+            assert_eq!(2, loaded.n_trees());
+            assert_same_predictions(&model, &loaded);
+
+            let partial = Model::load_partial(Cursor::new(&buf), &[1]).unwrap();
+            assert_same_predictions(&model.take_trees(&[1]), &partial);
+
+            let mut buf = Vec::new();
+            model.save_compressed(&mut buf, compression, None).unwrap();
+            assert_same_predictions(&model, &Model::load_from_reader(&buf[..]).unwrap());
+
+            let level = Some(compression.levels().end() + 1);
+            assert_eq!(
+                io::ErrorKind::InvalidInput,
+                model
+                    .save_compressed(Vec::new(), compression, level)
+                    .unwrap_err()
+                    .kind()
+            );
+        }
+    }
+
+    #[test]
+    fn test_load_corrupt_compressed() {
+        let model = toy_model(2, 0);
+        for &compression in &[Compression::Gzip, Compression::Bzip2, Compression::Zstd] {
+            if !is_available(compression) {
+                continue;
+            }
+            let mut buf = Vec::new();
+            model.save_compressed(&mut buf, compression, None).unwrap();
+
+            let truncated = &buf[..buf.len() / 2];
+            assert!(Model::load_from_reader(truncated).is_err());
+
+            let mut flipped = buf.clone();
+            let middle = flipped.len() / 2;
+            flipped[middle] ^= 0xff;
+            assert!(Model::load_from_reader(&flipped[..]).is_err());
+        }
+    }
+
+    #[test]
+    fn test_tree_spill() {
+        let model = toy_model(3, 0);
+        let dir = std::env::temp_dir().join(format!("omikuji-spill-test-{}", std::process::id()));
+        let spill = TreeSpill::new(&dir, 3).unwrap();
+        for (i, tree) in model.trees.iter().enumerate() {
+            spill.spill(i, tree).unwrap();
+        }
+
+        let mut expected = Vec::new();
+        model.save_to_writer(&mut expected).unwrap();
+        let without_trees = Model {
+            trees: Vec::new(),
+            ..model.clone()
+        };
+        let mut actual = Vec::new();
+        spill.write_model(&without_trees, &mut actual).unwrap();
+        assert_eq!(expected, actual);
+
+        let loaded = Model {
+            trees: spill.load_trees(model.settings).unwrap(),
+            ..without_trees.clone()
+        };
+        assert_same_predictions(&model, &loaded);
+
+        // Saving into a directory gives the same model as saving the trees from memory
+        let model_dir = dir.join("model");
+        spill.save_model(&without_trees, &model_dir).unwrap();
+        let saved = Model::load(&model_dir).unwrap();
+        assert_eq!(
+            serde_cbor::to_vec(&model.trees).unwrap(),
+            serde_cbor::to_vec(&saved.trees).unwrap()
+        );
+        assert_same_predictions(&model, &saved);
+        fs::remove_dir_all(&model_dir).unwrap();
+
+        drop(spill);
+        assert_eq!(0, fs::read_dir(&dir).unwrap().count());
+        fs::remove_dir(&dir).unwrap();
+    }
+}
@@ -0,0 +1,321 @@
+//! Swapping the model used for serving without blocking predictions.
+//!
+//! A [`ModelHandle`] holds the current model behind an atomically swappable pointer. Predictions
+//! pin the model that's current when they start and keep using it until they finish, while a new
+//! model is loaded and checked on the side; swapping it in is a single pointer store, so readers
+//! never wait for a writer.
+use super::conformance::ConformanceReport;
+use super::predict::{PredictError, PredictOptions};
+use super::Model;
+use crate::{Index, IndexValueVec};
+use arc_swap::ArcSwap;
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time;
+
+/// A shared, swappable model for serving.
+pub struct ModelHandle {
+    current: ArcSwap<Model>,
+    /// The number of models swapped in so far; only changed while holding the lock, so that
+    /// concurrent swaps are numbered in the order they took effect.
+    generation: Mutex<u64>,
+    conformance_suite: Option<PathBuf>,
+}
+
+/// The outcome of successfully swapping in a new model.
+#[derive(Clone, Debug)]
+pub struct SwapReport {
+    /// The number of models swapped in since the handle was created, including this one.
+    pub generation: u64,
+    pub n_trees: usize,
+    /// The time spent loading the model, excluding checks.
+    pub load_time: time::Duration,
+    /// The result of the conformance check, if the handle has a conformance suite.
+    pub conformance: Option<ConformanceReport>,
+}
+
+/// Why a new model wasn't swapped in; the current model is kept in every case.
+#[derive(Debug)]
+pub enum SwapError {
+    /// The model couldn't be loaded, or the conformance suite couldn't be read.
+    Io(io::Error),
+    /// The model failed [`Model::validate`].
+    Invalid(String),
+    /// Some predictions of the model differ from those in the conformance suite.
+    Conformance(ConformanceReport),
+}
+
+impl fmt::Display for SwapError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SwapError::Io(e) => write!(f, "Failed to load model: {}", e),
+            SwapError::Invalid(message) => write!(f, "Invalid model: {}", message),
+            SwapError::Conformance(report) => write!(
+                f,
+                "Model failed {} of {} conformance cases",
+                report.mismatches.len(),
+                report.n_cases
+            ),
+        }
+    }
+}
+
+impl std::error::Error for SwapError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            SwapError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for SwapError {
+    fn from(e: io::Error) -> Self {
+        SwapError::Io(e)
+    }
+}
+
+impl ModelHandle {
+    /// Create a handle serving the given model.
+    ///
+    /// Returns an error if the model fails [`Model::validate`], since it couldn't serve
+    /// predictions.
+    pub fn new(model: Model) -> Result<Self, String> {
+        Self::from_arc(Arc::new(model))
+    }
+
+    /// Create a handle serving a model that's already shared; see [`Self::new`].
+    pub fn from_arc(model: Arc<Model>) -> Result<Self, String> {
+        model.validate()?;
+        Ok(Self {
+            current: ArcSwap::new(model),
+            generation: Mutex::new(0),
+            conformance_suite: None,
+        })
+    }
+
+    /// Check every new model against the conformance suite at the given path before swapping
+    /// it in, rejecting models whose predictions differ.
+    ///
+    /// The suite is written by [`Model::export_conformance_suite`], and is read again on every
+    /// swap, so that it can be updated together with the model.
+    pub fn with_conformance_suite<P: AsRef<Path>>(mut self, path: P) -> Self {
+        self.conformance_suite = Some(path.as_ref().to_owned());
+        self
+    }
+
+    /// The current model.
+    ///
+    /// The returned model stays alive, and unchanged, for as long as it's held, even if another
+    /// model is swapped in meanwhile.
+    pub fn model(&self) -> Arc<Model> {
+        self.current.load_full()
+    }
+
+    /// The number of models swapped in since the handle was created.
+    pub fn generation(&self) -> u64 {
+        *self.generation.lock().unwrap()
+    }
+
+    /// Returns a ranked list of predictions of the current model, as with [`Model::predict`].
+    pub fn predict(
+        &self,
+        feature_vec: impl AsRef<[(Index, f32)]>,
+        beam_size: usize,
+    ) -> IndexValueVec {
+        self.current.load().predict(feature_vec, beam_size)
+    }
+
+    /// Returns a ranked list of predictions of the current model, as with
+    /// [`Model::predict_with_options`].
+    pub fn predict_with_options(
+        &self,
+        feature_vec: impl AsRef<[(Index, f32)]>,
+        options: &PredictOptions,
+    ) -> Result<IndexValueVec, PredictError> {
+        self.current
+            .load()
+            .predict_with_options(feature_vec, options)
+    }
+
+    /// Load a model, check it, and swap it in if it passes.
+    ///
+    /// The path can be either a directory written by [`Model::save`] or a file written by
+    /// [`Model::save_to_writer`]. Predictions keep using the current model while the new one is
+    /// loaded and checked.
+    pub fn load_and_swap<P: AsRef<Path>>(&self, path: P) -> Result<SwapReport, SwapError> {
+        let start_t = time::Instant::now();
+        let path = path.as_ref();
+        let model = if path.is_dir() {
+            Model::load(path)?
+        } else {
+            Model::load_from_reader(io::BufReader::new(File::open(path)?))?
+        };
+        let load_time = start_t.elapsed();
+        self.check_and_swap(Arc::new(model), load_time)
+    }
+
+    /// Check a model, and swap it in if it passes.
+    pub fn swap(&self, model: Arc<Model>) -> Result<SwapReport, SwapError> {
+        self.check_and_swap(model, time::Duration::ZERO)
+    }
+
+    fn check_and_swap(
+        &self,
+        model: Arc<Model>,
+        load_time: time::Duration,
+    ) -> Result<SwapReport, SwapError> {
+        model.validate().map_err(SwapError::Invalid)?;
+        let conformance = match &self.conformance_suite {
+            Some(path) => {
+                let report = model.verify_conformance(io::BufReader::new(File::open(path)?))?;
+                if !report.is_success() {
+                    return Err(SwapError::Conformance(report));
+                }
+                Some(report)
+            }
+            None => None,
+        };
+
+        let n_trees = model.n_trees();
+        let mut generation = self.generation.lock().unwrap();
+        self.current.store(model);
+        *generation += 1;
+        Ok(SwapReport {
+            generation: *generation,
+            n_trees,
+            load_time,
+            conformance,
+        })
+    }
+}
+
+impl fmt::Debug for ModelHandle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ModelHandle")
+            .field("generation", &self.generation())
+            .field("conformance_suite", &self.conformance_suite)
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{toy_dataset, toy_model};
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::thread;
+
+    #[test]
+    fn test_swap_under_load() {
+        let models = [toy_model(2, 0), toy_model(3, 1)];
+        let inputs = toy_dataset(20, 8, 2).feature_lists;
+        let expected = models
+            .iter()
+            .map(|model| {
+                inputs
+                    .iter()
+                    .map(|feature_vec| model.predict(feature_vec, 5))
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+        assert_ne!(expected[0], expected[1]);
+
+        let dir = std::env::temp_dir().join(format!("omikuji-handle-{}", std::process::id()));
+        let paths = [dir.join("a"), dir.join("b.bin")];
+        models[0].save(&paths[0]).unwrap();
+        models[1]
+            .save_to_writer(File::create(&paths[1]).unwrap())
+            .unwrap();
+
+        let handle = ModelHandle::new(models[0].clone()).unwrap();
+        let done = AtomicBool::new(false);
+        thread::scope(|scope| {
+            for _ in 0..4 {
+                scope.spawn(|| {
+                    let mut n_checked = 0;
+                    while !done.load(Ordering::Relaxed) || n_checked == 0 {
+                        for (i, feature_vec) in inputs.iter().enumerate() {
+                            let predictions = handle.predict(feature_vec, 5);
+                            assert!(
+                                predictions == expected[0][i] || predictions == expected[1][i],
+                                "Prediction {} matches neither model",
+                                i
+                            );
+                            n_checked += 1;
+                        }
+                    }
+                });
+            }
+
+            for i in 0..20 {
+                let report = handle.load_and_swap(&paths[(i + 1) % 2]).unwrap();
+                assert_eq!(i as u64 + 1, report.generation);
+                assert_eq!(models[(i + 1) % 2].n_trees(), report.n_trees);
+                assert!(report.conformance.is_none());
+            }
+            done.store(true, Ordering::Relaxed);
+        });
+
+        // Swapped an even number of times, so the first model is back
+        assert_eq!(20, handle.generation());
+        for (feature_vec, expected) in inputs.iter().zip(&expected[0]) {
+            assert_eq!(expected, &handle.predict(feature_vec, 5));
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_rejected_swaps() {
+        let model = toy_model(2, 0);
+        let inputs = toy_dataset(10, 8, 1).feature_lists;
+        let dir =
+            std::env::temp_dir().join(format!("omikuji-handle-rejected-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let suite_path = dir.join("suite.json");
+        model
+            .export_conformance_suite(&inputs, &[1, 5], File::create(&suite_path).unwrap())
+            .unwrap();
+
+        let handle = ModelHandle::new(model.clone())
+            .unwrap()
+            .with_conformance_suite(&suite_path);
+        let pinned = handle.model();
+
+        match handle.load_and_swap(dir.join("missing")) {
+            Err(SwapError::Io(_)) => {}
+            result => panic!("Unexpected result {:?}", result),
+        }
+
+        let mut empty = model.clone();
+        empty.trees_mut().clear();
+        let empty = Arc::new(empty);
+        assert!(ModelHandle::from_arc(empty.clone()).is_err());
+        match handle.swap(empty) {
+            Err(SwapError::Invalid(_)) => {}
+            result => panic!("Unexpected result {:?}", result),
+        }
+
+        match handle.swap(Arc::new(toy_model(2, 1))) {
+            Err(SwapError::Conformance(report)) => {
+                assert_eq!(2 * inputs.len(), report.n_cases);
+                assert!(!report.is_success());
+            }
+            result => panic!("Unexpected result {:?}", result),
+        }
+        assert_eq!(0, handle.generation());
+        assert!(Arc::ptr_eq(&pinned, &handle.model()));
+
+        let report = handle.swap(Arc::new(model.clone())).unwrap();
+        assert_eq!(1, report.generation);
+        assert_eq!(
+            Some(2 * inputs.len()),
+            report.conformance.map(|r| r.n_cases)
+        );
+        assert!(!Arc::ptr_eq(&pinned, &handle.model()));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
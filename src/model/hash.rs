@@ -0,0 +1,317 @@
+//! Hashing the logical content of models, independent of how they're stored.
+//!
+//! The hash is SHA-256 over a canonical encoding of everything that affects predictions: the
+//! settings, the inference limits, the feature projection, the label thresholds, the label graph,
+//! the original labels of compacted models, the used features of models compacted with
+//! [`Model::compact_features`], and the trees.
+//! Training metadata only records how the model came about, so it's left out, and so are label
+//! names, which only change how labels are presented. The encoding is:
+//!
+//! * integers as 64-bit little-endian, and enum variants and `Option`s as a one-byte tag;
+//! * floats as the little-endian bytes of their bit patterns, with every NaN replaced by the
+//!   quiet NaN `0x7fc00000` and `-0.0` replaced by `0.0`;
+//! * weight matrices as their shape, the number of non-zero weights, and then each non-zero
+//!   weight as (row, column, value) in row-major order, so that dense and sparse storage of the
+//!   same weights hash the same;
+//! * nodes in pre-order, with the number of children of each branch and the labels of each leaf;
+//! * trees as the sorted SHA-256 digests of their encodings, since predictions don't depend on the
+//!   order of trees, and loading a model from a directory doesn't preserve it.
+use super::{liblinear, limits, schema, Model, TreeNode};
+use crate::mat_util::WeightMat;
+use sha2::{Digest, Sha256};
+use std::io;
+use std::path::Path;
+
+/// Bumped whenever the canonical encoding changes, so that hashes of different encodings differ.
+const ENCODING_TAG: &[u8] = b"omikuji-model-content-v5";
+
+/// The bit pattern all NaNs are hashed as.
+const CANONICAL_NAN_BITS: u32 = 0x7fc0_0000;
+
+/// Feeds values to a hasher in their canonical encoding.
+struct CanonicalHasher(Sha256);
+
+impl CanonicalHasher {
+    fn new() -> Self {
+        Self(Sha256::new())
+    }
+
+    fn tag(&mut self, tag: u8) {
+        self.0.update(&[tag]);
+    }
+
+    fn usize(&mut self, value: usize) {
+        self.0.update(&(value as u64).to_le_bytes());
+    }
+
+    fn f32(&mut self, value: f32) {
+        let bits = if value.is_nan() {
+            CANONICAL_NAN_BITS
+        } else if value == 0. {
+            0
+        } else {
+            value.to_bits()
+        };
+        self.0.update(&bits.to_le_bytes());
+    }
+
+    fn option_usize(&mut self, value: Option<usize>) {
+        match value {
+            None => self.tag(0),
+            Some(value) => {
+                self.tag(1);
+                self.usize(value);
+            }
+        }
+    }
+
+    fn weights(&mut self, weights: &WeightMat) {
+        let (n_rows, n_cols) = weights.shape();
+        self.usize(n_rows);
+        self.usize(n_cols);
+        self.usize(weights.nonzero_entries().count());
+        for (row, col, value) in weights.nonzero_entries() {
+            self.usize(row);
+            self.usize(col);
+            self.f32(value);
+        }
+    }
+
+    fn node(&mut self, node: &TreeNode) {
+        match node {
+            TreeNode::Branch { weights, children } => {
+                self.tag(0);
+                self.weights(weights);
+                self.usize(children.len());
+                for child in children {
+                    self.node(child);
+                }
+            }
+            TreeNode::Leaf { weights, labels } => {
+                self.tag(1);
+                self.weights(weights);
+                self.usize(labels.len());
+                for &label in labels {
+                    self.usize(label as usize);
+                }
+            }
+        }
+    }
+
+    fn finish(self) -> [u8; 32] {
+        self.0.finalize().into()
+    }
+}
+
+impl Model {
+    /// A SHA-256 hash of the logical content of the model.
+    ///
+    /// The hash stays the same regardless of how the model was stored or loaded, whether its
+    /// weights are dense or sparse, and in which order its trees are, while changing anything
+    /// that could affect predictions changes it. See
+    /// [`hash`](crate::model::hash) for the exact encoding.
+    pub fn content_hash(&self) -> [u8; 32] {
+        let mut hasher = CanonicalHasher::new();
+        hasher.0.update(ENCODING_TAG);
+
+        hasher.usize(self.settings.n_features);
+        hasher.tag(match self.settings.classifier_loss_type {
+            liblinear::LossType::Log => 0,
+            liblinear::LossType::Hinge => 1,
+        });
+        hasher.tag(match self.settings.feature_transform {
+            schema::FeatureTransform::L2Normalize => 0,
+            schema::FeatureTransform::Identity => 1,
+        });
+
+        let limits = &self.inference_limits;
+        hasher.option_usize(limits.max_beam);
+        hasher.option_usize(limits.max_labels_returned);
+        hasher.option_usize(limits.max_nodes_visited);
+        hasher.option_usize(limits.max_leaf_labels_scored);
+        hasher.tag(match limits.policy {
+            limits::LimitPolicy::Truncate => 0,
+            limits::LimitPolicy::Error => 1,
+        });
+
+        match &self.feature_projection {
+            None => hasher.tag(0),
+            Some(projection) => {
+                hasher.tag(1);
+                hasher.usize(projection.n_components);
+                hasher.usize(projection.nnz_per_feature);
+                hasher.0.update(&projection.seed.to_le_bytes());
+            }
+        }
+
+        match &self.label_thresholds {
+            None => hasher.tag(0),
+            Some(thresholds) => {
+                hasher.tag(1);
+                hasher.usize(thresholds.as_slice().len());
+                for &threshold in thresholds.as_slice() {
+                    hasher.f32(threshold);
+                }
+            }
+        }
+
+        match &self.label_graph {
+            None => hasher.tag(0),
+            Some(graph) => {
+                hasher.tag(1);
+                hasher.f32(graph.alpha());
+                hasher.usize(graph.rows().len());
+                for (label, neighbors) in graph.rows() {
+                    hasher.usize(*label as usize);
+                    hasher.usize(neighbors.len());
+                    for &(neighbor, weight) in neighbors {
+                        hasher.usize(neighbor as usize);
+                        hasher.f32(weight);
+                    }
+                }
+            }
+        }
+
+        match &self.original_labels {
+            None => hasher.tag(0),
+            Some(original_labels) => {
+                hasher.tag(1);
+                hasher.usize(original_labels.len());
+                for &label in original_labels {
+                    hasher.usize(label as usize);
+                }
+            }
+        }
+
+        match &self.used_features {
+            None => hasher.tag(0),
+            Some(used_features) => {
+                hasher.tag(1);
+                hasher.usize(used_features.len());
+                for &feature in used_features {
+                    hasher.usize(feature as usize);
+                }
+            }
+        }
+
+        let mut tree_digests = self
+            .trees
+            .iter()
+            .map(|tree| {
+                let mut tree_hasher = CanonicalHasher::new();
+                tree_hasher.node(tree);
+                tree_hasher.finish()
+            })
+            .collect::<Vec<_>>();
+        tree_digests.sort_unstable();
+        hasher.usize(tree_digests.len());
+        for digest in &tree_digests {
+            hasher.0.update(digest);
+        }
+
+        hasher.finish()
+    }
+
+    /// The [content hash](Self::content_hash) of a saved model, which can be either a directory
+    /// written by [`Model::save`] or a file written by [`Model::save_to_writer`].
+    pub fn hash_from_file<P: AsRef<Path>>(path: P) -> io::Result<[u8; 32]> {
+        let path = path.as_ref();
+        let model = if path.is_dir() {
+            Self::load(path)?
+        } else {
+            Self::load_from_reader(io::BufReader::new(std::fs::File::open(path)?))?
+        };
+        Ok(model.content_hash())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::toy_model;
+
+    fn first_leaf_weights(node: &mut TreeNode) -> &mut WeightMat {
+        match node {
+            TreeNode::Branch { children, .. } => first_leaf_weights(&mut children[0]),
+            TreeNode::Leaf { weights, .. } => weights,
+        }
+    }
+
+    #[test]
+    fn test_content_hash_round_trip() {
+        let model = toy_model(3, 0);
+        let hash = model.content_hash();
+        assert_eq!(hash, model.clone().content_hash());
+
+        let dir = std::env::temp_dir().join(format!("omikuji-hash-{}", std::process::id()));
+        model.save(&dir).unwrap();
+        assert_eq!(hash, Model::load(&dir).unwrap().content_hash());
+        assert_eq!(hash, Model::hash_from_file(&dir).unwrap());
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let path = std::env::temp_dir().join(format!("omikuji-hash-{}.bin", std::process::id()));
+        model
+            .save_to_writer(std::fs::File::create(&path).unwrap())
+            .unwrap();
+        assert_eq!(hash, Model::hash_from_file(&path).unwrap());
+        std::fs::remove_file(&path).unwrap();
+
+        // Neither storage format nor tree order is part of the content
+        let mut densified = model.clone();
+        densified.densify_weights(0.);
+        assert_eq!(hash, densified.content_hash());
+        let mut reordered = model.clone();
+        reordered.trees.reverse();
+        assert_eq!(hash, reordered.content_hash());
+
+        // Nor is training metadata
+        let mut with_metadata = model.clone();
+        with_metadata.training_metadata.cluster_excluded_labels = vec![1, 2];
+        assert_eq!(hash, with_metadata.content_hash());
+    }
+
+    #[test]
+    fn test_content_hash_changes() {
+        let model = toy_model(2, 0);
+        let hash = model.content_hash();
+        assert_ne!(hash, toy_model(2, 1).content_hash());
+        assert_ne!(hash, model.take_trees(&[0]).content_hash());
+
+        let mut pruned = model.clone();
+        let threshold = match &pruned.trees[0] {
+            TreeNode::Branch { weights, .. } | TreeNode::Leaf { weights, .. } => {
+                weights.nonzero_abs_range().unwrap().1
+            }
+        };
+        assert!(pruned.prune_weights(threshold).n_weights_removed > 0);
+        assert_ne!(hash, pruned.content_hash());
+
+        let mut changed = model.clone();
+        let mut dense = first_leaf_weights(&mut changed.trees_mut()[1]).to_dense();
+        dense[[0, 0]] += 1.;
+        *first_leaf_weights(&mut changed.trees_mut()[1]) = WeightMat::Dense(dense);
+        assert_ne!(hash, changed.content_hash());
+
+        let mut limited = model.clone();
+        limited.inference_limits.max_beam = Some(3);
+        assert_ne!(hash, limited.content_hash());
+
+        let mut unnormalized = model.clone();
+        unnormalized.settings.feature_transform = schema::FeatureTransform::Identity;
+        assert_ne!(hash, unnormalized.content_hash());
+    }
+
+    #[test]
+    fn test_canonical_floats() {
+        let hash_f32 = |value: f32| {
+            let mut hasher = CanonicalHasher::new();
+            hasher.f32(value);
+            hasher.finish()
+        };
+        assert_eq!(hash_f32(0.), hash_f32(-0.));
+        assert_eq!(hash_f32(f32::NAN), hash_f32(-f32::NAN));
+        assert_eq!(hash_f32(f32::NAN), hash_f32(f32::from_bits(0x7f80_0001)));
+        assert_ne!(hash_f32(f32::NAN), hash_f32(f32::INFINITY));
+        assert_ne!(hash_f32(1.), hash_f32(1. + f32::EPSILON));
+    }
+}
@@ -0,0 +1,226 @@
+//! Writing files so that readers never see them partially written.
+//!
+//! Every file is first written to a temporary file next to its final path, named after it with a
+//! leading `.` and a `.tmp` suffix, and only renamed to its final path once fully written and
+//! synced to disk, so that a crash right after the rename can't leave an empty or partial file.
+//! If writing fails, e.g., when the disk is full, the temporary file is removed, and any earlier
+//! file at the final path is left as it was. The temporary names never match the names that
+//! models are loaded from.
+//!
+//! Files are created through an [`IoSink`], so that tests can inject failures into writes.
+use log::warn;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+/// A file being written through an [`IoSink`].
+pub(crate) trait SinkWriter: Write {
+    /// Flush the file and sync its contents to disk.
+    fn sync_all(&mut self) -> io::Result<()>;
+}
+
+impl SinkWriter for io::BufWriter<fs::File> {
+    fn sync_all(&mut self) -> io::Result<()> {
+        self.flush()?;
+        self.get_ref().sync_all()
+    }
+}
+
+/// Creates files for writing.
+pub(crate) trait IoSink {
+    /// Create the file at the given path, truncating it if it exists.
+    fn create(&self, path: &Path) -> io::Result<Box<dyn SinkWriter + '_>>;
+}
+
+/// Creates buffered files on the file system.
+pub(crate) struct FileSink;
+
+impl IoSink for FileSink {
+    fn create(&self, path: &Path) -> io::Result<Box<dyn SinkWriter + '_>> {
+        Ok(Box::new(io::BufWriter::new(fs::File::create(path)?)))
+    }
+}
+
+/// The temporary path that the file at the given path is written to first.
+fn temp_path(path: &Path) -> PathBuf {
+    let file_name = path
+        .file_name()
+        .expect("Path should have a file name")
+        .to_string_lossy();
+    path.with_file_name(format!(".{}.tmp", file_name))
+}
+
+/// Write the file at the given path with the given function, through a temporary file that is
+/// renamed to the path once fully written.
+pub(crate) fn write_atomically<S, F>(sink: &S, path: &Path, write: F) -> io::Result<()>
+where
+    S: IoSink + ?Sized,
+    F: FnOnce(&mut dyn Write) -> io::Result<()>,
+{
+    let temp_path = temp_path(path);
+    let result = sink.create(&temp_path).and_then(|mut writer| {
+        write(&mut writer)?;
+        writer.sync_all()
+    });
+    match result {
+        Ok(()) => fs::rename(&temp_path, path),
+        Err(e) => {
+            if temp_path.exists() {
+                if let Err(remove_error) = fs::remove_file(&temp_path) {
+                    warn!(
+                        "Failed to remove temporary file {}: {}",
+                        temp_path.display(),
+                        remove_error
+                    );
+                }
+            }
+            Err(e)
+        }
+    }
+}
+
+/// Creates files whose writes fail once a given number of bytes have been written to all of them
+/// together, as when the disk runs out of space.
+#[cfg(test)]
+pub(crate) struct FailingSink {
+    n_bytes_left: std::cell::Cell<usize>,
+}
+
+#[cfg(test)]
+impl FailingSink {
+    pub fn new(n_bytes: usize) -> Self {
+        Self {
+            n_bytes_left: std::cell::Cell::new(n_bytes),
+        }
+    }
+}
+
+#[cfg(test)]
+impl IoSink for FailingSink {
+    fn create(&self, path: &Path) -> io::Result<Box<dyn SinkWriter + '_>> {
+        struct FailingWriter<'a> {
+            file: fs::File,
+            n_bytes_left: &'a std::cell::Cell<usize>,
+        }
+
+        impl Write for FailingWriter<'_> {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                let n_bytes = buf.len().min(self.n_bytes_left.get());
+                if n_bytes == 0 && !buf.is_empty() {
+                    return Err(io::Error::new(
+                        io::ErrorKind::Other,
+                        "No space left on device (injected)",
+                    ));
+                }
+                let n_written = self.file.write(&buf[..n_bytes])?;
+                self.n_bytes_left.set(self.n_bytes_left.get() - n_written);
+                Ok(n_written)
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                self.file.flush()
+            }
+        }
+
+        impl SinkWriter for FailingWriter<'_> {
+            fn sync_all(&mut self) -> io::Result<()> {
+                self.file.sync_all()
+            }
+        }
+
+        Ok(Box::new(FailingWriter {
+            file: fs::File::create(path)?,
+            n_bytes_left: &self.n_bytes_left,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::{Model, SaveOptions};
+    use crate::test_util::toy_model;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("omikuji-io-sink-{}-{}", name, std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn file_names(dir: &Path) -> Vec<String> {
+        let mut names = fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .collect::<Vec<_>>();
+        names.sort_unstable();
+        names
+    }
+
+    #[test]
+    fn test_write_atomically() {
+        let dir = temp_dir("file");
+        let path = dir.join("file.txt");
+        write_atomically(&FileSink, &path, |writer| writer.write_all(b"first")).unwrap();
+        assert_eq!(b"first", &fs::read(&path).unwrap()[..]);
+
+        // An interrupted overwrite leaves the earlier file as it was
+        let result = write_atomically(&FailingSink::new(3), &path, |writer| {
+            writer.write_all(b"second")
+        });
+        assert!(result.is_err());
+        assert_eq!(b"first", &fs::read(&path).unwrap()[..]);
+        assert_eq!(vec!["file.txt"], file_names(&dir));
+
+        // Errors of the writing function are reported the same way
+        let result = write_atomically(&FileSink, &dir.join("other.txt"), |writer| {
+            writer.write_all(b"partial")?;
+            Err(io::Error::new(io::ErrorKind::Other, "failed"))
+        });
+        assert!(result.is_err());
+        assert_eq!(vec!["file.txt"], file_names(&dir));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_interrupted_save() {
+        let model = toy_model(3, 0);
+        let dir = temp_dir("model");
+        model.save(&dir).unwrap();
+        let n_bytes = file_names(&dir)
+            .iter()
+            .map(|name| fs::metadata(dir.join(name)).unwrap().len() as usize)
+            .sum::<usize>();
+        fs::remove_dir_all(&dir).unwrap();
+
+        for &fail_after in &[0, 10, n_bytes / 3, n_bytes / 2, n_bytes - 1] {
+            let dir = temp_dir(&format!("model-{}", fail_after));
+            let result =
+                model.save_with_sink(&dir, &SaveOptions::default(), &FailingSink::new(fail_after));
+            assert!(result.is_err());
+
+            // Whatever was saved before the failure is complete, but the directory stays marked,
+            // so the partial model is never loaded
+            let names = file_names(&dir);
+            assert!(
+                names.iter().all(|name| !name.ends_with(".tmp")),
+                "{:?}",
+                names
+            );
+            assert!(names.iter().any(|name| name == "save_in_progress"));
+            assert!(!names.iter().any(|name| name == "format.json"));
+            assert_eq!(
+                io::ErrorKind::InvalidData,
+                Model::load(&dir).unwrap_err().kind()
+            );
+            assert_eq!(
+                io::ErrorKind::InvalidData,
+                Model::verify_dir(&dir).unwrap_err().kind()
+            );
+            // Nor are trees added to it
+            assert!(model.save(&dir).is_err());
+            fs::remove_dir_all(&dir).unwrap();
+        }
+    }
+}
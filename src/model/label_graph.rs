@@ -0,0 +1,278 @@
+//! Smoothing predicted scores over a label co-occurrence graph.
+//!
+//! Labels that often occur together should reinforce each other. After scores are aggregated over
+//! trees, one round of propagation mixes the score of each predicted label with the scores of its
+//! predicted neighbors: `(1 - alpha) * score + alpha * sum(weight * neighbor_score)`. Only the
+//! graph rows of predicted labels are read, so smoothing costs little next to beam search.
+use super::Model;
+use crate::mat_util::*;
+use crate::{Index, IndexValueVec};
+use hashbrown::HashMap;
+use itertools::Itertools;
+use ordered_float::NotNan;
+use serde::{Deserialize, Serialize};
+use std::cmp::Reverse;
+
+/// The largest number of neighbors kept per label when a graph is attached to a model, so that
+/// the graph stays small next to the trees.
+pub const MAX_NEIGHBORS_PER_LABEL: usize = 100;
+
+/// A label co-occurrence graph with the weight of propagated scores.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct LabelGraph {
+    alpha: f32,
+    /// Labels with neighbors, sorted, each with its neighbors and edge weights sorted by
+    /// neighbor.
+    rows: Vec<(Index, Vec<(Index, f32)>)>,
+}
+
+impl LabelGraph {
+    /// Create a graph from a labels-by-labels adjacency matrix, usually row-normalized, keeping
+    /// the `max_neighbors` heaviest edges of each label.
+    pub fn new(adjacency: &SparseMat, alpha: f32, max_neighbors: usize) -> Result<Self, String> {
+        if !(0. ..=1.).contains(&alpha) {
+            return Err(format!("alpha must be in [0, 1], but is {}", alpha));
+        }
+        if adjacency.rows() != adjacency.cols() {
+            return Err(format!(
+                "adjacency matrix must be square, but has shape {:?}",
+                adjacency.shape()
+            ));
+        }
+        if let Some(&weight) = adjacency
+            .data()
+            .iter()
+            .find(|w| !(w.is_finite() && **w >= 0.))
+        {
+            return Err(format!(
+                "edge weights must be non-negative and finite, but include {}",
+                weight
+            ));
+        }
+
+        let rows = adjacency
+            .outer_iterator()
+            .enumerate()
+            .filter_map(|(label, row)| {
+                let neighbors = row
+                    .iter()
+                    .filter(|(_, &weight)| weight > 0.)
+                    .map(|(neighbor, &weight)| (neighbor as Index, weight))
+                    // Ties are broken by neighbor, so that pruning is deterministic
+                    .sorted_unstable_by_key(|&(neighbor, weight)| {
+                        (Reverse(NotNan::new(weight).unwrap()), neighbor)
+                    })
+                    .take(max_neighbors)
+                    .sorted_unstable_by_key(|&(neighbor, _)| neighbor)
+                    .collect_vec();
+                if neighbors.is_empty() {
+                    None
+                } else {
+                    Some((label as Index, neighbors))
+                }
+            })
+            .collect();
+        Ok(Self { alpha, rows })
+    }
+
+    /// The weight of propagated scores.
+    pub fn alpha(&self) -> f32 {
+        self.alpha
+    }
+
+    /// The number of edges in the graph.
+    pub fn n_edges(&self) -> usize {
+        self.rows.iter().map(|(_, neighbors)| neighbors.len()).sum()
+    }
+
+    /// Labels with neighbors, sorted, each with its neighbors and edge weights.
+    pub(crate) fn rows(&self) -> &[(Index, Vec<(Index, f32)>)] {
+        &self.rows
+    }
+
+    /// The graph with labels renumbered as given, dropping labels that aren't renumbered; the
+    /// renumbering must preserve the order of labels.
+    pub(crate) fn renumber(&self, new_labels: &HashMap<Index, Index>) -> Self {
+        let rows = self
+            .rows
+            .iter()
+            .filter_map(|(label, neighbors)| {
+                let neighbors = neighbors
+                    .iter()
+                    .filter_map(|&(neighbor, weight)| {
+                        new_labels
+                            .get(&neighbor)
+                            .map(|&neighbor| (neighbor, weight))
+                    })
+                    .collect_vec();
+                match new_labels.get(label) {
+                    Some(&label) if !neighbors.is_empty() => Some((label, neighbors)),
+                    _ => None,
+                }
+            })
+            .collect();
+        Self {
+            alpha: self.alpha,
+            rows,
+        }
+    }
+
+    fn neighbors(&self, label: Index) -> &[(Index, f32)] {
+        match self.rows.binary_search_by_key(&label, |&(l, _)| l) {
+            Ok(i) => &self.rows[i].1,
+            Err(_) => &[],
+        }
+    }
+
+    /// Smooth the scores of the given predictions, and rank them again, returning the `top_k`
+    /// best if given.
+    pub(crate) fn smooth(&self, predictions: IndexValueVec, top_k: Option<usize>) -> IndexValueVec {
+        let label_to_score = predictions.iter().cloned().collect::<HashMap<_, _>>();
+        let smoothed = predictions
+            .iter()
+            .map(|&(label, score)| {
+                let propagated = self
+                    .neighbors(label)
+                    .iter()
+                    .filter_map(|(neighbor, weight)| {
+                        label_to_score.get(neighbor).map(|score| weight * score)
+                    })
+                    .sum::<f32>();
+                (label, (1. - self.alpha) * score + self.alpha * propagated)
+            })
+            .collect_vec();
+        let k = top_k.unwrap_or(smoothed.len());
+        super::rank_top_k(smoothed.into_iter(), k)
+    }
+}
+
+impl Model {
+    /// Attach a label co-occurrence graph, given as a labels-by-labels row-normalized adjacency
+    /// matrix, for smoothing scores with [`PredictOptions::smooth_scores`]; `alpha` is the weight
+    /// of propagated scores.
+    ///
+    /// Only the [`MAX_NEIGHBORS_PER_LABEL`] heaviest edges of each label are kept. The graph is
+    /// saved with the model.
+    ///
+    /// [`PredictOptions::smooth_scores`]: super::PredictOptions::smooth_scores
+    pub fn attach_label_graph(&mut self, graph: SparseMat, alpha: f32) {
+        let graph = LabelGraph::new(&graph, alpha, MAX_NEIGHBORS_PER_LABEL)
+            .unwrap_or_else(|e| panic!("Invalid label graph: {}", e));
+        self.label_graph = Some(graph);
+    }
+
+    /// The attached label graph, if any.
+    pub fn label_graph(&self) -> Option<&LabelGraph> {
+        self.label_graph.as_ref()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::PredictOptions;
+    use crate::test_util::{toy_dataset, toy_model};
+    use assert_approx_eq::assert_approx_eq;
+
+    /// Labels 0 and 1 co-occur often, and label 2 co-occurs with label 1 only.
+    fn graph() -> SparseMat {
+        csrmat_from_index_value_pair_lists(
+            vec![vec![(1, 1.)], vec![(0, 0.75), (2, 0.25)], vec![(1, 1.)]],
+            3,
+        )
+    }
+
+    #[test]
+    fn test_smooth() {
+        let predictions = vec![(0, 0.8), (2, 0.5), (1, 0.4)];
+        let graph = LabelGraph::new(&graph(), 0.5, MAX_NEIGHBORS_PER_LABEL).unwrap();
+        assert_eq!(4, graph.n_edges());
+        let smoothed = graph.smooth(predictions.clone(), None);
+        let expected = [
+            (0, 0.5 * 0.8 + 0.5 * 0.4),
+            (1, 0.5 * 0.4 + 0.5 * (0.75 * 0.8 + 0.25 * 0.5)),
+            (2, 0.5 * 0.5 + 0.5 * 0.4),
+        ];
+        // Label 1 rises above label 2 through its neighbors
+        assert_eq!(
+            vec![0, 1, 2],
+            smoothed.iter().map(|&(l, _)| l).collect_vec()
+        );
+        for &(label, score) in &smoothed {
+            assert_approx_eq!(expected[label as usize].1, score);
+        }
+        assert_eq!(2, graph.smooth(predictions.clone(), Some(2)).len());
+
+        // Neighbors that weren't predicted propagate nothing
+        let smoothed = graph.smooth(vec![(0, 0.8)], None);
+        assert_approx_eq!(0.4, smoothed[0].1);
+
+        let no_op = LabelGraph::new(&graph(), 0., MAX_NEIGHBORS_PER_LABEL).unwrap();
+        assert_eq!(predictions, no_op.smooth(predictions.clone(), None));
+    }
+
+    #[test]
+    fn test_prune_and_validate() {
+        let pruned = LabelGraph::new(&graph(), 0.5, 1).unwrap();
+        assert_eq!(
+            &[(0, vec![(1, 1.)]), (1, vec![(0, 0.75)]), (2, vec![(1, 1.)])],
+            pruned.rows()
+        );
+        assert!(LabelGraph::new(&graph(), 1.5, 1).is_err());
+        assert!(LabelGraph::new(&graph(), f32::NAN, 1).is_err());
+        let negative = csrmat_from_index_value_pair_lists(vec![vec![(0, -1.)]], 1);
+        assert!(LabelGraph::new(&negative, 0.5, 1).is_err());
+        let non_square = csrmat_from_index_value_pair_lists(vec![vec![(0, 1.)]], 2);
+        assert!(LabelGraph::new(&non_square, 0.5, 1).is_err());
+    }
+
+    #[test]
+    fn test_predict_smoothed() {
+        let mut model = toy_model(2, 0);
+        let options = PredictOptions {
+            smooth_scores: true,
+            ..PredictOptions::default()
+        };
+        assert!(options.validate_for(&model).is_err());
+
+        // Each label is linked to the next, and the last label to the first
+        let labels = model.labels();
+        let n_labels = *labels.last().unwrap() as usize + 1;
+        let adjacency = csrmat_from_index_value_pair_lists(
+            (0..n_labels)
+                .map(|label| vec![((label + 1) % n_labels, 1.)])
+                .collect(),
+            n_labels,
+        );
+        model.attach_label_graph(adjacency.clone(), 0.);
+        for feature_vec in &toy_dataset(10, 8, 1).feature_lists {
+            assert_eq!(
+                model.predict(feature_vec, 10),
+                model.predict_with_options(feature_vec, &options).unwrap()
+            );
+        }
+
+        model.attach_label_graph(adjacency, 0.3);
+        let graph = model.label_graph().unwrap().clone();
+        let top_k = PredictOptions {
+            top_k: Some(2),
+            ..options.clone()
+        };
+        for feature_vec in &toy_dataset(10, 8, 1).feature_lists {
+            let expected = graph.smooth(model.predict(feature_vec, 10), None);
+            let smoothed = model.predict_with_options(feature_vec, &options).unwrap();
+            assert_eq!(expected, smoothed);
+            assert_eq!(
+                &smoothed[..2.min(smoothed.len())],
+                &model.predict_with_options(feature_vec, &top_k).unwrap()[..]
+            );
+        }
+
+        // The graph is saved with the model
+        let mut buffer = Vec::new();
+        model.save_to_writer(&mut buffer).unwrap();
+        let loaded = Model::load_from_reader(&buffer[..]).unwrap();
+        assert_eq!(Some(&graph), loaded.label_graph());
+        assert_eq!(model.content_hash(), loaded.content_hash());
+    }
+}
@@ -0,0 +1,212 @@
+//! Names of labels kept in the model, so that predictions can be given by name without a separate
+//! mapping to keep in sync.
+//!
+//! Names are indexed by the labels the model was trained with, i.e., the original labels if the
+//! model was compacted with [`Model::compact_labels`].
+use super::{Model, PredictError};
+use crate::Index;
+use std::io;
+
+impl Model {
+    /// Set the name of each label, indexed by label, replacing any names set before.
+    ///
+    /// Every label the model can predict must have a name; names of labels beyond those are
+    /// allowed, e.g., of labels pruned from the model. Names are saved with the model. They can
+    /// also be given at training time with [`TrainOptions::label_names`].
+    ///
+    /// [`TrainOptions::label_names`]: super::TrainOptions::label_names
+    pub fn set_label_names(&mut self, label_names: Vec<String>) -> Result<(), String> {
+        self.check_n_label_names(label_names.len())?;
+        self.label_names = Some(label_names);
+        Ok(())
+    }
+
+    /// Check that the given number of names covers every label the model can predict.
+    fn check_n_label_names(&self, n_names: usize) -> Result<(), String> {
+        let max_label = match &self.original_labels {
+            Some(original_labels) => original_labels.iter().max().copied(),
+            None => self.sorted_labels().last().copied(),
+        };
+        match max_label {
+            Some(max_label) if max_label as usize >= n_names => Err(format!(
+                "Got {} label names, but the model has labels up to {}",
+                n_names, max_label
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    /// Check that the label names of a loaded model, if any, cover all of its labels.
+    pub(super) fn check_loaded_label_names(&self) -> io::Result<()> {
+        match &self.label_names {
+            Some(label_names) => self
+                .check_n_label_names(label_names.len())
+                .map_err(|message| io::Error::new(io::ErrorKind::InvalidData, message)),
+            None => Ok(()),
+        }
+    }
+
+    /// Remove the label names, if any.
+    pub fn clear_label_names(&mut self) {
+        self.label_names = None;
+    }
+
+    /// The name of each label, if set with [`Self::set_label_names`].
+    pub fn label_names(&self) -> Option<&[String]> {
+        self.label_names.as_deref()
+    }
+
+    /// The name of a label as predicted by the model, if label names are set.
+    pub fn label_name(&self, label: Index) -> Option<&str> {
+        let label_names = self.label_names.as_ref()?;
+        let label = match &self.original_labels {
+            Some(original_labels) => *original_labels.get(label as usize)?,
+            None => label,
+        };
+        label_names.get(label as usize).map(String::as_str)
+    }
+
+    /// Like [`Self::predict`], but returns labels by name.
+    ///
+    /// Labels are given as their original indices formatted as strings if the model has no label
+    /// names. Names are checked when they are set and when the model is loaded, so a label
+    /// without a name is only reported if the model was changed since.
+    pub fn predict_named(
+        &self,
+        feature_vec: impl AsRef<[(Index, f32)]>,
+        beam_size: usize,
+    ) -> Result<Vec<(String, f32)>, PredictError> {
+        let mut predictions = self.predict(feature_vec, beam_size);
+        self.translate_labels(&mut predictions);
+        predictions
+            .into_iter()
+            .map(|(label, score)| {
+                let name = match &self.label_names {
+                    Some(label_names) => label_names
+                        .get(label as usize)
+                        .ok_or(PredictError::MissingLabelName { label })?
+                        .clone(),
+                    None => label.to_string(),
+                };
+                Ok((name, score))
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::train::TrainError;
+    use crate::model::{TrainHyperParam, TrainOptions};
+    use crate::test_util::{toy_dataset, toy_model};
+    use crate::Warnings;
+
+    fn names(n: usize) -> Vec<String> {
+        (0..n).map(|i| format!("label-{}", i)).collect()
+    }
+
+    #[test]
+    fn test_label_names() {
+        let mut model = toy_model(2, 0);
+        let n_labels = *model.labels().last().unwrap() as usize + 1;
+        let feature_vec = &toy_dataset(1, 8, 1).feature_lists[0];
+        let predictions = model.predict(feature_vec, 10);
+
+        // Without names, labels are given by index
+        assert_eq!(None, model.label_names());
+        assert_eq!(None, model.label_name(0));
+        let expected = predictions
+            .iter()
+            .map(|&(label, score)| (label.to_string(), score))
+            .collect::<Vec<_>>();
+        assert_eq!(expected, model.predict_named(feature_vec, 10).unwrap());
+
+        assert!(model.set_label_names(names(n_labels - 1)).is_err());
+        assert_eq!(None, model.label_names());
+        model.set_label_names(names(n_labels)).unwrap();
+        assert_eq!(Some("label-1"), model.label_name(1));
+        let expected = predictions
+            .iter()
+            .map(|&(label, score)| (format!("label-{}", label), score))
+            .collect::<Vec<_>>();
+        assert_eq!(expected, model.predict_named(feature_vec, 10).unwrap());
+
+        // Names are kept when saving and loading
+        let mut buffer = Vec::new();
+        model.save_to_writer(&mut buffer).unwrap();
+        let loaded = Model::load_from_reader(&buffer[..]).unwrap();
+        assert_eq!(model.label_names(), loaded.label_names());
+        let dir = std::env::temp_dir().join(format!("omikuji-label-names-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        model.save(&dir).unwrap();
+        let loaded = Model::load(&dir).unwrap();
+        assert_eq!(model.label_names(), loaded.label_names());
+
+        // Too few names are rejected on load
+        let mut truncated = model.clone();
+        truncated.label_names = Some(names(n_labels - 1));
+        std::fs::write(
+            dir.join(super::super::LABEL_NAMES_FILE_NAME),
+            serde_json::to_vec(truncated.label_names().unwrap()).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(
+            std::io::ErrorKind::InvalidData,
+            Model::load(&dir).unwrap_err().kind()
+        );
+        let mut buffer = Vec::new();
+        truncated.save_to_writer(&mut buffer).unwrap();
+        assert_eq!(
+            std::io::ErrorKind::InvalidData,
+            Model::load_from_reader(&buffer[..]).unwrap_err().kind()
+        );
+        truncated.label_names = Some(Vec::new());
+        assert!(matches!(
+            truncated.predict_named(feature_vec, 10),
+            Err(PredictError::MissingLabelName { .. })
+        ));
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        // Names are kept by original label when labels are compacted
+        let previous_labels = model.compact_labels();
+        assert_eq!(
+            Some(format!("label-{}", previous_labels[0]).as_str()),
+            model.label_name(0)
+        );
+        assert_eq!(expected, model.predict_named(feature_vec, 10).unwrap());
+
+        model.clear_label_names();
+        assert_eq!(None, model.label_names());
+    }
+
+    #[test]
+    fn test_train_with_label_names() {
+        let mut hyper_param = TrainHyperParam::default();
+        hyper_param.n_trees = 1;
+        hyper_param.min_branch_size = 2;
+        let dataset = toy_dataset(60, 8, 0);
+        let n_labels = dataset.n_labels;
+
+        let options = |label_names| TrainOptions {
+            label_names: Some(label_names),
+            ..TrainOptions::default()
+        };
+        let result = hyper_param.try_train_with_options(
+            dataset.clone(),
+            &options(names(n_labels - 1)),
+            &Warnings::new(),
+        );
+        assert_eq!(
+            Some(TrainError::InvalidLabelNames {
+                n_names: n_labels - 1,
+                n_labels
+            }),
+            result.err()
+        );
+        let model = hyper_param
+            .try_train_with_options(dataset, &options(names(n_labels)), &Warnings::new())
+            .unwrap();
+        assert_eq!(Some(&names(n_labels)[..]), model.label_names());
+    }
+}
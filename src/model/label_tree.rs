@@ -0,0 +1,476 @@
+//! Label trees, i.e., the nested label partitions that the trees of a model are built on.
+//!
+//! A label tree exported from a trained model can be passed back into training with
+//! [`TrainOptions::label_tree`], so that retraining on new data skips clustering and keeps labels in
+//! the same leaves; only the classifiers are trained again.
+//!
+//! [`TrainOptions::label_tree`]: super::train::TrainOptions::label_tree
+use super::train::TrainError;
+use super::{Model, TreeNode};
+use crate::mat_util::*;
+use crate::{Index, IndexSet};
+use hashbrown::HashMap;
+use itertools::Itertools;
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::io::{self, Write};
+
+/// The label partitions of the trees of a model, one per tree.
+#[derive(Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LabelTree {
+    pub trees: Vec<LabelTreeNode>,
+}
+
+/// A node of a label tree.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum LabelTreeNode {
+    /// A node partitioning its labels among its children.
+    Branch(Vec<LabelTreeNode>),
+    /// A node with the labels it ranks.
+    Leaf(Vec<Index>),
+}
+
+/// What to do with labels that differ between the training data and a label tree reused for
+/// training.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TreeReusePolicy {
+    /// Attach labels missing from the tree to the leaf whose centroid is nearest to theirs, and
+    /// drop labels of the tree without training examples, along with nodes left empty.
+    #[default]
+    Adapt,
+    /// Fail training with [`TrainError::LabelTreeMismatch`] if the labels differ.
+    Strict,
+}
+
+/// Labels that differed between the training data and a reused label tree.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LabelTreeChanges {
+    /// Labels with training examples that weren't in the tree, in increasing order.
+    pub new_labels: Vec<Index>,
+    /// Labels of the tree without training examples, in increasing order.
+    pub removed_labels: Vec<Index>,
+}
+
+impl LabelTreeChanges {
+    pub fn is_empty(&self) -> bool {
+        self.new_labels.is_empty() && self.removed_labels.is_empty()
+    }
+}
+
+impl Model {
+    /// Export the label partitions of the trees, e.g., to reuse them for retraining with
+    /// [`TrainOptions::label_tree`](super::train::TrainOptions::label_tree).
+    ///
+    /// Labels excluded from clustering are exported as the leaf they were attached to.
+    pub fn export_label_tree(&self) -> LabelTree {
+        LabelTree {
+            trees: self
+                .trees
+                .iter()
+                .map(LabelTreeNode::from_tree_node)
+                .collect(),
+        }
+    }
+}
+
+impl LabelTree {
+    pub fn n_trees(&self) -> usize {
+        self.trees.len()
+    }
+
+    /// Check that the trees are non-empty, and that each of them contains every label at most
+    /// once.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.trees.is_empty() {
+            return Err("Label tree has no trees".to_owned());
+        }
+        for (i, tree) in self.trees.iter().enumerate() {
+            if tree.has_empty_nodes() {
+                return Err(format!("Tree {} has empty nodes", i));
+            }
+            let labels = tree.labels();
+            if labels.iter().unique().count() != labels.len() {
+                return Err(format!("Tree {} contains labels more than once", i));
+            }
+        }
+        Ok(())
+    }
+
+    /// Write the topology of the trees in the Graphviz DOT language, e.g., to render with
+    /// `dot -Tsvg`, annotating each node with its number of labels.
+    ///
+    /// Each tree is drawn as a cluster, with branches as ellipses and leaves as boxes. Labels
+    /// themselves are left out, since trees can have millions of them; the JSON serialization of
+    /// the label tree has them all.
+    pub fn write_graphviz<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        writeln!(writer, "digraph label_tree {{")?;
+        for (i, tree) in self.trees.iter().enumerate() {
+            writeln!(writer, "  subgraph cluster_tree{} {{", i)?;
+            writeln!(writer, "    label=\"tree {}\";", i)?;
+            tree.write_graphviz_nodes(i, &mut 0, &mut writer)?;
+            writeln!(writer, "  }}")?;
+        }
+        writeln!(writer, "}}")?;
+        writer.flush()
+    }
+
+    /// Like [`Self::write_graphviz`], but returns the DOT source as a string.
+    pub fn to_graphviz(&self) -> String {
+        let mut buffer = Vec::new();
+        self.write_graphviz(&mut buffer)
+            .expect("Writing to a vector should not fail");
+        String::from_utf8(buffer).expect("DOT source should be valid UTF-8")
+    }
+
+    /// Adapt the trees to the labels with training examples, given with their centroids as rows
+    /// in the same order, returning the adapted tree with the labels that changed.
+    ///
+    /// See [`TreeReusePolicy::Adapt`] for how the trees are adapted.
+    pub(crate) fn adapt(
+        &self,
+        labels: &[Index],
+        label_centroids: &SparseMat,
+        policy: TreeReusePolicy,
+    ) -> Result<(Self, LabelTreeChanges), TrainError> {
+        assert_eq!(labels.len(), label_centroids.rows());
+        let present: IndexSet = labels.iter().cloned().collect();
+        let in_tree: IndexSet = self.trees.iter().flat_map(|tree| tree.labels()).collect();
+        let changes = LabelTreeChanges {
+            new_labels: labels
+                .iter()
+                .filter(|label| !in_tree.contains(label))
+                .cloned()
+                .sorted_unstable()
+                .collect(),
+            removed_labels: in_tree
+                .iter()
+                .filter(|label| !present.contains(label))
+                .cloned()
+                .sorted_unstable()
+                .collect(),
+        };
+        if changes.is_empty() {
+            return Ok((self.clone(), changes));
+        }
+        if policy == TreeReusePolicy::Strict {
+            return Err(TrainError::LabelTreeMismatch {
+                n_new_labels: changes.new_labels.len(),
+                n_removed_labels: changes.removed_labels.len(),
+            });
+        }
+
+        let label_to_row: HashMap<Index, usize> = labels
+            .iter()
+            .enumerate()
+            .map(|(row, &label)| (label, row))
+            .collect();
+        let trees = self
+            .trees
+            .iter()
+            .map(|tree| {
+                // Labels in the tree all have centroids, unless every one of them was removed
+                let mut tree = tree
+                    .retain_labels(&present)
+                    .unwrap_or_else(|| LabelTreeNode::Leaf(Vec::new()));
+                tree.attach_labels(&changes.new_labels, &label_to_row, label_centroids);
+                tree
+            })
+            .collect();
+        Ok((Self { trees }, changes))
+    }
+}
+
+impl fmt::Debug for LabelTree {
+    /// Only summarized, since trees can have millions of labels.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("LabelTree")
+            .field("n_trees", &self.n_trees())
+            .field(
+                "n_leaves",
+                &self.trees.iter().map(|tree| tree.n_leaves()).sum::<usize>(),
+            )
+            .finish_non_exhaustive()
+    }
+}
+
+impl LabelTreeNode {
+    fn from_tree_node(node: &TreeNode) -> Self {
+        match node {
+            TreeNode::Branch { children, .. } => {
+                LabelTreeNode::Branch(children.iter().map(Self::from_tree_node).collect())
+            }
+            TreeNode::Leaf { labels, .. } => LabelTreeNode::Leaf(labels.clone()),
+        }
+    }
+
+    /// Labels in the subtree, in the order of its leaves.
+    pub fn labels(&self) -> Vec<Index> {
+        let mut labels = Vec::new();
+        self.visit_leaves(&mut |leaf_labels| labels.extend_from_slice(leaf_labels));
+        labels
+    }
+
+    pub fn n_leaves(&self) -> usize {
+        let mut n_leaves = 0;
+        self.visit_leaves(&mut |_| n_leaves += 1);
+        n_leaves
+    }
+
+    pub(crate) fn visit_leaves<F: FnMut(&[Index])>(&self, visit: &mut F) {
+        match self {
+            LabelTreeNode::Branch(children) => {
+                children.iter().for_each(|child| child.visit_leaves(visit))
+            }
+            LabelTreeNode::Leaf(labels) => visit(labels),
+        }
+    }
+
+    /// Write the nodes of the subtree and the edges between them as DOT statements, numbering
+    /// nodes in pre-order from the given id, and return the id of the subtree's root with its
+    /// number of labels.
+    fn write_graphviz_nodes<W: Write>(
+        &self,
+        tree: usize,
+        next_id: &mut usize,
+        writer: &mut W,
+    ) -> io::Result<(usize, usize)> {
+        let id = *next_id;
+        *next_id += 1;
+        let (shape, n_labels) = match self {
+            LabelTreeNode::Branch(children) => {
+                let mut n_labels = 0;
+                for child in children {
+                    let (child_id, n_child_labels) =
+                        child.write_graphviz_nodes(tree, next_id, writer)?;
+                    writeln!(writer, "    t{}n{} -> t{}n{};", tree, id, tree, child_id)?;
+                    n_labels += n_child_labels;
+                }
+                ("ellipse", n_labels)
+            }
+            LabelTreeNode::Leaf(labels) => ("box", labels.len()),
+        };
+        writeln!(
+            writer,
+            "    t{}n{} [shape={}, label=\"{}\"];",
+            tree, id, shape, n_labels
+        )?;
+        Ok((id, n_labels))
+    }
+
+    fn leaves_mut<'a>(&'a mut self, leaves: &mut Vec<&'a mut Vec<Index>>) {
+        match self {
+            LabelTreeNode::Branch(children) => children
+                .iter_mut()
+                .for_each(|child| child.leaves_mut(leaves)),
+            LabelTreeNode::Leaf(labels) => leaves.push(labels),
+        }
+    }
+
+    fn has_empty_nodes(&self) -> bool {
+        match self {
+            LabelTreeNode::Branch(children) => {
+                children.is_empty() || children.iter().any(Self::has_empty_nodes)
+            }
+            LabelTreeNode::Leaf(labels) => labels.is_empty(),
+        }
+    }
+
+    /// Keep only the given labels, dropping nodes left empty and replacing branches left with a
+    /// single child by that child; returns `None` if no labels are left.
+    fn retain_labels(&self, labels: &IndexSet) -> Option<Self> {
+        match self {
+            LabelTreeNode::Branch(children) => {
+                let mut children = children
+                    .iter()
+                    .filter_map(|child| child.retain_labels(labels))
+                    .collect_vec();
+                match children.len() {
+                    0 => None,
+                    1 => children.pop(),
+                    _ => Some(LabelTreeNode::Branch(children)),
+                }
+            }
+            LabelTreeNode::Leaf(leaf_labels) => {
+                let leaf_labels = leaf_labels
+                    .iter()
+                    .filter(|label| labels.contains(label))
+                    .cloned()
+                    .collect_vec();
+                if leaf_labels.is_empty() {
+                    None
+                } else {
+                    Some(LabelTreeNode::Leaf(leaf_labels))
+                }
+            }
+        }
+    }
+
+    /// Attach each of the given labels to the leaf whose centroid, the normalized sum of the
+    /// centroids of its labels, has the largest cosine similarity with the label's centroid.
+    ///
+    /// Ties go to the first leaf, so that labels are attached to some leaf even if their
+    /// centroids share no features with any leaf.
+    fn attach_labels(
+        &mut self,
+        new_labels: &[Index],
+        label_to_row: &HashMap<Index, usize>,
+        label_centroids: &SparseMat,
+    ) {
+        let centroid = |label: &Index| label_centroids.outer_view(label_to_row[label]).unwrap();
+        let mut leaves = Vec::new();
+        self.leaves_mut(&mut leaves);
+
+        let leaf_centroids = leaves
+            .par_iter()
+            .map(|labels| {
+                let mut sums = HashMap::<Index, f32>::new();
+                for label in labels.iter() {
+                    for (feature, &value) in centroid(label).iter() {
+                        *sums.entry(feature as Index).or_insert(0.) += value;
+                    }
+                }
+                let mut leaf_centroid = sums.into_iter().collect_vec();
+                leaf_centroid.l2_normalize();
+                leaf_centroid.into_iter().collect::<HashMap<_, _>>()
+            })
+            .collect::<Vec<_>>();
+
+        let nearest_leaves = new_labels
+            .par_iter()
+            .map(|label| {
+                let label_centroid = centroid(label);
+                let mut nearest = (0, f32::NEG_INFINITY);
+                for (i, leaf_centroid) in leaf_centroids.iter().enumerate() {
+                    let similarity = label_centroid
+                        .iter()
+                        .filter_map(|(feature, &value)| {
+                            leaf_centroid
+                                .get(&(feature as Index))
+                                .map(|&leaf_value| value * leaf_value)
+                        })
+                        .sum::<f32>();
+                    if similarity > nearest.1 {
+                        nearest = (i, similarity);
+                    }
+                }
+                nearest.0
+            })
+            .collect::<Vec<_>>();
+
+        for (&label, leaf_index) in new_labels.iter().zip_eq(nearest_leaves) {
+            leaves[leaf_index].push(label);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_adapt() {
+        use LabelTreeNode::*;
+        let tree = LabelTree {
+            trees: vec![Branch(vec![
+                Leaf(vec![0, 1]),
+                Branch(vec![Leaf(vec![2]), Leaf(vec![3])]),
+            ])],
+        };
+        assert!(tree.validate().is_ok());
+
+        // Label 3 is removed, and label 4 shares features with label 0 only
+        let labels = [0, 1, 2, 4];
+        let label_centroids = csrmat_from_index_value_pair_lists(
+            vec![
+                vec![(0, 1.)],
+                vec![(1, 1.)],
+                vec![(2, 1.)],
+                vec![(0, 0.6), (3, 0.8)],
+            ],
+            4,
+        );
+        let (adapted, changes) = tree
+            .adapt(&labels, &label_centroids, TreeReusePolicy::Adapt)
+            .unwrap();
+        assert_eq!(
+            LabelTreeChanges {
+                new_labels: vec![4],
+                removed_labels: vec![3],
+            },
+            changes
+        );
+        assert_eq!(
+            vec![Branch(vec![Leaf(vec![0, 1, 4]), Leaf(vec![2])])],
+            adapted.trees
+        );
+        assert!(adapted.validate().is_ok());
+
+        assert_eq!(
+            Err(TrainError::LabelTreeMismatch {
+                n_new_labels: 1,
+                n_removed_labels: 1,
+            }),
+            tree.adapt(&labels, &label_centroids, TreeReusePolicy::Strict)
+        );
+
+        let invalid = LabelTree {
+            trees: vec![Branch(vec![Leaf(vec![0, 1]), Leaf(vec![1])])],
+        };
+        assert!(invalid.validate().is_err());
+        assert!(LabelTree {
+            trees: vec![Branch(vec![Leaf(vec![0]), Leaf(vec![])])]
+        }
+        .validate()
+        .is_err());
+    }
+
+    #[test]
+    fn test_graphviz() {
+        use LabelTreeNode::*;
+        let tree = LabelTree {
+            trees: vec![
+                Branch(vec![
+                    Leaf(vec![0, 1]),
+                    Branch(vec![Leaf(vec![2]), Leaf(vec![3])]),
+                ]),
+                Leaf(vec![0, 1, 2, 3]),
+            ],
+        };
+        assert_eq!(
+            "digraph label_tree {
+  subgraph cluster_tree0 {
+    label=\"tree 0\";
+    t0n1 [shape=box, label=\"2\"];
+    t0n0 -> t0n1;
+    t0n3 [shape=box, label=\"1\"];
+    t0n2 -> t0n3;
+    t0n4 [shape=box, label=\"1\"];
+    t0n2 -> t0n4;
+    t0n2 [shape=ellipse, label=\"2\"];
+    t0n0 -> t0n2;
+    t0n0 [shape=ellipse, label=\"4\"];
+  }
+  subgraph cluster_tree1 {
+    label=\"tree 1\";
+    t1n0 [shape=box, label=\"4\"];
+  }
+}
+",
+            tree.to_graphviz()
+        );
+
+        // The structure of a trained model can be exported for comparison
+        let exported = crate::test_util::toy_model(2, 0).export_label_tree();
+        let dot = exported.to_graphviz();
+        assert_eq!(2, dot.matches("subgraph").count());
+        assert_eq!(
+            exported
+                .trees
+                .iter()
+                .map(|tree| tree.n_leaves())
+                .sum::<usize>(),
+            dot.matches("shape=box").count()
+        );
+    }
+}
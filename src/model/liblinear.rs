@@ -1,12 +1,23 @@
+//! Linear classifiers trained with liblinear's dual coordinate descent solvers.
+//!
+//! Two L2-regularized losses are supported, see [`LossType`]; both are solved in the dual, so
+//! training time grows with the number of examples rather than the number of features. Single
+//! binary problems can be solved with [`solve`], while the trees of a model are trained with a
+//! one-vs-all classifier per child or label.
 use crate::mat_util::*;
+use crate::math;
 use const_default::ConstDefault;
 use itertools::Itertools;
 use rand::prelude::*;
 use rayon::prelude::*;
 use serde::{Deserialize, Serialize};
 use std::f32::{INFINITY, NEG_INFINITY};
+use std::mem;
 use std::ops::Deref;
 
+/// Maximum number of points kept in an [`ObjectiveCurve`].
+const MAX_OBJECTIVE_POINTS: usize = 64;
+
 /// The loss function used by liblinear model.
 #[derive(Eq, PartialEq, Copy, Clone, Debug, Serialize, Deserialize)]
 pub enum LossType {
@@ -80,25 +91,43 @@ impl HyperParam {
     }
 
     /// Train a one-vs-all multi-label classifier with the given data.
+    ///
+    /// If example weights are given, the loss of each example is scaled by its weight, which must
+    /// be positive. Returns an error if the hyper-parameters are invalid.
     pub(crate) fn train<Indices: Deref<Target = [usize]> + Sync>(
         &self,
         feature_matrix: &SparseMatView,
         label_to_example_indices: &[Indices],
-    ) -> WeightMat {
-        self.validate().unwrap();
+        example_weights: Option<&[f32]>,
+    ) -> Result<WeightMat, String> {
+        self.train_recording_objective(feature_matrix, label_to_example_indices, example_weights, 0)
+            .map(|(weights, _)| weights)
+    }
+
+    /// Like [`Self::train`], but also record the solver objective of the first `n_recorded`
+    /// classifiers, returning their curves in order.
+    pub(crate) fn train_recording_objective<Indices: Deref<Target = [usize]> + Sync>(
+        &self,
+        feature_matrix: &SparseMatView,
+        label_to_example_indices: &[Indices],
+        example_weights: Option<&[f32]>,
+        n_recorded: usize,
+    ) -> Result<(WeightMat, Vec<ObjectiveCurve>), String> {
+        self.validate()?;
+        if let Some(example_weights) = example_weights {
+            assert_eq!(feature_matrix.rows(), example_weights.len());
+            assert!(example_weights.iter().all(|&w| w > 0.));
+        }
 
         assert!(feature_matrix.is_csr());
         // Remove empty columns from features matrix to speed up training
         let n_features = feature_matrix.inner_dims();
         let (feature_matrix, index_to_feature) = feature_matrix.to_owned().shrink_inner_indices();
 
-        let solver = match self.loss_type {
-            LossType::Hinge => solve_l2r_l2_svc,
-            LossType::Log => solve_l2r_lr_dual,
-        };
-        let weights = label_to_example_indices
+        let (weights, curves): (Vec<_>, Vec<_>) = label_to_example_indices
             .par_iter()
-            .map(|indices| {
+            .enumerate()
+            .map(|(j, indices)| {
                 // For the current classifier, an example is positive iff its index is in the given list
                 let mut labels = vec![false; feature_matrix.rows()];
                 let mut n_pos = 0;
@@ -108,44 +137,206 @@ impl HyperParam {
                 }
                 assert_ne!(n_pos, 0);
 
-                let (indices, data) = solver(
-                    &feature_matrix.view(),
-                    &labels,
-                    self.eps,
-                    self.c,
-                    self.c,
-                    self.max_iter,
-                )
-                .indexed_iter()
-                .filter_map(|(index, &value)| {
-                    if value.abs() <= self.weight_threshold {
-                        None
-                    } else {
-                        Some((index_to_feature[index], value))
-                    }
-                })
-                .unzip();
-
-                SparseVec::new(n_features, indices, data)
+                let mut curve = if j < n_recorded {
+                    Some(ObjectiveCurve::new())
+                } else {
+                    None
+                };
+                let problem = Problem {
+                    features: feature_matrix.view(),
+                    targets: &labels,
+                    weights: example_weights,
+                };
+                let (weights, _) = self.solve_dense(&problem, curve.as_mut());
+                let (indices, data) = threshold_weights(&weights, self.weight_threshold)
+                    .map(|(index, value)| (index_to_feature[index], value))
+                    .unzip();
+
+                (SparseVec::new(n_features, indices, data), curve)
             })
-            .collect::<Vec<_>>();
+            .unzip();
+
+        Ok((
+            WeightMat::from_rows(&weights),
+            curves.into_iter().flatten().collect(),
+        ))
+    }
+
+    /// Solve the problem with the solver for the loss type, returning dense weights.
+    fn solve_dense(
+        &self,
+        problem: &Problem,
+        objective_curve: Option<&mut ObjectiveCurve>,
+    ) -> (DenseVec, SolverStats) {
+        let solver = match self.loss_type {
+            LossType::Hinge => solve_l2r_l2_svc,
+            LossType::Log => solve_l2r_lr_dual,
+        };
+        solver(
+            problem,
+            self.eps,
+            self.c,
+            self.c,
+            self.max_iter,
+            objective_curve,
+        )
+    }
+}
+
+/// A binary classification problem, for training a single linear classifier.
+#[derive(Clone, Debug)]
+pub struct Problem<'a> {
+    /// The feature vectors of the examples, as rows of a matrix in CSR format.
+    pub features: SparseMatView<'a>,
+    /// Whether each example is positive.
+    pub targets: &'a [bool],
+    /// Weights scaling the loss of each example, which must be positive.
+    pub weights: Option<&'a [f32]>,
+}
 
-        WeightMat::from_rows(&weights)
+impl Problem<'_> {
+    /// Check that the problem is well-formed.
+    pub fn validate(&self) -> Result<(), String> {
+        if !self.features.is_csr() {
+            Err("features must be in CSR format".to_owned())
+        } else if self.features.rows() != self.targets.len() {
+            Err(format!(
+                "There are {} feature vectors but {} targets",
+                self.features.rows(),
+                self.targets.len()
+            ))
+        } else if let Some(weights) = self.weights {
+            if weights.len() != self.targets.len() {
+                Err(format!(
+                    "There are {} weights but {} targets",
+                    weights.len(),
+                    self.targets.len()
+                ))
+            } else if !weights.iter().all(|&w| w > 0.) {
+                Err("weights must be positive".to_owned())
+            } else {
+                Ok(())
+            }
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// Statistics of a solver run.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SolverStats {
+    /// The number of passes over the examples.
+    pub n_iters: u32,
+    /// Whether the stopping tolerance was reached before `max_iter` passes.
+    pub converged: bool,
+}
+
+/// Train a linear classifier for the given problem, returning its weights with solver statistics.
+///
+/// The weights have the dimension of the feature vectors, and those with absolute values not
+/// larger than `hyper_param.weight_threshold` are dropped. Solvers take time proportional to the
+/// number of columns as well, so empty columns are best removed from wide feature matrices
+/// beforehand; tree training does so once for all classifiers of a node.
+///
+/// Returns an error if the hyper-parameters are invalid or the problem is malformed.
+pub fn solve(
+    problem: &Problem,
+    hyper_param: &HyperParam,
+) -> Result<(SparseVec, SolverStats), String> {
+    hyper_param
+        .validate()
+        .map_err(|msg| format!("Invalid hyper-parameter; {}", msg))?;
+    problem
+        .validate()
+        .map_err(|msg| format!("Invalid problem; {}", msg))?;
+    let (weights, stats) = hyper_param.solve_dense(problem, None);
+    let (indices, data) = threshold_weights(&weights, hyper_param.weight_threshold).unzip();
+    Ok((
+        SparseVec::new(problem.features.cols(), indices, data),
+        stats,
+    ))
+}
+
+/// Weights with absolute values larger than the threshold, with their indices.
+fn threshold_weights(
+    weights: &DenseVec,
+    threshold: f32,
+) -> impl Iterator<Item = (usize, f32)> + '_ {
+    weights
+        .indexed_iter()
+        .filter(move |(_, value)| value.abs() > threshold)
+        .map(|(index, &value)| (index, value))
+}
+
+/// Values of the dual objective that a solver minimizes, recorded while training a classifier.
+///
+/// The objective is recorded before the first iteration and then after every `interval`
+/// iterations. To keep memory bounded, every other point is dropped and the interval doubled
+/// whenever there would be more than 64 points.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ObjectiveCurve {
+    /// The number of iterations between consecutive points.
+    pub interval: u32,
+    /// The objective after `i * interval` iterations at each index `i`.
+    pub values: Vec<f32>,
+}
+
+impl ObjectiveCurve {
+    fn new() -> Self {
+        Self {
+            interval: 1,
+            values: Vec::new(),
+        }
+    }
+
+    /// Record the objective after the given number of iterations, computing it only if it's kept.
+    fn record(&mut self, n_iters: u32, objective: impl FnOnce() -> f32) {
+        if n_iters % self.interval != 0 {
+            return;
+        }
+        self.values.push(objective());
+        if self.values.len() > MAX_OBJECTIVE_POINTS {
+            self.values = mem::take(&mut self.values).into_iter().step_by(2).collect();
+            self.interval *= 2;
+        }
     }
 }
 
+/// Score the classifiers with the given weights, storing the scores in the given buffer.
 pub(crate) fn predict(
     weights: &WeightMat,
     loss_type: LossType,
     feature_vec: &SparseVec,
+    buffer: Vec<f32>,
 ) -> DenseVec {
-    let scores = weights.t_dot_vec(feature_vec.view());
+    let mut scores = weights.t_dot_vec_with(feature_vec.view(), buffer);
+    scores.mapv_inplace(|margin| log_score(margin, loss_type));
+    scores
+}
+
+/// The log-space score of a classifier with the given margin; see [`super::scores`].
+#[inline]
+pub(crate) fn log_score(margin: f32, loss_type: LossType) -> f32 {
     match loss_type {
-        LossType::Log => scores.mapv(|score| -(-score).exp().ln_1p()),
-        LossType::Hinge => scores.mapv(|score| -(1. - score).max(0.).powi(2)),
+        LossType::Log => -math::exp(-margin).ln_1p(),
+        LossType::Hinge => -(1. - margin).max(0.).powi(2),
     }
 }
 
+/// Per-example costs, i.e., Cp or Cn depending on the label, scaled by example weights if given.
+fn example_costs<'a>(
+    y: &'a [bool],
+    cp: f32,
+    cn: f32,
+    example_weights: Option<&'a [f32]>,
+) -> impl Iterator<Item = f32> + 'a {
+    y.iter().enumerate().map(move |(i, &yi)| {
+        let c = if yi { cp } else { cn };
+        example_weights.map_or(c, |weights| c * weights[i])
+    })
+}
+
 /// A coordinate descent solver for L2-loss SVM dual problems.
 ///
 /// This is pretty much a line-by-line port from liblinear (with some simplification) to avoid
@@ -170,16 +361,21 @@ pub(crate) fn predict(
 /// x, y, Cp, Cn
 /// eps is the stopping tolerance
 ///
+/// With example weights, Cp and Cn are further multiplied by the weight of each example.
+///
 /// See Algorithm 3 of Hsieh et al., ICML 2008.
 #[allow(clippy::many_single_char_names)]
 fn solve_l2r_l2_svc(
-    x: &SparseMatView,
-    y: &[bool],
+    problem: &Problem,
     eps: f32,
     cp: f32,
     cn: f32,
     max_iter: u32,
-) -> DenseVec {
+    mut objective_curve: Option<&mut ObjectiveCurve>,
+) -> (DenseVec, SolverStats) {
+    let x = &problem.features;
+    let y = problem.targets;
+    let example_weights = problem.weights;
     assert!(x.is_csr());
     assert_eq!(x.rows(), y.len());
 
@@ -196,7 +392,9 @@ fn solve_l2r_l2_svc(
     let mut pgmin_new: f32;
 
     // default solver_type: L2R_L2LOSS_SVC_DUAL
-    let diag: [f32; 2] = [0.5 / cn, 0.5 / cp];
+    let diag = example_costs(y, cp, cn, example_weights)
+        .map(|c| 0.5 / c)
+        .collect_vec();
 
     // Note that 0 <= alpha[i] <= upper_bound[y[i]]
     let mut alpha = vec![0.; l];
@@ -204,12 +402,26 @@ fn solve_l2r_l2_svc(
     let mut index = (0..l).collect_vec();
     let qd = x
         .outer_iterator()
-        .zip(y.iter())
-        .map(|(xi, &yi)| diag[yi as usize] + csvec_dot_self(&xi))
+        .zip(diag.iter())
+        .map(|(xi, &d)| d + csvec_dot_self(&xi))
         .collect_vec();
 
+    // The dual objective, where alpha^T Q alpha = w^T w
+    let objective = |w: &DenseVec, alpha: &[f32]| {
+        0.5 * w.dot(w)
+            + alpha
+                .iter()
+                .zip(&diag)
+                .map(|(&a, &d)| 0.5 * d * a * a - a)
+                .sum::<f32>()
+    };
+
     let mut iter = 0;
+    let mut converged = false;
     let mut rng = thread_rng();
+    if let Some(curve) = objective_curve.as_deref_mut() {
+        curve.record(iter, || objective(&w, &alpha));
+    }
     while iter < max_iter {
         pgmax_new = NEG_INFINITY;
         pgmin_new = INFINITY;
@@ -230,7 +442,7 @@ fn solve_l2r_l2_svc(
             });
             let alpha_i = &mut alpha[i];
 
-            let g = yi_sign * xi.dot_dense(w.view()) - 1. + *alpha_i * diag[yi as usize];
+            let g = yi_sign * xi.dot_dense(w.view()) - 1. + *alpha_i * diag[i];
 
             pg = 0.;
             if *alpha_i == 0. {
@@ -259,9 +471,13 @@ fn solve_l2r_l2_svc(
         }
 
         iter += 1;
+        if let Some(curve) = objective_curve.as_deref_mut() {
+            curve.record(iter, || objective(&w, &alpha));
+        }
 
         if pgmax_new - pgmin_new <= eps {
             if active_size == l {
+                converged = true;
                 break;
             } else {
                 active_size = l;
@@ -275,7 +491,13 @@ fn solve_l2r_l2_svc(
         }
     }
 
-    w
+    (
+        w,
+        SolverStats {
+            n_iters: iter,
+            converged,
+        },
+    )
 }
 
 /// A coordinate descent solver for the dual of L2-regularized logistic regression problems.
@@ -294,16 +516,21 @@ fn solve_l2r_l2_svc(
 /// x, y, Cp, Cn
 /// eps is the stopping tolerance
 ///
+/// With example weights, Cp and Cn are further multiplied by the weight of each example.
+///
 /// See Algorithm 5 of Yu et al., MLJ 2010.
 #[allow(clippy::many_single_char_names)]
 fn solve_l2r_lr_dual(
-    x: &SparseMatView,
-    y: &[bool],
+    problem: &Problem,
     eps: f32,
     cp: f32,
     cn: f32,
     max_iter: u32,
-) -> DenseVec {
+    mut objective_curve: Option<&mut ObjectiveCurve>,
+) -> (DenseVec, SolverStats) {
+    let x = &problem.features;
+    let y = problem.targets;
+    let example_weights = problem.weights;
     assert!(x.is_csr());
     assert_eq!(x.rows(), y.len());
 
@@ -313,15 +540,14 @@ fn solve_l2r_lr_dual(
     let max_inner_iter = 100; // for inner Newton
     let mut innereps = 1e-2;
     let innereps_min = eps.min(1e-8);
-    let upper_bound = [cn, cp];
+    let upper_bound = example_costs(y, cp, cn, example_weights).collect_vec();
 
     // store alpha and C - alpha. Note that
-    // 0 < alpha[i] < upper_bound[GETI(i)]
-    // alpha[2*i] + alpha[2*i+1] = upper_bound[GETI(i)]
-    let mut alpha = y
+    // 0 < alpha[i] < upper_bound[i]
+    // alpha[2*i] + alpha[2*i+1] = upper_bound[i]
+    let mut alpha = upper_bound
         .iter()
-        .flat_map(|&yi| {
-            let c = upper_bound[yi as usize];
+        .flat_map(|&c| {
             let alpha = (0.001 * c).min(1e-8);
             vec![alpha, c - alpha]
         })
@@ -340,8 +566,23 @@ fn solve_l2r_lr_dual(
 
     let mut index = (0..l).collect_vec();
 
+    // The dual objective, up to the constant sum of C log(C), as computed by liblinear
+    let objective = |w: &DenseVec, alpha: &[f32]| {
+        let x_log_x = |v: f32| if v > 0. { v * v.ln() } else { 0. };
+        0.5 * w.dot(w)
+            + upper_bound
+                .iter()
+                .enumerate()
+                .map(|(i, &c)| x_log_x(alpha[2 * i]) + x_log_x(alpha[2 * i + 1]) - x_log_x(c))
+                .sum::<f32>()
+    };
+
     let mut iter = 0;
+    let mut converged = false;
     let mut rng = thread_rng();
+    if let Some(curve) = objective_curve.as_deref_mut() {
+        curve.record(iter, || objective(&w, &alpha));
+    }
     while iter < max_iter {
         index.shuffle(&mut rng);
         let mut newton_iter = 0;
@@ -349,7 +590,7 @@ fn solve_l2r_lr_dual(
         for &i in &index {
             let yi = y[i];
             let yi_sign = if yi { 1. } else { -1. };
-            let c = upper_bound[yi as usize];
+            let c = upper_bound[i];
             let xi = x.outer_view(i).unwrap_or_else(|| {
                 panic!(
                     "Failed to take {}-th outer view for matrix x of shape {:?}",
@@ -410,8 +651,12 @@ fn solve_l2r_lr_dual(
         }
 
         iter += 1;
+        if let Some(curve) = objective_curve.as_deref_mut() {
+            curve.record(iter, || objective(&w, &alpha));
+        }
 
         if gmax < eps {
+            converged = true;
             break;
         }
 
@@ -420,5 +665,187 @@ fn solve_l2r_lr_dual(
         }
     }
 
-    w
+    (
+        w,
+        SolverStats {
+            n_iters: iter,
+            converged,
+        },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::toy_dataset;
+    use crate::Index;
+    use assert_approx_eq::assert_approx_eq;
+
+    #[test]
+    fn test_objective_curves() {
+        let dataset = toy_dataset(60, 4, 0);
+        let feature_matrix =
+            csrmat_from_index_value_pair_lists(dataset.feature_lists.clone(), dataset.n_features);
+        let label_to_example_indices = (0..dataset.n_labels)
+            .map(|label| {
+                dataset
+                    .label_lists()
+                    .enumerate()
+                    .filter(|(_, labels)| labels.contains(&(label as Index)))
+                    .map(|(i, _)| i)
+                    .collect_vec()
+            })
+            .collect_vec();
+
+        for &loss_type in &[LossType::Hinge, LossType::Log] {
+            let hyper_param = HyperParam {
+                loss_type,
+                eps: 1e-4,
+                max_iter: 200,
+                ..HyperParam::default()
+            };
+            let (weights, curves) = hyper_param
+                .train_recording_objective(
+                    &feature_matrix.view(),
+                    &label_to_example_indices,
+                    None,
+                    3,
+                )
+                .unwrap();
+            assert_eq!((dataset.n_features, 4), weights.shape());
+            assert_eq!(3, curves.len());
+            for curve in &curves {
+                assert!(curve.values.len() > 1);
+                assert!(curve.values.len() <= MAX_OBJECTIVE_POINTS);
+                // Both dual objectives are convex, and each coordinate step decreases them
+                for (prev, next) in curve.values.iter().tuple_windows() {
+                    assert!(
+                        next - prev <= 1e-3 * prev.abs().max(1.),
+                        "{:?} objective increased: {:?}",
+                        loss_type,
+                        curve.values
+                    );
+                }
+            }
+        }
+    }
+
+    /// Solve the problem with a tight tolerance and no weights dropped, returning dense weights.
+    fn solve_exactly(problem: &Problem, loss_type: LossType, c: f32) -> Vec<f32> {
+        let hyper_param = HyperParam {
+            loss_type,
+            eps: 1e-4,
+            c,
+            weight_threshold: 0.,
+            max_iter: 1000,
+        };
+        let (weights, stats) = solve(problem, &hyper_param).unwrap();
+        assert!(stats.converged, "{:?}", stats);
+        assert!(stats.n_iters > 0);
+        let mut dense = vec![0.; weights.dim()];
+        for (index, &value) in weights.iter() {
+            dense[index] = value;
+        }
+        dense
+    }
+
+    #[test]
+    fn test_solve() {
+        // Two examples on orthogonal features decouple into one-dimensional problems
+        let features = csrmat_from_index_value_pair_lists(vec![vec![(0, 1.)], vec![(1, 1.)]], 3);
+        let problem = Problem {
+            features: features.view(),
+            targets: &[true, false],
+            weights: None,
+        };
+        assert!(problem.validate().is_ok());
+
+        // Squared hinge: w - 2C(1 - w) = 0, so w = 2C / (1 + 2C)
+        let weights = solve_exactly(&problem, LossType::Hinge, 1.);
+        assert_approx_eq!(2. / 3., weights[0], 1e-3);
+        assert_approx_eq!(-2. / 3., weights[1], 1e-3);
+        assert_eq!(0., weights[2]);
+
+        let weights = solve_exactly(&problem, LossType::Hinge, 2.);
+        assert_approx_eq!(0.8, weights[0], 1e-3);
+
+        // Weighting an example is the same as scaling C for it
+        let weighted = Problem {
+            weights: Some(&[2., 2.]),
+            ..problem.clone()
+        };
+        let weights = solve_exactly(&weighted, LossType::Hinge, 1.);
+        assert_approx_eq!(0.8, weights[0], 1e-3);
+        assert_approx_eq!(-0.8, weights[1], 1e-3);
+
+        // Logistic: w = C / (1 + exp(w)), solved numerically
+        let weights = solve_exactly(&problem, LossType::Log, 1.);
+        assert_approx_eq!(0.401_058, weights[0], 1e-3);
+        assert_approx_eq!(-0.401_058, weights[1], 1e-3);
+
+        // With two opposite examples on the same feature: w = 2C / (1 + exp(w))
+        let features = csrmat_from_index_value_pair_lists(vec![vec![(0, 1.)], vec![(0, -1.)]], 1);
+        let problem = Problem {
+            features: features.view(),
+            targets: &[true, false],
+            weights: None,
+        };
+        let weights = solve_exactly(&problem, LossType::Log, 1.);
+        assert_approx_eq!(0.674_832, weights[0], 1e-3);
+        // Squared hinge: w - 4C(1 - w) = 0
+        let weights = solve_exactly(&problem, LossType::Hinge, 1.);
+        assert_approx_eq!(0.8, weights[0], 1e-3);
+
+        // Small weights are dropped
+        let (weights, _) = solve(
+            &problem,
+            &HyperParam {
+                weight_threshold: 0.9,
+                ..HyperParam::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(0, weights.nnz());
+
+        // Invalid inputs are errors rather than panics
+        let invalid = HyperParam {
+            c: 0.,
+            ..HyperParam::default()
+        };
+        assert!(solve(&problem, &invalid).is_err());
+        assert!(solve(
+            &Problem {
+                targets: &[true],
+                ..problem.clone()
+            },
+            &HyperParam::default()
+        )
+        .is_err());
+
+        assert!(Problem {
+            targets: &[true],
+            ..problem.clone()
+        }
+        .validate()
+        .is_err());
+        assert!(Problem {
+            weights: Some(&[1., 0.]),
+            ..problem
+        }
+        .validate()
+        .is_err());
+    }
+
+    #[test]
+    fn test_objective_curve_is_bounded() {
+        let mut curve = ObjectiveCurve::new();
+        for i in 0..=1000 {
+            curve.record(i, || -(i as f32));
+        }
+        assert!(curve.values.len() <= MAX_OBJECTIVE_POINTS);
+        assert!(curve.values.len() > MAX_OBJECTIVE_POINTS / 2);
+        for (i, &value) in curve.values.iter().enumerate() {
+            assert_eq!(-((i as u32 * curve.interval) as f32), value);
+        }
+    }
 }
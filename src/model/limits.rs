@@ -0,0 +1,542 @@
+//! Caps on the work done by each prediction and on the size of its output.
+//!
+//! Limits are stored in the model and serialized with it, so that a latency budget can be baked
+//! into a model at deploy time. See [`Model::set_inference_limits`].
+use super::Model;
+use const_default::ConstDefault;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// What happens when a prediction hits one of the limits.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum LimitPolicy {
+    /// Return what was found within the limits.
+    Truncate,
+    /// Fail checked prediction with [`PredictError::LimitExceeded`].
+    ///
+    /// [`Model::predict`] can't report errors, so it always truncates.
+    ///
+    /// [`PredictError::LimitExceeded`]: super::PredictError::LimitExceeded
+    Error,
+}
+
+/// One of the limits in [`InferenceLimits`].
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Limit {
+    Beam,
+    LabelsReturned,
+    NodesVisited,
+    LeafLabelsScored,
+}
+
+impl fmt::Display for Limit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Limit::Beam => write!(f, "max_beam"),
+            Limit::LabelsReturned => write!(f, "max_labels_returned"),
+            Limit::NodesVisited => write!(f, "max_nodes_visited"),
+            Limit::LeafLabelsScored => write!(f, "max_leaf_labels_scored"),
+        }
+    }
+}
+
+/// Caps applied to each prediction; `None` means no cap.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct InferenceLimits {
+    /// The largest beam size used; larger requested beams are reduced to this.
+    pub max_beam: Option<usize>,
+    /// The largest number of labels returned.
+    pub max_labels_returned: Option<usize>,
+    /// The largest number of nodes, over all trees, whose classifiers are evaluated.
+    ///
+    /// When the budget runs out, the highest-scoring nodes on the frontier are evaluated first and
+    /// the rest are dropped. Trees are searched in order, and scores are averaged over the trees
+    /// that weren't left without budget to score any leaf.
+    pub max_nodes_visited: Option<usize>,
+    /// The largest number of labels, over all trees, scored in leaves.
+    ///
+    /// Leaves are scored as a whole, from the highest-scoring one, and leaves whose labels would
+    /// exceed the remaining budget are skipped.
+    pub max_leaf_labels_scored: Option<usize>,
+    /// What happens when a limit is hit.
+    pub policy: LimitPolicy,
+}
+
+impl ConstDefault for InferenceLimits {
+    const DEFAULT: Self = Self {
+        max_beam: None,
+        max_labels_returned: None,
+        max_nodes_visited: None,
+        max_leaf_labels_scored: None,
+        policy: LimitPolicy::Truncate,
+    };
+}
+
+impl Default for InferenceLimits {
+    fn default() -> Self {
+        <Self as ConstDefault>::DEFAULT
+    }
+}
+
+impl InferenceLimits {
+    /// Check if the limits are valid.
+    pub fn validate(&self) -> Result<(), String> {
+        let limits = [
+            (Limit::Beam, self.max_beam),
+            (Limit::LabelsReturned, self.max_labels_returned),
+            (Limit::NodesVisited, self.max_nodes_visited),
+            (Limit::LeafLabelsScored, self.max_leaf_labels_scored),
+        ];
+        for &(limit, value) in &limits {
+            if value == Some(0) {
+                return Err(format!("{} must be positive if set", limit));
+            }
+        }
+        Ok(())
+    }
+
+    /// The beam size used for a requested beam size, recording if it was capped.
+    pub(crate) fn beam_size(&self, beam_size: usize, hits: &mut LimitHits) -> usize {
+        match self.max_beam {
+            Some(max_beam) if beam_size > max_beam => {
+                hits.beam = true;
+                max_beam
+            }
+            _ => beam_size,
+        }
+    }
+
+    /// The number of labels returned out of the given number of labels reached, when at most
+    /// `top_k` are requested, recording if the limit cut any requested labels.
+    pub(crate) fn n_labels_returned(
+        &self,
+        top_k: Option<usize>,
+        n_labels: usize,
+        hits: &mut LimitHits,
+    ) -> usize {
+        let n_requested = top_k.map_or(n_labels, |k| k.min(n_labels));
+        match self.max_labels_returned {
+            Some(max_labels_returned) if n_requested > max_labels_returned => {
+                hits.labels_returned = true;
+                max_labels_returned
+            }
+            _ => n_requested,
+        }
+    }
+}
+
+/// Which limits were hit during a prediction.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub(crate) struct LimitHits {
+    pub beam: bool,
+    pub labels_returned: bool,
+    pub nodes_visited: bool,
+    pub leaf_labels_scored: bool,
+}
+
+impl LimitHits {
+    /// The first limit hit, in the order of [`Limit`] variants.
+    pub fn first(&self) -> Option<Limit> {
+        if self.beam {
+            Some(Limit::Beam)
+        } else if self.labels_returned {
+            Some(Limit::LabelsReturned)
+        } else if self.nodes_visited {
+            Some(Limit::NodesVisited)
+        } else if self.leaf_labels_scored {
+            Some(Limit::LeafLabelsScored)
+        } else {
+            None
+        }
+    }
+}
+
+/// The work left for the beam search of one prediction, shared by all trees.
+#[derive(Debug)]
+pub(crate) struct SearchBudget {
+    max_nodes_visited: Option<usize>,
+    max_leaf_labels_scored: Option<usize>,
+    n_nodes_visited: usize,
+    n_leaf_labels_scored: usize,
+    n_leaves_scored: usize,
+    /// The number of leaves scored before the tree being searched.
+    n_leaves_before_tree: usize,
+    /// The number of trees searched so far that scored no leaf since the budget ran out.
+    n_trees_starved: usize,
+    pub hits: LimitHits,
+}
+
+impl SearchBudget {
+    pub fn new(limits: &InferenceLimits) -> Self {
+        Self {
+            max_nodes_visited: limits.max_nodes_visited,
+            max_leaf_labels_scored: limits.max_leaf_labels_scored,
+            n_nodes_visited: 0,
+            n_leaf_labels_scored: 0,
+            n_leaves_scored: 0,
+            n_leaves_before_tree: 0,
+            n_trees_starved: 0,
+            hits: LimitHits::default(),
+        }
+    }
+
+    pub fn unlimited() -> Self {
+        Self::new(&InferenceLimits::default())
+    }
+
+    /// Whether the search may have to leave out some nodes or leaves, in which case nodes should
+    /// be visited from the highest-scoring one.
+    pub fn is_limited(&self) -> bool {
+        self.max_nodes_visited.is_some() || self.max_leaf_labels_scored.is_some()
+    }
+
+    /// Take the visit of a node from the budget, if there is any left.
+    pub fn take_node(&mut self) -> bool {
+        if let Some(max) = self.max_nodes_visited {
+            if self.n_nodes_visited >= max {
+                self.hits.nodes_visited = true;
+                return false;
+            }
+        }
+        self.n_nodes_visited += 1;
+        true
+    }
+
+    /// Take the visit of a leaf with `n_labels` labels from the budget, if there is enough left.
+    pub fn take_leaf(&mut self, n_labels: usize) -> bool {
+        if let Some(max) = self.max_leaf_labels_scored {
+            if self.n_leaf_labels_scored + n_labels > max {
+                self.hits.leaf_labels_scored = true;
+                return false;
+            }
+        }
+        if !self.take_node() {
+            return false;
+        }
+        self.n_leaf_labels_scored += n_labels;
+        self.n_leaves_scored += 1;
+        true
+    }
+
+    /// Record the end of the search of a tree.
+    pub fn finish_tree(&mut self) {
+        let ran_out = self.hits.nodes_visited || self.hits.leaf_labels_scored;
+        if ran_out && self.n_leaves_scored == self.n_leaves_before_tree {
+            self.n_trees_starved += 1;
+        }
+        self.n_leaves_before_tree = self.n_leaves_scored;
+    }
+
+    /// The number of trees to average label scores over, out of the `n_trees` of the model: all
+    /// but those that scored no leaf because the budget ran out, so that they don't count as
+    /// scoring every label 0.
+    pub fn n_trees_averaged(&self, n_trees: usize) -> usize {
+        (n_trees - self.n_trees_starved).max(1)
+    }
+}
+
+impl Model {
+    /// Set caps applied to every prediction made with the model.
+    ///
+    /// The limits are serialized with the model. They apply to [`Self::predict`] and checked
+    /// prediction, while [`Self::truncate_depth`] and [`Self::predict_from_nodes`] ignore them.
+    /// Invalid limits are an error, and leave the model's limits unchanged.
+    pub fn set_inference_limits(&mut self, limits: InferenceLimits) -> Result<(), String> {
+        limits.validate()?;
+        self.inference_limits = limits;
+        Ok(())
+    }
+
+    /// The caps applied to every prediction made with the model.
+    pub fn inference_limits(&self) -> &InferenceLimits {
+        &self.inference_limits
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mat_util::*;
+    use crate::model::{liblinear, PredictError, PredictOptions, Settings, TreeNode};
+    use crate::test_util::toy_model;
+    use crate::{Index, IndexValueVec};
+    use hashbrown::HashSet;
+
+    const N_FEATURES: usize = 4;
+    const WIDTH: usize = 50;
+    const FEATURE_VEC: [(Index, f32); 2] = [(0, 1.), (2, 0.5)];
+
+    /// A model whose root has many children, each a leaf with many labels.
+    fn wide_model() -> Model {
+        // The small per-column term keeps all scores distinct
+        let weights = |offset: usize| {
+            WeightMat::Dense(DenseMat::from_shape_fn(
+                (N_FEATURES + 1, WIDTH),
+                |(feature, col)| {
+                    ((feature * 7 + col * 3 + offset) % 11) as f32 / 10. + col as f32 / 1000.
+                },
+            ))
+        };
+        let children = (0..WIDTH)
+            .map(|i| TreeNode::Leaf {
+                weights: weights(i),
+                labels: (0..WIDTH).map(|j| (i * WIDTH + j) as Index).collect(),
+            })
+            .collect();
+        Model {
+            trees: vec![TreeNode::Branch {
+                weights: weights(0),
+                children,
+            }],
+            settings: Settings {
+                n_features: N_FEATURES,
+                classifier_loss_type: liblinear::LossType::Log,
+                feature_transform: Default::default(),
+                n_used_features: None,
+            },
+            label_thresholds: None,
+            training_metadata: Default::default(),
+            inference_limits: InferenceLimits::default(),
+            feature_projection: None,
+            input_profile: None,
+            label_graph: None,
+            original_labels: None,
+            label_names: None,
+            used_features: None,
+            sorted_labels: Default::default(),
+            label_index: Default::default(),
+        }
+    }
+
+    fn with_limits(limits: InferenceLimits) -> Model {
+        let mut model = wide_model();
+        model.set_inference_limits(limits).unwrap();
+        model
+    }
+
+    fn predict(model: &Model, beam_size: usize) -> Result<IndexValueVec, PredictError> {
+        let options = PredictOptions {
+            beam_size,
+            ..PredictOptions::default()
+        };
+        model.predict_with_options(&FEATURE_VEC, &options)
+    }
+
+    /// The leaves that predicted labels come from.
+    fn leaves_of(predictions: &IndexValueVec) -> HashSet<usize> {
+        predictions
+            .iter()
+            .map(|&(label, _)| label as usize / WIDTH)
+            .collect()
+    }
+
+    #[test]
+    fn test_validate() {
+        assert!(InferenceLimits::default().validate().is_ok());
+        let invalid = InferenceLimits {
+            max_nodes_visited: Some(0),
+            ..InferenceLimits::default()
+        };
+        assert!(invalid.validate().is_err());
+
+        let mut model = with_limits(InferenceLimits {
+            max_beam: Some(5),
+            ..InferenceLimits::default()
+        });
+        assert!(model.set_inference_limits(invalid).is_err());
+        assert_eq!(Some(5), model.inference_limits().max_beam);
+    }
+
+    #[test]
+    fn test_max_beam() {
+        let unlimited = predict(&wide_model(), 5).unwrap();
+        let model = with_limits(InferenceLimits {
+            max_beam: Some(5),
+            ..InferenceLimits::default()
+        });
+        assert_eq!(unlimited, predict(&model, 5).unwrap());
+        assert_eq!(unlimited, predict(&model, WIDTH).unwrap());
+        assert_eq!(unlimited, model.predict(&FEATURE_VEC, WIDTH));
+
+        let predictor = model
+            .predictor(PredictOptions {
+                beam_size: WIDTH,
+                ..PredictOptions::default()
+            })
+            .unwrap();
+        predictor.predict(&[(0, 1.)]).unwrap();
+        predictor.predict(&[(1, 1.)]).unwrap();
+        assert_eq!(2, predictor.stats().n_beam_limit_hits);
+        assert_eq!(0, predictor.stats().n_failed);
+    }
+
+    #[test]
+    fn test_max_labels_returned() {
+        let unlimited = predict(&wide_model(), 10).unwrap();
+        assert!(unlimited.len() > 3);
+        let model = with_limits(InferenceLimits {
+            max_labels_returned: Some(3),
+            ..InferenceLimits::default()
+        });
+        assert_eq!(&unlimited[..3], &predict(&model, 10).unwrap()[..]);
+        assert_eq!(&unlimited[..3], &model.predict(&FEATURE_VEC, 10)[..]);
+
+        // The limit is only hit if it cuts labels that were requested
+        for &(top_k, n_returned, n_hits) in &[(2, 2, 0), (3, 3, 0), (4, 3, 1)] {
+            let predictor = model
+                .predictor(PredictOptions {
+                    beam_size: 10,
+                    top_k: Some(top_k),
+                    ..PredictOptions::default()
+                })
+                .unwrap();
+            let predictions = predictor.predict(&FEATURE_VEC).unwrap();
+            assert_eq!(&unlimited[..n_returned], &predictions[..]);
+            assert_eq!(n_hits, predictor.stats().n_labels_returned_limit_hits);
+        }
+    }
+
+    #[test]
+    fn test_max_nodes_visited() {
+        // The root and the best two leaves fit in the budget
+        let model = with_limits(InferenceLimits {
+            max_nodes_visited: Some(3),
+            ..InferenceLimits::default()
+        });
+        let predictions = predict(&model, 10).unwrap();
+        assert_eq!(20, predictions.len());
+        assert_eq!(
+            leaves_of(&predict(&wide_model(), 2).unwrap()),
+            leaves_of(&predictions)
+        );
+
+        // Only the root fits, so no leaf is scored
+        let model = with_limits(InferenceLimits {
+            max_nodes_visited: Some(1),
+            ..InferenceLimits::default()
+        });
+        assert!(predict(&model, 10).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_trees_without_budget_not_averaged() {
+        // The first of two identical trees takes the whole budget, so the second scores no leaf
+        // and the scores are those of the first tree alone rather than halved
+        let limits = InferenceLimits {
+            max_nodes_visited: Some(3),
+            ..InferenceLimits::default()
+        };
+        let single = with_limits(limits);
+        let mut double = single.clone();
+        let tree = double.trees[0].clone();
+        double.trees_mut().push(tree);
+
+        let expected = predict(&single, 10).unwrap();
+        assert_eq!(20, expected.len());
+        assert_eq!(expected, predict(&double, 10).unwrap());
+        assert_eq!(expected, double.predict(&FEATURE_VEC, 10));
+        assert_eq!(
+            expected,
+            double.predict_with_aggregation(
+                &FEATURE_VEC,
+                10,
+                crate::model::predict::Aggregation::Mean
+            )
+        );
+
+        // Without limits, both trees are searched and averaged
+        let mut unlimited = double.clone();
+        unlimited
+            .set_inference_limits(InferenceLimits::default())
+            .unwrap();
+        assert_eq!(
+            predict(&wide_model(), 10).unwrap(),
+            predict(&unlimited, 10).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_max_leaf_labels_scored() {
+        // Only the best of the wide leaves fits in the budget
+        let model = with_limits(InferenceLimits {
+            max_leaf_labels_scored: Some(WIDTH + WIDTH / 2),
+            ..InferenceLimits::default()
+        });
+        let best_leaf_only = with_limits(InferenceLimits {
+            max_nodes_visited: Some(2),
+            ..InferenceLimits::default()
+        });
+        let predictions = model.predict(&FEATURE_VEC, WIDTH);
+        assert_eq!(WIDTH, predictions.len());
+        assert_eq!(best_leaf_only.predict(&FEATURE_VEC, WIDTH), predictions);
+
+        let predictor = model
+            .predictor(PredictOptions {
+                beam_size: WIDTH,
+                ..PredictOptions::default()
+            })
+            .unwrap();
+        predictor.predict(&FEATURE_VEC).unwrap();
+        let stats = predictor.stats();
+        assert_eq!(1, stats.n_leaf_labels_scored_limit_hits);
+        assert_eq!(0, stats.n_nodes_visited_limit_hits);
+    }
+
+    #[test]
+    fn test_error_policy() {
+        let limits = InferenceLimits {
+            max_nodes_visited: Some(3),
+            policy: LimitPolicy::Error,
+            ..InferenceLimits::default()
+        };
+        let model = with_limits(limits);
+        assert_eq!(
+            Err(PredictError::LimitExceeded {
+                limit: Limit::NodesVisited
+            }),
+            predict(&model, 10)
+        );
+        // Predictions within the limits succeed
+        assert!(predict(&model, 2).is_ok());
+        // The unchecked path truncates instead
+        let truncating = with_limits(InferenceLimits {
+            policy: LimitPolicy::Truncate,
+            ..limits
+        });
+        assert_eq!(
+            predict(&truncating, 10).unwrap(),
+            model.predict(&FEATURE_VEC, 10)
+        );
+
+        let predictor = model.predictor(PredictOptions::default()).unwrap();
+        assert!(predictor.predict(&FEATURE_VEC).is_err());
+        let stats = predictor.stats();
+        assert_eq!(1, stats.n_failed);
+        assert_eq!(1, stats.n_nodes_visited_limit_hits);
+    }
+
+    #[test]
+    fn test_limits_are_serialized() {
+        let mut model = toy_model(1, 0);
+        let limits = InferenceLimits {
+            max_beam: Some(3),
+            max_labels_returned: Some(2),
+            max_nodes_visited: Some(100),
+            max_leaf_labels_scored: Some(1000),
+            policy: LimitPolicy::Error,
+        };
+        model.set_inference_limits(limits).unwrap();
+
+        let dir = std::env::temp_dir().join(format!("omikuji-limits-{}", std::process::id()));
+        model.save(&dir).unwrap();
+        assert_eq!(&limits, Model::load(&dir).unwrap().inference_limits());
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let mut buf = Vec::new();
+        model.save_to_writer(&mut buf).unwrap();
+        let loaded = Model::load_from_reader(std::io::Cursor::new(&buf)).unwrap();
+        assert_eq!(&limits, loaded.inference_limits());
+        assert!(loaded.predict(&[(0, 1.)], 10).len() <= 2);
+    }
+}
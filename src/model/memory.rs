@@ -0,0 +1,226 @@
+//! Bookkeeping of the dominant allocations made during training, checked against a budget.
+//!
+//! Sizes are estimated from the data structures involved rather than measured by hooking into the
+//! allocator, so they cover the bulk of the memory used but not every small allocation.
+use super::train::TrainError;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::mem;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+/// A phase of training that memory is accounted for.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum MemoryPhase {
+    /// The training examples, held for the whole training.
+    Dataset,
+    /// Copies of the examples reaching each node, and their copies made for training classifiers.
+    ExampleCopies,
+    /// Label centroids and similarities used by k-means clustering.
+    Clustering,
+    /// Classifier weights accumulated in the trees trained so far.
+    ClassifierWeights,
+}
+
+impl MemoryPhase {
+    const ALL: [MemoryPhase; 4] = [
+        MemoryPhase::Dataset,
+        MemoryPhase::ExampleCopies,
+        MemoryPhase::Clustering,
+        MemoryPhase::ClassifierWeights,
+    ];
+
+    #[inline]
+    fn index(self) -> usize {
+        self as usize
+    }
+}
+
+impl fmt::Display for MemoryPhase {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MemoryPhase::Dataset => write!(f, "dataset"),
+            MemoryPhase::ExampleCopies => write!(f, "example copies"),
+            MemoryPhase::Clustering => write!(f, "clustering"),
+            MemoryPhase::ClassifierWeights => write!(f, "classifier weights"),
+        }
+    }
+}
+
+/// Memory accounted for during training, in bytes.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MemoryUsage {
+    /// The budget training was limited to, if any.
+    pub budget_bytes: Option<usize>,
+    /// The peak of all accounted memory in use at the same time.
+    pub peak_bytes: usize,
+    /// The peak of accounted memory in use for each phase, in the order of [`MemoryPhase`]
+    /// variants.
+    pub peak_bytes_by_phase: Vec<(MemoryPhase, usize)>,
+    /// The number of times nodes were trained one after another instead of in parallel to stay
+    /// within the budget.
+    pub n_sequential_fallbacks: usize,
+}
+
+/// A thread-safe tracker of accounted memory.
+#[derive(Debug, Default)]
+pub(crate) struct MemoryTracker {
+    budget: Option<usize>,
+    in_use: AtomicUsize,
+    peak: AtomicUsize,
+    in_use_by_phase: [AtomicUsize; 4],
+    peak_by_phase: [AtomicUsize; 4],
+    n_sequential_fallbacks: AtomicUsize,
+}
+
+impl MemoryTracker {
+    pub fn new(budget: Option<usize>) -> Arc<Self> {
+        Arc::new(Self {
+            budget,
+            ..Self::default()
+        })
+    }
+
+    /// Whether the given number of bytes can currently be reserved within the budget.
+    pub fn fits(&self, bytes: usize) -> bool {
+        self.budget.map_or(true, |budget| {
+            self.in_use.load(Ordering::SeqCst).saturating_add(bytes) <= budget
+        })
+    }
+
+    /// Account for an upcoming allocation, which is released when the reservation is dropped.
+    pub fn reserve(
+        self: &Arc<Self>,
+        phase: MemoryPhase,
+        bytes: usize,
+    ) -> Result<Reservation, TrainError> {
+        let mut in_use = self.in_use.load(Ordering::SeqCst);
+        loop {
+            let new_in_use = in_use.saturating_add(bytes);
+            if let Some(budget) = self.budget {
+                if new_in_use > budget {
+                    return Err(TrainError::MemoryBudgetExceeded {
+                        phase,
+                        requested_bytes: bytes,
+                        in_use_bytes: in_use,
+                        budget_bytes: budget,
+                    });
+                }
+            }
+            match self.in_use.compare_exchange_weak(
+                in_use,
+                new_in_use,
+                Ordering::SeqCst,
+                Ordering::SeqCst,
+            ) {
+                Ok(_) => break,
+                Err(current) => in_use = current,
+            }
+        }
+
+        self.peak
+            .fetch_max(in_use.saturating_add(bytes), Ordering::SeqCst);
+        let phase_in_use = self.in_use_by_phase[phase.index()].fetch_add(bytes, Ordering::SeqCst);
+        self.peak_by_phase[phase.index()].fetch_max(phase_in_use + bytes, Ordering::SeqCst);
+        Ok(Reservation {
+            tracker: Arc::clone(self),
+            phase,
+            bytes,
+        })
+    }
+
+    /// Record that work was serialized to stay within the budget.
+    pub fn record_sequential_fallback(&self) {
+        self.n_sequential_fallbacks.fetch_add(1, Ordering::SeqCst);
+    }
+
+    /// A report of the memory accounted for so far.
+    pub fn usage(&self) -> MemoryUsage {
+        MemoryUsage {
+            budget_bytes: self.budget,
+            peak_bytes: self.peak.load(Ordering::SeqCst),
+            peak_bytes_by_phase: MemoryPhase::ALL
+                .iter()
+                .map(|&phase| {
+                    (
+                        phase,
+                        self.peak_by_phase[phase.index()].load(Ordering::SeqCst),
+                    )
+                })
+                .collect(),
+            n_sequential_fallbacks: self.n_sequential_fallbacks.load(Ordering::SeqCst),
+        }
+    }
+}
+
+/// Memory accounted for in a tracker, released when dropped.
+#[derive(Debug)]
+pub(crate) struct Reservation {
+    tracker: Arc<MemoryTracker>,
+    phase: MemoryPhase,
+    bytes: usize,
+}
+
+impl Reservation {
+    /// Keep the memory accounted for until training ends, e.g., for weights that end up in the
+    /// model.
+    pub fn persist(self) {
+        mem::forget(self);
+    }
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        self.tracker.in_use.fetch_sub(self.bytes, Ordering::SeqCst);
+        self.tracker.in_use_by_phase[self.phase.index()].fetch_sub(self.bytes, Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_memory_tracker() {
+        let tracker = MemoryTracker::new(Some(100));
+        let dataset = tracker.reserve(MemoryPhase::Dataset, 60).unwrap();
+        assert!(tracker.fits(40));
+        assert!(!tracker.fits(41));
+
+        let copy = tracker.reserve(MemoryPhase::ExampleCopies, 30).unwrap();
+        assert_eq!(
+            TrainError::MemoryBudgetExceeded {
+                phase: MemoryPhase::Clustering,
+                requested_bytes: 20,
+                in_use_bytes: 90,
+                budget_bytes: 100,
+            },
+            tracker.reserve(MemoryPhase::Clustering, 20).unwrap_err()
+        );
+        drop(copy);
+        tracker
+            .reserve(MemoryPhase::ClassifierWeights, 40)
+            .unwrap()
+            .persist();
+        drop(dataset);
+        assert!(tracker.fits(60));
+        assert!(!tracker.fits(61));
+
+        let usage = tracker.usage();
+        assert_eq!(100, usage.peak_bytes);
+        assert_eq!(
+            vec![
+                (MemoryPhase::Dataset, 60),
+                (MemoryPhase::ExampleCopies, 30),
+                (MemoryPhase::Clustering, 0),
+                (MemoryPhase::ClassifierWeights, 40),
+            ],
+            usage.peak_bytes_by_phase
+        );
+
+        // Without a budget, everything fits
+        let tracker = MemoryTracker::new(None);
+        assert!(tracker.fits(usize::MAX));
+        tracker.reserve(MemoryPhase::Dataset, usize::MAX).unwrap();
+    }
+}
@@ -0,0 +1,156 @@
+//! Merging separately trained models into a single ensemble of trees.
+use super::train::TrainingMetadata;
+use super::Model;
+use log::{info, warn};
+use std::fmt;
+
+/// Why models couldn't be merged.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum MergeError {
+    /// No models were given.
+    NoModels,
+    /// The model at the given index differs from the first one in the named field, so their
+    /// trees don't take the same inputs.
+    Mismatch { model: usize, field: &'static str },
+}
+
+impl fmt::Display for MergeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            MergeError::NoModels => write!(f, "At least one model must be given to merge"),
+            MergeError::Mismatch { model, field } => write!(
+                f,
+                "Model {} has a different {} than model 0, so they can't be merged",
+                model, field
+            ),
+        }
+    }
+}
+
+impl std::error::Error for MergeError {}
+
+impl Model {
+    /// Merge separately trained models into one, whose trees are those of the given models, one
+    /// model after another.
+    ///
+    /// Models must take the same inputs, i.e., have the same number of features, classifier loss,
+    /// feature transform, feature projection, label mapping, label names, and used features. Since
+    /// predictions average over trees, the merged model predicts the average of the given models
+    /// weighted by their numbers of trees. Label thresholds, inference limits, the input profile,
+    /// and the label graph are taken from the first model.
+    pub fn merge(models: Vec<Model>) -> Result<Model, MergeError> {
+        let first = models.first().ok_or(MergeError::NoModels)?;
+        for (i, model) in models.iter().enumerate().skip(1) {
+            let mismatch = |field| Err(MergeError::Mismatch { model: i, field });
+            if model.settings.n_features != first.settings.n_features {
+                return mismatch("n_features");
+            }
+            if model.settings.classifier_loss_type != first.settings.classifier_loss_type {
+                return mismatch("classifier_loss_type");
+            }
+            if model.settings.feature_transform != first.settings.feature_transform {
+                return mismatch("feature_transform");
+            }
+            if model.feature_projection != first.feature_projection {
+                return mismatch("feature_projection");
+            }
+            if model.original_labels != first.original_labels {
+                return mismatch("original_labels");
+            }
+            if model.label_names != first.label_names {
+                return mismatch("label_names");
+            }
+            if model.used_features != first.used_features {
+                return mismatch("used_features");
+            }
+            if model.label_thresholds != first.label_thresholds
+                || model.inference_limits != first.inference_limits
+            {
+                warn!(
+                    "Model {} has different label thresholds or inference limits than model 0; \
+                     those of model 0 are kept",
+                    i
+                );
+            }
+        }
+
+        let training_metadata = TrainingMetadata::concat(
+            &models
+                .iter()
+                .map(|model| (&model.training_metadata, model.trees.len()))
+                .collect::<Vec<_>>(),
+        );
+        let n_models = models.len();
+        let mut models = models.into_iter();
+        let mut merged = models.next().expect("Models should not be empty");
+        merged.training_metadata = training_metadata;
+        for model in models {
+            merged.trees_mut().extend(model.trees);
+        }
+
+        info!(
+            "Merged {} models into one with {} trees",
+            n_models,
+            merged.trees.len()
+        );
+        Ok(merged)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::schema::FeatureTransform;
+    use crate::test_util::{toy_dataset, toy_model};
+    use assert_approx_eq::assert_approx_eq;
+    use hashbrown::HashMap;
+
+    #[test]
+    fn test_merge() {
+        let models = (0..3).map(|seed| toy_model(1, seed)).collect::<Vec<_>>();
+        let merged = Model::merge(models.clone()).unwrap();
+        assert_eq!(3, merged.n_trees());
+
+        for feature_vec in &toy_dataset(20, 8, 3).feature_lists {
+            let mut expected = HashMap::new();
+            for model in &models {
+                for (label, score) in model.predict(feature_vec, 100) {
+                    *expected.entry(label).or_insert(0.) += score / models.len() as f32;
+                }
+            }
+            let predictions = merged.predict(feature_vec, 100);
+            assert_eq!(expected.len(), predictions.len());
+            for (label, score) in predictions {
+                assert_approx_eq!(expected[&label], score, 1e-6);
+            }
+        }
+    }
+
+    #[test]
+    fn test_merge_mismatch() {
+        assert_eq!(Some(MergeError::NoModels), Model::merge(vec![]).err());
+
+        let model = toy_model(1, 0);
+        let mut other = toy_model(1, 1);
+        other.settings.feature_transform = FeatureTransform::Identity;
+        let error = Model::merge(vec![model.clone(), model.clone(), other]).unwrap_err();
+        assert_eq!(
+            MergeError::Mismatch {
+                model: 2,
+                field: "feature_transform"
+            },
+            error
+        );
+        assert!(error.to_string().contains("feature_transform"));
+
+        let mut other = toy_model(1, 1);
+        other.settings.n_features += 1;
+        assert_eq!(
+            Some(MergeError::Mismatch {
+                model: 1,
+                field: "n_features"
+            }),
+            Model::merge(vec![model, other]).err()
+        );
+    }
+}
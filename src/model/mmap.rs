@@ -0,0 +1,474 @@
+//! A binary model format whose weights can be memory-mapped and used for prediction as is.
+//!
+//! # Layout
+//!
+//! All integers are little-endian.
+//!
+//! | Offset        | Content                                                          |
+//! |---------------|------------------------------------------------------------------|
+//! | 0             | Magic bytes [`MODEL_MMAP_MAGIC`]                                 |
+//! | 8             | Length of the JSON header as `u64`                               |
+//! | 16            | JSON header with the manifest and the layout of the trees        |
+//! | aligned to 8  | Arrays of weight matrices, each aligned to 8 bytes               |
+//!
+//! Dense matrices are stored as their values of `f32` in row-major order, and sparse matrices as
+//! the arrays of [`LilMat`], with indices as `u64` or `u32`. The layout of the trees in the
+//! header gives the offsets of the arrays from the start of the arrays. Quantized and
+//! half-precision matrices are small, so they're stored in the header as is.
+use super::framed::Manifest;
+use super::{compact, io_sink, version, Model, TreeNode};
+use crate::mat_util::*;
+use crate::Index;
+use log::info;
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::fs::File;
+use std::io::{self, BufReader, Error, ErrorKind, Read, Write};
+use std::path::Path;
+use std::sync::Arc;
+use std::time;
+
+/// Magic bytes at the start of a memory-mappable model file, the last of which is the format
+/// version.
+pub(crate) const MODEL_MMAP_MAGIC: &[u8; 8] = b"OMKJMDL1";
+
+#[derive(Serialize, Deserialize)]
+struct Header {
+    manifest: Manifest,
+    trees: Vec<NodeLayout>,
+}
+
+#[derive(Serialize, Deserialize)]
+enum NodeLayout {
+    Branch {
+        weights: MatLayout,
+        children: Vec<NodeLayout>,
+    },
+    Leaf {
+        weights: MatLayout,
+        labels: Vec<Index>,
+    },
+}
+
+/// Where the arrays of a weight matrix are, as offsets from the start of the arrays.
+#[derive(Serialize, Deserialize)]
+enum MatLayout {
+    Dense {
+        shape: (usize, usize),
+        values: usize,
+    },
+    Sparse {
+        shape: (usize, usize),
+        n_outer: usize,
+        nnz: usize,
+        indptr: usize,
+        outer_inds: usize,
+        inner_inds: usize,
+        data: usize,
+    },
+    /// A matrix that isn't mapped, stored in the header.
+    Inline(WeightMat),
+}
+
+#[inline]
+fn align_to_8(offset: usize) -> usize {
+    (offset + 7) / 8 * 8
+}
+
+enum Array<'a> {
+    Usize(&'a [usize]),
+    Index(&'a [Index]),
+    F32(Cow<'a, [f32]>),
+}
+
+impl Array<'_> {
+    fn n_bytes(&self) -> usize {
+        match self {
+            Array::Usize(values) => 8 * values.len(),
+            Array::Index(values) => 4 * values.len(),
+            Array::F32(values) => 4 * values.len(),
+        }
+    }
+
+    fn write(&self, writer: &mut dyn Write) -> io::Result<()> {
+        match self {
+            Array::Usize(values) => {
+                for &v in values.iter() {
+                    writer.write_all(&(v as u64).to_le_bytes())?;
+                }
+            }
+            Array::Index(values) => {
+                for &v in values.iter() {
+                    writer.write_all(&v.to_le_bytes())?;
+                }
+            }
+            Array::F32(values) => {
+                for &v in values.iter() {
+                    writer.write_all(&v.to_le_bytes())?;
+                }
+            }
+        }
+        writer.write_all(&[0u8; 8][..align_to_8(self.n_bytes()) - self.n_bytes()])
+    }
+}
+
+/// The arrays of weight matrices in the order they're written.
+#[derive(Default)]
+struct ArrayLayout<'a> {
+    arrays: Vec<Array<'a>>,
+    n_bytes: usize,
+}
+
+impl<'a> ArrayLayout<'a> {
+    /// Add an array, returning its offset.
+    fn push(&mut self, array: Array<'a>) -> usize {
+        let offset = self.n_bytes;
+        self.n_bytes += align_to_8(array.n_bytes());
+        self.arrays.push(array);
+        offset
+    }
+
+    fn add_weights(&mut self, weights: &'a WeightMat) -> MatLayout {
+        match weights.flat_arrays() {
+            Some(FlatArrays::Dense { shape, values }) => MatLayout::Dense {
+                shape,
+                values: self.push(Array::F32(values)),
+            },
+            Some(FlatArrays::Sparse {
+                shape,
+                indptr,
+                outer_inds,
+                inner_inds,
+                data,
+            }) => MatLayout::Sparse {
+                shape,
+                n_outer: outer_inds.len(),
+                nnz: data.len(),
+                indptr: self.push(Array::Usize(indptr)),
+                outer_inds: self.push(Array::Index(outer_inds)),
+                inner_inds: self.push(Array::Index(inner_inds)),
+                data: self.push(Array::F32(Cow::Borrowed(data))),
+            },
+            None => MatLayout::Inline(weights.clone()),
+        }
+    }
+
+    fn add_tree(&mut self, node: &'a TreeNode) -> NodeLayout {
+        match node {
+            TreeNode::Branch { weights, children } => NodeLayout::Branch {
+                weights: self.add_weights(weights),
+                children: children.iter().map(|child| self.add_tree(child)).collect(),
+            },
+            TreeNode::Leaf { weights, labels } => NodeLayout::Leaf {
+                weights: self.add_weights(weights),
+                labels: labels.clone(),
+            },
+        }
+    }
+}
+
+/// Creates matrices whose arrays are in a mapped file.
+struct Mapper {
+    mmap: Arc<memmap2::Mmap>,
+    arrays_start: usize,
+}
+
+impl Mapper {
+    /// The array of the given length at the given offset from the start of the arrays.
+    fn array<T>(&self, offset: usize, len: usize) -> io::Result<&[T]> {
+        let start = self.arrays_start.checked_add(offset);
+        let end =
+            start.and_then(|start| start.checked_add(len.checked_mul(std::mem::size_of::<T>())?));
+        match (start, end) {
+            (Some(start), Some(end))
+                if end <= self.mmap.len() && start % std::mem::align_of::<T>() == 0 =>
+            {
+                // Safety: the array is within the mapping as checked above, and it's properly
+                // aligned because the mapping is page-aligned. The array borrows from `self`,
+                // which keeps the mapping alive.
+                Ok(unsafe {
+                    std::slice::from_raw_parts(self.mmap.as_ptr().add(start) as *const T, len)
+                })
+            }
+            _ => Err(Error::new(
+                ErrorKind::InvalidData,
+                "Array out of range or misaligned",
+            )),
+        }
+    }
+
+    fn weights(&self, layout: MatLayout) -> io::Result<WeightMat> {
+        let arrays = match layout {
+            MatLayout::Dense { shape, values } => FlatArrays::Dense {
+                shape,
+                values: Cow::Borrowed(self.array(values, shape.0 * shape.1)?),
+            },
+            MatLayout::Sparse {
+                shape,
+                n_outer,
+                nnz,
+                indptr,
+                outer_inds,
+                inner_inds,
+                data,
+            } => FlatArrays::Sparse {
+                shape,
+                indptr: self.array(indptr, n_outer + 1)?,
+                outer_inds: self.array(outer_inds, n_outer)?,
+                inner_inds: self.array(inner_inds, nnz)?,
+                data: self.array(data, nnz)?,
+            },
+            MatLayout::Inline(weights) => return Ok(weights),
+        };
+        // Safety: the arrays are within the mapping, as checked by `Self::array`
+        unsafe { MappedMat::new(arrays, self.mmap.clone()) }
+            .map(WeightMat::Mapped)
+            .map_err(|e| Error::new(ErrorKind::InvalidData, e))
+    }
+
+    fn tree(&self, layout: NodeLayout) -> io::Result<TreeNode> {
+        Ok(match layout {
+            NodeLayout::Branch { weights, children } => TreeNode::Branch {
+                weights: self.weights(weights)?,
+                children: children
+                    .into_iter()
+                    .map(|child| self.tree(child))
+                    .collect::<io::Result<_>>()?,
+            },
+            NodeLayout::Leaf { weights, labels } => TreeNode::Leaf {
+                weights: self.weights(weights)?,
+                labels,
+            },
+        })
+    }
+}
+
+impl Model {
+    /// Write the model in a binary format that can be loaded with [`Self::load_mmap`].
+    ///
+    /// Unlike the other formats, the file has no checksum, so corruption is only detected where
+    /// it makes the header or the layout of the arrays invalid.
+    pub fn save_mmap<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let path = path.as_ref();
+        info!("Saving model to {}", path.display());
+        let start_t = time::Instant::now();
+
+        let mut layout = ArrayLayout::default();
+        let trees = self
+            .trees
+            .iter()
+            .map(|tree| layout.add_tree(tree))
+            .collect();
+        let header = serde_json::to_vec(&Header {
+            manifest: self.manifest(self.trees.len()),
+            trees,
+        })
+        .map_err(|e| {
+            Error::new(
+                ErrorKind::Other,
+                format!("Unable to serialize header: {}", e),
+            )
+        })?;
+
+        io_sink::write_atomically(&io_sink::FileSink, path, |writer| {
+            writer.write_all(MODEL_MMAP_MAGIC)?;
+            writer.write_all(&(header.len() as u64).to_le_bytes())?;
+            writer.write_all(&header)?;
+            let header_end = MODEL_MMAP_MAGIC.len() + 8 + header.len();
+            writer.write_all(&vec![0u8; align_to_8(header_end) - header_end])?;
+            for array in &layout.arrays {
+                array.write(writer)?;
+            }
+            Ok(())
+        })?;
+
+        info!(
+            "Model saved; it took {:.2}s",
+            start_t.elapsed().as_secs_f32()
+        );
+        Ok(())
+    }
+
+    /// Load a model with weights memory-mapped from a file written by [`Self::save_mmap`].
+    ///
+    /// Only the header is parsed; weights stay in the mapped file, which must not be modified
+    /// while the returned model is in use, and predictions read them directly from there.
+    /// Processes that map the same file share its memory. Memory-mapped files are only supported
+    /// on 64-bit little-endian platforms, where their layout matches the in-memory one.
+    ///
+    /// Models in other formats are loaded as with [`Self::load`] if the path is a directory, or
+    /// with [`Self::load_from_reader`] otherwise.
+    ///
+    /// # Safety
+    ///
+    /// The file must not be modified or truncated, by this process or any other, while the
+    /// returned model or any matrix cloned from it is alive. Doing so is undefined behavior, as
+    /// the weights would change under shared references to them, or accessing them would fault.
+    pub unsafe fn load_mmap<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let path = path.as_ref();
+        if path.is_dir() {
+            return Self::load(path);
+        }
+
+        let mut file = File::open(path)?;
+        let mut magic = Vec::with_capacity(MODEL_MMAP_MAGIC.len());
+        file.by_ref()
+            .take(MODEL_MMAP_MAGIC.len() as u64)
+            .read_to_end(&mut magic)?;
+        if !version::check_magic(&magic, MODEL_MMAP_MAGIC)? {
+            info!("Model file is not memory-mappable; loading it as a stream");
+            return Self::load_from_reader(BufReader::new(File::open(path)?));
+        }
+        if !cfg!(all(target_endian = "little", target_pointer_width = "64")) {
+            return Err(Error::new(
+                ErrorKind::Unsupported,
+                "Memory-mapped models are only supported on 64-bit little-endian platforms",
+            ));
+        }
+
+        let start_t = time::Instant::now();
+        info!("Mapping model from {}", path.display());
+        // Safety: the caller guarantees that the file is not modified while it's mapped
+        let mmap = memmap2::Mmap::map(&file)?;
+
+        let invalid_data = |msg: &str| Error::new(ErrorKind::InvalidData, msg.to_owned());
+        if mmap.len() < MODEL_MMAP_MAGIC.len() + 8 {
+            return Err(invalid_data("Truncated header"));
+        }
+        let header_len = {
+            let mut buf = [0u8; 8];
+            buf.copy_from_slice(&mmap[MODEL_MMAP_MAGIC.len()..MODEL_MMAP_MAGIC.len() + 8]);
+            u64::from_le_bytes(buf) as usize
+        };
+        let header_start = MODEL_MMAP_MAGIC.len() + 8;
+        let header_end = header_start
+            .checked_add(header_len)
+            .filter(|&end| end <= mmap.len())
+            .ok_or_else(|| invalid_data("Truncated header"))?;
+        let Header { manifest, trees } = serde_json::from_slice(&mmap[header_start..header_end])
+            .map_err(|e| {
+                Error::new(
+                    ErrorKind::InvalidData,
+                    format!("Unable to parse header: {}", e),
+                )
+            })?;
+        if trees.len() != manifest.n_trees {
+            return Err(invalid_data("Number of trees doesn't match the manifest"));
+        }
+        compact::check_used_features(manifest.settings, manifest.used_features.as_deref())?;
+
+        let mapper = Mapper {
+            mmap: Arc::new(mmap),
+            arrays_start: align_to_8(header_end),
+        };
+        let settings = manifest.settings;
+        let trees = trees
+            .into_iter()
+            .enumerate()
+            .map(|(i, layout)| {
+                let tree = mapper.tree(layout)?;
+                if !tree.is_valid(settings) {
+                    return Err(Error::new(
+                        ErrorKind::InvalidData,
+                        format!("Tree {} is invalid", i),
+                    ));
+                }
+                Ok(tree)
+            })
+            .collect::<io::Result<Vec<_>>>()?;
+
+        info!(
+            "Mapped model with {} trees; it took {:.2}s",
+            trees.len(),
+            start_t.elapsed().as_secs_f32()
+        );
+        Self::from_manifest(manifest, trees)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{toy_dataset, toy_model};
+
+    fn assert_same_predictions(expected: &Model, actual: &Model) {
+        for feature_vec in &toy_dataset(20, 8, 1).feature_lists {
+            assert_eq!(
+                expected.predict(feature_vec, 3),
+                actual.predict(feature_vec, 3)
+            );
+        }
+    }
+
+    fn is_mapped(model: &Model) -> bool {
+        let mut is_mapped = true;
+        for tree in &model.trees {
+            tree.visit_weights(&mut |weights| is_mapped &= matches!(weights, WeightMat::Mapped(_)));
+        }
+        is_mapped
+    }
+
+    #[test]
+    fn test_save_and_load_mmap() {
+        let path = std::env::temp_dir().join(format!("omikuji-model-mmap-{}", std::process::id()));
+        let mut model = toy_model(2, 0);
+        // Keep dense and sparse matrices in different trees
+        model.trees_mut()[0].densify_weights(0.);
+
+        model.save_mmap(&path).unwrap();
+        let mapped = unsafe { Model::load_mmap(&path) }.unwrap();
+        assert!(is_mapped(&mapped));
+        assert_eq!(model.n_labels(), mapped.n_labels());
+        assert_eq!(model.nnz_weights(), mapped.nnz_weights());
+        assert_eq!(model.mem_size(), mapped.mem_size());
+        assert_same_predictions(&model, &mapped);
+
+        // Mapped weights are saved as the matrices they map
+        let mut buffer = Vec::new();
+        mapped.save_to_writer(&mut buffer).unwrap();
+        let loaded = Model::load_from_reader(buffer.as_slice()).unwrap();
+        assert!(!is_mapped(&loaded));
+        assert_same_predictions(&model, &loaded);
+
+        // Modifying mapped weights copies them
+        let mut pruned = mapped.clone();
+        pruned.prune_weights(0.);
+        assert!(!is_mapped(&pruned));
+        assert_same_predictions(&model, &pruned);
+
+        // Quantized weights are kept in the header
+        let mut quantized = model.clone();
+        quantized.quantize(8).unwrap();
+        quantized.save_mmap(&path).unwrap();
+        assert_same_predictions(&quantized, &unsafe { Model::load_mmap(&path) }.unwrap());
+
+        // Models in other formats are loaded as usual
+        model.save_to_writer(File::create(&path).unwrap()).unwrap();
+        let loaded = unsafe { Model::load_mmap(&path) }.unwrap();
+        assert!(!is_mapped(&loaded));
+        assert_same_predictions(&model, &loaded);
+        std::fs::remove_file(&path).unwrap();
+
+        model.save(&path).unwrap();
+        assert_same_predictions(&model, &unsafe { Model::load_mmap(&path) }.unwrap());
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn test_load_mmap_truncated() {
+        let path = std::env::temp_dir().join(format!(
+            "omikuji-model-mmap-truncated-{}",
+            std::process::id()
+        ));
+        let model = toy_model(1, 0);
+        model.save_mmap(&path).unwrap();
+        let len = std::fs::metadata(&path).unwrap().len();
+        let file = std::fs::OpenOptions::new().write(true).open(&path).unwrap();
+        file.set_len(len - 16).unwrap();
+        drop(file);
+
+        let error = unsafe { Model::load_mmap(&path) }.unwrap_err();
+        assert_eq!(ErrorKind::InvalidData, error.kind());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
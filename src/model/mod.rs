@@ -1,8 +1,52 @@
+#[cfg(feature = "async")]
+pub mod async_predict;
+pub mod bench;
+pub mod cascade;
+mod checkpoint;
+mod checksum;
 pub mod cluster;
+mod compact;
+pub mod conformance;
+pub mod density;
+pub mod drift;
+mod embeddings;
+pub mod ensemble;
 pub mod eval;
+mod explain;
+pub mod feature_vec;
+mod framed;
+pub mod handle;
+pub mod hash;
+mod io_sink;
+pub mod label_graph;
+mod label_names;
+pub mod label_tree;
 pub mod liblinear;
+pub mod limits;
+pub mod memory;
+mod merge;
+mod mmap;
+pub mod multi_beam;
+pub mod predict;
+pub mod prewarm;
+pub mod projection;
+#[cfg(test)]
+mod proptests;
+pub mod prune;
+mod quantize;
+pub mod registry;
+pub mod schema;
+pub mod scores;
+pub mod stats;
+pub mod subset;
+pub mod text;
+pub mod thresholds;
+pub mod trace;
 pub mod train;
+pub mod tune;
+pub mod version;
 
+use crate::index::{to_index, IndexKind};
 use crate::mat_util::*;
 use crate::{Index, IndexValueVec};
 use hashbrown::HashMap;
@@ -11,18 +55,42 @@ use log::{info, warn};
 use ordered_float::NotNan;
 use rayon::prelude::*;
 use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
 use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::fmt;
 use std::io;
-use std::mem::swap;
+use std::mem::{self, swap};
+use std::sync::OnceLock;
 use std::time;
 
 /// Model training hyper-parameters.
 pub type TrainHyperParam = train::HyperParam;
 
+pub use feature_vec::FeatureVecError;
+pub use framed::{LoadProgress, SaveProgress};
+pub use merge::MergeError;
+pub use predict::{PredictError, PredictOptions, Predictor};
+pub use quantize::{SaveOptions, WeightPrecision};
+pub use train::TrainOptions;
+
 #[derive(Eq, PartialEq, Clone, Copy, Debug, Serialize, Deserialize)]
 struct Settings {
     n_features: usize,
     classifier_loss_type: liblinear::LossType,
+    #[serde(default)]
+    feature_transform: schema::FeatureTransform,
+    /// The number of features with weights, if fewer than `n_features` after
+    /// [`Model::compact_features`]; weight matrices have one row for each, plus the bias.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    n_used_features: Option<usize>,
+}
+
+impl Settings {
+    /// The number of rows of weight matrices, without the bias.
+    fn n_weight_features(&self) -> usize {
+        self.n_used_features.unwrap_or(self.n_features)
+    }
 }
 
 /// A Omikuji model, which contains a forest of trees.
@@ -30,10 +98,143 @@ struct Settings {
 pub struct Model {
     trees: Vec<TreeNode>,
     settings: Settings,
+    #[serde(default)]
+    label_thresholds: Option<thresholds::LabelThresholds>,
+    #[serde(default)]
+    training_metadata: train::TrainingMetadata,
+    #[serde(default)]
+    inference_limits: limits::InferenceLimits,
+    #[serde(default)]
+    feature_projection: Option<projection::ProjectionParams>,
+    #[serde(default)]
+    input_profile: Option<drift::InputProfile>,
+    #[serde(default)]
+    label_graph: Option<label_graph::LabelGraph>,
+    #[serde(default)]
+    original_labels: Option<Vec<Index>>,
+    #[serde(default)]
+    label_names: Option<Vec<String>>,
+    #[serde(default)]
+    used_features: Option<Vec<Index>>,
+    /// The distinct labels of the trees, sorted; collected on first use.
+    #[serde(skip)]
+    sorted_labels: OnceLock<Vec<Index>>,
+    /// The leaves of each label, for predicting in label subsets; built on first use.
+    #[serde(skip)]
+    label_index: OnceLock<subset::LabelIndex>,
 }
 
 static MODEL_SETTINGS_FILE_NAME: &str = "settings.json";
+static LABEL_THRESHOLDS_FILE_NAME: &str = "label_thresholds.json";
+static TRAINING_METADATA_FILE_NAME: &str = "training_metadata.json";
+static INFERENCE_LIMITS_FILE_NAME: &str = "inference_limits.json";
+static FEATURE_PROJECTION_FILE_NAME: &str = "feature_projection.json";
+static INPUT_PROFILE_FILE_NAME: &str = "input_profile.json";
+static LABEL_GRAPH_FILE_NAME: &str = "label_graph.json";
+static ORIGINAL_LABELS_FILE_NAME: &str = "original_labels.json";
+static LABEL_NAMES_FILE_NAME: &str = "label_names.json";
+static USED_FEATURES_FILE_NAME: &str = "used_features.json";
 static TREE_FILE_NAME_PREFIX: &str = "tree";
+static FORMAT_HEADER_FILE_NAME: &str = "format.json";
+static SAVE_MARKER_FILE_NAME: &str = "save_in_progress";
+
+/// Fail if a save into the model directory was interrupted, in which case its files may be
+/// missing or from different saves.
+fn check_save_completed(dir_path: &std::path::Path) -> io::Result<()> {
+    if dir_path.join(SAVE_MARKER_FILE_NAME).exists() {
+        Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "A save into model directory {} was interrupted, so the model in it is incomplete",
+                dir_path.display()
+            ),
+        ))
+    } else {
+        Ok(())
+    }
+}
+
+/// Paths of the tree files in a model directory, in the order of their indices.
+fn tree_file_paths(dir_path: &std::path::Path) -> io::Result<Vec<std::path::PathBuf>> {
+    let mut paths = Vec::new();
+    for entry in dir_path.read_dir()? {
+        let entry = entry?;
+        let file_name = entry.file_name();
+        let file_name_str = file_name.to_string_lossy();
+        if let Some(index) = file_name_str
+            .strip_prefix(TREE_FILE_NAME_PREFIX)
+            .and_then(|s| s.strip_suffix(".cbor"))
+        {
+            // Files not named by an index, which we never write, go last
+            paths.push((index.parse::<usize>().unwrap_or(usize::MAX), entry.path()));
+        }
+    }
+    paths.sort_unstable();
+    Ok(paths.into_iter().map(|(_, path)| path).collect())
+}
+
+/// Load the tree in the given file, checking the file against the given checksum, if any.
+fn load_tree_file(
+    tree_path: &std::path::Path,
+    settings: Settings,
+    expected_checksum: Option<&str>,
+) -> io::Result<TreeNode> {
+    info!("Loading tree from {}...", tree_path.display());
+    let mut file = checksum::Hashed::new(std::fs::File::open(tree_path)?);
+    let (reader, _) = crate::data::compression::decompress(&mut file)?;
+    let tree: TreeNode = serde_cbor::from_reader(reader).map_err(|e| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "Unable to deserialize tree from {} with error: {}",
+                tree_path.display(),
+                e
+            ),
+        )
+    })?;
+    if let Some(expected_checksum) = expected_checksum {
+        // Anything left after the tree is part of the file too
+        io::copy(&mut file, &mut io::sink())?;
+        checksum::check_digest(
+            &file.digest(),
+            expected_checksum,
+            &tree_path.display().to_string(),
+        )?;
+    }
+    if !tree.is_valid(settings) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Tree loaded from {} is invalid", tree_path.display()),
+        ));
+    }
+    Ok(tree)
+}
+
+/// Read the format header of a model directory and check that it can be read, if it has one.
+///
+/// A directory without a header holds a model saved before headers were written, unless a save
+/// into it was interrupted, which is an error; every save marks the directory until its header is
+/// written, so interrupted saves are never mistaken for legacy models.
+fn read_format_header(dir_path: &std::path::Path) -> io::Result<Option<version::FormatHeader>> {
+    check_save_completed(dir_path)?;
+    let header_path = dir_path.join(FORMAT_HEADER_FILE_NAME);
+    if !header_path.exists() {
+        return Ok(None);
+    }
+    let reader = std::io::BufReader::new(std::fs::File::open(header_path)?);
+    let header: version::FormatHeader = serde_json::from_reader(reader).map_err(|e| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Unable to deserialize model format header: {}", e),
+        )
+    })?;
+    header.check_version()?;
+    Ok(Some(header))
+}
+
+/// Inputs with a smaller l2 norm are treated as all-zero; below it, squares of feature values can
+/// underflow, so the norm may be zero even if some values aren't.
+const MIN_INPUT_NORM: f32 = 1.0842022e-19; // sqrt(f32::MIN_POSITIVE)
 
 impl Model {
     /// Returns a ranked list of predictions for the given input example.
@@ -43,27 +244,632 @@ impl Model {
     /// * `feature_vec` - An input vector for prediction, assumed to be ordered by indices and have
     /// no duplicate or out-of-range indices
     /// * `beam_size` - Beam size for beam search.
-    pub fn predict(&self, feature_vec: &[(Index, f32)], beam_size: usize) -> IndexValueVec {
+    ///
+    /// Trees are searched one after another on the calling thread, without Rayon, so predicting
+    /// from within parallel code doesn't oversubscribe threads.
+    ///
+    /// A model without trees predicts no labels. Panics if a classifier scores NaN, e.g., for an
+    /// input with infinite values; [`Self::predict_with_options`] returns an error instead.
+    pub fn predict(
+        &self,
+        feature_vec: impl AsRef<[(Index, f32)]>,
+        beam_size: usize,
+    ) -> IndexValueVec {
+        let feature_vec = self.prepare_feature_vec(feature_vec.as_ref());
+        self.predict_prepared(&feature_vec, beam_size)
+    }
+
+    /// Like [`Self::predict`], but returns the predictions in no particular order, which may
+    /// differ between calls, skipping the cost of ranking them, e.g., for callers that rank
+    /// predictions themselves.
+    ///
+    /// The labels and scores returned are the same as those of [`Self::predict`], including under
+    /// the model's inference limits on the number of labels returned. Panics as [`Self::predict`]
+    /// does; [`Self::predict_unsorted_checked`] returns an error instead.
+    pub fn predict_unsorted(
+        &self,
+        feature_vec: impl AsRef<[(Index, f32)]>,
+        beam_size: usize,
+    ) -> IndexValueVec {
+        let feature_vec = self.prepare_feature_vec(feature_vec.as_ref());
+        self.search_trees(
+            &feature_vec,
+            beam_size,
+            predict::LeafTransform::Exp,
+            None,
+            false,
+            &mut SearchBuffers::default(),
+        )
+        .map(|(predictions, _)| predictions)
+        .unwrap_or_else(|(_, message)| panic!("Corrupt tree: {}", message))
+    }
+
+    /// Like [`Self::predict_unsorted`], but checks the input and reports problems as
+    /// [`Self::predict_checked`] does.
+    pub fn predict_unsorted_checked(
+        &self,
+        feature_vec: impl AsRef<[(Index, f32)]>,
+        beam_size: usize,
+    ) -> Result<IndexValueVec, PredictError> {
+        let options = self.checked_options(beam_size, None)?;
+        self.predict_validated(
+            feature_vec.as_ref(),
+            &options,
+            false,
+            &mut predict::PrepareBuffers::default(),
+            &mut SearchBuffers::default(),
+        )
+    }
+
+    /// Returns the `top_k` highest ranked predictions for the given input example, as with
+    /// [`Self::predict`].
+    ///
+    /// The best labels are selected with a bounded heap after aggregating scores across trees,
+    /// rather than by sorting every label reached. With a `top_k` of 0 or `usize::MAX`, all
+    /// predictions are returned as with [`Self::predict`]. Panics as [`Self::predict`] does;
+    /// [`Self::predict_top_k_checked`] returns an error instead.
+    pub fn predict_top_k(
+        &self,
+        feature_vec: impl AsRef<[(Index, f32)]>,
+        beam_size: usize,
+        top_k: usize,
+    ) -> IndexValueVec {
+        let feature_vec = self.prepare_feature_vec(feature_vec.as_ref());
+        let top_k = Some(top_k).filter(|&k| k != 0 && k != usize::MAX);
+        self.predict_prepared_with(
+            &feature_vec,
+            beam_size,
+            top_k,
+            &mut SearchBuffers::default(),
+        )
+    }
+
+    /// Like [`Self::predict_top_k`], but checks the input and reports problems as
+    /// [`Self::predict_checked`] does.
+    pub fn predict_top_k_checked(
+        &self,
+        feature_vec: impl AsRef<[(Index, f32)]>,
+        beam_size: usize,
+        top_k: usize,
+    ) -> Result<IndexValueVec, PredictError> {
+        let top_k = Some(top_k).filter(|&k| k != 0 && k != usize::MAX);
+        let options = self.checked_options(beam_size, top_k)?;
+        self.predict_validated(
+            feature_vec.as_ref(),
+            &options,
+            true,
+            &mut predict::PrepareBuffers::default(),
+            &mut SearchBuffers::default(),
+        )
+    }
+
+    /// Returns the predictions of each tree for the given input example, in the order of the
+    /// trees, before they are averaged.
+    ///
+    /// Each list holds the labels reached in a tree with their scores in that tree, ranked by
+    /// decreasing score with ties broken by increasing label. [`Self::predict`] returns the
+    /// scores of labels averaged over these lists, where labels missing from a tree count as 0.
+    ///
+    /// Panics as [`Self::predict`] does; [`Self::predict_per_tree_checked`] returns an error
+    /// instead.
+    pub fn predict_per_tree(
+        &self,
+        feature_vec: impl AsRef<[(Index, f32)]>,
+        beam_size: usize,
+    ) -> Vec<IndexValueVec> {
+        let feature_vec = self.prepare_feature_vec(feature_vec.as_ref());
+        self.search_per_tree(&feature_vec, beam_size)
+            .unwrap_or_else(|(_, message)| panic!("Corrupt tree: {}", message))
+            .0
+    }
+
+    /// Like [`Self::predict_per_tree`], but checks the input and reports problems as
+    /// [`Self::predict_checked`] does.
+    pub fn predict_per_tree_checked(
+        &self,
+        feature_vec: impl AsRef<[(Index, f32)]>,
+        beam_size: usize,
+    ) -> Result<Vec<IndexValueVec>, PredictError> {
+        self.checked_options(beam_size, None)?;
+        if self.trees.is_empty() {
+            return Err(PredictError::EmptyModel);
+        }
+        let feature_vec = feature_vec.as_ref();
+        self.validate_feature_vec(feature_vec)
+            .map_err(PredictError::InvalidFeatureVec)?;
         let feature_vec = self.prepare_feature_vec(feature_vec);
-        let mut label_to_total_score = HashMap::<Index, f32>::new();
-        let tree_predictions: Vec<_> = self
+        let (tree_predictions, budget) = self
+            .search_per_tree(&feature_vec, beam_size)
+            .map_err(|(tree, error)| error.into_predict_error(tree))?;
+        match budget.hits.first() {
+            Some(limit) if self.inference_limits.policy == limits::LimitPolicy::Error => {
+                Err(PredictError::LimitExceeded { limit })
+            }
+            _ => Ok(tree_predictions),
+        }
+    }
+
+    /// Like [`Self::predict_per_tree`], but for a prepared input, also returning the budget left
+    /// after searching all trees; or the index of the first malformed tree with an error.
+    fn search_per_tree(
+        &self,
+        feature_vec: &SparseVec,
+        beam_size: usize,
+    ) -> Result<(Vec<IndexValueVec>, limits::SearchBudget), (usize, SearchError)> {
+        let mut budget = limits::SearchBudget::new(&self.inference_limits);
+        let beam_size = self.inference_limits.beam_size(beam_size, &mut budget.hits);
+        let mut buffers = SearchBuffers::default();
+        let tree_predictions = self
             .trees
             .iter()
-            .map(|tree| tree.predict(self.settings.classifier_loss_type, &feature_vec, beam_size))
-            .collect();
-        for label_score_pairs in tree_predictions {
-            for (label, score) in label_score_pairs {
-                let total_score = label_to_total_score.entry(label).or_insert(0.);
-                *total_score += score;
+            .enumerate()
+            .map(|(tree, root)| {
+                buffers.label_score_pairs.clear();
+                root.try_predict(
+                    self.settings.classifier_loss_type,
+                    feature_vec,
+                    beam_size,
+                    predict::LeafTransform::Exp,
+                    &mut budget,
+                    &mut buffers,
+                )
+                .map_err(|error| (tree, error))?;
+                budget.finish_tree();
+                let n_pairs = buffers.label_score_pairs.len();
+                Ok(rank_top_k(buffers.label_score_pairs.drain(..), n_pairs))
+            })
+            .collect::<Result<_, _>>()?;
+        Ok((tree_predictions, budget))
+    }
+
+    /// Returns ranked lists of predictions for the given input examples, in the same order.
+    ///
+    /// The results are the same as those of [`Self::predict`] for each example. Examples are
+    /// predicted in parallel on the current Rayon thread pool, with each thread reusing its
+    /// buffers for preparing inputs and for beam search across the examples it predicts.
+    ///
+    /// Panics as [`Self::predict`] does for any example, and so do the other batch methods,
+    /// except for [`Self::predict_batch_checked`] and [`Self::predict_batch_sequential_checked`].
+    /// To predict with checks on a given thread pool, call [`Self::predict_batch_checked`] within
+    /// [`rayon::ThreadPool::install`].
+    pub fn predict_batch<F>(&self, feature_vecs: &[F], beam_size: usize) -> Vec<IndexValueVec>
+    where
+        F: AsRef<[(Index, f32)]> + Sync,
+    {
+        feature_vecs
+            .par_iter()
+            .map_init(
+                <(predict::PrepareBuffers, SearchBuffers)>::default,
+                |(prepare_buffers, search_buffers), feature_vec| {
+                    let feature_vec =
+                        self.prepare_feature_vec_with(feature_vec.as_ref(), prepare_buffers);
+                    let predictions =
+                        self.predict_prepared_with(&feature_vec, beam_size, None, search_buffers);
+                    prepare_buffers.recycle(feature_vec);
+                    predictions
+                },
+            )
+            .collect()
+    }
+
+    /// Like [`Self::predict_batch`], but checks each input and reports problems as
+    /// [`Self::predict_checked`] does, so that a bad example only fails its own prediction.
+    ///
+    /// Invalid options, i.e., a zero beam size, fail every example.
+    pub fn predict_batch_checked<F>(
+        &self,
+        feature_vecs: &[F],
+        beam_size: usize,
+    ) -> Vec<Result<IndexValueVec, PredictError>>
+    where
+        F: AsRef<[(Index, f32)]> + Sync,
+    {
+        let options = match self.checked_options(beam_size, None) {
+            Ok(options) => options,
+            Err(e) => return vec![Err(e); feature_vecs.len()],
+        };
+        feature_vecs
+            .par_iter()
+            .map_init(
+                <(predict::PrepareBuffers, SearchBuffers)>::default,
+                |(prepare_buffers, search_buffers), feature_vec| {
+                    self.predict_validated(
+                        feature_vec.as_ref(),
+                        &options,
+                        true,
+                        prepare_buffers,
+                        search_buffers,
+                    )
+                },
+            )
+            .collect()
+    }
+
+    /// Like [`Self::predict_batch`], but also profiles where beam search spends its time by depth
+    /// of each tree, aggregated over the batch.
+    ///
+    /// Profiling only takes two timestamps per depth searched, but [`Self::predict_batch`] skips
+    /// even those.
+    pub fn predict_batch_profiled<F>(
+        &self,
+        feature_vecs: &[F],
+        beam_size: usize,
+    ) -> (Vec<IndexValueVec>, predict::BatchPredictProfile)
+    where
+        F: AsRef<[(Index, f32)]> + Sync,
+    {
+        feature_vecs
+            .par_iter()
+            .fold(
+                || {
+                    (
+                        predict::PrepareBuffers::default(),
+                        SearchBuffers::profiled(),
+                        Vec::new(),
+                    )
+                },
+                |(mut prepare_buffers, mut search_buffers, mut predictions), feature_vec| {
+                    let feature_vec =
+                        self.prepare_feature_vec_with(feature_vec.as_ref(), &mut prepare_buffers);
+                    predictions.push(self.predict_prepared_with(
+                        &feature_vec,
+                        beam_size,
+                        None,
+                        &mut search_buffers,
+                    ));
+                    prepare_buffers.recycle(feature_vec);
+                    (prepare_buffers, search_buffers, predictions)
+                },
+            )
+            .map(|(_, search_buffers, predictions)| {
+                (predictions, search_buffers.profile.unwrap_or_default())
+            })
+            // Folded chunks are reduced in order, so predictions stay in the order of the inputs
+            .reduce(
+                || (Vec::new(), predict::BatchPredictProfile::default()),
+                |(mut predictions, mut profile), (other_predictions, other_profile)| {
+                    predictions.extend(other_predictions);
+                    profile.merge(&other_profile);
+                    (predictions, profile)
+                },
+            )
+    }
+
+    /// Like [`Self::predict_batch`], but predicts with the given number of threads instead of
+    /// on the current thread pool, e.g., to leave some cores free.
+    ///
+    /// If `n_threads` is 0, the number is selected automatically. If it's 1, examples are
+    /// predicted with [`Self::predict_batch_sequential`], without building a thread pool.
+    /// Otherwise a thread pool is built for the call, which takes time of its own, so callers
+    /// predicting many batches should build one once and use [`Self::predict_batch_in_pool`].
+    ///
+    /// Returns an error if the thread pool can't be built.
+    pub fn predict_batch_with_n_threads<F>(
+        &self,
+        feature_vecs: &[F],
+        beam_size: usize,
+        n_threads: usize,
+    ) -> Result<Vec<IndexValueVec>, rayon::ThreadPoolBuildError>
+    where
+        F: AsRef<[(Index, f32)]> + Sync,
+    {
+        if n_threads == 1 {
+            return Ok(self.predict_batch_sequential(feature_vecs, beam_size));
+        }
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(n_threads)
+            .build()?;
+        Ok(self.predict_batch_in_pool(feature_vecs, beam_size, &pool))
+    }
+
+    /// Like [`Self::predict_batch`], but predicts on the given thread pool instead of the current
+    /// one, e.g., to keep prediction apart from an application's own use of Rayon.
+    pub fn predict_batch_in_pool<F>(
+        &self,
+        feature_vecs: &[F],
+        beam_size: usize,
+        pool: &rayon::ThreadPool,
+    ) -> Vec<IndexValueVec>
+    where
+        F: AsRef<[(Index, f32)]> + Sync,
+    {
+        pool.install(|| self.predict_batch(feature_vecs, beam_size))
+    }
+
+    /// Like [`Self::predict_batch`], but predicts the examples one after another on the calling
+    /// thread, without Rayon, reusing the same buffers for all of them.
+    ///
+    /// This avoids the overhead of parallelism for small batches and small models, and is safe
+    /// to call from within parallel code.
+    pub fn predict_batch_sequential<F>(
+        &self,
+        feature_vecs: &[F],
+        beam_size: usize,
+    ) -> Vec<IndexValueVec>
+    where
+        F: AsRef<[(Index, f32)]>,
+    {
+        let mut prepare_buffers = predict::PrepareBuffers::default();
+        let mut search_buffers = SearchBuffers::default();
+        feature_vecs
+            .iter()
+            .map(|feature_vec| {
+                let feature_vec =
+                    self.prepare_feature_vec_with(feature_vec.as_ref(), &mut prepare_buffers);
+                let predictions =
+                    self.predict_prepared_with(&feature_vec, beam_size, None, &mut search_buffers);
+                prepare_buffers.recycle(feature_vec);
+                predictions
+            })
+            .collect()
+    }
+
+    /// Like [`Self::predict_batch_sequential`], but checks each input and reports problems as
+    /// [`Self::predict_batch_checked`] does.
+    pub fn predict_batch_sequential_checked<F>(
+        &self,
+        feature_vecs: &[F],
+        beam_size: usize,
+    ) -> Vec<Result<IndexValueVec, PredictError>>
+    where
+        F: AsRef<[(Index, f32)]>,
+    {
+        let options = match self.checked_options(beam_size, None) {
+            Ok(options) => options,
+            Err(e) => return vec![Err(e); feature_vecs.len()],
+        };
+        let mut prepare_buffers = predict::PrepareBuffers::default();
+        let mut search_buffers = SearchBuffers::default();
+        feature_vecs
+            .iter()
+            .map(|feature_vec| {
+                self.predict_validated(
+                    feature_vec.as_ref(),
+                    &options,
+                    true,
+                    &mut prepare_buffers,
+                    &mut search_buffers,
+                )
+            })
+            .collect()
+    }
+
+    /// Options for the checked variants of the prediction methods, with the given beam size and
+    /// number of labels returned, checked against the model.
+    fn checked_options(
+        &self,
+        beam_size: usize,
+        top_k: Option<usize>,
+    ) -> Result<PredictOptions, PredictError> {
+        let options = PredictOptions {
+            beam_size,
+            top_k,
+            ..PredictOptions::default()
+        };
+        options
+            .validate_for(self)
+            .map_err(PredictError::InvalidOptions)?;
+        Ok(options)
+    }
+
+    /// Check the input with [`Self::validate_feature_vec`], then predict for it as the given
+    /// options specify, ranking predictions if `ranked` is set.
+    fn predict_validated<'a>(
+        &'a self,
+        feature_vec: &[(Index, f32)],
+        options: &PredictOptions,
+        ranked: bool,
+        prepare_buffers: &mut predict::PrepareBuffers,
+        search_buffers: &mut SearchBuffers<'a>,
+    ) -> Result<IndexValueVec, PredictError> {
+        self.validate_feature_vec(feature_vec)
+            .map_err(PredictError::InvalidFeatureVec)?;
+        let feature_vec = self.prepare_feature_vec_with(feature_vec, prepare_buffers);
+        let result = self.predict_prepared_checked(
+            &feature_vec,
+            options,
+            options.top_k,
+            ranked,
+            &mut predict::PredictStats::default(),
+            search_buffers,
+        );
+        prepare_buffers.recycle(feature_vec);
+        result
+    }
+
+    /// Predict for a feature vector already prepared by [`Self::prepare_feature_vec`].
+    ///
+    /// Predictions are truncated at the model's inference limits.
+    fn predict_prepared(&self, feature_vec: &SparseVec, beam_size: usize) -> IndexValueVec {
+        self.predict_prepared_with(feature_vec, beam_size, None, &mut SearchBuffers::default())
+    }
+
+    /// Like [`Self::predict_prepared`], but only returns the `top_k` best predictions if given,
+    /// and searches with the given buffers.
+    fn predict_prepared_with<'a>(
+        &'a self,
+        feature_vec: &SparseVec,
+        beam_size: usize,
+        top_k: Option<usize>,
+        buffers: &mut SearchBuffers<'a>,
+    ) -> IndexValueVec {
+        self.search_trees(
+            feature_vec,
+            beam_size,
+            predict::LeafTransform::Exp,
+            top_k,
+            true,
+            buffers,
+        )
+        .map(|(predictions, _)| predictions)
+        .unwrap_or_else(|(_, message)| panic!("Corrupt tree: {}", message))
+    }
+
+    /// Like [`Self::predict_prepared`], but searches as the given options specify, only returns
+    /// the `top_k` best predictions if given, ranked if `ranked` is set, and reports corrupt
+    /// trees, and hit limits under [`limits::LimitPolicy::Error`], as errors instead of
+    /// panicking.
+    fn predict_prepared_checked<'a>(
+        &'a self,
+        feature_vec: &SparseVec,
+        options: &PredictOptions,
+        top_k: Option<usize>,
+        ranked: bool,
+        stats: &mut predict::PredictStats,
+        buffers: &mut SearchBuffers<'a>,
+    ) -> Result<IndexValueVec, PredictError> {
+        if self.trees.is_empty() {
+            return Err(PredictError::EmptyModel);
+        }
+        buffers.frontier.score_gap = options.score_gap;
+        let result = self.search_trees(
+            feature_vec,
+            options.beam_size,
+            options.leaf_transform,
+            top_k,
+            ranked,
+            buffers,
+        );
+        buffers.frontier.score_gap = None;
+        let (predictions, hits) = result.map_err(|(tree, error)| error.into_predict_error(tree))?;
+        stats.record_limit_hits(&hits);
+        match hits.first() {
+            Some(limit) if self.inference_limits.policy == limits::LimitPolicy::Error => {
+                Err(PredictError::LimitExceeded { limit })
             }
+            _ => Ok(predictions),
+        }
+    }
+
+    /// Beam search in all trees within the model's inference limits, returning the averaged
+    /// predictions, or only the `top_k` best if given, ranked if `ranked` is set, with the limits
+    /// hit; or the index of the first malformed tree with an error message.
+    fn search_trees<'a>(
+        &'a self,
+        feature_vec: &SparseVec,
+        beam_size: usize,
+        leaf_transform: predict::LeafTransform,
+        top_k: Option<usize>,
+        ranked: bool,
+        buffers: &mut SearchBuffers<'a>,
+    ) -> Result<(IndexValueVec, limits::LimitHits), (usize, SearchError)> {
+        let mut budget = limits::SearchBudget::new(&self.inference_limits);
+        let beam_size = self.inference_limits.beam_size(beam_size, &mut budget.hits);
+        buffers.label_score_pairs.clear();
+        let start_t = buffers.profile.is_some().then(time::Instant::now);
+        for (tree, root) in self.trees.iter().enumerate() {
+            root.try_predict(
+                self.settings.classifier_loss_type,
+                feature_vec,
+                beam_size,
+                leaf_transform,
+                &mut budget,
+                buffers,
+            )
+            .map_err(|message| (tree, message))?;
+            budget.finish_tree();
+            if let (Some(profile), Some(depths)) =
+                (buffers.profile.as_mut(), buffers.frontier.depths.as_ref())
+            {
+                profile.record_tree(tree, depths);
+            }
+        }
+        if let (Some(profile), Some(start_t)) = (buffers.profile.as_mut(), start_t) {
+            profile.n_predictions += 1;
+            profile.search_time += start_t.elapsed();
+        }
+
+        let predictions = self.average_label_scores(
+            buffers.label_score_pairs.drain(..),
+            &mut buffers.label_score_sums,
+            self.dense_label_bound(),
+            budget.n_trees_averaged(self.trees.len()),
+            ranked,
+            |n_labels| {
+                self.inference_limits
+                    .n_labels_returned(top_k, n_labels, &mut budget.hits)
+            },
+        );
+        debug_assert!(
+            predictions.iter().all(|&(_, score)| scores::is_within(
+                score,
+                leaf_transform.score_range(self.settings.classifier_loss_type)
+            )),
+            "Scores outside of {:?}",
+            leaf_transform.score_range(self.settings.classifier_loss_type)
+        );
+        Ok((predictions, budget.hits))
+    }
+
+    /// Average the predictions of the trees over `n_trees`, returning the best labels by average
+    /// score, as many as `n_returned` gives for the number of distinct labels.
+    fn average_tree_predictions(
+        &self,
+        tree_predictions: Vec<IndexValueVec>,
+        n_trees: usize,
+        n_returned: impl FnOnce(usize) -> usize,
+    ) -> IndexValueVec {
+        let n_pairs = tree_predictions.iter().map(Vec::len).sum();
+        self.average_label_scores(
+            tree_predictions.into_iter().flatten(),
+            &mut LabelScoreSums::with_map_capacity(n_pairs),
+            None,
+            n_trees,
+            true,
+            n_returned,
+        )
+    }
+
+    /// Like [`Self::average_tree_predictions`], but with the predictions of the trees given one
+    /// after another, and summed in the given sums, which are left empty for reuse, densely if
+    /// `label_bound` is given; see [`LabelScoreSums::add`]. The best labels are left in no
+    /// particular order unless `ranked` is set.
+    ///
+    /// Scores are summed over the trees in a fixed order before any label is ranked, since a
+    /// label's total can still grow with each tree; only then are the best labels selected,
+    /// without sorting the rest. See [`rank_top_k`].
+    fn average_label_scores(
+        &self,
+        label_score_pairs: impl Iterator<Item = (Index, f32)>,
+        label_score_sums: &mut LabelScoreSums,
+        label_bound: Option<usize>,
+        n_trees: usize,
+        ranked: bool,
+        n_returned: impl FnOnce(usize) -> usize,
+    ) -> IndexValueVec {
+        label_score_sums.add(label_score_pairs, label_bound);
+        let k = n_returned(label_score_sums.len());
+        let n_trees = n_trees as f32;
+        let label_score_pairs = label_score_sums
+            .drain()
+            .map(|(label, total_score)| (label, total_score / n_trees));
+        if ranked {
+            rank_top_k(label_score_pairs, k)
+        } else {
+            select_top_k(label_score_pairs, k)
         }
+    }
+
+    /// One more than the largest label index, if label indices are dense enough for scores to be
+    /// summed in a vector indexed by label, i.e., at most [`MAX_DENSE_LABEL_SPREAD`] times the
+    /// number of labels.
+    fn dense_label_bound(&self) -> Option<usize> {
+        let labels = self.sorted_labels();
+        let label_bound = labels.last().map_or(0, |&label| label as usize + 1);
+        (label_bound <= labels.len() * MAX_DENSE_LABEL_SPREAD).then_some(label_bound)
+    }
 
-        let mut label_score_pairs = label_to_total_score
+    /// The smallest beam size beyond which predictions no longer change.
+    ///
+    /// This is the largest number of nodes that can be on the beam search frontier of any tree, or
+    /// the largest number of labels in any leaf, whichever is larger.
+    pub fn max_useful_beam(&self) -> usize {
+        self.trees
             .iter()
-            .map(|(&label, &total_score)| (label, total_score / self.trees.len() as f32))
-            .collect_vec();
-        label_score_pairs.sort_unstable_by_key(|&(_, score)| Reverse(NotNan::new(score).unwrap()));
-        label_score_pairs
+            .map(|tree| tree.max_useful_beam())
+            .max()
+            .unwrap_or(0)
+            .max(1)
     }
 
     /// The expected dimension of feature vectors.
@@ -76,28 +882,252 @@ impl Model {
         self.trees.len()
     }
 
-    /// Prepare the feature vector in both dense and sparse forms to make prediction more efficient.
-    fn prepare_feature_vec(&self, sparse_vec: &[(Index, f32)]) -> SparseVec {
-        let norm = sparse_vec
+    /// The number of distinct labels the model can predict.
+    ///
+    /// The labels are collected from the leaves of all trees when first needed, and kept for
+    /// later calls.
+    pub fn n_labels(&self) -> usize {
+        self.sorted_labels().len()
+    }
+
+    /// The distinct labels the model can predict, sorted.
+    pub fn labels(&self) -> Vec<Index> {
+        self.sorted_labels().to_vec()
+    }
+
+    fn sorted_labels(&self) -> &[Index] {
+        self.sorted_labels
+            .get_or_init(|| self.collect_sorted_labels())
+    }
+
+    /// Mutable access to the trees, which forgets the labels collected from them.
+    fn trees_mut(&mut self) -> &mut Vec<TreeNode> {
+        self.sorted_labels = OnceLock::new();
+        self.label_index = OnceLock::new();
+        &mut self.trees
+    }
+
+    /// The memory used by the weights and leaf labels of the trees, in bytes.
+    pub fn mem_size(&self) -> usize {
+        let mut size = 0;
+        let mut labels = Vec::new();
+        for tree in &self.trees {
+            tree.visit_weights(&mut |weights| size += weights.mem_size());
+            tree.collect_labels(&mut labels);
+        }
+        size + labels.len() * std::mem::size_of::<Index>()
+    }
+
+    /// Check that the model can make predictions, i.e., that it has at least one tree, that its
+    /// trees have at least one label, and that every tree is well-formed.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.trees.is_empty() {
+            return Err("The model has no trees".to_owned());
+        }
+        if let Some(i) = self
+            .trees
             .iter()
-            .map(|(_, v)| v.powi(2))
-            .sum::<f32>()
-            .sqrt();
+            .position(|tree| !tree.is_valid(self.settings))
+        {
+            return Err(format!("Tree {} is malformed", i));
+        }
+        if self.n_labels() == 0 {
+            return Err("The model has no labels".to_owned());
+        }
+        Ok(())
+    }
 
-        let (mut indices, mut data): (Vec<_>, Vec<_>) = sparse_vec
+    /// Create a new model with only the trees at the given indices, in the given order.
+    ///
+    /// Predictions are averaged over the trees kept. Panics if no indices are given, since a
+    /// model without trees predicts nothing.
+    pub fn take_trees(&self, tree_indices: &[usize]) -> Self {
+        assert!(!tree_indices.is_empty(), "At least one tree must be taken");
+        let trees = tree_indices
             .iter()
-            .cloned()
-            .map(|(i, v)| (i, v / norm))
-            .unzip();
+            .map(|&i| {
+                assert!(
+                    i < self.trees.len(),
+                    "Tree index {} out of range for {} trees",
+                    i,
+                    self.trees.len()
+                );
+                self.trees[i].clone()
+            })
+            .collect();
+        Self {
+            trees,
+            settings: self.settings,
+            label_thresholds: self.label_thresholds.clone(),
+            training_metadata: self
+                .training_metadata
+                .select_trees(self.trees.len(), tree_indices),
+            inference_limits: self.inference_limits,
+            feature_projection: self.feature_projection,
+            input_profile: self.input_profile.clone(),
+            label_graph: self.label_graph.clone(),
+            original_labels: self.original_labels.clone(),
+            label_names: self.label_names.clone(),
+            used_features: self.used_features.clone(),
+            sorted_labels: Default::default(),
+            label_index: Default::default(),
+        }
+    }
+
+    /// Information recorded while training the model.
+    pub fn training_metadata(&self) -> &train::TrainingMetadata {
+        &self.training_metadata
+    }
+
+    /// Collect the distinct labels of all leaves, sorted.
+    fn collect_sorted_labels(&self) -> Vec<Index> {
+        let mut labels = Vec::new();
+        for tree in &self.trees {
+            tree.collect_labels(&mut labels);
+        }
+        labels.sort_unstable();
+        labels.dedup();
+        labels
+    }
+
+    /// The transform applied to input feature vectors before prediction.
+    fn feature_transform(&self) -> schema::FeatureTransform {
+        self.settings.feature_transform
+    }
+
+    /// The index of the bias feature appended to input feature vectors, which is the feature
+    /// index right after those with weights.
+    fn bias_index(&self) -> Index {
+        to_index(self.settings.n_weight_features(), IndexKind::Feature)
+    }
 
-        indices.push(self.settings.n_features as Index);
+    /// Prepare the feature vector in both dense and sparse forms to make prediction more efficient.
+    ///
+    /// Inputs are first projected if the model was trained with a feature projection, and features
+    /// are renumbered if the model was compacted with [`Self::compact_features`].
+    fn prepare_feature_vec(&self, sparse_vec: &[(Index, f32)]) -> SparseVec {
+        self.prepare_feature_vec_with(sparse_vec, &mut predict::PrepareBuffers::default())
+    }
+
+    /// Like [`Self::prepare_feature_vec`], but takes storage from the given buffers instead of
+    /// allocating; the storage can be returned with [`predict::PrepareBuffers::recycle`].
+    fn prepare_feature_vec_with(
+        &self,
+        sparse_vec: &[(Index, f32)],
+        buffers: &mut predict::PrepareBuffers,
+    ) -> SparseVec {
+        let projected;
+        let sparse_vec = match self.feature_projection {
+            Some(params) => {
+                projected = params.project(sparse_vec);
+                &projected[..]
+            }
+            None => sparse_vec,
+        };
+        self.prepare_pairs_with(sparse_vec.iter().cloned(), sparse_vec.len(), buffers)
+    }
+
+    /// Like [`Self::prepare_feature_vec`], but for a dense input with one value for each feature,
+    /// which must not be projected; zeros are left out.
+    fn prepare_dense_feature_vec(&self, features: &[f32]) -> SparseVec {
+        debug_assert!(self.feature_projection.is_none());
+        debug_assert_eq!(self.settings.n_features, features.len());
+        self.prepare_pairs_with(
+            features
+                .iter()
+                .enumerate()
+                .filter(|&(_, &v)| v != 0.)
+                .map(|(i, &v)| (i as Index, v)),
+            features.len(),
+            &mut predict::PrepareBuffers::default(),
+        )
+    }
+
+    /// Transform the given index-value pairs, which must not need projection, and append the bias
+    /// feature, with storage for `capacity` pairs taken from the given buffers.
+    fn prepare_pairs_with<I>(
+        &self,
+        pairs: I,
+        capacity: usize,
+        buffers: &mut predict::PrepareBuffers,
+    ) -> SparseVec
+    where
+        I: Iterator<Item = (Index, f32)> + Clone,
+    {
+        let (mut indices, mut data) = buffers.take(capacity + 1);
+        let norm = match self.feature_transform() {
+            schema::FeatureTransform::L2Normalize => {
+                Some(pairs.clone().map(|(_, v)| v.powi(2)).sum::<f32>().sqrt())
+                    .filter(|&norm| norm >= MIN_INPUT_NORM)
+            }
+            schema::FeatureTransform::Identity => Some(1.),
+        };
+        // Empty and all-zero inputs, which have nothing to normalize, leave only the bias active
+        if let Some(norm) = norm {
+            match &self.used_features {
+                // Features without weights are left out after the norm is taken over all of them
+                Some(used_features) => {
+                    for (i, v) in pairs {
+                        if let Ok(new_i) = used_features.binary_search(&i) {
+                            indices.push(to_index(new_i, IndexKind::Feature));
+                            data.push(v / norm);
+                        }
+                    }
+                }
+                None => {
+                    for (i, v) in pairs {
+                        indices.push(i);
+                        data.push(v / norm);
+                    }
+                }
+            }
+        }
+
+        indices.push(self.bias_index());
         data.push(1.);
 
-        SparseVec::new(self.settings.n_features + 1, indices, data)
+        SparseVec::new(self.settings.n_weight_features() + 1, indices, data)
     }
 
     /// Serialize model into the directory with the given path.
+    ///
+    /// Each file is written under a temporary name and only renamed once complete, so that a
+    /// failed save never leaves a partially written file behind. The directory is marked as being
+    /// saved into until the save completes, so that [`Self::load`] rejects a directory whose save
+    /// was interrupted rather than loading part of the model.
     pub fn save<P: AsRef<std::path::Path>>(&self, dir_path: P) -> io::Result<()> {
+        self.save_with_options(dir_path, &SaveOptions::default())
+    }
+
+    /// Like [`Self::save`], but with the given options.
+    pub fn save_with_options<P: AsRef<std::path::Path>>(
+        &self,
+        dir_path: P,
+        options: &SaveOptions,
+    ) -> io::Result<()> {
+        self.save_with_sink(dir_path, options, &io_sink::FileSink)
+    }
+
+    /// Like [`Self::save_with_options`], but creates files through the given sink.
+    fn save_with_sink<P: AsRef<std::path::Path>>(
+        &self,
+        dir_path: P,
+        options: &SaveOptions,
+        sink: &impl io_sink::IoSink,
+    ) -> io::Result<()> {
+        let trees = self.trees.iter().map(|tree| Ok(Cow::Borrowed(tree)));
+        self.save_with_trees(dir_path, options, sink, trees)
+    }
+
+    /// Like [`Self::save_with_sink`], but saves the given trees in place of the model's own,
+    /// taking them one at a time, so that they never all need to be in memory.
+    fn save_with_trees<'a, P: AsRef<std::path::Path>>(
+        &self,
+        dir_path: P,
+        options: &SaveOptions,
+        sink: &impl io_sink::IoSink,
+        trees: impl IntoIterator<Item = io::Result<Cow<'a, TreeNode>>>,
+    ) -> io::Result<()> {
         info!("Saving model...");
         let start_t = time::Instant::now();
 
@@ -110,36 +1140,167 @@ impl Model {
                 "file with the given name already exists",
             ));
         }
-
+        // Trees shouldn't be added to a model that is incomplete itself, or saved in a newer format
+        let existing_header = read_format_header(dir_path)?;
         let settings_path = dir_path.join(MODEL_SETTINGS_FILE_NAME);
-        if settings_path.exists() {
-            let reader = std::io::BufReader::new(std::fs::File::open(settings_path)?);
+        let settings_exist = settings_path.exists();
+        if settings_exist {
+            let reader = std::io::BufReader::new(std::fs::File::open(&settings_path)?);
             let existing_settings = serde_json::from_reader(reader)?;
             if self.settings != existing_settings {
                 return Err(io::Error::new(
                     io::ErrorKind::InvalidInput,
                     "a model with different settings is already saved in the given directory",
                 ));
-            } else {
-                info!(
-                    "A model is already saved at {}; trees will be added to the existing model",
-                    dir_path.display(),
-                );
             }
-        } else {
-            let writer = std::io::BufWriter::new(std::fs::File::create(settings_path)?);
-            serde_json::to_writer_pretty(writer, &self.settings).map_err(|e| {
-                io::Error::new(
-                    io::ErrorKind::Other,
-                    format!("Unable to serialize settings: {}", e),
-                )
+            info!(
+                "A model is already saved at {}; trees will be added to the existing model",
+                dir_path.display(),
+            );
+        }
+
+        // The directory is marked until the save completes, so that the model in it isn't loaded
+        // if the save is interrupted
+        let marker_path = dir_path.join(SAVE_MARKER_FILE_NAME);
+        std::fs::File::create(&marker_path)?.sync_all()?;
+
+        // The header is rewritten once trees are added; until then, the marker keeps the stale
+        // one from being trusted
+        let mut tree_checksums = existing_header
+            .filter(|_| settings_exist)
+            .map(|header| header.tree_checksums)
+            .unwrap_or_default();
+        if !settings_exist {
+            io_sink::write_atomically(sink, &settings_path, |writer| {
+                serde_json::to_writer_pretty(writer, &self.settings).map_err(|e| {
+                    io::Error::new(
+                        io::ErrorKind::Other,
+                        format!("Unable to serialize settings: {}", e),
+                    )
+                })
+            })?;
+        }
+
+        if let Some(label_thresholds) = self.label_thresholds.as_ref() {
+            io_sink::write_atomically(
+                sink,
+                &dir_path.join(LABEL_THRESHOLDS_FILE_NAME),
+                |writer| {
+                    serde_json::to_writer(writer, label_thresholds).map_err(|e| {
+                        io::Error::new(
+                            io::ErrorKind::Other,
+                            format!("Unable to serialize label thresholds: {}", e),
+                        )
+                    })
+                },
+            )?;
+        }
+
+        if self.training_metadata != train::TrainingMetadata::default() {
+            io_sink::write_atomically(
+                sink,
+                &dir_path.join(TRAINING_METADATA_FILE_NAME),
+                |writer| {
+                    serde_json::to_writer_pretty(writer, &self.training_metadata).map_err(|e| {
+                        io::Error::new(
+                            io::ErrorKind::Other,
+                            format!("Unable to serialize training metadata: {}", e),
+                        )
+                    })
+                },
+            )?;
+        }
+
+        if self.inference_limits != limits::InferenceLimits::default() {
+            io_sink::write_atomically(
+                sink,
+                &dir_path.join(INFERENCE_LIMITS_FILE_NAME),
+                |writer| {
+                    serde_json::to_writer_pretty(writer, &self.inference_limits).map_err(|e| {
+                        io::Error::new(
+                            io::ErrorKind::Other,
+                            format!("Unable to serialize inference limits: {}", e),
+                        )
+                    })
+                },
+            )?;
+        }
+
+        if let Some(feature_projection) = self.feature_projection {
+            io_sink::write_atomically(
+                sink,
+                &dir_path.join(FEATURE_PROJECTION_FILE_NAME),
+                |writer| {
+                    serde_json::to_writer_pretty(writer, &feature_projection).map_err(|e| {
+                        io::Error::new(
+                            io::ErrorKind::Other,
+                            format!("Unable to serialize feature projection: {}", e),
+                        )
+                    })
+                },
+            )?;
+        }
+
+        if let Some(input_profile) = self.input_profile.as_ref() {
+            io_sink::write_atomically(sink, &dir_path.join(INPUT_PROFILE_FILE_NAME), |writer| {
+                serde_json::to_writer(writer, input_profile).map_err(|e| {
+                    io::Error::new(
+                        io::ErrorKind::Other,
+                        format!("Unable to serialize input profile: {}", e),
+                    )
+                })
+            })?;
+        }
+
+        if let Some(label_graph) = self.label_graph.as_ref() {
+            io_sink::write_atomically(sink, &dir_path.join(LABEL_GRAPH_FILE_NAME), |writer| {
+                serde_json::to_writer(writer, label_graph).map_err(|e| {
+                    io::Error::new(
+                        io::ErrorKind::Other,
+                        format!("Unable to serialize label graph: {}", e),
+                    )
+                })
+            })?;
+        }
+
+        if let Some(original_labels) = self.original_labels.as_ref() {
+            io_sink::write_atomically(sink, &dir_path.join(ORIGINAL_LABELS_FILE_NAME), |writer| {
+                serde_json::to_writer(writer, original_labels).map_err(|e| {
+                    io::Error::new(
+                        io::ErrorKind::Other,
+                        format!("Unable to serialize original labels: {}", e),
+                    )
+                })
+            })?;
+        }
+
+        if let Some(label_names) = self.label_names.as_ref() {
+            io_sink::write_atomically(sink, &dir_path.join(LABEL_NAMES_FILE_NAME), |writer| {
+                serde_json::to_writer(writer, label_names).map_err(|e| {
+                    io::Error::new(
+                        io::ErrorKind::Other,
+                        format!("Unable to serialize label names: {}", e),
+                    )
+                })
+            })?;
+        }
+
+        if let Some(used_features) = self.used_features.as_ref() {
+            io_sink::write_atomically(sink, &dir_path.join(USED_FEATURES_FILE_NAME), |writer| {
+                serde_json::to_writer(writer, used_features).map_err(|e| {
+                    io::Error::new(
+                        io::ErrorKind::Other,
+                        format!("Unable to serialize used features: {}", e),
+                    )
+                })
             })?;
         }
 
         let index_to_tree_path =
             |index: usize| dir_path.join(format!("{}{}.cbor", TREE_FILE_NAME_PREFIX, index));
         let mut curr_index = 0usize;
-        for tree in &self.trees {
+        for tree in trees {
+            let tree = tree?;
             let mut tree_path = index_to_tree_path(curr_index);
             while tree_path.exists() {
                 info!(
@@ -150,17 +1311,61 @@ impl Model {
                 tree_path = index_to_tree_path(curr_index);
             }
 
+            let converted_tree;
+            let tree = match options.weight_precision {
+                Some(precision) => {
+                    converted_tree = tree.with_weight_precision(precision);
+                    &converted_tree
+                }
+                None => &*tree,
+            };
+
             info!("Saving tree to {}", tree_path.display());
-            let writer = std::io::BufWriter::new(std::fs::File::create(tree_path)?);
-            serde_cbor::to_writer(writer, tree).map_err(|e| {
-                io::Error::new(
-                    io::ErrorKind::Other,
-                    format!("Unable to serialize tree: {}", e),
-                )
+            let write_tree = |writer: &mut dyn io::Write| {
+                serde_cbor::to_writer(writer, tree).map_err(|e| {
+                    io::Error::new(
+                        io::ErrorKind::Other,
+                        format!("Unable to serialize tree: {}", e),
+                    )
+                })
+            };
+            let mut digest = [0u8; 32];
+            io_sink::write_atomically(sink, &tree_path, |writer| {
+                let mut writer = checksum::Hashed::new(writer);
+                match options.compression {
+                    Some(compression) => crate::data::compression::compress(
+                        &mut writer,
+                        compression,
+                        options.compression_level,
+                        write_tree,
+                    )?,
+                    None => write_tree(&mut writer)?,
+                }
+                digest = writer.digest();
+                Ok(())
             })?;
+            tree_checksums.insert(
+                format!("{}{}.cbor", TREE_FILE_NAME_PREFIX, curr_index),
+                checksum::to_hex(&digest),
+            );
             curr_index += 1;
         }
 
+        // The header is written last, so that it counts trees already in the directory too, and
+        // only complete saves have one
+        let mut header =
+            version::FormatHeader::new(self.settings.n_features, tree_file_paths(dir_path)?.len());
+        header.tree_checksums = tree_checksums;
+        io_sink::write_atomically(sink, &dir_path.join(FORMAT_HEADER_FILE_NAME), |writer| {
+            serde_json::to_writer_pretty(writer, &header).map_err(|e| {
+                io::Error::new(
+                    io::ErrorKind::Other,
+                    format!("Unable to serialize model format header: {}", e),
+                )
+            })
+        })?;
+        std::fs::remove_file(&marker_path)?;
+
         info!(
             "Model saved; it took {:.2}s",
             start_t.elapsed().as_secs_f32()
@@ -169,13 +1374,43 @@ impl Model {
     }
 
     /// Deserialize model from the given directory.
+    ///
+    /// Tree files compressed with any codec enabled by features are decompressed transparently.
     pub fn load<P: AsRef<std::path::Path>>(dir_path: P) -> io::Result<Self> {
+        Self::load_dir(dir_path.as_ref(), None)
+    }
+
+    /// Deserialize only the trees at the given indices from the model in the given directory.
+    ///
+    /// Trees are indexed in the order they were saved, and files of trees that are not selected
+    /// are never read. Trees in the returned model are in the order of the given indices, and
+    /// predictions average over them only.
+    pub fn load_from_dir_partial<P: AsRef<std::path::Path>>(
+        dir_path: P,
+        tree_indices: &[usize],
+    ) -> io::Result<Self> {
+        Self::load_dir(dir_path.as_ref(), Some(tree_indices))
+    }
+
+    /// Deserialize model from the given directory, with all trees or only those at the given
+    /// indices.
+    fn load_dir(dir_path: &std::path::Path, tree_indices: Option<&[usize]>) -> io::Result<Self> {
         let start_t = time::Instant::now();
 
-        let dir_path = dir_path.as_ref();
         info!("Loading model from {}...", dir_path.display());
 
-        let settings = {
+        // The header is checked first, so that models saved in a newer format are rejected before
+        // anything else is misread
+        let format_header = read_format_header(dir_path)?;
+        match format_header.as_ref() {
+            Some(header) => info!(
+                "Model was saved with format v{} by omikuji {}",
+                header.format_version, header.crate_version
+            ),
+            None => info!("No format header found; loading model in the legacy format"),
+        }
+
+        let settings: Settings = {
             let settings_path = dir_path.join(MODEL_SETTINGS_FILE_NAME);
             info!("Loading model settings from {}...", settings_path.display());
             let reader = std::io::BufReader::new(std::fs::File::open(settings_path)?);
@@ -183,45 +1418,150 @@ impl Model {
         };
         info!("Loaded model settings {:?}...", settings);
 
-        let mut trees = Vec::<TreeNode>::new();
-        for entry in dir_path.read_dir()? {
-            let entry = entry?;
+        let label_thresholds = {
+            let label_thresholds_path = dir_path.join(LABEL_THRESHOLDS_FILE_NAME);
+            if label_thresholds_path.exists() {
+                info!(
+                    "Loading label thresholds from {}...",
+                    label_thresholds_path.display()
+                );
+                let reader = std::io::BufReader::new(std::fs::File::open(label_thresholds_path)?);
+                Some(serde_json::from_reader(reader)?)
+            } else {
+                None
+            }
+        };
 
-            let has_tree_file_name = {
-                let file_name = entry.file_name();
-                let file_name_str = file_name.to_string_lossy();
-                file_name_str.starts_with(TREE_FILE_NAME_PREFIX) && file_name_str.ends_with(".cbor")
-            };
-            if !has_tree_file_name {
-                continue;
+        let mut training_metadata: train::TrainingMetadata = {
+            let metadata_path = dir_path.join(TRAINING_METADATA_FILE_NAME);
+            if metadata_path.exists() {
+                let reader = std::io::BufReader::new(std::fs::File::open(metadata_path)?);
+                serde_json::from_reader(reader)?
+            } else {
+                train::TrainingMetadata::default()
             }
+        };
 
-            let tree_path = entry.path();
-            info!("Loading tree from {}...", tree_path.display());
-            let reader = std::io::BufReader::new(std::fs::File::open(tree_path.as_path())?);
-            let tree: TreeNode = serde_cbor::from_reader(reader).map_err(|e| {
-                io::Error::new(
-                    io::ErrorKind::InvalidData,
-                    format!(
-                        "Unable to deserialize tree from {} with error: {}",
-                        tree_path.display(),
-                        e
-                    ),
-                )
-            })?;
-            if !tree.is_valid(settings) {
-                return Err(io::Error::new(
-                    io::ErrorKind::InvalidData,
-                    format!("Tree loaded from {} is invalid", tree_path.display()),
-                ));
+        let inference_limits: limits::InferenceLimits = {
+            let limits_path = dir_path.join(INFERENCE_LIMITS_FILE_NAME);
+            if limits_path.exists() {
+                let reader = std::io::BufReader::new(std::fs::File::open(limits_path)?);
+                serde_json::from_reader(reader)?
+            } else {
+                limits::InferenceLimits::default()
             }
-            trees.push(tree);
+        };
+
+        let feature_projection = {
+            let projection_path = dir_path.join(FEATURE_PROJECTION_FILE_NAME);
+            if projection_path.exists() {
+                let reader = std::io::BufReader::new(std::fs::File::open(projection_path)?);
+                Some(serde_json::from_reader(reader)?)
+            } else {
+                None
+            }
+        };
+
+        let input_profile = {
+            let profile_path = dir_path.join(INPUT_PROFILE_FILE_NAME);
+            if profile_path.exists() {
+                let reader = std::io::BufReader::new(std::fs::File::open(profile_path)?);
+                Some(serde_json::from_reader(reader)?)
+            } else {
+                None
+            }
+        };
+
+        let label_graph = {
+            let graph_path = dir_path.join(LABEL_GRAPH_FILE_NAME);
+            if graph_path.exists() {
+                let reader = std::io::BufReader::new(std::fs::File::open(graph_path)?);
+                Some(serde_json::from_reader(reader)?)
+            } else {
+                None
+            }
+        };
+
+        let original_labels = {
+            let labels_path = dir_path.join(ORIGINAL_LABELS_FILE_NAME);
+            if labels_path.exists() {
+                let reader = std::io::BufReader::new(std::fs::File::open(labels_path)?);
+                Some(serde_json::from_reader(reader)?)
+            } else {
+                None
+            }
+        };
+
+        let label_names = {
+            let names_path = dir_path.join(LABEL_NAMES_FILE_NAME);
+            if names_path.exists() {
+                let reader = std::io::BufReader::new(std::fs::File::open(names_path)?);
+                Some(serde_json::from_reader(reader)?)
+            } else {
+                None
+            }
+        };
+
+        let used_features = {
+            let features_path = dir_path.join(USED_FEATURES_FILE_NAME);
+            if features_path.exists() {
+                let reader = std::io::BufReader::new(std::fs::File::open(features_path)?);
+                Some(serde_json::from_reader(reader)?)
+            } else {
+                None
+            }
+        };
+        compact::check_used_features(settings, used_features.as_deref())?;
+
+        let tree_paths = tree_file_paths(dir_path)?;
+        let n_tree_files = tree_paths.len();
+        if let Some(header) = format_header.as_ref() {
+            header.check_model(settings.n_features, n_tree_files)?;
+        }
+        let n_summaries = training_metadata.tree_weight_summaries.len();
+        if n_summaries != 0 && n_summaries != n_tree_files {
+            warn!(
+                "Found {} tree weight summaries for {} trees; ignoring them",
+                n_summaries, n_tree_files
+            );
+            training_metadata.tree_weight_summaries.clear();
         }
 
+        let tree_paths = match tree_indices {
+            Some(tree_indices) => {
+                framed::check_tree_indices(tree_indices, n_tree_files)?;
+                training_metadata = training_metadata.select_trees(n_tree_files, tree_indices);
+                tree_indices.iter().map(|&i| &tree_paths[i]).collect_vec()
+            }
+            None => tree_paths.iter().collect_vec(),
+        };
+        let checksums = tree_paths
+            .iter()
+            .map(|tree_path| {
+                format_header
+                    .as_ref()
+                    .and_then(|header| header.tree_checksum(tree_path))
+            })
+            .collect_vec();
+        let n_unchecked = checksums.iter().filter(|c| c.is_none()).count();
+        if n_unchecked > 0 {
+            warn!(
+                "{} tree files have no checksum, so their integrity can't be verified",
+                n_unchecked
+            );
+        }
+        // Trees are in separate files, so they can be read and deserialized in parallel
+        let trees = tree_paths
+            .into_par_iter()
+            .zip(checksums)
+            .map(|(tree_path, checksum)| load_tree_file(tree_path, settings, checksum))
+            .collect::<io::Result<Vec<_>>>()?;
+
         if !trees.is_empty() {
             info!(
-                "Loaded model with {} trees; it took {:.2}s",
+                "Loaded {} of {} trees; it took {:.2}s",
                 trees.len(),
+                n_tree_files,
                 start_t.elapsed().as_secs_f32()
             );
         } else {
@@ -230,10 +1570,28 @@ impl Model {
                 dir_path.display()
             )
         }
-        Ok(Self { trees, settings })
+        let model = Self {
+            trees,
+            settings,
+            label_thresholds,
+            training_metadata,
+            inference_limits,
+            feature_projection,
+            input_profile,
+            label_graph,
+            original_labels,
+            label_names,
+            used_features,
+            sorted_labels: Default::default(),
+            label_index: Default::default(),
+        };
+        model.check_loaded_label_names()?;
+        Ok(model)
     }
 
     /// Densify model weights to speed up prediction at the cost of more memory usage.
+    ///
+    /// See [`Self::set_density_policy`] for choosing the storage of each matrix.
     pub fn densify_weights(&mut self, max_sparse_density: f32) {
         info!("Densifying model weights...");
         let start_t = time::Instant::now();
@@ -268,13 +1626,13 @@ impl TreeNode {
                 ref weights,
                 ref children,
             } => {
-                weights.shape() == (settings.n_features + 1, children.len())
+                weights.shape() == (settings.n_weight_features() + 1, children.len())
                     && children.iter().all(|c| c.is_valid(settings))
             }
             TreeNode::Leaf {
                 ref weights,
                 ref labels,
-            } => weights.shape() == (settings.n_features + 1, labels.len()),
+            } => weights.shape() == (settings.n_weight_features() + 1, labels.len()),
         }
     }
 
@@ -282,6 +1640,61 @@ impl TreeNode {
         matches!(self, TreeNode::Leaf { .. })
     }
 
+    /// Call the given function on weight matrices of all nodes in the subtree, in pre-order.
+    fn visit_weights<F: FnMut(&WeightMat)>(&self, f: &mut F) {
+        match self {
+            TreeNode::Branch {
+                ref weights,
+                ref children,
+            } => {
+                f(weights);
+                for child in children {
+                    child.visit_weights(f);
+                }
+            }
+            TreeNode::Leaf { ref weights, .. } => f(weights),
+        }
+    }
+
+    /// Prune weights of all nodes in the subtree, returning the number of weights removed.
+    ///
+    /// Each matrix is then stored in whichever format takes up less memory.
+    fn prune_weights(&mut self, threshold: f32) -> usize {
+        fn prune(weights: &mut WeightMat, threshold: f32) -> usize {
+            let n_removed = weights.prune_with_threshold(threshold);
+            weights.shrink_storage();
+            n_removed
+        }
+
+        match self {
+            TreeNode::Branch {
+                ref mut weights,
+                ref mut children,
+            } => {
+                prune(weights, threshold)
+                    + children
+                        .par_iter_mut()
+                        .map(|child| child.prune_weights(threshold))
+                        .sum::<usize>()
+            }
+            TreeNode::Leaf {
+                ref mut weights, ..
+            } => prune(weights, threshold),
+        }
+    }
+
+    /// Append labels of all leaves in the subtree to the given vector.
+    fn collect_labels(&self, all_labels: &mut Vec<Index>) {
+        match self {
+            TreeNode::Branch { ref children, .. } => {
+                for child in children {
+                    child.collect_labels(all_labels);
+                }
+            }
+            TreeNode::Leaf { ref labels, .. } => all_labels.extend_from_slice(labels),
+        }
+    }
+
     fn densify_weights(&mut self, max_sparse_density: f32) {
         fn densify(weights: &mut WeightMat, max_sparse_density: f32) {
             if !weights.is_dense() && weights.density() > max_sparse_density {
@@ -307,69 +1720,503 @@ impl TreeNode {
         }
     }
 
-    fn predict(
-        &self,
+    /// The largest frontier width during beam search or number of labels in a leaf, whichever is
+    /// larger.
+    fn max_useful_beam(&self) -> usize {
+        let mut max_beam = 1;
+        let mut n_carried_leaves = 0;
+        let mut curr_level = vec![self];
+        while !curr_level.is_empty() {
+            let mut next_level = Vec::new();
+            for node in curr_level {
+                match node {
+                    TreeNode::Branch { children, .. } => next_level.extend(children),
+                    TreeNode::Leaf { labels, .. } => {
+                        // Leaves stay on the frontier until all branches are expanded
+                        n_carried_leaves += 1;
+                        max_beam = max_beam.max(labels.len());
+                    }
+                }
+            }
+            max_beam = max_beam.max(n_carried_leaves + next_level.len());
+            curr_level = next_level;
+        }
+        max_beam
+    }
+
+    /// Beam search for the highest-scoring labels within the given budget, appending them to the
+    /// label-score pairs of the buffers; returns an error message if the tree turns out to be
+    /// malformed.
+    fn try_predict<'a>(
+        &'a self,
         classifier_loss_type: liblinear::LossType,
         feature_vec: &SparseVec,
         beam_size: usize,
-    ) -> IndexValueVec {
+        leaf_transform: predict::LeafTransform,
+        budget: &mut limits::SearchBudget,
+        buffers: &mut SearchBuffers<'a>,
+    ) -> Result<(), SearchError> {
+        let frontier = &mut buffers.frontier;
+        frontier.nodes.clear();
+        frontier.nodes.push((self, 0., ()));
+        if let Some(depths) = frontier.depths.as_mut() {
+            depths.clear();
+        }
+        Self::expand_frontier(
+            frontier,
+            classifier_loss_type,
+            feature_vec,
+            beam_size,
+            usize::MAX,
+            |_, _| Some(()),
+            budget,
+        )?;
+        Self::score_leaves(
+            frontier,
+            classifier_loss_type,
+            feature_vec,
+            beam_size,
+            leaf_transform,
+            budget,
+            &mut buffers.label_score_pairs,
+        )
+    }
+
+    /// Continue beam search from the given frontier of nodes with their path scores, until only
+    /// leaves are left or the given number of levels have been expanded.
+    ///
+    /// Each node on the frontier carries a payload, and the payload of a child is derived from
+    /// that of its parent and its position among its siblings; children without a payload are
+    /// dropped. Branches that don't fit in the budget are dropped too.
+    fn expand_frontier<'a, P>(
+        frontier: &mut Frontier<'a, P>,
+        classifier_loss_type: liblinear::LossType,
+        feature_vec: &SparseVec,
+        beam_size: usize,
+        max_levels: usize,
+        child_payload: impl Fn(&P, usize) -> Option<P>,
+        budget: &mut limits::SearchBudget,
+    ) -> Result<(), SearchError> {
         assert!(beam_size > 0);
-        let mut curr_level = Vec::<(&TreeNode, f32)>::with_capacity(beam_size * 2);
-        let mut next_level = Vec::<(&TreeNode, f32)>::with_capacity(beam_size * 2);
 
-        curr_level.push((self, 0.));
+        // NB: the frontier may be much narrower than the beam, so we let its buffers grow as
+        // needed instead of allocating for the full beam upfront
+        let Frontier {
+            nodes,
+            next_level,
+            scores,
+            depths,
+            score_gap,
+        } = frontier;
 
         // Iterate until only leaves are left
-        while curr_level.iter().any(|(node, _)| !node.is_leaf()) {
-            assert!(!curr_level.is_empty());
+        let mut n_levels = 0;
+        while n_levels < max_levels && nodes.iter().any(|(node, _, _)| !node.is_leaf()) {
+            let level_start_t = depths.is_some().then(time::Instant::now);
+            let mut n_branches = 0;
             next_level.clear();
-            for &(node, node_score) in &curr_level {
+            if budget.is_limited() {
+                sort_by_score_desc(nodes);
+            }
+            for (node, node_score, payload) in nodes.drain(..) {
                 match node {
                     TreeNode::Branch { weights, children } => {
-                        let mut child_scores =
-                            liblinear::predict(weights, classifier_loss_type, feature_vec);
+                        if !budget.take_node() {
+                            continue;
+                        }
+                        check_shape(weights, (feature_vec.dim(), children.len()))?;
+                        n_branches += 1;
+                        let mut child_scores = liblinear::predict(
+                            weights,
+                            classifier_loss_type,
+                            feature_vec,
+                            mem::take(scores),
+                        );
+                        if child_scores.iter().any(|score| score.is_nan()) {
+                            return Err(SearchError::NanScore);
+                        }
                         child_scores += node_score;
-                        next_level
-                            .extend(children.iter().zip_eq(child_scores.into_iter().cloned()));
+                        next_level.extend(
+                            children
+                                .iter()
+                                .zip(child_scores.iter().cloned())
+                                .enumerate()
+                                .filter_map(|(i, (child, score))| {
+                                    Some((child, score, child_payload(&payload, i)?))
+                                }),
+                        );
+                        *scores = child_scores.into_raw_vec();
                     }
                     TreeNode::Leaf { .. } => {
-                        next_level.push((node, node_score));
+                        next_level.push((node, node_score, payload));
                     }
                 }
             }
 
-            swap(&mut curr_level, &mut next_level);
-            if curr_level.len() > beam_size {
-                pdqselect::select_by_key(curr_level.as_mut_slice(), beam_size, |&(_, score)| {
-                    Reverse(NotNan::new(score).unwrap())
+            swap(nodes, next_level);
+            if nodes.len() > beam_size {
+                truncate_frontier(nodes, beam_size, scores);
+            }
+            if let Some(score_gap) = *score_gap {
+                let best_score = nodes
+                    .iter()
+                    .map(|&(_, score, _)| score)
+                    .fold(f32::NEG_INFINITY, f32::max);
+                nodes.retain(|&(_, score, _)| score >= best_score - score_gap);
+            }
+            if let (Some(depths), Some(start_t)) = (depths.as_mut(), level_start_t) {
+                depths.push(predict::DepthProfile {
+                    n_nodes: n_branches,
+                    time: start_t.elapsed(),
                 });
-                curr_level.truncate(beam_size);
             }
+            n_levels += 1;
         }
+        Ok(())
+    }
 
-        curr_level
-            .iter()
-            .flat_map(|&(leaf, leaf_score)| match leaf {
+    /// Score labels in the leaves of a fully expanded frontier, appending them to the given
+    /// label-score pairs and skipping leaves that don't fit in the budget.
+    fn score_leaves<P>(
+        frontier: &mut Frontier<'_, P>,
+        classifier_loss_type: liblinear::LossType,
+        feature_vec: &SparseVec,
+        beam_size: usize,
+        leaf_transform: predict::LeafTransform,
+        budget: &mut limits::SearchBudget,
+        label_score_pairs: &mut IndexValueVec,
+    ) -> Result<(), SearchError> {
+        let start_t = frontier.depths.is_some().then(time::Instant::now);
+        let mut n_leaves = 0;
+        if budget.is_limited() {
+            sort_by_score_desc(&mut frontier.nodes);
+        }
+        for &(leaf, leaf_score, _) in frontier.nodes.iter() {
+            match leaf {
                 TreeNode::Leaf { weights, labels } => {
-                    let mut label_scores =
-                        liblinear::predict(weights, classifier_loss_type, feature_vec);
-                    label_scores.mapv_inplace(|v| (v + leaf_score).exp());
-
-                    let mut label_score_pairs = labels
-                        .iter()
-                        .cloned()
-                        .zip_eq(label_scores.into_iter().cloned())
-                        .collect_vec();
-                    pdqselect::select_by_key(
-                        label_score_pairs.as_mut_slice(),
-                        beam_size,
-                        |&(_, score)| Reverse(NotNan::new(score).unwrap()),
+                    if !budget.take_leaf(labels.len()) {
+                        continue;
+                    }
+                    check_shape(weights, (feature_vec.dim(), labels.len()))?;
+                    n_leaves += 1;
+                    let label_scores = leaf_transform.score_leaf(
+                        weights,
+                        classifier_loss_type,
+                        feature_vec,
+                        leaf_score,
+                        mem::take(&mut frontier.scores),
                     );
-                    label_score_pairs.truncate(beam_size);
+                    if label_scores.iter().any(|score| score.is_nan()) {
+                        return Err(SearchError::NanScore);
+                    }
+
+                    let start = label_score_pairs.len();
                     label_score_pairs
+                        .extend(labels.iter().cloned().zip(label_scores.iter().cloned()));
+                    let leaf_label_score_pairs = &mut label_score_pairs[start..];
+                    if leaf_label_score_pairs.len() > beam_size {
+                        pdqselect::select_by_key(leaf_label_score_pairs, beam_size, |&pair| {
+                            label_rank(pair)
+                        });
+                        label_score_pairs.truncate(start + beam_size);
+                    }
+                    frontier.scores = label_scores.into_raw_vec();
                 }
                 _ => unreachable!(),
-            })
-            .collect_vec()
+            }
+        }
+        if let (Some(depths), Some(start_t)) = (frontier.depths.as_mut(), start_t) {
+            depths.push(predict::DepthProfile {
+                n_nodes: n_leaves,
+                time: start_t.elapsed(),
+            });
+        }
+        Ok(())
+    }
+}
+
+/// Why beam search in a tree failed.
+#[derive(Debug)]
+enum SearchError {
+    /// The tree is malformed, e.g., a node's weight matrix doesn't match its children.
+    Corrupt(String),
+    /// A classifier scored NaN, e.g., on an input with infinite values.
+    NanScore,
+}
+
+impl SearchError {
+    /// The error of checked prediction for this error in the given tree.
+    fn into_predict_error(self, tree: usize) -> PredictError {
+        match self {
+            SearchError::Corrupt(message) => PredictError::ModelCorrupt { tree, message },
+            SearchError::NanScore => PredictError::NanScore { tree },
+        }
+    }
+}
+
+impl From<String> for SearchError {
+    fn from(message: String) -> Self {
+        SearchError::Corrupt(message)
+    }
+}
+
+impl fmt::Display for SearchError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SearchError::Corrupt(message) => write!(f, "{}", message),
+            SearchError::NanScore => write!(f, "a classifier scored NaN; is the input finite?"),
+        }
+    }
+}
+
+/// A beam search frontier of nodes with their path scores and payloads, along with storage that
+/// is reused while expanding it.
+struct Frontier<'a, P> {
+    nodes: Vec<(&'a TreeNode, f32, P)>,
+    next_level: Vec<(&'a TreeNode, f32, P)>,
+    scores: Vec<f32>,
+    /// If set, the nodes evaluated and the time spent at each depth are recorded here, with the
+    /// scored leaves last.
+    depths: Option<Vec<predict::DepthProfile>>,
+    /// If set, nodes whose path score trails the best at their level by more than this are
+    /// dropped, even if the beam isn't full.
+    score_gap: Option<f32>,
+}
+
+impl<'a, P> Frontier<'a, P> {
+    fn new(nodes: Vec<(&'a TreeNode, f32, P)>) -> Self {
+        Self {
+            nodes,
+            next_level: Vec::new(),
+            scores: Vec::new(),
+            depths: None,
+            score_gap: None,
+        }
+    }
+}
+
+impl<P> Default for Frontier<'_, P> {
+    fn default() -> Self {
+        Self::new(Vec::new())
+    }
+}
+
+/// Storage for beam search in the trees of a model, reused across predictions to avoid
+/// allocating for each.
+#[derive(Default)]
+struct SearchBuffers<'a> {
+    frontier: Frontier<'a, ()>,
+    /// Label-score pairs from all trees searched so far, in tree order.
+    label_score_pairs: IndexValueVec,
+    label_score_sums: LabelScoreSums,
+    /// If set, searches are profiled into it.
+    profile: Option<predict::BatchPredictProfile>,
+}
+
+impl SearchBuffers<'_> {
+    /// Buffers that also profile the searches made with them.
+    fn profiled() -> Self {
+        Self {
+            frontier: Frontier {
+                depths: Some(Vec::new()),
+                ..Frontier::default()
+            },
+            profile: Some(predict::BatchPredictProfile::default()),
+            ..Self::default()
+        }
+    }
+}
+
+/// How many times the number of labels label indices can reach for predictions to sum scores in a
+/// vector indexed by label; see [`Model::dense_label_bound`].
+const MAX_DENSE_LABEL_SPREAD: usize = 4;
+
+/// Total scores by label, reused across predictions.
+///
+/// Labels below a known bound are summed in a vector indexed by label, which is cheaper than
+/// hashing, with the labels seen so far listed so that draining only visits those; others are
+/// summed in a hash map.
+#[derive(Default)]
+struct LabelScoreSums {
+    totals: Vec<f32>,
+    seen: Vec<bool>,
+    seen_labels: Vec<Index>,
+    map: HashMap<Index, f32>,
+}
+
+impl LabelScoreSums {
+    fn with_map_capacity(capacity: usize) -> Self {
+        Self {
+            map: HashMap::with_capacity(capacity),
+            ..Self::default()
+        }
+    }
+
+    /// Add the scores of the given pairs to the totals of their labels, in a vector indexed by
+    /// label if all labels are below `label_bound`, or else in a hash map.
+    ///
+    /// The sums must be empty, or only have been added to with the same `label_bound`.
+    fn add(
+        &mut self,
+        label_score_pairs: impl Iterator<Item = (Index, f32)>,
+        label_bound: Option<usize>,
+    ) {
+        match label_bound {
+            Some(label_bound) => {
+                if self.totals.len() != label_bound {
+                    debug_assert!(self.seen_labels.is_empty());
+                    self.totals = vec![0.; label_bound];
+                    self.seen = vec![false; label_bound];
+                }
+                for (label, score) in label_score_pairs {
+                    let i = label as usize;
+                    if !self.seen[i] {
+                        self.seen[i] = true;
+                        self.seen_labels.push(label);
+                    }
+                    self.totals[i] += score;
+                }
+            }
+            None => {
+                for (label, score) in label_score_pairs {
+                    *self.map.entry(label).or_insert(0.) += score;
+                }
+            }
+        }
+    }
+
+    /// The number of labels with a total.
+    fn len(&self) -> usize {
+        self.seen_labels.len() + self.map.len()
+    }
+
+    /// Take the labels with their totals, in no particular order, leaving the sums empty.
+    fn drain(&mut self) -> impl ExactSizeIterator<Item = (Index, f32)> + '_ {
+        let Self {
+            totals,
+            seen,
+            seen_labels,
+            map,
+        } = self;
+        if seen_labels.is_empty() {
+            itertools::Either::Left(map.drain())
+        } else {
+            debug_assert!(map.is_empty());
+            itertools::Either::Right(seen_labels.drain(..).map(move |label| {
+                let i = label as usize;
+                seen[i] = false;
+                (label, mem::take(&mut totals[i]))
+            }))
+        }
+    }
+}
+
+/// Sort nodes on a frontier by decreasing path score, keeping the order of nodes with equal
+/// scores.
+fn sort_by_score_desc<P>(frontier: &mut [(&TreeNode, f32, P)]) {
+    frontier.sort_by_key(|&(_, score, _)| Reverse(NotNan::new(score).unwrap()));
+}
+
+/// Keep the `beam_size` nodes with the highest path scores on a frontier, in their order.
+///
+/// Nodes with equal scores are kept in the order they are on the frontier, i.e., by the rank of
+/// their parents and then by child index, so that ties are broken the same way in every run. The
+/// cutoff score is selected in a copy of the scores, in the given buffer, to leave the nodes in
+/// place.
+fn truncate_frontier<P>(
+    frontier: &mut Vec<(&TreeNode, f32, P)>,
+    beam_size: usize,
+    scores: &mut Vec<f32>,
+) {
+    debug_assert!(0 < beam_size && beam_size < frontier.len());
+    scores.clear();
+    scores.extend(frontier.iter().map(|&(_, score, _)| score));
+    let (cutoff, mut n_ties_kept) = frontier_cutoff(scores, beam_size);
+    frontier.retain(|&(_, score, _)| {
+        if score > cutoff {
+            true
+        } else if score == cutoff && n_ties_kept > 0 {
+            n_ties_kept -= 1;
+            true
+        } else {
+            false
+        }
+    });
+}
+
+/// The lowest of the `beam_size` highest scores among the given ones, which are reordered, and
+/// how many scores equal to it are among the `beam_size` highest.
+fn frontier_cutoff(scores: &mut [f32], beam_size: usize) -> (f32, usize) {
+    pdqselect::select_by_key(scores, beam_size - 1, |&score| {
+        Reverse(NotNan::new(score).unwrap())
+    });
+    let cutoff = scores[beam_size - 1];
+    let n_ties_kept = beam_size
+        - scores[..beam_size - 1]
+            .iter()
+            .filter(|&&score| score > cutoff)
+            .count();
+    (cutoff, n_ties_kept)
+}
+
+/// The rank of a label-score pair: by decreasing score, and then by increasing label.
+fn label_rank((label, score): (Index, f32)) -> (Reverse<NotNan<f32>>, Index) {
+    (Reverse(NotNan::new(score).unwrap()), label)
+}
+
+/// Rank label-score pairs by decreasing score, breaking ties by increasing label, and return
+/// the first `k`.
+///
+/// When fewer than all pairs are wanted, a bounded max-heap keyed by rank keeps the `k` best
+/// pairs seen so far, with the worst of them on top to be replaced by any better pair; only those
+/// `k` are sorted at the end. Since the ranking is a strict total order, the result is exactly the
+/// first `k` pairs of the fully sorted list.
+fn rank_top_k(
+    label_score_pairs: impl ExactSizeIterator<Item = (Index, f32)>,
+    k: usize,
+) -> IndexValueVec {
+    if k >= label_score_pairs.len() {
+        let mut label_score_pairs = label_score_pairs.collect_vec();
+        label_score_pairs.sort_unstable_by_key(|&pair| label_rank(pair));
+        return label_score_pairs;
+    }
+    if k == 0 {
+        return Vec::new();
+    }
+
+    let mut heap = BinaryHeap::with_capacity(k);
+    for pair in label_score_pairs {
+        let key = label_rank(pair);
+        if heap.len() < k {
+            heap.push(key);
+        } else if key < *heap.peek().unwrap() {
+            *heap.peek_mut().unwrap() = key;
+        }
+    }
+    heap.into_sorted_vec()
+        .into_iter()
+        .map(|(Reverse(score), label)| (label, score.into_inner()))
+        .collect()
+}
+
+/// Like [`rank_top_k`], but leaves the first `k` pairs in no particular order.
+fn select_top_k(label_score_pairs: impl Iterator<Item = (Index, f32)>, k: usize) -> IndexValueVec {
+    let mut label_score_pairs = label_score_pairs.collect_vec();
+    if k < label_score_pairs.len() {
+        pdqselect::select_by_key(&mut label_score_pairs, k, |&pair| label_rank(pair));
+        label_score_pairs.truncate(k);
+    }
+    label_score_pairs
+}
+
+fn check_shape(weights: &WeightMat, expected: (usize, usize)) -> Result<(), String> {
+    if weights.shape() == expected {
+        Ok(())
+    } else {
+        Err(format!(
+            "weight matrix has shape {:?}, but {:?} is expected",
+            weights.shape(),
+            expected
+        ))
     }
 }
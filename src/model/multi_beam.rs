@@ -0,0 +1,215 @@
+//! Beam search with several beam sizes at once.
+//!
+//! A wider beam doesn't always keep the nodes kept by a narrower one, since the nodes only it keeps
+//! can push others out at deeper levels. So rather than searching with the widest beam alone, each
+//! node on the frontier is tagged with the beams that keep it, which reproduces the search with
+//! each beam size exactly while evaluating each classifier on the way only once.
+use super::{
+    check_shape, frontier_cutoff, label_rank, limits, predict, Frontier, Model, SearchError,
+    TreeNode,
+};
+use crate::{Index, IndexValueVec};
+use itertools::Itertools;
+
+/// The most beam sizes that [`Model::predict_multi_beam`] can search with at once.
+pub const MAX_BEAM_SIZES: usize = 64;
+
+/// Keep only the `beam_size` highest-scoring nodes among those tagged with the given beam bit,
+/// untagging the others, with ties kept in frontier order as in beam search with a single beam.
+fn truncate_beam(
+    nodes: &mut [(&TreeNode, f32, u64)],
+    bit: u64,
+    beam_size: usize,
+    scores: &mut Vec<f32>,
+) {
+    scores.clear();
+    scores.extend(
+        nodes
+            .iter()
+            .filter(|&&(_, _, beams)| beams & bit != 0)
+            .map(|&(_, score, _)| score),
+    );
+    if scores.len() <= beam_size {
+        return;
+    }
+    let (cutoff, mut n_ties_kept) = frontier_cutoff(scores, beam_size);
+    for (_, score, beams) in nodes.iter_mut().filter(|(_, _, beams)| *beams & bit != 0) {
+        if *score > cutoff {
+            continue;
+        }
+        if *score == cutoff && n_ties_kept > 0 {
+            n_ties_kept -= 1;
+        } else {
+            *beams &= !bit;
+        }
+    }
+}
+
+/// Check that beam sizes are as [`Model::predict_multi_beam`] requires: between 1 and
+/// [`MAX_BEAM_SIZES`] of them, positive and strictly increasing.
+pub fn check_beam_sizes(beam_sizes: &[usize]) -> Result<(), String> {
+    if beam_sizes.is_empty() || beam_sizes.len() > MAX_BEAM_SIZES {
+        return Err(format!(
+            "Between 1 and {} beam sizes must be given, got {}",
+            MAX_BEAM_SIZES,
+            beam_sizes.len()
+        ));
+    }
+    if beam_sizes[0] == 0 || beam_sizes.windows(2).any(|w| w[0] >= w[1]) {
+        return Err(format!(
+            "Beam sizes must be positive and strictly increasing, got {:?}",
+            beam_sizes
+        ));
+    }
+    Ok(())
+}
+
+impl Model {
+    /// Like [`Self::predict`], but returns the predictions for each of the given beam sizes, in
+    /// the same order, from a single search.
+    ///
+    /// The predictions are the same as those of predicting with each beam size separately, but
+    /// each classifier is evaluated at most once for all beam sizes. Beam sizes that aren't as
+    /// [`check_beam_sizes`] requires are an error. Under inference limits on the nodes visited or
+    /// leaf labels scored, which depend on the order of the search, each beam size is searched
+    /// separately.
+    pub fn predict_multi_beam(
+        &self,
+        feature_vec: impl AsRef<[(Index, f32)]>,
+        beam_sizes: &[usize],
+    ) -> Result<Vec<IndexValueVec>, String> {
+        check_beam_sizes(beam_sizes)?;
+        let feature_vec = self.prepare_feature_vec(feature_vec.as_ref());
+        let mut budget = limits::SearchBudget::new(&self.inference_limits);
+        if budget.is_limited() {
+            return Ok(beam_sizes
+                .iter()
+                .map(|&beam_size| self.predict_prepared(&feature_vec, beam_size))
+                .collect());
+        }
+
+        let loss_type = self.settings.classifier_loss_type;
+        let beam_sizes = beam_sizes
+            .iter()
+            .map(|&beam_size| self.inference_limits.beam_size(beam_size, &mut budget.hits))
+            .collect_vec();
+        let all_beams = u64::MAX >> (MAX_BEAM_SIZES - beam_sizes.len());
+
+        let mut tree_predictions = vec![Vec::with_capacity(self.trees.len()); beam_sizes.len()];
+        let mut scores = Vec::new();
+        for root in &self.trees {
+            let mut frontier = Frontier::new(vec![(root, 0., all_beams)]);
+            while frontier.nodes.iter().any(|(node, _, _)| !node.is_leaf()) {
+                // Expand the union of the beams, then truncate each beam on its own
+                TreeNode::expand_frontier(
+                    &mut frontier,
+                    loss_type,
+                    &feature_vec,
+                    usize::MAX,
+                    1,
+                    |&beams: &u64, _| Some(beams),
+                    &mut budget,
+                )
+                .unwrap_or_else(|error| panic!("Corrupt tree: {}", error));
+                for (i, &beam_size) in beam_sizes.iter().enumerate() {
+                    truncate_beam(&mut frontier.nodes, 1 << i, beam_size, &mut scores);
+                }
+                frontier.nodes.retain(|&(_, _, beams)| beams != 0);
+            }
+
+            let mut label_score_pairs = vec![Vec::new(); beam_sizes.len()];
+            for &(leaf, leaf_score, beams) in &frontier.nodes {
+                let (weights, labels) = match leaf {
+                    TreeNode::Leaf { weights, labels } => (weights, labels),
+                    _ => unreachable!(),
+                };
+                check_shape(weights, (feature_vec.dim(), labels.len()))
+                    .unwrap_or_else(|message| panic!("Corrupt tree: {}", message));
+                let label_scores = predict::LeafTransform::Exp.score_leaf(
+                    weights,
+                    loss_type,
+                    &feature_vec,
+                    leaf_score,
+                    Vec::new(),
+                );
+                if label_scores.iter().any(|score| score.is_nan()) {
+                    panic!("Corrupt tree: {}", SearchError::NanScore);
+                }
+
+                // Each beam takes the best labels of the leaf up to its size
+                let mut leaf_pairs = labels
+                    .iter()
+                    .cloned()
+                    .zip(label_scores.iter().cloned())
+                    .collect_vec();
+                leaf_pairs.sort_unstable_by_key(|&pair| label_rank(pair));
+                for (i, &beam_size) in beam_sizes.iter().enumerate() {
+                    if beams & (1 << i) != 0 {
+                        label_score_pairs[i]
+                            .extend_from_slice(&leaf_pairs[..beam_size.min(leaf_pairs.len())]);
+                    }
+                }
+            }
+            for (predictions, pairs) in tree_predictions.iter_mut().zip(label_score_pairs) {
+                predictions.push(pairs);
+            }
+        }
+
+        Ok(tree_predictions
+            .into_iter()
+            .map(|tree_predictions| {
+                self.average_tree_predictions(tree_predictions, self.trees.len(), |n_labels| {
+                    self.inference_limits
+                        .n_labels_returned(None, n_labels, &mut budget.hits)
+                })
+            })
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{toy_dataset, toy_model};
+
+    #[test]
+    fn test_predict_multi_beam() {
+        let model = toy_model(3, 0);
+        let beam_sizes = [1, 2, 3, 5, 10];
+        for feature_vec in &toy_dataset(20, 8, 1).feature_lists {
+            let predictions = model.predict_multi_beam(feature_vec, &beam_sizes).unwrap();
+            assert_eq!(beam_sizes.len(), predictions.len());
+            for (&beam_size, predictions) in beam_sizes.iter().zip(&predictions) {
+                assert_eq!(&model.predict(feature_vec, beam_size), predictions);
+            }
+        }
+
+        let mut model = model;
+        model
+            .set_inference_limits(limits::InferenceLimits {
+                max_beam: Some(2),
+                max_nodes_visited: Some(4),
+                ..limits::InferenceLimits::default()
+            })
+            .unwrap();
+        for feature_vec in &toy_dataset(5, 8, 2).feature_lists {
+            let predictions = model.predict_multi_beam(feature_vec, &beam_sizes).unwrap();
+            for (&beam_size, predictions) in beam_sizes.iter().zip(&predictions) {
+                assert_eq!(&model.predict(feature_vec, beam_size), predictions);
+            }
+        }
+    }
+
+    #[test]
+    fn test_invalid_beam_sizes() {
+        let model = toy_model(1, 0);
+        let feature_vec = &toy_dataset(1, 8, 1).feature_lists[0];
+        let too_many = (1..=MAX_BEAM_SIZES + 1).collect_vec();
+        for beam_sizes in [&[][..], &[0, 1], &[2, 2], &[3, 1], &too_many] {
+            assert!(check_beam_sizes(beam_sizes).is_err());
+            assert!(model.predict_multi_beam(feature_vec, beam_sizes).is_err());
+        }
+        let most = (1..=MAX_BEAM_SIZES).collect_vec();
+        assert!(model.predict_multi_beam(feature_vec, &most).is_ok());
+    }
+}
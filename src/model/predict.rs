@@ -0,0 +1,1914 @@
+//! Prediction with explicit options, typed errors, and running statistics.
+//!
+//! Unlike [`Model::predict`], which assumes well-formed input, the entry points here check their
+//! input and report problems as [`PredictError`].
+use super::limits::{Limit, LimitHits};
+use super::{liblinear, rank_top_k, scores, FeatureVecError, Model, SearchBuffers};
+use crate::mat_util::*;
+use crate::math;
+use crate::{FeaturePairs, Index, IndexValueVec};
+use const_default::ConstDefault;
+use hashbrown::HashMap;
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::mem;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// How to handle feature indices that are out of range for the model.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum OovPolicy {
+    /// Fail with [`PredictError::FeatureIndexOutOfRange`].
+    Error,
+    /// Ignore out-of-range features, counting them in [`PredictStats::n_oov_dropped`].
+    Drop,
+    /// Map out-of-range indices into the valid range with [`hash_feature_index`], counting them
+    /// in [`PredictStats::n_oov_hashed`].
+    ///
+    /// Values of features that end up with the same index are summed before the vector is
+    /// normalized.
+    HashInto,
+}
+
+/// Rehash a feature index into the range `0..n_features`.
+///
+/// The hash is 32-bit FNV-1a over the little-endian bytes of the index, taken modulo
+/// `n_features`, so the mapping is the same across platforms and runs.
+pub fn hash_feature_index(index: Index, n_features: usize) -> Index {
+    assert!(n_features > 0);
+    let mut hash = 0x811c_9dc5u32;
+    for byte in index.to_le_bytes().iter() {
+        hash ^= u32::from(*byte);
+        hash = hash.wrapping_mul(0x0100_0193);
+    }
+    (hash as usize % n_features) as Index
+}
+
+/// How the labels of a leaf reached by beam search are scored.
+///
+/// Each transform takes the leaf's classifiers, the input, and the leaf's path score `p`, i.e.,
+/// the sum of the log-space scores of the classifiers from the root to the leaf (see
+/// [`scores`](crate::model::scores)). For a label with raw classifier margin `m` and log-space
+/// score `v` (e.g., `-ln(1 + exp(-m))` for log loss), the score within the tree is:
+///
+/// * [`Exp`](Self::Exp): `exp(v + p)`;
+/// * [`Sigmoid`](Self::Sigmoid): `exp(p) * 1 / (1 + exp(-m))`, whatever the loss type;
+/// * [`Identity`](Self::Identity): `v + p`, i.e., the log-space score itself;
+/// * [`SoftmaxWithinLeaf`](Self::SoftmaxWithinLeaf): `exp(p) * s`, where `s` is the softmax of
+///   `m / temperature` over the labels of the leaf. The softmax is taken first, so it only
+///   normalizes over the leaf's own labels, and the path score is multiplied in afterwards.
+///
+/// Scores are then averaged over trees as usual, with labels not reached in a tree counting as 0.
+/// Since that would rank unreached labels above reached ones under [`Identity`](Self::Identity),
+/// it can only be used with single-tree models.
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum LeafTransform {
+    /// The exponential of the path score, as in [`Model::predict`].
+    Exp,
+    /// A sigmoid on raw margins, scaled by the exponential of the path score.
+    Sigmoid,
+    /// The log-space path score, which is at most 0; only for models with a single tree.
+    Identity,
+    /// A softmax on raw margins over the labels of the leaf, scaled by the exponential of the
+    /// path score.
+    SoftmaxWithinLeaf { temperature: f32 },
+}
+
+impl ConstDefault for LeafTransform {
+    const DEFAULT: Self = LeafTransform::Exp;
+}
+
+impl Default for LeafTransform {
+    fn default() -> Self {
+        <Self as ConstDefault>::DEFAULT
+    }
+}
+
+impl LeafTransform {
+    /// The range of scores predicted with this transform by a model with the given loss type,
+    /// as inclusive bounds.
+    pub fn score_range(self, loss_type: liblinear::LossType) -> (f32, f32) {
+        match self {
+            LeafTransform::Exp => scores::range(loss_type),
+            LeafTransform::Sigmoid | LeafTransform::SoftmaxWithinLeaf { .. } => (0., 1.),
+            LeafTransform::Identity => (f32::NEG_INFINITY, 0.),
+        }
+    }
+
+    /// Score the labels of a leaf with the given weights and path score, storing the scores in
+    /// the given buffer.
+    pub(crate) fn score_leaf(
+        self,
+        weights: &WeightMat,
+        loss_type: liblinear::LossType,
+        feature_vec: &SparseVec,
+        path_score: f32,
+        buffer: Vec<f32>,
+    ) -> DenseVec {
+        match self {
+            LeafTransform::Exp => {
+                let mut label_scores = liblinear::predict(weights, loss_type, feature_vec, buffer);
+                label_scores.mapv_inplace(|v| math::exp(v + path_score));
+                label_scores
+            }
+            LeafTransform::Sigmoid => {
+                let path_prob = math::exp(path_score);
+                let mut label_scores = weights.t_dot_vec_with(feature_vec.view(), buffer);
+                label_scores.mapv_inplace(|m| path_prob / (1. + math::exp(-m)));
+                label_scores
+            }
+            LeafTransform::Identity => {
+                let mut label_scores = liblinear::predict(weights, loss_type, feature_vec, buffer);
+                label_scores.mapv_inplace(|v| v + path_score);
+                label_scores
+            }
+            LeafTransform::SoftmaxWithinLeaf { temperature } => {
+                let mut label_scores = weights.t_dot_vec_with(feature_vec.view(), buffer);
+                // Shift by the largest margin so that the exponentials can't overflow
+                let max = label_scores.fold(f32::NEG_INFINITY, |max, &m| max.max(m));
+                label_scores.mapv_inplace(|m| math::exp((m - max) / temperature));
+                let path_prob = math::exp(path_score);
+                let sum = label_scores.sum();
+                label_scores.mapv_inplace(|e| path_prob * (e / sum));
+                label_scores
+            }
+        }
+    }
+}
+
+/// How label scores are combined over the trees of a model.
+///
+/// A label not reached in a tree has a score of 0 from that tree, except under
+/// [`Aggregation::GeometricMean`].
+#[derive(Copy, Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub enum Aggregation {
+    /// The arithmetic mean of scores, as in [`Model::predict`].
+    #[default]
+    Mean,
+    /// The maximum of scores.
+    Max,
+    /// The geometric mean of scores, where scores below the floor are raised to it; labels not
+    /// reached in a tree have the floor as their score from that tree.
+    GeometricMean { floor: f32 },
+    /// The sum of scores.
+    Sum,
+}
+
+impl Aggregation {
+    /// Check if the aggregation is valid.
+    pub fn validate(&self) -> Result<(), String> {
+        match *self {
+            Aggregation::GeometricMean { floor } if !(floor >= 0. && floor.is_finite()) => Err(
+                format!("floor must be non-negative and finite, but is {}", floor),
+            ),
+            _ => Ok(()),
+        }
+    }
+
+    /// Combine the predictions of each of the given number of trees into ranked predictions.
+    fn aggregate(self, tree_predictions: Vec<IndexValueVec>, n_trees: usize) -> IndexValueVec {
+        // The combined score so far and the number of trees that reached each label
+        let mut label_to_total = HashMap::<Index, (f32, usize)>::new();
+        for (label, score) in tree_predictions.into_iter().flatten() {
+            let (total, count) = label_to_total.entry(label).or_insert((0., 0));
+            *total = match self {
+                Aggregation::Mean | Aggregation::Sum => *total + score,
+                Aggregation::Max => total.max(score),
+                Aggregation::GeometricMean { floor } => *total + score.max(floor).ln(),
+            };
+            *count += 1;
+        }
+
+        let scores = label_to_total
+            .into_iter()
+            .map(|(label, (total, count))| {
+                let score = match self {
+                    Aggregation::Mean => total / n_trees as f32,
+                    Aggregation::Sum | Aggregation::Max => total,
+                    Aggregation::GeometricMean { floor } => {
+                        // Only add the floor for missing trees, since 0 times ln(0) is NaN
+                        let n_missing = n_trees - count;
+                        let total = if n_missing > 0 {
+                            total + n_missing as f32 * floor.ln()
+                        } else {
+                            total
+                        };
+                        (total / n_trees as f32).exp()
+                    }
+                };
+                (label, score)
+            })
+            .collect::<Vec<_>>();
+        let n_labels = scores.len();
+        rank_top_k(scores.into_iter(), n_labels)
+    }
+}
+
+/// Options for checked prediction.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PredictOptions {
+    /// Beam size for beam search.
+    pub beam_size: usize,
+    /// How to handle feature indices that are out of range for the model.
+    pub oov_policy: OovPolicy,
+    /// Multipliers for groups of input features, if any, given as the group id of each feature
+    /// followed by the multiplier of each group.
+    ///
+    /// Feature values are multiplied before the input is normalized, so boosting a group shifts
+    /// weight towards it; the bias feature is unaffected. Unless the model projects its inputs,
+    /// there must be a group id for each of the model's features; otherwise features beyond the
+    /// given group ids are left as they are.
+    #[serde(default)]
+    pub feature_group_weights: Option<(Vec<Index>, Vec<f32>)>,
+    /// How the labels of reached leaves are scored; the default keeps the scores of
+    /// [`Model::predict`].
+    #[serde(default)]
+    pub leaf_transform: LeafTransform,
+    /// The number of best labels to return, if not all labels reached.
+    ///
+    /// Only the best labels are ranked, which is cheaper than ranking all labels and then
+    /// truncating, especially with large beams; the labels returned are the same.
+    #[serde(default)]
+    pub top_k: Option<usize>,
+    /// Whether to smooth scores over the label graph of the model, which must have one; see
+    /// [`Model::attach_label_graph`].
+    ///
+    /// All labels reached are smoothed before the `top_k` best are kept, since a label can rise
+    /// above others through its neighbors.
+    #[serde(default)]
+    pub smooth_scores: bool,
+    /// If set, beam search drops nodes whose path score trails the best node at their depth by
+    /// more than this, even if the beam isn't full.
+    ///
+    /// With wide beams, most nodes on the frontier of deep trees are far behind the best and
+    /// rarely lead to the best labels, so dropping them saves evaluating their classifiers.
+    /// Path scores sum the log-scores of classifiers, so with a large gap, predictions are nearly
+    /// the same as without one.
+    #[serde(default)]
+    pub score_gap: Option<f32>,
+    /// Whether to return labels by their original indices, for models whose labels were
+    /// renumbered with [`Model::compact_labels`].
+    #[serde(default)]
+    pub translate_output: bool,
+}
+
+impl ConstDefault for PredictOptions {
+    const DEFAULT: Self = Self {
+        beam_size: 10,
+        oov_policy: OovPolicy::Error,
+        feature_group_weights: None,
+        leaf_transform: LeafTransform::DEFAULT,
+        top_k: None,
+        smooth_scores: false,
+        score_gap: None,
+        translate_output: false,
+    };
+}
+
+impl Default for PredictOptions {
+    fn default() -> Self {
+        <Self as ConstDefault>::DEFAULT
+    }
+}
+
+impl PredictOptions {
+    /// Check if the options are valid.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.beam_size == 0 {
+            return Err(format!(
+                "beam_size must be positive, but is {}",
+                self.beam_size
+            ));
+        }
+        if self.top_k == Some(0) {
+            return Err("top_k must be positive".to_owned());
+        }
+        if let Some(score_gap) = self.score_gap {
+            if score_gap.is_nan() || score_gap < 0. {
+                return Err(format!(
+                    "score_gap must be non-negative, but is {}",
+                    score_gap
+                ));
+            }
+        }
+        if let Some((groups, weights)) = &self.feature_group_weights {
+            if let Some(&group) = groups.iter().find(|&&g| g as usize >= weights.len()) {
+                return Err(format!(
+                    "feature_group_weights has group id {}, but only {} group weights",
+                    group,
+                    weights.len()
+                ));
+            }
+            if let Some(&weight) = weights.iter().find(|w| !w.is_finite()) {
+                return Err(format!(
+                    "feature_group_weights must be finite, but has {}",
+                    weight
+                ));
+            }
+        }
+        if let LeafTransform::SoftmaxWithinLeaf { temperature } = self.leaf_transform {
+            if !(temperature > 0. && temperature.is_finite()) {
+                return Err(format!(
+                    "leaf_transform temperature must be positive and finite, but is {}",
+                    temperature
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Check if the options are valid for predicting with the given model.
+    pub fn validate_for(&self, model: &Model) -> Result<(), String> {
+        self.validate()?;
+        if self.smooth_scores && model.label_graph.is_none() {
+            return Err("smooth_scores is set, but the model has no label graph".to_owned());
+        }
+        if self.translate_output && model.original_labels.is_none() {
+            return Err(
+                "translate_output is set, but the model's labels weren't compacted".to_owned(),
+            );
+        }
+        if self.leaf_transform == LeafTransform::Identity && model.n_trees() > 1 {
+            return Err(format!(
+                "leaf_transform Identity gives log-space scores, which can't be combined over \
+                 trees that don't all reach the same labels, but the model has {} trees",
+                model.n_trees()
+            ));
+        }
+        match &self.feature_group_weights {
+            Some((groups, _))
+                if model.feature_projection.is_none() && groups.len() != model.n_features() =>
+            {
+                Err(format!(
+                    "feature_group_weights has group ids for {} features, but the model has {}",
+                    groups.len(),
+                    model.n_features()
+                ))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Multiply feature values by the weights of their groups.
+fn apply_feature_group_weights(
+    feature_vec: &[(Index, f32)],
+    (groups, weights): &(Vec<Index>, Vec<f32>),
+) -> FeaturePairs {
+    feature_vec
+        .iter()
+        .map(|&(index, value)| match groups.get(index as usize) {
+            Some(&group) => (index, value * weights[group as usize]),
+            None => (index, value),
+        })
+        .collect()
+}
+
+/// Errors from checked prediction.
+#[derive(Clone, Debug, PartialEq)]
+pub enum PredictError {
+    /// The input has a feature index out of range, and out-of-range indices are not allowed.
+    FeatureIndexOutOfRange { index: Index, n_features: usize },
+    /// A tree in the model is malformed, e.g., a node's weight matrix doesn't match its labels.
+    ModelCorrupt { tree: usize, message: String },
+    /// A limit in the model's [`InferenceLimits`] was hit under [`LimitPolicy::Error`].
+    ///
+    /// [`InferenceLimits`]: super::limits::InferenceLimits
+    /// [`LimitPolicy::Error`]: super::limits::LimitPolicy::Error
+    LimitExceeded { limit: Limit },
+    /// The model has no trees, so it can't predict anything.
+    EmptyModel,
+    /// Probabilities were requested from a model whose classifiers don't estimate them, i.e., one
+    /// trained with hinge loss.
+    ProbabilitiesUnavailable { loss_type: liblinear::LossType },
+    /// A dense input doesn't have one value for each of the model's features.
+    DimensionMismatch { n_values: usize, n_features: usize },
+    /// A classifier in a tree scored NaN, which happens with infinite or NaN feature values, or
+    /// with NaN weights in the model.
+    NanScore { tree: usize },
+    /// The prediction options are invalid, or invalid for the model; see
+    /// [`PredictOptions::validate_for`].
+    InvalidOptions(String),
+    /// A label was predicted by name, but the model's label names don't include it.
+    MissingLabelName { label: Index },
+    /// The input isn't a valid feature vector for the model; see
+    /// [`Model::validate_feature_vec`].
+    InvalidFeatureVec(FeatureVecError),
+    /// The task running the prediction panicked or was cancelled before it finished; only
+    /// returned from async prediction.
+    TaskFailed(String),
+}
+
+impl fmt::Display for PredictError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PredictError::FeatureIndexOutOfRange { index, n_features } => write!(
+                f,
+                "Feature index {} out of range for {} features",
+                index, n_features
+            ),
+            PredictError::ModelCorrupt { tree, message } => {
+                write!(f, "Tree {} is corrupt: {}", tree, message)
+            }
+            PredictError::LimitExceeded { limit } => {
+                write!(f, "Inference limit {} exceeded", limit)
+            }
+            PredictError::EmptyModel => write!(f, "The model has no trees"),
+            PredictError::ProbabilitiesUnavailable { loss_type } => write!(
+                f,
+                "Probabilities are only available for models trained with log loss, not {:?} loss",
+                loss_type
+            ),
+            PredictError::DimensionMismatch {
+                n_values,
+                n_features,
+            } => write!(
+                f,
+                "Dense input has {} values, but the model has {} features",
+                n_values, n_features
+            ),
+            PredictError::NanScore { tree } => write!(
+                f,
+                "A classifier in tree {} scored NaN; the input or the model isn't finite",
+                tree
+            ),
+            PredictError::InvalidOptions(message) => {
+                write!(f, "Invalid prediction options: {}", message)
+            }
+            PredictError::MissingLabelName { label } => {
+                write!(f, "Label {} has no name", label)
+            }
+            PredictError::InvalidFeatureVec(e) => write!(f, "{}", e),
+            PredictError::TaskFailed(message) => write!(f, "Prediction task failed: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for PredictError {}
+
+/// Counters collected over checked predictions.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct PredictStats {
+    /// The number of predictions attempted.
+    pub n_predictions: u64,
+    /// The number of predictions that failed with an error.
+    pub n_failed: u64,
+    /// The number of out-of-range features dropped under [`OovPolicy::Drop`].
+    pub n_oov_dropped: u64,
+    /// The number of out-of-range features rehashed under [`OovPolicy::HashInto`].
+    pub n_oov_hashed: u64,
+    /// The number of predictions whose beam was reduced to the model's `max_beam`.
+    pub n_beam_limit_hits: u64,
+    /// The number of predictions with more labels than the model's `max_labels_returned`.
+    pub n_labels_returned_limit_hits: u64,
+    /// The number of predictions that ran out of the model's `max_nodes_visited`.
+    pub n_nodes_visited_limit_hits: u64,
+    /// The number of predictions that ran out of the model's `max_leaf_labels_scored`.
+    pub n_leaf_labels_scored_limit_hits: u64,
+}
+
+impl PredictStats {
+    /// Add counters from another set of statistics to this one.
+    pub fn merge(&mut self, other: &Self) {
+        self.n_predictions += other.n_predictions;
+        self.n_failed += other.n_failed;
+        self.n_oov_dropped += other.n_oov_dropped;
+        self.n_oov_hashed += other.n_oov_hashed;
+        self.n_beam_limit_hits += other.n_beam_limit_hits;
+        self.n_labels_returned_limit_hits += other.n_labels_returned_limit_hits;
+        self.n_nodes_visited_limit_hits += other.n_nodes_visited_limit_hits;
+        self.n_leaf_labels_scored_limit_hits += other.n_leaf_labels_scored_limit_hits;
+    }
+
+    /// Count the limits hit by a prediction.
+    pub(crate) fn record_limit_hits(&mut self, hits: &LimitHits) {
+        self.n_beam_limit_hits += u64::from(hits.beam);
+        self.n_labels_returned_limit_hits += u64::from(hits.labels_returned);
+        self.n_nodes_visited_limit_hits += u64::from(hits.nodes_visited);
+        self.n_leaf_labels_scored_limit_hits += u64::from(hits.leaf_labels_scored);
+    }
+}
+
+/// Where beam search spent its time, aggregated over a batch of predictions.
+///
+/// Beam search evaluates the nodes of a tree one depth at a time, and times are measured at the
+/// boundaries of depths only. Leaves are scored after the search reaches the deepest depth, so
+/// the last depth of a tree counts all scored leaves, including those at smaller depths.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct BatchPredictProfile {
+    pub n_predictions: usize,
+    /// The total time spent searching the trees, summed over predictions.
+    pub search_time: Duration,
+    /// Profiles by tree, in the order of the trees in the model.
+    pub trees: Vec<TreeProfile>,
+}
+
+/// Where beam search spent its time in a tree.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct TreeProfile {
+    /// Profiles by depth, starting from the root.
+    pub depths: Vec<DepthProfile>,
+}
+
+/// Where beam search spent its time at a depth of a tree.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DepthProfile {
+    /// The number of nodes evaluated at the depth, summed over predictions.
+    pub n_nodes: usize,
+    /// The time spent evaluating nodes at the depth, summed over predictions.
+    pub time: Duration,
+}
+
+impl BatchPredictProfile {
+    /// Add measurements from another profile to this one.
+    pub fn merge(&mut self, other: &Self) {
+        self.n_predictions += other.n_predictions;
+        self.search_time += other.search_time;
+        for (tree, other_tree) in other.trees.iter().enumerate() {
+            self.record_tree(tree, &other_tree.depths);
+        }
+    }
+
+    /// Add the depths measured in a search of the given tree.
+    pub(crate) fn record_tree(&mut self, tree: usize, depths: &[DepthProfile]) {
+        if self.trees.len() <= tree {
+            self.trees.resize_with(tree + 1, TreeProfile::default);
+        }
+        let tree_depths = &mut self.trees[tree].depths;
+        if tree_depths.len() < depths.len() {
+            tree_depths.resize(depths.len(), DepthProfile::default());
+        }
+        for (total, depth) in tree_depths.iter_mut().zip(depths) {
+            total.n_nodes += depth.n_nodes;
+            total.time += depth.time;
+        }
+    }
+
+    /// The time spent at all depths of all trees.
+    pub fn total_depth_time(&self) -> Duration {
+        self.trees
+            .iter()
+            .flat_map(|tree| &tree.depths)
+            .map(|depth| depth.time)
+            .sum()
+    }
+}
+
+/// Storage for prepared feature vectors, reused across predictions to avoid allocating for each.
+#[derive(Debug, Default)]
+pub(crate) struct PrepareBuffers {
+    indices: Vec<Index>,
+    data: Vec<f32>,
+}
+
+impl PrepareBuffers {
+    /// Take the buffers, cleared and with room for at least `capacity` entries.
+    pub(crate) fn take(&mut self, capacity: usize) -> (Vec<Index>, Vec<f32>) {
+        let mut indices = mem::take(&mut self.indices);
+        let mut data = mem::take(&mut self.data);
+        indices.clear();
+        data.clear();
+        indices.reserve(capacity);
+        data.reserve(capacity);
+        (indices, data)
+    }
+
+    /// Give the storage of a prepared feature vector back for reuse.
+    pub(crate) fn recycle(&mut self, feature_vec: SparseVec) {
+        let (indices, data) = feature_vec.into_raw_storage();
+        self.indices = indices;
+        self.data = data;
+    }
+}
+
+/// A model paired with prediction options, which keeps statistics over the predictions made.
+///
+/// The predictor can be shared between threads. Buffers for preparing input vectors and for beam
+/// search are kept between predictions, so that steady-state serving doesn't allocate them for
+/// each request; concurrent predictions each take their own buffers from a pool.
+pub struct Predictor<'a> {
+    model: &'a Model,
+    options: PredictOptions,
+    stats: Mutex<PredictStats>,
+    buffers: Mutex<Vec<(PrepareBuffers, SearchBuffers<'a>)>>,
+}
+
+impl<'a> Predictor<'a> {
+    /// Create a predictor for the given model.
+    ///
+    /// Returns [`PredictError::InvalidOptions`] if the options fail
+    /// [`PredictOptions::validate_for`].
+    pub fn new(model: &'a Model, options: PredictOptions) -> Result<Self, PredictError> {
+        options
+            .validate_for(model)
+            .map_err(PredictError::InvalidOptions)?;
+        Ok(Self {
+            model,
+            options,
+            stats: Mutex::new(PredictStats::default()),
+            buffers: Mutex::new(Vec::new()),
+        })
+    }
+
+    /// The options used for prediction.
+    pub fn options(&self) -> &PredictOptions {
+        &self.options
+    }
+
+    /// Returns a ranked list of predictions for the given input example.
+    pub fn predict(
+        &self,
+        feature_vec: impl AsRef<[(Index, f32)]>,
+    ) -> Result<IndexValueVec, PredictError> {
+        let (mut prepare_buffers, mut search_buffers) =
+            self.buffers.lock().unwrap().pop().unwrap_or_default();
+        let mut stats = PredictStats::default();
+        let result = self.model.predict_with_stats(
+            feature_vec.as_ref(),
+            &self.options,
+            &mut stats,
+            &mut prepare_buffers,
+            &mut search_buffers,
+        );
+        self.stats.lock().unwrap().merge(&stats);
+        self.buffers
+            .lock()
+            .unwrap()
+            .push((prepare_buffers, search_buffers));
+        result
+    }
+
+    /// Returns a ranked list of predictions for the given input example with the given beam
+    /// size, exactly as [`Model::predict`] does, but with the predictor's buffers.
+    ///
+    /// As with [`Model::predict`], the input isn't checked and the predictor's options are
+    /// ignored, so the prediction isn't counted in its statistics.
+    pub fn predict_with_beam(
+        &self,
+        feature_vec: impl AsRef<[(Index, f32)]>,
+        beam_size: usize,
+    ) -> IndexValueVec {
+        let (mut prepare_buffers, mut search_buffers) =
+            self.buffers.lock().unwrap().pop().unwrap_or_default();
+        let feature_vec = self
+            .model
+            .prepare_feature_vec_with(feature_vec.as_ref(), &mut prepare_buffers);
+        let predictions =
+            self.model
+                .predict_prepared_with(&feature_vec, beam_size, None, &mut search_buffers);
+        prepare_buffers.recycle(feature_vec);
+        self.buffers
+            .lock()
+            .unwrap()
+            .push((prepare_buffers, search_buffers));
+        predictions
+    }
+
+    /// Statistics over predictions made since creation or the last reset.
+    pub fn stats(&self) -> PredictStats {
+        *self.stats.lock().unwrap()
+    }
+
+    /// Reset the statistics to zero.
+    pub fn reset_stats(&self) {
+        *self.stats.lock().unwrap() = PredictStats::default();
+    }
+}
+
+impl Model {
+    /// Create a [`Predictor`] for this model with the given options; see [`Predictor::new`].
+    pub fn predictor(&self, options: PredictOptions) -> Result<Predictor<'_>, PredictError> {
+        Predictor::new(self, options)
+    }
+
+    /// Returns a ranked list of predictions for the given input example, checking the input
+    /// according to the given options.
+    ///
+    /// The feature vector is assumed to be ordered by indices and have no duplicate indices;
+    /// out-of-range indices are handled according to `options.oov_policy`.
+    pub fn predict_with_options(
+        &self,
+        feature_vec: impl AsRef<[(Index, f32)]>,
+        options: &PredictOptions,
+    ) -> Result<IndexValueVec, PredictError> {
+        options
+            .validate_for(self)
+            .map_err(PredictError::InvalidOptions)?;
+        self.predict_with_stats(
+            feature_vec.as_ref(),
+            options,
+            &mut PredictStats::default(),
+            &mut PrepareBuffers::default(),
+            &mut SearchBuffers::default(),
+        )
+    }
+
+    /// Returns a ranked list of predictions for the given dense input example, e.g., an
+    /// embedding, with one value for each of the model's features.
+    ///
+    /// The results are the same as those of [`Self::predict`] for the non-zero values of the
+    /// input as (index, value) pairs, but the input is normalized directly into the vector used
+    /// for beam search, without building those pairs first. Inputs of models with a feature
+    /// projection are projected as usual, so their length isn't checked.
+    pub fn predict_dense(
+        &self,
+        features: &[f32],
+        beam_size: usize,
+    ) -> Result<IndexValueVec, PredictError> {
+        if self.feature_projection.is_some() {
+            let feature_vec = features
+                .iter()
+                .enumerate()
+                .filter(|&(_, &value)| value != 0.)
+                .map(|(index, &value)| (index as Index, value))
+                .collect::<FeaturePairs>();
+            return Ok(self.predict(feature_vec, beam_size));
+        }
+        if features.len() != self.n_features() {
+            return Err(PredictError::DimensionMismatch {
+                n_values: features.len(),
+                n_features: self.n_features(),
+            });
+        }
+        let feature_vec = self.prepare_dense_feature_vec(features);
+        Ok(self.predict_prepared(&feature_vec, beam_size))
+    }
+
+    /// Like [`Self::predict`], but for a feature vector given as an `sprs` vector view, which is
+    /// normalized straight from its storage, without first copying it into index-value pairs.
+    ///
+    /// Models with a feature projection still copy the pairs, to project them.
+    pub fn predict_csvec(&self, vec: SparseVecView, beam_size: usize) -> IndexValueVec {
+        self.predict_csvec_with(
+            vec,
+            beam_size,
+            &mut PrepareBuffers::default(),
+            &mut SearchBuffers::default(),
+        )
+    }
+
+    /// Predict for each row of the given CSR matrix, as [`Self::predict_csvec`] does, in
+    /// parallel like [`Self::predict_batch`].
+    pub fn predict_csr_rows(&self, mat: SparseMatView, beam_size: usize) -> Vec<IndexValueVec> {
+        assert!(mat.is_csr(), "Matrix should be in CSR format");
+        (0..mat.rows())
+            .into_par_iter()
+            .map_init(
+                <(PrepareBuffers, SearchBuffers)>::default,
+                |(prepare_buffers, search_buffers), row| {
+                    let vec = mat
+                        .outer_view(row)
+                        .expect("Row should be within the matrix");
+                    self.predict_csvec_with(vec, beam_size, prepare_buffers, search_buffers)
+                },
+            )
+            .collect()
+    }
+
+    fn predict_csvec_with<'a>(
+        &'a self,
+        vec: SparseVecView,
+        beam_size: usize,
+        prepare_buffers: &mut PrepareBuffers,
+        search_buffers: &mut SearchBuffers<'a>,
+    ) -> IndexValueVec {
+        let pairs = vec
+            .indices()
+            .iter()
+            .cloned()
+            .zip(vec.data().iter().cloned());
+        let feature_vec = if self.feature_projection.is_some() {
+            self.prepare_feature_vec_with(&pairs.collect::<FeaturePairs>(), prepare_buffers)
+        } else {
+            self.prepare_pairs_with(pairs, vec.nnz(), prepare_buffers)
+        };
+        let predictions = self.predict_prepared_with(&feature_vec, beam_size, None, search_buffers);
+        prepare_buffers.recycle(feature_vec);
+        predictions
+    }
+
+    /// Returns a ranked list of label probabilities for the given input example, checking the
+    /// input according to the given options; `options.leaf_transform`, `options.smooth_scores`,
+    /// and `options.translate_output` are ignored, since smoothed scores aren't probabilities,
+    /// and labels are returned by the indices the model's classifiers were trained on.
+    ///
+    /// Probabilities are only available for models trained with [`liblinear::LossType::Log`],
+    /// whose classifiers are logistic regressions; otherwise
+    /// [`PredictError::ProbabilitiesUnavailable`] is returned, since the squared hinge scores of
+    /// hinge loss models have no probabilistic interpretation, and mapping them to probabilities
+    /// would need calibration data the model doesn't have.
+    ///
+    /// In each tree, the probability of a label is the product of the sigmoids of the margins of
+    /// the classifiers along its path, i.e., the probability of reaching its leaf times that of the
+    /// label given the leaf, by the chain rule over the tree's nested label partitions. The model
+    /// returns the mean of these over trees, counting labels not reached by the beam search in a
+    /// tree as 0, so smaller beams can only lower probabilities. Each probability is that of the
+    /// label being relevant on its own, so they don't sum to one. These are the same scores as
+    /// those of [`Model::predict`], which are probabilities for log loss models only.
+    pub fn predict_proba(
+        &self,
+        feature_vec: impl AsRef<[(Index, f32)]>,
+        options: &PredictOptions,
+    ) -> Result<IndexValueVec, PredictError> {
+        let loss_type = self.settings.classifier_loss_type;
+        if loss_type != liblinear::LossType::Log {
+            return Err(PredictError::ProbabilitiesUnavailable { loss_type });
+        }
+        let options = PredictOptions {
+            leaf_transform: LeafTransform::Exp,
+            smooth_scores: false,
+            translate_output: false,
+            ..options.clone()
+        };
+        self.predict_with_options(feature_vec, &options)
+    }
+
+    /// Returns a ranked list of predictions, with label scores combined over trees as given
+    /// instead of averaged.
+    ///
+    /// With [`Aggregation::Mean`], the result equals that of [`Self::predict`].
+    pub fn predict_with_aggregation(
+        &self,
+        feature_vec: impl AsRef<[(Index, f32)]>,
+        beam_size: usize,
+        aggregation: Aggregation,
+    ) -> IndexValueVec {
+        aggregation.validate().unwrap();
+        let feature_vec = self.prepare_feature_vec(feature_vec.as_ref());
+        let (tree_predictions, budget) = self
+            .search_per_tree(&feature_vec, beam_size)
+            .unwrap_or_else(|(_, message)| panic!("Corrupt tree: {}", message));
+        let n_trees = budget.n_trees_averaged(self.trees.len());
+        if aggregation == Aggregation::Mean {
+            // Sum in the same order as prediction does, so that the scores are the same
+            return self.average_tree_predictions(tree_predictions, n_trees, |n_labels| {
+                self.inference_limits
+                    .n_labels_returned(None, n_labels, &mut LimitHits::default())
+            });
+        }
+        let mut predictions = aggregation.aggregate(tree_predictions, n_trees);
+        let n_returned = self.inference_limits.n_labels_returned(
+            None,
+            predictions.len(),
+            &mut LimitHits::default(),
+        );
+        predictions.truncate(n_returned);
+        predictions
+    }
+
+    /// Predict with options already checked with [`PredictOptions::validate_for`], which scans
+    /// all feature group weights, so that a [`Predictor`] only checks its options once.
+    fn predict_with_stats<'a>(
+        &'a self,
+        feature_vec: &[(Index, f32)],
+        options: &PredictOptions,
+        stats: &mut PredictStats,
+        prepare_buffers: &mut PrepareBuffers,
+        search_buffers: &mut SearchBuffers<'a>,
+    ) -> Result<IndexValueVec, PredictError> {
+        stats.n_predictions += 1;
+        let result = self
+            .prepare_feature_vec_checked(feature_vec, options, stats, prepare_buffers)
+            .and_then(|feature_vec| {
+                let label_graph = self.label_graph.as_ref().filter(|_| options.smooth_scores);
+                let result = self.predict_prepared_checked(
+                    &feature_vec,
+                    options,
+                    if label_graph.is_some() {
+                        None
+                    } else {
+                        options.top_k
+                    },
+                    true,
+                    stats,
+                    search_buffers,
+                );
+                prepare_buffers.recycle(feature_vec);
+                result.map(|mut predictions| {
+                    if let Some(graph) = label_graph {
+                        predictions = graph.smooth(predictions, options.top_k);
+                    }
+                    if options.translate_output {
+                        self.translate_labels(&mut predictions);
+                    }
+                    predictions
+                })
+            });
+        if result.is_err() {
+            stats.n_failed += 1;
+        }
+        result
+    }
+
+    /// Like [`Self::prepare_feature_vec`], but handles out-of-range indices as specified.
+    fn prepare_feature_vec_checked(
+        &self,
+        feature_vec: &[(Index, f32)],
+        options: &PredictOptions,
+        stats: &mut PredictStats,
+        buffers: &mut PrepareBuffers,
+    ) -> Result<SparseVec, PredictError> {
+        let weighted;
+        let feature_vec = match &options.feature_group_weights {
+            Some(group_weights) => {
+                weighted = apply_feature_group_weights(feature_vec, group_weights);
+                &weighted[..]
+            }
+            None => feature_vec,
+        };
+        if self.feature_projection.is_some() {
+            // Inputs of any dimension are projected into the feature space of the model
+            return Ok(self.prepare_feature_vec_with(feature_vec, buffers));
+        }
+        let n_features = self.settings.n_features;
+        let is_oov = |index: Index| index as usize >= n_features;
+        let first_oov = match feature_vec.iter().find(|&&(i, _)| is_oov(i)) {
+            Some(&(index, _)) => index,
+            None => return Ok(self.prepare_feature_vec_with(feature_vec, buffers)),
+        };
+
+        match options.oov_policy {
+            OovPolicy::Drop => {
+                let kept = feature_vec
+                    .iter()
+                    .cloned()
+                    .filter(|&(i, _)| !is_oov(i))
+                    .collect::<FeaturePairs>();
+                stats.n_oov_dropped += (feature_vec.len() - kept.len()) as u64;
+                Ok(self.prepare_feature_vec_with(&kept, buffers))
+            }
+            OovPolicy::HashInto if n_features > 0 => {
+                let mut hashed = feature_vec
+                    .iter()
+                    .map(|&(i, v)| {
+                        if is_oov(i) {
+                            stats.n_oov_hashed += 1;
+                            (hash_feature_index(i, n_features), v)
+                        } else {
+                            (i, v)
+                        }
+                    })
+                    .collect::<FeaturePairs>();
+                hashed.sort_by_index();
+                hashed.sum_duplicate_indices();
+                Ok(self.prepare_feature_vec_with(&hashed, buffers))
+            }
+            // Nothing to hash into if the model has no features
+            OovPolicy::Error | OovPolicy::HashInto => Err(PredictError::FeatureIndexOutOfRange {
+                index: first_oov,
+                n_features,
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::liblinear::LossType;
+    use crate::model::{Settings, TreeNode};
+    use crate::test_util::{count_allocations, toy_dataset, toy_model};
+    use assert_approx_eq::assert_approx_eq;
+
+    fn options(oov_policy: OovPolicy) -> PredictOptions {
+        PredictOptions {
+            beam_size: 5,
+            oov_policy,
+            feature_group_weights: None,
+            leaf_transform: LeafTransform::Exp,
+            top_k: None,
+            smooth_scores: false,
+            score_gap: None,
+            translate_output: false,
+        }
+    }
+
+    #[test]
+    fn test_hash_feature_index() {
+        // Pin down the hash so that it never changes silently
+        assert_eq!(805, hash_feature_index(0, 1000));
+        for index in 0..100 {
+            assert!((hash_feature_index(index, 7) as usize) < 7);
+            assert_eq!(hash_feature_index(index, 7), hash_feature_index(index, 7));
+        }
+    }
+
+    #[test]
+    fn test_oov_policy_error() {
+        let model = toy_model(1, 0);
+        let n_features = model.n_features();
+        let oov_index = n_features as Index + 3;
+        let predictor = model.predictor(options(OovPolicy::Error)).unwrap();
+
+        assert_eq!(
+            Err(PredictError::FeatureIndexOutOfRange {
+                index: oov_index,
+                n_features,
+            }),
+            predictor.predict(&[(0, 1.), (oov_index, 1.)])
+        );
+        assert_eq!(
+            model.predict(&[(0, 1.), (1, 2.)], 5),
+            predictor.predict(&[(0, 1.), (1, 2.)]).unwrap()
+        );
+        assert_eq!(
+            PredictStats {
+                n_predictions: 2,
+                n_failed: 1,
+                ..PredictStats::default()
+            },
+            predictor.stats()
+        );
+    }
+
+    #[test]
+    fn test_oov_policy_drop() {
+        let model = toy_model(1, 0);
+        let n_features = model.n_features() as Index;
+        let predictor = model.predictor(options(OovPolicy::Drop)).unwrap();
+
+        assert_eq!(
+            model.predict(&[(0, 1.), (2, 3.)], 5),
+            predictor
+                .predict(&[(0, 1.), (2, 3.), (n_features, 5.), (n_features + 10, 1.)])
+                .unwrap()
+        );
+        assert_eq!(2, predictor.stats().n_oov_dropped);
+        assert_eq!(0, predictor.stats().n_failed);
+
+        predictor.reset_stats();
+        assert_eq!(PredictStats::default(), predictor.stats());
+    }
+
+    #[test]
+    fn test_oov_policy_hash_into() {
+        let model = toy_model(1, 0);
+        let n_features = model.n_features();
+        let oov_index = n_features as Index + 1;
+        let hashed_index = hash_feature_index(oov_index, n_features);
+        let predictor = model.predictor(options(OovPolicy::HashInto)).unwrap();
+
+        // An out-of-range feature behaves as if it was given at its hashed index
+        assert_eq!(
+            model.predict(&[(hashed_index, 2.)], 5),
+            predictor.predict(&[(oov_index, 2.)]).unwrap()
+        );
+
+        // Values colliding with an in-range feature are summed
+        let mut expected = vec![(0, 1.), (hashed_index, 2.)];
+        expected.sort_by_index();
+        expected.sum_duplicate_indices();
+        assert_eq!(
+            model.predict(&expected, 5),
+            predictor
+                .predict(&[(0, 1.), (hashed_index, 1.), (oov_index, 1.)])
+                .unwrap()
+        );
+        assert_eq!(2, predictor.stats().n_oov_hashed);
+    }
+
+    #[test]
+    fn test_feature_pairs() {
+        let model = toy_model(1, 0);
+        let feature_vec = &toy_dataset(1, 8, 1).feature_lists[0];
+
+        let (pairs, n_allocations) =
+            count_allocations(|| feature_vec.iter().cloned().collect::<FeaturePairs>());
+        assert_eq!(0, n_allocations);
+        assert!(!pairs.spilled());
+
+        let predictor = model.predictor(options(OovPolicy::Error)).unwrap();
+        assert_eq!(model.predict(feature_vec, 5), model.predict(&pairs, 5));
+        assert_eq!(
+            model.predict(feature_vec, 5),
+            predictor.predict(&pairs).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_prepare_reuses_buffers() {
+        let model = toy_model(1, 0);
+        let feature_vec = &toy_dataset(1, 8, 1).feature_lists[0];
+
+        let mut buffers = PrepareBuffers::default();
+        let prepared = model.prepare_feature_vec_with(feature_vec, &mut buffers);
+        assert_eq!(model.prepare_feature_vec(feature_vec), prepared);
+        buffers.recycle(prepared);
+
+        let (_, n_allocations) = count_allocations(|| {
+            let prepared = model.prepare_feature_vec_with(feature_vec, &mut buffers);
+            buffers.recycle(prepared);
+        });
+        assert_eq!(0, n_allocations);
+
+        // A warmed-up predictor saves the two allocations for the prepared vector
+        let options = options(OovPolicy::Error);
+        let predictor = model.predictor(options.clone()).unwrap();
+        predictor.predict(feature_vec).unwrap();
+        let (reused, n_reused) = count_allocations(|| predictor.predict(feature_vec).unwrap());
+        let (fresh, n_fresh) =
+            count_allocations(|| model.predict_with_options(feature_vec, &options).unwrap());
+        assert_eq!(fresh, reused);
+        assert!(n_reused + 2 <= n_fresh);
+    }
+
+    #[test]
+    fn test_predictor_reuses_search_buffers() {
+        let model = toy_model(3, 0);
+        let predictor = model.predictor(PredictOptions::default()).unwrap();
+        let feature_vecs = toy_dataset(20, 8, 1).feature_lists;
+        for feature_vec in &feature_vecs {
+            for &beam_size in &[1, 2, 10] {
+                assert_eq!(
+                    model.predict(feature_vec, beam_size),
+                    predictor.predict_with_beam(feature_vec, beam_size)
+                );
+            }
+        }
+        assert_eq!(PredictStats::default(), predictor.stats());
+
+        // Once warmed up, only the returned predictions are allocated
+        let (predictions, n_allocations) =
+            count_allocations(|| predictor.predict_with_beam(&feature_vecs[0], 10));
+        assert_eq!(model.predict(&feature_vecs[0], 10), predictions);
+        assert_eq!(1, n_allocations);
+    }
+
+    #[test]
+    fn test_beam_larger_than_model() {
+        let model = toy_model(2, 0);
+        let max_useful_beam = model.max_useful_beam();
+        assert!(max_useful_beam >= 2);
+
+        let options = PredictOptions {
+            beam_size: 10_000,
+            ..PredictOptions::default()
+        };
+        for feature_vec in &toy_dataset(10, 8, 1).feature_lists {
+            let predictions = model.predict(feature_vec, 10_000);
+            assert_eq!(model.predict(feature_vec, max_useful_beam), predictions);
+            assert_eq!(
+                predictions,
+                model.predict_with_options(feature_vec, &options).unwrap()
+            );
+        }
+    }
+
+    #[test]
+    fn test_unit_feature_group_weights() {
+        let model = toy_model(1, 0);
+        let n_features = model.n_features();
+        let options = PredictOptions {
+            beam_size: 5,
+            oov_policy: OovPolicy::Error,
+            feature_group_weights: Some((
+                (0..n_features as Index).map(|i| i % 3).collect(),
+                vec![1.; 3],
+            )),
+            leaf_transform: LeafTransform::Exp,
+            top_k: None,
+            smooth_scores: false,
+            score_gap: None,
+            translate_output: false,
+        };
+        let predictor = model.predictor(options.clone()).unwrap();
+        for feature_vec in &toy_dataset(10, 8, 1).feature_lists {
+            let predictions = model.predict(feature_vec, 5);
+            assert_eq!(
+                predictions,
+                model.predict_with_options(feature_vec, &options).unwrap()
+            );
+            assert_eq!(predictions, predictor.predict(feature_vec).unwrap());
+        }
+
+        let mut invalid = options.clone();
+        invalid.feature_group_weights = Some((vec![0, 3], vec![1.; 3]));
+        assert!(invalid.validate().is_err());
+        invalid.feature_group_weights = Some((vec![0], vec![f32::NAN]));
+        assert!(invalid.validate().is_err());
+        invalid.feature_group_weights = Some((vec![0; n_features + 1], vec![1.]));
+        assert!(invalid.validate().is_ok());
+        assert!(invalid.validate_for(&model).is_err());
+        assert!(matches!(
+            model.predictor(invalid),
+            Err(PredictError::InvalidOptions(_))
+        ));
+    }
+
+    #[test]
+    fn test_boost_feature_group() {
+        // A single leaf whose two labels each depend on one of two features
+        let model = Model {
+            trees: vec![TreeNode::Leaf {
+                weights: WeightMat::Dense(
+                    DenseMat::from_shape_vec((3, 2), vec![1., 0., 0., 1., 0., 0.]).unwrap(),
+                ),
+                labels: vec![0, 1],
+            }],
+            settings: Settings {
+                n_features: 2,
+                classifier_loss_type: LossType::Log,
+                feature_transform: Default::default(),
+                n_used_features: None,
+            },
+            label_thresholds: None,
+            training_metadata: Default::default(),
+            inference_limits: Default::default(),
+            feature_projection: None,
+            input_profile: None,
+            label_graph: None,
+            original_labels: None,
+            label_names: None,
+            used_features: None,
+            sorted_labels: Default::default(),
+            label_index: Default::default(),
+        };
+        let feature_vec = [(0, 1.), (1, 1.)];
+        let scores = |weights: Vec<f32>| {
+            let options = PredictOptions {
+                beam_size: 5,
+                oov_policy: OovPolicy::Error,
+                feature_group_weights: Some((vec![0, 1], weights)),
+                leaf_transform: LeafTransform::Exp,
+                top_k: None,
+                smooth_scores: false,
+                score_gap: None,
+                translate_output: false,
+            };
+            let predictions = model.predict_with_options(&feature_vec, &options).unwrap();
+            let score = |label| predictions.iter().find(|&&(l, _)| l == label).unwrap().1;
+            (score(0), score(1))
+        };
+
+        let (score_0, score_1) = scores(vec![1., 1.]);
+        assert_eq!(score_0, score_1);
+        let (boosted_0, boosted_1) = scores(vec![2., 1.]);
+        assert!(boosted_0 > score_0);
+        assert!(boosted_1 < score_1);
+    }
+
+    #[test]
+    fn test_leaf_transforms() {
+        // A single leaf with hinge loss, whose labels have margins 0.6, 0.8 and 0.5 for the input
+        // below, the last through the bias feature
+        let model = Model {
+            trees: vec![TreeNode::Leaf {
+                weights: WeightMat::Dense(
+                    DenseMat::from_shape_vec((3, 3), vec![1., 0., 0., 0., 1., 0., 0., 0., 0.5])
+                        .unwrap(),
+                ),
+                labels: vec![0, 1, 2],
+            }],
+            settings: Settings {
+                n_features: 2,
+                classifier_loss_type: LossType::Hinge,
+                feature_transform: Default::default(),
+                n_used_features: None,
+            },
+            label_thresholds: None,
+            training_metadata: Default::default(),
+            inference_limits: Default::default(),
+            feature_projection: None,
+            input_profile: None,
+            label_graph: None,
+            original_labels: None,
+            label_names: None,
+            used_features: None,
+            sorted_labels: Default::default(),
+            label_index: Default::default(),
+        };
+        let feature_vec = [(0, 3.), (1, 4.)];
+        let margins = [0.6f32, 0.8, 0.5];
+        let scores = |leaf_transform| {
+            let options = PredictOptions {
+                leaf_transform,
+                ..PredictOptions::default()
+            };
+            let predictions = model.predict_with_options(&feature_vec, &options).unwrap();
+            assert_eq!(3, predictions.len());
+            let score = |label| predictions.iter().find(|&&(l, _)| l == label).unwrap().1;
+            [score(0), score(1), score(2)]
+        };
+
+        // The default is bit-for-bit the same as plain prediction
+        assert_eq!(
+            model.predict(&feature_vec, 10),
+            model
+                .predict_with_options(&feature_vec, &PredictOptions::default())
+                .unwrap()
+        );
+        for (&score, &m) in scores(LeafTransform::Exp).iter().zip(margins.iter()) {
+            assert_approx_eq!((-(1. - m).powi(2)).exp(), score);
+        }
+        for (&score, &m) in scores(LeafTransform::Sigmoid).iter().zip(margins.iter()) {
+            assert_approx_eq!(1. / (1. + (-m).exp()), score);
+        }
+        for (&score, &m) in scores(LeafTransform::Identity).iter().zip(margins.iter()) {
+            assert_approx_eq!(-(1. - m).powi(2), score);
+        }
+        let softmax = scores(LeafTransform::SoftmaxWithinLeaf { temperature: 0.5 });
+        let sum = margins.iter().map(|m| (m / 0.5).exp()).sum::<f32>();
+        for (&score, &m) in softmax.iter().zip(margins.iter()) {
+            assert_approx_eq!((m / 0.5).exp() / sum, score);
+        }
+        assert_approx_eq!(1., softmax.iter().sum::<f32>());
+
+        for &temperature in &[0., -1., f32::NAN, f32::INFINITY] {
+            let options = PredictOptions {
+                leaf_transform: LeafTransform::SoftmaxWithinLeaf { temperature },
+                ..PredictOptions::default()
+            };
+            assert!(options.validate().is_err());
+        }
+        let options: PredictOptions =
+            serde_json::from_str(r#"{"beam_size": 5, "oov_policy": "Error"}"#).unwrap();
+        assert_eq!(LeafTransform::Exp, options.leaf_transform);
+
+        // Log-space scores can't be averaged with the 0 of labels a tree doesn't reach
+        let options = PredictOptions {
+            leaf_transform: LeafTransform::Identity,
+            ..PredictOptions::default()
+        };
+        assert!(options.validate_for(&model).is_ok());
+        let forest = toy_model(3, 0);
+        assert!(options.validate_for(&forest).is_err());
+        assert!(matches!(
+            forest.predict_with_options(&toy_dataset(1, 8, 1).feature_lists[0], &options),
+            Err(PredictError::InvalidOptions(_))
+        ));
+        assert!(matches!(
+            forest.predictor(options),
+            Err(PredictError::InvalidOptions(_))
+        ));
+    }
+
+    #[test]
+    fn test_aggregation() {
+        // Two single-leaf trees, where labels 0 and 2 are only in one tree each, and the margins
+        // of the labels for the input below are 2 and 0 in the first tree, and 1 and -1 in the
+        // second
+        let leaf = |margins: Vec<f32>, labels| TreeNode::Leaf {
+            weights: WeightMat::Dense(
+                DenseMat::from_shape_vec((2, 2), [margins, vec![0., 0.]].concat()).unwrap(),
+            ),
+            labels,
+        };
+        let model = Model {
+            trees: vec![
+                leaf(vec![2., 0.], vec![0, 1]),
+                leaf(vec![1., -1.], vec![1, 2]),
+            ],
+            settings: Settings {
+                n_features: 1,
+                classifier_loss_type: LossType::Log,
+                feature_transform: Default::default(),
+                n_used_features: None,
+            },
+            label_thresholds: None,
+            training_metadata: Default::default(),
+            inference_limits: Default::default(),
+            feature_projection: None,
+            input_profile: None,
+            label_graph: None,
+            original_labels: None,
+            label_names: None,
+            used_features: None,
+            sorted_labels: Default::default(),
+            label_index: Default::default(),
+        };
+        let feature_vec = [(0, 1.)];
+        let sigmoid = |m: f32| 1. / (1. + (-m).exp());
+        let check = |aggregation, expected: [(Index, f32); 3]| {
+            let predictions = model.predict_with_aggregation(&feature_vec, 10, aggregation);
+            assert_eq!(3, predictions.len());
+            for (&(label, score), &(expected_label, expected_score)) in
+                predictions.iter().zip(&expected)
+            {
+                assert_eq!(expected_label, label, "{:?}", aggregation);
+                assert_approx_eq!(expected_score, score, 1e-4);
+            }
+        };
+
+        check(
+            Aggregation::Mean,
+            [
+                (1, (sigmoid(0.) + sigmoid(1.)) / 2.),
+                (0, sigmoid(2.) / 2.),
+                (2, sigmoid(-1.) / 2.),
+            ],
+        );
+        assert_eq!(
+            model.predict(&feature_vec, 10),
+            model.predict_with_aggregation(&feature_vec, 10, Aggregation::default())
+        );
+        check(
+            Aggregation::Max,
+            [(0, sigmoid(2.)), (1, sigmoid(1.)), (2, sigmoid(-1.))],
+        );
+        check(
+            Aggregation::Sum,
+            [
+                (1, sigmoid(0.) + sigmoid(1.)),
+                (0, sigmoid(2.)),
+                (2, sigmoid(-1.)),
+            ],
+        );
+        check(
+            Aggregation::GeometricMean { floor: 0.01 },
+            [
+                (1, (sigmoid(0.) * sigmoid(1.)).sqrt()),
+                (0, (sigmoid(2.) * 0.01).sqrt()),
+                (2, (sigmoid(-1.) * 0.01).sqrt()),
+            ],
+        );
+        // Without a floor, labels missing from any tree score 0
+        check(
+            Aggregation::GeometricMean { floor: 0. },
+            [(1, (sigmoid(0.) * sigmoid(1.)).sqrt()), (0, 0.), (2, 0.)],
+        );
+
+        for &floor in &[-0.1, f32::NAN, f32::INFINITY] {
+            assert!(Aggregation::GeometricMean { floor }.validate().is_err());
+        }
+    }
+
+    #[test]
+    fn test_predict_batch() {
+        let model = toy_model(3, 0);
+        let feature_vecs = toy_dataset(50, 8, 1).feature_lists;
+        for &beam_size in &[1, 5] {
+            let expected = feature_vecs
+                .iter()
+                .map(|feature_vec| model.predict(feature_vec, beam_size))
+                .collect::<Vec<_>>();
+            assert_eq!(expected, model.predict_batch(&feature_vecs, beam_size));
+            for &n_threads in &[0, 1, 2] {
+                assert_eq!(
+                    expected,
+                    model
+                        .predict_batch_with_n_threads(&feature_vecs, beam_size, n_threads)
+                        .unwrap()
+                );
+            }
+            assert_eq!(
+                expected,
+                model.predict_batch_sequential(&feature_vecs, beam_size)
+            );
+            let pool = rayon::ThreadPoolBuilder::new()
+                .num_threads(2)
+                .build()
+                .unwrap();
+            assert_eq!(
+                expected,
+                model.predict_batch_in_pool(&feature_vecs, beam_size, &pool)
+            );
+        }
+        assert!(model
+            .predict_batch(&Vec::<Vec<(Index, f32)>>::new(), 5)
+            .is_empty());
+    }
+
+    #[test]
+    fn test_predict_unsorted() {
+        let model = toy_model(3, 0);
+        for feature_vec in &toy_dataset(20, 8, 1).feature_lists {
+            for &beam_size in &[1, 5] {
+                let mut predictions = model.predict_unsorted(feature_vec, beam_size);
+                predictions.sort_unstable_by_key(|&pair| super::super::label_rank(pair));
+                assert_eq!(model.predict(feature_vec, beam_size), predictions);
+            }
+        }
+    }
+
+    #[test]
+    fn test_predict_batch_profiled() {
+        fn depth(node: &TreeNode) -> usize {
+            match node {
+                TreeNode::Branch { children, .. } => 1 + children.iter().map(depth).max().unwrap(),
+                TreeNode::Leaf { .. } => 1,
+            }
+        }
+
+        let model = toy_model(3, 0);
+        let feature_vecs = toy_dataset(50, 8, 1).feature_lists;
+        // The beam is wide enough for the search to reach the deepest leaves of every tree
+        let (predictions, profile) = model.predict_batch_profiled(&feature_vecs, 100);
+        assert_eq!(model.predict_batch(&feature_vecs, 100), predictions);
+        assert_eq!(feature_vecs.len(), profile.n_predictions);
+        assert_eq!(model.trees.len(), profile.trees.len());
+        for (tree, tree_profile) in model.trees.iter().zip(&profile.trees) {
+            assert_eq!(depth(tree), tree_profile.depths.len());
+            assert_eq!(feature_vecs.len(), tree_profile.depths[0].n_nodes);
+            assert!(tree_profile
+                .depths
+                .iter()
+                .all(|depth| depth.n_nodes >= feature_vecs.len()));
+        }
+
+        // Depths are timed within the search, so their times can't add up to more; how much of
+        // the search is bookkeeping between depths depends on the machine, so it isn't checked
+        let depth_time = profile.total_depth_time();
+        assert!(depth_time <= profile.search_time, "{:?}", profile);
+
+        let mut merged = profile.clone();
+        merged.merge(&profile);
+        assert_eq!(2 * profile.n_predictions, merged.n_predictions);
+        assert_eq!(2 * depth_time, merged.total_depth_time());
+
+        let (predictions, profile) =
+            model.predict_batch_profiled(&Vec::<Vec<(Index, f32)>>::new(), 5);
+        assert!(predictions.is_empty());
+        assert_eq!(BatchPredictProfile::default(), profile);
+    }
+
+    #[test]
+    fn test_zero_norm_inputs() {
+        let model = toy_model(2, 0);
+        // Only the bias feature is active for inputs without anything to normalize
+        let bias_only = model.predict(&[], 10);
+        assert!(!bias_only.is_empty());
+        assert!(bias_only.iter().all(|&(_, score)| score.is_finite()));
+        for feature_vec in &[
+            vec![(0, 0.)],
+            vec![(0, 0.), (3, 0.), (5, -0.)],
+            vec![(2, 1e-30)],
+        ] {
+            assert_eq!(bias_only, model.predict(feature_vec, 10));
+            assert_eq!(
+                bias_only,
+                model
+                    .predict_with_options(feature_vec, &PredictOptions::default())
+                    .unwrap()
+            );
+        }
+
+        // Single features are normalized to unit length, whatever their value; powers of two keep
+        // the division exact
+        let single_feature = model.predict(&[(3, 1.)], 10);
+        assert!(single_feature.iter().all(|&(_, score)| score.is_finite()));
+        assert_eq!(single_feature, model.predict(&[(3, 4.)], 10));
+        assert_eq!(single_feature, model.predict(&[(3, 0.5)], 10));
+    }
+
+    #[test]
+    fn test_predict_proba() {
+        let inputs = toy_dataset(20, 8, 1).feature_lists;
+        let mut model = toy_model(2, 0);
+        let options = PredictOptions::default();
+        assert_eq!(
+            Err(PredictError::ProbabilitiesUnavailable {
+                loss_type: LossType::Hinge
+            }),
+            model.predict_proba(&inputs[0], &options)
+        );
+
+        model.settings.classifier_loss_type = LossType::Log;
+        let ignored_options = PredictOptions {
+            leaf_transform: LeafTransform::Sigmoid,
+            smooth_scores: true,
+            translate_output: true,
+            ..PredictOptions::default()
+        };
+        for feature_vec in &inputs {
+            let probabilities = model.predict_proba(feature_vec, &options).unwrap();
+            assert!(!probabilities.is_empty());
+            assert!(probabilities.iter().all(|&(_, p)| (0. ..=1.).contains(&p)));
+            assert_eq!(model.predict(feature_vec, options.beam_size), probabilities);
+            // The leaf transform, smoothing, and translation of the options don't apply, so they
+            // aren't checked against the model either
+            assert_eq!(
+                probabilities,
+                model.predict_proba(feature_vec, &ignored_options).unwrap()
+            );
+        }
+    }
+
+    #[test]
+    fn test_top_k() {
+        let inputs = toy_dataset(20, 8, 1).feature_lists;
+        for seed in 0..4 {
+            let model = toy_model(1 + seed as usize % 3, seed);
+            for &beam_size in &[1, 3, 10] {
+                for feature_vec in &inputs {
+                    let all = model.predict(feature_vec, beam_size);
+                    for &k in &[1, 2, 5, all.len(), all.len() + 1] {
+                        let options = PredictOptions {
+                            beam_size,
+                            top_k: Some(k),
+                            ..PredictOptions::default()
+                        };
+                        let top_k = model.predict_with_options(feature_vec, &options).unwrap();
+                        assert_eq!(&all[..k.min(all.len())], &top_k[..]);
+                        assert_eq!(top_k, model.predict_top_k(feature_vec, beam_size, k));
+                    }
+                    assert_eq!(all, model.predict_top_k(feature_vec, beam_size, 0));
+                    assert_eq!(all, model.predict_top_k(feature_vec, beam_size, usize::MAX));
+                }
+            }
+        }
+
+        assert!(PredictOptions {
+            top_k: Some(0),
+            ..PredictOptions::default()
+        }
+        .validate()
+        .is_err());
+    }
+
+    #[test]
+    fn test_score_gap() {
+        let model = toy_model(3, 0);
+        // Returns the predictions with the number of branches and leaves evaluated
+        let predict = |feature_vec: &[(Index, f32)], score_gap| {
+            let options = PredictOptions {
+                beam_size: 50,
+                score_gap,
+                ..PredictOptions::default()
+            };
+            let mut buffers = SearchBuffers::profiled();
+            let predictions = model
+                .predict_with_stats(
+                    feature_vec,
+                    &options,
+                    &mut PredictStats::default(),
+                    &mut PrepareBuffers::default(),
+                    &mut buffers,
+                )
+                .unwrap();
+            let profile = buffers.profile.unwrap();
+            let n_nodes = profile
+                .trees
+                .iter()
+                .flat_map(|tree| &tree.depths)
+                .map(|depth| depth.n_nodes)
+                .sum::<usize>();
+            (predictions, n_nodes)
+        };
+
+        let mut n_fewer = 0;
+        for feature_vec in &toy_dataset(20, 8, 1).feature_lists {
+            let (predictions, n_nodes) = predict(feature_vec, None);
+            assert_eq!(model.predict(feature_vec, 50), predictions);
+            assert_eq!(
+                (predictions.clone(), n_nodes),
+                predict(feature_vec, Some(1e6))
+            );
+
+            // With no gap, only the paths tied with the best are followed
+            let (narrow_predictions, narrow_n_nodes) = predict(feature_vec, Some(0.));
+            assert!(narrow_n_nodes <= n_nodes);
+            assert!(narrow_predictions.len() <= predictions.len());
+            if narrow_n_nodes < n_nodes {
+                n_fewer += 1;
+            }
+        }
+        assert!(n_fewer > 0);
+
+        for &score_gap in &[-1., f32::NAN] {
+            assert!(PredictOptions {
+                score_gap: Some(score_gap),
+                ..PredictOptions::default()
+            }
+            .validate()
+            .is_err());
+        }
+    }
+
+    #[test]
+    fn test_predict_dense() {
+        let model = toy_model(2, 0);
+        let n_features = model.n_features();
+        for feature_vec in &toy_dataset(20, 8, 1).feature_lists {
+            let mut features = vec![0.; n_features];
+            for &(index, value) in feature_vec {
+                features[index as usize] = value;
+            }
+            for &beam_size in &[1, 5] {
+                assert_eq!(
+                    model.predict(feature_vec, beam_size),
+                    model.predict_dense(&features, beam_size).unwrap()
+                );
+            }
+        }
+        assert_eq!(
+            model.predict(Vec::<(Index, f32)>::new(), 5),
+            model.predict_dense(&vec![0.; n_features], 5).unwrap()
+        );
+        assert_eq!(
+            Err(PredictError::DimensionMismatch {
+                n_values: n_features - 1,
+                n_features,
+            }),
+            model.predict_dense(&vec![1.; n_features - 1], 5)
+        );
+    }
+
+    #[test]
+    fn test_predict_csvec() {
+        let model = toy_model(2, 0);
+        let n_features = model.n_features();
+        let feature_vecs = toy_dataset(20, 8, 1).feature_lists;
+        let mat = csrmat_from_index_value_pair_lists(feature_vecs.clone(), n_features);
+        for (row, feature_vec) in feature_vecs.iter().enumerate() {
+            for &beam_size in &[1, 5] {
+                assert_eq!(
+                    model.predict(feature_vec, beam_size),
+                    model.predict_csvec(mat.outer_view(row).unwrap(), beam_size)
+                );
+            }
+        }
+        assert_eq!(
+            model.predict_batch(&feature_vecs, 5),
+            model.predict_csr_rows(mat.view(), 5)
+        );
+    }
+
+    #[test]
+    fn test_predict_per_tree() {
+        let model = toy_model(3, 0);
+        for feature_vec in &toy_dataset(20, 8, 1).feature_lists {
+            for &beam_size in &[1, 3, 10] {
+                let tree_predictions = model.predict_per_tree(feature_vec, beam_size);
+                assert_eq!(3, tree_predictions.len());
+                for predictions in &tree_predictions {
+                    assert!(!predictions.is_empty());
+                    assert!(predictions
+                        .iter()
+                        .all(|&(_, score)| (0. ..=1.).contains(&score)));
+                    assert!(predictions.windows(2).all(|w| w[0].1 >= w[1].1));
+                }
+                assert_eq!(
+                    model.predict(feature_vec, beam_size),
+                    model.average_tree_predictions(tree_predictions, 3, |n_labels| n_labels)
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_rank_top_k_ties() {
+        use super::super::rank_top_k;
+
+        let pairs = vec![(4, 0.5), (1, 0.5), (3, 1.), (0, 0.25), (2, 0.5), (5, 0.5)];
+        let ranked = vec![(3, 1.), (1, 0.5), (2, 0.5), (4, 0.5), (5, 0.5), (0, 0.25)];
+        for k in 0..=ranked.len() + 1 {
+            assert_eq!(
+                &ranked[..k.min(ranked.len())],
+                &rank_top_k(pairs.iter().copied(), k)[..]
+            );
+        }
+    }
+
+    #[test]
+    fn test_tie_breaking() {
+        // All weights are zero, so all children, and all labels, score the same
+        let mut model = toy_model(1, 0);
+        let n_rows = model.n_features() + 1;
+        let leaf = |labels: Vec<Index>| TreeNode::Leaf {
+            weights: WeightMat::Dense(DenseMat::zeros((n_rows, labels.len()))),
+            labels,
+        };
+        *model.trees_mut() = vec![TreeNode::Branch {
+            weights: WeightMat::Dense(DenseMat::zeros((n_rows, 3))),
+            children: vec![leaf(vec![5, 1, 6]), leaf(vec![0, 7]), leaf(vec![3, 2])],
+        }];
+        let feature_vec = &toy_dataset(1, 8, 1).feature_lists[0];
+
+        // The first children are kept in the beam, and the smallest labels in each leaf
+        let expected_labels = [vec![1], vec![0, 1, 5, 7], vec![0, 1, 2, 3, 5, 6, 7]];
+        for _ in 0..100 {
+            for (beam_size, expected_labels) in (1..).zip(&expected_labels) {
+                let predictions = model.predict(feature_vec, beam_size);
+                assert_eq!(
+                    *expected_labels,
+                    predictions
+                        .iter()
+                        .map(|&(label, _)| label)
+                        .collect::<Vec<_>>()
+                );
+                assert_eq!(
+                    predictions,
+                    model.predict_batch(&[feature_vec], beam_size)[0]
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_label_score_sums() {
+        use super::super::LabelScoreSums;
+
+        let pairs = vec![(4, 0.5), (1, 0.25), (4, 1.), (0, 0.), (1, 0.5)];
+        let expected = vec![(0, 0.), (1, 0.75), (4, 1.5)];
+        let mut sums = LabelScoreSums::default();
+        for &label_bound in &[Some(5), None, Some(5), Some(8)] {
+            sums.add(pairs.iter().copied(), label_bound);
+            assert_eq!(3, sums.len());
+            let mut totals = sums.drain().collect::<Vec<_>>();
+            totals.sort_unstable_by_key(|&(label, _)| label);
+            assert_eq!(expected, totals);
+            assert_eq!(0, sums.len());
+        }
+
+        // Predictions are the same whether scores are summed densely or not
+        let mut model = toy_model(3, 0);
+        let feature_vecs = toy_dataset(20, 8, 1).feature_lists;
+        assert!(model.dense_label_bound().is_some());
+        let expected = model.predict_batch(&feature_vecs, 10);
+        let spread = model
+            .labels()
+            .into_iter()
+            .map(|label| (label, label * 1000))
+            .collect::<HashMap<_, _>>();
+        for tree in model.trees_mut() {
+            tree.renumber_labels(&spread);
+        }
+        assert_eq!(None, model.dense_label_bound());
+        let predictions = model.predict_batch(&feature_vecs, 10);
+        for (expected, predictions) in expected.iter().zip(&predictions) {
+            assert_eq!(
+                expected
+                    .iter()
+                    .map(|&(label, score)| (label * 1000, score))
+                    .collect::<Vec<_>>(),
+                *predictions
+            );
+        }
+    }
+
+    #[test]
+    fn test_empty_model() {
+        let mut model = toy_model(1, 0);
+        assert!(model.validate().is_ok());
+        assert_eq!(8, model.n_labels());
+        let feature_vec = &toy_dataset(1, 8, 1).feature_lists[0];
+
+        // A tree left without labels still predicts nothing
+        *model.trees_mut() = vec![TreeNode::Leaf {
+            weights: WeightMat::Dense(DenseMat::zeros((model.n_features() + 1, 0))),
+            labels: vec![],
+        }];
+        assert!(model.validate().is_err());
+        assert!(model.predict(feature_vec, 5).is_empty());
+        assert_eq!(
+            Ok(vec![]),
+            model.predict_with_options(feature_vec, &PredictOptions::default())
+        );
+
+        model.trees_mut().clear();
+        assert!(model.validate().is_err());
+        assert_eq!(0, model.n_labels());
+        assert_eq!(1, model.max_useful_beam());
+        assert!(model.predict(feature_vec, 5).is_empty());
+        let predictor = model.predictor(PredictOptions::default()).unwrap();
+        assert_eq!(
+            Err(PredictError::EmptyModel),
+            predictor.predict(feature_vec)
+        );
+        assert_eq!(1, predictor.stats().n_failed);
+    }
+
+    #[test]
+    #[should_panic(expected = "At least one tree must be taken")]
+    fn test_take_no_trees() {
+        toy_model(2, 0).take_trees(&[]);
+    }
+
+    #[test]
+    fn test_corrupt_leaf() {
+        fn first_leaf_labels(node: &mut TreeNode) -> &mut Vec<Index> {
+            match node {
+                TreeNode::Branch { children, .. } => first_leaf_labels(&mut children[0]),
+                TreeNode::Leaf { labels, .. } => labels,
+            }
+        }
+
+        let mut model = toy_model(2, 0);
+        first_leaf_labels(&mut model.trees_mut()[1]).pop();
+
+        // Use a beam wide enough to reach every leaf
+        let options = PredictOptions {
+            beam_size: model.max_useful_beam(),
+            ..PredictOptions::default()
+        };
+        match model.predict_with_options(&[(0, 1.)], &options) {
+            Err(PredictError::ModelCorrupt { tree, .. }) => assert_eq!(1, tree),
+            other => panic!("Expected corrupt model error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_nan_score() {
+        let model = toy_model(2, 0);
+        let predictor = model.predictor(PredictOptions::default()).unwrap();
+        for &value in &[f32::INFINITY, f32::NAN] {
+            assert_eq!(
+                Err(PredictError::NanScore { tree: 0 }),
+                predictor.predict(&[(0, 1.), (1, value)])
+            );
+        }
+        assert!(predictor.predict(&[(0, 1.), (1, f32::MAX)]).is_ok());
+        assert_eq!(2, predictor.stats().n_failed);
+    }
+}
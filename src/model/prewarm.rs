@@ -0,0 +1,120 @@
+//! Warming up a freshly loaded model before it serves predictions.
+//!
+//! Right after loading, the first predictions are slow: weights that were never read fault in
+//! their memory pages, and caches, branch predictors and allocator pools are cold. Reading all
+//! weights once and making a few real predictions moves that cost out of the first requests.
+use super::{Model, PredictOptions, TreeNode};
+use crate::Index;
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+/// What warming up a model did.
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct PrewarmReport {
+    /// The number of bytes of weights and leaf labels read, which equals [`Model::mem_size`].
+    pub bytes_touched: usize,
+    /// The number of sample predictions made.
+    pub n_predictions: usize,
+    /// The time spent.
+    pub time: Duration,
+}
+
+impl TreeNode {
+    /// Read the weights and labels of all nodes in the subtree, in pre-order, returning a
+    /// checksum with the number of bytes read.
+    fn touch(&self) -> (u64, usize) {
+        match self {
+            TreeNode::Branch { weights, children } => children.iter().fold(
+                (weights.touch(), weights.mem_size()),
+                |(sum, n_bytes), child| {
+                    let (child_sum, child_bytes) = child.touch();
+                    (sum.wrapping_add(child_sum), n_bytes + child_bytes)
+                },
+            ),
+            TreeNode::Leaf { weights, labels } => {
+                let sum = labels.iter().fold(weights.touch(), |sum, &label| {
+                    sum.wrapping_add(label as u64)
+                });
+                (
+                    sum,
+                    weights.mem_size() + std::mem::size_of_val(labels.as_slice()),
+                )
+            }
+        }
+    }
+}
+
+impl Model {
+    /// Read all weights and leaf labels once, and predict the given sample inputs, if any, so that
+    /// later predictions don't pay for cold memory.
+    ///
+    /// Trees are read one after another, each in the order its nodes are stored, or all at once
+    /// on the current Rayon thread pool if `parallel` is set. Samples are predicted with the
+    /// default options of [`PredictOptions`], and their results are discarded; predictions are
+    /// unaffected.
+    pub fn prewarm(
+        &self,
+        sample_inputs: Option<&[Vec<(Index, f32)>]>,
+        parallel: bool,
+    ) -> PrewarmReport {
+        let start_t = Instant::now();
+        let (checksum, bytes_touched) = if parallel {
+            self.trees
+                .par_iter()
+                .map(TreeNode::touch)
+                .reduce(|| (0, 0), |(a, m), (b, n)| (a.wrapping_add(b), m + n))
+        } else {
+            self.trees
+                .iter()
+                .map(TreeNode::touch)
+                .fold((0, 0), |(a, m), (b, n)| (a.wrapping_add(b), m + n))
+        };
+        black_box(checksum);
+
+        let sample_inputs = sample_inputs.unwrap_or_default();
+        let beam_size = PredictOptions::default().beam_size;
+        if parallel {
+            black_box(self.predict_batch(sample_inputs, beam_size));
+        } else {
+            for feature_vec in sample_inputs {
+                black_box(self.predict(feature_vec, beam_size));
+            }
+        }
+
+        PrewarmReport {
+            bytes_touched,
+            n_predictions: sample_inputs.len(),
+            time: start_t.elapsed(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{toy_dataset, toy_model};
+
+    #[test]
+    fn test_prewarm() {
+        let mut model = toy_model(3, 0);
+        let samples = toy_dataset(20, 8, 1).feature_lists;
+        let expected = model.predict_batch(&samples, 10);
+
+        for &parallel in &[false, true] {
+            let report = model.prewarm(Some(&samples), parallel);
+            assert_eq!(model.mem_size(), report.bytes_touched);
+            assert_eq!(samples.len(), report.n_predictions);
+            assert_eq!(expected, model.predict_batch(&samples, 10));
+        }
+
+        // Dense weights are counted the same way
+        model.densify_weights(0.);
+        let expected = model.predict_batch(&samples, 10);
+        let report = model.prewarm(None, false);
+        assert_eq!(model.mem_size(), report.bytes_touched);
+        assert_eq!(0, report.n_predictions);
+        assert_eq!(expected, model.predict_batch(&samples, 10));
+    }
+}
@@ -0,0 +1,219 @@
+//! Sparse random projection of feature vectors to a lower dimension.
+//!
+//! Each input feature is mapped to `nnz_per_feature` output dimensions, each with a random sign
+//! and a magnitude of `1 / sqrt(nnz_per_feature)`. The mapping is computed by hashing the feature
+//! index with the seed, so the projection matrix is never stored and inputs of any dimension can
+//! be projected; only the parameters are saved with the model.
+use crate::mat_util::*;
+use crate::{DataSet, Index, IndexValueVec};
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+
+/// Parameters of a sparse sign projection.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ProjectionParams {
+    /// The dimension of projected feature vectors.
+    pub n_components: usize,
+    /// The number of output dimensions each input feature contributes to.
+    pub nnz_per_feature: usize,
+    /// Seed that the projection matrix is derived from.
+    pub seed: u64,
+}
+
+impl ProjectionParams {
+    /// Check if the parameters are valid.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.n_components == 0 {
+            Err("n_components must be positive".to_owned())
+        } else if self.n_components > Index::MAX as usize {
+            // The bias feature is appended at index n_components
+            Err(format!(
+                "n_components must be at most {}, but is {}",
+                Index::MAX,
+                self.n_components
+            ))
+        } else if self.nnz_per_feature == 0 {
+            Err("nnz_per_feature must be positive".to_owned())
+        } else if self.nnz_per_feature > self.n_components {
+            Err(format!(
+                "nnz_per_feature must be at most n_components = {}, but is {}",
+                self.n_components, self.nnz_per_feature
+            ))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// The output dimension and signed weight of the `k`-th entry for the given input feature.
+    fn entry(&self, feature: Index, k: usize) -> (Index, f32) {
+        let hash = mix(self.seed ^ mix((u64::from(feature) << 16) ^ k as u64));
+        let output = (hash >> 1) % self.n_components as u64;
+        let magnitude = (self.nnz_per_feature as f32).sqrt().recip();
+        let value = if hash & 1 == 0 { magnitude } else { -magnitude };
+        (output as Index, value)
+    }
+
+    /// Project a sparse vector, returning pairs sorted by index.
+    ///
+    /// Only the entries of non-zero input features are computed, so the cost is proportional to
+    /// the number of input pairs times `nnz_per_feature`.
+    pub fn project(&self, feature_vec: &[(Index, f32)]) -> IndexValueVec {
+        let mut projected = Vec::with_capacity(feature_vec.len() * self.nnz_per_feature);
+        for &(feature, value) in feature_vec {
+            for k in 0..self.nnz_per_feature {
+                let (output, weight) = self.entry(feature, k);
+                projected.push((output, weight * value));
+            }
+        }
+        projected.sort_by_index();
+        projected.sum_duplicate_indices();
+        projected.retain(|&(_, v)| v != 0.);
+        projected
+    }
+
+    /// Project all feature vectors of a dataset.
+    pub(crate) fn project_dataset(&self, dataset: DataSet) -> DataSet {
+        DataSet {
+            n_features: self.n_components,
+            feature_lists: dataset
+                .feature_lists
+                .par_iter()
+                .map(|feature_vec| self.project(feature_vec))
+                .collect(),
+            ..dataset
+        }
+    }
+}
+
+/// The SplitMix64 finalizer, which scrambles all bits of the input.
+fn mix(mut x: u64) -> u64 {
+    x = x.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    x ^ (x >> 31)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{reproducible_hyper_param, toy_dataset};
+    use assert_approx_eq::assert_approx_eq;
+    use hashbrown::HashMap;
+    use itertools::Itertools;
+
+    /// Find parameters mapping each of the given features to a distinct dimension with a single
+    /// entry, so that the projection preserves inner products like the identity.
+    fn identity_like_params(n_features: usize) -> ProjectionParams {
+        (0..)
+            .map(|seed| ProjectionParams {
+                // Wide enough for collisions to be unlikely
+                n_components: n_features * n_features * 4,
+                nnz_per_feature: 1,
+                seed,
+            })
+            .find(|params| {
+                (0..n_features)
+                    .map(|i| params.entry(i as Index, 0).0)
+                    .all_unique()
+            })
+            .unwrap()
+    }
+
+    #[test]
+    fn test_project() {
+        let params = ProjectionParams {
+            n_components: 16,
+            nnz_per_feature: 4,
+            seed: 42,
+        };
+        assert!(params.validate().is_ok());
+        let feature_vec = vec![(3, 1.), (1000, 2.), (4_000_000, 0.5)];
+        let projected = params.project(&feature_vec);
+        assert!(projected.is_valid_sparse_vec(16));
+        assert_eq!(projected, params.project(&feature_vec));
+
+        // Every entry has the same magnitude, and input features contribute linearly
+        let mut expected = vec![0.; 16];
+        for &(feature, value) in &feature_vec {
+            for k in 0..4 {
+                let (output, weight) = params.entry(feature, k);
+                assert_eq!(0.5, weight.abs());
+                expected[output as usize] += weight * value;
+            }
+        }
+        for (output, value) in projected {
+            assert_approx_eq!(expected[output as usize], value, 1e-6);
+        }
+
+        assert!(ProjectionParams {
+            n_components: 4,
+            nnz_per_feature: 5,
+            seed: 0
+        }
+        .validate()
+        .is_err());
+    }
+
+    #[test]
+    fn test_identity_like_projection_reproduces_baseline() {
+        let dataset = toy_dataset(60, 8, 0);
+        let mut hyper_param = reproducible_hyper_param();
+        let baseline = hyper_param.train(dataset.clone());
+
+        let params = identity_like_params(dataset.n_features);
+        hyper_param.feature_projection = Some(params);
+        let projected = hyper_param.train(dataset);
+        assert_eq!(params.n_components, projected.n_features());
+
+        // Training only sees inner products, which the projection preserves
+        for feature_vec in &toy_dataset(20, 8, 1).feature_lists {
+            let expected = baseline.predict(feature_vec, 10);
+            let actual = projected.predict(feature_vec, 10);
+            assert_eq!(expected[0].0, actual[0].0);
+            assert_eq!(expected.len(), actual.len());
+            let actual: HashMap<_, _> = actual.into_iter().collect();
+            for (label, expected_score) in expected {
+                assert_approx_eq!(expected_score, actual[&label], 1e-3);
+            }
+        }
+    }
+
+    #[test]
+    fn test_projected_model_predicts_sanely() {
+        let params = ProjectionParams {
+            n_components: 24,
+            nnz_per_feature: 2,
+            seed: 7,
+        };
+        let dataset = toy_dataset(100, 8, 0);
+        let mut hyper_param = crate::model::TrainHyperParam::default();
+        hyper_param.min_branch_size = 2;
+        hyper_param.feature_projection = Some(params);
+        let model = hyper_param.train(dataset);
+        assert_eq!(24, model.n_features());
+        assert_eq!(Some(params), model.feature_projection);
+
+        let DataSet {
+            feature_lists,
+            labels,
+            ..
+        } = toy_dataset(40, 8, 1);
+        let n_correct = feature_lists
+            .iter()
+            .zip(labels.iter())
+            .filter(|(feature_vec, labels)| labels.contains(&model.predict(feature_vec, 10)[0].0))
+            .count();
+        assert!(n_correct >= 30, "Only {} of 40 correct", n_correct);
+
+        // The projection is saved with the model
+        let path = std::env::temp_dir().join(format!("omikuji-projection-{}", std::process::id()));
+        model.save(&path).unwrap();
+        let loaded = crate::Model::load(&path).unwrap();
+        assert_eq!(model.feature_projection, loaded.feature_projection);
+        assert_eq!(
+            model.predict(&feature_lists[0], 10),
+            loaded.predict(&feature_lists[0], 10)
+        );
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+}
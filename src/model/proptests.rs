@@ -0,0 +1,219 @@
+//! Property-based tests of the prediction path.
+//!
+//! Models are trained on small synthetic datasets, and inputs are drawn from structured strategies
+//! so that failing cases shrink to small models and short feature vectors.
+use super::eval;
+use super::liblinear::LossType;
+use super::predict::{LeafTransform, OovPolicy, PredictError, PredictOptions};
+use super::{Model, TrainHyperParam};
+use crate::data::LabelMatrix;
+use crate::test_util::{toy_dataset, TOY_FEATURES_PER_LABEL, TOY_NOISE_FEATURES};
+use crate::{DataSet, Index, IndexValueVec};
+use proptest::prelude::*;
+use rayon::prelude::*;
+
+/// Parameters of a synthetic model.
+#[derive(Clone, Debug)]
+struct ModelParams {
+    n_labels: usize,
+    n_examples: usize,
+    n_trees: usize,
+    min_branch_size: usize,
+    loss_type: LossType,
+    seed: u64,
+}
+
+impl ModelParams {
+    fn n_features(&self) -> usize {
+        self.n_labels * TOY_FEATURES_PER_LABEL + TOY_NOISE_FEATURES
+    }
+
+    fn train(&self) -> Model {
+        let mut hyper_param = TrainHyperParam::default();
+        hyper_param.n_trees = self.n_trees;
+        hyper_param.min_branch_size = self.min_branch_size;
+        hyper_param.linear.loss_type = self.loss_type;
+        hyper_param.train(toy_dataset(self.n_examples, self.n_labels, self.seed))
+    }
+}
+
+fn model_params() -> impl Strategy<Value = ModelParams> {
+    (
+        2..12usize,
+        10..50usize,
+        1..4usize,
+        2..5usize,
+        prop_oneof![Just(LossType::Hinge), Just(LossType::Log)],
+        any::<u64>(),
+    )
+        .prop_map(
+            |(n_labels, n_examples, n_trees, min_branch_size, loss_type, seed)| ModelParams {
+                n_labels,
+                n_examples,
+                n_trees,
+                min_branch_size,
+                loss_type,
+                seed,
+            },
+        )
+}
+
+/// Feature vectors sorted by index without duplicates, whose indices fall out of range for a
+/// model with `n_features` features about a quarter of the time.
+///
+/// Values are zero about a quarter of the time, so that vectors, empty or not, can have zero norm.
+fn feature_vec(n_features: usize) -> impl Strategy<Value = IndexValueVec> {
+    let index = prop_oneof![
+        3 => 0..n_features as Index,
+        1 => n_features as Index..Index::MAX,
+    ];
+    let value = prop_oneof![
+        3 => -10f32..10.,
+        1 => Just(0f32),
+    ];
+    prop::collection::vec((index, value), 0..20).prop_map(|mut pairs| {
+        pairs.sort_by_key(|&(index, _)| index);
+        pairs.dedup_by_key(|&mut (index, _)| index);
+        pairs
+    })
+}
+
+fn predict_options() -> impl Strategy<Value = PredictOptions> {
+    (
+        1..20usize,
+        prop_oneof![
+            Just(OovPolicy::Error),
+            Just(OovPolicy::Drop),
+            Just(OovPolicy::HashInto)
+        ],
+    )
+        .prop_map(|(beam_size, oov_policy)| PredictOptions {
+            beam_size,
+            oov_policy,
+            feature_group_weights: None,
+            leaf_transform: LeafTransform::Exp,
+            top_k: None,
+            smooth_scores: false,
+            score_gap: None,
+            translate_output: false,
+        })
+}
+
+/// A model with inputs and options to predict with; there may be no inputs at all.
+fn prediction_case() -> impl Strategy<Value = (ModelParams, Vec<IndexValueVec>, PredictOptions)> {
+    model_params().prop_flat_map(|params| {
+        let inputs = prop::collection::vec(feature_vec(params.n_features()), 0..8);
+        (Just(params), inputs, predict_options())
+    })
+}
+
+fn check_ranking(predictions: &IndexValueVec) -> Result<(), TestCaseError> {
+    for &(label, score) in predictions {
+        // Both losses give scores that are products of values in (0, 1], averaged over trees
+        prop_assert!(
+            score.is_finite() && (0. ..=1.).contains(&score),
+            "Score {} of label {} out of range",
+            score,
+            label
+        );
+    }
+    for pair in predictions.windows(2) {
+        prop_assert!(pair[0].1 >= pair[1].1, "Not sorted: {:?}", predictions);
+    }
+    let mut labels = predictions
+        .iter()
+        .map(|&(label, _)| label)
+        .collect::<Vec<_>>();
+    labels.sort_unstable();
+    labels.dedup();
+    prop_assert_eq!(labels.len(), predictions.len(), "Duplicate labels");
+    Ok(())
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(32))]
+
+    #[test]
+    fn test_checked_prediction((params, inputs, options) in prediction_case()) {
+        let model = params.train();
+        for feature_vec in &inputs {
+            let has_oov = feature_vec
+                .iter()
+                .any(|&(index, _)| index as usize >= model.n_features());
+            match model.predict_with_options(feature_vec, &options) {
+                Ok(predictions) => {
+                    prop_assert!(!has_oov || options.oov_policy != OovPolicy::Error);
+                    check_ranking(&predictions)?;
+                    if !has_oov {
+                        prop_assert_eq!(model.predict(feature_vec, options.beam_size), predictions);
+                    }
+                }
+                Err(PredictError::FeatureIndexOutOfRange { index, n_features }) => {
+                    prop_assert_eq!(OovPolicy::Error, options.oov_policy);
+                    prop_assert!(index as usize >= n_features);
+                }
+                Err(e) => return Err(TestCaseError::fail(format!("Unexpected error: {}", e))),
+            }
+        }
+    }
+
+    #[test]
+    fn test_sequential_equals_parallel((params, inputs, options) in prediction_case()) {
+        let model = params.train();
+        let inputs = inputs
+            .into_iter()
+            .map(|mut feature_vec| {
+                feature_vec.retain(|&(index, _)| (index as usize) < model.n_features());
+                feature_vec
+            })
+            .collect::<Vec<_>>();
+
+        let sequential = inputs
+            .iter()
+            .map(|feature_vec| model.predict(feature_vec, options.beam_size))
+            .collect::<Vec<_>>();
+        let dataset = DataSet {
+            n_features: model.n_features(),
+            n_labels: params.n_labels,
+            labels: LabelMatrix::from_rows(vec![Vec::new(); inputs.len()]),
+            feature_lists: inputs.clone(),
+        };
+        prop_assert_eq!(&sequential, &eval::predict_all(&model, &dataset, options.beam_size));
+
+        let predictor = model.predictor(options).unwrap();
+        let shared = inputs
+            .par_iter()
+            .map(|feature_vec| predictor.predict(feature_vec).unwrap())
+            .collect::<Vec<_>>();
+        prop_assert_eq!(&sequential, &shared);
+        prop_assert_eq!(inputs.len() as u64, predictor.stats().n_predictions);
+    }
+
+    #[test]
+    fn test_top_k_is_prefix(
+        (params, inputs, options) in prediction_case(),
+        top_k in 1..30usize,
+    ) {
+        let model = params.train();
+        let options = PredictOptions {
+            oov_policy: OovPolicy::Drop,
+            top_k: Some(top_k),
+            ..options
+        };
+        for feature_vec in &inputs {
+            let mut feature_vec = feature_vec.clone();
+            feature_vec.retain(|&(index, _)| (index as usize) < model.n_features());
+
+            let all = model.predict(&feature_vec, options.beam_size);
+            let expected = &all[..top_k.min(all.len())];
+            prop_assert_eq!(
+                expected,
+                &model.predict_top_k(&feature_vec, options.beam_size, top_k)[..]
+            );
+            prop_assert_eq!(
+                expected,
+                &model.predict_with_options(&feature_vec, &options).unwrap()[..]
+            );
+        }
+    }
+}
@@ -0,0 +1,837 @@
+//! Post-training pruning of classifier weights and of branches that are never taken.
+use super::cascade::NodeId;
+use super::eval::{self, Metric};
+use super::limits::SearchBudget;
+use super::{Frontier, Model, TreeNode};
+use crate::data::compute_label_centroids;
+use crate::mat_util::*;
+use crate::{DataSet, Index, IndexValueVec};
+use hashbrown::{HashMap, HashSet};
+use log::info;
+use rayon::prelude::*;
+use std::time;
+
+/// Number of rounds when searching for a pruning threshold, each of which narrows the range of
+/// thresholds left by a factor of `N_SEARCH_CANDIDATES + 1` in log space.
+const N_SEARCH_ROUNDS: usize = 4;
+
+/// Number of thresholds tried in each round of the search for a pruning threshold.
+const N_SEARCH_CANDIDATES: usize = 7;
+
+/// Statistics of a pruning pass.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PruneStats {
+    /// The number of non-zero weights removed.
+    pub n_weights_removed: usize,
+    /// The number of non-zero weights left in the model.
+    pub n_weights_left: usize,
+    /// The ratio of non-zero weights among all weights of the nodes at each depth after pruning,
+    /// from the root down.
+    pub density_by_depth: Vec<f32>,
+    /// The estimated memory saved, as the decrease of [`Model::mem_size`], in bytes.
+    pub bytes_saved: usize,
+}
+
+/// Result of searching for the largest pruning threshold within a metric tolerance.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct PruneOutcome {
+    /// The threshold applied; weights with smaller absolute values were removed.
+    pub threshold: f32,
+    /// The number of non-zero weights before pruning.
+    pub nnz_before: usize,
+    /// The number of non-zero weights after pruning.
+    pub nnz_after: usize,
+    /// The metric on the validation data before pruning.
+    pub metric_before: f32,
+    /// The metric on the validation data after pruning.
+    pub metric_after: f32,
+}
+
+/// A label moved out of a removed branch by [`Model::prune_dead_branches`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MovedLabel {
+    pub label: Index,
+    /// The leaf the label was in, identified in the model before pruning.
+    pub from: NodeId,
+    /// The leaf the label is in now, identified in the model after pruning.
+    pub to: NodeId,
+}
+
+/// What was changed by [`Model::prune_dead_branches`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct DeadBranchReport {
+    /// The children removed, identified in the model before pruning, in order.
+    pub removed_nodes: Vec<NodeId>,
+    /// Labels moved out of the removed children, ordered by their original leaves.
+    pub moved_labels: Vec<MovedLabel>,
+}
+
+impl TreeNode {
+    /// Add the number of non-zero weights and of all weights of each node in the subtree to the
+    /// counts of its depth, starting from the given one.
+    fn count_weights_by_depth(&self, depth: usize, counts: &mut Vec<(usize, usize)>) {
+        let weights = match self {
+            TreeNode::Branch { weights, .. } | TreeNode::Leaf { weights, .. } => weights,
+        };
+        if counts.len() <= depth {
+            counts.resize(depth + 1, (0, 0));
+        }
+        let (rows, cols) = weights.shape();
+        counts[depth].0 += weights.nnz();
+        counts[depth].1 += rows * cols;
+        if let TreeNode::Branch { children, .. } = self {
+            for child in children {
+                child.count_weights_by_depth(depth + 1, counts);
+            }
+        }
+    }
+}
+
+impl Model {
+    /// The number of non-zero weights in all trees.
+    pub fn nnz_weights(&self) -> usize {
+        let mut nnz = 0;
+        for tree in &self.trees {
+            tree.visit_weights(&mut |weights| nnz += weights.nnz());
+        }
+        nnz
+    }
+
+    /// Remove weights with absolute values smaller than the threshold from all trees.
+    ///
+    /// Each weight matrix is then stored in whichever of the dense and sparse formats takes up
+    /// less memory, as in [`WeightMat::from_rows`], so densified matrices may become sparse again;
+    /// call [`Self::densify_weights`] afterwards to trade size for speed.
+    pub fn prune_weights(&mut self, threshold: f32) -> PruneStats {
+        assert!(threshold >= 0., "threshold must be non-negative");
+        let mem_size_before = self.mem_size();
+        let n_weights_removed = self
+            .trees
+            .par_iter_mut()
+            .map(|tree| tree.prune_weights(threshold))
+            .sum();
+
+        let mut counts = Vec::new();
+        for tree in &self.trees {
+            tree.count_weights_by_depth(0, &mut counts);
+        }
+        let density_by_depth = counts
+            .into_iter()
+            .map(|(nnz, n_weights)| nnz as f32 / n_weights.max(1) as f32)
+            .collect();
+        PruneStats {
+            n_weights_removed,
+            n_weights_left: self.nnz_weights(),
+            density_by_depth,
+            bytes_saved: mem_size_before.saturating_sub(self.mem_size()),
+        }
+    }
+
+    /// Prune weights with the largest threshold found whose metric drop on validation data stays
+    /// within `max_drop`.
+    ///
+    /// The threshold is searched in log space between the smallest and the largest absolute weight,
+    /// in rounds that each try evenly spaced thresholds within the range left, from the smallest
+    /// up. Pruning at a larger threshold only removes more weights, so each round prunes a single
+    /// copy of the last accepted model further for each threshold, until the metric drops too
+    /// much; the model is then pruned at the last threshold accepted. So only one copy of the
+    /// model is made in each round. Since thresholds are only accepted after being evaluated, the
+    /// final metric drop is always within tolerance.
+    ///
+    /// An invalid metric, a zero beam size, a negative tolerance, or empty validation data is an
+    /// error, and leaves the model unchanged.
+    pub fn prune_to_tolerance(
+        &mut self,
+        validation: &DataSet,
+        beam_size: usize,
+        metric: Metric,
+        max_drop: f32,
+    ) -> Result<PruneOutcome, String> {
+        metric.validate()?;
+        if beam_size == 0 {
+            return Err("Beam size must be positive".to_owned());
+        }
+        if max_drop.is_nan() || max_drop < 0. {
+            return Err(format!(
+                "Maximum metric drop must be non-negative, got {}",
+                max_drop
+            ));
+        }
+        if validation.is_empty() {
+            return Err("Validation data must not be empty".to_owned());
+        }
+        info!(
+            "Searching for pruning threshold within {} drop of {:?}",
+            max_drop, metric
+        );
+        let start_t = time::Instant::now();
+
+        let true_labels = validation.labels.to_sets();
+        let evaluate = |model: &Model| {
+            metric.compute(
+                &true_labels,
+                &eval::predict_all(model, validation, beam_size),
+            )
+        };
+        let nnz_before = self.nnz_weights();
+        let metric_before = evaluate(self);
+
+        let mut range = None;
+        for tree in &self.trees {
+            tree.visit_weights(&mut |weights| {
+                if let Some((min, max)) = weights.nonzero_abs_range() {
+                    range = Some(match range {
+                        None => (min, max),
+                        Some((range_min, range_max)) => (min.min(range_min), max.max(range_max)),
+                    });
+                }
+            });
+        }
+
+        let mut threshold = 0.;
+        let mut metric_after = metric_before;
+        if let Some((min, max)) = range {
+            // Pruning at lo never removes anything, while pruning at hi removes everything
+            let (mut lo, mut hi) = (min, max * 2.);
+            for round in 0..N_SEARCH_ROUNDS {
+                // Only the first round tries hi itself, in case everything can be pruned
+                let n_candidates = N_SEARCH_CANDIDATES + usize::from(round == 0);
+                let step = (hi / lo).powf(1. / (N_SEARCH_CANDIDATES + 1) as f32);
+                let mut candidate = self.clone();
+                let mut accepted = None;
+                for i in 1..=n_candidates {
+                    let candidate_threshold = if i == N_SEARCH_CANDIDATES + 1 {
+                        hi
+                    } else {
+                        lo * step.powi(i as i32)
+                    };
+                    candidate.prune_weights(candidate_threshold);
+                    let candidate_metric = evaluate(&candidate);
+                    info!(
+                        "Threshold {:e}: {:?} = {:.4}",
+                        candidate_threshold, metric, candidate_metric
+                    );
+
+                    if metric_before - candidate_metric <= max_drop {
+                        accepted = Some((candidate_threshold, candidate_metric));
+                    } else {
+                        hi = candidate_threshold;
+                        break;
+                    }
+                }
+                drop(candidate);
+
+                if let Some((candidate_threshold, candidate_metric)) = accepted {
+                    self.prune_weights(candidate_threshold);
+                    threshold = candidate_threshold;
+                    metric_after = candidate_metric;
+                    if candidate_threshold >= hi {
+                        break; // Everything can be pruned
+                    }
+                    lo = candidate_threshold;
+                }
+            }
+        }
+
+        let outcome = PruneOutcome {
+            threshold,
+            nnz_before,
+            nnz_after: self.nnz_weights(),
+            metric_before,
+            metric_after,
+        };
+        info!(
+            "Pruned with threshold {:e}, {} -> {} weights; it took {:.2}s",
+            threshold,
+            outcome.nnz_before,
+            outcome.nnz_after,
+            start_t.elapsed().as_secs_f32()
+        );
+        Ok(outcome)
+    }
+
+    /// Remove children of branches that beam search on the given data rarely reaches.
+    ///
+    /// Each example is routed through every tree by beam search with the given beam size, and a
+    /// node counts as visited by an example if it ever enters the beam. Children visited by less
+    /// than `min_visit_fraction` of the examples are removed along with the corresponding
+    /// columns of their parents' weight matrices, unless all children of the parent would be
+    /// removed. Labels of removed children are moved, together with their classifiers, into the
+    /// leaf among the subtrees of the remaining siblings whose label centroids are most similar,
+    /// so that they can still be predicted.
+    ///
+    /// Since children are removed and renumbered, node identifiers in the model can change; those
+    /// recorded in its training metadata are renumbered to match, and records of removed nodes
+    /// are dropped. A zero beam size or a fraction outside of [0, 1] is an error.
+    pub fn prune_dead_branches(
+        &mut self,
+        dataset: &DataSet,
+        beam_size: usize,
+        min_visit_fraction: f32,
+    ) -> Result<DeadBranchReport, String> {
+        if beam_size == 0 {
+            return Err("Beam size must be positive".to_owned());
+        }
+        if !(0. ..=1.).contains(&min_visit_fraction) {
+            return Err(format!(
+                "Minimum visit fraction must be within [0, 1], got {}",
+                min_visit_fraction
+            ));
+        }
+        info!(
+            "Pruning branches visited by less than {} of {} examples",
+            min_visit_fraction,
+            dataset.len()
+        );
+        let start_t = time::Instant::now();
+
+        let feature_vecs = dataset
+            .feature_lists
+            .par_iter()
+            .map(|feature_vec| self.prepare_feature_vec(feature_vec))
+            .collect::<Vec<_>>();
+        let visit_counts = self.count_visits(&feature_vecs, beam_size);
+        let min_visits = min_visit_fraction * dataset.len() as f32;
+        let is_dead = |node: &NodeId| {
+            let key = (node.tree(), node.path().to_vec());
+            (visit_counts.get(&key).copied().unwrap_or(0) as f32) < min_visits
+        };
+
+        // Centroids are computed from the inputs as the model sees them
+        let rows = feature_vecs
+            .iter()
+            .map(|feature_vec| {
+                feature_vec
+                    .iter()
+                    .map(|(i, &v)| (i as Index, v))
+                    .collect::<IndexValueVec>()
+            })
+            .collect::<Vec<_>>();
+        let (labels, centroids) =
+            compute_label_centroids(|i| &rows[i], dataset.labels.iter(), dataset.n_labels, 0.);
+        let label_to_centroid = labels.into_iter().zip(centroids).collect::<HashMap<_, _>>();
+
+        let mut report = DeadBranchReport::default();
+        let n_features = self.settings.n_weight_features();
+        for (i, tree) in self.trees_mut().iter_mut().enumerate() {
+            let root = NodeId::root(i);
+            let pruned = std::mem::replace(tree, TreeNode::empty_leaf(n_features));
+            *tree = pruned.prune_dead_children(
+                &root,
+                &root,
+                n_features,
+                &is_dead,
+                &label_to_centroid,
+                &mut report,
+            );
+        }
+        report.removed_nodes.sort_unstable();
+        report
+            .moved_labels
+            .sort_unstable_by(|a, b| (&a.from, a.label).cmp(&(&b.from, b.label)));
+        self.training_metadata = self
+            .training_metadata
+            .renumber_nodes(|node| renumber_node(node, &report.removed_nodes));
+
+        info!(
+            "Removed {} branches and moved {} labels; it took {:.2}s",
+            report.removed_nodes.len(),
+            report.moved_labels.len(),
+            start_t.elapsed().as_secs_f32()
+        );
+        Ok(report)
+    }
+
+    /// Count the number of feature vectors for which each node enters the beam, with nodes given
+    /// by their tree indices and paths.
+    fn count_visits(
+        &self,
+        feature_vecs: &[SparseVec],
+        beam_size: usize,
+    ) -> HashMap<(usize, Vec<usize>), usize> {
+        let loss_type = self.settings.classifier_loss_type;
+        feature_vecs
+            .par_iter()
+            .fold(HashMap::new, |mut counts, feature_vec| {
+                for (tree, root) in self.trees.iter().enumerate() {
+                    let mut visited = HashSet::new();
+                    let mut frontier = Frontier::new(vec![(root, 0., Vec::new())]);
+                    loop {
+                        visited.extend(frontier.nodes.iter().map(|(_, _, path)| path.clone()));
+                        if frontier.nodes.iter().all(|(node, _, _)| node.is_leaf()) {
+                            break;
+                        }
+                        TreeNode::expand_frontier(
+                            &mut frontier,
+                            loss_type,
+                            feature_vec,
+                            beam_size,
+                            1,
+                            |path: &Vec<usize>, i| {
+                                let mut path = path.clone();
+                                path.push(i);
+                                Some(path)
+                            },
+                            &mut SearchBudget::unlimited(),
+                        )
+                        .unwrap_or_else(|message| panic!("Corrupt tree: {}", message));
+                    }
+                    for path in visited {
+                        *counts.entry((tree, path)).or_insert(0) += 1;
+                    }
+                }
+                counts
+            })
+            .reduce(HashMap::new, |mut counts, other| {
+                for (node, count) in other {
+                    *counts.entry(node).or_insert(0) += count;
+                }
+                counts
+            })
+    }
+}
+
+impl TreeNode {
+    /// A leaf without labels, used as a placeholder while a tree is rebuilt.
+    fn empty_leaf(n_features: usize) -> Self {
+        TreeNode::Leaf {
+            weights: WeightMat::Sparse(LilMat::new((n_features + 1, 0))),
+            labels: Vec::new(),
+        }
+    }
+
+    /// Remove dead children in the subtree, moving their labels into the most similar leaves
+    /// under their remaining siblings; `old_id` and `new_id` identify this node before and after
+    /// pruning.
+    fn prune_dead_children(
+        self,
+        old_id: &NodeId,
+        new_id: &NodeId,
+        n_features: usize,
+        is_dead: &impl Fn(&NodeId) -> bool,
+        label_to_centroid: &HashMap<Index, IndexValueVec>,
+        report: &mut DeadBranchReport,
+    ) -> Self {
+        let (weights, children) = match self {
+            TreeNode::Branch { weights, children } => (weights, children),
+            leaf => return leaf,
+        };
+        let (dead, alive): (Vec<_>, Vec<_>) =
+            (0..children.len()).partition(|&i| is_dead(&old_id.child(i)));
+        if alive.is_empty() || dead.is_empty() {
+            let children = children
+                .into_iter()
+                .enumerate()
+                .map(|(i, child)| {
+                    child.prune_dead_children(
+                        &old_id.child(i),
+                        &new_id.child(i),
+                        n_features,
+                        is_dead,
+                        label_to_centroid,
+                        report,
+                    )
+                })
+                .collect();
+            return TreeNode::Branch { weights, children };
+        }
+
+        let weights = WeightMat::from_columns_of(
+            n_features + 1,
+            &alive.iter().map(|&i| (&weights, i)).collect::<Vec<_>>(),
+        );
+        let mut dead_leaves = Vec::new();
+        let mut new_children = Vec::with_capacity(alive.len());
+        for (i, child) in children.into_iter().enumerate() {
+            if dead.contains(&i) {
+                report.removed_nodes.push(old_id.child(i));
+                child.into_leaves(old_id.child(i), &mut dead_leaves);
+            } else {
+                new_children.push(child.prune_dead_children(
+                    &old_id.child(i),
+                    &new_id.child(new_children.len()),
+                    n_features,
+                    is_dead,
+                    label_to_centroid,
+                    report,
+                ));
+            }
+        }
+
+        // Find the most similar remaining leaf for each label of the removed children
+        let mut targets = Vec::new();
+        for (j, child) in new_children.iter_mut().enumerate() {
+            child.leaves_mut(new_id.child(j), &mut targets);
+        }
+        let target_centroids = targets
+            .iter()
+            .map(|(_, _, labels)| {
+                let mut feature_to_sum = HashMap::<Index, f32>::new();
+                for centroid in labels.iter().filter_map(|l| label_to_centroid.get(l)) {
+                    for &(feature, value) in centroid {
+                        *feature_to_sum.entry(feature).or_default() += value;
+                    }
+                }
+                let mut centroid = feature_to_sum.into_iter().collect::<IndexValueVec>();
+                centroid.l2_normalize();
+                centroid.sort_by_index();
+                centroid
+            })
+            .collect::<Vec<_>>();
+        let mut target_to_moved = vec![Vec::new(); targets.len()];
+        for (leaf_id, leaf_weights, leaf_labels) in &dead_leaves {
+            for (col, &label) in leaf_labels.iter().enumerate() {
+                let similarity = |centroid: &IndexValueVec| {
+                    label_to_centroid
+                        .get(&label)
+                        .map_or(0., |label_centroid| sparse_dot(label_centroid, centroid))
+                };
+                // Ties go to the first leaf
+                let mut best = 0;
+                for (k, centroid) in target_centroids.iter().enumerate().skip(1) {
+                    if similarity(centroid) > similarity(&target_centroids[best]) {
+                        best = k;
+                    }
+                }
+                target_to_moved[best].push((leaf_weights, col, label));
+                report.moved_labels.push(MovedLabel {
+                    label,
+                    from: leaf_id.clone(),
+                    to: targets[best].0.clone(),
+                });
+            }
+        }
+
+        for ((_, weights, labels), moved) in targets.into_iter().zip(target_to_moved) {
+            if moved.is_empty() {
+                continue;
+            }
+            let old_weights = weights.clone();
+            let mut columns = (0..labels.len())
+                .map(|col| (&old_weights, col))
+                .collect::<Vec<_>>();
+            columns.extend(moved.iter().map(|&(weights, col, _)| (weights, col)));
+            *weights = WeightMat::from_columns_of(n_features + 1, &columns);
+            labels.extend(moved.iter().map(|&(_, _, label)| label));
+        }
+
+        TreeNode::Branch {
+            weights,
+            children: new_children,
+        }
+    }
+
+    /// Take apart the subtree into its leaves, with their identifiers, weights, and labels.
+    fn into_leaves(self, id: NodeId, leaves: &mut Vec<(NodeId, WeightMat, Vec<Index>)>) {
+        match self {
+            TreeNode::Branch { children, .. } => {
+                for (i, child) in children.into_iter().enumerate() {
+                    child.into_leaves(id.child(i), leaves);
+                }
+            }
+            TreeNode::Leaf { weights, labels } => leaves.push((id, weights, labels)),
+        }
+    }
+
+    /// Collect mutable references to the weights and labels of leaves in the subtree.
+    fn leaves_mut<'a>(
+        &'a mut self,
+        id: NodeId,
+        leaves: &mut Vec<(NodeId, &'a mut WeightMat, &'a mut Vec<Index>)>,
+    ) {
+        match self {
+            TreeNode::Branch { children, .. } => {
+                for (i, child) in children.iter_mut().enumerate() {
+                    child.leaves_mut(id.child(i), leaves);
+                }
+            }
+            TreeNode::Leaf { weights, labels } => leaves.push((id, weights, labels)),
+        }
+    }
+}
+
+/// The identifier after pruning of a node identified before it, given the removed children in
+/// sorted order, or `None` if the node was removed along with itself or an ancestor.
+fn renumber_node(node: &NodeId, removed_nodes: &[NodeId]) -> Option<NodeId> {
+    let is_removed = |id: &NodeId| removed_nodes.binary_search(id).is_ok();
+    let mut old_id = NodeId::root(node.tree());
+    let mut new_id = old_id.clone();
+    for &i in node.path() {
+        if is_removed(&old_id.child(i)) {
+            return None;
+        }
+        let n_removed_before = (0..i).filter(|&j| is_removed(&old_id.child(j))).count();
+        new_id = new_id.child(i - n_removed_before);
+        old_id = old_id.child(i);
+    }
+    Some(new_id)
+}
+
+/// Dot product of sparse vectors sorted by index.
+fn sparse_dot(a: &[(Index, f32)], b: &[(Index, f32)]) -> f32 {
+    let (mut i, mut j) = (0, 0);
+    let mut dot = 0.;
+    while i < a.len() && j < b.len() {
+        match a[i].0.cmp(&b[j].0) {
+            std::cmp::Ordering::Less => i += 1,
+            std::cmp::Ordering::Greater => j += 1,
+            std::cmp::Ordering::Equal => {
+                dot += a[i].1 * b[j].1;
+                i += 1;
+                j += 1;
+            }
+        }
+    }
+    dot
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::liblinear;
+    use crate::model::train::{
+        ClassifierObjectiveCurve, DepthObjectiveCurves, NodeFailure, NodeRecovery,
+    };
+    use crate::model::TrainHyperParam;
+    use crate::test_util::{toy_dataset, toy_model};
+    use itertools::Itertools;
+
+    /// Remove the last label of the first leaf with more than one, returning it with its
+    /// classifier.
+    fn split_off_label(node: &mut TreeNode, n_rows: usize) -> Option<(Index, WeightMat)> {
+        match node {
+            TreeNode::Branch { children, .. } => children
+                .iter_mut()
+                .find_map(|child| split_off_label(child, n_rows)),
+            TreeNode::Leaf { weights, labels } if labels.len() > 1 => {
+                let label = labels.pop().unwrap();
+                let columns = (0..=labels.len()).map(|col| (&*weights, col)).collect_vec();
+                let (kept, split) = columns.split_at(labels.len());
+                let split = WeightMat::from_columns_of(n_rows, split);
+                *weights = WeightMat::from_columns_of(n_rows, kept);
+                Some((label, split))
+            }
+            TreeNode::Leaf { .. } => None,
+        }
+    }
+
+    #[test]
+    fn test_renumber_node() {
+        let root = NodeId::root(1);
+        let removed_nodes = vec![root.child(0), root.child(2).child(1)];
+        let renumber = |node: &NodeId| renumber_node(node, &removed_nodes);
+        assert_eq!(Some(root.clone()), renumber(&root));
+        assert_eq!(None, renumber(&root.child(0)));
+        assert_eq!(None, renumber(&root.child(0).child(3)));
+        assert_eq!(Some(root.child(0)), renumber(&root.child(1)));
+        assert_eq!(
+            Some(root.child(1).child(1)),
+            renumber(&root.child(2).child(2))
+        );
+        assert_eq!(None, renumber(&root.child(2).child(1)));
+        // Nodes of other trees are unaffected
+        let other = NodeId::root(0).child(0).child(2);
+        assert_eq!(Some(other.clone()), renumber(&other));
+    }
+
+    #[test]
+    fn test_prune_weights() {
+        let mut model = toy_model(2, 0);
+        let nnz = model.nnz_weights();
+        let stats = model.prune_weights(0.);
+        assert_eq!((0, nnz), (stats.n_weights_removed, stats.n_weights_left));
+        assert!(!stats.density_by_depth.is_empty());
+        assert!(stats.density_by_depth.iter().all(|&d| d > 0. && d <= 1.));
+
+        let mut max_abs_weight = 0f32;
+        for tree in &model.trees {
+            tree.visit_weights(&mut |weights| {
+                if let Some((_, max)) = weights.nonzero_abs_range() {
+                    max_abs_weight = max_abs_weight.max(max);
+                }
+            });
+        }
+        let stats = model.prune_weights(max_abs_weight / 2.);
+        assert!(stats.n_weights_removed > 0);
+        assert_eq!(nnz, stats.n_weights_removed + stats.n_weights_left);
+        assert_eq!(stats.n_weights_left, model.nnz_weights());
+
+        // Densified weights are stored sparse again once all of them are removed
+        model.densify_weights(0.);
+        let stats = model.prune_weights(f32::MAX);
+        assert_eq!(0, model.nnz_weights());
+        assert!(stats.bytes_saved > 0);
+        assert!(stats.density_by_depth.iter().all(|&d| d == 0.));
+        // Prediction still works with all-zero weights, also after saving and loading
+        assert!(!model.predict(&[(0, 1.)], 5).is_empty());
+        let mut buffer = Vec::new();
+        model.save_to_writer(&mut buffer).unwrap();
+        let loaded = Model::load_from_reader(buffer.as_slice()).unwrap();
+        assert_eq!(0, loaded.nnz_weights());
+        assert_eq!(model.predict(&[(0, 1.)], 5), loaded.predict(&[(0, 1.)], 5));
+    }
+
+    #[test]
+    fn test_prune_to_tolerance() {
+        let validation = toy_dataset(40, 8, 1);
+        let metric = Metric::PrecisionAtK(1);
+        let mut model = toy_model(2, 0);
+        let nnz = model.nnz_weights();
+        assert!(model
+            .prune_to_tolerance(&validation, 5, Metric::PrecisionAtK(0), 0.)
+            .is_err());
+        assert!(model
+            .prune_to_tolerance(&validation, 0, metric, 0.)
+            .is_err());
+        assert!(model
+            .prune_to_tolerance(&validation, 5, metric, -0.1)
+            .is_err());
+        assert!(model
+            .prune_to_tolerance(&validation, 5, metric, f32::NAN)
+            .is_err());
+        assert!(model
+            .prune_to_tolerance(&toy_dataset(0, 8, 1), 5, metric, 0.)
+            .is_err());
+        assert_eq!(nnz, model.nnz_weights());
+
+        for &max_drop in &[0., 0.05, 0.2, 1.] {
+            let mut model = toy_model(2, 0);
+            let outcome = model
+                .prune_to_tolerance(&validation, 5, metric, max_drop)
+                .unwrap();
+            if max_drop == 1. {
+                assert_eq!(0, outcome.nnz_after);
+            }
+
+            assert!(outcome.metric_before - outcome.metric_after <= max_drop);
+            assert!(outcome.nnz_after <= outcome.nnz_before);
+            assert_eq!(outcome.nnz_after, model.nnz_weights());
+            assert_eq!(
+                outcome.metric_after,
+                metric.compute(
+                    &validation.labels.to_sets(),
+                    &eval::predict_all(&model, &validation, 5)
+                )
+            );
+        }
+    }
+
+    #[test]
+    fn test_prune_dead_branches() {
+        let dataset = toy_dataset(60, 8, 0);
+        let mut hyper_param = TrainHyperParam::default();
+        hyper_param.n_trees = 1;
+        hyper_param.min_branch_size = 4;
+        let mut model = hyper_param.train(dataset.clone());
+
+        let mut unchanged = model.clone();
+        assert_eq!(
+            DeadBranchReport::default(),
+            unchanged.prune_dead_branches(&dataset, 1, 0.).unwrap()
+        );
+        assert_eq!(model.content_hash(), unchanged.content_hash());
+
+        // Move a label into a new child of the root whose classifier always loses
+        let n_rows = model.settings.n_features + 1;
+        let (label, label_weights) = split_off_label(&mut model.trees_mut()[0], n_rows).unwrap();
+        let n_children = match &mut model.trees_mut()[0] {
+            TreeNode::Branch { weights, children } => {
+                let mut dead_weights = DenseMat::zeros((n_rows, 1));
+                dead_weights[[n_rows - 1, 0]] = -100.;
+                let dead_weights = WeightMat::Dense(dead_weights);
+                let mut columns = (0..children.len())
+                    .map(|col| (&*weights, col))
+                    .collect_vec();
+                columns.push((&dead_weights, 0));
+                *weights = WeightMat::from_columns_of(n_rows, &columns);
+                children.push(TreeNode::Leaf {
+                    weights: label_weights,
+                    labels: vec![label],
+                });
+                children.len() - 1
+            }
+            TreeNode::Leaf { .. } => panic!("Expected the root to branch"),
+        };
+        model.validate().unwrap();
+        assert!(model
+            .clone()
+            .prune_dead_branches(&dataset, 0, 0.01)
+            .is_err());
+        assert!(model.clone().prune_dead_branches(&dataset, 1, 1.5).is_err());
+        assert!(model
+            .clone()
+            .prune_dead_branches(&dataset, 1, f32::NAN)
+            .is_err());
+
+        // Records of the removed child are dropped from the training metadata
+        let dead = NodeId::root(0).child(n_children);
+        let failure = |node: NodeId| NodeFailure {
+            node,
+            message: String::new(),
+            recovery: NodeRecovery::CentroidFallback,
+        };
+        let curve = |column| ClassifierObjectiveCurve {
+            node: NodeId::root(0),
+            column,
+            curve: liblinear::ObjectiveCurve {
+                interval: 1,
+                values: vec![1.],
+            },
+        };
+        model.training_metadata.node_failures =
+            vec![failure(NodeId::root(0).child(0)), failure(dead.clone())];
+        model.training_metadata.time_budget_shortcuts.centroid_nodes = vec![dead.clone()];
+        model.training_metadata.objective_curves = vec![DepthObjectiveCurves {
+            depth: 1,
+            curves: vec![curve(0), curve(n_children)],
+        }];
+
+        let report = model.prune_dead_branches(&dataset, 1, 0.01).unwrap();
+        assert_eq!(vec![dead.clone()], report.removed_nodes);
+        assert_eq!(1, report.moved_labels.len());
+        assert_eq!(label, report.moved_labels[0].label);
+        assert_eq!(dead, report.moved_labels[0].from);
+        let metadata = &model.training_metadata;
+        assert_eq!(
+            vec![failure(NodeId::root(0).child(0))],
+            metadata.node_failures
+        );
+        assert!(metadata.time_budget_shortcuts.is_empty());
+        assert_eq!(1, metadata.objective_curves.len());
+        assert_eq!(vec![curve(0)], metadata.objective_curves[0].curves);
+
+        model.validate().unwrap();
+        assert_eq!(8, model.n_labels());
+        match &model.trees[0] {
+            TreeNode::Branch { weights, children } => {
+                assert_eq!(n_children, children.len());
+                assert_eq!((n_rows, n_children), weights.shape());
+            }
+            TreeNode::Leaf { .. } => unreachable!(),
+        }
+
+        // The moved label is still predicted for most of its examples
+        let examples = dataset
+            .feature_lists
+            .iter()
+            .zip(dataset.labels.iter())
+            .filter(|(_, labels)| labels.contains(&label))
+            .collect_vec();
+        let n_hits = examples
+            .iter()
+            .filter(|(feature_vec, _)| {
+                model
+                    .predict(feature_vec, 10)
+                    .iter()
+                    .take(3)
+                    .any(|&(predicted, _)| predicted == label)
+            })
+            .count();
+        assert!(
+            n_hits * 2 > examples.len(),
+            "{} of {}",
+            n_hits,
+            examples.len()
+        );
+    }
+}
@@ -0,0 +1,334 @@
+//! Storing weights with lower precision to shrink models.
+//!
+//! Weights can be quantized in memory to 8 bits each instead of 32, with a scale and a zero point
+//! per classifier; see [`QuantizedMat`]. They can also be saved in half precision; see
+//! [`HalfMat`]. Both are variants of [`WeightMat`], so the serialized model records how each
+//! matrix is stored, and loading needs nothing special.
+use super::{Model, TreeNode};
+use crate::data::Compression;
+use crate::mat_util::*;
+use log::info;
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::time;
+
+/// The precision of weights when saving a model.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum WeightPrecision {
+    /// 32-bit floats, as weights are trained.
+    Single,
+    /// 16-bit floats, which halve the size of weights with a small loss of accuracy. Weights are
+    /// converted back to 32 bits on the fly during prediction.
+    Half,
+}
+
+/// Options for saving a model.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SaveOptions {
+    /// The precision to save weights in, or `None` to save them as they are stored in memory.
+    /// Quantized weights are always kept as they are.
+    pub weight_precision: Option<WeightPrecision>,
+    /// The codec to compress tree files with, or `None` to leave them uncompressed. Compressed
+    /// files are detected when loading.
+    #[serde(default)]
+    pub compression: Option<Compression>,
+    /// The compression level, or `None` for the default level of the codec.
+    #[serde(default)]
+    pub compression_level: Option<u32>,
+}
+
+/// Convert the given weights to the given precision, unless they're quantized.
+fn with_precision(weights: &WeightMat, precision: WeightPrecision) -> WeightMat {
+    match (weights, precision) {
+        (WeightMat::Quantized(_), _) => weights.clone(),
+        (WeightMat::Half(mat), WeightPrecision::Single) => mat.to_single(),
+        (_, WeightPrecision::Single) => weights.clone(),
+        (_, WeightPrecision::Half) => WeightMat::Half(HalfMat::from_single(weights)),
+    }
+}
+
+impl TreeNode {
+    /// A copy of the subtree with weights converted to the given precision.
+    pub(super) fn with_weight_precision(&self, precision: WeightPrecision) -> TreeNode {
+        match self {
+            TreeNode::Branch { weights, children } => TreeNode::Branch {
+                weights: with_precision(weights, precision),
+                children: children
+                    .par_iter()
+                    .map(|child| child.with_weight_precision(precision))
+                    .collect(),
+            },
+            TreeNode::Leaf { weights, labels } => TreeNode::Leaf {
+                weights: with_precision(weights, precision),
+                labels: labels.clone(),
+            },
+        }
+    }
+
+    /// Quantize weights of all nodes in the subtree, returning the number of matrices quantized.
+    fn quantize_weights(&mut self) -> usize {
+        fn quantize(weights: &mut WeightMat) -> usize {
+            if weights.is_quantized() {
+                return 0;
+            }
+            let quantized = QuantizedMat::quantize(weights);
+            if quantized.mem_size() >= weights.mem_size() {
+                return 0;
+            }
+            *weights = WeightMat::Quantized(quantized);
+            1
+        }
+
+        match self {
+            TreeNode::Branch {
+                ref mut weights,
+                ref mut children,
+            } => {
+                quantize(weights)
+                    + children
+                        .par_iter_mut()
+                        .map(|child| child.quantize_weights())
+                        .sum::<usize>()
+            }
+            TreeNode::Leaf {
+                ref mut weights, ..
+            } => quantize(weights),
+        }
+    }
+}
+
+impl Model {
+    /// Quantize weights to the given number of bits, returning the number of weight matrices
+    /// quantized.
+    ///
+    /// Only 8 bits are supported for now. Matrices are quantized only if that makes them smaller,
+    /// so sparse matrices with few non-zero weights are left as they are, while dense matrices
+    /// shrink to about a quarter of their size. Quantized weights are slightly off, so predictions
+    /// should be checked on validation data afterwards. Returns an error for any other number of
+    /// bits, leaving the model unchanged.
+    pub fn quantize(&mut self, bits: u8) -> Result<usize, String> {
+        if bits != 8 {
+            return Err(format!(
+                "Only 8-bit quantization is supported, but got {} bits",
+                bits
+            ));
+        }
+        info!("Quantizing model weights to {} bits...", bits);
+        let start_t = time::Instant::now();
+
+        let n_quantized = self
+            .trees
+            .par_iter_mut()
+            .map(|tree| tree.quantize_weights())
+            .sum();
+
+        info!(
+            "{} weight matrices quantized; it took {:.2}s",
+            n_quantized,
+            start_t.elapsed().as_secs_f32()
+        );
+        Ok(n_quantized)
+    }
+
+    /// Returns whether any weight matrix of the model is quantized.
+    pub fn is_quantized(&self) -> bool {
+        let mut is_quantized = false;
+        for tree in &self.trees {
+            tree.visit_weights(&mut |weights| is_quantized |= weights.is_quantized());
+        }
+        is_quantized
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::eval::{self, Metric};
+    use crate::model::TREE_FILE_NAME_PREFIX;
+    use crate::test_util::{toy_dataset, toy_model};
+
+    /// The largest drop in precision@k allowed with lower-precision weights.
+    const MAX_PRECISION_DROP: f32 = 0.02;
+
+    fn weights_mem_size(model: &Model) -> usize {
+        let mut size = 0;
+        for tree in &model.trees {
+            tree.visit_weights(&mut |weights| size += weights.mem_size());
+        }
+        size
+    }
+
+    #[test]
+    fn test_quantize() {
+        let mut model = toy_model(3, 0);
+        model.densify_weights(0.);
+        let mut quantized = model.clone();
+        assert!(!quantized.is_quantized());
+        assert!(quantized.quantize(4).is_err());
+        assert!(!quantized.is_quantized());
+        assert!(quantized.quantize(8).unwrap() > 0);
+        assert!(quantized.is_quantized());
+        assert_eq!(Ok(0), quantized.quantize(8));
+
+        // Dense weights shrink close to 4x, in memory and on disk. CBOR floats take three bytes if
+        // they're zero and five otherwise, while quantized values are packed in one byte each.
+        assert!(weights_mem_size(&quantized) * 3 < weights_mem_size(&model));
+        let n_values = weights_mem_size(&model) / std::mem::size_of::<f32>();
+        let dir = std::env::temp_dir().join(format!("omikuji-quantize-{}", std::process::id()));
+        let trees_size_on_disk = |model: &Model| {
+            let _ = std::fs::remove_dir_all(&dir);
+            model.save(&dir).unwrap();
+            std::fs::read_dir(&dir)
+                .unwrap()
+                .map(|entry| entry.unwrap())
+                .filter(|entry| {
+                    let name = entry.file_name();
+                    name.to_string_lossy().starts_with(TREE_FILE_NAME_PREFIX)
+                })
+                .map(|entry| entry.metadata().unwrap().len() as usize)
+                .sum::<usize>()
+        };
+        let single_size = trees_size_on_disk(&model);
+        let quantized_size = trees_size_on_disk(&quantized);
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(quantized_size + 3 * n_values / 2 < single_size);
+        assert!(quantized_size * 2 < single_size);
+
+        let dataset = toy_dataset(50, 8, 1);
+        let true_labels = dataset.labels.to_sets();
+        for k in 1..=3 {
+            let metric = Metric::PrecisionAtK(k);
+            let precision = metric.compute(&true_labels, &eval::predict_all(&model, &dataset, 10));
+            let quantized_precision =
+                metric.compute(&true_labels, &eval::predict_all(&quantized, &dataset, 10));
+            assert!(precision - quantized_precision <= MAX_PRECISION_DROP);
+        }
+
+        // Quantized weights are kept when saving and loading
+        let mut buffer = Vec::new();
+        quantized.save_to_writer(&mut buffer).unwrap();
+        let loaded = Model::load_from_reader(buffer.as_slice()).unwrap();
+        assert!(loaded.is_quantized());
+        for feature_vec in &dataset.feature_lists {
+            assert_eq!(
+                quantized.predict(feature_vec, 10),
+                loaded.predict(feature_vec, 10)
+            );
+        }
+    }
+
+    #[test]
+    fn test_save_with_weight_precision() {
+        let model = toy_model(3, 0);
+        let dataset = toy_dataset(50, 8, 1);
+        let dir =
+            std::env::temp_dir().join(format!("omikuji-weight-precision-{}", std::process::id()));
+        let save_and_load = |weight_precision| {
+            let _ = std::fs::remove_dir_all(&dir);
+            let options = SaveOptions {
+                weight_precision: Some(weight_precision),
+                ..Default::default()
+            };
+            model.save_with_options(&dir, &options).unwrap();
+            Model::load(&dir).unwrap()
+        };
+
+        let half = save_and_load(WeightPrecision::Half);
+        let mut is_half = true;
+        for tree in &half.trees {
+            tree.visit_weights(&mut |weights| is_half &= weights.is_half());
+        }
+        assert!(is_half);
+        assert!(weights_mem_size(&half) < weights_mem_size(&model));
+
+        // Predictions with half-precision weights are close to those with single precision
+        let true_labels = dataset.labels.to_sets();
+        for feature_vec in &dataset.feature_lists {
+            let predictions = model.predict(feature_vec, 10);
+            let half_predictions = half.predict(feature_vec, 10);
+            assert_eq!(predictions.len(), half_predictions.len());
+            for (&(_, score), &(_, half_score)) in predictions.iter().zip(&half_predictions) {
+                assert!((score - half_score).abs() < 1e-2);
+            }
+        }
+        for k in 1..=3 {
+            let metric = Metric::PrecisionAtK(k);
+            let precision = metric.compute(&true_labels, &eval::predict_all(&model, &dataset, 10));
+            let half_precision =
+                metric.compute(&true_labels, &eval::predict_all(&half, &dataset, 10));
+            assert!(precision - half_precision <= MAX_PRECISION_DROP);
+        }
+
+        // Saving in single precision gives back the half-precision weights exactly
+        let single = {
+            let _ = std::fs::remove_dir_all(&dir);
+            let options = SaveOptions {
+                weight_precision: Some(WeightPrecision::Single),
+                ..Default::default()
+            };
+            half.save_with_options(&dir, &options).unwrap();
+            Model::load(&dir).unwrap()
+        };
+        assert_eq!(weights_mem_size(&model), weights_mem_size(&single));
+        for feature_vec in &dataset.feature_lists {
+            assert_eq!(
+                half.predict(feature_vec, 10),
+                single.predict(feature_vec, 10)
+            );
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_half_precision_size_on_disk() {
+        let mut model = toy_model(3, 0);
+        model.densify_weights(0.);
+        let n_values = weights_mem_size(&model) / std::mem::size_of::<f32>();
+        let dir = std::env::temp_dir().join(format!("omikuji-half-size-{}", std::process::id()));
+        let size_on_disk = |weight_precision| {
+            let _ = std::fs::remove_dir_all(&dir);
+            let options = SaveOptions {
+                weight_precision: Some(weight_precision),
+                ..Default::default()
+            };
+            model.save_with_options(&dir, &options).unwrap();
+            std::fs::read_dir(&dir)
+                .unwrap()
+                .map(|entry| entry.unwrap().metadata().unwrap().len() as usize)
+                .sum::<usize>()
+        };
+
+        // CBOR floats take at least three bytes each, while half-precision values are packed in
+        // two bytes each
+        let single_size = size_on_disk(WeightPrecision::Single);
+        let half_size = size_on_disk(WeightPrecision::Half);
+        assert!(half_size + n_values / 2 < single_size);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_save_with_compression() {
+        let model = toy_model(2, 0);
+        let dir = std::env::temp_dir().join(format!("omikuji-compression-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let options = SaveOptions {
+            compression: Some(Compression::Gzip),
+            compression_level: Some(9),
+            ..Default::default()
+        };
+        let result = model.save_with_options(&dir, &options);
+        if cfg!(feature = "gzip") {
+            result.unwrap();
+            let loaded = Model::load(&dir).unwrap();
+            for feature_vec in &toy_dataset(20, 8, 1).feature_lists {
+                assert_eq!(
+                    model.predict(feature_vec, 10),
+                    loaded.predict(feature_vec, 10)
+                );
+            }
+        } else {
+            assert_eq!(std::io::ErrorKind::Unsupported, result.unwrap_err().kind());
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,440 @@
+//! Serving many models from one process within a memory budget.
+//!
+//! A [`ModelRegistry`] keeps models by key, and evicts the least recently used ones once their
+//! total [`Model::mem_size`] exceeds the budget. With a loader directory, models missing from the
+//! registry, whether never loaded or evicted, are loaded from it when they're asked for.
+use super::predict::{PredictError, PredictOptions};
+use super::Model;
+use crate::{Index, IndexValueVec};
+use hashbrown::HashMap;
+use std::fmt;
+use std::fs::File;
+use std::hash::Hash;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+
+/// Models shared by key, within a memory budget.
+pub struct ModelRegistry<K> {
+    entries: RwLock<HashMap<K, Entry>>,
+    budget_bytes: usize,
+    loader_dir: Option<PathBuf>,
+    /// Locks of keys being loaded, so that concurrent misses on a key load the model only once.
+    loads: Mutex<HashMap<K, Arc<Mutex<()>>>>,
+    /// Ticks on every access, ordering entries by how recently they were used.
+    clock: AtomicU64,
+    counters: Counters,
+}
+
+struct Entry {
+    model: Arc<Model>,
+    mem_size: usize,
+    last_used: AtomicU64,
+}
+
+#[derive(Default)]
+struct Counters {
+    hits: AtomicU64,
+    misses: AtomicU64,
+    loads: AtomicU64,
+    load_failures: AtomicU64,
+    evictions: AtomicU64,
+}
+
+/// Counts of registry events since the registry was created.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct RegistryMetrics {
+    /// Lookups that found the model in the registry.
+    pub hits: u64,
+    /// Lookups that didn't, whether or not the model was loaded then.
+    pub misses: u64,
+    /// Models loaded from the loader directory.
+    pub loads: u64,
+    /// Models that failed to load, or were invalid.
+    pub load_failures: u64,
+    /// Models evicted to stay within the budget.
+    pub evictions: u64,
+}
+
+/// Errors from getting a model from the registry, or predicting with it.
+#[derive(Debug)]
+pub enum RegistryError {
+    /// The key isn't in the registry, which has no loader directory to load it from.
+    NotFound(String),
+    /// The key doesn't name a single entry of the loader directory, e.g., it contains a path
+    /// separator or `..`, so no model is loaded for it.
+    InvalidKey(String),
+    /// The model couldn't be loaded.
+    Io(io::Error),
+    /// The loaded model failed [`Model::validate`].
+    Invalid(String),
+    /// Prediction with the model failed.
+    Predict(PredictError),
+}
+
+impl fmt::Display for RegistryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RegistryError::NotFound(key) => write!(f, "No model for key {}", key),
+            RegistryError::InvalidKey(key) => {
+                write!(f, "Key {:?} isn't a file name in the loader directory", key)
+            }
+            RegistryError::Io(e) => write!(f, "Failed to load model: {}", e),
+            RegistryError::Invalid(message) => write!(f, "Invalid model: {}", message),
+            RegistryError::Predict(e) => write!(f, "Prediction failed: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for RegistryError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            RegistryError::Io(e) => Some(e),
+            RegistryError::Predict(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for RegistryError {
+    fn from(e: io::Error) -> Self {
+        RegistryError::Io(e)
+    }
+}
+
+impl From<PredictError> for RegistryError {
+    fn from(e: PredictError) -> Self {
+        RegistryError::Predict(e)
+    }
+}
+
+impl<K: Clone + Eq + Hash + fmt::Display> ModelRegistry<K> {
+    /// Create an empty registry keeping models within the given number of bytes.
+    pub fn new(budget_bytes: usize) -> Self {
+        Self {
+            entries: RwLock::new(HashMap::new()),
+            budget_bytes,
+            loader_dir: None,
+            loads: Mutex::new(HashMap::new()),
+            clock: AtomicU64::new(0),
+            counters: Counters::default(),
+        }
+    }
+
+    /// Load models missing from the registry from the given directory.
+    ///
+    /// The model of a key is at the path of the key formatted with [`fmt::Display`] within the
+    /// directory, which can be either a directory written by [`Model::save`] or a file written by
+    /// [`Model::save_to_writer`]. Keys that aren't plain file names, e.g., with path separators
+    /// or `..`, are rejected with [`RegistryError::InvalidKey`], so that they can't load models
+    /// from outside the directory.
+    pub fn with_loader<P: AsRef<Path>>(mut self, dir: P) -> Self {
+        self.loader_dir = Some(dir.as_ref().to_owned());
+        self
+    }
+
+    pub fn budget_bytes(&self) -> usize {
+        self.budget_bytes
+    }
+
+    /// The number of models in the registry.
+    pub fn len(&self) -> usize {
+        self.entries.read().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The total memory used by models in the registry, in bytes.
+    pub fn mem_size(&self) -> usize {
+        let entries = self.entries.read().unwrap();
+        entries.values().map(|entry| entry.mem_size).sum()
+    }
+
+    pub fn metrics(&self) -> RegistryMetrics {
+        let counters = &self.counters;
+        RegistryMetrics {
+            hits: counters.hits.load(Ordering::Relaxed),
+            misses: counters.misses.load(Ordering::Relaxed),
+            loads: counters.loads.load(Ordering::Relaxed),
+            load_failures: counters.load_failures.load(Ordering::Relaxed),
+            evictions: counters.evictions.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Add a model under the given key, returning the model it replaced, if any.
+    ///
+    /// Least recently used models are evicted until the registry is within its budget again,
+    /// except for the model just added, which is kept even if it exceeds the budget by itself.
+    /// Evicted models are dropped once they're no longer used by predictions in progress.
+    pub fn insert(&self, key: K, model: impl Into<Arc<Model>>) -> Option<Arc<Model>> {
+        let model = model.into();
+        let entry = Entry {
+            mem_size: model.mem_size(),
+            model,
+            last_used: AtomicU64::new(self.tick()),
+        };
+        let (replaced, evicted) = {
+            let mut entries = self.entries.write().unwrap();
+            let replaced = entries.insert(key.clone(), entry);
+            let evicted = self.evict(&mut entries, &key);
+            (replaced, evicted)
+        };
+        // Dropped without holding the lock, since dropping a large model takes a while
+        drop(evicted);
+        replaced.map(|entry| entry.model)
+    }
+
+    /// Remove the model under the given key, returning it if there was one.
+    pub fn remove(&self, key: &K) -> Option<Arc<Model>> {
+        let entry = self.entries.write().unwrap().remove(key);
+        entry.map(|entry| entry.model)
+    }
+
+    /// Returns the model under the given key if it's in the registry, without loading it.
+    pub fn get(&self, key: &K) -> Option<Arc<Model>> {
+        let model = self.lookup(key);
+        let counter = match model {
+            Some(_) => &self.counters.hits,
+            None => &self.counters.misses,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        model
+    }
+
+    /// Returns the model under the given key, loading it from the loader directory if it's not
+    /// in the registry.
+    ///
+    /// Concurrent calls missing the same key wait for a single load of the model.
+    pub fn get_or_load(&self, key: &K) -> Result<Arc<Model>, RegistryError> {
+        if let Some(model) = self.get(key) {
+            return Ok(model);
+        }
+        let loader_dir = self
+            .loader_dir
+            .as_ref()
+            .ok_or_else(|| RegistryError::NotFound(key.to_string()))?;
+        let model_path = model_path(loader_dir, &key.to_string())?;
+
+        let load_lock = self
+            .loads
+            .lock()
+            .unwrap()
+            .entry(key.clone())
+            .or_default()
+            .clone();
+        let _guard = load_lock.lock().unwrap();
+        // The model may have been loaded while waiting for the lock
+        if let Some(model) = self.lookup(key) {
+            return Ok(model);
+        }
+        let result = load_model(&model_path);
+        let counter = match result {
+            Ok(_) => &self.counters.loads,
+            Err(_) => &self.counters.load_failures,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        if let Ok(model) = &result {
+            self.insert(key.clone(), model.clone());
+        }
+        // Removed only once the model is in the registry, so that later misses find it there
+        self.loads.lock().unwrap().remove(key);
+        result
+    }
+
+    /// Returns a ranked list of predictions of the model under the given key, as with
+    /// [`Model::predict_with_options`], loading the model if it's not in the registry.
+    pub fn predict(
+        &self,
+        key: &K,
+        feature_vec: impl AsRef<[(Index, f32)]>,
+        options: &PredictOptions,
+    ) -> Result<IndexValueVec, RegistryError> {
+        let model = self.get_or_load(key)?;
+        Ok(model.predict_with_options(feature_vec, options)?)
+    }
+
+    fn tick(&self) -> u64 {
+        self.clock.fetch_add(1, Ordering::Relaxed)
+    }
+
+    /// Look up a model, marking it as used, without counting the lookup.
+    fn lookup(&self, key: &K) -> Option<Arc<Model>> {
+        let entries = self.entries.read().unwrap();
+        entries.get(key).map(|entry| {
+            entry.last_used.store(self.tick(), Ordering::Relaxed);
+            entry.model.clone()
+        })
+    }
+
+    /// Remove least recently used entries other than the given one until the registry is within
+    /// its budget, returning the models removed.
+    fn evict(&self, entries: &mut HashMap<K, Entry>, keep: &K) -> Vec<Arc<Model>> {
+        let mut total_size = entries.values().map(|entry| entry.mem_size).sum::<usize>();
+        let mut evicted = Vec::new();
+        while total_size > self.budget_bytes {
+            let oldest = entries
+                .iter()
+                .filter(|&(key, _)| key != keep)
+                .min_by_key(|(_, entry)| entry.last_used.load(Ordering::Relaxed))
+                .map(|(key, _)| key.clone());
+            let entry = match oldest {
+                Some(key) => entries.remove(&key).unwrap(),
+                None => break,
+            };
+            total_size -= entry.mem_size;
+            evicted.push(entry.model);
+            self.counters.evictions.fetch_add(1, Ordering::Relaxed);
+        }
+        evicted
+    }
+}
+
+/// The path of the model of the given key in the loader directory, if the key is a plain file
+/// name.
+fn model_path(loader_dir: &Path, key: &str) -> Result<PathBuf, RegistryError> {
+    let is_file_name = !key.contains(|c| c == '/' || c == '\\')
+        && matches!(
+            Path::new(key).components().collect::<Vec<_>>()[..],
+            [Component::Normal(_)]
+        );
+    if is_file_name {
+        Ok(loader_dir.join(key))
+    } else {
+        Err(RegistryError::InvalidKey(key.to_owned()))
+    }
+}
+
+/// Load and validate a model saved at the given path, either as a directory or a single file.
+fn load_model(path: &Path) -> Result<Arc<Model>, RegistryError> {
+    let model = if path.is_dir() {
+        Model::load(path)?
+    } else {
+        Model::load_from_reader(io::BufReader::new(File::open(path)?))?
+    };
+    model.validate().map_err(RegistryError::Invalid)?;
+    Ok(Arc::new(model))
+}
+
+impl<K> fmt::Debug for ModelRegistry<K> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ModelRegistry")
+            .field("budget_bytes", &self.budget_bytes)
+            .field("loader_dir", &self.loader_dir)
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{toy_dataset, toy_model};
+    use std::thread;
+
+    #[test]
+    fn test_eviction_order() {
+        let model = toy_model(2, 0);
+        let size = model.mem_size();
+        assert!(size > 0);
+        let registry = ModelRegistry::new(2 * size);
+
+        assert!(registry.insert("a", model.clone()).is_none());
+        assert!(registry.insert("b", model.clone()).is_none());
+        assert_eq!(2 * size, registry.mem_size());
+        // "a" is now used more recently than "b"
+        assert!(registry.get(&"a").is_some());
+        let evicted = Arc::downgrade(&registry.get(&"b").unwrap());
+        assert!(registry.get(&"a").is_some());
+
+        registry.insert("c", model.clone());
+        assert_eq!(2, registry.len());
+        assert!(registry.get(&"b").is_none());
+        assert!(evicted.upgrade().is_none());
+        assert!(registry.get(&"a").is_some());
+        assert!(registry.get(&"c").is_some());
+
+        // Models still in use outlive their eviction
+        let pinned = registry.get(&"a").unwrap();
+        registry.insert("d", model.clone());
+        registry.insert("e", model.clone());
+        assert!(registry.get(&"a").is_none());
+        assert_eq!(1, Arc::strong_count(&pinned));
+
+        assert_eq!(
+            RegistryMetrics {
+                hits: 6,
+                misses: 2,
+                loads: 0,
+                load_failures: 0,
+                evictions: 3,
+            },
+            registry.metrics()
+        );
+        assert!(matches!(
+            registry.get_or_load(&"b"),
+            Err(RegistryError::NotFound(_))
+        ));
+
+        // A model over budget by itself is kept, at the expense of all others
+        let registry = ModelRegistry::new(size / 2);
+        registry.insert("x", model.clone());
+        registry.insert("y", model);
+        assert_eq!(1, registry.len());
+        assert!(registry.get(&"y").is_some());
+    }
+
+    #[test]
+    fn test_concurrent_loads() {
+        let model = toy_model(2, 1);
+        let dir = std::env::temp_dir().join(format!("omikuji-registry-{}", std::process::id()));
+        model.save(dir.join("m")).unwrap();
+        let registry = ModelRegistry::new(usize::MAX).with_loader(&dir);
+
+        let loaded = thread::scope(|scope| {
+            let handles = (0..8)
+                .map(|_| scope.spawn(|| registry.get_or_load(&"m").unwrap()))
+                .collect::<Vec<_>>();
+            handles
+                .into_iter()
+                .map(|handle| handle.join().unwrap())
+                .collect::<Vec<_>>()
+        });
+        assert!(loaded.iter().all(|m| Arc::ptr_eq(&loaded[0], m)));
+        let metrics = registry.metrics();
+        assert_eq!(1, metrics.loads);
+        assert_eq!(8, metrics.hits + metrics.misses);
+
+        let options = PredictOptions::default();
+        for feature_vec in &toy_dataset(10, 8, 2).feature_lists {
+            assert_eq!(
+                model.predict_with_options(feature_vec, &options).unwrap(),
+                registry.predict(&"m", feature_vec, &options).unwrap()
+            );
+        }
+
+        match registry.get_or_load(&"missing") {
+            Err(RegistryError::Io(_)) => {}
+            result => panic!("Unexpected result {:?}", result),
+        }
+        assert_eq!(1, registry.metrics().load_failures);
+
+        // Keys can't reach outside the loader directory
+        model.save(dir.join("outside")).unwrap();
+        let absolute = dir.join("outside").display().to_string();
+        let registry = ModelRegistry::new(usize::MAX).with_loader(dir.join("inner"));
+        for key in &["../outside", "..", ".", "", "a/b", "a\\b"] {
+            match registry.get_or_load(key) {
+                Err(RegistryError::InvalidKey(_)) => {}
+                result => panic!("Unexpected result {:?} for key {:?}", result, key),
+            }
+        }
+        assert!(matches!(
+            registry.get_or_load(&absolute.as_str()),
+            Err(RegistryError::InvalidKey(_))
+        ));
+        assert_eq!(0, registry.metrics().loads);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,124 @@
+//! A self-describing summary of the input a model expects and the meaning of its scores.
+//!
+//! The schema is meant for interop layers that need to validate input or interpret scores without
+//! knowing the internals of the model.
+use super::liblinear::LossType;
+use super::projection::ProjectionParams;
+use super::Model;
+use crate::Index;
+use serde::{Deserialize, Serialize};
+
+/// How input feature vectors are transformed before the bias is appended.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum FeatureTransform {
+    /// Values are divided by the l2 norm of the input vector.
+    #[default]
+    L2Normalize,
+    /// Values are used as given, for models trained under
+    /// [`NormalizationPolicy::None`](super::train::NormalizationPolicy::None).
+    Identity,
+}
+
+/// What prediction scores mean.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ScoreSemantics {
+    /// Scores are products of classifier probabilities along tree paths, averaged over trees.
+    Probability,
+    /// Scores are exponentials of negated squared hinge losses of classifier margins along tree
+    /// paths, averaged over trees; they lie in `(0, 1]` and rank labels, but aren't calibrated.
+    Margin,
+}
+
+impl From<LossType> for ScoreSemantics {
+    fn from(loss_type: LossType) -> Self {
+        match loss_type {
+            LossType::Log => ScoreSemantics::Probability,
+            LossType::Hinge => ScoreSemantics::Margin,
+        }
+    }
+}
+
+/// Description of the input a model expects and the meaning of its scores.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ModelSchema {
+    /// The expected dimension of input feature vectors; indices must be smaller than this,
+    /// unless inputs are projected.
+    pub n_features: usize,
+    /// The random projection applied to input feature vectors before the transform, if any; the
+    /// projection accepts indices of any size, and `n_features` is its output dimension.
+    #[serde(default)]
+    pub feature_projection: Option<ProjectionParams>,
+    /// The transform applied to input feature vectors.
+    pub feature_transform: FeatureTransform,
+    /// The index of the bias feature appended to each transformed input, if any; every model
+    /// currently appends one.
+    pub bias_index: Option<Index>,
+    /// The number of distinct labels the model can predict.
+    pub n_labels: usize,
+    /// The number of trees that predictions are averaged over.
+    pub n_trees: usize,
+    /// The loss the classifiers were trained with.
+    pub loss_type: LossType,
+    /// What prediction scores mean.
+    pub score_semantics: ScoreSemantics,
+}
+
+impl Model {
+    /// Describe the input the model expects and the meaning of its scores.
+    pub fn schema(&self) -> ModelSchema {
+        ModelSchema {
+            n_features: self.n_features(),
+            feature_projection: self.feature_projection,
+            feature_transform: self.feature_transform(),
+            bias_index: Some(self.bias_index()),
+            n_labels: self.collect_sorted_labels().len(),
+            n_trees: self.n_trees(),
+            loss_type: self.settings.classifier_loss_type,
+            score_semantics: self.settings.classifier_loss_type.into(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::toy_model;
+    use crate::IndexValueVec;
+
+    #[test]
+    fn test_schema_matches_prediction() {
+        let model = toy_model(2, 0);
+        let schema = model.schema();
+        assert_eq!(model.n_features(), schema.n_features);
+        assert_eq!(8, schema.n_labels);
+        assert_eq!(2, schema.n_trees);
+        assert_eq!(ScoreSemantics::Margin, schema.score_semantics);
+
+        // Prepared vectors are transformed and get the bias as the schema says
+        let feature_vec = model.prepare_feature_vec(&[(0, 3.), (1, 4.)]);
+        assert_eq!(FeatureTransform::L2Normalize, schema.feature_transform);
+        assert_eq!(
+            vec![(0, 0.6), (1, 0.8), (schema.bias_index.unwrap(), 1.)],
+            feature_vec
+                .iter()
+                .map(|(i, &v)| (i as Index, v))
+                .collect::<IndexValueVec>()
+        );
+
+        // The schema follows changes to the model
+        assert_eq!(1, model.take_trees(&[0]).schema().n_trees);
+        let mut log_model = model.clone();
+        log_model.settings.classifier_loss_type = LossType::Log;
+        assert_eq!(
+            ScoreSemantics::Probability,
+            log_model.schema().score_semantics
+        );
+        log_model.settings.n_features += 1;
+        let log_schema = log_model.schema();
+        assert_eq!(schema.n_features + 1, log_schema.n_features);
+        assert_eq!(Some(schema.bias_index.unwrap() + 1), log_schema.bias_index);
+
+        let json = serde_json::to_string(&schema).unwrap();
+        assert_eq!(schema, serde_json::from_str(&json).unwrap());
+    }
+}
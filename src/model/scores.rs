@@ -0,0 +1,117 @@
+//! The scale of prediction scores, and conversions between classifier margins and scores.
+//!
+//! Each classifier on a tree path turns its margin `m` into a log-space score that is at most 0:
+//! `-ln(1 + exp(-m))` for log loss, and `-max(0, 1 - m)^2` for hinge loss, i.e., the squared hinge
+//! loss clipped at zero as in Parabel. A label's score in a tree is the exponential of the sum of
+//! these along its path, and the model averages scores over trees, counting labels not reached in
+//! a tree as 0. Scores of both loss types therefore lie in `[0, 1]`, although only log loss scores
+//! are probabilities.
+use super::liblinear::{self, LossType};
+use super::Model;
+
+/// The range of scores a model with the given loss type can return, as inclusive bounds.
+///
+/// Scores can reach 0 when the exponential underflows.
+pub fn range(loss_type: LossType) -> (f32, f32) {
+    match loss_type {
+        LossType::Log | LossType::Hinge => (0., 1.),
+    }
+}
+
+/// The log-space score of a single classifier with the given margin.
+///
+/// This is never positive, so sums along tree paths only decrease. It is computed as in
+/// prediction, including with the `fast-math` feature.
+pub fn log_score(margin: f32, loss_type: LossType) -> f32 {
+    liblinear::log_score(margin, loss_type)
+}
+
+/// The score of a single classifier with the given margin, on the same scale as predictions.
+pub fn from_margin(margin: f32, loss_type: LossType) -> f32 {
+    log_score(margin, loss_type).exp()
+}
+
+/// Map a prediction score of a model with the given loss type to `[0, 1]`.
+///
+/// Scores are rescaled from [`range`] to the unit interval and clamped, so that downstream code
+/// can threshold scores of any model uniformly. NaN is returned unchanged.
+pub fn to_unit_interval(score: f32, loss_type: LossType) -> f32 {
+    let (min, max) = range(loss_type);
+    ((score - min) / (max - min)).clamp(0., 1.)
+}
+
+/// Check if a score lies within the given range.
+pub(crate) fn is_within(score: f32, (min, max): (f32, f32)) -> bool {
+    min <= score && score <= max
+}
+
+impl Model {
+    /// The range of scores the model can return, as inclusive bounds.
+    ///
+    /// This depends on the loss type of the classifiers; averaging over trees keeps scores within
+    /// the range of a single tree. See [`scores`](crate::model::scores) for details, and
+    /// [`Ensemble::score_range`](crate::model::ensemble::Ensemble::score_range) for the range of
+    /// scores combined over models.
+    pub fn score_range(&self) -> (f32, f32) {
+        range(self.settings.classifier_loss_type)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::TrainHyperParam;
+    use crate::test_util::toy_dataset;
+    use assert_approx_eq::assert_approx_eq;
+
+    #[test]
+    fn test_from_margin() {
+        for &loss_type in &[LossType::Log, LossType::Hinge] {
+            let (min, max) = range(loss_type);
+            for &margin in &[-1e6, -100., -1., 0., 0.5, 1., 2., 100., 1e6] {
+                let score = from_margin(margin, loss_type);
+                assert!(is_within(score, (min, max)), "{} -> {}", margin, score);
+                assert!(log_score(margin, loss_type) <= 0.);
+            }
+        }
+
+        assert_approx_eq!(0.5, from_margin(0., LossType::Log));
+        assert_approx_eq!(1. / (1. + (-2f32).exp()), from_margin(2., LossType::Log));
+        // Hinge scores are clipped at 1 once the margin is reached
+        assert_approx_eq!((-1f32).exp(), from_margin(0., LossType::Hinge));
+        assert_eq!(1., from_margin(1., LossType::Hinge));
+        assert_eq!(1., from_margin(1e6, LossType::Hinge));
+        assert_eq!(0., from_margin(-1e6, LossType::Hinge));
+    }
+
+    #[test]
+    fn test_to_unit_interval() {
+        for &loss_type in &[LossType::Log, LossType::Hinge] {
+            assert_eq!(0., to_unit_interval(0., loss_type));
+            assert_eq!(0.25, to_unit_interval(0.25, loss_type));
+            assert_eq!(1., to_unit_interval(1., loss_type));
+            assert_eq!(1., to_unit_interval(1. + 1e-6, loss_type));
+            assert_eq!(0., to_unit_interval(-1e-6, loss_type));
+            assert!(to_unit_interval(f32::NAN, loss_type).is_nan());
+        }
+    }
+
+    #[test]
+    fn test_predictions_within_score_range() {
+        let dataset = toy_dataset(40, 8, 1);
+        for &loss_type in &[LossType::Log, LossType::Hinge] {
+            let mut hyper_param = TrainHyperParam::default();
+            hyper_param.n_trees = 3;
+            hyper_param.min_branch_size = 2;
+            hyper_param.linear.loss_type = loss_type;
+            let model = hyper_param.train(toy_dataset(60, 8, 0));
+            let range = model.score_range();
+            assert_eq!((0., 1.), range);
+            for feature_vec in &dataset.feature_lists {
+                for (_, score) in model.predict(feature_vec, 10) {
+                    assert!(is_within(score, range), "{:?}: {}", loss_type, score);
+                }
+            }
+        }
+    }
+}
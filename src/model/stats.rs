@@ -0,0 +1,205 @@
+//! Statistics of the structure and memory of a model, e.g., for capacity planning.
+use super::{liblinear, schema, Model, TreeNode};
+use crate::mat_util::*;
+use serde::{Deserialize, Serialize};
+
+/// Statistics of a group of weight matrices.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WeightStats {
+    /// The number of matrices.
+    pub n_matrices: usize,
+    /// The number of matrices stored in dense format with full-precision values, including
+    /// memory-mapped ones.
+    pub n_dense: usize,
+    /// The number of matrices stored in sparse format with full-precision values, including
+    /// memory-mapped ones.
+    pub n_sparse: usize,
+    /// The number of matrices stored with quantized or half-precision values.
+    pub n_low_precision: usize,
+    /// The number of non-zero weights.
+    pub nnz: usize,
+    /// The memory used by the weights and their indices, in bytes.
+    pub mem_size: usize,
+    /// The memory the weights would use if all matrices were stored in dense format with
+    /// full-precision values, in bytes.
+    pub dense_mem_size: usize,
+}
+
+impl WeightStats {
+    fn add(&mut self, weights: &WeightMat) {
+        let (rows, cols) = weights.shape();
+        self.n_matrices += 1;
+        match weights {
+            WeightMat::Quantized(_) | WeightMat::Half(_) => self.n_low_precision += 1,
+            _ if weights.is_dense() => self.n_dense += 1,
+            _ => self.n_sparse += 1,
+        }
+        self.nnz += weights.nnz();
+        self.mem_size += weights.mem_size();
+        self.dense_mem_size += std::mem::size_of::<f32>() * rows * cols;
+    }
+
+    fn merge(&mut self, other: &WeightStats) {
+        self.n_matrices += other.n_matrices;
+        self.n_dense += other.n_dense;
+        self.n_sparse += other.n_sparse;
+        self.n_low_precision += other.n_low_precision;
+        self.nnz += other.nnz;
+        self.mem_size += other.mem_size;
+        self.dense_mem_size += other.dense_mem_size;
+    }
+}
+
+/// Statistics of a tree.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct TreeStats {
+    /// The depth of the deepest leaf, which is 0 if the root is a leaf.
+    pub depth: usize,
+    /// The number of nodes at each depth, from the root down.
+    pub n_nodes_by_depth: Vec<usize>,
+    /// The number of leaves.
+    pub n_leaves: usize,
+    /// The number of labels in the leaves.
+    pub n_labels: usize,
+    /// The largest number of labels in a leaf.
+    pub max_leaf_size: usize,
+    /// The weights of branch nodes, which score their children.
+    pub branch_weights: WeightStats,
+    /// The weights of leaves, which score their labels.
+    pub leaf_weights: WeightStats,
+}
+
+impl TreeStats {
+    /// The mean number of labels in a leaf.
+    pub fn mean_leaf_size(&self) -> f32 {
+        self.n_labels as f32 / self.n_leaves.max(1) as f32
+    }
+
+    fn add_subtree(&mut self, node: &TreeNode, depth: usize) {
+        self.depth = self.depth.max(depth);
+        if self.n_nodes_by_depth.len() <= depth {
+            self.n_nodes_by_depth.resize(depth + 1, 0);
+        }
+        self.n_nodes_by_depth[depth] += 1;
+
+        match node {
+            TreeNode::Branch { weights, children } => {
+                self.branch_weights.add(weights);
+                for child in children {
+                    self.add_subtree(child, depth + 1);
+                }
+            }
+            TreeNode::Leaf { weights, labels } => {
+                self.leaf_weights.add(weights);
+                self.n_leaves += 1;
+                self.n_labels += labels.len();
+                self.max_leaf_size = self.max_leaf_size.max(labels.len());
+            }
+        }
+    }
+}
+
+/// Statistics of the structure and memory of a model.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ModelStats {
+    /// The expected dimension of feature vectors.
+    pub n_features: usize,
+    /// The loss the classifiers were trained with.
+    pub classifier_loss_type: liblinear::LossType,
+    /// The transform applied to feature values before prediction.
+    pub feature_transform: schema::FeatureTransform,
+    /// The number of distinct labels the model can predict.
+    pub n_labels: usize,
+    /// Statistics of each tree, in order.
+    pub trees: Vec<TreeStats>,
+    /// The weights of branch nodes in all trees.
+    pub branch_weights: WeightStats,
+    /// The weights of leaves in all trees.
+    pub leaf_weights: WeightStats,
+    /// The memory used by the weights and leaf labels of all trees, as in [`Model::mem_size`], in
+    /// bytes.
+    pub mem_size: usize,
+}
+
+impl Model {
+    /// Collect statistics of the structure and memory of the model.
+    pub fn stats(&self) -> ModelStats {
+        let trees = self
+            .trees
+            .iter()
+            .map(|tree| {
+                let mut stats = TreeStats::default();
+                stats.add_subtree(tree, 0);
+                stats
+            })
+            .collect::<Vec<_>>();
+
+        let mut branch_weights = WeightStats::default();
+        let mut leaf_weights = WeightStats::default();
+        for tree_stats in &trees {
+            branch_weights.merge(&tree_stats.branch_weights);
+            leaf_weights.merge(&tree_stats.leaf_weights);
+        }
+
+        ModelStats {
+            n_features: self.settings.n_features,
+            classifier_loss_type: self.settings.classifier_loss_type,
+            feature_transform: self.settings.feature_transform,
+            n_labels: self.n_labels(),
+            trees,
+            branch_weights,
+            leaf_weights,
+            mem_size: self.mem_size(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::toy_model;
+
+    #[test]
+    fn test_stats() {
+        let mut model = toy_model(3, 0);
+        let stats = model.stats();
+        assert_eq!(model.n_features(), stats.n_features);
+        assert_eq!(model.n_labels(), stats.n_labels);
+        assert_eq!(model.n_trees(), stats.trees.len());
+        assert_eq!(
+            model.nnz_weights(),
+            stats.branch_weights.nnz + stats.leaf_weights.nnz
+        );
+
+        let mut n_labels = 0;
+        for tree_stats in &stats.trees {
+            assert_eq!(tree_stats.depth + 1, tree_stats.n_nodes_by_depth.len());
+            assert_eq!(1, tree_stats.n_nodes_by_depth[0]);
+            assert_eq!(
+                tree_stats.n_nodes_by_depth.iter().sum::<usize>(),
+                tree_stats.branch_weights.n_matrices + tree_stats.leaf_weights.n_matrices
+            );
+            assert_eq!(tree_stats.n_leaves, tree_stats.leaf_weights.n_matrices);
+            assert!(tree_stats.n_labels >= model.n_labels());
+            assert!(tree_stats.mean_leaf_size() <= tree_stats.max_leaf_size as f32);
+            n_labels += tree_stats.n_labels;
+        }
+        assert_eq!(
+            stats.mem_size,
+            stats.branch_weights.mem_size
+                + stats.leaf_weights.mem_size
+                + n_labels * std::mem::size_of::<crate::Index>()
+        );
+
+        let json = serde_json::to_string(&stats).unwrap();
+        assert_eq!(stats, serde_json::from_str(&json).unwrap());
+
+        // Densified weights take up their dense equivalent
+        model.densify_weights(0.);
+        let stats = model.stats();
+        for weights in &[stats.branch_weights, stats.leaf_weights] {
+            assert_eq!(weights.n_matrices, weights.n_dense);
+            assert_eq!(weights.dense_mem_size, weights.mem_size);
+        }
+    }
+}
@@ -0,0 +1,263 @@
+//! Prediction restricted to a subset of labels.
+//!
+//! [`Model::predict_in_subset`] only scores allowed labels, and beam search skips subtrees without
+//! allowed labels in their leaves, so that the beam is only spent where allowed labels can be
+//! reached. To find those subtrees quickly, nodes are numbered in pre-order, where the nodes of a
+//! subtree are numbered consecutively from its root, and the model keeps an index of the leaves
+//! that each label is in and of the numbers of the children of each node, built on first use.
+use super::limits::SearchBudget;
+use super::predict::LeafTransform;
+use super::{check_shape, label_rank, sort_by_score_desc, Frontier, Model, TreeNode};
+use crate::{Index, IndexSet, IndexValueVec};
+use hashbrown::HashMap;
+
+/// The nodes of a tree, by their numbers in pre-order.
+#[derive(Clone, Debug, Default)]
+struct TreeNumbering {
+    /// The number of nodes in the subtree of each node.
+    subtree_sizes: Vec<usize>,
+    /// The position in `child_numbers` of the children of each node.
+    child_offsets: Vec<usize>,
+    /// The numbers of the children of all branches, those of each branch consecutively.
+    child_numbers: Vec<usize>,
+}
+
+impl TreeNumbering {
+    /// The number of the child at the given index of the given node.
+    fn child(&self, number: usize, index: usize) -> usize {
+        self.child_numbers[self.child_offsets[number] + index]
+    }
+}
+
+/// The leaves that each label is in, by their numbers in pre-order.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct LabelIndex {
+    /// The numbering of the nodes of each tree.
+    trees: Vec<TreeNumbering>,
+    /// The leaves of each label, as pairs of tree and node numbers.
+    label_leaves: HashMap<Index, Vec<(usize, usize)>>,
+}
+
+impl LabelIndex {
+    pub(crate) fn new(trees: &[TreeNode]) -> Self {
+        let mut index = Self::default();
+        for (tree, root) in trees.iter().enumerate() {
+            let mut numbering = TreeNumbering::default();
+            index.add_subtree(tree, root, &mut numbering);
+            index.trees.push(numbering);
+        }
+        index
+    }
+
+    fn add_subtree(&mut self, tree: usize, node: &TreeNode, numbering: &mut TreeNumbering) {
+        let number = numbering.subtree_sizes.len();
+        numbering.subtree_sizes.push(1);
+        numbering.child_offsets.push(0);
+        match node {
+            TreeNode::Branch { children, .. } => {
+                let mut child_numbers = Vec::with_capacity(children.len());
+                for child in children {
+                    child_numbers.push(numbering.subtree_sizes.len());
+                    self.add_subtree(tree, child, numbering);
+                }
+                numbering.child_offsets[number] = numbering.child_numbers.len();
+                numbering.child_numbers.extend(child_numbers);
+            }
+            TreeNode::Leaf { labels, .. } => {
+                for &label in labels {
+                    self.label_leaves
+                        .entry(label)
+                        .or_default()
+                        .push((tree, number));
+                }
+            }
+        }
+        numbering.subtree_sizes[number] = numbering.subtree_sizes.len() - number;
+    }
+
+    /// The numbers of the leaves with any of the given labels, sorted, for each tree.
+    fn allowed_leaves(&self, allowed: &IndexSet) -> Vec<Vec<usize>> {
+        let mut leaves = vec![Vec::new(); self.trees.len()];
+        for label in allowed {
+            for &(tree, number) in self.label_leaves.get(label).into_iter().flatten() {
+                leaves[tree].push(number);
+            }
+        }
+        for tree_leaves in &mut leaves {
+            tree_leaves.sort_unstable();
+            tree_leaves.dedup();
+        }
+        leaves
+    }
+}
+
+impl Model {
+    /// Returns a ranked list of predictions among the allowed labels only.
+    ///
+    /// Beam search only expands nodes with allowed labels somewhere below them, so the whole
+    /// beam is spent on reaching allowed labels, and labels that aren't allowed are dropped
+    /// before the best labels of each leaf are kept. When all labels of the model are allowed,
+    /// the result equals that of [`Self::predict`].
+    pub fn predict_in_subset(
+        &self,
+        feature_vec: impl AsRef<[(Index, f32)]>,
+        beam_size: usize,
+        allowed: &IndexSet,
+    ) -> IndexValueVec {
+        let feature_vec = self.prepare_feature_vec(feature_vec.as_ref());
+        let index = self
+            .label_index
+            .get_or_init(|| LabelIndex::new(&self.trees));
+        let allowed_leaves = index.allowed_leaves(allowed);
+        let loss_type = self.settings.classifier_loss_type;
+        let mut budget = SearchBudget::new(&self.inference_limits);
+        let beam_size = self.inference_limits.beam_size(beam_size, &mut budget.hits);
+
+        let mut tree_predictions = Vec::with_capacity(self.trees.len());
+        for (tree, root) in self.trees.iter().enumerate() {
+            let numbering = &index.trees[tree];
+            let sizes = &numbering.subtree_sizes;
+            let leaves = &allowed_leaves[tree];
+            let has_allowed_leaves = |number: usize| {
+                let i = leaves.partition_point(|&leaf| leaf < number);
+                i < leaves.len() && leaves[i] < number + sizes[number]
+            };
+            if !has_allowed_leaves(0) {
+                continue;
+            }
+
+            let mut frontier = Frontier::new(vec![(root, 0., 0)]);
+            TreeNode::expand_frontier(
+                &mut frontier,
+                loss_type,
+                &feature_vec,
+                beam_size,
+                usize::MAX,
+                |&number, i| {
+                    Some(numbering.child(number, i)).filter(|&child| has_allowed_leaves(child))
+                },
+                &mut budget,
+            )
+            .unwrap_or_else(|message| panic!("Corrupt tree: {}", message));
+
+            if budget.is_limited() {
+                sort_by_score_desc(&mut frontier.nodes);
+            }
+            let mut label_score_pairs = Vec::new();
+            for &(leaf, leaf_score, _) in &frontier.nodes {
+                let (weights, labels) = match leaf {
+                    TreeNode::Leaf { weights, labels } => (weights, labels),
+                    TreeNode::Branch { .. } => unreachable!(),
+                };
+                if !budget.take_leaf(labels.len()) {
+                    continue;
+                }
+                check_shape(weights, (feature_vec.dim(), labels.len()))
+                    .unwrap_or_else(|message| panic!("Corrupt tree: {}", message));
+                let label_scores = LeafTransform::Exp.score_leaf(
+                    weights,
+                    loss_type,
+                    &feature_vec,
+                    leaf_score,
+                    Vec::new(),
+                );
+
+                let start = label_score_pairs.len();
+                label_score_pairs.extend(
+                    labels
+                        .iter()
+                        .cloned()
+                        .zip(label_scores.iter().cloned())
+                        .filter(|(label, _)| allowed.contains(label)),
+                );
+                let leaf_label_score_pairs = &mut label_score_pairs[start..];
+                if leaf_label_score_pairs.len() > beam_size {
+                    pdqselect::select_by_key(leaf_label_score_pairs, beam_size, |&pair| {
+                        label_rank(pair)
+                    });
+                    label_score_pairs.truncate(start + beam_size);
+                }
+            }
+            tree_predictions.push(label_score_pairs);
+            budget.finish_tree();
+        }
+
+        let n_trees = budget.n_trees_averaged(self.trees.len());
+        self.average_tree_predictions(tree_predictions, n_trees, |n_labels| {
+            self.inference_limits
+                .n_labels_returned(None, n_labels, &mut budget.hits)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{toy_dataset, toy_model};
+
+    #[test]
+    fn test_label_index() {
+        fn n_nodes(node: &TreeNode) -> usize {
+            match node {
+                TreeNode::Branch { children, .. } => {
+                    1 + children.iter().map(n_nodes).sum::<usize>()
+                }
+                TreeNode::Leaf { .. } => 1,
+            }
+        }
+
+        let model = toy_model(2, 0);
+        let index = LabelIndex::new(&model.trees);
+        assert_eq!(2, index.trees.len());
+        for (root, numbering) in model.trees.iter().zip(&index.trees) {
+            let n_nodes = n_nodes(root);
+            let sizes = &numbering.subtree_sizes;
+            assert_eq!(n_nodes, sizes[0]);
+            assert_eq!(n_nodes, sizes.len());
+            // Every node but the root is the child of exactly one branch
+            assert_eq!(n_nodes - 1, numbering.child_numbers.len());
+
+            // Each child is numbered right after the subtrees of its previous siblings
+            if let TreeNode::Branch { children, .. } = root {
+                let mut expected = 1;
+                for (i, child) in children.iter().enumerate() {
+                    assert_eq!(expected, numbering.child(0, i));
+                    expected += n_nodes(child);
+                }
+            }
+        }
+        assert_eq!(model.labels().len(), index.label_leaves.len());
+        assert!(index.label_leaves.values().all(|leaves| leaves.len() == 2));
+    }
+
+    #[test]
+    fn test_predict_in_subset() {
+        let model = toy_model(2, 0);
+        let all_labels = model.labels().into_iter().collect::<IndexSet>();
+        let subset = [2, 5].iter().cloned().collect::<IndexSet>();
+        for feature_vec in &toy_dataset(20, 8, 1).feature_lists {
+            for &beam_size in &[1, 3, 10] {
+                assert_eq!(
+                    model.predict(feature_vec, beam_size),
+                    model.predict_in_subset(feature_vec, beam_size, &all_labels)
+                );
+
+                // Even a beam of 1 reaches the allowed labels
+                let predictions = model.predict_in_subset(feature_vec, beam_size, &subset);
+                assert!(!predictions.is_empty());
+                assert!(predictions.iter().all(|(label, _)| subset.contains(label)));
+            }
+
+            // With a beam wide enough to reach every leaf, scores are those of all labels
+            let expected = model
+                .predict(feature_vec, 100)
+                .into_iter()
+                .filter(|(label, _)| subset.contains(label))
+                .collect::<Vec<_>>();
+            assert_eq!(expected, model.predict_in_subset(feature_vec, 100, &subset));
+            assert!(model
+                .predict_in_subset(feature_vec, 10, &IndexSet::new())
+                .is_empty());
+        }
+    }
+}
@@ -0,0 +1,442 @@
+//! A canonical, line-oriented text format for small models, e.g., test fixtures under review.
+//!
+//! The format starts with header lines of settings and inference limits, as `<key> <value>`,
+//! followed by one line per node in pre-order:
+//!
+//! ```text
+//! <branch|leaf> <path> <rows>x<cols> <sparse|dense> [labels=<label>,...] <row>:<col>:<bits>...
+//! ```
+//!
+//! The path is the index of the tree followed by the indices of the children taken from the
+//! root, separated by dots. Weights are listed in row-major order as triplets, with values as the
+//! 8 hexadecimal digits of their IEEE 754 bit patterns, so that they round-trip exactly. Sparse
+//! matrices list their stored values, and dense matrices their values with non-zero bit patterns.
+//!
+//! The same model is always written the same way. Training metadata is left out, and models with
+//! label thresholds, a feature projection or an input profile can't be written.
+use super::limits::{InferenceLimits, LimitPolicy};
+use super::{liblinear, schema, Model, Settings, TreeNode};
+use crate::mat_util::*;
+use crate::Index;
+use itertools::Itertools;
+use std::io::{self, BufRead, Write};
+use std::iter::Peekable;
+
+/// The first line of the format, bumped on incompatible changes.
+const FORMAT_HEADER: &str = "omikuji-canonical-text v1";
+
+impl Model {
+    /// Write the model in the canonical text format.
+    pub fn to_canonical_text<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        if self.label_thresholds.is_some()
+            || self.feature_projection.is_some()
+            || self.input_profile.is_some()
+            || self.label_graph.is_some()
+            || self.original_labels.is_some()
+            || self.label_names.is_some()
+            || self.used_features.is_some()
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Label thresholds, feature projections, input profiles, label graphs, original \
+                 labels, label names and used features can't be written as canonical text",
+            ));
+        }
+        let mut has_low_precision = false;
+        for tree in &self.trees {
+            tree.visit_weights(&mut |weights| {
+                has_low_precision |= weights.is_quantized() || weights.is_half()
+            });
+        }
+        if has_low_precision {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Quantized or half-precision weights can't be written as canonical text",
+            ));
+        }
+
+        writeln!(writer, "{}", FORMAT_HEADER)?;
+        writeln!(writer, "n_features {}", self.settings.n_features)?;
+        writeln!(
+            writer,
+            "loss_type {}",
+            match self.settings.classifier_loss_type {
+                liblinear::LossType::Log => "log",
+                liblinear::LossType::Hinge => "hinge",
+            }
+        )?;
+        writeln!(
+            writer,
+            "feature_transform {}",
+            match self.settings.feature_transform {
+                schema::FeatureTransform::L2Normalize => "l2_normalize",
+                schema::FeatureTransform::Identity => "identity",
+            }
+        )?;
+        let limits = &self.inference_limits;
+        for (key, value) in [
+            ("max_beam", limits.max_beam),
+            ("max_labels_returned", limits.max_labels_returned),
+            ("max_nodes_visited", limits.max_nodes_visited),
+            ("max_leaf_labels_scored", limits.max_leaf_labels_scored),
+        ] {
+            match value {
+                Some(value) => writeln!(writer, "{} {}", key, value)?,
+                None => writeln!(writer, "{} none", key)?,
+            }
+        }
+        writeln!(
+            writer,
+            "limit_policy {}",
+            match limits.policy {
+                LimitPolicy::Truncate => "truncate",
+                LimitPolicy::Error => "error",
+            }
+        )?;
+        writeln!(writer, "n_trees {}", self.trees.len())?;
+
+        for (tree, root) in self.trees.iter().enumerate() {
+            write_node(&mut writer, root, &mut vec![tree])?;
+        }
+        writer.flush()
+    }
+
+    /// Read a model written by [`Self::to_canonical_text`].
+    pub fn from_canonical_text<R: BufRead>(reader: R) -> io::Result<Self> {
+        let mut lines = reader
+            .lines()
+            .enumerate()
+            .map(|(i, line)| line.map(|line| (i + 1, line)))
+            .collect::<io::Result<Vec<_>>>()?
+            .into_iter()
+            .peekable();
+
+        let (line_no, header) = lines
+            .next()
+            .ok_or_else(|| invalid(1, "Missing format header"))?;
+        if header != FORMAT_HEADER {
+            return Err(invalid(line_no, "Unexpected format header"));
+        }
+        let mut field = |key: &str| -> io::Result<(usize, String)> {
+            let (line_no, line) = lines
+                .next()
+                .ok_or_else(|| invalid(0, &format!("Missing {}", key)))?;
+            match line.split_once(' ') {
+                Some((line_key, value)) if line_key == key => Ok((line_no, value.to_owned())),
+                _ => Err(invalid(line_no, &format!("Expected {}", key))),
+            }
+        };
+
+        let n_features = parse_field(field("n_features")?)?;
+        let classifier_loss_type = match field("loss_type")? {
+            (_, value) if value == "log" => liblinear::LossType::Log,
+            (_, value) if value == "hinge" => liblinear::LossType::Hinge,
+            (line_no, _) => return Err(invalid(line_no, "Unknown loss type")),
+        };
+        let feature_transform = match field("feature_transform")? {
+            (_, value) if value == "l2_normalize" => schema::FeatureTransform::L2Normalize,
+            (_, value) if value == "identity" => schema::FeatureTransform::Identity,
+            (line_no, _) => return Err(invalid(line_no, "Unknown feature transform")),
+        };
+        let mut limit = |key: &str| -> io::Result<Option<usize>> {
+            match field(key)? {
+                (_, value) if value == "none" => Ok(None),
+                line => parse_field(line).map(Some),
+            }
+        };
+        let inference_limits = InferenceLimits {
+            max_beam: limit("max_beam")?,
+            max_labels_returned: limit("max_labels_returned")?,
+            max_nodes_visited: limit("max_nodes_visited")?,
+            max_leaf_labels_scored: limit("max_leaf_labels_scored")?,
+            policy: match field("limit_policy")? {
+                (_, value) if value == "truncate" => LimitPolicy::Truncate,
+                (_, value) if value == "error" => LimitPolicy::Error,
+                (line_no, _) => return Err(invalid(line_no, "Unknown limit policy")),
+            },
+        };
+        let n_trees: usize = parse_field(field("n_trees")?)?;
+
+        let settings = Settings {
+            n_features,
+            classifier_loss_type,
+            feature_transform,
+            n_used_features: None,
+        };
+        let trees = (0..n_trees)
+            .map(|tree| read_node(&mut lines, &[tree]))
+            .collect::<io::Result<Vec<_>>>()?;
+        if let Some((line_no, _)) = lines.next() {
+            return Err(invalid(line_no, "Unexpected line after the last tree"));
+        }
+        if let Some(tree) = trees.iter().position(|tree| !tree.is_valid(settings)) {
+            return Err(invalid(0, &format!("Tree {} is invalid", tree)));
+        }
+
+        Ok(Model {
+            trees,
+            settings,
+            label_thresholds: None,
+            training_metadata: Default::default(),
+            inference_limits,
+            feature_projection: None,
+            input_profile: None,
+            label_graph: None,
+            original_labels: None,
+            label_names: None,
+            used_features: None,
+            sorted_labels: Default::default(),
+            label_index: Default::default(),
+        })
+    }
+}
+
+fn write_node<W: Write>(writer: &mut W, node: &TreeNode, path: &mut Vec<usize>) -> io::Result<()> {
+    let (kind, weights) = match node {
+        TreeNode::Branch { weights, .. } => ("branch", weights),
+        TreeNode::Leaf { weights, .. } => ("leaf", weights),
+    };
+    let (n_rows, n_cols) = weights.shape();
+    write!(
+        writer,
+        "{} {} {}x{} {}",
+        kind,
+        path.iter().join("."),
+        n_rows,
+        n_cols,
+        if weights.is_dense() {
+            "dense"
+        } else {
+            "sparse"
+        }
+    )?;
+    if let TreeNode::Leaf { labels, .. } = node {
+        write!(writer, " labels={}", labels.iter().join(","))?;
+    }
+    let mapped_dense;
+    let dense = match weights {
+        WeightMat::Dense(mat) => Some(mat),
+        WeightMat::Mapped(mat) if mat.is_dense() => {
+            mapped_dense = mat.to_dense();
+            Some(&mapped_dense)
+        }
+        _ => None,
+    };
+    let entries: Box<dyn Iterator<Item = (usize, usize, f32)>> = match dense {
+        // Unlike `nonzero_entries`, negative zeros are kept
+        Some(mat) => Box::new(
+            mat.indexed_iter()
+                .filter(|(_, v)| v.to_bits() != 0)
+                .map(|((row, col), &v)| (row, col, v)),
+        ),
+        None => weights.nonzero_entries(),
+    };
+    for (row, col, value) in entries {
+        write!(writer, " {}:{}:{:08x}", row, col, value.to_bits())?;
+    }
+    writeln!(writer)?;
+
+    if let TreeNode::Branch { children, .. } = node {
+        for (i, child) in children.iter().enumerate() {
+            path.push(i);
+            write_node(writer, child, path)?;
+            path.pop();
+        }
+    }
+    Ok(())
+}
+
+fn read_node<I: Iterator<Item = (usize, String)>>(
+    lines: &mut Peekable<I>,
+    path: &[usize],
+) -> io::Result<TreeNode> {
+    let (line_no, line) = lines
+        .next()
+        .ok_or_else(|| invalid(0, "Missing node line"))?;
+    let mut tokens = line.split(' ');
+    let mut token = |what: &str| {
+        tokens
+            .next()
+            .ok_or_else(|| invalid(line_no, &format!("Missing {}", what)))
+    };
+
+    let kind = token("node kind")?;
+    if token("path")? != path.iter().join(".") {
+        return Err(invalid(
+            line_no,
+            &format!("Expected node {}", path.iter().join(".")),
+        ));
+    }
+    let (n_rows, n_cols) = token("shape")?
+        .split_once('x')
+        .and_then(|(rows, cols)| Some((rows.parse().ok()?, cols.parse().ok()?)))
+        .ok_or_else(|| invalid(line_no, "Invalid shape"))?;
+    let is_dense = match token("storage")? {
+        "dense" => true,
+        "sparse" => false,
+        _ => return Err(invalid(line_no, "Unknown storage kind")),
+    };
+    let labels = if kind == "leaf" {
+        let labels = token("labels")?
+            .strip_prefix("labels=")
+            .ok_or_else(|| invalid(line_no, "Expected labels"))?;
+        Some(
+            labels
+                .split(',')
+                .filter(|label| !label.is_empty())
+                .map(|label| label.parse::<Index>())
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(|_| invalid(line_no, "Invalid label"))?,
+        )
+    } else if kind == "branch" {
+        None
+    } else {
+        return Err(invalid(line_no, "Unknown node kind"));
+    };
+
+    let entries = tokens
+        .map(|triplet| {
+            parse_triplet(triplet)
+                .filter(|&(row, col, _)| row < n_rows && col < n_cols)
+                .ok_or_else(|| invalid(line_no, &format!("Invalid weight {}", triplet)))
+        })
+        .collect::<io::Result<Vec<_>>>()?;
+    if entries
+        .iter()
+        .tuple_windows()
+        .any(|(&(r1, c1, _), &(r2, c2, _))| (r1, c1) >= (r2, c2))
+    {
+        return Err(invalid(line_no, "Weights out of order"));
+    }
+    let weights = if is_dense {
+        let mut mat = DenseMat::zeros((n_rows, n_cols));
+        for (row, col, value) in entries {
+            mat[[row, col]] = value;
+        }
+        WeightMat::Dense(mat)
+    } else {
+        let mut mat = LilMat::new((n_rows, n_cols));
+        for (row, col, value) in entries {
+            mat.append_value(row, col, value);
+        }
+        WeightMat::Sparse(mat)
+    };
+
+    if let Some(labels) = labels {
+        return Ok(TreeNode::Leaf { weights, labels });
+    }
+    let mut children = Vec::new();
+    let mut child_path = path.to_vec();
+    child_path.push(0);
+    // Children come right after their parent, so the next node is a child if its path extends
+    // the path of the parent
+    while let Some((_, next_line)) = lines.peek() {
+        let next_path = next_line.split(' ').nth(1).unwrap_or_default();
+        if !next_path.starts_with(&format!("{}.", path.iter().join("."))) {
+            break;
+        }
+        *child_path.last_mut().unwrap() = children.len();
+        children.push(read_node(lines, &child_path)?);
+    }
+    if children.is_empty() {
+        return Err(invalid(line_no, "Branch has no children"));
+    }
+    Ok(TreeNode::Branch { weights, children })
+}
+
+fn parse_triplet(triplet: &str) -> Option<(usize, usize, f32)> {
+    let mut parts = triplet.split(':');
+    let row = parts.next()?.parse().ok()?;
+    let col = parts.next()?.parse().ok()?;
+    let bits = parts.next()?;
+    if bits.len() != 8 || parts.next().is_some() {
+        return None;
+    }
+    let value = f32::from_bits(u32::from_str_radix(bits, 16).ok()?);
+    Some((row, col, value))
+}
+
+fn parse_field<T: std::str::FromStr>((line_no, value): (usize, String)) -> io::Result<T> {
+    value
+        .parse()
+        .map_err(|_| invalid(line_no, &format!("Invalid value {}", value)))
+}
+
+/// An error for invalid data on the given 1-based line, or in the model as a whole for line 0.
+fn invalid(line_no: usize, message: &str) -> io::Error {
+    let message = if line_no > 0 {
+        format!("Line {}: {}", line_no, message)
+    } else {
+        message.to_owned()
+    };
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::toy_model;
+
+    fn to_text(model: &Model) -> String {
+        let mut buffer = Vec::new();
+        model.to_canonical_text(&mut buffer).unwrap();
+        String::from_utf8(buffer).unwrap()
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let sparse = toy_model(2, 0);
+        let mut dense = sparse.clone();
+        dense.densify_weights(0.);
+        let mut limited = sparse.clone();
+        limited.inference_limits.max_beam = Some(3);
+        limited.inference_limits.policy = LimitPolicy::Error;
+
+        for model in [&sparse, &dense, &limited] {
+            let text = to_text(model);
+            let loaded = Model::from_canonical_text(text.as_bytes()).unwrap();
+            assert_eq!(model.content_hash(), loaded.content_hash());
+            assert_eq!(text, to_text(&loaded));
+            let storage = |model: &Model| {
+                let mut is_dense = Vec::new();
+                for tree in &model.trees {
+                    tree.visit_weights(&mut |weights| is_dense.push(weights.is_dense()));
+                }
+                is_dense
+            };
+            assert_eq!(storage(model), storage(&loaded));
+        }
+        assert!(to_text(&dense).contains(" dense "));
+        assert!(to_text(&sparse).contains(" sparse "));
+        assert!(to_text(&sparse).starts_with("omikuji-canonical-text v1\nn_features "));
+    }
+
+    #[test]
+    fn test_deterministic() {
+        let model = toy_model(2, 0);
+        let text = to_text(&model);
+        assert_eq!(text, to_text(&model));
+
+        let mut buffer = Vec::new();
+        model.save_to_writer(&mut buffer).unwrap();
+        let reloaded = Model::load_from_reader(&buffer[..]).unwrap();
+        assert_eq!(text, to_text(&reloaded));
+    }
+
+    #[test]
+    fn test_invalid_text() {
+        let text = to_text(&toy_model(1, 0));
+        let lines = text.lines().collect::<Vec<_>>();
+        let corrupt = |i: usize, line: &str| {
+            let mut lines = lines.clone();
+            lines[i] = line;
+            Model::from_canonical_text(lines.join("\n").as_bytes())
+                .unwrap_err()
+                .to_string()
+        };
+        assert_eq!("Line 1: Unexpected format header", corrupt(0, "omikuji"));
+        assert_eq!("Line 3: Unknown loss type", corrupt(2, "loss_type l1"));
+        assert_eq!("Line 11: Expected node 0", corrupt(10, "leaf 1 0x0 sparse"));
+        assert!(Model::from_canonical_text(lines[..10].join("\n").as_bytes()).is_err());
+    }
+}
@@ -0,0 +1,323 @@
+//! Per-label decision thresholds for turning ranked predictions into label sets.
+use super::{eval, Model};
+use crate::{DataSet, Index, IndexSet, IndexValueVec};
+use itertools::{izip, Itertools};
+use log::info;
+use ordered_float::NotNan;
+use serde::{Deserialize, Serialize};
+use std::cmp::Reverse;
+use std::time;
+
+/// Threshold used for labels that never appear in validation predictions.
+pub const DEFAULT_LABEL_THRESHOLD: f32 = 0.5;
+
+/// What per-label thresholds are chosen to optimize.
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum ThresholdTarget {
+    /// Maximize the F1 score of each label.
+    MaxF1,
+    /// Maximize the recall of each label subject to its precision being at least the given value.
+    Precision(f32),
+}
+
+impl ThresholdTarget {
+    /// Check if the target is valid.
+    pub fn validate(&self) -> Result<(), String> {
+        match *self {
+            ThresholdTarget::Precision(p) if !(p > 0. && p <= 1.) => {
+                Err(format!("target precision must be in (0, 1], but is {}", p))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Decision thresholds indexed by label.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct LabelThresholds {
+    thresholds: Vec<f32>,
+}
+
+impl LabelThresholds {
+    /// The threshold a label's score must exceed for the label to be predicted.
+    pub fn get(&self, label: Index) -> f32 {
+        self.thresholds
+            .get(label as usize)
+            .cloned()
+            .unwrap_or(DEFAULT_LABEL_THRESHOLD)
+    }
+
+    /// The thresholds of labels `0, 1, ...`; labels beyond them have the default threshold.
+    pub(crate) fn as_slice(&self) -> &[f32] {
+        &self.thresholds
+    }
+
+    /// The thresholds of the labels at the given indices, as labels `0, 1, ...`.
+    pub(crate) fn select(&self, labels: &[Index]) -> Self {
+        Self {
+            thresholds: labels.iter().map(|&label| self.get(label)).collect(),
+        }
+    }
+
+    /// Fit thresholds from ranked predictions and true labels of validation examples.
+    fn fit(
+        true_labels: &[IndexSet],
+        predicted_labels: &[IndexValueVec],
+        target: ThresholdTarget,
+    ) -> Self {
+        assert_eq!(true_labels.len(), predicted_labels.len());
+        let n_labels = true_labels
+            .iter()
+            .flatten()
+            .chain(predicted_labels.iter().flatten().map(|(label, _)| label))
+            .map(|&label| label as usize + 1)
+            .max()
+            .unwrap_or(0);
+
+        let mut n_positives = vec![0usize; n_labels];
+        for &label in true_labels.iter().flatten() {
+            n_positives[label as usize] += 1;
+        }
+        let mut label_to_scored = vec![Vec::<(f32, bool)>::new(); n_labels];
+        for (truth, predictions) in izip!(true_labels, predicted_labels) {
+            for &(label, score) in predictions {
+                label_to_scored[label as usize].push((score, truth.contains(&label)));
+            }
+        }
+
+        let thresholds = label_to_scored
+            .into_iter()
+            .zip(n_positives)
+            .map(|(mut scored, n_positives)| fit_threshold(&mut scored, n_positives, target))
+            .collect();
+        Self { thresholds }
+    }
+}
+
+/// Sweep observed scores of a label from high to low and pick the threshold optimizing the target.
+///
+/// Positive examples for which the label was never predicted still count as false negatives. The
+/// returned threshold lies halfway between the lowest accepted score and the next lower one, or
+/// equals the highest score if no score should be accepted.
+fn fit_threshold(scored: &mut [(f32, bool)], n_positives: usize, target: ThresholdTarget) -> f32 {
+    if scored.is_empty() {
+        return DEFAULT_LABEL_THRESHOLD;
+    }
+    scored.sort_unstable_by_key(|&(score, _)| Reverse(NotNan::new(score).unwrap()));
+
+    let mut best: Option<(usize, f32)> = None; // Number of scores accepted, objective
+    let mut n_true_positives = 0;
+    for (i, &(score, is_positive)) in scored.iter().enumerate() {
+        if is_positive {
+            n_true_positives += 1;
+        }
+        // Only consider cutting between distinct scores
+        if scored.get(i + 1).map_or(false, |&(next, _)| next == score) {
+            continue;
+        }
+
+        let n_accepted = i + 1;
+        let objective = match target {
+            ThresholdTarget::MaxF1 => {
+                2. * n_true_positives as f32 / (n_accepted + n_positives) as f32
+            }
+            ThresholdTarget::Precision(min_precision) => {
+                if n_true_positives as f32 / n_accepted as f32 >= min_precision {
+                    n_true_positives as f32 // Maximizing recall
+                } else {
+                    continue;
+                }
+            }
+        };
+        if objective > 0. && best.map_or(true, |(_, best_objective)| objective > best_objective) {
+            best = Some((n_accepted, objective));
+        }
+    }
+
+    match best {
+        Some((n_accepted, _)) => {
+            let lowest_accepted = scored[n_accepted - 1].0;
+            let next_lower = scored.get(n_accepted).map_or(0., |&(score, _)| score);
+            (lowest_accepted + next_lower) / 2.
+        }
+        None => scored[0].0,
+    }
+}
+
+impl Model {
+    /// Fit per-label decision thresholds on validation data, to be used by
+    /// [`Self::predict_binary`].
+    ///
+    /// Thresholds are stored in the model and serialized with it. Labels that never appear in
+    /// the validation predictions get [`DEFAULT_LABEL_THRESHOLD`].
+    pub fn fit_label_thresholds(
+        &mut self,
+        validation: &DataSet,
+        beam_size: usize,
+        target: ThresholdTarget,
+    ) {
+        target.validate().unwrap();
+        info!("Fitting label thresholds for {:?}", target);
+        let start_t = time::Instant::now();
+
+        let predictions = eval::predict_all(self, validation, beam_size);
+        self.label_thresholds = Some(LabelThresholds::fit(
+            &validation.labels.to_sets(),
+            &predictions,
+            target,
+        ));
+
+        info!(
+            "Fitted label thresholds; it took {:.2}s",
+            start_t.elapsed().as_secs_f32()
+        );
+    }
+
+    /// The fitted per-label decision thresholds, if any.
+    pub fn label_thresholds(&self) -> Option<&LabelThresholds> {
+        self.label_thresholds.as_ref()
+    }
+
+    /// Returns labels whose scores exceed their thresholds, ordered by decreasing score.
+    ///
+    /// Panics if thresholds have not been fitted with [`Self::fit_label_thresholds`].
+    pub fn predict_binary(
+        &self,
+        feature_vec: impl AsRef<[(Index, f32)]>,
+        beam_size: usize,
+    ) -> Vec<Index> {
+        let thresholds = self
+            .label_thresholds
+            .as_ref()
+            .expect("Label thresholds have not been fitted");
+        self.predict(feature_vec, beam_size)
+            .into_iter()
+            .filter(|&(label, score)| score > thresholds.get(label))
+            .map(|(label, _)| label)
+            .collect_vec()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mat_util::{DenseMat, WeightMat};
+    use crate::model::liblinear::LossType;
+    use crate::model::{Settings, TreeNode};
+    use crate::test_util::{toy_dataset, toy_model};
+    use assert_approx_eq::assert_approx_eq;
+
+    #[test]
+    fn test_fit_recovers_low_and_high_thresholds() {
+        // Label 0 is always scored low, but positives are still ranked above negatives; label 1 is
+        // always scored high, with negatives close behind positives
+        let mut true_labels = Vec::new();
+        let mut predictions = Vec::new();
+        for i in 0..20 {
+            let is_positive = i % 2 == 0;
+            let offset = i as f32 / 1000.;
+            let mut labels = IndexSet::new();
+            if is_positive {
+                labels.insert(0);
+                labels.insert(1);
+            }
+            true_labels.push(labels);
+            predictions.push(if is_positive {
+                vec![(1, 0.9 + offset), (0, 0.2 + offset)]
+            } else {
+                vec![(1, 0.7 + offset), (0, 0.05 + offset)]
+            });
+        }
+
+        for &target in &[ThresholdTarget::MaxF1, ThresholdTarget::Precision(1.)] {
+            let thresholds = LabelThresholds::fit(&true_labels, &predictions, target);
+            assert!(thresholds.get(0) > 0.05 + 0.019 && thresholds.get(0) < 0.2);
+            assert!(thresholds.get(1) > 0.7 + 0.019 && thresholds.get(1) < 0.9);
+            assert_eq!(DEFAULT_LABEL_THRESHOLD, thresholds.get(2));
+        }
+    }
+
+    #[test]
+    fn test_fit_threshold() {
+        let target = ThresholdTarget::Precision(0.6);
+        // Precision at the cut after each score: 1, 1/2, 2/3, 3/4, 3/5
+        let mut scored = vec![
+            (0.9, true),
+            (0.8, false),
+            (0.7, true),
+            (0.6, true),
+            (0.5, false),
+        ];
+        assert_approx_eq!(0.55, fit_threshold(&mut scored, 3, target));
+        // With ties, cuts can only happen between distinct scores
+        let mut scored = vec![(0.9, true), (0.5, true), (0.5, false), (0.5, false)];
+        assert_approx_eq!(0.7, fit_threshold(&mut scored, 2, target));
+        // No true positives at all
+        let mut scored = vec![(0.3, false), (0.2, false)];
+        assert_eq!(0.3, fit_threshold(&mut scored, 1, ThresholdTarget::MaxF1));
+    }
+
+    #[test]
+    fn test_predict_binary() {
+        // A single leaf with hinge loss, whose labels have margins 0.6, 0.8 and 0.5 for the input
+        // below, the last through the bias feature, and so scores of exp(-(1 - margin)^2): about
+        // 0.852, 0.961 and 0.779
+        let mut model = Model {
+            trees: vec![TreeNode::Leaf {
+                weights: WeightMat::Dense(
+                    DenseMat::from_shape_vec((3, 3), vec![1., 0., 0., 0., 1., 0., 0., 0., 0.5])
+                        .unwrap(),
+                ),
+                labels: vec![0, 1, 2],
+            }],
+            settings: Settings {
+                n_features: 2,
+                classifier_loss_type: LossType::Hinge,
+                feature_transform: Default::default(),
+                n_used_features: None,
+            },
+            label_thresholds: None,
+            training_metadata: Default::default(),
+            inference_limits: Default::default(),
+            feature_projection: None,
+            input_profile: None,
+            label_graph: None,
+            original_labels: None,
+            label_names: None,
+            used_features: None,
+            sorted_labels: Default::default(),
+            label_index: Default::default(),
+        };
+        let feature_vec = [(0, 3.), (1, 4.)];
+        let with_thresholds = |model: &mut Model, thresholds: Vec<f32>| {
+            model.label_thresholds = Some(LabelThresholds { thresholds });
+            model.predict_binary(&feature_vec, 10)
+        };
+
+        // Label 2 has no threshold of its own, so it has the default of 0.5
+        assert_eq!(vec![1, 0, 2], with_thresholds(&mut model, vec![0.8, 0.95]));
+        assert_eq!(vec![0, 2], with_thresholds(&mut model, vec![0.8, 0.97]));
+        assert_eq!(vec![1], with_thresholds(&mut model, vec![0.9, 0.95, 0.8]));
+        assert!(with_thresholds(&mut model, vec![1.; 3]).is_empty());
+    }
+
+    #[test]
+    fn test_thresholds_serialized() {
+        let mut model = toy_model(1, 0);
+        let validation = toy_dataset(40, 8, 1);
+        model.fit_label_thresholds(&validation, 5, ThresholdTarget::MaxF1);
+        let thresholds = model.label_thresholds().unwrap();
+
+        // Thresholds are serialized with the model
+        let dir = std::env::temp_dir().join(format!("omikuji-thresholds-{}", std::process::id()));
+        model.save(&dir).unwrap();
+        let loaded = Model::load(&dir).unwrap();
+        assert_eq!(Some(thresholds), loaded.label_thresholds());
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let mut buf = Vec::new();
+        model.save_to_writer(&mut buf).unwrap();
+        let loaded = Model::load_from_reader(std::io::Cursor::new(&buf)).unwrap();
+        assert_eq!(Some(thresholds), loaded.label_thresholds());
+    }
+}
@@ -0,0 +1,141 @@
+//! Tracing the nodes that beam search visits.
+//!
+//! Tree nodes have no ids, so each node is identified by its path of child indices from the root,
+//! e.g., `[2, 0]` for the first child of the third child of the root.
+use super::{limits, predict, Frontier, Model, TreeNode};
+use crate::{Index, IndexValueVec};
+use serde::{Deserialize, Serialize};
+
+/// A node kept in the beam, with its path score.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct TraceNode {
+    /// The index of each child on the way from the root to the node.
+    pub path: Vec<usize>,
+    /// The sum of the scores of the classifiers on the way, as in [`liblinear`]; for log loss
+    /// models, this is the log probability of reaching the node.
+    ///
+    /// [`liblinear`]: super::liblinear
+    pub score: f32,
+}
+
+impl TraceNode {
+    /// The depth of the node, which is 0 for the root.
+    pub fn depth(&self) -> usize {
+        self.path.len()
+    }
+}
+
+/// The nodes that beam search kept in a tree.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct TreeTrace {
+    /// The nodes kept in the beam after expanding each level, in beam order. Leaves reached at a
+    /// level stay in the beam, so they show up again at the levels below.
+    pub levels: Vec<Vec<TraceNode>>,
+    /// The leaves whose labels were scored, in beam order.
+    pub leaves: Vec<TraceNode>,
+}
+
+/// The nodes on the given frontier, in order.
+fn trace_nodes(frontier: &Frontier<'_, Vec<usize>>) -> Vec<TraceNode> {
+    frontier
+        .nodes
+        .iter()
+        .map(|(_, score, path)| TraceNode {
+            path: path.clone(),
+            score: *score,
+        })
+        .collect()
+}
+
+impl Model {
+    /// Like [`Self::predict`], but also returns the nodes that beam search kept in each tree, in
+    /// the order of the trees.
+    ///
+    /// This is meant for debugging, e.g., to see at which depth a label is dropped from the beam,
+    /// and is slower than prediction.
+    pub fn predict_with_trace(
+        &self,
+        feature_vec: impl AsRef<[(Index, f32)]>,
+        beam_size: usize,
+    ) -> (IndexValueVec, Vec<TreeTrace>) {
+        let feature_vec = self.prepare_feature_vec(feature_vec.as_ref());
+        let loss_type = self.settings.classifier_loss_type;
+        let mut budget = limits::SearchBudget::new(&self.inference_limits);
+        let beam_size = self.inference_limits.beam_size(beam_size, &mut budget.hits);
+
+        let mut tree_predictions = Vec::with_capacity(self.trees.len());
+        let mut traces = Vec::with_capacity(self.trees.len());
+        for root in &self.trees {
+            let mut trace = TreeTrace::default();
+            let mut frontier = Frontier::new(vec![(root, 0., Vec::new())]);
+            while frontier.nodes.iter().any(|(node, _, _)| !node.is_leaf()) {
+                TreeNode::expand_frontier(
+                    &mut frontier,
+                    loss_type,
+                    &feature_vec,
+                    beam_size,
+                    1,
+                    |path: &Vec<usize>, i| Some([&path[..], &[i]].concat()),
+                    &mut budget,
+                )
+                .unwrap_or_else(|error| panic!("Corrupt tree: {}", error));
+                trace.levels.push(trace_nodes(&frontier));
+            }
+
+            let mut label_score_pairs = Vec::new();
+            trace.leaves = trace_nodes(&frontier);
+            TreeNode::score_leaves(
+                &mut frontier,
+                loss_type,
+                &feature_vec,
+                beam_size,
+                predict::LeafTransform::Exp,
+                &mut budget,
+                &mut label_score_pairs,
+            )
+            .unwrap_or_else(|error| panic!("Corrupt tree: {}", error));
+            tree_predictions.push(label_score_pairs);
+            traces.push(trace);
+            budget.finish_tree();
+        }
+
+        let n_trees = budget.n_trees_averaged(self.trees.len());
+        let predictions = self.average_tree_predictions(tree_predictions, n_trees, |n_labels| {
+            self.inference_limits
+                .n_labels_returned(None, n_labels, &mut budget.hits)
+        });
+        (predictions, traces)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{toy_dataset, toy_model};
+
+    #[test]
+    fn test_predict_with_trace() {
+        let model = toy_model(3, 0);
+        for feature_vec in &toy_dataset(10, 8, 1).feature_lists {
+            for &beam_size in &[1, 2, 10] {
+                let (predictions, traces) = model.predict_with_trace(feature_vec, beam_size);
+                assert_eq!(model.predict(feature_vec, beam_size), predictions);
+                assert_eq!(model.n_trees(), traces.len());
+                for trace in &traces {
+                    assert!(!trace.levels.is_empty());
+                    for (depth, level) in (1..).zip(&trace.levels) {
+                        assert!(!level.is_empty() && level.len() <= beam_size);
+                        assert!(level.iter().all(|node| node.depth() <= depth));
+                    }
+                    assert_eq!(trace.levels.last(), Some(&trace.leaves));
+                }
+
+                let json = serde_json::to_string(&traces).unwrap();
+                assert_eq!(
+                    traces,
+                    serde_json::from_str::<Vec<TreeTrace>>(&json).unwrap()
+                );
+            }
+        }
+    }
+}
@@ -1,29 +1,723 @@
+use super::cascade::NodeId;
+use super::framed::TreeSpill;
+use super::label_tree::{LabelTree, LabelTreeChanges, LabelTreeNode, TreeReusePolicy};
+use super::limits::InferenceLimits;
+use super::memory::{MemoryPhase, MemoryTracker, MemoryUsage, Reservation};
+use super::projection::ProjectionParams;
+use super::schema::FeatureTransform;
 use super::{cluster, liblinear, Model, Settings, TreeNode};
-use crate::data::DataSet;
+use crate::data::{compute_label_centroids, DataSet, LabelMatrix, MappedCsr, MmapDataSet};
+use crate::index::{check_dimensions, to_index, IndexKind, IndexOverflow};
 use crate::mat_util::*;
 use crate::util::{create_progress_bar, ProgressBar};
-use crate::{Index, IndexSet, IndexValueVec};
+use crate::{CancellationToken, FloatFormat, Index, IndexSet, IndexValueVec, Warning, Warnings};
 use const_default::ConstDefault;
 use hashbrown::HashMap;
-use itertools::{izip, Itertools};
-use log::info;
+use itertools::Itertools;
+use log::{info, warn};
 use rayon::prelude::*;
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io::{self, Write};
+use std::mem;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
 use std::time;
 
+/// How trees in a forest are trained relative to each other.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum EnsembleMode {
+    /// Trees are trained independently of each other.
+    #[default]
+    Independent,
+    /// Experimental: trees are trained one by one, and classifiers in each tree weight training
+    /// examples by how poorly the trees before it rank them.
+    Boosted { reweight: ReweightFn },
+}
+
+/// The per-example loss that training examples are weighted by in boosted mode.
+///
+/// Losses are computed from beam-1 predictions of the trees trained so far and lie in `[0, 1]`.
+/// The weight of an example is one plus its loss, normalized so that weights average to one.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ReweightFn {
+    /// One minus precision@1.
+    PrecisionAt1,
+    /// One minus the reciprocal rank of the highest ranked true label, or one if no true label
+    /// is predicted.
+    ReciprocalRank,
+}
+
+impl ReweightFn {
+    fn loss(&self, true_labels: &[Index], predictions: &[(Index, f32)]) -> f32 {
+        match self {
+            ReweightFn::PrecisionAt1 => match predictions.first() {
+                Some((label, _)) if true_labels.contains(label) => 0.,
+                _ => 1.,
+            },
+            ReweightFn::ReciprocalRank => predictions
+                .iter()
+                .position(|(label, _)| true_labels.contains(label))
+                .map_or(1., |rank| 1. - 1. / (rank + 1) as f32),
+        }
+    }
+}
+
+/// Summary statistics of the example weights a tree was trained with.
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct WeightSummary {
+    pub min: f32,
+    pub max: f32,
+    pub mean: f32,
+    pub std_dev: f32,
+}
+
+impl WeightSummary {
+    fn new(weights: &[f32]) -> Self {
+        assert!(!weights.is_empty());
+        let n = weights.len() as f32;
+        let mean = weights.iter().sum::<f32>() / n;
+        let variance = weights.iter().map(|w| (w - mean).powi(2)).sum::<f32>() / n;
+        Self {
+            min: weights.iter().cloned().fold(f32::INFINITY, f32::min),
+            max: weights.iter().cloned().fold(f32::NEG_INFINITY, f32::max),
+            mean,
+            std_dev: variance.sqrt(),
+        }
+    }
+}
+
+/// Information recorded while training a model.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct TrainingMetadata {
+    /// Summaries of the example weights each tree was trained with; empty unless trees were
+    /// trained in boosted mode.
+    #[serde(default)]
+    pub tree_weight_summaries: Vec<WeightSummary>,
+    /// Labels that were left out of clustering and attached to the root of each tree as a flat
+    /// leaf instead.
+    #[serde(default)]
+    pub cluster_excluded_labels: Vec<Index>,
+    /// Memory accounted for while training.
+    #[serde(default)]
+    pub memory_usage: Option<MemoryUsage>,
+    /// Nodes whose classifiers failed to train and were recovered, ordered by node.
+    #[serde(default)]
+    pub node_failures: Vec<NodeFailure>,
+    /// Parts of training that were cut short to stay within [`HyperParam::time_budget`].
+    #[serde(default)]
+    pub time_budget_shortcuts: TimeBudgetShortcuts,
+    /// Solver objective curves of sampled classifiers, grouped by depth in increasing order;
+    /// empty unless [`HyperParam::record_objective`] is set.
+    #[serde(default)]
+    pub objective_curves: Vec<DepthObjectiveCurves>,
+    /// The timings behind the density weight matrices were densified above, if the weight
+    /// storage was calibrated with [`WeightStorage::SpeedOptimalCalibrated`].
+    #[serde(default)]
+    pub weight_storage_calibration: Option<WeightStorageCalibration>,
+    /// Labels that differed from those of [`TrainOptions::label_tree`], if the model was trained
+    /// with one.
+    #[serde(default)]
+    pub label_tree_changes: Option<LabelTreeChanges>,
+}
+
+impl TrainingMetadata {
+    /// Metadata for a model consisting of the trees at the given indices.
+    pub(crate) fn select_trees(&self, n_trees: usize, tree_indices: &[usize]) -> Self {
+        let tree_weight_summaries = if self.tree_weight_summaries.len() == n_trees {
+            tree_indices
+                .iter()
+                .map(|&i| self.tree_weight_summaries[i])
+                .collect()
+        } else {
+            Vec::new()
+        };
+        Self {
+            tree_weight_summaries,
+            cluster_excluded_labels: self.cluster_excluded_labels.clone(),
+            memory_usage: self.memory_usage.clone(),
+            node_failures: tree_indices
+                .iter()
+                .enumerate()
+                .flat_map(|(new_index, &i)| {
+                    self.node_failures
+                        .iter()
+                        .filter(move |failure| failure.node.tree() == i)
+                        .map(move |failure| NodeFailure {
+                            node: failure.node.with_tree(new_index),
+                            ..failure.clone()
+                        })
+                })
+                .collect(),
+            time_budget_shortcuts: self.time_budget_shortcuts.select_trees(tree_indices),
+            objective_curves: self
+                .objective_curves
+                .iter()
+                .map(|depth_curves| DepthObjectiveCurves {
+                    depth: depth_curves.depth,
+                    curves: tree_indices
+                        .iter()
+                        .enumerate()
+                        .flat_map(|(new_index, &i)| {
+                            depth_curves
+                                .curves
+                                .iter()
+                                .filter(move |curve| curve.node.tree() == i)
+                                .map(move |curve| ClassifierObjectiveCurve {
+                                    node: curve.node.with_tree(new_index),
+                                    ..curve.clone()
+                                })
+                        })
+                        .collect(),
+                })
+                .collect(),
+            weight_storage_calibration: self.weight_storage_calibration.clone(),
+            label_tree_changes: self.label_tree_changes.clone(),
+        }
+    }
+
+    /// Metadata for a model consisting of the trees of models with the given metadata and numbers
+    /// of trees, one model after another.
+    ///
+    /// Records that describe a training run as a whole, i.e., memory usage, the weight storage
+    /// calibration, and label tree changes, are left out, since no run trained the merged model.
+    pub(crate) fn concat(parts: &[(&Self, usize)]) -> Self {
+        let has_weight_summaries = parts
+            .iter()
+            .all(|&(metadata, n_trees)| metadata.tree_weight_summaries.len() == n_trees);
+        let mut merged = Self::default();
+        let mut offset = 0;
+        for &(metadata, n_trees) in parts {
+            let shift = |node: &NodeId| node.with_tree(node.tree() + offset);
+            if has_weight_summaries {
+                merged
+                    .tree_weight_summaries
+                    .extend_from_slice(&metadata.tree_weight_summaries);
+            }
+            merged
+                .cluster_excluded_labels
+                .extend_from_slice(&metadata.cluster_excluded_labels);
+            merged
+                .node_failures
+                .extend(metadata.node_failures.iter().map(|failure| NodeFailure {
+                    node: shift(&failure.node),
+                    ..failure.clone()
+                }));
+
+            let shortcuts = &metadata.time_budget_shortcuts;
+            let merged_shortcuts = &mut merged.time_budget_shortcuts;
+            merged_shortcuts.n_trees_skipped += shortcuts.n_trees_skipped;
+            merged_shortcuts
+                .truncated_nodes
+                .extend(shortcuts.truncated_nodes.iter().map(shift));
+            merged_shortcuts
+                .centroid_nodes
+                .extend(shortcuts.centroid_nodes.iter().map(shift));
+
+            for depth_curves in &metadata.objective_curves {
+                let curves = depth_curves
+                    .curves
+                    .iter()
+                    .map(|curve| ClassifierObjectiveCurve {
+                        node: shift(&curve.node),
+                        ..curve.clone()
+                    });
+                match merged
+                    .objective_curves
+                    .iter_mut()
+                    .find(|merged_curves| merged_curves.depth == depth_curves.depth)
+                {
+                    Some(merged_curves) => merged_curves.curves.extend(curves),
+                    None => merged.objective_curves.push(DepthObjectiveCurves {
+                        depth: depth_curves.depth,
+                        curves: curves.collect(),
+                    }),
+                }
+            }
+            offset += n_trees;
+        }
+
+        merged.cluster_excluded_labels.sort_unstable();
+        merged.cluster_excluded_labels.dedup();
+        merged.objective_curves.sort_by_key(|curves| curves.depth);
+        for depth_curves in &mut merged.objective_curves {
+            depth_curves.curves.truncate(MAX_OBJECTIVE_CURVES_PER_DEPTH);
+        }
+        merged
+    }
+
+    /// Metadata with each node identified anew by `renumber`, which returns `None` for nodes that
+    /// no longer exist; records of those nodes are dropped.
+    ///
+    /// The column of an objective curve is renumbered as the child of its node that it scores,
+    /// which leaves the columns of leaves, and of branches whose children are kept, unchanged.
+    pub(crate) fn renumber_nodes(&self, renumber: impl Fn(&NodeId) -> Option<NodeId>) -> Self {
+        let renumber_all = |nodes: &[NodeId]| nodes.iter().filter_map(&renumber).collect();
+        let shortcuts = &self.time_budget_shortcuts;
+        Self {
+            node_failures: self
+                .node_failures
+                .iter()
+                .filter_map(|failure| {
+                    Some(NodeFailure {
+                        node: renumber(&failure.node)?,
+                        ..failure.clone()
+                    })
+                })
+                .collect(),
+            time_budget_shortcuts: TimeBudgetShortcuts {
+                n_trees_skipped: shortcuts.n_trees_skipped,
+                truncated_nodes: renumber_all(&shortcuts.truncated_nodes),
+                centroid_nodes: renumber_all(&shortcuts.centroid_nodes),
+            },
+            objective_curves: self
+                .objective_curves
+                .iter()
+                .map(|depth_curves| DepthObjectiveCurves {
+                    depth: depth_curves.depth,
+                    curves: depth_curves
+                        .curves
+                        .iter()
+                        .filter_map(|curve| {
+                            let node = renumber(&curve.node)?;
+                            let child = renumber(&curve.node.child(curve.column))?;
+                            Some(ClassifierObjectiveCurve {
+                                node,
+                                column: *child.path().last().unwrap(),
+                                ..curve.clone()
+                            })
+                        })
+                        .collect(),
+                })
+                .filter(|depth_curves| !depth_curves.curves.is_empty())
+                .collect(),
+            ..self.clone()
+        }
+    }
+
+    /// Write the objective curves as CSV for plotting, with a header and one row per recorded
+    /// point, formatting objectives with the given format, usually [`FloatFormat::Shortest`].
+    ///
+    /// The columns are the depth, the tree index, the path of the node as child indices
+    /// separated by `/`, the column of the classifier in the node's weight matrix, the number of
+    /// solver iterations, and the objective.
+    pub fn write_objective_curves_csv<W: Write>(
+        &self,
+        mut writer: W,
+        float_format: FloatFormat,
+    ) -> io::Result<()> {
+        writeln!(writer, "depth,tree,path,column,iteration,objective")?;
+        for depth_curves in &self.objective_curves {
+            for curve in &depth_curves.curves {
+                let path = curve.node.path().iter().join("/");
+                for (i, value) in curve.curve.values.iter().enumerate() {
+                    writeln!(
+                        writer,
+                        "{},{},{},{},{},{}",
+                        depth_curves.depth,
+                        curve.node.tree(),
+                        path,
+                        curve.column,
+                        i as u32 * curve.curve.interval,
+                        float_format.display_f32(*value)
+                    )?;
+                }
+            }
+        }
+        writer.flush()
+    }
+}
+
+/// Solver objective curves of classifiers sampled at one depth of the trees.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct DepthObjectiveCurves {
+    /// The depth of the nodes, where roots have depth 1.
+    pub depth: usize,
+    /// Curves of the first [`MAX_OBJECTIVE_CURVES_PER_DEPTH`] classifiers at most, ordered by
+    /// node and column.
+    pub curves: Vec<ClassifierObjectiveCurve>,
+}
+
+/// The solver objective curve of one classifier in a node.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ClassifierObjectiveCurve {
+    pub node: NodeId,
+    /// The column of the classifier in the node's weight matrix, i.e., the index of the child or
+    /// label it scores.
+    pub column: usize,
+    pub curve: liblinear::ObjectiveCurve,
+}
+
+/// Maximum number of classifiers whose objective curves are recorded at each depth.
+pub const MAX_OBJECTIVE_CURVES_PER_DEPTH: usize = 8;
+
+/// Parts of training cut short because the time budget ran out.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TimeBudgetShortcuts {
+    /// The number of trees that weren't trained at all.
+    pub n_trees_skipped: usize,
+    /// Nodes made leaves instead of being split further, ordered by node.
+    pub truncated_nodes: Vec<NodeId>,
+    /// Nodes whose classifiers are label centroids instead of being trained, ordered by node.
+    pub centroid_nodes: Vec<NodeId>,
+}
+
+impl TimeBudgetShortcuts {
+    /// Returns whether nothing was cut short.
+    pub fn is_empty(&self) -> bool {
+        self.n_trees_skipped == 0
+            && self.truncated_nodes.is_empty()
+            && self.centroid_nodes.is_empty()
+    }
+
+    /// Shortcuts in the trees at the given indices, renumbered by their positions.
+    fn select_trees(&self, tree_indices: &[usize]) -> Self {
+        let select = |nodes: &[NodeId]| {
+            tree_indices
+                .iter()
+                .enumerate()
+                .flat_map(|(new_index, &i)| {
+                    nodes
+                        .iter()
+                        .filter(move |node| node.tree() == i)
+                        .map(move |node| node.with_tree(new_index))
+                })
+                .collect()
+        };
+        Self {
+            n_trees_skipped: self.n_trees_skipped,
+            truncated_nodes: select(&self.truncated_nodes),
+            centroid_nodes: select(&self.centroid_nodes),
+        }
+    }
+}
+
+/// Errors from training.
+#[derive(Clone, Debug, PartialEq)]
+pub enum TrainError {
+    /// An allocation would exceed the memory budget, even with nodes trained one after another.
+    MemoryBudgetExceeded {
+        phase: MemoryPhase,
+        requested_bytes: usize,
+        in_use_bytes: usize,
+        budget_bytes: usize,
+    },
+    /// The classifier of a node failed to train, and the failure policy didn't recover from it.
+    NodeFailed { node: NodeId, message: String },
+    /// The dataset has more features, labels or examples than indices can represent.
+    IndexOverflow(IndexOverflow),
+    /// Spilling trees to disk, or writing the model out, failed.
+    Io(String),
+    /// The labels of the training data differ from those of [`TrainOptions::label_tree`] under
+    /// [`TreeReusePolicy::Strict`].
+    LabelTreeMismatch {
+        n_new_labels: usize,
+        n_removed_labels: usize,
+    },
+    /// A feature vector isn't l2-normalized under [`NormalizationPolicy::RequireNormalized`].
+    NotNormalized {
+        /// The index of the example in the dataset trained on; callers that loaded it from a file
+        /// know which line that is.
+        example: usize,
+        norm: f32,
+    },
+    /// The label names given for training don't cover all labels of the dataset.
+    InvalidLabelNames { n_names: usize, n_labels: usize },
+    /// The hyper-parameters or training options are invalid, or can't be used with the dataset.
+    InvalidArgument(String),
+    /// Training was cancelled through [`TrainOptions::cancellation`].
+    Cancelled,
+}
+
+impl fmt::Display for TrainError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TrainError::MemoryBudgetExceeded {
+                phase,
+                requested_bytes,
+                in_use_bytes,
+                budget_bytes,
+            } => write!(
+                f,
+                "Memory budget of {} bytes exceeded in {}: {} bytes requested with {} bytes in use",
+                budget_bytes, phase, requested_bytes, in_use_bytes
+            ),
+            TrainError::NodeFailed { node, message } => write!(
+                f,
+                "Training the classifier of node {:?} in tree {} failed: {}",
+                node.path(),
+                node.tree(),
+                message
+            ),
+            TrainError::IndexOverflow(e) => write!(f, "{}", e),
+            TrainError::Io(message) => write!(f, "I/O error: {}", message),
+            TrainError::LabelTreeMismatch {
+                n_new_labels,
+                n_removed_labels,
+            } => write!(
+                f,
+                "Labels differ from the label tree: {} labels are new and {} are removed",
+                n_new_labels, n_removed_labels
+            ),
+            TrainError::NotNormalized { example, norm } => write!(
+                f,
+                "Feature vector of example {} has l2 norm {}, but should be normalized",
+                example, norm
+            ),
+            TrainError::InvalidLabelNames { n_names, n_labels } => write!(
+                f,
+                "Got {} label names, but the dataset has {} labels",
+                n_names, n_labels
+            ),
+            TrainError::InvalidArgument(message) => write!(f, "Invalid argument: {}", message),
+            TrainError::Cancelled => write!(f, "Training was cancelled"),
+        }
+    }
+}
+
+impl std::error::Error for TrainError {}
+
+impl From<IndexOverflow> for TrainError {
+    fn from(e: IndexOverflow) -> Self {
+        TrainError::IndexOverflow(e)
+    }
+}
+
+impl From<io::Error> for TrainError {
+    fn from(e: io::Error) -> Self {
+        TrainError::Io(e.to_string())
+    }
+}
+
+/// What to do when training the classifier of a node fails, e.g., when the solver panics on
+/// pathological data.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum NodeFailurePolicy {
+    /// Fail training with [`TrainError::NodeFailed`].
+    #[default]
+    Abort,
+    /// Retry once with a smaller cost coefficient and more iterations, and fail training if the
+    /// retry fails too.
+    Retry,
+    /// Use the normalized centroids of the positive examples of each classifier as its weights.
+    CentroidFallback,
+}
+
+/// How training feature vectors are normalized.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub enum NormalizationPolicy {
+    /// l2-normalize every feature vector, as the classifiers assume.
+    #[default]
+    Normalize,
+    /// Fail training with [`TrainError::NotNormalized`] if the l2 norm of a non-empty feature
+    /// vector differs from 1 by more than the tolerance, e.g., because the data was already
+    /// scaled differently upstream; vectors within tolerance are normalized exactly.
+    RequireNormalized { tolerance: f32 },
+    /// Use feature vectors as given, both for training and for prediction with the model.
+    None,
+}
+
+impl NormalizationPolicy {
+    pub fn validate(&self) -> Result<(), String> {
+        match *self {
+            NormalizationPolicy::RequireNormalized { tolerance }
+                if !(tolerance >= 0. && tolerance.is_finite()) =>
+            {
+                Err(format!(
+                    "tolerance must be non-negative and finite, but is {}",
+                    tolerance
+                ))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// The transform that the model applies to input feature vectors before prediction, so that
+    /// they are prepared like the training data.
+    pub(crate) fn feature_transform(&self) -> FeatureTransform {
+        match self {
+            NormalizationPolicy::Normalize | NormalizationPolicy::RequireNormalized { .. } => {
+                FeatureTransform::L2Normalize
+            }
+            NormalizationPolicy::None => FeatureTransform::Identity,
+        }
+    }
+
+    /// Check training feature vectors as given, before any projection, according to the policy.
+    fn check(&self, feature_lists: &[IndexValueVec]) -> Result<(), TrainError> {
+        if let NormalizationPolicy::RequireNormalized { tolerance } = *self {
+            let not_normalized = feature_lists
+                .par_iter()
+                .enumerate()
+                .filter(|(_, v)| !v.is_empty())
+                .map(|(i, v)| (i, v.iter().map(|(_, x)| x * x).sum::<f32>().sqrt()))
+                .find_first(|&(_, norm)| norm.is_nan() || (norm - 1.).abs() > tolerance);
+            if let Some((example, norm)) = not_normalized {
+                return Err(TrainError::NotNormalized { example, norm });
+            }
+        }
+        Ok(())
+    }
+
+    /// Normalize training feature vectors, as trained on, according to the policy.
+    fn apply(&self, feature_lists: &mut [IndexValueVec]) {
+        if *self != NormalizationPolicy::None {
+            feature_lists.par_iter_mut().for_each(|v| v.l2_normalize());
+        }
+    }
+}
+
+/// How a node was recovered after its classifier failed to train.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum NodeRecovery {
+    /// The classifier was trained on retry with safer hyper-parameters.
+    Retried,
+    /// The classifier was replaced by label centroids.
+    CentroidFallback,
+}
+
+/// A node whose classifier failed to train, but was recovered.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NodeFailure {
+    pub node: NodeId,
+    /// The error from the failed attempt.
+    pub message: String,
+    pub recovery: NodeRecovery,
+}
+
+/// How the weight matrices of classifiers are stored.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub enum WeightStorage {
+    /// Each matrix is stored in whichever of the dense and sparse formats is smaller.
+    #[default]
+    MemoryOptimal,
+    /// Like [`Self::MemoryOptimal`], but matrices denser than the given density are stored in
+    /// dense format, which takes more memory but makes prediction faster.
+    SpeedOptimal { max_sparse_density: f32 },
+    /// Like [`Self::SpeedOptimal`], with the density where dense storage becomes faster measured
+    /// on the current machine at the start of training, by timing both formats for about the
+    /// given time.
+    ///
+    /// The timings are recorded in [`TrainingMetadata::weight_storage_calibration`].
+    SpeedOptimalCalibrated { time_budget: time::Duration },
+}
+
+impl WeightStorage {
+    pub fn validate(&self) -> Result<(), String> {
+        match *self {
+            WeightStorage::SpeedOptimal { max_sparse_density }
+                if !(0. ..=1.).contains(&max_sparse_density) =>
+            {
+                Err(format!(
+                    "max_sparse_density must be within [0, 1], but is {}",
+                    max_sparse_density
+                ))
+            }
+            WeightStorage::SpeedOptimalCalibrated { time_budget } if time_budget.is_zero() => {
+                Err("Calibration time_budget must be positive".to_owned())
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Timings of sparse and dense weight storage that a density threshold was calibrated from.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct WeightStorageCalibration {
+    /// The density above which weight matrices were stored in dense format.
+    pub max_sparse_density: f32,
+    /// The shapes of the random matrices timed, as (rows, columns).
+    pub shapes: Vec<(usize, usize)>,
+    /// The number of non-zero features of the random inputs.
+    pub input_nnz: usize,
+    /// The densities timed, in ascending order.
+    pub densities: Vec<f32>,
+    /// Seconds per prediction summed over the shapes at each density, with sparse storage.
+    pub sparse_secs: Vec<f64>,
+    /// Seconds per prediction summed over the shapes at each density, with dense storage.
+    pub dense_secs: Vec<f64>,
+}
+
+/// Densities timed when calibrating weight storage.
+const CALIBRATION_DENSITIES: [f32; 9] = [0.01, 0.02, 0.05, 0.1, 0.2, 0.3, 0.5, 0.75, 1.];
+/// Maximum number of rows of the matrices timed when calibrating weight storage, which keeps
+/// dense matrices small for datasets with many features.
+const CALIBRATION_MAX_ROWS: usize = 1 << 14;
+
+/// Factor the cost coefficient is scaled by when retrying a failed classifier.
+const RETRY_C_FACTOR: f32 = 0.1;
+/// Factor the max number of iterations is scaled by when retrying a failed classifier.
+const RETRY_MAX_ITER_FACTOR: u32 = 10;
+
 /// Model training hyper-parameters.
-#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
 pub struct HyperParam {
     pub n_trees: usize,
     pub min_branch_size: usize,
     pub max_depth: usize,
-    pub centroid_threshold: f32,
     pub collapse_every_n_layers: usize,
     pub linear: liblinear::HyperParam,
     pub cluster: cluster::HyperParam,
     pub tree_structure_only: bool,
     pub train_trees_1_by_1: bool,
+    #[serde(default)]
+    pub ensemble_mode: EnsembleMode,
+    /// Upper bound on the memory accounted for during training, in bytes.
+    ///
+    /// Accounting covers the dataset, copies of examples made for each node, clustering scratch
+    /// space, and classifier weights. When copies for training child nodes in parallel would
+    /// exceed the budget, the children are trained one after another instead; if even that isn't
+    /// enough, training fails with [`TrainError::MemoryBudgetExceeded`]. Weight matrices are
+    /// accounted for in whichever of the dense and sparse formats is smaller, so there is no
+    /// storage format left to fall back to; matrices densified for speed according to
+    /// [`Self::weight_storage`] aren't accounted for.
+    #[serde(default)]
+    pub memory_budget_bytes: Option<usize>,
+    /// What to do when the classifier of a node fails to train.
+    ///
+    /// Recovered failures are recorded in [`TrainingMetadata::node_failures`].
+    #[serde(default)]
+    pub node_failure_policy: NodeFailurePolicy,
+    /// A random projection applied to feature vectors before training, if any.
+    ///
+    /// The projection is stored in the model and applied to inputs at prediction time as well, so
+    /// the model expects feature vectors of the original dimension while its
+    /// [`Model::n_features`] is the projected one. Not supported for memory-mapped datasets.
+    #[serde(default)]
+    pub feature_projection: Option<ProjectionParams>,
+    /// Wall-clock time that training should finish within, if any, counted from once the training
+    /// data is loaded.
+    ///
+    /// A tree isn't started if the budget has run out, or if the last finished tree took longer
+    /// than the time left; the first tree is always trained, so that the model isn't empty. Once
+    /// the budget runs out in the middle of a tree, nodes not yet split become leaves, and
+    /// classifiers not yet trained are replaced by label centroids as with
+    /// [`NodeFailurePolicy::CentroidFallback`], so the tree is finished quickly rather than
+    /// abandoned. What was cut short is recorded in [`TrainingMetadata::time_budget_shortcuts`].
+    ///
+    /// When trees are trained in parallel, those started together, up to one per thread, are all
+    /// started before any of them finishes, so only the deadline keeps them from starting; trees
+    /// started later are estimated from the last tree finished, as in sequential training.
+    #[serde(default)]
+    pub time_budget: Option<time::Duration>,
+    /// Whether to record how the solver objective evolves over iterations, for the classifiers
+    /// of up to [`MAX_OBJECTIVE_CURVES_PER_DEPTH`] labels or children at each depth.
+    ///
+    /// The classifiers recorded are the first ones at each depth by node and column, so the same
+    /// ones are recorded however training is scheduled across threads. The curves are recorded in
+    /// [`TrainingMetadata::objective_curves`].
+    #[serde(default)]
+    pub record_objective: bool,
+    /// How the weight matrices of classifiers are stored.
+    #[serde(default)]
+    pub weight_storage: WeightStorage,
+    /// What to do with labels that differ between the training data and [`TrainOptions::label_tree`].
+    ///
+    /// The labels that differed are recorded in [`TrainingMetadata::label_tree_changes`].
+    #[serde(default)]
+    pub tree_reuse_policy: TreeReusePolicy,
 }
 
 impl ConstDefault for HyperParam {
@@ -31,12 +725,19 @@ impl ConstDefault for HyperParam {
         n_trees: 3,
         min_branch_size: 100,
         max_depth: 20,
-        centroid_threshold: 0.,
         collapse_every_n_layers: 0,
         linear: liblinear::HyperParam::DEFAULT,
         cluster: cluster::HyperParam::DEFAULT,
         tree_structure_only: false,
         train_trees_1_by_1: false,
+        ensemble_mode: EnsembleMode::Independent,
+        memory_budget_bytes: None,
+        node_failure_policy: NodeFailurePolicy::Abort,
+        feature_projection: None,
+        time_budget: None,
+        record_objective: false,
+        weight_storage: WeightStorage::MemoryOptimal,
+        tree_reuse_policy: TreeReusePolicy::Adapt,
     };
 }
 
@@ -46,6 +747,88 @@ impl Default for HyperParam {
     }
 }
 
+/// Training inputs besides the hyper-parameters, which can hold data of any size and so are kept
+/// out of [`HyperParam`].
+#[derive(Clone, Debug, Default)]
+pub struct TrainOptions {
+    /// Labels to leave out of clustering, e.g., catch-all labels that co-occur with everything.
+    ///
+    /// These labels are attached to the root of each tree as a flat leaf instead, so they are
+    /// still predicted without distorting the tree structure.
+    pub cluster_exclude_labels: Vec<Index>,
+    /// Directory that finished trees are written to while later trees are trained, if any.
+    ///
+    /// Spilled trees are read back once training is done and the training data is freed, or
+    /// copied straight into the output by [`HyperParam::train_to_writer`] without being held in
+    /// memory all at once. Not supported for boosted ensembles, which predict with the trees
+    /// trained so far.
+    pub spill_dir: Option<PathBuf>,
+    /// The label partitions to build trees on instead of clustering labels, if any, e.g., those of
+    /// a previous model exported with [`Model::export_label_tree`].
+    ///
+    /// Only classifiers are trained, so labels stay in the same leaves across retrains; the tree
+    /// must have [`HyperParam::n_trees`] trees, and [`HyperParam::min_branch_size`],
+    /// [`HyperParam::max_depth`] and [`HyperParam::collapse_every_n_layers`] don't apply. Not
+    /// supported together with [`Self::cluster_exclude_labels`], since excluded labels are already
+    /// part of exported trees.
+    pub label_tree: Option<LabelTree>,
+    /// The name of each label to set in the trained model, as with [`Model::set_label_names`], if
+    /// any.
+    ///
+    /// Names are checked against the labels of the dataset before training starts.
+    pub label_names: Option<Vec<String>>,
+    /// How the feature vectors of the dataset are normalized; the model prepares input vectors
+    /// the same way when predicting.
+    ///
+    /// Feature vectors are checked as given, before [`HyperParam::feature_projection`], and
+    /// normalized as trained on, after it.
+    pub normalization_policy: NormalizationPolicy,
+    /// A token to stop training early with, if any.
+    ///
+    /// Once cancelled, training fails with [`TrainError::Cancelled`] before the classifier of the
+    /// next node is trained, so it stops within about the time a single classifier takes.
+    pub cancellation: Option<CancellationToken>,
+}
+
+impl TrainOptions {
+    /// Check if the options are valid together with the hyper-parameters, for a dataset with the
+    /// given number of labels.
+    pub fn validate_for(&self, hyper_param: &HyperParam, n_labels: usize) -> Result<(), String> {
+        if self.spill_dir.is_some() && hyper_param.ensemble_mode != EnsembleMode::Independent {
+            Err("spill_dir is only supported for independent ensembles".to_owned())
+        } else if let Err(msg) = self.normalization_policy.validate() {
+            Err(format!("Invalid normalization policy; {}", msg))
+        } else if let Some(Err(msg)) = self.label_tree.as_ref().map(|tree| tree.validate()) {
+            Err(format!("Invalid label tree; {}", msg))
+        } else if let Some(tree) = self
+            .label_tree
+            .as_ref()
+            .filter(|tree| tree.n_trees() != hyper_param.n_trees)
+        {
+            Err(format!(
+                "label_tree has {} trees, but n_trees is {}",
+                tree.n_trees(),
+                hyper_param.n_trees
+            ))
+        } else if self.label_tree.is_some() && !self.cluster_exclude_labels.is_empty() {
+            Err("label_tree can't be combined with cluster_exclude_labels".to_owned())
+        } else if let Some(label) = self
+            .cluster_exclude_labels
+            .iter()
+            .find(|&&label| label as usize >= n_labels)
+        {
+            Err(format!(
+                "Excluded label {} is out of range for {} labels",
+                label, n_labels
+            ))
+        } else if self.cluster_exclude_labels.iter().unique().count() >= n_labels {
+            Err("cluster_exclude_labels must not contain all labels".to_owned())
+        } else {
+            Ok(())
+        }
+    }
+}
+
 impl HyperParam {
     /// Check if the hyper-parameter settings are valid.
     pub fn validate(&self) -> Result<(), String> {
@@ -56,20 +839,23 @@ impl HyperParam {
                 "min_branch_size must be greater than 1, but is {}",
                 self.min_branch_size
             ))
-        } else if self.centroid_threshold < 0. {
-            Err(format!(
-                "centroid_threshold must be non-negative, but is {}",
-                self.centroid_threshold
-            ))
+        } else if self.memory_budget_bytes == Some(0) {
+            Err("memory_budget_bytes must be positive".to_owned())
+        } else if self.time_budget == Some(time::Duration::ZERO) {
+            Err("time_budget must be positive".to_owned())
         } else if self.max_depth == 0 {
             Err(format!(
                 "max_depth must be positive, but is {}",
                 self.max_depth
             ))
+        } else if let Err(msg) = self.weight_storage.validate() {
+            Err(format!("Invalid weight storage; {}", msg))
         } else if let Err(msg) = self.linear.validate() {
             Err(format!("Invalid liblinear hyper-parameter; {}", msg))
         } else if let Err(msg) = self.cluster.validate() {
             Err(format!("Invalid clustering hyper-parameter; {}", msg))
+        } else if let Some(Err(msg)) = self.feature_projection.map(|params| params.validate()) {
+            Err(format!("Invalid feature projection; {}", msg))
         } else {
             Ok(())
         }
@@ -80,86 +866,559 @@ impl HyperParam {
     /// Here we take ownership of the dataset object to perform necessary prepossessing. One can
     /// choose to clone a dataset before passing it in to avoid losing the original data.
     pub fn train(&self, dataset: DataSet) -> Model {
-        self.validate().unwrap();
+        self.train_with_warnings(dataset, &Warnings::new())
+    }
+
+    /// Train a omikuji model on the given dataset, collecting warnings about the tree structure.
+    ///
+    /// See [`Self::train()`] for details.
+    pub fn train_with_warnings(&self, dataset: DataSet, warnings: &Warnings) -> Model {
+        self.try_train_with_warnings(dataset, warnings)
+            .unwrap_or_else(|e| panic!("Training failed: {}", e))
+    }
+
+    /// Train a omikuji model on the given dataset, returning an error instead of panicking if
+    /// the hyper-parameters are invalid, training can't stay within the memory budget, a node fails under
+    /// [`NodeFailurePolicy::Abort`], the dataset dimensions don't fit in indices, or feature vectors
+    /// aren't normalized as [`NormalizationPolicy::RequireNormalized`] requires.
+    ///
+    /// See [`Self::train_with_warnings()`] for details.
+    pub fn try_train_with_warnings(
+        &self,
+        dataset: DataSet,
+        warnings: &Warnings,
+    ) -> Result<Model, TrainError> {
+        self.try_train_with_options(dataset, &TrainOptions::default(), warnings)
+    }
+
+    /// Like [`Self::try_train_with_warnings()`], but with the given training options.
+    ///
+    /// Label names that don't cover the labels of the dataset fail with
+    /// [`TrainError::InvalidLabelNames`] before training starts.
+    pub fn try_train_with_options(
+        &self,
+        dataset: DataSet,
+        options: &TrainOptions,
+        warnings: &Warnings,
+    ) -> Result<Model, TrainError> {
+        let (trainer, n_features, start_t) = self.initialize_trainer(dataset, options)?;
+        self.train_forest_and_reload(trainer, n_features, start_t, options, warnings)
+    }
+
+    /// Train a omikuji model on the given dataset, and serialize it into the writer in the format
+    /// of [`Model::save_to_writer`].
+    ///
+    /// With [`TrainOptions::spill_dir`] set, the trees are copied from their spill files into the
+    /// writer one at a time, so the whole model is never held in memory.
+    pub fn train_to_writer<W: Write>(
+        &self,
+        dataset: DataSet,
+        writer: W,
+        options: &TrainOptions,
+        warnings: &Warnings,
+    ) -> Result<(), TrainError> {
+        let (trainer, n_features, start_t) = self.initialize_trainer(dataset, options)?;
+        match &options.spill_dir {
+            Some(spill_dir) => {
+                let spill = TreeSpill::new(spill_dir, self.n_trees)?;
+                let model = self.train_forest(
+                    trainer,
+                    n_features,
+                    start_t,
+                    options,
+                    warnings,
+                    Some(&spill),
+                )?;
+                spill.write_model(&model, writer)?;
+            }
+            None => self
+                .train_forest(trainer, n_features, start_t, options, warnings, None)?
+                .save_to_writer(writer)?,
+        }
+        Ok(())
+    }
+
+    /// Train a omikuji model on the given dataset, and save it into the directory with the given
+    /// path as [`Model::save`] does, returning the metadata recorded in training.
+    ///
+    /// With [`TrainOptions::spill_dir`] set, the trees are read back from their spill files and
+    /// saved one at a time, so the whole model is never held in memory.
+    pub fn train_to_dir<P: AsRef<Path>>(
+        &self,
+        dataset: DataSet,
+        dir_path: P,
+        options: &TrainOptions,
+        warnings: &Warnings,
+    ) -> Result<TrainingMetadata, TrainError> {
+        let (trainer, n_features, start_t) = self.initialize_trainer(dataset, options)?;
+        let model = match &options.spill_dir {
+            Some(spill_dir) => {
+                let spill = TreeSpill::new(spill_dir, self.n_trees)?;
+                let model = self.train_forest(
+                    trainer,
+                    n_features,
+                    start_t,
+                    options,
+                    warnings,
+                    Some(&spill),
+                )?;
+                spill.save_model(&model, dir_path.as_ref())?;
+                model
+            }
+            None => {
+                let model =
+                    self.train_forest(trainer, n_features, start_t, options, warnings, None)?;
+                model.save(dir_path)?;
+                model
+            }
+        };
+        Ok(model.training_metadata)
+    }
+
+    /// Check that the label names, if any, cover the labels of a dataset.
+    fn check_label_names(options: &TrainOptions, n_labels: usize) -> Result<(), TrainError> {
+        match &options.label_names {
+            Some(label_names) if label_names.len() < n_labels => {
+                Err(TrainError::InvalidLabelNames {
+                    n_names: label_names.len(),
+                    n_labels,
+                })
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Validate the hyper-parameters, the options and the dataset, and initialize a trainer with
+    /// them, returning the trainer with the number of features trained on and the time training
+    /// started.
+    fn initialize_trainer(
+        &self,
+        dataset: DataSet,
+        options: &TrainOptions,
+    ) -> Result<(TreeTrainer, usize, time::Instant), TrainError> {
+        self.validate().map_err(TrainError::InvalidArgument)?;
+        options
+            .validate_for(self, dataset.n_labels)
+            .map_err(TrainError::InvalidArgument)?;
+        Self::check_label_names(options, dataset.n_labels)?;
+        options.normalization_policy.check(&dataset.feature_lists)?;
+        let dataset = match self.feature_projection {
+            Some(params) => {
+                info!(
+                    "Projecting {} features to {} dimensions",
+                    dataset.n_features, params.n_components
+                );
+                params.project_dataset(dataset)
+            }
+            None => dataset,
+        };
         let n_features = dataset.n_features;
+        check_dimensions(dataset.len(), n_features, dataset.n_labels)?;
 
         info!("Training model with hyper-parameters {:?}", self);
         let start_t = time::Instant::now();
 
         info!("Initializing tree trainer");
-        let trainer = TreeTrainer::initialize(dataset, *self);
+        let trainer = TreeTrainer::initialize(dataset, *self, options)?;
+        Ok((trainer, n_features, start_t))
+    }
 
-        info!("Start training forest");
-        let trees = if !self.train_trees_1_by_1 {
-            (0..self.n_trees)
-                .into_par_iter()
-                .map(|_| trainer.train())
-                .collect()
-        } else {
-            let mut trees = Vec::with_capacity(self.n_trees);
-            for i in 1..=self.n_trees {
-                trainer
-                    .progress_bar
-                    .lock()
-                    .unwrap()
-                    .message(&format!("[Tree {}/{}] ", i, self.n_trees));
-                trees.push(trainer.train());
+    /// Train a omikuji model on a memory-mapped dataset.
+    ///
+    /// Unlike [`Self::train()`], the feature vectors of the dataset are used directly from the
+    /// mapping without being copied, since they are already stored in the form needed for training.
+    /// Warnings about training are pushed to the given [`Warnings`], as in
+    /// [`Self::train_with_warnings()`]. Errors are returned as by
+    /// [`Self::try_train_with_options()`]; feature projection and
+    /// [`NormalizationPolicy::None`] aren't supported, since the dataset is stored normalized.
+    pub fn train_on_mmap(
+        &self,
+        dataset: &MmapDataSet,
+        options: &TrainOptions,
+        warnings: &Warnings,
+    ) -> Result<Model, TrainError> {
+        self.validate().map_err(TrainError::InvalidArgument)?;
+        if self.feature_projection.is_some() {
+            return Err(TrainError::InvalidArgument(
+                "Feature projection is not supported for memory-mapped datasets".to_owned(),
+            ));
+        }
+        if options.normalization_policy == NormalizationPolicy::None {
+            return Err(TrainError::InvalidArgument(
+                "Memory-mapped datasets are stored normalized, so they can't be used as given"
+                    .to_owned(),
+            ));
+        }
+        options
+            .validate_for(self, dataset.n_labels)
+            .map_err(TrainError::InvalidArgument)?;
+        Self::check_label_names(options, dataset.n_labels)?;
+        let n_features = dataset.n_features;
+        check_dimensions(dataset.len(), n_features, dataset.n_labels)?;
+
+        info!("Training model with hyper-parameters {:?}", self);
+        let start_t = time::Instant::now();
+
+        info!("Initializing tree trainer");
+        let trainer = TreeTrainer::initialize_from_mmap(dataset, *self, options)?;
+        self.train_forest_and_reload(trainer, n_features, start_t, options, warnings)
+    }
+
+    /// Train the forest, spilling trees into [`TrainOptions::spill_dir`] if set and reading them
+    /// back once the trainer is dropped.
+    fn train_forest_and_reload(
+        &self,
+        trainer: TreeTrainer,
+        n_features: usize,
+        start_t: time::Instant,
+        options: &TrainOptions,
+        warnings: &Warnings,
+    ) -> Result<Model, TrainError> {
+        match &options.spill_dir {
+            Some(spill_dir) => {
+                let spill = TreeSpill::new(spill_dir, self.n_trees)?;
+                let mut model = self.train_forest(
+                    trainer,
+                    n_features,
+                    start_t,
+                    options,
+                    warnings,
+                    Some(&spill),
+                )?;
+                info!("Reading back spilled trees");
+                *model.trees_mut() = spill.load_trees(model.settings)?;
+                Ok(model)
             }
-            trees
-        };
+            None => self.train_forest(trainer, n_features, start_t, options, warnings, None),
+        }
+    }
 
-        info!(
-            "Model training complete; it took {:.2}s",
-            start_t.elapsed().as_secs_f32()
-        );
-        Model {
-            trees,
+    /// Train the forest; if a spill is given, finished trees are written into it instead of being
+    /// kept in the returned model.
+    fn train_forest(
+        &self,
+        trainer: TreeTrainer,
+        n_features: usize,
+        start_t: time::Instant,
+        options: &TrainOptions,
+        warnings: &Warnings,
+        spill: Option<&TreeSpill>,
+    ) -> Result<Model, TrainError> {
+        info!("Start training forest");
+        let keep_or_spill = |index: usize, tree: TreeNode| match spill {
+            Some(spill) => spill.spill(index, &tree).map(|_| None),
+            None => Ok(Some(tree)),
+        };
+        let mut model = Model {
+            trees: Vec::with_capacity(self.n_trees),
             settings: Settings {
                 n_features,
                 classifier_loss_type: self.linear.loss_type,
+                feature_transform: options.normalization_policy.feature_transform(),
+                n_used_features: None,
+            },
+            label_thresholds: None,
+            training_metadata: TrainingMetadata {
+                cluster_excluded_labels: trainer.excluded_labels.clone(),
+                ..TrainingMetadata::default()
             },
+            inference_limits: InferenceLimits::default(),
+            feature_projection: self.feature_projection,
+            input_profile: None,
+            label_graph: None,
+            original_labels: None,
+            // Already checked to cover the labels of the dataset
+            label_names: options.label_names.clone(),
+            used_features: None,
+            sorted_labels: Default::default(),
+            label_index: Default::default(),
+        };
+        // Indices of the trees trained, which are all of them unless the time budget runs out
+        let mut trained_indices = Vec::with_capacity(self.n_trees);
+        match self.ensemble_mode {
+            EnsembleMode::Independent if !self.train_trees_1_by_1 => {
+                let trees = (0..self.n_trees)
+                    .into_par_iter()
+                    .map(|i| {
+                        if !trainer.should_train_tree(i) {
+                            return Ok(None);
+                        }
+                        Ok(Some(keep_or_spill(i, trainer.train(i, None)?)?))
+                    })
+                    .collect::<Result<Vec<_>, TrainError>>()?;
+                for (i, tree) in trees.into_iter().enumerate() {
+                    if let Some(tree) = tree {
+                        trained_indices.push(i);
+                        model.trees.extend(tree);
+                    }
+                }
+            }
+            EnsembleMode::Independent => {
+                for i in 1..=self.n_trees {
+                    if !trainer.should_train_tree(i - 1) {
+                        continue;
+                    }
+                    trainer.set_progress_message(i);
+                    let tree = trainer.train(i - 1, None)?;
+                    model.trees.extend(keep_or_spill(i - 1, tree)?);
+                    trained_indices.push(i - 1);
+                }
+            }
+            EnsembleMode::Boosted { reweight } => {
+                for i in 1..=self.n_trees {
+                    if !trainer.should_train_tree(i - 1) {
+                        continue;
+                    }
+                    trainer.set_progress_message(i);
+                    let example_weights = if model.trees.is_empty() {
+                        vec![1.; trainer.all_examples.len()]
+                    } else {
+                        trainer.compute_boosting_weights(&model, reweight)
+                    };
+                    model
+                        .training_metadata
+                        .tree_weight_summaries
+                        .push(WeightSummary::new(&example_weights));
+                    model
+                        .trees_mut()
+                        .push(trainer.train(i - 1, Some(example_weights))?);
+                    trained_indices.push(i - 1);
+                }
+            }
+        }
+
+        let memory_usage = trainer.memory.usage();
+        info!(
+            "Peak accounted memory: {} bytes; fell back to sequential training {} times",
+            memory_usage.peak_bytes, memory_usage.n_sequential_fallbacks
+        );
+        model.training_metadata.memory_usage = Some(memory_usage);
+        model.training_metadata.weight_storage_calibration = trainer.weight_storage_calibration;
+        model.training_metadata.label_tree_changes = trainer.label_tree_changes;
+        let mut node_failures = trainer.node_failures.into_inner().unwrap();
+        if !node_failures.is_empty() {
+            warn!("Recovered from {} node failures", node_failures.len());
+        }
+        node_failures.sort_unstable_by(|a, b| a.node.cmp(&b.node));
+        model.training_metadata.node_failures = node_failures;
+        let mut shortcuts = trainer.time_budget_shortcuts.into_inner().unwrap();
+        if !shortcuts.is_empty() {
+            warn!(
+                "Ran out of time budget: skipped {} trees, made {} nodes leaves early, and used \
+                 centroids for {} classifiers",
+                shortcuts.n_trees_skipped,
+                shortcuts.truncated_nodes.len(),
+                shortcuts.centroid_nodes.len()
+            );
         }
+        shortcuts.truncated_nodes.sort_unstable();
+        shortcuts.centroid_nodes.sort_unstable();
+        model.training_metadata.time_budget_shortcuts = shortcuts;
+        model.training_metadata.objective_curves = trainer
+            .objective_curves
+            .into_inner()
+            .unwrap()
+            .into_iter()
+            .sorted_unstable_by_key(|&(depth, _)| depth)
+            .map(|(depth, claimed)| DepthObjectiveCurves {
+                depth,
+                curves: claimed
+                    .into_iter()
+                    .filter_map(|((node, column), curve)| {
+                        Some(ClassifierObjectiveCurve {
+                            node,
+                            column,
+                            curve: curve?,
+                        })
+                    })
+                    .collect(),
+            })
+            .filter(|depth_curves| !depth_curves.curves.is_empty())
+            .collect();
+        if trained_indices
+            .iter()
+            .enumerate()
+            .any(|(i, &index)| i != index)
+        {
+            // Skipped trees leave gaps, so node identifiers are renumbered to match the trees kept
+            model.training_metadata = model
+                .training_metadata
+                .select_trees(self.n_trees, &trained_indices);
+        }
+        warnings.append(trainer.warnings);
+
+        info!(
+            "Model training complete; it took {:.2}s",
+            start_t.elapsed().as_secs_f32()
+        );
+        Ok(model)
     }
 }
 
+/// A test-only hook deciding whether the solver should panic when training the classifier of the
+/// given node with the given hyper-parameters.
+#[cfg(test)]
+type SolverFault = Arc<dyn Fn(&NodeId, &liblinear::HyperParam) -> bool + Send + Sync>;
+
 struct TreeTrainer {
     all_examples: Arc<TrainingExamples>,
     all_labels: Arc<LabelCluster>,
+    excluded_labels: Vec<Index>,
     hyper_param: HyperParam,
     progress_bar: Mutex<ProgressBar>,
+    warnings: Warnings,
+    memory: Arc<MemoryTracker>,
+    node_failures: Mutex<Vec<NodeFailure>>,
+    /// When the time budget runs out, if there is one.
+    deadline: Option<time::Instant>,
+    cancellation: Option<CancellationToken>,
+    /// How long the last finished tree took to train, in any ensemble mode.
+    last_tree_duration: Mutex<Option<time::Duration>>,
+    time_budget_shortcuts: Mutex<TimeBudgetShortcuts>,
+    /// The classifiers claimed for recording objective curves at each depth, by node and column,
+    /// with their curves once recorded.
+    objective_curves:
+        Mutex<HashMap<usize, BTreeMap<(NodeId, usize), Option<liblinear::ObjectiveCurve>>>>,
+    /// The density above which weight matrices are densified once their tree is trained, if any.
+    max_sparse_density: Option<f32>,
+    weight_storage_calibration: Option<WeightStorageCalibration>,
+    /// The label tree to build trees on, adapted to the labels of the training data, if any.
+    label_tree: Option<LabelTree>,
+    label_tree_changes: Option<LabelTreeChanges>,
+    #[cfg(test)]
+    solver_fault: Option<SolverFault>,
 }
 
 impl TreeTrainer {
-    /// Initialize a reusable tree trainer with the dataset and hyper-parameters.
+    /// Initialize a reusable tree trainer with the dataset, hyper-parameters and options.
     ///
     /// Dataset is assumed to be well-formed.
-    fn initialize(mut dataset: DataSet, hyper_param: HyperParam) -> Self {
-        assert_eq!(dataset.feature_lists.len(), dataset.label_sets.len());
-        // l2-normalize all examples in the dataset
-        dataset
-            .feature_lists
-            .par_iter_mut()
-            .for_each(|v| v.l2_normalize());
+    fn initialize(
+        mut dataset: DataSet,
+        hyper_param: HyperParam,
+        options: &TrainOptions,
+    ) -> Result<Self, TrainError> {
+        assert_eq!(dataset.feature_lists.len(), dataset.labels.len());
+        options
+            .normalization_policy
+            .apply(&mut dataset.feature_lists);
 
         // Initialize label clusters
-        let all_labels = Arc::new(LabelCluster::new_from_dataset(
-            &dataset,
-            hyper_param.centroid_threshold,
-        ));
+        let all_labels =
+            LabelCluster::new_from_dataset(&dataset, hyper_param.cluster.centroid_threshold);
 
         // Initialize examples set
         let all_examples = Arc::new(TrainingExamples::new_from_dataset(dataset));
 
+        Self::new(all_examples, all_labels, hyper_param, options)
+    }
+
+    /// Initialize a reusable tree trainer with a memory-mapped dataset, hyper-parameters and
+    /// options.
+    ///
+    /// The mapped feature vectors are already l2-normalized and have bias terms appended.
+    fn initialize_from_mmap(
+        dataset: &MmapDataSet,
+        hyper_param: HyperParam,
+        options: &TrainOptions,
+    ) -> Result<Self, TrainError> {
+        let all_examples = Arc::new(TrainingExamples::new(
+            FeatureMatrix::Mapped(dataset.feature_matrix.clone()),
+            Arc::new(dataset.labels.clone()),
+            (0..dataset.len()).collect(),
+        ));
+
+        let all_labels = LabelCluster::new_from_examples(
+            &all_examples,
+            dataset.n_features,
+            dataset.n_labels,
+            hyper_param.cluster.centroid_threshold,
+        );
+
+        Self::new(all_examples, all_labels, hyper_param, options)
+    }
+
+    fn new(
+        all_examples: Arc<TrainingExamples>,
+        all_labels: LabelCluster,
+        hyper_param: HyperParam,
+        options: &TrainOptions,
+    ) -> Result<Self, TrainError> {
+        let memory = MemoryTracker::new(hyper_param.memory_budget_bytes);
+        memory
+            .reserve(MemoryPhase::Dataset, all_examples.mem_size())?
+            .persist();
+
         let progress_bar = Mutex::new(create_progress_bar(
             (all_labels.len() * hyper_param.n_trees) as u64,
         ));
 
-        Self {
+        let (all_labels, excluded_labels) =
+            all_labels.exclude_labels(&options.cluster_exclude_labels);
+        if !excluded_labels.is_empty() {
+            info!(
+                "Excluded {} labels from clustering: {:?}",
+                excluded_labels.len(),
+                excluded_labels
+            );
+        }
+
+        let (label_tree, label_tree_changes) = match &options.label_tree {
+            Some(label_tree) => {
+                let (label_tree, changes) = label_tree.adapt(
+                    &all_labels.labels,
+                    &all_labels.feature_matrix,
+                    hyper_param.tree_reuse_policy,
+                )?;
+                if !changes.is_empty() {
+                    info!(
+                        "Adapted label tree to {} new and {} removed labels",
+                        changes.new_labels.len(),
+                        changes.removed_labels.len()
+                    );
+                }
+                (Some(label_tree), Some(changes))
+            }
+            None => (None, None),
+        };
+
+        let weight_storage_calibration = match hyper_param.weight_storage {
+            WeightStorage::SpeedOptimalCalibrated { time_budget } => Some(
+                calibrate_weight_storage(&all_examples, &hyper_param, time_budget),
+            ),
+            _ => None,
+        };
+        let max_sparse_density = match hyper_param.weight_storage {
+            WeightStorage::MemoryOptimal => None,
+            WeightStorage::SpeedOptimal { max_sparse_density } => Some(max_sparse_density),
+            WeightStorage::SpeedOptimalCalibrated { .. } => weight_storage_calibration
+                .as_ref()
+                .map(|calibration| calibration.max_sparse_density),
+        };
+
+        Ok(Self {
             all_examples,
-            all_labels,
-            hyper_param,
+            all_labels: Arc::new(all_labels),
+            excluded_labels,
             progress_bar,
-        }
+            warnings: Warnings::new(),
+            memory,
+            node_failures: Mutex::new(Vec::new()),
+            deadline: hyper_param
+                .time_budget
+                .map(|budget| time::Instant::now() + budget),
+            cancellation: options.cancellation.clone(),
+            last_tree_duration: Mutex::new(None),
+            time_budget_shortcuts: Mutex::new(TimeBudgetShortcuts::default()),
+            objective_curves: Mutex::new(HashMap::new()),
+            max_sparse_density,
+            weight_storage_calibration,
+            label_tree,
+            label_tree_changes,
+            hyper_param,
+            #[cfg(test)]
+            solver_fault: None,
+        })
     }
 
     #[inline]
@@ -169,22 +1428,116 @@ impl TreeTrainer {
             .adapt_to_sample_size(n_examples, self.all_examples.len())
     }
 
-    fn train(&self) -> TreeNode {
-        self.train_subtree(1, self.all_examples.clone(), self.all_labels.clone())
+    fn set_progress_message(&self, tree_index: usize) {
+        self.progress_bar.lock().unwrap().message(&format!(
+            "[Tree {}/{}] ",
+            tree_index, self.hyper_param.n_trees
+        ));
+    }
+
+    /// Train the tree at the given index, optionally weighting training examples for classifiers.
+    fn train(
+        &self,
+        tree_index: usize,
+        example_weights: Option<Vec<f32>>,
+    ) -> Result<TreeNode, TrainError> {
+        let start_t = time::Instant::now();
+        let examples = match example_weights {
+            None => self.all_examples.clone(),
+            Some(example_weights) => Arc::new(self.all_examples.with_weights(example_weights)),
+        };
+        let root = NodeId::root(tree_index);
+        let mut tree = match &self.label_tree {
+            Some(label_tree) => {
+                self.train_fixed_subtree(&root, examples, &label_tree.trees[tree_index])?
+            }
+            None => self.train_subtree(
+                &root,
+                examples,
+                self.all_labels.clone(),
+                &self.excluded_labels,
+            )?,
+        };
+        if let Some(max_sparse_density) = self.max_sparse_density {
+            tree.densify_weights(max_sparse_density);
+        }
+        *self.last_tree_duration.lock().unwrap() = Some(start_t.elapsed());
+        Ok(tree)
+    }
+
+    /// Returns whether the time budget has run out.
+    fn is_out_of_time(&self) -> bool {
+        self.deadline
+            .map_or(false, |deadline| time::Instant::now() >= deadline)
     }
 
+    /// Returns whether to start training the tree at the given index within the time budget,
+    /// recording it as skipped otherwise.
+    fn should_train_tree(&self, tree_index: usize) -> bool {
+        let deadline = match self.deadline {
+            Some(deadline) if tree_index > 0 => deadline,
+            _ => return true,
+        };
+        let now = time::Instant::now();
+        // Assume the tree would take as long as the last one
+        let estimate = self.last_tree_duration.lock().unwrap().unwrap_or_default();
+        if now + estimate < deadline {
+            return true;
+        }
+        info!(
+            "Skipping tree {} since it can't be trained within the time budget",
+            tree_index
+        );
+        self.time_budget_shortcuts.lock().unwrap().n_trees_skipped += 1;
+        false
+    }
+
+    /// Weight training examples by the loss of beam-1 predictions from the trees trained so far.
+    fn compute_boosting_weights(&self, model: &Model, reweight: ReweightFn) -> Vec<f32> {
+        let feature_matrix = self.all_examples.feature_matrix.view();
+        // Training examples are already normalized and have bias terms appended, so they can be
+        // used directly as prepared feature vectors
+        let mut example_weights = (0..self.all_examples.len())
+            .into_par_iter()
+            .map(|i| {
+                let feature_vec = feature_matrix.outer_view(i).unwrap().to_owned();
+                let predictions = model.predict_prepared(&feature_vec, 1);
+                1. + reweight.loss(self.all_examples.example_labels(i), &predictions)
+            })
+            .collect::<Vec<f32>>();
+
+        let mean = example_weights.iter().sum::<f32>() / example_weights.len() as f32;
+        example_weights.iter_mut().for_each(|w| *w /= mean);
+        example_weights
+    }
+
+    /// Train a subtree for the given label cluster.
+    ///
+    /// Flat labels are not clustered; if the subtree branches they are attached as an extra leaf
+    /// child, otherwise they are added to the leaf.
     fn train_subtree(
         &self,
-        depth: usize,
+        node: &NodeId,
         examples: Arc<TrainingExamples>,
         label_cluster: Arc<LabelCluster>,
-    ) -> TreeNode {
+        flat_labels: &[Index],
+    ) -> Result<TreeNode, TrainError> {
+        let depth = node.depth();
         // If we haven't reached depth limit, have enough labels for further branching,
         // and also successfully performed clustering, then recursively branch and train subtrees
-        if depth < self.hyper_param.max_depth
-            && label_cluster.len() >= self.hyper_param.min_branch_size
-        {
-            if let Some(mut label_clusters) = label_cluster.split(self.hyper_param.cluster) {
+        if label_cluster.len() >= self.hyper_param.min_branch_size {
+            if self.is_out_of_time() {
+                self.time_budget_shortcuts
+                    .lock()
+                    .unwrap()
+                    .truncated_nodes
+                    .push(node.clone());
+            } else if depth >= self.hyper_param.max_depth {
+                self.warnings.push(Warning::MaxDepthReached {
+                    depth,
+                    n_labels: label_cluster.len(),
+                });
+            } else if let Some(mut label_clusters) = self.split_labels(&label_cluster)? {
                 drop(label_cluster); // No longer needed
                 assert!(label_clusters.len() > 1);
 
@@ -194,18 +1547,19 @@ impl TreeTrainer {
                     let prev_len = label_clusters.len();
                     label_clusters = label_clusters
                         .into_par_iter()
-                        .flat_map(|sub_cluster| {
+                        .map(|sub_cluster| -> Result<_, TrainError> {
                             if sub_cluster.len() >= self.hyper_param.min_branch_size {
-                                if let Some(sub_sub_clusters) =
-                                    sub_cluster.split(self.hyper_param.cluster)
-                                {
-                                    return sub_sub_clusters;
+                                if let Some(sub_sub_clusters) = self.split_labels(&sub_cluster)? {
+                                    return Ok(sub_sub_clusters);
                                 }
                             }
                             // Return without further clustering if it's too small or
                             // fails to split
-                            vec![sub_cluster]
+                            Ok(vec![sub_cluster])
                         })
+                        .collect::<Result<Vec<_>, TrainError>>()?
+                        .into_iter()
+                        .flatten()
                         .collect();
 
                     // Break early if no more sub-clusters were created
@@ -214,119 +1568,533 @@ impl TreeTrainer {
                     }
                 }
 
-                self.progress_bar.lock().unwrap().total += label_clusters.len() as u64;
+                let n_clusters = label_clusters.len();
+                let n_children = n_clusters + usize::from(!flat_labels.is_empty());
+                self.progress_bar.lock().unwrap().total += n_children as u64;
 
-                let example_index_lists = label_clusters
+                let mut example_index_lists = label_clusters
                     .par_iter()
                     .map(|cluster| examples.find_examples_with_labels(&cluster.labels))
                     .collect::<Vec<_>>();
+                if !flat_labels.is_empty() {
+                    example_index_lists.push(examples.find_examples_with_labels(flat_labels));
+                }
 
-                let (children, weights) = rayon::join(
-                    {
-                        let examples = examples.clone();
-                        || {
-                            self.train_child_nodes(
-                                depth,
-                                examples,
-                                label_clusters,
-                                &example_index_lists,
-                            )
+                // Children and the classifier of this node are trained in parallel, unless their
+                // copies of the examples don't fit in the memory budget together
+                let copy_size = example_index_lists
+                    .iter()
+                    .map(|indices| examples.subset_mem_size(indices))
+                    .sum::<usize>()
+                    + examples.feature_mem_size();
+                let parallel = self.memory.fits(copy_size);
+                if !parallel {
+                    self.memory.record_sequential_fallback();
+                }
+
+                let train_children = {
+                    let examples = examples.clone();
+                    || -> Result<_, TrainError> {
+                        let examples = examples; // Move the Arc into this closure
+                        let mut children = self.train_child_nodes(
+                            node,
+                            examples.clone(),
+                            label_clusters,
+                            &example_index_lists[..n_clusters],
+                            parallel,
+                        )?;
+                        if !flat_labels.is_empty() {
+                            let flat_examples =
+                                self.take_examples(&examples, &example_index_lists[n_clusters])?;
+                            children.push(self.train_leaf_node(
+                                &node.child(n_clusters),
+                                Arc::new(flat_examples),
+                                flat_labels,
+                            )?);
                         }
-                    },
-                    || {
-                        self.train_classifier(
-                            examples, // NB: the Arc "examples" is moved into this closure
-                            &example_index_lists,
-                        )
-                    },
-                );
+                        Ok(children)
+                    }
+                };
+                let train_classifier = || {
+                    self.train_classifier(
+                        node,
+                        examples, // NB: the Arc "examples" is moved into this closure
+                        &example_index_lists,
+                    )
+                };
 
-                return TreeNode::Branch { weights, children };
+                let (children, weights) = if parallel {
+                    let (children, weights) = rayon::join(train_children, train_classifier);
+                    (children?, weights?)
+                } else {
+                    // The classifier's copy of the examples is released before children make theirs
+                    let weights = train_classifier()?;
+                    (train_children()?, weights)
+                };
+                return Ok(TreeNode::Branch { weights, children });
+            } else {
+                self.warnings.push(Warning::ClusteringFailed {
+                    depth,
+                    n_labels: label_cluster.len(),
+                });
             }
         }
 
         // Otherwise stop branching and train a leaf node
-        self.train_leaf_node(examples, &label_cluster.labels)
+        let leaf_labels = label_cluster
+            .labels
+            .iter()
+            .chain(flat_labels)
+            .cloned()
+            .collect_vec();
+        self.train_leaf_node(node, examples, &leaf_labels)
+    }
+
+    /// Train a subtree with the structure of the given label tree node instead of clustering.
+    fn train_fixed_subtree(
+        &self,
+        node: &NodeId,
+        examples: Arc<TrainingExamples>,
+        label_tree_node: &LabelTreeNode,
+    ) -> Result<TreeNode, TrainError> {
+        let label_tree_children = match label_tree_node {
+            LabelTreeNode::Branch(children) => children,
+            LabelTreeNode::Leaf(labels) => return self.train_leaf_node(node, examples, labels),
+        };
+        self.progress_bar.lock().unwrap().total += label_tree_children.len() as u64;
+
+        let example_index_lists = label_tree_children
+            .par_iter()
+            .map(|child| examples.find_examples_with_labels(&child.labels()))
+            .collect::<Vec<_>>();
+
+        // As in train_subtree, children are only trained in parallel with the classifier of this
+        // node if their copies of the examples fit in the memory budget together
+        let copy_size = example_index_lists
+            .iter()
+            .map(|indices| examples.subset_mem_size(indices))
+            .sum::<usize>()
+            + examples.feature_mem_size();
+        let parallel = self.memory.fits(copy_size);
+        if !parallel {
+            self.memory.record_sequential_fallback();
+        }
+
+        let train_children = {
+            let examples = examples.clone();
+            || -> Result<Vec<_>, TrainError> {
+                let examples = examples; // Move the Arc into this closure
+                let train_child = |index: usize, example_indices: &[usize]| {
+                    let child_examples = self.take_examples(&examples, example_indices)?;
+                    self.train_fixed_subtree(
+                        &node.child(index),
+                        Arc::new(child_examples),
+                        &label_tree_children[index],
+                    )
+                };
+                if parallel {
+                    example_index_lists
+                        .par_iter()
+                        .enumerate()
+                        .map(|(i, example_indices)| train_child(i, example_indices))
+                        .collect()
+                } else {
+                    example_index_lists
+                        .iter()
+                        .enumerate()
+                        .map(|(i, example_indices)| train_child(i, example_indices))
+                        .collect()
+                }
+            }
+        };
+        let train_classifier = || self.train_classifier(node, examples, &example_index_lists);
+
+        let (children, weights) = if parallel {
+            let (children, weights) = rayon::join(train_children, train_classifier);
+            (children?, weights?)
+        } else {
+            let weights = train_classifier()?;
+            (train_children()?, weights)
+        };
+        Ok(TreeNode::Branch { weights, children })
+    }
+
+    /// Split a label cluster, accounting for the memory used by clustering.
+    fn split_labels(
+        &self,
+        label_cluster: &LabelCluster,
+    ) -> Result<Option<Vec<LabelCluster>>, TrainError> {
+        let _reservation = self.memory.reserve(
+            MemoryPhase::Clustering,
+            label_cluster.split_mem_size(self.hyper_param.cluster.k),
+        )?;
+        Ok(label_cluster.split(self.hyper_param.cluster))
+    }
+
+    /// Copy the examples at the given indices, accounting for the memory used by the copy until
+    /// it's dropped.
+    fn take_examples(
+        &self,
+        examples: &TrainingExamples,
+        indices: &[usize],
+    ) -> Result<TrainingExamples, TrainError> {
+        let reservation = self.memory.reserve(
+            MemoryPhase::ExampleCopies,
+            examples.subset_mem_size(indices),
+        )?;
+        Ok(TrainingExamples {
+            reservation: Some(reservation),
+            ..examples.take_examples_by_indices(indices)
+        })
     }
 
     fn train_child_nodes(
         &self,
-        depth: usize,
+        node: &NodeId,
         examples: Arc<TrainingExamples>,
         label_clusters: Vec<LabelCluster>,
         example_index_lists: &[Vec<usize>],
-    ) -> Vec<TreeNode> {
-        // NB: the examples arc itself is moved when creating this vector of clones
-        let example_arcs = vec![examples; label_clusters.len()];
-        label_clusters
-            .into_par_iter()
-            .zip_eq(example_index_lists.par_iter())
-            .zip_eq(example_arcs.into_par_iter())
-            .map(|((label_cluster, example_indices), examples)| {
-                let cluster_examples = examples.take_examples_by_indices(example_indices);
-                drop(examples); // No longer needed
-                self.train_subtree(
-                    depth + 1,
-                    Arc::new(cluster_examples),
-                    Arc::new(label_cluster),
-                )
-            })
-            .collect()
+        parallel: bool,
+    ) -> Result<Vec<TreeNode>, TrainError> {
+        let train_child = |index: usize,
+                           label_cluster: LabelCluster,
+                           example_indices: &[usize],
+                           examples: Arc<TrainingExamples>| {
+            let cluster_examples = self.take_examples(&examples, example_indices)?;
+            drop(examples); // No longer needed
+            self.train_subtree(
+                &node.child(index),
+                Arc::new(cluster_examples),
+                Arc::new(label_cluster),
+                &[],
+            )
+        };
+
+        if parallel {
+            // NB: the examples arc itself is moved when creating this vector of clones
+            let example_arcs = vec![examples; label_clusters.len()];
+            label_clusters
+                .into_par_iter()
+                .zip_eq(example_index_lists.par_iter())
+                .zip_eq(example_arcs.into_par_iter())
+                .enumerate()
+                .map(|(i, ((label_cluster, example_indices), examples))| {
+                    train_child(i, label_cluster, example_indices, examples)
+                })
+                .collect()
+        } else {
+            // Only one child's copy of the examples exists at a time
+            label_clusters
+                .into_iter()
+                .zip_eq(example_index_lists)
+                .enumerate()
+                .map(|(i, (label_cluster, example_indices))| {
+                    train_child(i, label_cluster, example_indices, examples.clone())
+                })
+                .collect()
+        }
     }
 
-    fn train_leaf_node(&self, examples: Arc<TrainingExamples>, leaf_labels: &[Index]) -> TreeNode {
+    fn train_leaf_node(
+        &self,
+        node: &NodeId,
+        examples: Arc<TrainingExamples>,
+        leaf_labels: &[Index],
+    ) -> Result<TreeNode, TrainError> {
         let weights = {
             let example_index_lists = leaf_labels
                 .par_iter()
                 .map(|&label| examples.find_examples_with_label(label))
                 .collect::<Vec<_>>();
-            self.train_classifier(examples, &example_index_lists)
+            self.train_classifier(node, examples, &example_index_lists)?
         };
-        TreeNode::Leaf {
+        Ok(TreeNode::Leaf {
             weights,
             labels: leaf_labels.to_vec(),
-        }
+        })
     }
 
     fn train_classifier(
         &self,
+        node: &NodeId,
         examples: Arc<TrainingExamples>,
         label_to_example_indices: &[Vec<usize>],
-    ) -> WeightMat {
-        let weights = if !self.hyper_param.tree_structure_only {
-            self.classifier_hyper_param(examples.len())
-                .train(&examples.feature_matrix.view(), label_to_example_indices)
-        } else {
+    ) -> Result<WeightMat, TrainError> {
+        if self
+            .cancellation
+            .as_ref()
+            .map_or(false, CancellationToken::is_cancelled)
+        {
+            return Err(TrainError::Cancelled);
+        }
+        let weights = if self.hyper_param.tree_structure_only {
             WeightMat::Sparse(LilMat::new((
                 label_to_example_indices.len(),
-                examples.feature_matrix.cols(),
+                examples.feature_matrix.view().cols(),
             )))
+        } else if self.is_out_of_time() {
+            self.time_budget_shortcuts
+                .lock()
+                .unwrap()
+                .centroid_nodes
+                .push(node.clone());
+            centroid_classifier(&examples.feature_matrix.view(), label_to_example_indices)
+        } else {
+            // The solver works on its own copy of the feature matrix
+            let _reservation = self
+                .memory
+                .reserve(MemoryPhase::ExampleCopies, examples.feature_mem_size())?;
+            self.train_classifier_or_recover(node, &examples, label_to_example_indices)?
         };
 
         assert_eq!(weights.shape().1, label_to_example_indices.len());
+        self.memory
+            .reserve(MemoryPhase::ClassifierWeights, weights.mem_size())?
+            .persist();
         self.progress_bar
             .lock()
             .expect("Failed to lock progress bar")
             .add(label_to_example_indices.len() as u64);
 
-        weights
+        Ok(weights)
+    }
+}
+
+impl TreeTrainer {
+    /// Train the classifier of a node, turning solver panics into errors and applying the node
+    /// failure policy to them.
+    fn train_classifier_or_recover(
+        &self,
+        node: &NodeId,
+        examples: &TrainingExamples,
+        label_to_example_indices: &[Vec<usize>],
+    ) -> Result<WeightMat, TrainError> {
+        let n_recorded = self.claim_objective_curves(node, label_to_example_indices.len());
+        let attempt = |hyper_param: liblinear::HyperParam| {
+            panic::catch_unwind(AssertUnwindSafe(|| {
+                #[cfg(test)]
+                if let Some(solver_fault) = &self.solver_fault {
+                    if solver_fault(node, &hyper_param) {
+                        panic!("Injected solver failure");
+                    }
+                }
+                let (weights, curves) = hyper_param.train_recording_objective(
+                    &examples.feature_matrix.view(),
+                    label_to_example_indices,
+                    examples.example_weights.as_deref(),
+                    n_recorded,
+                )?;
+                if !curves.is_empty() {
+                    let mut objective_curves = self.objective_curves.lock().unwrap();
+                    let claimed = objective_curves.entry(node.depth()).or_default();
+                    for (column, curve) in curves.into_iter().enumerate() {
+                        // Claims taken over by earlier classifiers since are dropped
+                        if let Some(slot) = claimed.get_mut(&(node.clone(), column)) {
+                            *slot = Some(curve);
+                        }
+                    }
+                }
+                Ok(weights)
+            }))
+            .map_err(|payload| panic_message(&*payload))
+            .and_then(|result| result)
+        };
+
+        let hyper_param = self.classifier_hyper_param(examples.len());
+        let message = match attempt(hyper_param) {
+            Ok(weights) => return Ok(weights),
+            Err(message) => message,
+        };
+        warn!(
+            "Training the classifier of node {:?} in tree {} failed: {}",
+            node.path(),
+            node.tree(),
+            message
+        );
+
+        let (weights, recovery) = match self.hyper_param.node_failure_policy {
+            NodeFailurePolicy::Abort => {
+                return Err(TrainError::NodeFailed {
+                    node: node.clone(),
+                    message,
+                });
+            }
+            NodeFailurePolicy::Retry => {
+                let safer_hyper_param = liblinear::HyperParam {
+                    c: hyper_param.c * RETRY_C_FACTOR,
+                    max_iter: hyper_param.max_iter.saturating_mul(RETRY_MAX_ITER_FACTOR),
+                    ..hyper_param
+                };
+                let weights =
+                    attempt(safer_hyper_param).map_err(|retry_message| TrainError::NodeFailed {
+                        node: node.clone(),
+                        message: format!("{}; retry failed: {}", message, retry_message),
+                    })?;
+                (weights, NodeRecovery::Retried)
+            }
+            NodeFailurePolicy::CentroidFallback => (
+                centroid_classifier(&examples.feature_matrix.view(), label_to_example_indices),
+                NodeRecovery::CentroidFallback,
+            ),
+        };
+        self.node_failures.lock().unwrap().push(NodeFailure {
+            node: node.clone(),
+            message,
+            recovery,
+        });
+        Ok(weights)
+    }
+
+    /// Claim the objective curves of the node's first classifiers that are among the first
+    /// [`MAX_OBJECTIVE_CURVES_PER_DEPTH`] at its depth by node and column of those claimed so far,
+    /// returning the number claimed.
+    ///
+    /// Claims of later classifiers that no longer fit are given up, so whatever order nodes are
+    /// claimed in, the claims left in the end are those of the first classifiers at each depth.
+    fn claim_objective_curves(&self, node: &NodeId, n_classifiers: usize) -> usize {
+        if !self.hyper_param.record_objective {
+            return 0;
+        }
+        let mut objective_curves = self.objective_curves.lock().unwrap();
+        let claimed = objective_curves.entry(node.depth()).or_default();
+        let n_before = claimed.range(..(node.clone(), 0)).count();
+        let n_recorded = n_classifiers.min(MAX_OBJECTIVE_CURVES_PER_DEPTH.saturating_sub(n_before));
+        for column in 0..n_recorded {
+            claimed.insert((node.clone(), column), None);
+        }
+        while claimed.len() > MAX_OBJECTIVE_CURVES_PER_DEPTH {
+            let last = claimed.keys().next_back().unwrap().clone();
+            claimed.remove(&last);
+        }
+        n_recorded
+    }
+}
+
+/// Time sparse and dense weight storage on matrices shaped like the classifiers of branches and
+/// leaves, with inputs as sparse as the average training example, and find the density where
+/// dense storage becomes faster.
+fn calibrate_weight_storage(
+    examples: &TrainingExamples,
+    hyper_param: &HyperParam,
+    time_budget: time::Duration,
+) -> WeightStorageCalibration {
+    info!("Calibrating weight storage");
+    let start_t = time::Instant::now();
+
+    let feature_matrix = examples.feature_matrix.view();
+    let n_rows = feature_matrix.cols().min(CALIBRATION_MAX_ROWS);
+    let shapes = [hyper_param.cluster.k, hyper_param.min_branch_size]
+        .iter()
+        .map(|&n_cols| (n_rows, n_cols))
+        .dedup()
+        .collect_vec();
+    let input_nnz = (feature_matrix.nnz() / feature_matrix.rows().max(1)).clamp(1, n_rows);
+    let timings =
+        WeightMat::time_storage_formats(&shapes, &CALIBRATION_DENSITIES, input_nnz, time_budget);
+    let max_sparse_density = crossover_density(&CALIBRATION_DENSITIES, &timings);
+
+    info!(
+        "Weight matrices denser than {} will be stored densely; calibration took {:.2}s",
+        max_sparse_density,
+        start_t.elapsed().as_secs_f32()
+    );
+    WeightStorageCalibration {
+        max_sparse_density,
+        shapes,
+        input_nnz,
+        densities: CALIBRATION_DENSITIES.to_vec(),
+        sparse_secs: timings
+            .iter()
+            .map(|&(sparse_secs, _)| sparse_secs)
+            .collect(),
+        dense_secs: timings.iter().map(|&(_, dense_secs)| dense_secs).collect(),
+    }
+}
+
+/// The message of a caught panic.
+fn panic_message(payload: &(dyn std::any::Any + Send)) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        (*message).to_owned()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "solver panicked".to_owned()
+    }
+}
+
+/// A classifier whose weights for each label are the l2-normalized sum of the label's positive
+/// examples, used when the solver fails.
+fn centroid_classifier(
+    feature_matrix: &SparseMatView,
+    label_to_example_indices: &[Vec<usize>],
+) -> WeightMat {
+    let n_features = feature_matrix.cols();
+    let centroids = label_to_example_indices
+        .iter()
+        .map(|indices| {
+            let mut feature_to_sum = HashMap::<Index, f32>::new();
+            for &i in indices {
+                let row = feature_matrix.outer_view(i).unwrap();
+                for (feature, &value) in row.iter() {
+                    *feature_to_sum
+                        .entry(to_index(feature, IndexKind::Feature))
+                        .or_default() += value;
+                }
+            }
+            let mut centroid = feature_to_sum.into_iter().collect_vec();
+            centroid.l2_normalize();
+            centroid.sort_by_index();
+            let (indices, data) = centroid.into_iter().unzip();
+            SparseVec::new(n_features, indices, data)
+        })
+        .collect_vec();
+    WeightMat::from_rows(&centroids)
+}
+
+/// Feature matrix of training examples, which is either owned or borrowed from a mapped file.
+#[derive(Clone)]
+enum FeatureMatrix {
+    Owned(Arc<SparseMat>),
+    Mapped(Arc<MappedCsr>),
+}
+
+impl FeatureMatrix {
+    #[inline]
+    fn view(&self) -> SparseMatView {
+        match self {
+            Self::Owned(mat) => mat.view(),
+            Self::Mapped(mat) => mat.view(),
+        }
     }
 }
 
 /// Internal representation of training examples for training a subtree.
 struct TrainingExamples {
-    feature_matrix: SparseMat,
-    label_sets: Vec<Arc<IndexSet>>,
+    feature_matrix: FeatureMatrix,
+    /// Labels of all examples in the training set, shared between subsets.
+    labels: Arc<LabelMatrix>,
+    /// The row in `labels` of each example.
+    label_rows: Vec<usize>,
+    example_weights: Option<Vec<f32>>,
+    /// Memory accounted for the examples, which is released when they're dropped.
+    reservation: Option<Reservation>,
 }
 
 impl TrainingExamples {
     #[inline]
-    fn new(feature_matrix: SparseMat, label_sets: Vec<Arc<IndexSet>>) -> Self {
-        assert_eq!(feature_matrix.rows(), label_sets.len());
-        assert!(!label_sets.is_empty());
+    fn new(
+        feature_matrix: FeatureMatrix,
+        labels: Arc<LabelMatrix>,
+        label_rows: Vec<usize>,
+    ) -> Self {
+        assert_eq!(feature_matrix.view().rows(), label_rows.len());
+        assert!(!label_rows.is_empty());
         Self {
             feature_matrix,
-            label_sets,
+            labels,
+            label_rows,
+            example_weights: None,
+            reservation: None,
         }
     }
 
@@ -334,12 +2102,12 @@ impl TrainingExamples {
         let DataSet {
             n_features,
             mut feature_lists,
-            label_sets,
+            labels,
             ..
         } = dataset;
 
         // Append bias term to each vector to make training linear classifiers easier
-        let bias_index = n_features as Index;
+        let bias_index = to_index(n_features, IndexKind::Feature);
         feature_lists
             .iter_mut()
             .for_each(|v| v.push((bias_index, 1.)));
@@ -348,22 +2116,79 @@ impl TrainingExamples {
             feature_lists,
             n_features + 1, // + 1 because we added bias term
         );
-        let label_sets = label_sets.into_iter().map(Arc::new).collect_vec();
+        let label_rows = (0..labels.len()).collect_vec();
 
-        Self::new(feature_matrix, label_sets)
+        Self::new(
+            FeatureMatrix::Owned(Arc::new(feature_matrix)),
+            Arc::new(labels),
+            label_rows,
+        )
+    }
+
+    /// The same examples with the given weights, sharing the feature matrix.
+    fn with_weights(&self, example_weights: Vec<f32>) -> Self {
+        assert_eq!(self.len(), example_weights.len());
+        Self {
+            feature_matrix: self.feature_matrix.clone(),
+            labels: self.labels.clone(),
+            label_rows: self.label_rows.clone(),
+            example_weights: Some(example_weights),
+            reservation: None,
+        }
     }
 
     #[inline]
     fn len(&self) -> usize {
-        self.feature_matrix.rows()
+        self.label_rows.len()
+    }
+
+    /// The sorted labels of the example at the given index.
+    #[inline]
+    fn example_labels(&self, i: usize) -> &[Index] {
+        self.labels.row(self.label_rows[i])
+    }
+
+    /// Estimated memory used by the examples; mapped feature matrices are not counted.
+    fn mem_size(&self) -> usize {
+        let feature_size = match self.feature_matrix {
+            FeatureMatrix::Owned(_) => self.feature_mem_size(),
+            FeatureMatrix::Mapped(_) => 0,
+        };
+        feature_size + self.per_example_mem_size() * self.len()
+    }
+
+    /// Estimated memory used by a copy of the feature matrix.
+    fn feature_mem_size(&self) -> usize {
+        let feature_matrix = self.feature_matrix.view();
+        csr_mem_size(feature_matrix.rows(), feature_matrix.nnz())
+    }
+
+    /// Estimated memory used by a copy of the examples at the given indices.
+    fn subset_mem_size(&self, indices: &[usize]) -> usize {
+        let feature_matrix = self.feature_matrix.view();
+        let nnz = indices
+            .iter()
+            .map(|&i| feature_matrix.outer_view(i).unwrap().nnz())
+            .sum();
+        csr_mem_size(indices.len(), nnz) + self.per_example_mem_size() * indices.len()
+    }
+
+    #[inline]
+    fn per_example_mem_size(&self) -> usize {
+        let weight_size = if self.example_weights.is_some() {
+            mem::size_of::<f32>()
+        } else {
+            0
+        };
+        mem::size_of::<usize>() + weight_size
     }
 
     fn find_examples_with_label(&self, label: Index) -> Vec<usize> {
-        self.label_sets
+        self.label_rows
             .par_iter()
             .enumerate()
-            .filter_map(|(i, example_labels)| {
-                if example_labels.contains(&label) {
+            .filter_map(|(i, &row)| {
+                if self.labels.contains(row, label) {
                     Some(i)
                 } else {
                     None
@@ -374,29 +2199,47 @@ impl TrainingExamples {
 
     fn find_examples_with_labels(&self, labels: &[Index]) -> Vec<usize> {
         let labels: IndexSet = labels.iter().cloned().collect();
-        self.label_sets
+        self.label_rows
             .par_iter()
             .enumerate()
-            .filter_map(|(i, example_labels)| {
-                if example_labels.is_disjoint(&labels) {
-                    None
-                } else {
+            .filter_map(|(i, &row)| {
+                if self
+                    .labels
+                    .row(row)
+                    .iter()
+                    .any(|label| labels.contains(label))
+                {
                     Some(i)
+                } else {
+                    None
                 }
             })
             .collect()
     }
 
     fn take_examples_by_indices(&self, indices: &[usize]) -> Self {
-        let new_feature_matrix = self.feature_matrix.copy_outer_dims(indices);
-        let new_label_sets = indices
-            .iter()
-            .map(|&i| self.label_sets[i].clone())
-            .collect_vec();
-        Self::new(new_feature_matrix, new_label_sets)
+        let new_feature_matrix = self.feature_matrix.view().copy_outer_dims(indices);
+        let new_label_rows = indices.iter().map(|&i| self.label_rows[i]).collect_vec();
+        let new_example_weights = self
+            .example_weights
+            .as_ref()
+            .map(|weights| indices.iter().map(|&i| weights[i]).collect_vec());
+        Self {
+            example_weights: new_example_weights,
+            ..Self::new(
+                FeatureMatrix::Owned(Arc::new(new_feature_matrix)),
+                self.labels.clone(),
+                new_label_rows,
+            )
+        }
     }
 }
 
+/// Estimated memory used by a CSR matrix with the given numbers of rows and non-zero elements.
+fn csr_mem_size(n_rows: usize, nnz: usize) -> usize {
+    (n_rows + 1) * mem::size_of::<usize>() + nnz * (mem::size_of::<Index>() + mem::size_of::<f32>())
+}
+
 /// Internal representation of label cluster for building the structure of a subtree.
 struct LabelCluster {
     labels: Vec<Index>,
@@ -420,41 +2263,63 @@ impl LabelCluster {
         Self::new(labels, label_centroids)
     }
 
-    /// Compute centroid feature vectors for labels in a given dataset, pruned with the given threshold.
+    /// Initialize from training examples whose feature vectors have bias terms appended.
+    fn new_from_examples(
+        examples: &TrainingExamples,
+        n_features: usize,
+        n_labels: usize,
+        centroid_threshold: f32,
+    ) -> Self {
+        let feature_matrix = examples.feature_matrix.view();
+        let get_row = |i: usize| {
+            feature_matrix
+                .outer_view(i)
+                .unwrap()
+                .iter()
+                .filter(|&(j, _)| j < n_features) // Skip the bias term
+                .map(|(j, &v)| (to_index(j, IndexKind::Feature), v))
+                .collect_vec()
+        };
+        let label_lists = (0..examples.len()).map(|i| examples.example_labels(i));
+        let (labels, label_centroids) =
+            compute_label_centroids(get_row, label_lists, n_labels, centroid_threshold);
+        let label_centroids = csrmat_from_index_value_pair_lists(label_centroids, n_features);
+        Self::new(labels, label_centroids)
+    }
+
+    /// Compute centroid feature vectors for labels in a given dataset, pruned with the given threshold
+    /// and re-normalized.
     ///
     /// Assumes that dataset is well-formed.
     fn compute_label_centroids(
         dataset: &DataSet,
         threshold: f32,
     ) -> (Vec<Index>, Vec<IndexValueVec>) {
-        info!("Computing label centroids");
-        let mut label_to_feature_to_sum =
-            HashMap::<Index, HashMap<Index, f32>>::with_capacity(dataset.n_labels);
-        let mut pb = create_progress_bar(dataset.feature_lists.len() as u64);
-        pb.message("Examples ");
-        for (features, labels) in izip!(&dataset.feature_lists, &dataset.label_sets) {
-            pb.inc();
-            for &label in labels {
-                let feature_to_sum = label_to_feature_to_sum.entry(label).or_default();
-                for &(feature, value) in features {
-                    *feature_to_sum.entry(feature).or_default() += value;
-                }
-            }
-        }
+        compute_label_centroids(
+            |i| &dataset.feature_lists[i],
+            dataset.labels.iter(),
+            dataset.n_labels,
+            threshold,
+        )
+    }
 
-        let mut pb = create_progress_bar(label_to_feature_to_sum.len() as u64);
-        pb.message("Labels ");
-        label_to_feature_to_sum
-            .into_iter()
-            .map(|(label, feature_to_sum)| {
-                pb.inc();
-                let mut v = feature_to_sum.into_iter().collect_vec();
-                v.l2_normalize();
-                v.prune_with_threshold(threshold);
-                v.sort_by_index();
-                (label, v)
-            })
-            .unzip()
+    /// Remove the given labels from the cluster, returning the remaining cluster and the labels
+    /// removed, both in their original order.
+    ///
+    /// Labels not in the cluster are ignored.
+    fn exclude_labels(self, labels: &[Index]) -> (Self, Vec<Index>) {
+        if labels.is_empty() {
+            return (self, Vec::new());
+        }
+        let labels: IndexSet = labels.iter().cloned().collect();
+        let (excluded_indices, kept_indices): (Vec<_>, Vec<_>) =
+            (0..self.labels.len()).partition(|&i| labels.contains(&self.labels[i]));
+        assert!(
+            !kept_indices.is_empty(),
+            "All labels with examples are excluded from clustering"
+        );
+        let excluded_labels = excluded_indices.iter().map(|&i| self.labels[i]).collect();
+        (self.take_labels_by_indices(&kept_indices), excluded_labels)
     }
 
     fn take_labels_by_indices(&self, indices: &[usize]) -> Self {
@@ -472,6 +2337,17 @@ impl LabelCluster {
         self.feature_matrix.rows()
     }
 
+    /// Estimated memory used while splitting the cluster into `k` clusters, including the
+    /// sub-clusters created.
+    fn split_mem_size(&self, k: usize) -> usize {
+        // k-means keeps dense centroids and similarities, as well as a partition for each label
+        let scratch_size = (self.feature_matrix.cols() + self.len()) * k * mem::size_of::<f32>()
+            + self.len() * mem::size_of::<usize>();
+        let copy_size = csr_mem_size(self.len(), self.feature_matrix.nnz())
+            + self.len() * mem::size_of::<Index>();
+        scratch_size + copy_size
+    }
+
     fn split(&self, hyper_param: cluster::HyperParam) -> Option<Vec<Self>> {
         let clusters = hyper_param.train(&self.feature_matrix.view());
         if clusters.len() > 1 {
@@ -490,6 +2366,8 @@ impl LabelCluster {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::test_util::{reproducible_hyper_param, toy_dataset};
+    use assert_approx_eq::assert_approx_eq;
     use std::iter::FromIterator;
 
     #[test]
@@ -502,11 +2380,7 @@ mod tests {
                 vec![(1, 1.), (3, 2.)],
                 vec![(0, 1.), (3, 2.)],
             ],
-            label_sets: vec![
-                IndexSet::from_iter(vec![0, 1]),
-                IndexSet::from_iter(vec![0, 2]),
-                IndexSet::from_iter(vec![1, 2]),
-            ],
+            labels: LabelMatrix::from_rows(vec![vec![0, 1], vec![0, 2], vec![1, 2]]),
         };
 
         let (labels, vecs) =
@@ -537,7 +2411,9 @@ mod tests {
                             // The first two entries are pruned by the given threshold
                             // (0, 1. / 18f32.sqrt()),
                             // (1, 1. / 18f32.sqrt()),
-                            (3, 4. / 18f32.sqrt()),
+                            // (3, 4. / 18f32.sqrt()),
+                            // and the centroid is then re-normalized
+                            (3, 1.),
                         ]
                     ),
                 ]
@@ -546,4 +2422,1014 @@ mod tests {
             HashMap::<Index, IndexValueVec>::from_iter(labels.into_iter().zip(vecs.into_iter()))
         );
     }
+
+    #[test]
+    fn test_centroid_threshold_renormalizes() {
+        let mut dataset = toy_dataset(60, 6, 0);
+        dataset
+            .feature_lists
+            .iter_mut()
+            .for_each(|v| v.l2_normalize());
+
+        let (labels, dense) = LabelCluster::compute_label_centroids(&dataset, 0.);
+        let (pruned_labels, pruned) = LabelCluster::compute_label_centroids(&dataset, 0.3);
+        assert_eq!(labels, pruned_labels);
+        let nnz = |centroids: &[IndexValueVec]| centroids.iter().map(|v| v.len()).sum::<usize>();
+        assert!(nnz(&pruned) < nnz(&dense));
+        for v in dense.iter().chain(&pruned) {
+            assert!(!v.is_empty());
+            assert_approx_eq!(1., v.iter().map(|(_, x)| x * x).sum::<f32>(), 1e-5);
+        }
+
+        // Pruning changes the similarities that clustering is based on
+        let dense = csrmat_from_index_value_pair_lists(dense, dataset.n_features);
+        let pruned = csrmat_from_index_value_pair_lists(pruned, dataset.n_features);
+        let dense_similarities = &dense * &dense.transpose_view();
+        let pruned_similarities = &pruned * &pruned.transpose_view();
+        assert_ne!(
+            dense_similarities.to_dense(),
+            pruned_similarities.to_dense()
+        );
+
+        for centroids in &[dense, pruned] {
+            let mut clustered = cluster::HyperParam::default()
+                .train(&centroids.view())
+                .into_iter()
+                .flatten()
+                .collect_vec();
+            clustered.sort_unstable();
+            assert_eq!((0..labels.len()).collect_vec(), clustered);
+        }
+    }
+
+    #[test]
+    fn test_train_with_warnings() {
+        let mut hyper_param = HyperParam::default();
+        hyper_param.n_trees = 1;
+        hyper_param.min_branch_size = 2;
+        hyper_param.max_depth = 1;
+
+        let warnings = Warnings::new();
+        hyper_param.train_with_warnings(toy_dataset(30, 5, 0), &warnings);
+        assert_eq!(
+            vec![Warning::MaxDepthReached {
+                depth: 1,
+                n_labels: 5
+            }],
+            warnings.into_vec()
+        );
+    }
+
+    #[test]
+    fn test_normalization_policy() {
+        let dataset = toy_dataset(60, 4, 0);
+        let mut normalized = dataset.clone();
+        normalized
+            .feature_lists
+            .iter_mut()
+            .for_each(|v| v.l2_normalize());
+        let hyper_param = reproducible_hyper_param();
+        let model = hyper_param.train(dataset.clone());
+        assert_eq!(
+            FeatureTransform::L2Normalize,
+            model.schema().feature_transform
+        );
+
+        let with_policy = |normalization_policy| TrainOptions {
+            normalization_policy,
+            ..TrainOptions::default()
+        };
+        let strict = with_policy(NormalizationPolicy::RequireNormalized { tolerance: 1e-4 });
+        let norm = dataset.feature_lists[0]
+            .iter()
+            .map(|(_, v)| v * v)
+            .sum::<f32>()
+            .sqrt();
+        assert_eq!(
+            Err(TrainError::NotNormalized { example: 0, norm }),
+            hyper_param
+                .try_train_with_options(dataset.clone(), &strict, &Warnings::new())
+                .map(|_| ())
+        );
+        let strict_model = hyper_param
+            .try_train_with_options(normalized.clone(), &strict, &Warnings::new())
+            .unwrap();
+        // Inputs are checked as given, not as projected, which changes their norms
+        let projecting = HyperParam {
+            feature_projection: Some(ProjectionParams {
+                n_components: 3,
+                nnz_per_feature: 1,
+                seed: 0,
+            }),
+            ..hyper_param
+        };
+        assert!(projecting
+            .try_train_with_options(normalized.clone(), &strict, &Warnings::new())
+            .is_ok());
+        assert!(projecting
+            .try_train_with_options(dataset.clone(), &strict, &Warnings::new())
+            .is_err());
+        assert_eq!(
+            FeatureTransform::L2Normalize,
+            strict_model.schema().feature_transform
+        );
+
+        // Data used as given is also predicted on as given, so that scaled inputs score
+        // differently, while normalized ones score like in a model that normalizes them itself
+        let unnormalized_model = hyper_param
+            .try_train_with_options(
+                normalized.clone(),
+                &with_policy(NormalizationPolicy::None),
+                &Warnings::new(),
+            )
+            .unwrap();
+        assert_eq!(
+            FeatureTransform::Identity,
+            unnormalized_model.schema().feature_transform
+        );
+        for (raw, normalized) in dataset.feature_lists.iter().zip(&normalized.feature_lists) {
+            let expected = model.predict(raw, 10);
+            for actual in [
+                strict_model.predict(raw, 10),
+                unnormalized_model.predict(normalized, 10),
+            ] {
+                assert_eq!(expected.len(), actual.len());
+                for (&(label, score), &(actual_label, actual_score)) in expected.iter().zip(&actual)
+                {
+                    assert_eq!(label, actual_label);
+                    assert_approx_eq!(score, actual_score, 1e-4);
+                }
+            }
+            assert_ne!(expected, unnormalized_model.predict(raw, 10));
+        }
+
+        let mut buffer = Vec::new();
+        unnormalized_model.save_to_writer(&mut buffer).unwrap();
+        let loaded = Model::load_from_reader(&buffer[..]).unwrap();
+        assert_eq!(
+            FeatureTransform::Identity,
+            loaded.schema().feature_transform
+        );
+
+        assert!(
+            with_policy(NormalizationPolicy::RequireNormalized { tolerance: -1. })
+                .validate_for(&hyper_param, dataset.n_labels)
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_memory_budget() {
+        let dataset = toy_dataset(100, 8, 0);
+        let hyper_param = HyperParam {
+            n_trees: 2,
+            min_branch_size: 2,
+            ..HyperParam::default()
+        };
+        let model = hyper_param.train(dataset.clone());
+        let usage = model.training_metadata().memory_usage.clone().unwrap();
+        assert_eq!(None, usage.budget_bytes);
+        assert_eq!(0, usage.n_sequential_fallbacks);
+        let (phase, dataset_bytes) = usage.peak_bytes_by_phase[0];
+        assert_eq!(MemoryPhase::Dataset, phase);
+        assert!(dataset_bytes > 0 && usage.peak_bytes > dataset_bytes);
+
+        // A tiny budget fails before training starts
+        let tiny = HyperParam {
+            memory_budget_bytes: Some(1),
+            ..hyper_param
+        };
+        match tiny.try_train_with_warnings(dataset.clone(), &Warnings::new()) {
+            Err(TrainError::MemoryBudgetExceeded { phase, .. }) => {
+                assert_eq!(MemoryPhase::Dataset, phase)
+            }
+            Ok(_) => panic!("Training should have failed"),
+            Err(e) => panic!("Unexpected error: {}", e),
+        }
+
+        // Tighter budgets either fall back to sequential training and stay within the budget, or
+        // fail with an error, but never abort
+        let mut n_succeeded = 0;
+        for &fraction in &[0.1, 0.3, 0.5, 0.8, 1.] {
+            let budget =
+                dataset_bytes + ((usage.peak_bytes - dataset_bytes) as f32 * fraction) as usize;
+            let constrained = HyperParam {
+                memory_budget_bytes: Some(budget),
+                ..hyper_param
+            };
+            match constrained.try_train_with_warnings(dataset.clone(), &Warnings::new()) {
+                Ok(model) => {
+                    let usage = model.training_metadata().memory_usage.clone().unwrap();
+                    assert_eq!(Some(budget), usage.budget_bytes);
+                    assert!(usage.peak_bytes <= budget);
+                    n_succeeded += 1;
+                }
+                Err(TrainError::MemoryBudgetExceeded {
+                    phase,
+                    requested_bytes,
+                    in_use_bytes,
+                    budget_bytes,
+                }) => {
+                    assert_ne!(MemoryPhase::Dataset, phase);
+                    assert_eq!(budget, budget_bytes);
+                    assert!(in_use_bytes + requested_bytes > budget);
+                }
+                Err(e) => panic!("Unexpected error: {}", e),
+            }
+        }
+        // Sequential training needs less than the unconstrained peak
+        assert!(n_succeeded > 0);
+    }
+
+    /// Train with a solver that panics whenever the given fault returns true.
+    fn train_with_solver_fault(
+        hyper_param: &HyperParam,
+        dataset: DataSet,
+        fault: impl Fn(&NodeId, &liblinear::HyperParam) -> bool + Send + Sync + 'static,
+    ) -> Result<Model, TrainError> {
+        let n_features = dataset.n_features;
+        let mut trainer = TreeTrainer::initialize(dataset, *hyper_param, &TrainOptions::default())?;
+        trainer.solver_fault = Some(Arc::new(fault));
+        hyper_param.train_forest(
+            trainer,
+            n_features,
+            time::Instant::now(),
+            &TrainOptions::default(),
+            &Warnings::new(),
+            None,
+        )
+    }
+
+    #[test]
+    fn test_node_failure_policy() {
+        let dataset = toy_dataset(60, 8, 0);
+        let failing = NodeId::root(1).child(0);
+        let fails_at = |node: NodeId| move |n: &NodeId, _: &liblinear::HyperParam| *n == node;
+        let with_policy = |node_failure_policy| HyperParam {
+            n_trees: 2,
+            min_branch_size: 2,
+            node_failure_policy,
+            ..HyperParam::default()
+        };
+
+        let hyper_param = with_policy(NodeFailurePolicy::Abort);
+        match train_with_solver_fault(&hyper_param, dataset.clone(), fails_at(failing.clone())) {
+            Err(TrainError::NodeFailed { node, message }) => {
+                assert_eq!(failing, node);
+                assert_eq!("Injected solver failure", message);
+            }
+            other => panic!("Expected node failure, got {:?}", other),
+        }
+
+        // Only the first attempt fails, so the retry succeeds
+        let hyper_param = with_policy(NodeFailurePolicy::Retry);
+        let c = hyper_param.linear.c;
+        let node = failing.clone();
+        let model = train_with_solver_fault(&hyper_param, dataset.clone(), move |n, linear| {
+            *n == node && linear.c == c
+        })
+        .unwrap();
+        assert_eq!(
+            vec![NodeFailure {
+                node: failing.clone(),
+                message: "Injected solver failure".to_owned(),
+                recovery: NodeRecovery::Retried,
+            }],
+            model.training_metadata().node_failures
+        );
+        assert!(!model.predict(&dataset.feature_lists[0], 5).is_empty());
+
+        // Failed retries fail training
+        match train_with_solver_fault(&hyper_param, dataset.clone(), fails_at(failing.clone())) {
+            Err(TrainError::NodeFailed { node, message }) => {
+                assert_eq!(failing, node);
+                assert!(message.contains("retry failed"));
+            }
+            other => panic!("Expected node failure, got {:?}", other),
+        }
+
+        let hyper_param = with_policy(NodeFailurePolicy::CentroidFallback);
+        let model =
+            train_with_solver_fault(&hyper_param, dataset.clone(), fails_at(failing.clone()))
+                .unwrap();
+        let failures = &model.training_metadata().node_failures;
+        assert_eq!(1, failures.len());
+        assert_eq!(failing, failures[0].node);
+        assert_eq!(NodeRecovery::CentroidFallback, failures[0].recovery);
+        assert!(model.trees[1].is_valid(model.settings));
+        for feature_vec in &dataset.feature_lists {
+            assert!(!model.predict(feature_vec, 5).is_empty());
+        }
+
+        // Failures are renumbered along with the trees kept
+        let kept = model.take_trees(&[1]);
+        assert_eq!(
+            NodeId::root(0).child(0),
+            kept.training_metadata().node_failures[0].node
+        );
+        assert!(model
+            .take_trees(&[0])
+            .training_metadata()
+            .node_failures
+            .is_empty());
+    }
+
+    #[test]
+    fn test_reweight_fn() {
+        let true_labels = [1, 2];
+        let predictions = [(3, 0.9), (2, 0.8)];
+        assert_eq!(
+            1.,
+            ReweightFn::PrecisionAt1.loss(&true_labels, &predictions)
+        );
+        assert_eq!(
+            0.5,
+            ReweightFn::ReciprocalRank.loss(&true_labels, &predictions)
+        );
+        assert_eq!(
+            0.,
+            ReweightFn::ReciprocalRank.loss(&true_labels, &predictions[1..])
+        );
+        assert_eq!(1., ReweightFn::ReciprocalRank.loss(&true_labels, &[]));
+    }
+
+    #[test]
+    fn test_train_boosted() {
+        // Relabel some examples so that the first tree can't rank all of them well
+        let mut dataset = toy_dataset(60, 8, 0);
+        dataset.labels = dataset
+            .label_lists()
+            .enumerate()
+            .map(|(i, labels)| {
+                if i % 4 == 0 {
+                    vec![(i as Index + 3) % 8]
+                } else {
+                    labels.to_vec()
+                }
+            })
+            .collect();
+        let test_dataset = dataset.clone();
+
+        let mut hyper_param = HyperParam::default();
+        hyper_param.n_trees = 2;
+        hyper_param.min_branch_size = 2;
+        hyper_param.ensemble_mode = EnsembleMode::Boosted {
+            reweight: ReweightFn::PrecisionAt1,
+        };
+        let model = hyper_param.train(dataset);
+
+        let summaries = &model.training_metadata().tree_weight_summaries;
+        assert_eq!(2, summaries.len());
+        assert_eq!(
+            WeightSummary {
+                min: 1.,
+                max: 1.,
+                mean: 1.,
+                std_dev: 0.,
+            },
+            summaries[0]
+        );
+        assert_ne!(summaries[0], summaries[1]);
+        assert!(summaries[1].min < summaries[1].max);
+        assert_approx_eq!(1., summaries[1].mean, 1e-5);
+
+        for feature_vec in &test_dataset.feature_lists {
+            assert!(!model.predict(feature_vec, 3).is_empty());
+        }
+
+        let mut buf = Vec::new();
+        model.save_to_writer(&mut buf).unwrap();
+        let loaded = Model::load_from_reader(std::io::Cursor::new(&buf)).unwrap();
+        assert_eq!(summaries, &loaded.training_metadata().tree_weight_summaries);
+    }
+
+    #[test]
+    fn test_cluster_exclude_labels() {
+        // Label 8 is a catch-all label that every example has
+        let with_catch_all = |seed| {
+            let mut dataset = toy_dataset(60, 8, seed);
+            dataset.n_labels += 1;
+            dataset.labels = dataset
+                .label_lists()
+                .map(|labels| labels.iter().cloned().chain(Some(8)).collect_vec())
+                .collect();
+            dataset
+        };
+        let (train_set, test_set) = (with_catch_all(0), with_catch_all(1));
+
+        let mut hyper_param = HyperParam::default();
+        hyper_param.n_trees = 3;
+        hyper_param.min_branch_size = 2;
+        let baseline = hyper_param.train(train_set.clone());
+
+        let options = TrainOptions {
+            cluster_exclude_labels: vec![8],
+            ..TrainOptions::default()
+        };
+        assert!(options.validate_for(&hyper_param, 8).is_err());
+        assert!(options.validate_for(&hyper_param, 1).is_err());
+        let out_of_range = TrainOptions {
+            cluster_exclude_labels: vec![9],
+            ..TrainOptions::default()
+        };
+        assert!(matches!(
+            hyper_param.try_train_with_options(train_set.clone(), &out_of_range, &Warnings::new()),
+            Err(TrainError::InvalidArgument(_))
+        ));
+        let model = hyper_param
+            .try_train_with_options(train_set, &options, &Warnings::new())
+            .unwrap();
+        assert_eq!(vec![8], model.training_metadata().cluster_excluded_labels);
+
+        // Without exclusion, the catch-all label is clustered with the others
+        let excluded_leaf = LabelTreeNode::Leaf(vec![8]);
+        for tree in &baseline.export_label_tree().trees {
+            match tree {
+                LabelTreeNode::Branch(children) => assert!(!children.contains(&excluded_leaf)),
+                LabelTreeNode::Leaf(_) => panic!("Expected the root to branch"),
+            }
+        }
+
+        // With exclusion, it's only in a flat leaf under each root, and absent from the clustering
+        for tree in &model.export_label_tree().trees {
+            match tree {
+                LabelTreeNode::Branch(children) => {
+                    let (excluded, clustered) = children.split_last().unwrap();
+                    assert_eq!(&excluded_leaf, excluded);
+                    let mut clustered_labels = clustered
+                        .iter()
+                        .flat_map(LabelTreeNode::labels)
+                        .collect_vec();
+                    clustered_labels.sort_unstable();
+                    assert_eq!((0..8).collect_vec(), clustered_labels);
+                }
+                LabelTreeNode::Leaf(_) => panic!("Expected the root to branch"),
+            }
+        }
+
+        for feature_vec in &test_set.feature_lists {
+            assert!(model
+                .predict(feature_vec, 10)
+                .iter()
+                .any(|&(label, _)| label == 8));
+        }
+    }
+
+    #[test]
+    fn test_train_cancelled() {
+        let hyper_param = HyperParam::default();
+        let cancellation = CancellationToken::new();
+        let options = TrainOptions {
+            cancellation: Some(cancellation.clone()),
+            ..TrainOptions::default()
+        };
+        assert!(hyper_param
+            .try_train_with_options(toy_dataset(20, 4, 0), &options, &Warnings::new())
+            .is_ok());
+
+        cancellation.cancel();
+        assert_eq!(
+            Some(TrainError::Cancelled),
+            hyper_param
+                .try_train_with_options(toy_dataset(20, 4, 0), &options, &Warnings::new())
+                .err()
+        );
+    }
+
+    #[test]
+    fn test_reuse_label_tree() {
+        let mut hyper_param = HyperParam::default();
+        hyper_param.n_trees = 2;
+        hyper_param.min_branch_size = 2;
+        let model = hyper_param.train(toy_dataset(60, 8, 0));
+        let label_tree = model.export_label_tree();
+        assert_eq!(2, label_tree.n_trees());
+        assert!(label_tree.validate().is_ok());
+
+        // Retrain on new data, where label 7 is gone and label 8 comes with label 0
+        let mut dataset = toy_dataset(60, 8, 1);
+        dataset.n_labels += 1;
+        dataset.labels = dataset
+            .label_lists()
+            .map(|labels| {
+                labels
+                    .iter()
+                    .filter(|&&label| label != 7)
+                    .cloned()
+                    .chain(Some(8).filter(|_| labels.contains(&0)))
+                    .collect_vec()
+            })
+            .collect();
+
+        let mut options = TrainOptions {
+            label_tree: Some(label_tree.clone()),
+            ..TrainOptions::default()
+        };
+        hyper_param.tree_reuse_policy = TreeReusePolicy::Strict;
+        assert_eq!(
+            Some(TrainError::LabelTreeMismatch {
+                n_new_labels: 1,
+                n_removed_labels: 1,
+            }),
+            hyper_param
+                .try_train_with_options(dataset.clone(), &options, &Warnings::new())
+                .err()
+        );
+
+        hyper_param.tree_reuse_policy = TreeReusePolicy::Adapt;
+        let retrained = hyper_param
+            .try_train_with_options(dataset, &options, &Warnings::new())
+            .unwrap();
+        assert_eq!(
+            Some(LabelTreeChanges {
+                new_labels: vec![8],
+                removed_labels: vec![7],
+            }),
+            retrained.training_metadata().label_tree_changes
+        );
+
+        // Each unchanged label shares its leaf with the same unchanged labels as before
+        let leaf_mates = |tree: &LabelTreeNode| {
+            let mut leaves = Vec::new();
+            tree.visit_leaves(&mut |labels| {
+                leaves.push(labels.iter().filter(|&&l| l < 7).cloned().collect_vec())
+            });
+            (0..7)
+                .map(|label| {
+                    leaves
+                        .iter()
+                        .find(|leaf| leaf.contains(&label))
+                        .cloned()
+                        .unwrap()
+                })
+                .collect_vec()
+        };
+        let retrained_tree = retrained.export_label_tree();
+        for (before, after) in label_tree.trees.iter().zip_eq(&retrained_tree.trees) {
+            assert_eq!(leaf_mates(before), leaf_mates(after));
+            let labels = after.labels();
+            assert!(!labels.contains(&7));
+            assert_eq!(1, labels.iter().filter(|&&l| l == 8).count());
+        }
+
+        hyper_param.n_trees = 3;
+        assert!(options.validate_for(&hyper_param, 9).is_err());
+        hyper_param.n_trees = 2;
+        options.cluster_exclude_labels = vec![0];
+        assert!(options.validate_for(&hyper_param, 9).is_err());
+    }
+
+    #[test]
+    fn test_train_on_mmap() {
+        let mut dataset = toy_dataset(60, 6, 0);
+        let path =
+            std::env::temp_dir().join(format!("omikuji-train-mmap-{}.bin", std::process::id()));
+        dataset.save_mmap(&path).unwrap();
+        let mapped = DataSet::open_mmap(&path).unwrap();
+
+        // The mapped examples are exactly what training would build in memory
+        dataset
+            .feature_lists
+            .iter_mut()
+            .for_each(|v| v.l2_normalize());
+        let (labels, vecs) = LabelCluster::compute_label_centroids(&dataset, 0.);
+        let in_memory = TrainingExamples::new_from_dataset(dataset.clone());
+        assert_eq!(
+            in_memory.feature_matrix.view(),
+            mapped.feature_matrix.view()
+        );
+
+        let mapped_examples = TrainingExamples::new(
+            FeatureMatrix::Mapped(mapped.feature_matrix.clone()),
+            Arc::new(mapped.labels.clone()),
+            (0..mapped.len()).collect(),
+        );
+        let mapped_labels = LabelCluster::new_from_examples(
+            &mapped_examples,
+            mapped.n_features,
+            mapped.n_labels,
+            0.,
+        );
+        assert_eq!(labels.len(), mapped_labels.len());
+        let centroids = HashMap::<Index, IndexValueVec>::from_iter(labels.into_iter().zip(vecs));
+        for (label, row) in mapped_labels
+            .labels
+            .iter()
+            .zip(mapped_labels.feature_matrix.outer_iterator())
+        {
+            let expected = &centroids[label];
+            assert_eq!(expected.len(), row.nnz());
+            for (&(i, v), (j, &w)) in expected.iter().zip(row.iter()) {
+                assert_eq!(i as usize, j);
+                assert!((v - w).abs() < 1e-6);
+            }
+        }
+
+        // With a single leaf per tree there's no clustering involved, and since the classifiers
+        // are trained to convergence both models should give (nearly) the same scores
+        let hyper_param = HyperParam {
+            n_trees: 1,
+            linear: liblinear::HyperParam {
+                eps: 1e-4,
+                max_iter: 1000,
+                ..liblinear::HyperParam::default()
+            },
+            ..HyperParam::default()
+        };
+        let mapped_model = hyper_param
+            .train_on_mmap(&mapped, &TrainOptions::default(), &Warnings::new())
+            .unwrap();
+        let model = hyper_param.train(dataset.clone());
+        for feature_vec in &dataset.feature_lists {
+            let expected = model.predict(feature_vec, 10);
+            let actual = HashMap::<Index, f32>::from_iter(mapped_model.predict(feature_vec, 10));
+            assert_eq!(expected.len(), actual.len());
+            for (label, score) in expected {
+                assert!((score - actual[&label]).abs() < 1e-2);
+            }
+        }
+
+        // Warnings are pushed as when training in memory
+        let hyper_param = HyperParam {
+            n_trees: 1,
+            min_branch_size: 2,
+            max_depth: 1,
+            ..HyperParam::default()
+        };
+        let warnings = Warnings::new();
+        hyper_param
+            .train_on_mmap(&mapped, &TrainOptions::default(), &warnings)
+            .unwrap();
+        assert_eq!(
+            vec![Warning::MaxDepthReached {
+                depth: 1,
+                n_labels: 6
+            }],
+            warnings.into_vec()
+        );
+
+        drop(mapped_examples);
+        drop(mapped);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_spill_trees() {
+        let dataset = toy_dataset(60, 8, 0);
+        let spill_dir =
+            std::env::temp_dir().join(format!("omikuji-train-spill-{}", std::process::id()));
+        let hyper_param = reproducible_hyper_param();
+        let in_memory = hyper_param.train(dataset.clone());
+
+        let spilling = TrainOptions {
+            spill_dir: Some(spill_dir.clone()),
+            ..TrainOptions::default()
+        };
+        let reloaded = hyper_param
+            .try_train_with_options(dataset.clone(), &spilling, &Warnings::new())
+            .unwrap();
+        let mut buf = Vec::new();
+        hyper_param
+            .train_to_writer(dataset.clone(), &mut buf, &spilling, &Warnings::new())
+            .unwrap();
+        let streamed = Model::load_from_reader(std::io::Cursor::new(&buf)).unwrap();
+        // Spill files are removed once training is done
+        assert_eq!(0, std::fs::read_dir(&spill_dir).unwrap().count());
+
+        for model in &[reloaded, streamed] {
+            assert_eq!(in_memory.n_trees(), model.n_trees());
+            for feature_vec in &toy_dataset(20, 8, 1).feature_lists {
+                let expected = in_memory.predict(feature_vec, 10);
+                let actual = model.predict(feature_vec, 10);
+                assert_eq!(
+                    expected
+                        .iter()
+                        .map(|&(label, _)| label)
+                        .sorted()
+                        .collect_vec(),
+                    actual
+                        .iter()
+                        .map(|&(label, _)| label)
+                        .sorted()
+                        .collect_vec()
+                );
+                let actual: HashMap<_, _> = actual.into_iter().collect();
+                for (label, score) in expected {
+                    assert_approx_eq!(score, actual[&label], 1e-3);
+                }
+            }
+        }
+        std::fs::remove_dir(&spill_dir).unwrap();
+
+        // Trees with several levels are saved into a directory from the spill as well
+        let hyper_param = HyperParam {
+            n_trees: 3,
+            min_branch_size: 2,
+            ..HyperParam::default()
+        };
+        let model_dir =
+            std::env::temp_dir().join(format!("omikuji-train-to-dir-{}", std::process::id()));
+        for options in &[spilling.clone(), TrainOptions::default()] {
+            let metadata = hyper_param
+                .train_to_dir(dataset.clone(), &model_dir, options, &Warnings::new())
+                .unwrap();
+            if options.spill_dir.is_some() {
+                assert_eq!(0, std::fs::read_dir(&spill_dir).unwrap().count());
+            }
+            let saved = Model::load(&model_dir).unwrap();
+            assert_eq!(3, saved.n_trees());
+            assert!(saved.trees.iter().all(|tree| !tree.is_leaf()));
+            assert_eq!(&metadata, saved.training_metadata());
+            assert_eq!(8, saved.n_labels());
+            std::fs::remove_dir_all(&model_dir).unwrap();
+        }
+        std::fs::remove_dir(&spill_dir).unwrap();
+
+        let boosted = HyperParam {
+            ensemble_mode: EnsembleMode::Boosted {
+                reweight: ReweightFn::PrecisionAt1,
+            },
+            ..hyper_param
+        };
+        assert!(spilling.validate_for(&boosted, 8).is_err());
+    }
+
+    #[test]
+    fn test_time_budget() {
+        let dataset = toy_dataset(60, 8, 0);
+        let hyper_param = HyperParam {
+            n_trees: 3,
+            min_branch_size: 2,
+            ..HyperParam::default()
+        };
+        let model = HyperParam {
+            time_budget: Some(time::Duration::from_secs(3600)),
+            ..hyper_param
+        }
+        .train(dataset.clone());
+        assert_eq!(3, model.n_trees());
+        assert!(model.training_metadata().time_budget_shortcuts.is_empty());
+
+        let spill_dir =
+            std::env::temp_dir().join(format!("omikuji-time-budget-{}", std::process::id()));
+        for &(train_trees_1_by_1, spill) in &[(false, false), (true, false), (false, true)] {
+            let tiny = HyperParam {
+                time_budget: Some(time::Duration::from_nanos(1)),
+                train_trees_1_by_1,
+                ..hyper_param
+            };
+            let options = TrainOptions {
+                spill_dir: if spill { Some(spill_dir.clone()) } else { None },
+                ..TrainOptions::default()
+            };
+            let model = tiny
+                .try_train_with_options(dataset.clone(), &options, &Warnings::new())
+                .unwrap();
+
+            // Only the first tree is trained, and it's cut short right at the root
+            assert_eq!(1, model.n_trees());
+            model.validate().unwrap();
+            let root = NodeId::root(0);
+            assert_eq!(
+                TimeBudgetShortcuts {
+                    n_trees_skipped: 2,
+                    truncated_nodes: vec![root.clone()],
+                    centroid_nodes: vec![root],
+                },
+                model.training_metadata().time_budget_shortcuts
+            );
+
+            let mut buf = Vec::new();
+            model.save_to_writer(&mut buf).unwrap();
+            let loaded = Model::load_from_reader(std::io::Cursor::new(&buf)).unwrap();
+            assert_eq!(
+                model.training_metadata().time_budget_shortcuts,
+                loaded.training_metadata().time_budget_shortcuts
+            );
+            for feature_vec in &dataset.feature_lists {
+                let predictions = model.predict(feature_vec, 5);
+                assert!(!predictions.is_empty());
+                assert_eq!(predictions, loaded.predict(feature_vec, 5));
+            }
+        }
+        std::fs::remove_dir(&spill_dir).unwrap();
+
+        assert!(HyperParam {
+            time_budget: Some(time::Duration::ZERO),
+            ..hyper_param
+        }
+        .validate()
+        .is_err());
+    }
+
+    #[test]
+    fn test_time_budget_estimate() {
+        let hyper_param = HyperParam {
+            n_trees: 3,
+            min_branch_size: 2,
+            time_budget: Some(time::Duration::from_secs(3600)),
+            ..HyperParam::default()
+        };
+        let (trainer, _, _) = hyper_param
+            .initialize_trainer(toy_dataset(60, 8, 0), &TrainOptions::default())
+            .unwrap();
+        // Without a finished tree, trees are started as long as there is time left
+        assert!(trainer.should_train_tree(1));
+
+        // Trees trained in parallel record their durations too
+        (0..2).into_par_iter().for_each(|i| {
+            trainer.train(i, None).unwrap();
+        });
+        let duration = trainer.last_tree_duration.lock().unwrap().unwrap();
+        assert!(duration < time::Duration::from_secs(3600));
+        assert!(trainer.should_train_tree(2));
+
+        // A tree isn't started if the last one took longer than the time left, except the first
+        *trainer.last_tree_duration.lock().unwrap() = Some(time::Duration::from_secs(7200));
+        assert!(!trainer.should_train_tree(2));
+        assert!(trainer.should_train_tree(0));
+        assert_eq!(
+            1,
+            trainer
+                .time_budget_shortcuts
+                .lock()
+                .unwrap()
+                .n_trees_skipped
+        );
+    }
+
+    #[test]
+    fn test_record_objective() {
+        let dataset = toy_dataset(60, 8, 0);
+        let hyper_param = HyperParam {
+            n_trees: 2,
+            min_branch_size: 2,
+            ..HyperParam::default()
+        };
+        let model = hyper_param.train(dataset.clone());
+        assert!(model.training_metadata().objective_curves.is_empty());
+
+        let model = HyperParam {
+            record_objective: true,
+            ..hyper_param
+        }
+        .train(dataset);
+        let objective_curves = &model.training_metadata().objective_curves;
+        assert!(!objective_curves.is_empty());
+        assert_eq!(1, objective_curves[0].depth);
+        for (i, depth_curves) in objective_curves.iter().enumerate() {
+            if i > 0 {
+                assert!(objective_curves[i - 1].depth < depth_curves.depth);
+            }
+            assert!(!depth_curves.curves.is_empty());
+            assert!(depth_curves.curves.len() <= MAX_OBJECTIVE_CURVES_PER_DEPTH);
+            for curve in &depth_curves.curves {
+                assert_eq!(depth_curves.depth, curve.node.depth());
+                assert!(curve.node.tree() < 2);
+                assert!(curve.curve.values.len() >= 2);
+            }
+        }
+
+        // The curves are those of the first classifiers at each depth, whatever the scheduling
+        fn collect_classifiers(
+            node: &TreeNode,
+            id: NodeId,
+            classifiers: &mut Vec<(usize, NodeId, usize)>,
+        ) {
+            let (weights, children) = match node {
+                TreeNode::Branch { weights, children } => (weights, &children[..]),
+                TreeNode::Leaf { weights, .. } => (weights, &[][..]),
+            };
+            for column in 0..weights.shape().1 {
+                classifiers.push((id.depth(), id.clone(), column));
+            }
+            for (i, child) in children.iter().enumerate() {
+                collect_classifiers(child, id.child(i), classifiers);
+            }
+        }
+        let mut classifiers = Vec::new();
+        for (i, tree) in model.trees.iter().enumerate() {
+            collect_classifiers(tree, NodeId::root(i), &mut classifiers);
+        }
+        classifiers.sort_unstable();
+        let expected = classifiers
+            .into_iter()
+            .group_by(|&(depth, _, _)| depth)
+            .into_iter()
+            .flat_map(|(_, classifiers)| classifiers.take(MAX_OBJECTIVE_CURVES_PER_DEPTH))
+            .map(|(_, node, column)| (node, column))
+            .collect_vec();
+        let recorded = objective_curves
+            .iter()
+            .flat_map(|depth_curves| &depth_curves.curves)
+            .map(|curve| (curve.node.clone(), curve.column))
+            .collect_vec();
+        assert_eq!(expected, recorded);
+
+        let mut csv = Vec::new();
+        model
+            .training_metadata()
+            .write_objective_curves_csv(&mut csv, FloatFormat::Shortest)
+            .unwrap();
+        let csv = String::from_utf8(csv).unwrap();
+        let mut lines = csv.lines();
+        assert_eq!(
+            Some("depth,tree,path,column,iteration,objective"),
+            lines.next()
+        );
+        // Objectives are written in the shortest form that parses back to the same values
+        let values = objective_curves
+            .iter()
+            .flat_map(|depth_curves| &depth_curves.curves)
+            .flat_map(|curve| curve.curve.values.iter().copied())
+            .collect_vec();
+        let parsed_values = lines
+            .map(|line| line.rsplit(',').next().unwrap().parse::<f32>().unwrap())
+            .collect_vec();
+        assert_eq!(values, parsed_values);
+        assert!(csv.contains("\n1,0,,0,0,"));
+
+        let selected = model.training_metadata().select_trees(2, &[1]);
+        for depth_curves in &selected.objective_curves {
+            assert!(depth_curves.curves.iter().all(|c| c.node.tree() == 0));
+        }
+    }
+
+    #[test]
+    fn test_weight_storage() {
+        let dataset = toy_dataset(60, 8, 0);
+        let n_rows = dataset.n_features + 1;
+        let hyper_param = HyperParam {
+            n_trees: 2,
+            min_branch_size: 2,
+            ..HyperParam::default()
+        };
+        let count_dense = |model: &Model| {
+            let (mut n_dense, mut n_total) = (0, 0);
+            for tree in &model.trees {
+                tree.visit_weights(&mut |weights| {
+                    n_total += 1;
+                    n_dense += weights.is_dense() as usize;
+                });
+            }
+            (n_dense, n_total)
+        };
+        let memory_optimal = hyper_param.train(dataset.clone());
+        assert!(memory_optimal
+            .training_metadata()
+            .weight_storage_calibration
+            .is_none());
+        let (_, n_total) = count_dense(&memory_optimal);
+
+        let speed_optimal = HyperParam {
+            weight_storage: WeightStorage::SpeedOptimal {
+                max_sparse_density: 0.,
+            },
+            ..hyper_param
+        }
+        .train(dataset.clone());
+        assert_eq!((n_total, n_total), count_dense(&speed_optimal));
+        for feature_vec in &dataset.feature_lists {
+            let expected = memory_optimal.predict(feature_vec, 5);
+            let actual = speed_optimal.predict(feature_vec, 5);
+            assert_eq!(expected.len(), actual.len());
+            for (&(label, score), &(actual_label, actual_score)) in expected.iter().zip(&actual) {
+                assert_eq!(label, actual_label);
+                assert_approx_eq!(score, actual_score, 1e-5);
+            }
+        }
+
+        let calibrated = HyperParam {
+            weight_storage: WeightStorage::SpeedOptimalCalibrated {
+                time_budget: time::Duration::from_millis(50),
+            },
+            ..hyper_param
+        }
+        .train(dataset);
+        let calibration = calibrated
+            .training_metadata()
+            .weight_storage_calibration
+            .as_ref()
+            .unwrap();
+        assert!(calibration.max_sparse_density > 0. && calibration.max_sparse_density <= 1.);
+        assert_eq!(CALIBRATION_DENSITIES.len(), calibration.sparse_secs.len());
+        assert_eq!(CALIBRATION_DENSITIES.len(), calibration.dense_secs.len());
+        assert!(calibration.shapes.iter().all(|&shape| shape.0 == n_rows));
+
+        for weight_storage in [
+            WeightStorage::SpeedOptimal {
+                max_sparse_density: 1.5,
+            },
+            WeightStorage::SpeedOptimalCalibrated {
+                time_budget: time::Duration::ZERO,
+            },
+        ] {
+            assert!(HyperParam {
+                weight_storage,
+                ..hyper_param
+            }
+            .validate()
+            .is_err());
+        }
+    }
 }
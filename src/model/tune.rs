@@ -0,0 +1,433 @@
+//! Budget-aware random search over training hyper-parameters.
+//!
+//! Each trial samples a configuration from a [`SearchSpace`], trains on a subsample of the
+//! training part of the dataset, and scores the resulting model on a held-out split.
+use super::eval::{self, Metric};
+use super::{liblinear, train, Model};
+use crate::{CancellationToken, DataSet, Warnings};
+use log::info;
+use ordered_float::NotNan;
+use rand::prelude::*;
+use rand::rngs::StdRng;
+use serde::{Deserialize, Serialize};
+use std::cmp::Reverse;
+use std::time;
+
+/// Ranges and choices from which trial hyper-parameters are sampled.
+///
+/// Settings not covered by the search space are taken from `base`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SearchSpace {
+    /// Base hyper-parameters that sampled values are written into.
+    pub base: train::HyperParam,
+    /// Inclusive range of the liblinear cost coefficient, sampled log-uniformly.
+    pub linear_c: (f32, f32),
+    /// Loss types to choose from.
+    pub loss_types: Vec<liblinear::LossType>,
+    /// Inclusive range of the number of trees.
+    pub n_trees: (usize, usize),
+    /// Numbers of clusters to choose from.
+    pub cluster_k: Vec<usize>,
+    /// Whether to use balanced clustering, to choose from.
+    pub cluster_balanced: Vec<bool>,
+    /// Minimum branch sizes to choose from.
+    pub min_branch_size: Vec<usize>,
+    /// Fraction of the training split each trial is trained on.
+    pub subsample_fraction: f32,
+    /// Fraction of the dataset held out for scoring trials.
+    pub validation_fraction: f32,
+    /// Whether to retrain the best configuration on the full dataset once the search finishes.
+    pub retrain_best: bool,
+}
+
+impl Default for SearchSpace {
+    fn default() -> Self {
+        let base = train::HyperParam::default();
+        Self {
+            base,
+            linear_c: (0.1, 10.),
+            loss_types: vec![liblinear::LossType::Hinge, liblinear::LossType::Log],
+            n_trees: (1, base.n_trees),
+            cluster_k: vec![2, 4, 8],
+            cluster_balanced: vec![true, false],
+            min_branch_size: vec![base.min_branch_size],
+            subsample_fraction: 1.,
+            validation_fraction: 0.2,
+            retrain_best: false,
+        }
+    }
+}
+
+impl SearchSpace {
+    /// Check if the search space is valid.
+    pub fn validate(&self) -> Result<(), String> {
+        let (c_min, c_max) = self.linear_c;
+        let (n_trees_min, n_trees_max) = self.n_trees;
+        if !(c_min > 0. && c_min <= c_max) {
+            Err(format!(
+                "linear_c must be a non-empty range of positive values, but is {:?}",
+                self.linear_c
+            ))
+        } else if !(n_trees_min > 0 && n_trees_min <= n_trees_max) {
+            Err(format!(
+                "n_trees must be a non-empty range of positive values, but is {:?}",
+                self.n_trees
+            ))
+        } else if self.loss_types.is_empty() {
+            Err("loss_types must not be empty".to_owned())
+        } else if self.cluster_k.is_empty() {
+            Err("cluster_k must not be empty".to_owned())
+        } else if self.cluster_balanced.is_empty() {
+            Err("cluster_balanced must not be empty".to_owned())
+        } else if self.min_branch_size.is_empty() {
+            Err("min_branch_size must not be empty".to_owned())
+        } else if self.min_branch_size.iter().min().unwrap() < self.cluster_k.iter().max().unwrap()
+        {
+            Err(format!(
+                "every min_branch_size {:?} must be at least as large as every cluster_k {:?}",
+                self.min_branch_size, self.cluster_k
+            ))
+        } else if !(self.subsample_fraction > 0. && self.subsample_fraction <= 1.) {
+            Err(format!(
+                "subsample_fraction must be in (0, 1], but is {}",
+                self.subsample_fraction
+            ))
+        } else if !(self.validation_fraction > 0. && self.validation_fraction < 1.) {
+            Err(format!(
+                "validation_fraction must be in (0, 1), but is {}",
+                self.validation_fraction
+            ))
+        } else {
+            // Make sure that the sampled configurations are valid
+            let mut hyper_param = self.base;
+            hyper_param.linear.c = c_min;
+            hyper_param.n_trees = n_trees_min;
+            hyper_param.cluster.k = *self.cluster_k.iter().min().unwrap();
+            hyper_param.min_branch_size = *self.min_branch_size.iter().min().unwrap();
+            hyper_param
+                .validate()
+                .map_err(|msg| format!("Invalid sampled hyper-parameters; {}", msg))
+        }
+    }
+
+    /// Sample a configuration from the search space.
+    pub fn sample<R: Rng>(&self, rng: &mut R) -> train::HyperParam {
+        let mut hyper_param = self.base;
+
+        let (c_min, c_max) = self.linear_c;
+        hyper_param.linear.c = rng.gen_range(c_min.ln()..=c_max.ln()).exp();
+        hyper_param.linear.loss_type = *self.loss_types.choose(rng).unwrap();
+        hyper_param.n_trees = rng.gen_range(self.n_trees.0..=self.n_trees.1);
+        hyper_param.cluster.k = *self.cluster_k.choose(rng).unwrap();
+        hyper_param.cluster.balanced = *self.cluster_balanced.choose(rng).unwrap();
+        hyper_param.min_branch_size = *self.min_branch_size.choose(rng).unwrap();
+
+        hyper_param
+    }
+}
+
+/// Limit on how much work a search may do.
+#[derive(Copy, Clone, Debug)]
+pub enum Budget {
+    /// Stop starting new trials once the given wall-clock time has elapsed.
+    Duration(time::Duration),
+    /// Run the given number of trials.
+    Trials(usize),
+}
+
+/// The outcome of a single trial.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Trial {
+    /// The 0-based index of the trial in the order it was run.
+    pub index: usize,
+    pub hyper_param: train::HyperParam,
+    /// The metric evaluated on the held-out split.
+    pub score: f32,
+    /// Time spent on training and evaluation.
+    pub duration: time::Duration,
+}
+
+/// Summary of a random search.
+#[derive(Clone, Debug)]
+pub struct TuneReport {
+    /// The metric used for scoring trials.
+    pub metric: Metric,
+    /// Completed trials, ordered by score from best to worst.
+    pub trials: Vec<Trial>,
+    /// Whether the search stopped early because of cancellation.
+    pub cancelled: bool,
+    /// The best configuration retrained on the full dataset, if requested.
+    pub best_model: Option<Model>,
+}
+
+impl TuneReport {
+    /// The best trial, if any trial was completed.
+    pub fn best(&self) -> Option<&Trial> {
+        self.trials.first()
+    }
+}
+
+/// Run a random search over the given search space.
+///
+/// See [`random_search_with_cancellation`] for details.
+pub fn random_search(
+    dataset: &DataSet,
+    search_space: &SearchSpace,
+    budget: Budget,
+    beam_size: usize,
+    metric: Metric,
+    seed: u64,
+) -> Result<TuneReport, String> {
+    random_search_with_cancellation(
+        dataset,
+        search_space,
+        budget,
+        beam_size,
+        metric,
+        seed,
+        &CancellationToken::new(),
+    )
+}
+
+/// Run a random search over the given search space, stopping early if cancelled.
+///
+/// The dataset is split once into a training and a held-out part. Each trial samples a
+/// configuration, trains on a subsample of the training part, and is scored with the given metric
+/// on the held-out part. Training is cancelled through [`train::TrainOptions::cancellation`], so a
+/// cancelled search stops within a classifier of the running trial, which is then dropped;
+/// trials completed so far are kept in the report. Trials whose score is NaN are ranked last.
+///
+/// An invalid search space, metric or beam size, a dataset too small to split, or a trial failing
+/// to train is an error.
+pub fn random_search_with_cancellation(
+    dataset: &DataSet,
+    search_space: &SearchSpace,
+    budget: Budget,
+    beam_size: usize,
+    metric: Metric,
+    seed: u64,
+    cancellation: &CancellationToken,
+) -> Result<TuneReport, String> {
+    search_space
+        .validate()
+        .map_err(|msg| format!("Invalid search space; {}", msg))?;
+    metric
+        .validate()
+        .map_err(|msg| format!("Invalid metric; {}", msg))?;
+    if beam_size == 0 {
+        return Err("beam_size must be positive".to_owned());
+    }
+
+    let start_t = time::Instant::now();
+    let mut rng = StdRng::seed_from_u64(seed);
+
+    let (train_set, validation_set) = dataset.split(search_space.validation_fraction, rng.gen());
+    if train_set.is_empty() || validation_set.is_empty() {
+        return Err("Dataset is too small to split for tuning".to_owned());
+    }
+    let options = train::TrainOptions {
+        cancellation: Some(cancellation.clone()),
+        ..train::TrainOptions::default()
+    };
+
+    let mut trials = Vec::new();
+    let mut cancelled = false;
+    loop {
+        let budget_left = match budget {
+            Budget::Duration(limit) => start_t.elapsed() < limit,
+            Budget::Trials(n_trials) => trials.len() < n_trials,
+        };
+        if !budget_left {
+            break;
+        }
+        if cancellation.is_cancelled() {
+            info!("Search cancelled after {} trials", trials.len());
+            cancelled = true;
+            break;
+        }
+
+        let index = trials.len();
+        let hyper_param = search_space.sample(&mut rng);
+        info!("[Trial {}] Training with {:?}", index + 1, hyper_param);
+
+        let trial_t = time::Instant::now();
+        let trial_set = if search_space.subsample_fraction < 1. {
+            train_set.subsample(search_space.subsample_fraction, rng.gen())
+        } else {
+            train_set.clone()
+        };
+        let model = match hyper_param.try_train_with_options(trial_set, &options, &Warnings::new())
+        {
+            Ok(model) => model,
+            Err(train::TrainError::Cancelled) => {
+                info!("Search cancelled during trial {}", index + 1);
+                cancelled = true;
+                break;
+            }
+            Err(e) => return Err(format!("Trial {} failed to train: {}", index + 1, e)),
+        };
+        let predictions = eval::predict_all(&model, &validation_set, beam_size);
+        let score = metric.compute(&validation_set.labels.to_sets(), &predictions);
+
+        info!(
+            "[Trial {}] {:?} = {:.4}; it took {:.2}s",
+            index + 1,
+            metric,
+            score,
+            trial_t.elapsed().as_secs_f32()
+        );
+        trials.push(Trial {
+            index,
+            hyper_param,
+            score,
+            duration: trial_t.elapsed(),
+        });
+    }
+
+    // NB: the sort is stable, so ties are kept in the order the trials were run; NaN scores are
+    // None, which sorts last in reverse
+    trials.sort_by_key(|trial| Reverse(NotNan::new(trial.score).ok()));
+    info!(
+        "Search finished with {} trials; it took {:.2}s",
+        trials.len(),
+        start_t.elapsed().as_secs_f32()
+    );
+
+    let best_model = match trials.first() {
+        Some(best) if search_space.retrain_best && !cancelled => {
+            info!("Retraining best configuration on the full dataset");
+            match best.hyper_param.try_train_with_options(
+                dataset.clone(),
+                &options,
+                &Warnings::new(),
+            ) {
+                Ok(model) => Some(model),
+                Err(train::TrainError::Cancelled) => {
+                    cancelled = true;
+                    None
+                }
+                Err(e) => return Err(format!("Retraining the best configuration failed: {}", e)),
+            }
+        }
+        _ => None,
+    };
+
+    Ok(TuneReport {
+        metric,
+        trials,
+        cancelled,
+        best_model,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::toy_dataset;
+    use itertools::Itertools;
+
+    fn toy_search_space() -> SearchSpace {
+        let mut base = train::HyperParam::default();
+        base.n_trees = 1;
+        base.min_branch_size = 4;
+        SearchSpace {
+            base,
+            linear_c: (0.5, 2.),
+            n_trees: (1, 2),
+            cluster_k: vec![2],
+            min_branch_size: vec![4],
+            validation_fraction: 0.25,
+            ..SearchSpace::default()
+        }
+    }
+
+    #[test]
+    fn test_random_search_orders_trials_by_score() {
+        let dataset = toy_dataset(80, 8, 0);
+        let report = random_search(
+            &dataset,
+            &toy_search_space(),
+            Budget::Trials(2),
+            5,
+            Metric::PrecisionAtK(1),
+            7,
+        )
+        .unwrap();
+
+        assert_eq!(2, report.trials.len());
+        assert!(report.trials[0].score >= report.trials[1].score);
+        assert_eq!(report.trials[0].score, report.best().unwrap().score);
+        assert!(!report.cancelled);
+        assert!(report.best_model.is_none());
+
+        let mut indices = report.trials.iter().map(|t| t.index).collect_vec();
+        indices.sort_unstable();
+        assert_eq!(vec![0, 1], indices);
+    }
+
+    #[test]
+    fn test_random_search_cancelled() {
+        let dataset = toy_dataset(40, 4, 0);
+        let cancellation = CancellationToken::new();
+        cancellation.cancel();
+        let report = random_search_with_cancellation(
+            &dataset,
+            &SearchSpace {
+                retrain_best: true,
+                ..toy_search_space()
+            },
+            Budget::Trials(2),
+            5,
+            Metric::PrecisionAtK(1),
+            7,
+            &cancellation,
+        )
+        .unwrap();
+        assert!(report.cancelled);
+        assert!(report.trials.is_empty());
+        assert!(report.best_model.is_none());
+    }
+
+    #[test]
+    fn test_random_search_invalid() {
+        let dataset = toy_dataset(40, 4, 0);
+        let search = |dataset: &DataSet, search_space: &SearchSpace, beam_size, metric| {
+            random_search(
+                dataset,
+                search_space,
+                Budget::Trials(1),
+                beam_size,
+                metric,
+                7,
+            )
+        };
+        let search_space = toy_search_space();
+        let metric = Metric::PrecisionAtK(1);
+        assert!(search(&dataset, &search_space, 5, metric).is_ok());
+        assert!(search(&dataset, &search_space, 0, metric).is_err());
+        assert!(search(&dataset, &search_space, 5, Metric::PrecisionAtK(0)).is_err());
+        let invalid_space = SearchSpace {
+            linear_c: (2., 1.),
+            ..toy_search_space()
+        };
+        assert!(search(&dataset, &invalid_space, 5, metric).is_err());
+        assert!(search(&toy_dataset(1, 4, 0), &search_space, 5, metric).is_err());
+    }
+
+    #[test]
+    fn test_search_space_validate() {
+        assert!(toy_search_space().validate().is_ok());
+        assert!(SearchSpace {
+            linear_c: (2., 1.),
+            ..toy_search_space()
+        }
+        .validate()
+        .is_err());
+        assert!(SearchSpace {
+            cluster_k: vec![8],
+            ..toy_search_space()
+        }
+        .validate()
+        .is_err());
+    }
+}
@@ -0,0 +1,212 @@
+//! Versioning of saved models, so that a model saved in a format this build can't read is
+//! rejected with a clear error rather than misread.
+//!
+//! A model directory records its [`FormatHeader`] in `format.json`; directories saved before the
+//! header existed are loaded as before. Since every save marks the directory until its header is
+//! written, a directory whose save was interrupted is rejected rather than taken for one of those. Single-file formats carry their version as the last of
+//! their magic bytes.
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::io;
+use std::path::Path;
+
+/// The version of the model directory format written by this build.
+///
+/// It's incremented whenever a change would make older builds misread models saved by newer ones.
+pub const MODEL_FORMAT_VERSION: u32 = 1;
+
+/// The version of the crate, recorded in saved models for diagnostics.
+pub const CRATE_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// The header of a saved model, describing how it was saved.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FormatHeader {
+    /// The version of the format the model was saved in.
+    pub format_version: u32,
+    /// The version of the crate the model was saved by.
+    pub crate_version: String,
+    /// The expected dimension of feature vectors.
+    pub n_features: usize,
+    /// The number of trees.
+    pub n_trees: usize,
+    /// The SHA-256 digest of each tree file in hex, by file name; empty if the model was saved
+    /// before checksums were recorded.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub tree_checksums: BTreeMap<String, String>,
+}
+
+impl FormatHeader {
+    /// The header of a model saved by this build.
+    pub(super) fn new(n_features: usize, n_trees: usize) -> Self {
+        Self {
+            format_version: MODEL_FORMAT_VERSION,
+            crate_version: CRATE_VERSION.to_owned(),
+            n_features,
+            n_trees,
+            tree_checksums: BTreeMap::new(),
+        }
+    }
+
+    /// The recorded checksum of the tree file at the given path, if any.
+    pub(super) fn tree_checksum(&self, tree_path: &Path) -> Option<&str> {
+        let file_name = tree_path.file_name()?.to_str()?;
+        self.tree_checksums.get(file_name).map(String::as_str)
+    }
+
+    /// Check that this build can read the model.
+    pub(super) fn check_version(&self) -> io::Result<()> {
+        if self.format_version > MODEL_FORMAT_VERSION {
+            Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "Model was saved with format v{} by omikuji {}, but this build (omikuji {}) \
+                     reads up to format v{}",
+                    self.format_version, self.crate_version, CRATE_VERSION, MODEL_FORMAT_VERSION
+                ),
+            ))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Check that the loaded model matches the header.
+    pub(super) fn check_model(&self, n_features: usize, n_trees: usize) -> io::Result<()> {
+        if self.n_features != n_features {
+            Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "Model has {} features, but its header records {}",
+                    n_features, self.n_features
+                ),
+            ))
+        } else if self.n_trees != n_trees {
+            Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "Model has {} trees, but its header records {}; tree files may be missing",
+                    n_trees, self.n_trees
+                ),
+            ))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// Check the magic bytes at the start of a single-file model against those of a format, whose
+/// last byte is its version as an ASCII digit.
+///
+/// Returns whether the magic bytes match, or an error if they are of a version of the format
+/// this build can't read.
+pub(super) fn check_magic(prefix: &[u8], magic: &[u8; 8]) -> io::Result<bool> {
+    let (family, version) = magic.split_at(magic.len() - 1);
+    if prefix == magic {
+        Ok(true)
+    } else if prefix.len() == magic.len() && prefix.starts_with(family) {
+        Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "Model was saved with format v{}, but this build (omikuji {}) reads format v{}",
+                prefix[magic.len() - 1] as char,
+                CRATE_VERSION,
+                version[0] as char
+            ),
+        ))
+    } else {
+        Ok(false)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::Model;
+    use crate::test_util::toy_model;
+    use std::fs;
+
+    #[test]
+    fn test_check_version() {
+        let header = FormatHeader::new(10, 3);
+        header.check_version().unwrap();
+        header.check_model(10, 3).unwrap();
+        assert!(header.check_model(11, 3).is_err());
+        assert!(header.check_model(10, 2).is_err());
+
+        let newer = FormatHeader {
+            format_version: MODEL_FORMAT_VERSION + 1,
+            crate_version: "99.0.0".to_owned(),
+            ..header
+        };
+        let message = newer.check_version().unwrap_err().to_string();
+        assert!(message.contains(&format!("format v{}", MODEL_FORMAT_VERSION + 1)));
+        assert!(message.contains("99.0.0"));
+    }
+
+    #[test]
+    fn test_check_magic() {
+        assert!(check_magic(b"OMKJFRM1", b"OMKJFRM1").unwrap());
+        assert!(!check_magic(b"\xa4foo", b"OMKJFRM1").unwrap());
+        assert!(!check_magic(b"OMKJMDL1", b"OMKJFRM1").unwrap());
+        assert!(!check_magic(b"OMKJFRM", b"OMKJFRM1").unwrap());
+        let message = check_magic(b"OMKJFRM2", b"OMKJFRM1")
+            .unwrap_err()
+            .to_string();
+        assert!(message.contains("format v2"));
+        assert!(message.contains("reads format v1"));
+    }
+
+    #[test]
+    fn test_save_and_load_with_header() {
+        let model = toy_model(3, 0);
+        let dir = std::env::temp_dir().join(format!("omikuji-format-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        model.save(&dir).unwrap();
+
+        let header_path = dir.join("format.json");
+        let header: FormatHeader =
+            serde_json::from_reader(fs::File::open(&header_path).unwrap()).unwrap();
+        assert_eq!((model.n_features(), 3), (header.n_features, header.n_trees));
+        assert_eq!(3, header.tree_checksums.len());
+        assert_eq!(3, Model::load(&dir).unwrap().n_trees());
+
+        // Adding trees to the directory updates the header
+        toy_model(1, 1).save(&dir).unwrap();
+        let header: FormatHeader =
+            serde_json::from_reader(fs::File::open(&header_path).unwrap()).unwrap();
+        assert_eq!(4, header.n_trees);
+        assert_eq!(4, header.tree_checksums.len());
+        assert_eq!(4, Model::load(&dir).unwrap().n_trees());
+
+        // Missing trees are detected
+        fs::remove_file(dir.join("tree3.cbor")).unwrap();
+        let message = Model::load(&dir).unwrap_err().to_string();
+        assert!(message.contains("3 trees"), "{}", message);
+
+        // Newer formats are rejected, and so is adding trees to them
+        let newer = FormatHeader {
+            format_version: MODEL_FORMAT_VERSION + 1,
+            n_trees: 3,
+            ..header
+        };
+        fs::write(&header_path, serde_json::to_vec(&newer).unwrap()).unwrap();
+        let message = Model::load(&dir).unwrap_err().to_string();
+        assert!(message.contains("this build"), "{}", message);
+        assert!(model.save(&dir).is_err());
+
+        // Directories without a header are loaded as before
+        fs::remove_file(&header_path).unwrap();
+        assert_eq!(3, Model::load(&dir).unwrap().n_trees());
+
+        // Unless a save into them was interrupted before the header was written
+        fs::write(dir.join("save_in_progress"), b"").unwrap();
+        let error = Model::load(&dir).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, error.kind());
+        assert!(error.to_string().contains("interrupted"), "{}", error);
+        assert_eq!(
+            io::ErrorKind::InvalidData,
+            Model::verify_dir(&dir).unwrap_err().kind()
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
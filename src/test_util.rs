@@ -0,0 +1,94 @@
+//! Helpers shared by unit tests across the crate.
+
+use crate::data::LabelMatrix;
+use crate::mat_util::*;
+use crate::model::bench::{thread_allocations, CountingAllocator};
+use crate::model::TrainHyperParam;
+use crate::{DataSet, Index, IndexSet, Model};
+use rand::prelude::*;
+use rand::rngs::StdRng;
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+/// Run the closure, returning its result and the number of allocations it made on this thread.
+pub(crate) fn count_allocations<T>(f: impl FnOnce() -> T) -> (T, usize) {
+    let before = thread_allocations().0;
+    let result = f();
+    let after = thread_allocations().0;
+    (result, (after - before) as usize)
+}
+
+/// Number of features that are characteristic of each label in [`toy_dataset`].
+pub(crate) const TOY_FEATURES_PER_LABEL: usize = 4;
+
+/// Number of noise features appended after the label-specific ones in [`toy_dataset`].
+pub(crate) const TOY_NOISE_FEATURES: usize = 8;
+
+/// Generate a small, easily learnable dataset.
+///
+/// Label `l` owns features `l * TOY_FEATURES_PER_LABEL..(l + 1) * TOY_FEATURES_PER_LABEL`. Each
+/// example is assigned the label `i % n_labels` and occasionally a second random label; its
+/// features are those owned by its labels plus a couple of random noise features.
+pub(crate) fn toy_dataset(n_examples: usize, n_labels: usize, seed: u64) -> DataSet {
+    let n_features = n_labels * TOY_FEATURES_PER_LABEL + TOY_NOISE_FEATURES;
+    let mut rng = StdRng::seed_from_u64(seed);
+
+    let mut feature_lists = Vec::with_capacity(n_examples);
+    let mut label_matrix = LabelMatrix::with_capacity(n_examples, 0);
+    for i in 0..n_examples {
+        let mut labels = IndexSet::new();
+        labels.insert((i % n_labels) as Index);
+        if rng.gen_bool(0.3) {
+            labels.insert(rng.gen_range(0..n_labels) as Index);
+        }
+
+        let mut features = Vec::new();
+        for &label in &labels {
+            let offset = label as usize * TOY_FEATURES_PER_LABEL;
+            for f in offset..offset + TOY_FEATURES_PER_LABEL {
+                features.push((f as Index, rng.gen_range(0.5..1.5)));
+            }
+        }
+        for _ in 0..2 {
+            let f = n_labels * TOY_FEATURES_PER_LABEL + rng.gen_range(0..TOY_NOISE_FEATURES);
+            if features.iter().all(|&(i, _)| i as usize != f) {
+                features.push((f as Index, rng.gen_range(0.1..0.5)));
+            }
+        }
+        features.sort_by_index();
+
+        feature_lists.push(features);
+        label_matrix.push_row(labels);
+    }
+
+    DataSet {
+        n_features,
+        n_labels,
+        feature_lists,
+        labels: label_matrix,
+    }
+}
+
+/// Train a small model on [`toy_dataset`] with deep enough trees to exercise branch nodes.
+pub(crate) fn toy_model(n_trees: usize, seed: u64) -> Model {
+    let mut hyper_param = TrainHyperParam::default();
+    hyper_param.n_trees = n_trees;
+    hyper_param.min_branch_size = 2;
+    hyper_param.train(toy_dataset(60, 8, seed))
+}
+
+/// Hyper-parameters under which separate training runs on [`toy_dataset`] give the same model up
+/// to rounding.
+///
+/// Clustering and the liblinear solvers draw from unseeded random number generators, so trees are
+/// kept to single leaves and classifiers are trained to convergence.
+pub(crate) fn reproducible_hyper_param() -> TrainHyperParam {
+    let mut hyper_param = TrainHyperParam::default();
+    hyper_param.n_trees = 2;
+    hyper_param.min_branch_size = 1000;
+    hyper_param.linear.eps = 1e-6;
+    hyper_param.linear.max_iter = 10_000;
+    hyper_param.linear.weight_threshold = 0.;
+    hyper_param
+}
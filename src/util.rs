@@ -1,7 +1,32 @@
 use std::io::{stderr, Stderr};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 
 pub(crate) type ProgressBar = pbr::ProgressBar<Stderr>;
 
 pub(crate) fn create_progress_bar(total: u64) -> ProgressBar {
     ProgressBar::on(stderr(), total)
 }
+
+/// A flag shared between threads for requesting long-running operations to stop early.
+///
+/// Cloning the token is cheap, and all clones observe the same state.
+#[derive(Clone, Debug, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    /// Create a new token that is not yet cancelled.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Request cancellation; operations holding a clone of this token stop at their next check.
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    /// Whether cancellation has been requested.
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+}
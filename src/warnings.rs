@@ -0,0 +1,146 @@
+use crate::Index;
+use log::warn;
+use std::fmt;
+use std::sync::Mutex;
+
+/// A non-fatal issue noticed while loading data, training, or evaluating.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Warning {
+    /// An example in a data file has no labels.
+    ExampleWithoutLabels { line: usize },
+    /// An example in a data file has no features.
+    ExampleWithoutFeatures { line: usize },
+    /// An example in a data file has a label not smaller than the number of labels in the header.
+    LabelOutOfRange {
+        line: usize,
+        label: Index,
+        n_labels: usize,
+    },
+    /// A node became a leaf because the depth limit was reached, although it had enough labels
+    /// for further branching.
+    MaxDepthReached { depth: usize, n_labels: usize },
+    /// Clustering failed to split the labels of a node, which therefore became a leaf.
+    ClusteringFailed { depth: usize, n_labels: usize },
+    /// Some labels in the test data never appear in the model, so they can never be predicted.
+    LabelsNotInModel { n_labels: usize },
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Warning::ExampleWithoutLabels { line } => {
+                write!(f, "Example in line {} has no labels", line)
+            }
+            Warning::ExampleWithoutFeatures { line } => {
+                write!(f, "Example in line {} has no features", line)
+            }
+            Warning::LabelOutOfRange {
+                line,
+                label,
+                n_labels,
+            } => write!(
+                f,
+                "Label {} in line {} is out of range for {} labels",
+                label, line, n_labels
+            ),
+            Warning::MaxDepthReached { depth, n_labels } => write!(
+                f,
+                "Depth limit reached at depth {}; created a leaf with {} labels",
+                depth, n_labels
+            ),
+            Warning::ClusteringFailed { depth, n_labels } => write!(
+                f,
+                "Failed to cluster {} labels at depth {}; created a leaf instead",
+                n_labels, depth
+            ),
+            Warning::LabelsNotInModel { n_labels } => write!(
+                f,
+                "{} labels in the test data never appear in the model",
+                n_labels
+            ),
+        }
+    }
+}
+
+/// A collector of warnings that can be shared between threads.
+///
+/// Every warning pushed is also logged, so callers who only configure logging still see them.
+#[derive(Debug, Default)]
+pub struct Warnings(Mutex<Vec<Warning>>);
+
+impl Warnings {
+    /// Create an empty collector.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a warning.
+    pub fn push(&self, warning: Warning) {
+        warn!("{}", warning);
+        self.0
+            .lock()
+            .expect("Failed to lock warnings")
+            .push(warning);
+    }
+
+    /// Move all warnings from another collector into this one.
+    pub fn append(&self, other: Warnings) {
+        self.0
+            .lock()
+            .expect("Failed to lock warnings")
+            .extend(other.into_vec());
+    }
+
+    /// The number of warnings collected.
+    pub fn len(&self) -> usize {
+        self.0.lock().expect("Failed to lock warnings").len()
+    }
+
+    /// Whether no warnings have been collected.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// A copy of the warnings collected so far, in the order they were pushed.
+    pub fn to_vec(&self) -> Vec<Warning> {
+        self.0.lock().expect("Failed to lock warnings").clone()
+    }
+
+    /// Take the warnings collected, in the order they were pushed.
+    pub fn into_vec(self) -> Vec<Warning> {
+        self.0.into_inner().expect("Failed to lock warnings")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_warnings() {
+        let warnings = Warnings::new();
+        assert!(warnings.is_empty());
+
+        warnings.push(Warning::ExampleWithoutLabels { line: 3 });
+        let other = Warnings::new();
+        other.push(Warning::LabelsNotInModel { n_labels: 2 });
+        warnings.append(other);
+
+        assert_eq!(
+            vec![
+                Warning::ExampleWithoutLabels { line: 3 },
+                Warning::LabelsNotInModel { n_labels: 2 },
+            ],
+            warnings.into_vec()
+        );
+        assert_eq!(
+            "Label 7 in line 2 is out of range for 5 labels",
+            Warning::LabelOutOfRange {
+                line: 2,
+                label: 7,
+                n_labels: 5
+            }
+            .to_string()
+        );
+    }
+}
@@ -0,0 +1,101 @@
+//! Tests of the `omikuji` binary, run as a subprocess.
+use omikuji::model::TrainHyperParam;
+use omikuji::DataSet;
+use std::fmt::Write;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// A temporary directory for the files of a test, removed when dropped.
+struct TempDir(PathBuf);
+
+impl TempDir {
+    fn new(name: &str) -> Self {
+        let path =
+            std::env::temp_dir().join(format!("omikuji-cli-{}-{}", name, std::process::id()));
+        std::fs::create_dir_all(&path).unwrap();
+        Self(path)
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+/// Write a small dataset where each example has one of 5 labels, with two features owned by it.
+fn write_dataset(path: &Path) {
+    let mut text = "50 10 5\n".to_owned();
+    for i in 0..50 {
+        let label = i % 5;
+        writeln!(text, "{} {}:0.8 {}:0.6", label, 2 * label, 2 * label + 1).unwrap();
+    }
+    std::fs::write(path, text).unwrap();
+}
+
+#[test]
+fn test_bench() {
+    let dir = TempDir::new("bench");
+    let data_path = dir.0.join("data.txt");
+    let model_path = dir.0.join("model");
+    write_dataset(&data_path);
+    let dataset = DataSet::load_xc_repo_data_file(&data_path).unwrap();
+    let mut hyper_param = TrainHyperParam::default();
+    hyper_param.n_trees = 1;
+    hyper_param.train(dataset).save(&model_path).unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_omikuji"))
+        .arg("bench")
+        .arg(&model_path)
+        .arg("--test_file")
+        .arg(&data_path)
+        .args(["--duration", "1s", "--warmup", "100ms", "--threads", "1,2"])
+        .output()
+        .unwrap();
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let mut lines = stdout
+        .lines()
+        .map(|line| line.split_whitespace().collect::<Vec<_>>());
+    let header = lines.next().expect("Missing table header");
+    assert_eq!(
+        vec![
+            "threads",
+            "predictions",
+            "throughput/s",
+            "p50_us",
+            "p95_us",
+            "p99_us",
+            "max_us",
+            "allocs/pred",
+            "bytes/pred"
+        ],
+        header
+    );
+    let rows = lines.collect::<Vec<_>>();
+    assert_eq!(2, rows.len());
+    for (row, n_threads) in rows.iter().zip(["1", "2"]) {
+        assert_eq!(header.len(), row.len());
+        assert_eq!(n_threads, row[0]);
+        assert!(row[1].parse::<u64>().unwrap() > 0);
+        assert!(row[2].parse::<f64>().unwrap() > 0.);
+        // Latency percentiles, then the maximum, don't decrease
+        let latencies = row[3..7]
+            .iter()
+            .map(|value| value.parse::<f64>().unwrap())
+            .collect::<Vec<_>>();
+        assert!(latencies.windows(2).all(|w| w[0] <= w[1]));
+        for value in &row[7..] {
+            if cfg!(feature = "count-allocations") {
+                assert!(value.parse::<f64>().unwrap() >= 0.);
+            } else {
+                assert_eq!("-", *value);
+            }
+        }
+    }
+}
@@ -0,0 +1,93 @@
+//! Peak memory of loading a dataset, measured with a global allocator that tracks the bytes in use.
+//!
+//! This is a separate test binary with a single test, so that nothing else allocates while it
+//! measures.
+use omikuji::{DataSet, Warnings};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::fmt::Write;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Delegates to the system allocator, tracking the bytes in use and their peak over all threads.
+struct PeakAllocator;
+
+static IN_USE: AtomicUsize = AtomicUsize::new(0);
+static PEAK: AtomicUsize = AtomicUsize::new(0);
+
+fn add_in_use(size: usize) {
+    let in_use = IN_USE.fetch_add(size, Ordering::SeqCst) + size;
+    PEAK.fetch_max(in_use, Ordering::SeqCst);
+}
+
+unsafe impl GlobalAlloc for PeakAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        add_in_use(layout.size());
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        IN_USE.fetch_sub(layout.size(), Ordering::SeqCst);
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        // Count the old and the new block as both in use, as they may be while copying
+        add_in_use(new_size);
+        let new_ptr = System.realloc(ptr, layout, new_size);
+        IN_USE.fetch_sub(layout.size(), Ordering::SeqCst);
+        new_ptr
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: PeakAllocator = PeakAllocator;
+
+/// Generate data where each example has many labels and a single feature, so that labels take
+/// most of the memory.
+fn generate_data(n_examples: usize, n_labels_per_example: usize) -> String {
+    let mut text = format!("{} 10 {}\n", n_examples, n_labels_per_example + 10);
+    for i in 0..n_examples {
+        let labels = (0..n_labels_per_example)
+            .map(|label| (label + i % 10).to_string())
+            .collect::<Vec<_>>()
+            .join(",");
+        writeln!(text, "{} {}:1", labels, i % 10).unwrap();
+    }
+    text
+}
+
+#[test]
+fn test_load_peak_memory() {
+    let (n_examples, n_labels_per_example) = (200_000, 20);
+    // Start the thread pool outside the measurement
+    DataSet::read_xc_repo_data_with_warnings(generate_data(10, 2).as_bytes(), &Warnings::new())
+        .unwrap();
+    let text = generate_data(n_examples, n_labels_per_example);
+    let warnings = Warnings::new();
+
+    let before = IN_USE.load(Ordering::SeqCst);
+    PEAK.store(before, Ordering::SeqCst);
+    let dataset = DataSet::read_xc_repo_data_with_warnings(text.as_bytes(), &warnings).unwrap();
+    let peak = PEAK.load(Ordering::SeqCst) - before;
+    let retained = IN_USE.load(Ordering::SeqCst) - before;
+    assert_eq!(n_examples, dataset.len());
+
+    // Besides the dataset itself, loading needs a copy of the text, with up to twice its size
+    // allocated while it's read, and a slice for each line, which may be doubled while collected.
+    // Separate label lists of all examples alive at once would need more than 150 bytes per
+    // example on top of that.
+    let label_bytes = n_examples * (n_labels_per_example * 4 + 8);
+    let allowed = retained + 2 * text.len() + 32 * n_examples;
+    eprintln!(
+        "Loading {} bytes of text: {} bytes at peak, {} bytes retained, of which {} for labels",
+        text.len(),
+        peak,
+        retained,
+        label_bytes
+    );
+    assert!(
+        peak <= allowed,
+        "Peak memory of {} bytes exceeds {} bytes",
+        peak,
+        allowed
+    );
+}